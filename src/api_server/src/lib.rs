@@ -396,6 +396,7 @@ mod tests {
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
                 version: None,
+                free_page_hinting: false,
             })),
             start_time_us,
         );
@@ -410,6 +411,7 @@ mod tests {
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
                 version: None,
+                free_page_hinting: false,
             })),
             start_time_us,
         );
@@ -11,18 +11,24 @@ use super::VmmData;
 use crate::request::actions::parse_put_actions;
 use crate::request::balloon::{parse_get_balloon, parse_patch_balloon, parse_put_balloon};
 use crate::request::boot_source::parse_put_boot_source;
-use crate::request::drive::{parse_patch_drive, parse_put_drive};
+use crate::request::drive::{parse_patch_drive, parse_put_drive, parse_put_drive_detach};
 use crate::request::instance_info::parse_get_instance_info;
 use crate::request::logger::parse_put_logger;
 use crate::request::machine_configuration::{
     parse_get_machine_config, parse_patch_machine_config, parse_put_machine_config,
 };
+use crate::request::memory_hotplug::{
+    parse_get_memory_hotplug, parse_patch_memory_hotplug, parse_put_memory_hotplug,
+};
 use crate::request::metrics::parse_put_metrics;
 use crate::request::mmds::{parse_get_mmds, parse_patch_mmds, parse_put_mmds};
-use crate::request::net::{parse_patch_net, parse_put_net};
+use crate::request::net::{parse_patch_net, parse_put_net, parse_put_net_capture};
 use crate::request::snapshot::{parse_patch_vm_state, parse_put_snapshot};
 use crate::request::version::parse_get_version;
-use crate::request::vsock::parse_put_vsock;
+use crate::request::vsock::{
+    parse_get_vsock_connections, parse_patch_vsock, parse_put_vsock,
+    parse_put_vsock_connection_reset,
+};
 use crate::ApiServer;
 
 pub(crate) enum RequestAction {
@@ -99,16 +105,29 @@ impl ParsedRequest {
                 Ok(ParsedRequest::new_sync(VmmAction::GetFullVmConfig))
             }
             (Method::Get, "machine-config", None) => parse_get_machine_config(),
+            (Method::Get, "memory-hotplug", None) => parse_get_memory_hotplug(),
             (Method::Get, "mmds", None) => parse_get_mmds(),
+            (Method::Get, "vsock", None) if path_tokens.get(1) == Some(&"connections") => {
+                parse_get_vsock_connections()
+            }
             (Method::Get, _, Some(_)) => method_to_error(Method::Get),
             (Method::Put, "actions", Some(body)) => parse_put_actions(body),
             (Method::Put, "balloon", Some(body)) => parse_put_balloon(body),
             (Method::Put, "boot-source", Some(body)) => parse_put_boot_source(body),
+            (Method::Put, "drives", Some(body)) if path_tokens.get(2) == Some(&"detach") => {
+                parse_put_drive_detach(body, path_tokens.get(1))
+            }
             (Method::Put, "drives", Some(body)) => parse_put_drive(body, path_tokens.get(1)),
             (Method::Put, "logger", Some(body)) => parse_put_logger(body),
             (Method::Put, "machine-config", Some(body)) => parse_put_machine_config(body),
+            (Method::Put, "memory-hotplug", Some(body)) => parse_put_memory_hotplug(body),
             (Method::Put, "metrics", Some(body)) => parse_put_metrics(body),
             (Method::Put, "mmds", Some(body)) => parse_put_mmds(body, path_tokens.get(1)),
+            (Method::Put, "network-interfaces", Some(body))
+                if path_tokens.get(2) == Some(&"capture") =>
+            {
+                parse_put_net_capture(body, path_tokens.get(1))
+            }
             (Method::Put, "network-interfaces", Some(body)) => {
                 parse_put_net(body, path_tokens.get(1))
             }
@@ -116,16 +135,24 @@ impl ParsedRequest {
                 Ok(ParsedRequest::new(RequestAction::ShutdownInternal))
             }
             (Method::Put, "snapshot", Some(body)) => parse_put_snapshot(body, path_tokens.get(1)),
+            (Method::Put, "vsock", Some(body))
+                if path_tokens.get(1) == Some(&"connections")
+                    && path_tokens.get(2) == Some(&"reset") =>
+            {
+                parse_put_vsock_connection_reset(body)
+            }
             (Method::Put, "vsock", Some(body)) => parse_put_vsock(body),
             (Method::Put, _, None) => method_to_error(Method::Put),
             (Method::Patch, "balloon", Some(body)) => parse_patch_balloon(body, path_tokens.get(1)),
             (Method::Patch, "drives", Some(body)) => parse_patch_drive(body, path_tokens.get(1)),
             (Method::Patch, "machine-config", Some(body)) => parse_patch_machine_config(body),
+            (Method::Patch, "memory-hotplug", Some(body)) => parse_patch_memory_hotplug(body),
             (Method::Patch, "mmds", Some(body)) => parse_patch_mmds(body),
             (Method::Patch, "network-interfaces", Some(body)) => {
                 parse_patch_net(body, path_tokens.get(1))
             }
             (Method::Patch, "vm", Some(body)) => parse_patch_vm_state(body),
+            (Method::Patch, "vsock", Some(body)) => parse_patch_vsock(body),
             (Method::Patch, _, None) => method_to_error(Method::Patch),
             (method, unknown_uri, _) => {
                 Err(Error::InvalidPathMethod(unknown_uri.to_string(), method))
@@ -166,6 +193,7 @@ impl ParsedRequest {
                 VmmData::MachineConfiguration(vm_config) => {
                     Self::success_response_with_data(vm_config)
                 }
+                VmmData::MemoryHotplugStatus(status) => Self::success_response_with_data(status),
                 VmmData::MmdsValue(value) => Self::success_response_with_mmds_value(value),
                 VmmData::BalloonConfig(balloon_config) => {
                     Self::success_response_with_data(balloon_config)
@@ -176,6 +204,7 @@ impl ParsedRequest {
                     &serde_json::json!({ "firecracker_version": version.as_str() }),
                 ),
                 VmmData::FullVmConfig(config) => Self::success_response_with_data(config),
+                VmmData::VsockConnections(conns) => Self::success_response_with_data(conns),
             },
             Err(vmm_action_error) => {
                 let mut response = match vmm_action_error {
@@ -335,6 +364,7 @@ pub(crate) mod tests {
     use vmm::vmm_config::balloon::{BalloonDeviceConfig, BalloonStats};
     use vmm::vmm_config::instance_info::InstanceInfo;
     use vmm::vmm_config::machine_config::VmConfig;
+    use vmm::vmm_config::memory_hotplug::MemoryHotplugStatus;
 
     use super::*;
 
@@ -579,6 +609,9 @@ pub(crate) mod tests {
                 VmmData::MachineConfiguration(cfg) => {
                     http_response(&serde_json::to_string(cfg).unwrap(), 200)
                 }
+                VmmData::MemoryHotplugStatus(status) => {
+                    http_response(&serde_json::to_string(status).unwrap(), 200)
+                }
                 VmmData::MmdsValue(value) => {
                     http_response(&serde_json::to_string(value).unwrap(), 200)
                 }
@@ -589,6 +622,9 @@ pub(crate) mod tests {
                     &serde_json::json!({ "firecracker_version": version.as_str() }).to_string(),
                     200,
                 ),
+                VmmData::VsockConnections(conns) => {
+                    http_response(&serde_json::to_string(conns).unwrap(), 200)
+                }
             };
             let response = ParsedRequest::convert_to_response(&data);
             assert!(response.write_all(&mut buf).is_ok());
@@ -604,9 +640,11 @@ pub(crate) mod tests {
         verify_ok_response_with(VmmData::Empty);
         verify_ok_response_with(VmmData::FullVmConfig(VmmConfig::default()));
         verify_ok_response_with(VmmData::MachineConfiguration(VmConfig::default()));
+        verify_ok_response_with(VmmData::MemoryHotplugStatus(MemoryHotplugStatus::default()));
         verify_ok_response_with(VmmData::MmdsValue(serde_json::from_str("{}").unwrap()));
         verify_ok_response_with(VmmData::InstanceInformation(InstanceInfo::default()));
         verify_ok_response_with(VmmData::VmmVersion(String::default()));
+        verify_ok_response_with(VmmData::VsockConnections(Vec::new()));
 
         // Error.
         let error = VmmActionError::StartMicrovm(StartMicrovmError::MissingKernelConfig);
@@ -667,6 +705,18 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_get_memory_hotplug() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/memory-hotplug", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_get_mmds() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -691,6 +741,18 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_get_vsock_connections() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("GET", "/vsock/connections", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_put_actions() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -748,6 +810,40 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_put_drive_detach() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"drive_id\": \"string\" }";
+        sender
+            .write_all(http_request("PUT", "/drives/string/detach", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        match vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()) {
+            VmmAction::DetachBlockDevice(drive_id) => assert_eq!(drive_id, "string"),
+            _ => panic!("Test failed: Invalid parameters"),
+        }
+    }
+
+    #[test]
+    fn test_try_from_put_net_capture() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"iface_id\": \"string\", \"action\": \"Stop\" }";
+        sender
+            .write_all(
+                http_request("PUT", "/network-interfaces/string/capture", Some(body)).as_bytes(),
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        match vmm_action_from_request(ParsedRequest::try_from_request(&req).unwrap()) {
+            VmmAction::UpdateNetworkCapture(capture) => assert_eq!(capture.iface_id, "string"),
+            _ => panic!("Test failed: Invalid parameters"),
+        }
+    }
+
     #[test]
     fn test_try_from_put_logger() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -911,6 +1007,27 @@ pub(crate) mod tests {
         assert!(connection.try_read().is_ok());
         let req = connection.pop_parsed_request().unwrap();
         assert!(ParsedRequest::try_from_request(&req).is_ok());
+
+        let body = "{ \"local_port\": 1024, \"peer_port\": 52 }";
+        sender
+            .write_all(http_request("PUT", "/vsock/connections/reset", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
+    #[test]
+    fn test_try_from_patch_vsock() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"rate_limiter\": { \"ops\": { \"size\": 10, \"refill_time\": 100 } } }";
+        sender
+            .write_all(http_request("PATCH", "/vsock", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
     #[test]
@@ -933,6 +1050,26 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_put_patch_memory_hotplug() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"total_size_mib\": 1024, \"block_size_mib\": 2 }";
+        sender
+            .write_all(http_request("PUT", "/memory-hotplug", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+        let body = "{ \"requested_size_mib\": 128 }";
+        sender
+            .write_all(http_request("PATCH", "/memory-hotplug", Some(body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_patch_drives() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
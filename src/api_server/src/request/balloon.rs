@@ -165,5 +165,56 @@ mod tests {
                 "stats_polling_interval_s": 0
             }"#;
         assert!(parse_put_balloon(&Body::new(body)).is_ok());
+        // PUT with free page reporting.
+        let body = r#"{
+                "amount_mib": 1000,
+                "deflate_on_oom": true,
+                "free_page_reporting": true
+            }"#;
+        #[allow(clippy::match_wild_err_arm)]
+        match vmm_action_from_request(parse_put_balloon(&Body::new(body)).unwrap()) {
+            VmmAction::SetBalloonDevice(balloon_cfg) => assert!(balloon_cfg.free_page_reporting),
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+        // PUT with free page hinting.
+        let body = r#"{
+                "amount_mib": 1000,
+                "deflate_on_oom": true,
+                "free_page_hinting": true
+            }"#;
+        #[allow(clippy::match_wild_err_arm)]
+        match vmm_action_from_request(parse_put_balloon(&Body::new(body)).unwrap()) {
+            VmmAction::SetBalloonDevice(balloon_cfg) => assert!(balloon_cfg.free_page_hinting),
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+        // PUT with an automatic sizing policy.
+        let body = r#"{
+                "amount_mib": 0,
+                "deflate_on_oom": true,
+                "stats_polling_interval_s": 1,
+                "auto_size": {
+                    "min_mib": 0,
+                    "max_mib": 512,
+                    "target_free_mib": 128,
+                    "step_mib": 32
+                }
+            }"#;
+        #[allow(clippy::match_wild_err_arm)]
+        match vmm_action_from_request(parse_put_balloon(&Body::new(body)).unwrap()) {
+            VmmAction::SetBalloonDevice(balloon_cfg) => {
+                assert_eq!(balloon_cfg.auto_size.unwrap().target_free_mib, 128)
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+        // PUT with an incomplete automatic sizing policy.
+        let body = r#"{
+                "amount_mib": 0,
+                "deflate_on_oom": true,
+                "stats_polling_interval_s": 1,
+                "auto_size": {
+                    "max_mib": 512
+                }
+            }"#;
+        assert!(parse_put_balloon(&Body::new(body)).is_err());
     }
 }
@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0<Paste>
 
 use logger::{IncMetric, METRICS};
+use serde::{Deserialize, Serialize};
 use vmm::vmm_config::drive::{BlockDeviceConfig, BlockDeviceUpdateConfig};
 
 use super::super::VmmAction;
@@ -38,6 +39,43 @@ pub(crate) fn parse_put_drive(
     }
 }
 
+// The model of the json body of a drive detach request.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct DriveDetachBody {
+    drive_id: String,
+}
+
+pub(crate) fn parse_put_drive_detach(
+    body: &Body,
+    id_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.drive_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.put_api_requests.drive_fails.inc();
+        return Err(Error::EmptyID);
+    };
+
+    let detach_body = serde_json::from_slice::<DriveDetachBody>(body.raw()).map_err(|err| {
+        METRICS.put_api_requests.drive_fails.inc();
+        err
+    })?;
+
+    if id != detach_body.drive_id {
+        METRICS.put_api_requests.drive_fails.inc();
+        Err(Error::Generic(
+            StatusCode::BadRequest,
+            "The id from the path does not match the id from the body!".to_string(),
+        ))
+    } else {
+        Ok(ParsedRequest::new_sync(VmmAction::DetachBlockDevice(
+            detach_body.drive_id,
+        )))
+    }
+}
+
 pub(crate) fn parse_patch_drive(
     body: &Body,
     id_from_path: Option<&&str>,
@@ -245,6 +283,9 @@ mod tests {
                 "is_read_only": true,
                 "cache_type": "Unsafe",
                 "io_engine": "Sync",
+                "disk_format": "Raw",
+                "num_queues": 2,
+                "direct_io": true,
                 "rate_limiter": {
                     "bandwidth": {
                         "size": 0,
@@ -259,5 +300,47 @@ mod tests {
                 }
             }"#;
         assert!(parse_put_drive(&Body::new(body), Some(&"1000")).is_ok());
+
+        // PUT with a vhost-user backend.
+        let body = r#"{
+            "drive_id": "1000",
+            "socket": "/tmp/vhost-user-blk.sock",
+            "is_root_device": false,
+            "is_read_only": false,
+            "num_queues": 2
+        }"#;
+        match vmm_action_from_request(parse_put_drive(&Body::new(body), Some(&"1000")).unwrap()) {
+            VmmAction::InsertBlockDevice(cfg) => {
+                assert_eq!(cfg.path_on_host, None);
+                assert_eq!(cfg.socket.unwrap(), "/tmp/vhost-user-blk.sock");
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+    }
+
+    #[test]
+    fn test_parse_put_drive_detach_request() {
+        assert!(parse_put_drive_detach(&Body::new("invalid_payload"), None).is_err());
+        assert!(parse_put_drive_detach(&Body::new("invalid_payload"), Some(&"id")).is_err());
+
+        // PUT with invalid fields.
+        let body = r#"{
+            "drive_id": "foo",
+            "path_on_host": "dummy"
+        }"#;
+        assert!(parse_put_drive_detach(&Body::new(body), Some(&"foo")).is_err());
+
+        let body = r#"{
+            "drive_id": "foo"
+        }"#;
+        // Must fail since the drive id differs from id_from_path (foo vs bar).
+        assert!(parse_put_drive_detach(&Body::new(body), Some(&"bar")).is_err());
+
+        match vmm_action_from_request(
+            parse_put_drive_detach(&Body::new(body), Some(&"foo")).unwrap(),
+        ) {
+            VmmAction::DetachBlockDevice(drive_id) => assert_eq!(drive_id, "foo"),
+            _ => panic!("Test failed: Invalid parameters"),
+        };
     }
 }
@@ -45,7 +45,11 @@ pub(crate) fn parse_patch_machine_config(body: &Body) -> Result<ParsedRequest, E
 
 #[cfg(test)]
 mod tests {
-    use vmm::vmm_config::machine_config::CpuFeaturesTemplate;
+    use std::path::PathBuf;
+
+    use vmm::vmm_config::machine_config::{
+        CpuFeaturesTemplate, HugePageConfig, SharedMemoryConfig,
+    };
 
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
@@ -84,6 +88,9 @@ mod tests {
             smt: Some(false),
             cpu_template: Some(CpuFeaturesTemplate::None),
             track_dirty_pages: Some(false),
+            block_hotplug_slots: Some(0),
+            huge_pages: Some(HugePageConfig::None),
+            shared_memory: Some(None),
         };
 
         match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -95,7 +102,13 @@ mod tests {
                 "vcpu_count": 8,
                 "mem_size_mib": 1024,
                 "smt": false,
-                "track_dirty_pages": true
+                "track_dirty_pages": true,
+                "block_hotplug_slots": 2,
+                "huge_pages": "2M",
+                "shared_memory": {
+                    "mem_file_path": "/dev/shm/guest_mem",
+                    "uds_path": "/tmp/guest_mem.sock"
+                }
             }"#;
         let expected_config = VmUpdateConfig {
             vcpu_count: Some(8),
@@ -103,6 +116,12 @@ mod tests {
             smt: Some(false),
             cpu_template: Some(CpuFeaturesTemplate::None),
             track_dirty_pages: Some(true),
+            block_hotplug_slots: Some(2),
+            huge_pages: Some(HugePageConfig::Hugetlbfs2M),
+            shared_memory: Some(Some(SharedMemoryConfig {
+                mem_file_path: Some(PathBuf::from("/dev/shm/guest_mem")),
+                uds_path: Some(PathBuf::from("/tmp/guest_mem.sock")),
+            })),
         };
 
         match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -128,6 +147,9 @@ mod tests {
                 smt: Some(false),
                 cpu_template: Some(CpuFeaturesTemplate::T2),
                 track_dirty_pages: Some(true),
+                block_hotplug_slots: Some(0),
+                huge_pages: Some(HugePageConfig::None),
+                shared_memory: Some(None),
             };
 
             match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -157,6 +179,9 @@ mod tests {
                 smt: Some(true),
                 cpu_template: Some(CpuFeaturesTemplate::None),
                 track_dirty_pages: Some(true),
+                block_hotplug_slots: Some(0),
+                huge_pages: Some(HugePageConfig::None),
+                shared_memory: Some(None),
             };
 
             match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -182,6 +207,21 @@ mod tests {
               }"#;
         assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
 
+        let body = r#"{
+                "block_hotplug_slots": 4
+              }"#;
+        assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
+
+        let body = r#"{
+                "huge_pages": "1G"
+              }"#;
+        assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
+
+        let body = r#"{
+                "shared_memory": null
+              }"#;
+        assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
+
         // On aarch64, CPU template is also not patch compatible.
         let body = r#"{
                 "cpu_template": "T2"
@@ -0,0 +1,96 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use vmm::vmm_config::memory_hotplug::{MemoryHotplugConfig, MemoryHotplugSizeUpdate};
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+use crate::request::Body;
+
+pub(crate) fn parse_get_memory_hotplug() -> Result<ParsedRequest, Error> {
+    Ok(ParsedRequest::new_sync(VmmAction::GetMemoryHotplug))
+}
+
+pub(crate) fn parse_put_memory_hotplug(body: &Body) -> Result<ParsedRequest, Error> {
+    Ok(ParsedRequest::new_sync(VmmAction::SetMemoryHotplug(
+        serde_json::from_slice::<MemoryHotplugConfig>(body.raw())?,
+    )))
+}
+
+pub(crate) fn parse_patch_memory_hotplug(body: &Body) -> Result<ParsedRequest, Error> {
+    Ok(ParsedRequest::new_sync(VmmAction::UpdateMemoryHotplug(
+        serde_json::from_slice::<MemoryHotplugSizeUpdate>(body.raw())?,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_get_memory_hotplug_request() {
+        assert!(
+            vmm_action_from_request(parse_get_memory_hotplug().unwrap())
+                == VmmAction::GetMemoryHotplug
+        );
+    }
+
+    #[test]
+    fn test_parse_put_memory_hotplug_request() {
+        assert!(parse_put_memory_hotplug(&Body::new("invalid_payload")).is_err());
+
+        // PUT with invalid fields.
+        let body = r#"{
+                "total_size_mib": 1024,
+                "requested_size_mib": 128
+              }"#;
+        assert!(parse_put_memory_hotplug(&Body::new(body)).is_err());
+
+        // PUT with the default block size.
+        let body = r#"{
+                "total_size_mib": 1024
+              }"#;
+        assert!(
+            vmm_action_from_request(parse_put_memory_hotplug(&Body::new(body)).unwrap())
+                == VmmAction::SetMemoryHotplug(MemoryHotplugConfig {
+                    total_size_mib: 1024,
+                    block_size_mib: 2,
+                })
+        );
+
+        let body = r#"{
+                "total_size_mib": 1024,
+                "block_size_mib": 128
+              }"#;
+        assert!(
+            vmm_action_from_request(parse_put_memory_hotplug(&Body::new(body)).unwrap())
+                == VmmAction::SetMemoryHotplug(MemoryHotplugConfig {
+                    total_size_mib: 1024,
+                    block_size_mib: 128,
+                })
+        );
+    }
+
+    #[test]
+    fn test_parse_patch_memory_hotplug_request() {
+        assert!(parse_patch_memory_hotplug(&Body::new("invalid_payload")).is_err());
+
+        // PATCH with a field which can only be set before boot.
+        let body = r#"{
+                "requested_size_mib": 128,
+                "total_size_mib": 1024
+              }"#;
+        assert!(parse_patch_memory_hotplug(&Body::new(body)).is_err());
+
+        let body = r#"{
+                "requested_size_mib": 128
+              }"#;
+        assert!(
+            vmm_action_from_request(parse_patch_memory_hotplug(&Body::new(body)).unwrap())
+                == VmmAction::UpdateMemoryHotplug(MemoryHotplugSizeUpdate {
+                    requested_size_mib: 128,
+                })
+        );
+    }
+}
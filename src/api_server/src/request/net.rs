@@ -2,7 +2,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use logger::{IncMetric, METRICS};
-use vmm::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceUpdateConfig};
+use vmm::vmm_config::net::{
+    NetworkCaptureConfig, NetworkInterfaceConfig, NetworkInterfaceUpdateConfig, PacketCaptureAction,
+};
 
 use super::super::VmmAction;
 use crate::parsed_request::{checked_id, Error, ParsedRequest};
@@ -40,6 +42,53 @@ pub(crate) fn parse_put_net(
     )))
 }
 
+pub(crate) fn parse_put_net_capture(
+    body: &Body,
+    id_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.network_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.put_api_requests.network_fails.inc();
+        return Err(Error::EmptyID);
+    };
+
+    let capture = serde_json::from_slice::<NetworkCaptureConfig>(body.raw()).map_err(|err| {
+        METRICS.put_api_requests.network_fails.inc();
+        err
+    })?;
+    if id != capture.iface_id.as_str() {
+        METRICS.put_api_requests.network_fails.inc();
+        return Err(Error::Generic(
+            StatusCode::BadRequest,
+            format!(
+                "The id from the path [{}] does not match the id from the body [{}]!",
+                id,
+                capture.iface_id.as_str()
+            ),
+        ));
+    }
+    let valid = match capture.action {
+        PacketCaptureAction::Start => capture.path_on_host.is_some(),
+        PacketCaptureAction::Stop => {
+            capture.path_on_host.is_none() && capture.max_size_mib.is_none()
+        }
+    };
+    if !valid {
+        METRICS.put_api_requests.network_fails.inc();
+        return Err(Error::Generic(
+            StatusCode::BadRequest,
+            "Starting a packet capture requires `path_on_host`, stopping it takes no other \
+             parameters."
+                .to_string(),
+        ));
+    }
+    Ok(ParsedRequest::new_sync(VmmAction::UpdateNetworkCapture(
+        capture,
+    )))
+}
+
 pub(crate) fn parse_patch_net(
     body: &Body,
     id_from_path: Option<&&str>,
@@ -75,6 +124,8 @@ pub(crate) fn parse_patch_net(
 
 #[cfg(test)]
 mod tests {
+    use vmm::vmm_config::net::LinkState;
+
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
 
@@ -97,7 +148,67 @@ mod tests {
             _ => panic!("Test failed."),
         }
 
-        // 4. Serde error for invalid field (bytes instead of bandwidth).
+        // 4. Success case with multiple queue pairs.
+        let body = r#"{
+                "iface_id": "foo",
+                "host_dev_name": "bar",
+                "num_queue_pairs": 4
+              }"#;
+        match vmm_action_from_request(parse_put_net(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::InsertNetworkDevice(netif) => assert_eq!(netif.num_queue_pairs, 4),
+            _ => panic!("Test failed."),
+        }
+
+        // 5. Success case with some of the offloads disabled.
+        let body = r#"{
+                "iface_id": "foo",
+                "host_dev_name": "bar",
+                "offloads": {
+                    "tso6": false,
+                    "ecn": false
+                }
+              }"#;
+        match vmm_action_from_request(parse_put_net(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::InsertNetworkDevice(netif) => {
+                assert!(netif.offloads.checksum && netif.offloads.tso4);
+                assert!(!netif.offloads.tso6 && !netif.offloads.ecn);
+            }
+            _ => panic!("Test failed."),
+        }
+
+        // 6. Success case with vhost-net.
+        let body = r#"{
+                "iface_id": "foo",
+                "host_dev_name": "bar",
+                "vhost_net": true
+              }"#;
+        match vmm_action_from_request(parse_put_net(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::InsertNetworkDevice(netif) => assert!(netif.vhost_net),
+            _ => panic!("Test failed."),
+        }
+
+        // 7. Success case with an anti-spoofing policy.
+        let body = r#"{
+                "iface_id": "foo",
+                "host_dev_name": "bar",
+                "anti_spoofing": {
+                    "allowed_macs": ["12:34:56:78:9a:bc"],
+                    "ipv4_prefixes": [{"address": "192.168.0.0", "prefix_len": 16}],
+                    "ethertypes": [2048, 2054]
+                }
+              }"#;
+        match vmm_action_from_request(parse_put_net(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::InsertNetworkDevice(netif) => {
+                let policy = netif.anti_spoofing.unwrap();
+                assert_eq!(policy.allowed_macs.len(), 1);
+                assert_eq!(policy.ipv4_prefixes[0].prefix_len, 16);
+                assert!(policy.ipv6_prefixes.is_empty());
+                assert_eq!(policy.ethertypes, vec![0x0800, 0x0806]);
+            }
+            _ => panic!("Test failed."),
+        }
+
+        // 8. Serde error for invalid field (bytes instead of bandwidth).
         let body = r#"
         {
             "iface_id": "foo",
@@ -118,6 +229,68 @@ mod tests {
         assert!(parse_put_net(&Body::new(body), Some(&"foo")).is_err());
     }
 
+    #[test]
+    fn test_parse_put_net_capture_request() {
+        let body = r#"{
+                "iface_id": "foo",
+                "action": "Start",
+                "path_on_host": "capture.pcap",
+                "max_size_mib": 16
+              }"#;
+        // 1. Exercise infamous "The id from the path does not match id from the body!".
+        assert!(parse_put_net_capture(&Body::new(body), Some(&"bar")).is_err());
+        // 2. The `id_from_path` cannot be None.
+        assert!(parse_put_net_capture(&Body::new(body), None).is_err());
+
+        // 3. Success case.
+        let capture_clone = serde_json::from_str::<NetworkCaptureConfig>(body).unwrap();
+        match vmm_action_from_request(
+            parse_put_net_capture(&Body::new(body), Some(&"foo")).unwrap(),
+        ) {
+            VmmAction::UpdateNetworkCapture(capture) => {
+                assert_eq!(capture, capture_clone);
+                assert_eq!(capture.max_size(), 16 << 20);
+            }
+            _ => panic!("Test failed."),
+        }
+
+        // 4. Success case stopping the capture.
+        let body = r#"{
+                "iface_id": "foo",
+                "action": "Stop"
+              }"#;
+        match vmm_action_from_request(
+            parse_put_net_capture(&Body::new(body), Some(&"foo")).unwrap(),
+        ) {
+            VmmAction::UpdateNetworkCapture(capture) => {
+                assert_eq!(capture.action, PacketCaptureAction::Stop)
+            }
+            _ => panic!("Test failed."),
+        }
+
+        // 5. Starting a capture requires a path.
+        let body = r#"{
+                "iface_id": "foo",
+                "action": "Start"
+              }"#;
+        assert!(parse_put_net_capture(&Body::new(body), Some(&"foo")).is_err());
+
+        // 6. Stopping a capture takes no parameters.
+        let body = r#"{
+                "iface_id": "foo",
+                "action": "Stop",
+                "max_size_mib": 16
+              }"#;
+        assert!(parse_put_net_capture(&Body::new(body), Some(&"foo")).is_err());
+
+        // 7. Serde error for invalid action.
+        let body = r#"{
+                "iface_id": "foo",
+                "action": "Pause"
+              }"#;
+        assert!(parse_put_net_capture(&Body::new(body), Some(&"foo")).is_err());
+    }
+
     #[test]
     fn test_parse_patch_net_request() {
         let body = r#"{
@@ -139,7 +312,27 @@ mod tests {
             _ => panic!("Test failed."),
         }
 
-        // 4. Serde error for invalid field (bytes instead of bandwidth).
+        // 4. Success case bringing the link down.
+        let body = r#"{
+                "iface_id": "foo",
+                "link_state": "Down"
+        }"#;
+        match vmm_action_from_request(parse_patch_net(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::UpdateNetworkInterface(netif) => {
+                assert_eq!(netif.link_state, Some(LinkState::Down));
+                assert!(netif.rx_rate_limiter.is_none());
+            }
+            _ => panic!("Test failed."),
+        }
+
+        // 5. Serde error for invalid link state.
+        let body = r#"{
+                "iface_id": "foo",
+                "link_state": "Unknown"
+        }"#;
+        assert!(parse_patch_net(&Body::new(body), Some(&"foo")).is_err());
+
+        // 6. Serde error for invalid field (bytes instead of bandwidth).
         let body = r#"
         {
             "iface_id": "foo",
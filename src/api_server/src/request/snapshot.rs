@@ -94,6 +94,8 @@ fn parse_put_snapshot_load(body: &Body) -> Result<ParsedRequest, Error> {
         mem_backend,
         enable_diff_snapshots: snapshot_config.enable_diff_snapshots,
         resume_vm: snapshot_config.resume_vm,
+        vsock_override: snapshot_config.vsock_override,
+        shared_memory: snapshot_config.shared_memory,
     };
 
     // Construct the `ParsedRequest` object.
@@ -109,7 +111,8 @@ fn parse_put_snapshot_load(body: &Body) -> Result<ParsedRequest, Error> {
 
 #[cfg(test)]
 mod tests {
-    use vmm::vmm_config::snapshot::{MemBackendConfig, MemBackendType};
+    use vmm::vmm_config::machine_config::SharedMemoryConfig;
+    use vmm::vmm_config::snapshot::{MemBackendConfig, MemBackendType, VsockOverride};
 
     use super::*;
     use crate::parsed_request::tests::{depr_action_from_req, vmm_action_from_request};
@@ -124,7 +127,8 @@ mod tests {
                 "snapshot_type": "Diff",
                 "snapshot_path": "foo",
                 "mem_file_path": "bar",
-                "version": "0.23.0"
+                "version": "0.23.0",
+                "free_page_hinting": true
               }"#;
 
         let mut expected_cfg = CreateSnapshotParams {
@@ -132,6 +136,7 @@ mod tests {
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
             version: Some(String::from("0.23.0")),
+            free_page_hinting: true,
         };
 
         match vmm_action_from_request(
@@ -151,6 +156,7 @@ mod tests {
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
             version: None,
+            free_page_hinting: false,
         };
 
         match vmm_action_from_request(
@@ -183,6 +189,8 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: false,
+            vsock_override: None,
+            shared_memory: None,
         };
 
         let mut parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
@@ -213,6 +221,8 @@ mod tests {
             },
             enable_diff_snapshots: true,
             resume_vm: false,
+            vsock_override: None,
+            shared_memory: None,
         };
 
         let mut parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
@@ -242,6 +252,8 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: true,
+            vsock_override: None,
+            shared_memory: None,
         };
 
         let mut parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
@@ -254,6 +266,44 @@ mod tests {
             _ => panic!("Test failed."),
         }
 
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_backend": {
+                    "backend_path": "bar",
+                    "backend_type": "File"
+                },
+                "vsock_override": {
+                    "uds_path": "clone.vsock"
+                },
+                "shared_memory": {
+                    "mem_file_path": "/dev/shm/clone_mem",
+                    "uds_path": "clone_mem.sock"
+                }
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_backend: MemBackendConfig {
+                backend_path: PathBuf::from("bar"),
+                backend_type: MemBackendType::File,
+            },
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            vsock_override: Some(VsockOverride {
+                uds_path: "clone.vsock".to_string(),
+            }),
+            shared_memory: Some(SharedMemoryConfig {
+                mem_file_path: Some(PathBuf::from("/dev/shm/clone_mem")),
+                uds_path: Some(PathBuf::from("clone_mem.sock")),
+            }),
+        };
+
+        let parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
+        match vmm_action_from_request(parsed_request) {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
         body = r#"{
                 "snapshot_path": "foo",
                 "mem_file_path": "bar",
@@ -268,6 +318,8 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: true,
+            vsock_override: None,
+            shared_memory: None,
         };
 
         let parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
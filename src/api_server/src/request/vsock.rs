@@ -2,11 +2,11 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use logger::{IncMetric, METRICS};
-use vmm::vmm_config::vsock::VsockDeviceConfig;
+use vmm::vmm_config::vsock::{VsockConnectionKey, VsockDeviceConfig, VsockDeviceUpdateConfig};
 
 use super::super::VmmAction;
 use crate::parsed_request::{Error, ParsedRequest};
-use crate::request::Body;
+use crate::request::{Body, StatusCode};
 
 pub(crate) fn parse_put_vsock(body: &Body) -> Result<ParsedRequest, Error> {
     METRICS.put_api_requests.vsock_count.inc();
@@ -33,10 +33,41 @@ pub(crate) fn parse_put_vsock(body: &Body) -> Result<ParsedRequest, Error> {
     Ok(parsed_req)
 }
 
+pub(crate) fn parse_patch_vsock(body: &Body) -> Result<ParsedRequest, Error> {
+    METRICS.patch_api_requests.vsock_count.inc();
+    let vsock_update_cfg =
+        serde_json::from_slice::<VsockDeviceUpdateConfig>(body.raw()).map_err(|err| {
+            METRICS.patch_api_requests.vsock_fails.inc();
+            err
+        })?;
+
+    if vsock_update_cfg.rate_limiter.is_none() {
+        METRICS.patch_api_requests.vsock_fails.inc();
+        return Err(Error::Generic(
+            StatusCode::BadRequest,
+            String::from("Please specify at least one property to patch: rate_limiter."),
+        ));
+    }
+
+    Ok(ParsedRequest::new_sync(VmmAction::UpdateVsockDevice(
+        vsock_update_cfg,
+    )))
+}
+
+pub(crate) fn parse_get_vsock_connections() -> Result<ParsedRequest, Error> {
+    Ok(ParsedRequest::new_sync(VmmAction::GetVsockConnections))
+}
+
+pub(crate) fn parse_put_vsock_connection_reset(body: &Body) -> Result<ParsedRequest, Error> {
+    Ok(ParsedRequest::new_sync(VmmAction::ResetVsockConnection(
+        serde_json::from_slice::<VsockConnectionKey>(body.raw())?,
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::parsed_request::tests::depr_action_from_req;
+    use crate::parsed_request::tests::{depr_action_from_req, vmm_action_from_request};
 
     #[test]
     fn test_parse_put_vsock_request() {
@@ -46,6 +77,30 @@ mod tests {
               }"#;
         assert!(parse_put_vsock(&Body::new(body)).is_ok());
 
+        let body = r#"{
+                "guest_cid": 42,
+                "tcp_ports": [
+                    {
+                        "vsock_port": 1024,
+                        "host_addr": "127.0.0.1:8080",
+                        "direction": "GuestToHost"
+                    }
+                ]
+              }"#;
+        assert!(parse_put_vsock(&Body::new(body)).is_ok());
+
+        let body = r#"{
+                "guest_cid": 42,
+                "tcp_ports": [
+                    {
+                        "vsock_port": 1024,
+                        "host_addr": "not an address",
+                        "direction": "GuestToHost"
+                    }
+                ]
+              }"#;
+        assert!(parse_put_vsock(&Body::new(body)).is_err());
+
         let body = r#"{
                 "guest_cid": 42,
                 "invalid_field": false
@@ -53,6 +108,64 @@ mod tests {
         assert!(parse_put_vsock(&Body::new(body)).is_err());
     }
 
+    #[test]
+    fn test_parse_patch_vsock_request() {
+        let body = r#"{
+                "rate_limiter": {
+                    "bandwidth": {
+                        "size": 1000,
+                        "refill_time": 100
+                    }
+                }
+              }"#;
+        assert!(parse_patch_vsock(&Body::new(body)).is_ok());
+
+        // PATCH with nothing to update.
+        let body = r#"{}"#;
+        assert!(parse_patch_vsock(&Body::new(body)).is_err());
+
+        // PATCH that tries to update something else other than the rate limiter.
+        let body = r#"{
+                "guest_cid": 42
+              }"#;
+        assert!(parse_patch_vsock(&Body::new(body)).is_err());
+    }
+
+    #[test]
+    fn test_parse_get_vsock_connections_request() {
+        assert!(
+            vmm_action_from_request(parse_get_vsock_connections().unwrap())
+                == VmmAction::GetVsockConnections
+        );
+    }
+
+    #[test]
+    fn test_parse_put_vsock_connection_reset_request() {
+        let body = r#"{
+                "local_port": 1024,
+                "peer_port": 52
+              }"#;
+        assert!(
+            vmm_action_from_request(parse_put_vsock_connection_reset(&Body::new(body)).unwrap())
+                == VmmAction::ResetVsockConnection(VsockConnectionKey {
+                    local_port: 1024,
+                    peer_port: 52,
+                })
+        );
+
+        let body = r#"{
+                "local_port": 1024
+              }"#;
+        assert!(parse_put_vsock_connection_reset(&Body::new(body)).is_err());
+
+        let body = r#"{
+                "local_port": 1024,
+                "peer_port": 52,
+                "invalid_field": false
+              }"#;
+        assert!(parse_put_vsock_connection_reset(&Body::new(body)).is_err());
+    }
+
     #[test]
     fn test_depr_vsock_id() {
         let body = r#"{
@@ -386,6 +386,24 @@ fn create_rtc_node<T: DeviceInfoForFDT + Clone + Debug>(
     Ok(())
 }
 
+fn create_hotplug_events_node<T: DeviceInfoForFDT + Clone + Debug>(
+    fdt: &mut FdtWriter,
+    dev_info: &T,
+) -> Result<()> {
+    let hotplug_events = fdt.begin_node(&format!("hotplug_events@{:x}", dev_info.addr()))?;
+
+    fdt.property_string("compatible", "firecracker,hotplug-events")?;
+    fdt.property_array_u64("reg", &[dev_info.addr(), dev_info.length()])?;
+    fdt.property_array_u32(
+        "interrupts",
+        &[GIC_FDT_IRQ_TYPE_SPI, dev_info.irq(), IRQ_TYPE_EDGE_RISING],
+    )?;
+    fdt.property_u32("interrupt-parent", GIC_PHANDLE)?;
+    fdt.end_node(hotplug_events)?;
+
+    Ok(())
+}
+
 fn create_devices_node<T: DeviceInfoForFDT + Clone + Debug, S: std::hash::BuildHasher>(
     fdt: &mut FdtWriter,
     dev_info: &HashMap<(DeviceType, String), T, S>,
@@ -396,6 +414,7 @@ fn create_devices_node<T: DeviceInfoForFDT + Clone + Debug, S: std::hash::BuildH
     for ((device_type, _device_id), info) in dev_info {
         match device_type {
             DeviceType::BootTimer => (), // since it's not a real device
+            DeviceType::HotplugEvents => create_hotplug_events_node(fdt, info)?,
             DeviceType::Rtc => create_rtc_node(fdt, info)?,
             DeviceType::Serial => create_serial_node(fdt, info)?,
             DeviceType::Virtio(_) => {
@@ -471,6 +490,16 @@ mod tests {
                     irq: 3,
                 },
             ),
+            (
+                (
+                    DeviceType::HotplugEvents,
+                    DeviceType::HotplugEvents.to_string(),
+                ),
+                MMIODeviceInfo {
+                    addr: 3 * LEN,
+                    irq: 4,
+                },
+            ),
         ]
         .iter()
         .cloned()
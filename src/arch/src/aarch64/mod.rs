@@ -41,6 +41,19 @@ pub fn arch_memory_regions(size: usize) -> Vec<(GuestAddress, usize)> {
     vec![(GuestAddress(layout::DRAM_MEM_START), dram_size)]
 }
 
+/// Returns the guest-physical region of `hotplug_size` bytes reserved for hot-plugging memory,
+/// which follows the DRAM of `size` bytes, or `None` if it doesn't fit.
+pub fn memory_hotplug_region(size: usize, hotplug_size: usize) -> Option<(GuestAddress, usize)> {
+    let (dram_start, dram_size) = arch_memory_regions(size)[0];
+    let align = layout::MEMORY_HOTPLUG_ALIGNMENT;
+    let start = (dram_start.raw_value() + dram_size as u64 + align - 1) & !(align - 1);
+    let end = start.checked_add(hotplug_size as u64)?;
+    if end > layout::DRAM_MEM_START + layout::DRAM_MEM_MAX_SIZE {
+        return None;
+    }
+    Some((GuestAddress(start), hotplug_size))
+}
+
 /// Configures the system and should be called once per vm before starting vcpu threads.
 /// For aarch64, we only setup the FDT.
 ///
@@ -126,6 +139,22 @@ mod tests {
         assert_eq!(super::layout::DRAM_MEM_MAX_SIZE, regions[0].1 as u64);
     }
 
+    #[test]
+    fn test_memory_hotplug_region() {
+        assert_eq!(
+            memory_hotplug_region(1usize << 29, 1usize << 30),
+            Some((
+                GuestAddress(layout::DRAM_MEM_START + (1u64 << 30)),
+                1usize << 30
+            ))
+        );
+        // The region has to fit in the DRAM.
+        assert_eq!(
+            memory_hotplug_region(layout::DRAM_MEM_MAX_SIZE as usize, 1usize << 30),
+            None
+        );
+    }
+
     #[test]
     fn test_get_fdt_addr() {
         let regions = arch_memory_regions(layout::FDT_MAX_SIZE - 0x1000);
@@ -16,8 +16,8 @@ pub mod aarch64;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::{
     arch_memory_regions, configure_system, get_kernel_start, initrd_load_addr,
-    layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, layout::IRQ_MAX, regs, Error, MMIO_MEM_SIZE,
-    MMIO_MEM_START,
+    layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, layout::IRQ_MAX, memory_hotplug_region, regs,
+    Error, MMIO_MEM_SIZE, MMIO_MEM_START,
 };
 
 /// Module for x86_64 related functionality.
@@ -27,8 +27,8 @@ pub mod x86_64;
 #[cfg(target_arch = "x86_64")]
 pub use crate::x86_64::{
     arch_memory_regions, configure_system, get_kernel_start, initrd_load_addr,
-    layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, layout::IRQ_MAX, Error, MMIO_MEM_SIZE,
-    MMIO_MEM_START,
+    layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, layout::IRQ_MAX, memory_hotplug_region, Error,
+    MMIO_MEM_SIZE, MMIO_MEM_START,
 };
 
 /// Type for returning public functions outcome.
@@ -47,6 +47,8 @@ pub enum DeviceType {
     Rtc,
     /// Device Type: BootTimer.
     BootTimer,
+    /// Device Type: HotplugEvents.
+    HotplugEvents,
 }
 
 /// Type for passing information about the initrd in the guest memory.
@@ -24,6 +24,9 @@ pub const IRQ_BASE: u32 = 5;
 /// Last usable IRQ ID for virtio device interrupts on x86_64.
 pub const IRQ_MAX: u32 = 23;
 
+/// Alignment of the start of the region reserved for hot-plugging memory.
+pub const MEMORY_HOTPLUG_ALIGNMENT: u64 = 1 << 30; // 1 GB.
+
 /// Address for the TSS setup.
 pub const KVM_TSS_ADDRESS: u64 = 0xfffb_d000;
 
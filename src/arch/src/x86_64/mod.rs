@@ -67,6 +67,21 @@ pub fn arch_memory_regions(size: usize) -> Vec<(GuestAddress, usize)> {
     }
 }
 
+/// Returns the guest-physical region of `hotplug_size` bytes reserved for hot-plugging memory,
+/// which follows the memory regions of `size` bytes, or `None` if it doesn't fit.
+/// The region is never below 4 GiB, so that it doesn't overlap the MMIO gap.
+pub fn memory_hotplug_region(size: usize, hotplug_size: usize) -> Option<(GuestAddress, usize)> {
+    let (last_addr, last_size) = *arch_memory_regions(size).last()?;
+    let mem_end = std::cmp::max(
+        last_addr.checked_add(last_size as u64)?.raw_value(),
+        FIRST_ADDR_PAST_32BITS,
+    );
+    let align = layout::MEMORY_HOTPLUG_ALIGNMENT;
+    let start = mem_end.checked_add(align - 1)? & !(align - 1);
+    start.checked_add(hotplug_size as u64)?;
+    Some((GuestAddress(start), hotplug_size))
+}
+
 /// Returns the memory address where the kernel could be loaded.
 pub fn get_kernel_start() -> u64 {
     layout::HIMEM_START
@@ -212,6 +227,21 @@ mod tests {
         assert_eq!(GuestAddress(1u64 << 32), regions[1].0);
     }
 
+    #[test]
+    fn test_memory_hotplug_region() {
+        // The region starts past the MMIO gap.
+        assert_eq!(
+            memory_hotplug_region(1usize << 29, 1usize << 30),
+            Some((GuestAddress(1u64 << 32), 1usize << 30))
+        );
+        // The region is aligned after the memory above 4 GiB.
+        assert_eq!(
+            memory_hotplug_region((1usize << 32) + 0x8000, 1usize << 30),
+            Some((GuestAddress((1u64 << 32) + (1u64 << 30)), 1usize << 30))
+        );
+        assert_eq!(memory_hotplug_region(1usize << 29, usize::MAX), None);
+    }
+
     #[test]
     fn test_system_configuration() {
         let no_vcpus = 4;
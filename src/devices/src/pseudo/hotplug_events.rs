@@ -0,0 +1,248 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pseudo device notifying the guest about the MMIO slots reserved for hot-plugging, in the
+//! spirit of the ACPI Generic Event Device.
+//!
+//! Whenever a device is plugged in a slot, or the guest is asked to release the device
+//! plugged in a slot, the slot is flagged as having a pending event and the device interrupt
+//! is raised. The guest then reads the state of the slots and acknowledges the events.
+//!
+//! All the registers are 32 bits wide. Bit `n` of the bitmaps stands for the slot `n`, the
+//! slots being numbered in the order of their MMIO addresses:
+//!
+//! | Offset | Access | Content                                                          |
+//! |--------|--------|------------------------------------------------------------------|
+//! | 0x00   | R      | Number of slots.                                                 |
+//! | 0x04   | R      | Bitmap of the slots a device is plugged in.                      |
+//! | 0x08   | R      | Bitmap of the slots whose device the guest is asked to release.  |
+//! | 0x0c   | R      | Bitmap of the slots with pending events.                         |
+//! | 0x10   | W      | Acknowledges the events of the slots in the written bitmap.      |
+//! | 0x14   | W      | Selects the slot described by the address registers.             |
+//! | 0x18   | R      | Low 32 bits of the MMIO address of the selected slot.            |
+//! | 0x1c   | R      | High 32 bits of the MMIO address of the selected slot.           |
+
+use logger::{error, warn};
+use snapshot::Persist;
+use utils::byte_order;
+use utils::eventfd::EventFd;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+use crate::bus::BusDevice;
+
+/// Maximum number of slots whose events can be reported.
+pub const MAX_HOTPLUG_SLOTS: usize = 32;
+
+const SLOT_COUNT_OFFSET: u64 = 0x00;
+const PRESENT_OFFSET: u64 = 0x04;
+const EJECT_OFFSET: u64 = 0x08;
+const PENDING_OFFSET: u64 = 0x0c;
+const ACK_OFFSET: u64 = 0x10;
+const SELECT_OFFSET: u64 = 0x14;
+const ADDR_LOW_OFFSET: u64 = 0x18;
+const ADDR_HIGH_OFFSET: u64 = 0x1c;
+
+/// Pseudo device reporting hot-plug events to the guest.
+pub struct HotplugEvents {
+    // MMIO addresses of the slots.
+    slot_addrs: Vec<u64>,
+    present: u32,
+    eject: u32,
+    pending: u32,
+    selected: u32,
+    irq_evt: EventFd,
+}
+
+impl HotplugEvents {
+    /// Creates the event device of the slots at the given MMIO addresses.
+    pub fn new(slot_addrs: Vec<u64>) -> std::io::Result<HotplugEvents> {
+        if slot_addrs.len() > MAX_HOTPLUG_SLOTS {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        Ok(HotplugEvents {
+            slot_addrs,
+            present: 0,
+            eject: 0,
+            pending: 0,
+            selected: 0,
+            irq_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+        })
+    }
+
+    /// Provides the event signaling the device interrupt.
+    pub fn irq_evt(&self) -> &EventFd {
+        &self.irq_evt
+    }
+
+    /// Reports that a device was plugged in, or unplugged from, the given slot.
+    pub fn set_present(&mut self, slot: usize, present: bool) {
+        if present {
+            self.present |= 1 << slot;
+        } else {
+            self.present &= !(1 << slot);
+        }
+        // Whatever the guest was asked to release is gone, or was replaced.
+        self.eject &= !(1 << slot);
+        self.notify(slot);
+    }
+
+    /// Asks the guest to release the device plugged in the given slot.
+    pub fn request_eject(&mut self, slot: usize) {
+        self.eject |= 1 << slot;
+        self.notify(slot);
+    }
+
+    fn notify(&mut self, slot: usize) {
+        self.pending |= 1 << slot;
+        if let Err(err) = self.irq_evt.write(1) {
+            error!("Failed to signal hot-plug event: {:?}", err);
+        }
+    }
+}
+
+impl BusDevice for HotplugEvents {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if data.len() != 4 {
+            warn!("Invalid hot-plug events read: offset 0x{:x}", offset);
+            return;
+        }
+
+        let selected_addr = self
+            .slot_addrs
+            .get(self.selected as usize)
+            .copied()
+            .unwrap_or(0);
+        let v = match offset {
+            SLOT_COUNT_OFFSET => self.slot_addrs.len() as u32,
+            PRESENT_OFFSET => self.present,
+            EJECT_OFFSET => self.eject,
+            PENDING_OFFSET => self.pending,
+            ADDR_LOW_OFFSET => selected_addr as u32,
+            ADDR_HIGH_OFFSET => (selected_addr >> 32) as u32,
+            _ => {
+                warn!("Invalid hot-plug events read: offset 0x{:x}", offset);
+                return;
+            }
+        };
+        byte_order::write_le_u32(data, v);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.len() != 4 {
+            warn!("Invalid hot-plug events write: offset 0x{:x}", offset);
+            return;
+        }
+
+        let v = byte_order::read_le_u32(data);
+        match offset {
+            ACK_OFFSET => self.pending &= !v,
+            SELECT_OFFSET => self.selected = v,
+            _ => warn!("Invalid hot-plug events write: offset 0x{:x}", offset),
+        }
+    }
+}
+
+/// Holds the state of the hot-plug event device.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct HotplugEventsState {
+    slot_addrs: Vec<u64>,
+    present: u32,
+    eject: u32,
+    pending: u32,
+    selected: u32,
+}
+
+impl Persist<'_> for HotplugEvents {
+    type State = HotplugEventsState;
+    type ConstructorArgs = ();
+    type Error = std::io::Error;
+
+    fn save(&self) -> Self::State {
+        HotplugEventsState {
+            slot_addrs: self.slot_addrs.clone(),
+            present: self.present,
+            eject: self.eject,
+            pending: self.pending,
+            selected: self.selected,
+        }
+    }
+
+    fn restore(_: Self::ConstructorArgs, state: &Self::State) -> std::io::Result<Self> {
+        let mut events = HotplugEvents::new(state.slot_addrs.clone())?;
+        events.present = state.present;
+        events.eject = state.eject;
+        events.pending = state.pending;
+        events.selected = state.selected;
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_reg(events: &mut HotplugEvents, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        events.read(offset, &mut data);
+        byte_order::read_le_u32(&data)
+    }
+
+    fn write_reg(events: &mut HotplugEvents, offset: u64, v: u32) {
+        let mut data = [0u8; 4];
+        byte_order::write_le_u32(&mut data, v);
+        events.write(offset, &data);
+    }
+
+    #[test]
+    fn test_hotplug_events() {
+        assert!(HotplugEvents::new(vec![0; MAX_HOTPLUG_SLOTS + 1]).is_err());
+
+        let mut events = HotplugEvents::new(vec![0xd000_0000, 0x1_d000_1000]).unwrap();
+        assert_eq!(read_reg(&mut events, SLOT_COUNT_OFFSET), 2);
+        assert_eq!(read_reg(&mut events, PENDING_OFFSET), 0);
+        assert!(events.irq_evt().read().is_err());
+
+        // Plugging a device raises the interrupt.
+        events.set_present(1, true);
+        assert_eq!(events.irq_evt().read().unwrap(), 1);
+        assert_eq!(read_reg(&mut events, PRESENT_OFFSET), 0b10);
+        assert_eq!(read_reg(&mut events, PENDING_OFFSET), 0b10);
+
+        write_reg(&mut events, SELECT_OFFSET, 1);
+        assert_eq!(read_reg(&mut events, ADDR_LOW_OFFSET), 0xd000_1000);
+        assert_eq!(read_reg(&mut events, ADDR_HIGH_OFFSET), 0x1);
+        write_reg(&mut events, SELECT_OFFSET, 2);
+        assert_eq!(read_reg(&mut events, ADDR_LOW_OFFSET), 0);
+
+        write_reg(&mut events, ACK_OFFSET, 0b10);
+        assert_eq!(read_reg(&mut events, PENDING_OFFSET), 0);
+
+        // So does asking the guest to release it.
+        events.request_eject(1);
+        assert_eq!(events.irq_evt().read().unwrap(), 1);
+        assert_eq!(read_reg(&mut events, EJECT_OFFSET), 0b10);
+        assert_eq!(read_reg(&mut events, PENDING_OFFSET), 0b10);
+
+        // Unplugging it clears the request.
+        events.set_present(1, false);
+        assert_eq!(read_reg(&mut events, PRESENT_OFFSET), 0);
+        assert_eq!(read_reg(&mut events, EJECT_OFFSET), 0);
+
+        // Invalid accesses are ignored.
+        let mut data = [0xffu8; 2];
+        events.read(PRESENT_OFFSET, &mut data);
+        assert_eq!(data, [0xff; 2]);
+        write_reg(&mut events, PRESENT_OFFSET, 0xff);
+        assert_eq!(read_reg(&mut events, PRESENT_OFFSET), 0);
+
+        let restored = HotplugEvents::restore((), &events.save()).unwrap();
+        assert_eq!(restored.slot_addrs, events.slot_addrs);
+        assert_eq!(restored.present, events.present);
+        assert_eq!(restored.eject, events.eject);
+        assert_eq!(restored.pending, events.pending);
+        assert_eq!(restored.selected, events.selected);
+    }
+}
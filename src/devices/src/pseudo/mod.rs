@@ -2,5 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 mod boot_timer;
+mod hotplug_events;
 
 pub use self::boot_timer::BootTimer;
+pub use self::hotplug_events::{HotplugEvents, HotplugEventsState, MAX_HOTPLUG_SLOTS};
@@ -0,0 +1,171 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sizes the balloon automatically, from the memory statistics reported by the guest.
+
+use serde::{Deserialize, Serialize};
+
+use super::device::BalloonStats;
+use super::{Error, Result};
+
+/// The policy resizing the balloon each time the statistics are polled.
+///
+/// The balloon is inflated by `step_mib` when the guest has at least `step_mib` more free
+/// memory than `target_free_mib`, and deflated by `step_mib` when it has less than
+/// `target_free_mib`. Its size is kept between `min_mib` and `max_mib`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonAutoSize {
+    /// The smallest size of the balloon, in MiB.
+    pub min_mib: u32,
+    /// The largest size of the balloon, in MiB.
+    pub max_mib: u32,
+    /// The amount of memory the guest should keep free, in MiB.
+    pub target_free_mib: u32,
+    /// The amount by which the balloon is inflated or deflated at once, in MiB.
+    pub step_mib: u32,
+}
+
+/// A change of the balloon size decided by the policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutoSizeDecision {
+    /// Inflate the balloon to the given size, in MiB.
+    Inflate(u32),
+    /// Deflate the balloon to the given size, in MiB.
+    Deflate(u32),
+}
+
+impl BalloonAutoSize {
+    /// Checks that the sizes of the policy are consistent.
+    pub fn validate(&self) -> Result<()> {
+        if self.min_mib > self.max_mib || self.step_mib == 0 {
+            return Err(Error::InvalidAutoSize(*self));
+        }
+
+        Ok(())
+    }
+
+    /// Decides the next size of the balloon, which is `target_mib` large at the moment, from
+    /// the latest statistics of the guest. `inflating` tells whether the guest hasn't given
+    /// the balloon all the memory it asked for yet, in which case it isn't inflated further.
+    pub fn decide(
+        &self,
+        target_mib: u32,
+        inflating: bool,
+        stats: &BalloonStats,
+    ) -> Option<AutoSizeDecision> {
+        // The available memory accounts for the caches the guest can drop, so it is
+        // preferred over the free memory.
+        let free_mib = stats.available_memory.or(stats.free_memory)? >> 20;
+
+        if free_mib < u64::from(self.target_free_mib) {
+            let size_mib = target_mib
+                .saturating_sub(self.step_mib)
+                .clamp(self.min_mib, self.max_mib);
+            if size_mib < target_mib {
+                return Some(AutoSizeDecision::Deflate(size_mib));
+            }
+        } else if !inflating
+            && free_mib >= u64::from(self.target_free_mib) + u64::from(self.step_mib)
+        {
+            let size_mib = target_mib
+                .saturating_add(self.step_mib)
+                .clamp(self.min_mib, self.max_mib);
+            if size_mib > target_mib {
+                return Some(AutoSizeDecision::Inflate(size_mib));
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_with_free_mib(free_mib: u64) -> BalloonStats {
+        BalloonStats {
+            free_memory: Some(free_mib << 20),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut auto_size = BalloonAutoSize {
+            min_mib: 0,
+            max_mib: 512,
+            target_free_mib: 64,
+            step_mib: 32,
+        };
+        assert!(auto_size.validate().is_ok());
+
+        auto_size.min_mib = 1024;
+        assert!(matches!(
+            auto_size.validate(),
+            Err(Error::InvalidAutoSize(_))
+        ));
+
+        auto_size.min_mib = 0;
+        auto_size.step_mib = 0;
+        assert!(matches!(
+            auto_size.validate(),
+            Err(Error::InvalidAutoSize(_))
+        ));
+    }
+
+    #[test]
+    fn test_decide() {
+        let auto_size = BalloonAutoSize {
+            min_mib: 16,
+            max_mib: 128,
+            target_free_mib: 64,
+            step_mib: 32,
+        };
+
+        // No decision without the memory statistics.
+        assert_eq!(auto_size.decide(64, false, &BalloonStats::default()), None);
+
+        // Enough free memory to inflate by a step.
+        assert_eq!(
+            auto_size.decide(64, false, &stats_with_free_mib(96)),
+            Some(AutoSizeDecision::Inflate(96))
+        );
+        // Not while the guest is still inflating the balloon.
+        assert_eq!(auto_size.decide(64, true, &stats_with_free_mib(96)), None);
+        // Not above the largest size.
+        assert_eq!(
+            auto_size.decide(112, false, &stats_with_free_mib(96)),
+            Some(AutoSizeDecision::Inflate(128))
+        );
+        assert_eq!(auto_size.decide(128, false, &stats_with_free_mib(96)), None);
+
+        // Between the watermark and a step above it, the size is kept.
+        assert_eq!(auto_size.decide(64, false, &stats_with_free_mib(64)), None);
+        assert_eq!(auto_size.decide(64, false, &stats_with_free_mib(95)), None);
+
+        // Below the watermark, the balloon is deflated, even while it is inflating.
+        assert_eq!(
+            auto_size.decide(64, true, &stats_with_free_mib(63)),
+            Some(AutoSizeDecision::Deflate(32))
+        );
+        // Not below the smallest size.
+        assert_eq!(
+            auto_size.decide(32, false, &stats_with_free_mib(0)),
+            Some(AutoSizeDecision::Deflate(16))
+        );
+        assert_eq!(auto_size.decide(16, false, &stats_with_free_mib(0)), None);
+
+        // The available memory is preferred over the free memory.
+        let stats = BalloonStats {
+            free_memory: Some(0),
+            available_memory: Some(96 << 20),
+            ..Default::default()
+        };
+        assert_eq!(
+            auto_size.decide(64, false, &stats),
+            Some(AutoSizeDecision::Inflate(96))
+        );
+    }
+}
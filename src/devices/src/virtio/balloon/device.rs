@@ -8,7 +8,7 @@ use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use std::time::Duration;
 
-use logger::{error, IncMetric, METRICS};
+use logger::{error, IncMetric, StoreMetric, METRICS};
 use serde::Serialize;
 use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
 use utils::eventfd::EventFd;
@@ -16,14 +16,18 @@ use virtio_gen::virtio_blk::VIRTIO_F_VERSION_1;
 use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
 
 use super::super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BALLOON};
+use super::auto_size::{AutoSizeDecision, BalloonAutoSize};
 use super::utils::{compact_page_frame_numbers, remove_range};
 use super::{
-    BALLOON_DEV_ID, DEFLATE_INDEX, INFLATE_INDEX, MAX_PAGES_IN_DESC, MAX_PAGE_COMPACT_BUFFER,
-    MIB_TO_4K_PAGES, NUM_QUEUES, QUEUE_SIZES, STATS_INDEX, VIRTIO_BALLOON_F_DEFLATE_ON_OOM,
-    VIRTIO_BALLOON_F_STATS_VQ, VIRTIO_BALLOON_PFN_SHIFT, VIRTIO_BALLOON_S_AVAIL,
-    VIRTIO_BALLOON_S_CACHES, VIRTIO_BALLOON_S_HTLB_PGALLOC, VIRTIO_BALLOON_S_HTLB_PGFAIL,
-    VIRTIO_BALLOON_S_MAJFLT, VIRTIO_BALLOON_S_MEMFREE, VIRTIO_BALLOON_S_MEMTOT,
-    VIRTIO_BALLOON_S_MINFLT, VIRTIO_BALLOON_S_SWAP_IN, VIRTIO_BALLOON_S_SWAP_OUT,
+    BALLOON_DEV_ID, DEFLATE_INDEX, FREE_PAGE_HINT_CMD_ID_DONE, FREE_PAGE_HINT_CMD_ID_MIN,
+    FREE_PAGE_HINT_CMD_ID_STOP, FREE_PAGE_HINT_INDEX, INFLATE_INDEX, MAX_PAGES_IN_DESC,
+    MAX_PAGE_COMPACT_BUFFER, MIB_TO_4K_PAGES, NUM_QUEUES, QUEUE_SIZES, REPORTING_INDEX,
+    STATS_INDEX, VIRTIO_BALLOON_F_DEFLATE_ON_OOM, VIRTIO_BALLOON_F_FREE_PAGE_HINT,
+    VIRTIO_BALLOON_F_FREE_PAGE_REPORTING, VIRTIO_BALLOON_F_STATS_VQ, VIRTIO_BALLOON_PFN_SHIFT,
+    VIRTIO_BALLOON_S_AVAIL, VIRTIO_BALLOON_S_CACHES, VIRTIO_BALLOON_S_HTLB_PGALLOC,
+    VIRTIO_BALLOON_S_HTLB_PGFAIL, VIRTIO_BALLOON_S_MAJFLT, VIRTIO_BALLOON_S_MEMFREE,
+    VIRTIO_BALLOON_S_MEMTOT, VIRTIO_BALLOON_S_MINFLT, VIRTIO_BALLOON_S_SWAP_IN,
+    VIRTIO_BALLOON_S_SWAP_OUT,
 };
 use crate::virtio::balloon::Error as BalloonError;
 use crate::virtio::{IrqTrigger, IrqType};
@@ -46,6 +50,7 @@ fn pages_to_mib(amount_pages: u32) -> u32 {
 pub(crate) struct ConfigSpace {
     pub num_pages: u32,
     pub actual_pages: u32,
+    pub free_page_hint_cmd_id: u32,
 }
 
 // Safe because ConfigSpace only contains plain data.
@@ -69,6 +74,9 @@ pub struct BalloonConfig {
     pub amount_mib: u32,
     pub deflate_on_oom: bool,
     pub stats_polling_interval_s: u16,
+    pub free_page_hinting: bool,
+    pub free_page_reporting: bool,
+    pub auto_size: Option<BalloonAutoSize>,
 }
 
 // BalloonStats holds statistics returned from the stats_queue.
@@ -124,6 +132,19 @@ impl BalloonStats {
     }
 }
 
+// The progress of a free page hinting run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FreePageHintState {
+    // No run is in progress.
+    Idle,
+    // The device asked the driver to start a run.
+    Requested,
+    // The driver is sending the hints of the run.
+    Running,
+    // The driver sent all the hints of the run.
+    Stopped,
+}
+
 // Virtio balloon device.
 pub struct Balloon {
     // Virtio fields.
@@ -148,6 +169,14 @@ pub struct Balloon {
     pub(crate) latest_stats: BalloonStats,
     // A buffer used as pfn accumulator during descriptor processing.
     pub(crate) pfn_buffer: [u32; MAX_PAGE_COMPACT_BUFFER],
+    // The command id of the last free page hinting run. It is kept after the
+    // run, since the driver ignores a run with the same id as the previous one.
+    pub(crate) free_page_hint_cmd_id: u32,
+    pub(crate) free_page_hint_state: FreePageHintState,
+    // The guest memory ranges hinted as free during the current run.
+    pub(crate) free_page_hints: Vec<(GuestAddress, u64)>,
+    // The policy resizing the balloon from the statistics, if any.
+    pub(crate) auto_size: Option<BalloonAutoSize>,
 }
 
 impl Balloon {
@@ -155,6 +184,8 @@ impl Balloon {
         amount_mib: u32,
         deflate_on_oom: bool,
         stats_polling_interval_s: u16,
+        free_page_hinting: bool,
+        free_page_reporting: bool,
         restored: bool,
     ) -> Result<Balloon, BalloonError> {
         let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
@@ -167,16 +198,33 @@ impl Balloon {
             avail_features |= 1u64 << VIRTIO_BALLOON_F_STATS_VQ;
         }
 
+        if free_page_hinting {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_FREE_PAGE_HINT;
+        }
+
+        if free_page_reporting {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_FREE_PAGE_REPORTING;
+        }
+
         let queue_evts = [
             EventFd::new(libc::EFD_NONBLOCK).map_err(BalloonError::EventFd)?,
             EventFd::new(libc::EFD_NONBLOCK).map_err(BalloonError::EventFd)?,
             EventFd::new(libc::EFD_NONBLOCK).map_err(BalloonError::EventFd)?,
+            EventFd::new(libc::EFD_NONBLOCK).map_err(BalloonError::EventFd)?,
+            EventFd::new(libc::EFD_NONBLOCK).map_err(BalloonError::EventFd)?,
         ];
 
         let mut queues: Vec<Queue> = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
 
-        // The VirtIO specification states that the statistics queue should
-        // not be present at all if the statistics are not enabled.
+        // The VirtIO specification states that the statistics, free page hinting
+        // and reporting queues should not be present at all if their features are
+        // not enabled.
+        if !free_page_reporting {
+            let _ = queues.remove(REPORTING_INDEX);
+        }
+        if !free_page_hinting {
+            let _ = queues.remove(FREE_PAGE_HINT_INDEX);
+        }
         if stats_polling_interval_s == 0 {
             let _ = queues.remove(STATS_INDEX);
         }
@@ -190,6 +238,7 @@ impl Balloon {
             config_space: ConfigSpace {
                 num_pages: mib_to_pages(amount_mib)?,
                 actual_pages: 0,
+                free_page_hint_cmd_id: FREE_PAGE_HINT_CMD_ID_STOP,
             },
             queue_evts,
             queues,
@@ -202,6 +251,10 @@ impl Balloon {
             stats_desc_index: None,
             latest_stats: BalloonStats::default(),
             pfn_buffer: [0u32; MAX_PAGE_COMPACT_BUFFER],
+            free_page_hint_cmd_id: FREE_PAGE_HINT_CMD_ID_STOP,
+            free_page_hint_state: FreePageHintState::Idle,
+            free_page_hints: Vec::new(),
+            auto_size: None,
         })
     }
 
@@ -226,11 +279,50 @@ impl Balloon {
         self.process_stats_queue()
     }
 
+    pub(crate) fn process_free_page_hint_queue_event(&mut self) -> Result<(), BalloonError> {
+        self.queue_evts[self.free_page_hint_queue_index()]
+            .read()
+            .map_err(BalloonError::EventFd)?;
+        self.process_free_page_hint_queue()
+    }
+
+    pub(crate) fn process_reporting_queue_event(&mut self) -> Result<(), BalloonError> {
+        self.queue_evts[self.reporting_queue_index()]
+            .read()
+            .map_err(BalloonError::EventFd)?;
+        self.process_reporting_queue()
+    }
+
     pub(crate) fn process_stats_timer_event(&mut self) -> Result<(), BalloonError> {
         self.stats_timer.read();
+        self.auto_resize()?;
         self.trigger_stats_update()
     }
 
+    // Applies the automatic sizing policy to the statistics received since the
+    // previous timer event.
+    fn auto_resize(&mut self) -> Result<(), BalloonError> {
+        let auto_size = match self.auto_size {
+            Some(auto_size) => auto_size,
+            None => return Ok(()),
+        };
+
+        let inflating = self.config_space.actual_pages < self.config_space.num_pages;
+        match auto_size.decide(self.size_mb(), inflating, &self.latest_stats) {
+            Some(AutoSizeDecision::Inflate(size_mib)) => {
+                METRICS.balloon.auto_inflate_count.inc();
+                METRICS.balloon.auto_target_mib.store(size_mib as usize);
+                self.set_size(size_mib)
+            }
+            Some(AutoSizeDecision::Deflate(size_mib)) => {
+                METRICS.balloon.auto_deflate_count.inc();
+                METRICS.balloon.auto_target_mib.store(size_mib as usize);
+                self.set_size(size_mib)
+            }
+            None => Ok(()),
+        }
+    }
+
     pub(crate) fn process_inflate(&mut self) -> Result<(), BalloonError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
@@ -382,6 +474,95 @@ impl Balloon {
         Ok(())
     }
 
+    /// Processes the free page hinting queue, which holds the command ids
+    /// starting and stopping a run, and the ranges hinted during the run.
+    pub fn process_free_page_hint_queue(&mut self) -> Result<(), BalloonError> {
+        // This is safe since we checked in the event handler that the device is activated.
+        let mem = self.device_state.mem().unwrap();
+
+        let queue_index = self.free_page_hint_queue_index();
+        let queue = &mut self.queues[queue_index];
+        let mut needs_interrupt = false;
+
+        while let Some(head) = queue.pop(mem) {
+            if head.is_write_only() {
+                // The driver keeps the hinted pages until the run is done, so
+                // only the ones of the current run are free.
+                if self.free_page_hint_state == FreePageHintState::Running {
+                    METRICS.balloon.free_page_hint_bytes.add(head.len as usize);
+                    self.free_page_hints.push((head.addr, u64::from(head.len)));
+                }
+            } else if head.len as usize == SIZE_OF_U32 {
+                let cmd_id = mem
+                    .read_obj::<u32>(head.addr)
+                    .map_err(|_| BalloonError::MalformedDescriptor)?;
+                match (cmd_id, self.free_page_hint_state) {
+                    (FREE_PAGE_HINT_CMD_ID_STOP, FreePageHintState::Running) => {
+                        self.free_page_hint_state = FreePageHintState::Stopped;
+                    }
+                    (cmd_id, FreePageHintState::Requested)
+                        if cmd_id == self.free_page_hint_cmd_id =>
+                    {
+                        self.free_page_hint_state = FreePageHintState::Running;
+                    }
+                    _ => (),
+                }
+            }
+
+            queue
+                .add_used(mem, head.index, 0)
+                .map_err(BalloonError::Queue)?;
+            needs_interrupt = true;
+        }
+
+        if needs_interrupt {
+            self.signal_used_queue()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn process_reporting_queue(&mut self) -> Result<(), BalloonError> {
+        // This is safe since we checked in the event handler that the device is activated.
+        let mem = self.device_state.mem().unwrap();
+        METRICS.balloon.free_page_report_count.inc();
+
+        let queue_index = self.reporting_queue_index();
+        let queue = &mut self.queues[queue_index];
+        let mut needs_interrupt = false;
+
+        while let Some(head) = queue.pop(mem) {
+            let head_index = head.index;
+            // Each descriptor of the chain holds a range of free guest memory.
+            let mut next_desc = Some(head);
+            while let Some(desc) = next_desc {
+                match remove_range(mem, (desc.addr, u64::from(desc.len)), self.restored) {
+                    Ok(()) => METRICS
+                        .balloon
+                        .free_page_report_freed
+                        .add(desc.len as usize),
+                    Err(err) => {
+                        error!("Error removing reported memory range: {:?}", err);
+                        METRICS.balloon.free_page_report_fails.inc();
+                    }
+                }
+                next_desc = desc.next_descriptor();
+            }
+
+            // The device doesn't write anything back to the reported ranges.
+            queue
+                .add_used(mem, head_index, 0)
+                .map_err(BalloonError::Queue)?;
+            needs_interrupt = true;
+        }
+
+        if needs_interrupt {
+            self.signal_used_queue()
+        } else {
+            Ok(())
+        }
+    }
+
     pub(crate) fn signal_used_queue(&self) -> Result<(), BalloonError> {
         self.irq_trigger.trigger_irq(IrqType::Vring).map_err(|err| {
             METRICS.balloon.event_fails.inc();
@@ -393,6 +574,12 @@ impl Balloon {
     pub fn process_virtio_queues(&mut self) {
         let _ = self.process_inflate();
         let _ = self.process_deflate_queue();
+        if self.free_page_hinting() {
+            let _ = self.process_free_page_hint_queue();
+        }
+        if self.free_page_reporting() {
+            let _ = self.process_reporting_queue();
+        }
     }
 
     pub fn id(&self) -> &str {
@@ -416,7 +603,16 @@ impl Balloon {
         }
     }
 
+    /// Sets the target size of the balloon, unless the automatic sizing
+    /// policy takes care of it, since the policy would override the size.
     pub fn update_size(&mut self, amount_mib: u32) -> Result<(), BalloonError> {
+        if self.auto_size.is_some() {
+            return Err(BalloonError::AutoSizeEnabled);
+        }
+        self.set_size(amount_mib)
+    }
+
+    fn set_size(&mut self, amount_mib: u32) -> Result<(), BalloonError> {
         if self.is_activated() {
             self.config_space.num_pages = mib_to_pages(amount_mib)?;
             self.irq_trigger
@@ -468,6 +664,80 @@ impl Balloon {
         self.stats_polling_interval_s
     }
 
+    pub fn free_page_hinting(&self) -> bool {
+        self.avail_features & (1u64 << VIRTIO_BALLOON_F_FREE_PAGE_HINT) != 0
+    }
+
+    /// Asks the driver to start a new free page hinting run.
+    pub fn start_free_page_hinting(&mut self) -> Result<(), BalloonError> {
+        if !self.is_activated() {
+            return Err(BalloonError::DeviceNotActive);
+        }
+        if !self.has_feature(u64::from(VIRTIO_BALLOON_F_FREE_PAGE_HINT)) {
+            return Err(BalloonError::FreePageHintingDisabled);
+        }
+
+        self.free_page_hint_cmd_id = match self.free_page_hint_cmd_id.checked_add(1) {
+            Some(cmd_id) if cmd_id >= FREE_PAGE_HINT_CMD_ID_MIN => cmd_id,
+            _ => FREE_PAGE_HINT_CMD_ID_MIN,
+        };
+        self.config_space.free_page_hint_cmd_id = self.free_page_hint_cmd_id;
+        self.free_page_hint_state = FreePageHintState::Requested;
+        self.free_page_hints.clear();
+        METRICS.balloon.free_page_hint_count.inc();
+
+        self.irq_trigger
+            .trigger_irq(IrqType::Config)
+            .map_err(BalloonError::InterruptError)
+    }
+
+    /// Returns whether the driver sent all the hints of the current run.
+    pub fn free_page_hinting_stopped(&self) -> bool {
+        self.free_page_hint_state == FreePageHintState::Stopped
+    }
+
+    /// Ends the current free page hinting run, which lets the driver use the
+    /// hinted pages again, and returns the ranges hinted during the run.
+    pub fn finish_free_page_hinting(&mut self) -> Result<Vec<(GuestAddress, u64)>, BalloonError> {
+        self.free_page_hint_state = FreePageHintState::Idle;
+        self.config_space.free_page_hint_cmd_id = FREE_PAGE_HINT_CMD_ID_DONE;
+        self.irq_trigger
+            .trigger_irq(IrqType::Config)
+            .map_err(BalloonError::InterruptError)?;
+
+        Ok(std::mem::take(&mut self.free_page_hints))
+    }
+
+    /// Returns the event signaled by the driver when it adds buffers to the
+    /// free page hinting queue.
+    pub fn free_page_hint_queue_evt(&self) -> &EventFd {
+        &self.queue_evts[self.free_page_hint_queue_index()]
+    }
+
+    /// Sets the policy resizing the balloon each time the statistics are
+    /// polled, which needs the statistics to be enabled.
+    pub fn set_auto_size(
+        &mut self,
+        auto_size: Option<BalloonAutoSize>,
+    ) -> Result<(), BalloonError> {
+        if let Some(auto_size) = auto_size.as_ref() {
+            auto_size.validate()?;
+            if !self.stats_enabled() {
+                return Err(BalloonError::StatisticsDisabled);
+            }
+        }
+        self.auto_size = auto_size;
+        Ok(())
+    }
+
+    pub fn auto_size(&self) -> Option<&BalloonAutoSize> {
+        self.auto_size.as_ref()
+    }
+
+    pub fn free_page_reporting(&self) -> bool {
+        self.avail_features & (1u64 << VIRTIO_BALLOON_F_FREE_PAGE_REPORTING) != 0
+    }
+
     pub fn latest_stats(&mut self) -> Option<&BalloonStats> {
         if self.stats_enabled() {
             self.latest_stats.target_pages = self.config_space.num_pages;
@@ -485,6 +755,9 @@ impl Balloon {
             amount_mib: self.size_mb(),
             deflate_on_oom: self.deflate_on_oom(),
             stats_polling_interval_s: self.stats_polling_interval_s(),
+            free_page_hinting: self.free_page_hinting(),
+            free_page_reporting: self.free_page_reporting(),
+            auto_size: self.auto_size,
         }
     }
 
@@ -492,6 +765,17 @@ impl Balloon {
         self.stats_polling_interval_s > 0
     }
 
+    // The optional queues follow each other, in the order of the statistics,
+    // free page hinting and reporting queues, and are only present when their
+    // feature is enabled.
+    pub(crate) fn free_page_hint_queue_index(&self) -> usize {
+        STATS_INDEX + usize::from(self.stats_enabled())
+    }
+
+    pub(crate) fn reporting_queue_index(&self) -> usize {
+        self.free_page_hint_queue_index() + usize::from(self.free_page_hinting())
+    }
+
     pub(crate) fn set_stats_desc_index(&mut self, stats_desc_index: Option<u16>) {
         self.stats_desc_index = stats_desc_index;
     }
@@ -682,52 +966,82 @@ pub(crate) mod tests {
         // Test all feature combinations.
         for deflate_on_oom in vec![true, false].iter() {
             for stats_interval in vec![0, 1].iter() {
-                let mut balloon = Balloon::new(0, *deflate_on_oom, *stats_interval, false).unwrap();
-                assert_eq!(balloon.device_type(), TYPE_BALLOON);
-
-                let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
-                    | ((if *deflate_on_oom { 1 } else { 0 }) << VIRTIO_BALLOON_F_DEFLATE_ON_OOM)
-                    | ((*stats_interval as u64) << VIRTIO_BALLOON_F_STATS_VQ);
-
-                assert_eq!(balloon.avail_features_by_page(0), features as u32);
-                assert_eq!(balloon.avail_features_by_page(1), (features >> 32) as u32);
-                for i in 2..10 {
-                    assert_eq!(balloon.avail_features_by_page(i), 0u32);
-                }
+                for (free_page_hinting, free_page_reporting) in
+                    vec![(false, false), (true, false), (false, true), (true, true)].iter()
+                {
+                    let mut balloon = Balloon::new(
+                        0,
+                        *deflate_on_oom,
+                        *stats_interval,
+                        *free_page_hinting,
+                        *free_page_reporting,
+                        false,
+                    )
+                    .unwrap();
+                    assert_eq!(balloon.device_type(), TYPE_BALLOON);
+
+                    let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
+                        | ((if *deflate_on_oom { 1 } else { 0 })
+                            << VIRTIO_BALLOON_F_DEFLATE_ON_OOM)
+                        | ((*stats_interval as u64) << VIRTIO_BALLOON_F_STATS_VQ)
+                        | ((if *free_page_hinting { 1 } else { 0 })
+                            << VIRTIO_BALLOON_F_FREE_PAGE_HINT)
+                        | ((if *free_page_reporting { 1 } else { 0 })
+                            << VIRTIO_BALLOON_F_FREE_PAGE_REPORTING);
+
+                    assert_eq!(balloon.avail_features_by_page(0), features as u32);
+                    assert_eq!(balloon.avail_features_by_page(1), (features >> 32) as u32);
+                    for i in 2..10 {
+                        assert_eq!(balloon.avail_features_by_page(i), 0u32);
+                    }
 
-                for i in 0..10 {
-                    balloon.ack_features_by_page(i, u32::MAX);
+                    for i in 0..10 {
+                        balloon.ack_features_by_page(i, u32::MAX);
+                    }
+                    // Only present features should be acknowledged.
+                    assert_eq!(balloon.acked_features, features);
+
+                    // Only the queues of the present features should exist.
+                    assert_eq!(
+                        balloon.queues().len(),
+                        2 + *stats_interval as usize
+                            + *free_page_hinting as usize
+                            + *free_page_reporting as usize
+                    );
                 }
-                // Only present features should be acknowledged.
-                assert_eq!(balloon.acked_features, features);
             }
         }
     }
 
     #[test]
     fn test_virtio_read_config() {
-        let balloon = Balloon::new(0x10, true, 0, false).unwrap();
+        let balloon = Balloon::new(0x10, true, 0, false, false, false).unwrap();
 
         let cfg = BalloonConfig {
             amount_mib: 16,
             deflate_on_oom: true,
             stats_polling_interval_s: 0,
+            free_page_hinting: false,
+            free_page_reporting: false,
+            auto_size: None,
         };
         assert_eq!(balloon.config(), cfg);
 
         let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
         balloon.read_config(0, &mut actual_config_space);
-        // The first 4 bytes are num_pages, the last 4 bytes are actual_pages.
+        // The first 4 bytes are num_pages, the next 4 bytes are actual_pages,
+        // and the last 4 bytes are free_page_hint_cmd_id.
         // The config space is little endian.
         // 0x10 MB in the constructor corresponds to 0x1000 pages in the
         // config space.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; CONFIG_SPACE_SIZE] = [
+            0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
         assert_eq!(actual_config_space, expected_config_space);
 
         // Invalid read.
         let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
+            [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf, 0xd, 0xe, 0xa, 0xd];
         actual_config_space = expected_config_space;
         balloon.read_config(CONFIG_SPACE_SIZE as u64 + 1, &mut actual_config_space);
 
@@ -737,10 +1051,11 @@ pub(crate) mod tests {
 
     #[test]
     fn test_virtio_write_config() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 0, false, false, false).unwrap();
 
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; CONFIG_SPACE_SIZE] = [
+            0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
         balloon.write_config(0, &expected_config_space);
 
         let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
@@ -749,7 +1064,7 @@ pub(crate) mod tests {
 
         // Invalid write.
         let new_config_space = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
-        balloon.write_config(5, &new_config_space);
+        balloon.write_config(9, &new_config_space);
         // Make sure nothing got written.
         balloon.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
@@ -757,7 +1072,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_invalid_request() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 0, false, false, false).unwrap();
         let mem = default_mem();
         // Only initialize the inflate queue to demonstrate invalid request handling.
         let infq = VirtQueue::new(GuestAddress(0), &mem, 16);
@@ -816,7 +1131,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_inflate() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 0, false, false, false).unwrap();
         let mem = default_mem();
         let infq = VirtQueue::new(GuestAddress(0), &mem, 16);
         balloon.set_queue(INFLATE_INDEX, infq.create_queue());
@@ -874,7 +1189,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_deflate() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 0, false, false, false).unwrap();
         let mem = default_mem();
         let defq = VirtQueue::new(GuestAddress(0), &mem, 16);
         balloon.set_queue(DEFLATE_INDEX, defq.create_queue());
@@ -910,7 +1225,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_stats() {
-        let mut balloon = Balloon::new(0, true, 1, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 1, false, false, false).unwrap();
         let mem = default_mem();
         let statsq = VirtQueue::new(GuestAddress(0), &mem, 16);
         balloon.set_queue(STATS_INDEX, statsq.create_queue());
@@ -989,9 +1304,222 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_auto_size() {
+        let auto_size = BalloonAutoSize {
+            min_mib: 0,
+            max_mib: 64,
+            target_free_mib: 16,
+            step_mib: 8,
+        };
+
+        // The policy runs on the statistics timer.
+        let mut balloon = Balloon::new(0, true, 0, false, false, false).unwrap();
+        assert!(matches!(
+            balloon.set_auto_size(Some(auto_size)),
+            Err(BalloonError::StatisticsDisabled)
+        ));
+
+        let mut balloon = Balloon::new(16, true, 1, false, false, false).unwrap();
+        assert!(matches!(
+            balloon.set_auto_size(Some(BalloonAutoSize {
+                step_mib: 0,
+                ..auto_size
+            })),
+            Err(BalloonError::InvalidAutoSize(_))
+        ));
+        balloon.set_auto_size(Some(auto_size)).unwrap();
+        assert_eq!(balloon.auto_size(), Some(&auto_size));
+        assert_eq!(balloon.config().auto_size, Some(auto_size));
+        balloon.activate(default_mem()).unwrap();
+
+        // Without statistics, the size is kept.
+        balloon.process_stats_timer_event().unwrap();
+        assert_eq!(balloon.size_mb(), 16);
+
+        // The guest has enough free memory, but it didn't inflate the balloon yet.
+        balloon.latest_stats.free_memory = Some(32 << 20);
+        balloon.process_stats_timer_event().unwrap();
+        assert_eq!(balloon.size_mb(), 16);
+
+        balloon.update_actual_pages(balloon.num_pages());
+        check_metric_after_block!(
+            METRICS.balloon.auto_inflate_count,
+            1,
+            balloon.process_stats_timer_event().unwrap()
+        );
+        assert_eq!(balloon.size_mb(), 24);
+        assert_eq!(METRICS.balloon.auto_target_mib.fetch(), 24);
+        assert!(balloon.irq_trigger.has_pending_irq(IrqType::Config));
+
+        // The guest is short of memory.
+        balloon.latest_stats.free_memory = Some(8 << 20);
+        check_metric_after_block!(
+            METRICS.balloon.auto_deflate_count,
+            1,
+            balloon.process_stats_timer_event().unwrap()
+        );
+        assert_eq!(balloon.size_mb(), 16);
+
+        // The size can't be set by hand while the policy is in place.
+        assert!(matches!(
+            balloon.update_size(32),
+            Err(BalloonError::AutoSizeEnabled)
+        ));
+        assert_eq!(balloon.size_mb(), 16);
+
+        // The policy can be removed.
+        balloon.set_auto_size(None).unwrap();
+        balloon.process_stats_timer_event().unwrap();
+        assert_eq!(balloon.size_mb(), 16);
+        balloon.update_size(32).unwrap();
+        assert_eq!(balloon.size_mb(), 32);
+    }
+
+    #[test]
+    fn test_free_page_hinting() {
+        let mut balloon = Balloon::new(0, true, 0, true, false, false).unwrap();
+        // The free page hinting queue takes the place of the missing statistics queue.
+        let hint_index = balloon.free_page_hint_queue_index();
+        assert_eq!(hint_index, STATS_INDEX);
+        let mem = default_mem();
+        let hintq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        balloon.set_queue(hint_index, hintq.create_queue());
+
+        // Error case: the device is not activated.
+        assert_eq!(
+            format!("{:?}", balloon.start_free_page_hinting()),
+            "Err(DeviceNotActive)"
+        );
+
+        // Error case: the driver didn't negotiate free page hinting.
+        balloon.activate(mem.clone()).unwrap();
+        assert_eq!(
+            format!("{:?}", balloon.start_free_page_hinting()),
+            "Err(FreePageHintingDisabled)"
+        );
+
+        balloon.ack_features_by_page(0, u32::MAX);
+        check_metric_after_block!(
+            METRICS.balloon.free_page_hint_count,
+            1,
+            balloon.start_free_page_hinting().unwrap()
+        );
+        assert!(balloon.irq_trigger.has_pending_irq(IrqType::Config));
+        // Acknowledge the interrupt, as the driver would.
+        balloon
+            .irq_trigger
+            .irq_status
+            .store(0, std::sync::atomic::Ordering::SeqCst);
+        let mut cmd_id = [0u8; SIZE_OF_U32];
+        balloon.read_config(8, &mut cmd_id);
+        assert_eq!(u32::from_le_bytes(cmd_id), FREE_PAGE_HINT_CMD_ID_MIN);
+
+        // The hints sent before the driver starts the run are ignored.
+        set_request(&hintq, 0, 0x2000, 0x1000, VIRTQ_DESC_F_WRITE);
+        invoke_handler_for_queue_event(&mut balloon, hint_index);
+        check_request_completion(&hintq, 0);
+
+        // The driver starts the run by sending the command id.
+        mem.write_obj::<u32>(FREE_PAGE_HINT_CMD_ID_MIN, GuestAddress(0x1000))
+            .unwrap();
+        set_request(&hintq, 1, 0x1000, SIZE_OF_U32 as u32, 0);
+        invoke_handler_for_queue_event(&mut balloon, hint_index);
+        check_request_completion(&hintq, 1);
+
+        set_request(&hintq, 2, 0x4000, 0x2000, VIRTQ_DESC_F_WRITE);
+        check_metric_after_block!(
+            METRICS.balloon.free_page_hint_bytes,
+            0x2000,
+            invoke_handler_for_queue_event(&mut balloon, hint_index)
+        );
+        check_request_completion(&hintq, 2);
+        assert!(!balloon.free_page_hinting_stopped());
+
+        // The driver ends the run by sending the stop command id.
+        mem.write_obj::<u32>(FREE_PAGE_HINT_CMD_ID_STOP, GuestAddress(0x1004))
+            .unwrap();
+        set_request(&hintq, 3, 0x1004, SIZE_OF_U32 as u32, 0);
+        invoke_handler_for_queue_event(&mut balloon, hint_index);
+        check_request_completion(&hintq, 3);
+        assert!(balloon.free_page_hinting_stopped());
+
+        assert_eq!(
+            balloon.finish_free_page_hinting().unwrap(),
+            vec![(GuestAddress(0x4000), 0x2000)]
+        );
+        assert!(!balloon.free_page_hinting_stopped());
+        balloon.read_config(8, &mut cmd_id);
+        assert_eq!(u32::from_le_bytes(cmd_id), FREE_PAGE_HINT_CMD_ID_DONE);
+
+        // The next run uses a new command id.
+        balloon.start_free_page_hinting().unwrap();
+        balloon.read_config(8, &mut cmd_id);
+        assert_eq!(u32::from_le_bytes(cmd_id), FREE_PAGE_HINT_CMD_ID_MIN + 1);
+    }
+
+    #[test]
+    fn test_free_page_reporting() {
+        let mut balloon = Balloon::new(0, true, 0, false, true, false).unwrap();
+        // The reporting queue takes the place of the missing statistics queue.
+        let reporting_index = balloon.reporting_queue_index();
+        assert_eq!(reporting_index, STATS_INDEX);
+        let mem = default_mem();
+        let repq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        balloon.set_queue(reporting_index, repq.create_queue());
+        balloon.activate(mem.clone()).unwrap();
+
+        // Fill the second and third pages with non-zero bytes.
+        for i in 0..0x2000 {
+            assert!(mem.write_obj::<u8>(1, GuestAddress((1 << 12) + i)).is_ok());
+        }
+
+        // Error case: forgot to trigger the reporting event queue.
+        {
+            set_request(&repq, 0, 0x1000, 0x1000, VIRTQ_DESC_F_WRITE);
+            check_metric_after_block!(
+                METRICS.balloon.event_fails,
+                1,
+                balloon
+                    .process_reporting_queue_event()
+                    .unwrap_or_else(report_balloon_event_fail)
+            );
+            // Verify that nothing got processed.
+            assert_eq!(repq.used.idx.get(), 0);
+        }
+
+        // Happy case: the reported range is released.
+        {
+            check_metric_after_block!(
+                METRICS.balloon.free_page_report_freed,
+                0x1000,
+                invoke_handler_for_queue_event(&mut balloon, reporting_index)
+            );
+            check_request_completion(&repq, 0);
+
+            // Check that only the reported page was zeroed.
+            for i in 0..0x1000 {
+                assert_eq!(mem.read_obj::<u8>(GuestAddress((1 << 12) + i)).unwrap(), 0);
+                assert_eq!(mem.read_obj::<u8>(GuestAddress((2 << 12) + i)).unwrap(), 1);
+            }
+        }
+
+        // Invalid case: the reported range is outside of the guest memory.
+        {
+            set_request(&repq, 1, 0x1_0000_0000, 0x1000, VIRTQ_DESC_F_WRITE);
+            check_metric_after_block!(
+                METRICS.balloon.free_page_report_fails,
+                1,
+                invoke_handler_for_queue_event(&mut balloon, reporting_index)
+            );
+            // The descriptor is still returned to the driver.
+            check_request_completion(&repq, 1);
+        }
+    }
+
     #[test]
     fn test_process_balloon_queues() {
-        let mut balloon = Balloon::new(0x10, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0x10, true, 0, false, false, false).unwrap();
         let mem = default_mem();
         balloon.activate(mem).unwrap();
         balloon.process_virtio_queues()
@@ -999,7 +1527,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_update_stats_interval() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 0, false, false, false).unwrap();
         let mem = default_mem();
         balloon.activate(mem).unwrap();
         assert_eq!(
@@ -1008,7 +1536,7 @@ pub(crate) mod tests {
         );
         assert!(balloon.update_stats_polling_interval(0).is_ok());
 
-        let mut balloon = Balloon::new(0, true, 1, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 1, false, false, false).unwrap();
         let mem = default_mem();
         balloon.activate(mem).unwrap();
         assert_eq!(
@@ -1021,7 +1549,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_num_pages() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 0, false, false, false).unwrap();
         // Assert that we can't update an inactive device.
         assert!(balloon.update_size(1).is_err());
         // Switch the state to active.
@@ -1044,7 +1572,10 @@ pub(crate) mod tests {
 
         let mut actual_config = vec![0; CONFIG_SPACE_SIZE];
         balloon.read_config(0, &mut actual_config);
-        assert_eq!(actual_config, vec![0x0, 0x10, 0x0, 0x0, 0x34, 0x12, 0, 0]);
+        assert_eq!(
+            actual_config,
+            vec![0x0, 0x10, 0x0, 0x0, 0x34, 0x12, 0, 0, 0, 0, 0, 0]
+        );
         assert_eq!(balloon.num_pages(), 0x1000);
         assert_eq!(balloon.actual_pages(), 0x1234);
         assert_eq!(balloon.size_mb(), 16);
@@ -27,6 +27,22 @@ impl Balloon {
                 error!("Failed to register stats timerfd event: {}", err);
             }
         }
+        if self.free_page_hinting() {
+            if let Err(err) = ops.add(Events::new(
+                &self.queue_evts[self.free_page_hint_queue_index()],
+                EventSet::IN,
+            )) {
+                error!("Failed to register free page hinting queue event: {}", err);
+            }
+        }
+        if self.free_page_reporting() {
+            if let Err(err) = ops.add(Events::new(
+                &self.queue_evts[self.reporting_queue_index()],
+                EventSet::IN,
+            )) {
+                error!("Failed to register reporting queue event: {}", err);
+            }
+        }
     }
 
     fn register_activate_event(&self, ops: &mut EventOps) {
@@ -65,6 +81,16 @@ impl MutEventSubscriber for Balloon {
             let virtq_inflate_ev_fd = self.queue_evts[INFLATE_INDEX].as_raw_fd();
             let virtq_deflate_ev_fd = self.queue_evts[DEFLATE_INDEX].as_raw_fd();
             let virtq_stats_ev_fd = self.queue_evts[STATS_INDEX].as_raw_fd();
+            let virtq_hint_ev_fd = if self.free_page_hinting() {
+                Some(self.queue_evts[self.free_page_hint_queue_index()].as_raw_fd())
+            } else {
+                None
+            };
+            let virtq_reporting_ev_fd = if self.free_page_reporting() {
+                Some(self.queue_evts[self.reporting_queue_index()].as_raw_fd())
+            } else {
+                None
+            };
             let stats_timer_fd = self.stats_timer.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
 
@@ -76,6 +102,14 @@ impl MutEventSubscriber for Balloon {
                 _ if source == virtq_deflate_ev_fd => self
                     .process_deflate_queue_event()
                     .unwrap_or_else(report_balloon_event_fail),
+                // The optional queues use the events of the missing queues before
+                // them, so they have to be checked before the statistics queue.
+                _ if Some(source) == virtq_reporting_ev_fd => self
+                    .process_reporting_queue_event()
+                    .unwrap_or_else(report_balloon_event_fail),
+                _ if Some(source) == virtq_hint_ev_fd => self
+                    .process_free_page_hint_queue_event()
+                    .unwrap_or_else(report_balloon_event_fail),
                 _ if source == virtq_stats_ev_fd => self
                     .process_stats_queue_event()
                     .unwrap_or_else(report_balloon_event_fail),
@@ -122,7 +156,7 @@ pub mod tests {
     #[test]
     fn test_event_handler() {
         let mut event_manager = EventManager::new().unwrap();
-        let mut balloon = Balloon::new(0, true, 10, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 10, false, false, false).unwrap();
         let mem = default_mem();
         let infq = VirtQueue::new(GuestAddress(0), &mem, 16);
         balloon.set_queue(INFLATE_INDEX, infq.create_queue());
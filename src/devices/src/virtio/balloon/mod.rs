@@ -1,24 +1,26 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod auto_size;
 pub mod device;
 pub mod event_handler;
 pub mod persist;
 pub mod test_utils;
-mod utils;
+pub(crate) mod utils;
 
 use vm_memory::GuestMemoryError;
 
+pub use self::auto_size::BalloonAutoSize;
 pub use self::device::{Balloon, BalloonConfig, BalloonStats};
 pub use self::event_handler::*;
 
 /// Device ID used in MMIO device identification.
 /// Because Balloon is unique per-vm, this ID can be hardcoded.
 pub const BALLOON_DEV_ID: &str = "balloon";
-pub const CONFIG_SPACE_SIZE: usize = 8;
+pub const CONFIG_SPACE_SIZE: usize = 12;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 3;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE];
+pub const NUM_QUEUES: usize = 5;
+pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE];
 // Number of 4K pages in a MiB.
 pub const MIB_TO_4K_PAGES: u32 = 256;
 // The maximum number of pages that can be received in a single descriptor.
@@ -34,10 +36,23 @@ pub const INFLATE_INDEX: usize = 0;
 pub const DEFLATE_INDEX: usize = 1;
 // The index of the deflate queue from Balloon device queues/queues_evts vector.
 pub const STATS_INDEX: usize = 2;
+// The index of the free page hinting queue when the statistics queue is present.
+// The optional queues are only present when their feature is enabled, and the
+// ones which follow a missing queue take its place.
+pub const FREE_PAGE_HINT_INDEX: usize = 3;
+// The index of the reporting queue when all the optional queues before it are present.
+pub const REPORTING_INDEX: usize = 4;
 
 // The feature bitmap for virtio balloon.
 const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1; // Enable statistics.
 const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2; // Deflate balloon on OOM.
+const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u32 = 3; // Hint free pages.
+const VIRTIO_BALLOON_F_FREE_PAGE_REPORTING: u32 = 5; // Report free pages.
+
+// The command ids of free page hinting. Any other id asks the driver to start hinting.
+const FREE_PAGE_HINT_CMD_ID_STOP: u32 = 0;
+const FREE_PAGE_HINT_CMD_ID_DONE: u32 = 1;
+const FREE_PAGE_HINT_CMD_ID_MIN: u32 = 2;
 
 // The statistics tags.
 const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
@@ -55,14 +70,20 @@ const VIRTIO_BALLOON_S_HTLB_PGFAIL: u16 = 9;
 pub enum Error {
     /// Activation error.
     Activate(super::ActivateError),
+    /// The size of the balloon is set by the automatic sizing policy.
+    AutoSizeEnabled,
     /// No balloon device found.
     DeviceNotFound,
     /// Device not activated yet.
     DeviceNotActive,
     /// EventFd error.
     EventFd(std::io::Error),
+    /// The driver didn't negotiate free page hinting.
+    FreePageHintingDisabled,
     /// Guest gave us bad memory addresses.
     GuestMemory(GuestMemoryError),
+    /// The sizes of the automatic sizing policy are inconsistent.
+    InvalidAutoSize(BalloonAutoSize),
     /// Received error while sending an interrupt.
     InterruptError(std::io::Error),
     /// Guest gave us a malformed descriptor.
@@ -88,6 +109,7 @@ pub enum Error {
 #[derive(Debug)]
 pub enum RemoveRegionError {
     AddressTranslation,
+    FallocateFail(std::io::Error),
     MalformedRange,
     MadviseFail(std::io::Error),
     MmapFail(std::io::Error),
@@ -9,7 +9,7 @@ use std::time::Duration;
 
 use snapshot::Persist;
 use timerfd::{SetTimeFlags, TimerState};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::GuestMemoryMmap;
 
@@ -23,6 +23,8 @@ use crate::virtio::{DeviceState, TYPE_BALLOON};
 pub struct BalloonConfigSpaceState {
     num_pages: u32,
     actual_pages: u32,
+    #[version(start = 2)]
+    free_page_hint_cmd_id: u32,
 }
 
 #[derive(Clone, Versionize)]
@@ -76,6 +78,37 @@ impl BalloonStatsState {
     }
 }
 
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct BalloonAutoSizeState {
+    min_mib: u32,
+    max_mib: u32,
+    target_free_mib: u32,
+    step_mib: u32,
+}
+
+impl From<&BalloonAutoSize> for BalloonAutoSizeState {
+    fn from(auto_size: &BalloonAutoSize) -> Self {
+        BalloonAutoSizeState {
+            min_mib: auto_size.min_mib,
+            max_mib: auto_size.max_mib,
+            target_free_mib: auto_size.target_free_mib,
+            step_mib: auto_size.step_mib,
+        }
+    }
+}
+
+impl From<&BalloonAutoSizeState> for BalloonAutoSize {
+    fn from(state: &BalloonAutoSizeState) -> Self {
+        BalloonAutoSize {
+            min_mib: state.min_mib,
+            max_mib: state.max_mib,
+            target_free_mib: state.target_free_mib,
+            step_mib: state.step_mib,
+        }
+    }
+}
+
 #[derive(Clone, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct BalloonState {
@@ -84,6 +117,40 @@ pub struct BalloonState {
     latest_stats: BalloonStatsState,
     config_space: BalloonConfigSpaceState,
     virtio_state: VirtioDeviceState,
+    #[version(start = 2, ser_fn = "free_page_reporting_ser")]
+    free_page_reporting: bool,
+    #[version(start = 2, ser_fn = "free_page_hinting_ser")]
+    free_page_hinting: bool,
+    // The command id of the last free page hinting run.
+    #[version(start = 2)]
+    free_page_hint_cmd_id: u32,
+    #[version(start = 2)]
+    auto_size: Option<BalloonAutoSizeState>,
+}
+
+impl BalloonState {
+    fn free_page_hinting_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions don't know about the free page hinting queue and would fail to
+        // restore it.
+        if target_version < 2 && self.free_page_hinting {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support free page hinting.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn free_page_reporting_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions don't know about the reporting queue and would fail to restore it.
+        if target_version < 2 && self.free_page_reporting {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support free page reporting.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub struct BalloonConstructorArgs {
@@ -103,8 +170,13 @@ impl Persist<'_> for Balloon {
             config_space: BalloonConfigSpaceState {
                 num_pages: self.config_space.num_pages,
                 actual_pages: self.config_space.actual_pages,
+                free_page_hint_cmd_id: self.config_space.free_page_hint_cmd_id,
             },
             virtio_state: VirtioDeviceState::from_device(self),
+            free_page_reporting: self.free_page_reporting(),
+            free_page_hinting: self.free_page_hinting(),
+            free_page_hint_cmd_id: self.free_page_hint_cmd_id,
+            auto_size: self.auto_size().map(BalloonAutoSizeState::from),
         }
     }
 
@@ -114,14 +186,27 @@ impl Persist<'_> for Balloon {
     ) -> std::result::Result<Self, Self::Error> {
         // We can safely create the balloon with arbitrary flags and
         // num_pages because we will overwrite them after.
-        let mut balloon = Balloon::new(0, false, state.stats_polling_interval_s, true)?;
+        let mut balloon = Balloon::new(
+            0,
+            false,
+            state.stats_polling_interval_s,
+            state.free_page_hinting,
+            state.free_page_reporting,
+            true,
+        )?;
 
         let mut num_queues = NUM_QUEUES;
-        // As per the virtio 1.1 specification, the statistics queue
-        // should not exist if the statistics are not enabled.
+        // As per the virtio 1.1 specification, the statistics, free page hinting
+        // and reporting queues should not exist if their features are not enabled.
         if state.stats_polling_interval_s == 0 {
             num_queues -= 1;
         }
+        if !state.free_page_hinting {
+            num_queues -= 1;
+        }
+        if !state.free_page_reporting {
+            num_queues -= 1;
+        }
         balloon.queues = state
             .virtio_state
             .build_queues_checked(&constructor_args.mem, TYPE_BALLOON, num_queues, QUEUE_SIZE)
@@ -134,7 +219,10 @@ impl Persist<'_> for Balloon {
         balloon.config_space = ConfigSpace {
             num_pages: state.config_space.num_pages,
             actual_pages: state.config_space.actual_pages,
+            free_page_hint_cmd_id: state.config_space.free_page_hint_cmd_id,
         };
+        balloon.free_page_hint_cmd_id = state.free_page_hint_cmd_id;
+        balloon.auto_size = state.auto_size.as_ref().map(BalloonAutoSize::from);
 
         if state.virtio_state.activated {
             balloon.device_state = DeviceState::Activated(constructor_args.mem);
@@ -174,7 +262,7 @@ mod tests {
         let version_map = VersionMap::new();
 
         // Create and save the balloon device.
-        let balloon = Balloon::new(0x42, false, 2, false).unwrap();
+        let balloon = Balloon::new(0x42, false, 2, false, false, false).unwrap();
 
         <Balloon as Persist>::save(&balloon)
             .serialize(&mut mem.as_mut_slice(), &version_map, 1)
@@ -207,4 +295,115 @@ mod tests {
         assert_eq!(restored_balloon.stats_desc_index, balloon.stats_desc_index);
         assert_eq!(restored_balloon.latest_stats, balloon.latest_stats);
     }
+
+    #[test]
+    fn test_free_page_reporting_persistence() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BalloonState::type_id(), 2);
+
+        // Free page reporting can't be saved in the first version of the state.
+        let balloon = Balloon::new(0x42, false, 0, false, true, false).unwrap();
+        assert!(<Balloon as Persist>::save(&balloon)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Balloon as Persist>::save(&balloon)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_balloon = Balloon::restore(
+            BalloonConstructorArgs { mem: default_mem() },
+            &BalloonState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert!(restored_balloon.free_page_reporting());
+        assert_eq!(restored_balloon.queues().len(), 3);
+
+        // States without the field restore a balloon without free page reporting.
+        let balloon = Balloon::new(0x42, false, 0, false, false, false).unwrap();
+        <Balloon as Persist>::save(&balloon)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        let restored_balloon = Balloon::restore(
+            BalloonConstructorArgs { mem: default_mem() },
+            &BalloonState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+        )
+        .unwrap();
+        assert!(!restored_balloon.free_page_reporting());
+        assert_eq!(restored_balloon.queues().len(), 2);
+    }
+
+    #[test]
+    fn test_free_page_hinting_persistence() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BalloonState::type_id(), 2)
+            .set_type_version(BalloonConfigSpaceState::type_id(), 2);
+
+        let mut balloon = Balloon::new(0x42, false, 1, true, false, false).unwrap();
+        balloon.free_page_hint_cmd_id = 5;
+        balloon.config_space.free_page_hint_cmd_id = FREE_PAGE_HINT_CMD_ID_DONE;
+
+        // Free page hinting can't be saved in the first version of the state.
+        assert!(<Balloon as Persist>::save(&balloon)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Balloon as Persist>::save(&balloon)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_balloon = Balloon::restore(
+            BalloonConstructorArgs { mem: default_mem() },
+            &BalloonState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert!(restored_balloon.free_page_hinting());
+        assert_eq!(restored_balloon.queues().len(), 4);
+        assert_eq!(restored_balloon.free_page_hint_queue_index(), 3);
+        assert_eq!(restored_balloon.free_page_hint_cmd_id, 5);
+        assert_eq!(restored_balloon.config_space, balloon.config_space);
+    }
+
+    #[test]
+    fn test_auto_size_persistence() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BalloonState::type_id(), 2);
+
+        let auto_size = BalloonAutoSize {
+            min_mib: 16,
+            max_mib: 128,
+            target_free_mib: 64,
+            step_mib: 8,
+        };
+        let mut balloon = Balloon::new(0x42, false, 1, false, false, false).unwrap();
+        balloon.set_auto_size(Some(auto_size)).unwrap();
+
+        <Balloon as Persist>::save(&balloon)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_balloon = Balloon::restore(
+            BalloonConstructorArgs { mem: default_mem() },
+            &BalloonState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_balloon.auto_size(), Some(&auto_size));
+
+        // The policy is left out of the first version of the state.
+        <Balloon as Persist>::save(&balloon)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        let restored_balloon = Balloon::restore(
+            BalloonConstructorArgs { mem: default_mem() },
+            &BalloonState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_balloon.auto_size(), None);
+    }
 }
@@ -1,10 +1,14 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fs::File;
 use std::io;
+use std::os::unix::io::AsRawFd;
 
 use logger::error;
-use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_memory::{
+    Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, HugePageSize,
+};
 
 use super::{RemoveRegionError, MAX_PAGE_COMPACT_BUFFER};
 
@@ -70,16 +74,46 @@ pub(crate) fn remove_range(
     range: (GuestAddress, u64),
     restored: bool,
 ) -> std::result::Result<(), RemoveRegionError> {
-    let (guest_address, range_len) = range;
+    let (mut guest_address, mut range_len) = range;
 
     if let Some(region) = guest_memory.find_region(guest_address) {
         if guest_address.0 + range_len > region.start_addr().0 + region.len() {
             return Err(RemoveRegionError::MalformedRange);
         }
+
+        let huge_pages = HugePageSize::from_mmap_flags(region.flags());
+        if let Some(huge_pages) = huge_pages {
+            // Huge pages can only be released as a whole, so the parts of the range which only
+            // cover a huge page partially are left in place. Regions start on a huge page.
+            let huge_page_size = huge_pages.bytes() as u64;
+            let range_start = guest_address.unchecked_offset_from(region.start_addr());
+            let start = (range_start + huge_page_size - 1) / huge_page_size * huge_page_size;
+            let end = (range_start + range_len) / huge_page_size * huge_page_size;
+            if start >= end {
+                return Ok(());
+            }
+            guest_address = region.start_addr().unchecked_add(start);
+            range_len = end - start;
+        }
+
         let phys_address = guest_memory
             .get_host_address(guest_address)
             .map_err(|_| RemoveRegionError::AddressTranslation)?;
 
+        // The range of the file backing shared memory has to be released as well, since the
+        // file keeps the pages otherwise.
+        if region.flags() & libc::MAP_SHARED != 0 {
+            if let Some(file_offset) = region.file_offset() {
+                let offset =
+                    file_offset.start() + guest_address.unchecked_offset_from(region.start_addr());
+                return punch_hole(file_offset.file(), offset, range_len);
+            }
+        }
+
+        if let Some(huge_pages) = huge_pages {
+            return remove_huge_page_range(phys_address, range_len, huge_pages);
+        }
+
         // Mmap a new anonymous region over the present one in order to create a hole.
         // This workaround is (only) needed after resuming from a snapshot because the guest memory
         // is mmaped from file as private and there is no `madvise` flag that works for this case.
@@ -117,6 +151,47 @@ pub(crate) fn remove_range(
     }
 }
 
+fn punch_hole(file: &File, offset: u64, len: u64) -> std::result::Result<(), RemoveRegionError> {
+    // Safe because the file is valid and we check the return value.
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret < 0 {
+        return Err(RemoveRegionError::FallocateFail(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// `madvise(MADV_DONTNEED)` doesn't work on huge pages with older host kernels, so the range is
+// replaced by a new anonymous mapping.
+fn remove_huge_page_range(
+    phys_address: *mut u8,
+    range_len: u64,
+    huge_pages: HugePageSize,
+) -> std::result::Result<(), RemoveRegionError> {
+    // Safe because the range is part of the guest memory, which is ours to remap.
+    let ret = unsafe {
+        libc::mmap(
+            phys_address as *mut _,
+            range_len as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_FIXED | libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | huge_pages.mmap_flags(),
+            -1,
+            0,
+        )
+    };
+    if ret == libc::MAP_FAILED {
+        return Err(RemoveRegionError::MmapFail(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use vm_memory::Bytes;
@@ -266,6 +341,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_remove_range_on_shared() {
+        let page_size: usize = 0x1000;
+        let mem =
+            vm_memory::create_memfd_guest_memory(&[(GuestAddress(0), 2 * page_size)], false, None)
+                .unwrap();
+        let memfd = mem.iter().next().unwrap().file_offset().unwrap().file();
+
+        // Fill the memory with ones.
+        let ones = vec![1u8; 2 * page_size];
+        mem.write(&ones[..], GuestAddress(0)).unwrap();
+
+        // Remove the first page.
+        assert!(remove_range(&mem, (GuestAddress(0), page_size as u64), false).is_ok());
+
+        // Check that the first page is zeroed, in the guest memory and in the memfd.
+        let mut actual_page = vec![0u8; page_size];
+        mem.read(actual_page.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(vec![0u8; page_size], actual_page);
+        let mut file_page = vec![1u8; page_size];
+        // Safe because the buffer is valid and we check the return value.
+        let ret = unsafe {
+            libc::pread(
+                memfd.as_raw_fd(),
+                file_page.as_mut_ptr() as *mut libc::c_void,
+                page_size,
+                0,
+            )
+        };
+        assert_eq!(ret, page_size as isize);
+        assert_eq!(vec![0u8; page_size], file_page);
+        // Check that the second page still contains ones.
+        mem.read(actual_page.as_mut_slice(), GuestAddress(page_size as u64))
+            .unwrap();
+        assert_eq!(vec![1u8; page_size], actual_page);
+    }
+
     /// -------------------------------------
     /// BEGIN PROPERTY BASED TESTING
     use proptest::prelude::*;
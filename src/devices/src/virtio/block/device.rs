@@ -9,27 +9,32 @@ use std::convert::From;
 use std::fs::{File, OpenOptions};
 use std::io::{Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
-use std::path::PathBuf;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use std::{cmp, result};
 
-use block_io::FileEngine;
-use logger::{error, warn, IncMetric, METRICS};
+use block_io::{qcow2, FileEngine, Qcow2Image};
+use logger::{error, warn, BlockDeviceMetrics, IncMetric, METRICS};
 use rate_limiter::{BucketUpdate, RateLimiter};
 use serde::{Deserialize, Serialize};
 use utils::eventfd::EventFd;
 use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
 use virtio_gen::virtio_blk::{
-    VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO, VIRTIO_BLK_ID_BYTES, VIRTIO_F_VERSION_1,
+    VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ,
+    VIRTIO_BLK_F_RO, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES, VIRTIO_F_VERSION_1,
 };
 use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
-use vm_memory::GuestMemoryMmap;
+use vm_memory::{ByteValued, GuestMemoryMmap};
 
 use super::super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK};
 use super::io::async_io;
 use super::request::*;
-use super::{io as block_io, Error, CONFIG_SPACE_SIZE, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE};
+use super::{
+    io as block_io, Error, MAX_DISCARD_SECTORS, MAX_NUM_QUEUES, MAX_WRITE_ZEROES_SECTORS,
+    QUEUE_SIZE, SECTOR_SHIFT, SECTOR_SIZE,
+};
 use crate::virtio::{IrqTrigger, IrqType};
 
 /// Configuration options for disk caching.
@@ -50,7 +55,7 @@ impl Default for CacheType {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum FileEngineType {
     /// Use an Async engine, based on io_uring.
     Async,
@@ -73,11 +78,64 @@ impl FileEngineType {
     }
 }
 
+/// Format of the disk image backing a block device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DiskFormat {
+    /// The disk image is exposed to the guest as is.
+    Raw,
+    /// The disk image is a qcow2 copy-on-write overlay of a raw base image.
+    Qcow2,
+}
+
+impl Default for DiskFormat {
+    fn default() -> Self {
+        Self::Raw
+    }
+}
+
+// Discard requests are advertised as aligned to 4KiB, the most common host file system block
+// size, so that punching holes actually releases host storage.
+const DISCARD_SECTOR_ALIGNMENT: u32 = 8;
+
+/// The virtio block configuration space, as defined by `struct virtio_blk_config` in the
+/// virtio specification.
+///
+/// Only the fields that correspond to features offered by Firecracker are populated, the rest
+/// are left zeroed. Firecracker only runs on little endian platforms, so the fields are already
+/// in the byte order the driver expects.
+#[derive(Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct ConfigSpace {
+    pub capacity: u64,
+    size_max: u32,
+    seg_max: u32,
+    geometry: [u8; 4],
+    blk_size: u32,
+    topology: [u8; 8],
+    writeback: u8,
+    unused0: u8,
+    num_queues: u16,
+    pub max_discard_sectors: u32,
+    pub max_discard_seg: u32,
+    pub discard_sector_alignment: u32,
+    pub max_write_zeroes_sectors: u32,
+    pub max_write_zeroes_seg: u32,
+    pub write_zeroes_may_unmap: u8,
+    unused1: [u8; 3],
+}
+
+// Safe because ConfigSpace only contains plain data.
+unsafe impl ByteValued for ConfigSpace {}
+
 /// Helper object for setting up all `Block` fields derived from its backing file.
 pub(crate) struct DiskProperties {
     cache_type: CacheType,
+    disk_format: DiskFormat,
+    // The alignment direct IO requires, if the backing file is accessed with direct IO.
+    direct_io: Option<usize>,
     file_path: String,
-    file_engine: FileEngine<PendingRequest>,
+    // One engine per queue, so that each queue has its own io_uring ring with the async engine.
+    file_engines: Vec<FileEngine<PendingRequest>>,
     nsectors: u64,
     image_id: [u8; VIRTIO_BLK_ID_BYTES as usize],
 }
@@ -88,15 +146,43 @@ impl DiskProperties {
         is_disk_read_only: bool,
         cache_type: CacheType,
         file_engine_type: FileEngineType,
+        disk_format: DiskFormat,
+        num_queues: u16,
+        direct_io: bool,
     ) -> result::Result<Self, Error> {
+        // The qcow2 metadata is accessed with arbitrary offsets and lengths.
+        if direct_io && disk_format == DiskFormat::Qcow2 {
+            return Err(Error::Qcow2(qcow2::Error::UnsupportedFeature("direct IO")));
+        }
+
         let mut disk_image = OpenOptions::new()
             .read(true)
             .write(!is_disk_read_only)
+            .custom_flags(if direct_io { libc::O_DIRECT } else { 0 })
             .open(PathBuf::from(&disk_image_path))
             .map_err(Error::BackingFile)?;
-        let disk_size = disk_image
-            .seek(SeekFrom::End(0))
-            .map_err(Error::BackingFile)? as u64;
+        let (disk_size, qcow2) = match disk_format {
+            DiskFormat::Raw => (
+                disk_image
+                    .seek(SeekFrom::End(0))
+                    .map_err(Error::BackingFile)? as u64,
+                None,
+            ),
+            // The qcow2 metadata is cached by the engine, so it can't be shared between queues.
+            DiskFormat::Qcow2 if num_queues > 1 => {
+                return Err(Error::Qcow2(qcow2::Error::UnsupportedFeature(
+                    "multiple queues",
+                )))
+            }
+            DiskFormat::Qcow2 => {
+                let image = Qcow2Image::open(
+                    disk_image.try_clone().map_err(Error::BackingFile)?,
+                    Path::new(&disk_image_path),
+                )
+                .map_err(Error::Qcow2)?;
+                (image.virtual_size(), Some(image))
+            }
+        };
 
         // We only support disk size, which uses the first two words of the configuration space.
         // If the image is not a multiple of the sector size, the tail bits are not exposed.
@@ -108,27 +194,62 @@ impl DiskProperties {
             );
         }
 
+        // Direct IO has to be aligned to the logical block size of the backing storage.
+        let direct_io = match direct_io {
+            true => Some(block_io::direct::alignment(&disk_image).map_err(Error::BackingFile)?),
+            false => None,
+        };
+
+        let image_id = Self::build_disk_image_id(&disk_image);
+        let extra_files = (1..num_queues)
+            .map(|_| disk_image.try_clone())
+            .collect::<result::Result<Vec<_>, _>>()
+            .map_err(Error::BackingFile)?;
+        let mut file_engines = vec![match direct_io {
+            Some(alignment) => {
+                FileEngine::from_direct_file(disk_image, alignment, file_engine_type)
+            }
+            None => FileEngine::from_disk(disk_image, qcow2, file_engine_type),
+        }
+        .map_err(Error::FileEngine)?];
+        for file in extra_files {
+            file_engines.push(
+                match direct_io {
+                    Some(alignment) => {
+                        FileEngine::from_direct_file(file, alignment, file_engine_type)
+                    }
+                    None => FileEngine::from_file(file, file_engine_type),
+                }
+                .map_err(Error::FileEngine)?,
+            );
+        }
+
         Ok(Self {
             cache_type,
+            disk_format,
+            direct_io,
             nsectors: disk_size >> SECTOR_SHIFT,
-            image_id: Self::build_disk_image_id(&disk_image),
+            image_id,
             file_path: disk_image_path,
-            file_engine: FileEngine::from_file(disk_image, file_engine_type)
-                .map_err(Error::FileEngine)?,
+            file_engines,
         })
     }
 
-    pub fn file_engine(&self) -> &FileEngine<PendingRequest> {
-        &self.file_engine
+    pub fn file_engines(&self) -> &[FileEngine<PendingRequest>] {
+        &self.file_engines
     }
 
-    pub fn file_engine_mut(&mut self) -> &mut FileEngine<PendingRequest> {
-        &mut self.file_engine
+    pub fn file_engines_mut(&mut self) -> &mut [FileEngine<PendingRequest>] {
+        &mut self.file_engines
+    }
+
+    pub fn file_engine_mut(&mut self, queue_index: usize) -> &mut FileEngine<PendingRequest> {
+        &mut self.file_engines[queue_index]
     }
 
     #[cfg(test)]
     pub fn file(&self) -> &File {
-        self.file_engine.file()
+        self.file_engines[0].file()
     }
 
     pub fn nsectors(&self) -> u64 {
@@ -175,19 +296,41 @@ impl DiskProperties {
 
     /// Provides vec containing the virtio block configuration space
     /// buffer. The config space is populated with the disk size based
-    /// on the backing file size.
+    /// on the backing file size, the block size direct IO requires, the
+    /// discard and write zeroes limits and the number of queues.
     pub fn virtio_block_config_space(&self) -> Vec<u8> {
-        // The config space is little endian.
-        let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
-        for i in 0..CONFIG_SPACE_SIZE {
-            config.push((self.nsectors >> (8 * i)) as u8);
-        }
-        config
+        let config = ConfigSpace {
+            capacity: self.nsectors,
+            blk_size: self.direct_io.unwrap_or(0) as u32,
+            // We only handle a single segment per discard or write zeroes request.
+            max_discard_sectors: MAX_DISCARD_SECTORS,
+            max_discard_seg: 1,
+            discard_sector_alignment: DISCARD_SECTOR_ALIGNMENT,
+            max_write_zeroes_sectors: MAX_WRITE_ZEROES_SECTORS,
+            max_write_zeroes_seg: 1,
+            write_zeroes_may_unmap: 1,
+            num_queues: self.file_engines.len() as u16,
+            ..Default::default()
+        };
+        config.as_slice().to_vec()
     }
 
     pub fn cache_type(&self) -> CacheType {
         self.cache_type
     }
+
+    pub fn disk_format(&self) -> DiskFormat {
+        self.disk_format
+    }
+
+    pub fn direct_io(&self) -> bool {
+        self.direct_io.is_some()
+    }
+
+    /// The alignment of the requests, when direct IO is enabled.
+    pub fn direct_io_alignment(&self) -> Option<usize> {
+        self.direct_io
+    }
 }
 
 /// Virtio device for exposing block level read/write operations on a host file.
@@ -203,7 +346,7 @@ pub struct Block {
 
     // Transport related fields.
     pub(crate) queues: Vec<Queue>,
-    pub(crate) queue_evts: [EventFd; 1],
+    pub(crate) queue_evts: Vec<EventFd>,
     pub(crate) device_state: DeviceState,
     pub(crate) irq_trigger: IrqTrigger,
 
@@ -212,7 +355,9 @@ pub struct Block {
     pub(crate) partuuid: Option<String>,
     pub(crate) root_device: bool,
     pub(crate) rate_limiter: RateLimiter,
-    is_io_engine_throttled: bool,
+    pub(crate) metrics: Arc<BlockDeviceMetrics>,
+    // Whether the IO engine of each queue is waiting for completions before accepting requests.
+    is_io_engine_throttled: Vec<bool>,
 }
 
 macro_rules! unwrap_async_file_engine_or_return {
@@ -241,12 +386,22 @@ impl Block {
         is_disk_root: bool,
         rate_limiter: RateLimiter,
         file_engine_type: FileEngineType,
+        disk_format: DiskFormat,
+        num_queues: u16,
+        direct_io: bool,
     ) -> result::Result<Block, Error> {
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+
         let disk_properties = DiskProperties::new(
             disk_image_path,
             is_disk_read_only,
             cache_type,
             file_engine_type,
+            disk_format,
+            num_queues,
+            direct_io,
         )?;
 
         let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
@@ -257,17 +412,34 @@ impl Block {
 
         if is_disk_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else if disk_format == DiskFormat::Raw {
+            // Deallocating ranges of a qcow2 overlay is not supported.
+            avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
         };
 
-        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?];
+        if num_queues > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
+
+        // The guest has to issue requests aligned to the block size direct IO requires.
+        if direct_io {
+            avail_features |= 1u64 << VIRTIO_BLK_F_BLK_SIZE;
+        }
+
+        let queue_evts = (0..num_queues)
+            .map(|_| EventFd::new(libc::EFD_NONBLOCK))
+            .collect::<result::Result<Vec<_>, _>>()
+            .map_err(Error::EventFd)?;
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
+        let metrics = METRICS.block.alloc(&id);
 
         Ok(Block {
             id,
             root_device: is_disk_root,
             partuuid,
             rate_limiter,
+            metrics,
             config_space: disk_properties.virtio_block_config_space(),
             disk: disk_properties,
             avail_features,
@@ -277,35 +449,37 @@ impl Block {
             device_state: DeviceState::Inactive,
             irq_trigger: IrqTrigger::new().map_err(Error::IrqTrigger)?,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
-            is_io_engine_throttled: false,
+            is_io_engine_throttled: vec![false; num_queues as usize],
         })
     }
 
-    pub(crate) fn process_queue_event(&mut self) {
-        METRICS.block.queue_event_count.inc();
-        if let Err(err) = self.queue_evts[0].read() {
+    pub(crate) fn process_queue_event(&mut self, queue_index: usize) {
+        self.metrics.queue_event_count.inc();
+        if let Err(err) = self.queue_evts[queue_index].read() {
             error!("Failed to get queue event: {:?}", err);
-            METRICS.block.event_fails.inc();
+            self.metrics.event_fails.inc();
         } else if self.rate_limiter.is_blocked() {
-            METRICS.block.rate_limiter_throttled_events.inc();
-        } else if self.is_io_engine_throttled {
-            METRICS.block.io_engine_throttled_events.inc();
+            self.metrics.rate_limiter_throttled_events.inc();
+        } else if self.is_io_engine_throttled[queue_index] {
+            self.metrics.io_engine_throttled_events.inc();
         } else {
-            self.process_virtio_queues();
+            self.process_queue(queue_index);
         }
     }
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        self.process_queue(0);
+        for queue_index in 0..self.queues.len() {
+            self.process_queue(queue_index);
+        }
     }
 
     pub(crate) fn process_rate_limiter_event(&mut self) {
-        METRICS.block.rate_limiter_event_count.inc();
+        self.metrics.rate_limiter_event_count.inc();
         // Upon rate limiter event, call the rate limiter handler
-        // and restart processing the queue.
+        // and restart processing the queues.
         if self.rate_limiter.event_handler().is_ok() {
-            self.process_queue(0);
+            self.process_virtio_queues();
         }
     }
 
@@ -315,6 +489,7 @@ impl Block {
         len: u32,
         mem: &GuestMemoryMmap,
         irq_trigger: &IrqTrigger,
+        metrics: &BlockDeviceMetrics,
     ) {
         queue.add_used(mem, index, len).unwrap_or_else(|err| {
             error!("Failed to add available descriptor head {}: {}", index, err)
@@ -322,7 +497,7 @@ impl Block {
 
         if queue.prepare_kick(mem) {
             irq_trigger.trigger_irq(IrqType::Vring).unwrap_or_else(|_| {
-                METRICS.block.event_fails.inc();
+                metrics.event_fails.inc();
             });
         }
     }
@@ -335,22 +510,27 @@ impl Block {
         let mut used_any = false;
 
         while let Some(head) = queue.pop_or_enable_notification(mem) {
-            let processing_result = match Request::parse(&head, mem, self.disk.nsectors()) {
+            let processing_result = match Request::parse(
+                &head,
+                mem,
+                self.disk.nsectors(),
+                self.disk.direct_io_alignment(),
+            ) {
                 Ok(request) => {
                     if request.rate_limit(&mut self.rate_limiter) {
                         // Stop processing the queue and return this descriptor chain to the
                         // avail ring, for later processing.
                         queue.undo_pop();
-                        METRICS.block.rate_limiter_throttled_events.inc();
+                        self.metrics.rate_limiter_throttled_events.inc();
                         break;
                     }
 
                     used_any = true;
-                    request.process(&mut self.disk, head.index, mem)
+                    request.process(&mut self.disk, queue_index, head.index, mem, &self.metrics)
                 }
                 Err(err) => {
                     error!("Failed to parse available descriptor chain: {:?}", err);
-                    METRICS.block.execute_fails.inc();
+                    self.metrics.execute_fails.inc();
                     ProcessingResult::Executed(FinishedRequest {
                         num_bytes_to_mem: 0,
                         desc_idx: head.index,
@@ -362,7 +542,7 @@ impl Block {
                 ProcessingResult::Submitted => {}
                 ProcessingResult::Throttled => {
                     queue.undo_pop();
-                    self.is_io_engine_throttled = true;
+                    self.is_io_engine_throttled[queue_index] = true;
                     break;
                 }
                 ProcessingResult::Executed(finished) => {
@@ -372,28 +552,29 @@ impl Block {
                         finished.num_bytes_to_mem,
                         mem,
                         &self.irq_trigger,
+                        &self.metrics,
                     );
                 }
             }
         }
 
-        if let FileEngine::Async(engine) = self.disk.file_engine_mut() {
+        if let FileEngine::Async(engine) = self.disk.file_engine_mut(queue_index) {
             if let Err(err) = engine.kick_submission_queue() {
                 error!("Error submitting pending block requests: {:?}", err);
             }
         }
 
         if !used_any {
-            METRICS.block.no_avail_buffer.inc();
+            self.metrics.no_avail_buffer.inc();
         }
     }
 
-    fn process_async_completion_queue(&mut self) {
-        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engine);
+    fn process_async_completion_queue(&mut self, queue_index: usize) {
+        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engines[queue_index]);
 
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
-        let queue = &mut self.queues[0];
+        let queue = &mut self.queues[queue_index];
 
         loop {
             match engine.pop(mem) {
@@ -415,7 +596,7 @@ impl Block {
                             ))),
                         ),
                     };
-                    let finished = pending.finish(mem, res);
+                    let finished = pending.finish(mem, res, &self.metrics);
 
                     Self::add_used_descriptor(
                         queue,
@@ -423,23 +604,24 @@ impl Block {
                         finished.num_bytes_to_mem,
                         mem,
                         &self.irq_trigger,
+                        &self.metrics,
                     );
                 }
             }
         }
     }
 
-    pub fn process_async_completion_event(&mut self) {
-        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engine);
+    pub fn process_async_completion_event(&mut self, queue_index: usize) {
+        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engines[queue_index]);
 
         if let Err(err) = engine.completion_evt().read() {
             error!("Failed to get async completion event: {:?}", err);
         } else {
-            self.process_async_completion_queue();
+            self.process_async_completion_queue(queue_index);
 
-            if self.is_io_engine_throttled {
-                self.is_io_engine_throttled = false;
-                self.process_queue(0);
+            if self.is_io_engine_throttled[queue_index] {
+                self.is_io_engine_throttled[queue_index] = false;
+                self.process_queue(queue_index);
             }
         }
     }
@@ -451,6 +633,9 @@ impl Block {
             self.is_read_only(),
             self.cache_type(),
             self.file_engine_type(),
+            self.disk_format(),
+            self.num_queues(),
+            self.direct_io(),
         )?;
         self.disk = disk_properties;
         self.config_space = self.disk.virtio_block_config_space();
@@ -458,7 +643,7 @@ impl Block {
         // Kick the driver to pick up the changes.
         self.irq_trigger.trigger_irq(IrqType::Config).unwrap();
 
-        METRICS.block.update_count.inc();
+        self.metrics.update_count.inc();
         Ok(())
     }
 
@@ -497,32 +682,58 @@ impl Block {
         self.disk.cache_type()
     }
 
+    /// Specifies the format of the block device's disk image.
+    pub fn disk_format(&self) -> DiskFormat {
+        self.disk.disk_format()
+    }
+
+    /// Specifies if the backing file of this block device is accessed with direct IO.
+    pub fn direct_io(&self) -> bool {
+        self.disk.direct_io()
+    }
+
+    /// Provides the number of request queues of this block device.
+    pub fn num_queues(&self) -> u16 {
+        self.queues.len() as u16
+    }
+
     /// Provides non-mutable reference to this device's rate limiter.
     pub fn rate_limiter(&self) -> &RateLimiter {
         &self.rate_limiter
     }
 
     pub fn file_engine_type(&self) -> FileEngineType {
-        match self.disk.file_engine() {
+        // All the queues use the same type of engine.
+        match self.disk.file_engines()[0] {
             FileEngine::Sync(_) => FileEngineType::Sync,
             FileEngine::Async(_) => FileEngineType::Async,
         }
     }
 
     fn drain_and_flush(&mut self, discard: bool) {
-        if let Err(err) = self.disk.file_engine_mut().drain_and_flush(discard) {
-            error!("Failed to drain ops and flush block data: {:?}", err);
+        for engine in self.disk.file_engines_mut() {
+            if let Err(err) = engine.drain_and_flush(discard) {
+                error!("Failed to drain ops and flush block data: {:?}", err);
+            }
         }
     }
 
     pub fn prepare_save(&mut self) {
+        self.drain();
+    }
+
+    /// Completes all in-flight requests and flushes the backing file. Used before saving
+    /// the device state and before hot-unplugging the device.
+    pub fn drain(&mut self) {
         if !self.is_activated() {
             return;
         }
 
         self.drain_and_flush(false);
-        if let FileEngine::Async(_engine) = self.disk.file_engine_mut() {
-            self.process_async_completion_queue();
+        if self.file_engine_type() == FileEngineType::Async {
+            for queue_index in 0..self.queues.len() {
+                self.process_async_completion_queue(queue_index);
+            }
         }
     }
 }
@@ -569,7 +780,7 @@ impl VirtioDevice for Block {
         let config_len = self.config_space.len() as u64;
         if offset >= config_len {
             error!("Failed to read config space");
-            METRICS.block.cfg_fails.inc();
+            self.metrics.cfg_fails.inc();
             return;
         }
         if let Some(end) = offset.checked_add(data.len() as u64) {
@@ -584,7 +795,7 @@ impl VirtioDevice for Block {
         let config_len = self.config_space.len() as u64;
         if offset + data_len > config_len {
             error!("Failed to write config space");
-            METRICS.block.cfg_fails.inc();
+            self.metrics.cfg_fails.inc();
             return;
         }
 
@@ -616,8 +827,10 @@ impl Drop for Block {
     fn drop(&mut self) {
         match self.disk.cache_type {
             CacheType::Unsafe => {
-                if let Err(err) = self.disk.file_engine_mut().drain(true) {
-                    error!("Failed to drain ops on drop: {:?}", err);
+                for engine in self.disk.file_engines_mut() {
+                    if let Err(err) = engine.drain(true) {
+                        error!("Failed to drain ops on drop: {:?}", err);
+                    }
                 }
             }
             CacheType::Writeback => {
@@ -632,14 +845,18 @@ pub(crate) mod tests {
     use std::fs::metadata;
     use std::io::Read;
     use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
     use std::time::Duration;
     use std::{thread, u32};
 
     use rate_limiter::TokenType;
     use utils::skip_if_io_uring_unsupported;
+    use utils::tempdir::TempDir;
     use utils::tempfile::TempFile;
     use vm_memory::{Address, Bytes, GuestAddress};
 
+    use super::super::io::qcow2::tests::create_overlay;
+    use super::super::{CONFIG_SPACE_SIZE, DEFAULT_NUM_QUEUES};
     use super::*;
     use crate::check_metric_after_block;
     use crate::virtio::block::test_utils::{
@@ -663,6 +880,9 @@ pub(crate) mod tests {
             true,
             CacheType::Unsafe,
             default_engine_type_for_kv(),
+            DiskFormat::Raw,
+            DEFAULT_NUM_QUEUES,
+            false,
         )
         .unwrap();
 
@@ -670,9 +890,14 @@ pub(crate) mod tests {
         assert_eq!(disk_properties.nsectors, num_sectors);
         let cfg = disk_properties.virtio_block_config_space();
         assert_eq!(cfg.len(), CONFIG_SPACE_SIZE);
-        for (i, byte) in cfg.iter().enumerate() {
+        // The capacity is stored in the first 8 bytes of the config space, in little endian.
+        for (i, byte) in cfg[..8].iter().enumerate() {
             assert_eq!(*byte, (num_sectors >> (8 * i)) as u8);
         }
+        let config_space = ConfigSpace::from_slice(&cfg).unwrap();
+        assert_eq!({ config_space.max_discard_seg }, 1);
+        assert_eq!({ config_space.max_write_zeroes_seg }, 1);
+        assert_eq!({ config_space.write_zeroes_may_unmap }, 1);
         // Testing `backing_file.virtio_block_disk_image_id()` implies
         // duplicating that logic in tests, so skipping it.
 
@@ -681,17 +906,191 @@ pub(crate) mod tests {
             true,
             CacheType::Unsafe,
             default_engine_type_for_kv(),
+            DiskFormat::Raw,
+            DEFAULT_NUM_QUEUES,
+            false,
         )
         .is_err());
     }
 
+    #[test]
+    fn test_qcow2_disk() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.as_path().join("base");
+        let overlay_path = dir.as_path().join("overlay");
+        File::create(&base_path).unwrap().set_len(0x1000).unwrap();
+        // The virtual disk can be larger than the base image.
+        create_overlay(&overlay_path, &base_path, 0x10000);
+
+        let new_block = |path: &Path, num_queues| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                path.to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                default_engine_type_for_kv(),
+                DiskFormat::Qcow2,
+                num_queues,
+                false,
+            )
+        };
+
+        let block = new_block(&overlay_path, DEFAULT_NUM_QUEUES).unwrap();
+        assert_eq!(block.disk_format(), DiskFormat::Qcow2);
+        assert_eq!(block.disk.nsectors(), 0x10000 >> SECTOR_SHIFT);
+        // Discard and write zeroes are not offered for qcow2 overlays.
+        assert_eq!(
+            block.avail_features(),
+            (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+        );
+
+        // Overlays can only be used by a single queue.
+        assert!(matches!(
+            new_block(&overlay_path, 2),
+            Err(Error::Qcow2(qcow2::Error::UnsupportedFeature(_)))
+        ));
+        // A raw image is not a valid qcow2 image.
+        assert!(matches!(
+            new_block(&base_path, DEFAULT_NUM_QUEUES),
+            Err(Error::Qcow2(_))
+        ));
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let new_block = |disk_format| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                f.as_path().to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                default_engine_type_for_kv(),
+                disk_format,
+                2,
+                true,
+            )
+        };
+
+        // Direct IO can't be used for qcow2 overlays.
+        assert!(matches!(
+            new_block(DiskFormat::Qcow2),
+            Err(Error::Qcow2(qcow2::Error::UnsupportedFeature(_)))
+        ));
+
+        let block = match new_block(DiskFormat::Raw) {
+            Ok(block) => block,
+            // Not all file systems support direct IO.
+            Err(Error::BackingFile(err)) if err.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(err) => panic!("Failed to create block device: {:?}", err),
+        };
+        assert!(block.direct_io());
+        assert_eq!(block.disk.nsectors(), 0x1000 >> SECTOR_SHIFT);
+        // The guest is told about the block size direct IO requires.
+        assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_BLK_SIZE), 0);
+        let config_space = ConfigSpace::from_slice(&block.config_space).unwrap();
+        assert_eq!(
+            { config_space.blk_size },
+            block_io::direct::DEFAULT_DIRECT_IO_ALIGNMENT as u32
+        );
+        // All the queues share the direct IO backing file.
+        for engine in block.disk.file_engines() {
+            // Safe because the file descriptor is valid.
+            let flags = unsafe { libc::fcntl(engine.file().as_raw_fd(), libc::F_GETFL) };
+            assert_ne!(flags & libc::O_DIRECT, 0);
+        }
+    }
+
+    #[test]
+    fn test_multiqueue() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let new_block = |num_queues| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                f.as_path().to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                default_engine_type_for_kv(),
+                DiskFormat::Raw,
+                num_queues,
+                false,
+            )
+        };
+
+        for num_queues in [0, MAX_NUM_QUEUES + 1] {
+            assert!(matches!(
+                new_block(num_queues),
+                Err(Error::InvalidNumQueues(n)) if n == num_queues
+            ));
+        }
+
+        // A single queue device doesn't offer the MQ feature.
+        let block = new_block(DEFAULT_NUM_QUEUES).unwrap();
+        assert_eq!(block.avail_features() & (1u64 << VIRTIO_BLK_F_MQ), 0);
+
+        let mut block = new_block(2).unwrap();
+        assert_eq!(block.num_queues(), 2);
+        assert_eq!(block.queues().len(), 2);
+        assert_eq!(block.queue_events().len(), 2);
+        assert_eq!(block.disk.file_engines().len(), 2);
+        assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_MQ), 0);
+        // The number of queues is stored at offset 34 of the config space.
+        let mut num_queues = [0u8; 2];
+        block.read_config(34, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), 2);
+
+        // Requests on the second queue are served by its own engine.
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 1, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        let rand_data = utils::rand::rand_alphanumerics(512).as_bytes().to_vec();
+
+        mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+            .unwrap();
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1].len.set(512);
+        mem.write_slice(&rand_data, data_addr).unwrap();
+
+        block.queue_evts[1].write(1).unwrap();
+        block.process_queue_event(1);
+        simulate_async_completion_event(&mut block, true);
+
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        let mut buf = [0u8; 512];
+        block.disk.file().seek(SeekFrom::Start(0)).unwrap();
+        block.disk.file().read_exact(&mut buf).unwrap();
+        assert_eq!(buf, rand_data.as_slice());
+    }
+
     #[test]
     fn test_virtio_features() {
         let mut block = default_block(default_engine_type_for_kv());
 
         assert_eq!(block.device_type(), TYPE_BLOCK);
 
-        let features: u64 = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+        let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_BLK_F_DISCARD)
+            | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
 
         assert_eq!(block.avail_features_by_page(0), features as u32);
         assert_eq!(block.avail_features_by_page(1), (features >> 32) as u32);
@@ -710,18 +1109,24 @@ pub(crate) mod tests {
     fn test_virtio_read_config() {
         let block = default_block(default_engine_type_for_kv());
 
-        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        let mut actual_config_space = [0u8; 8];
         block.read_config(0, &mut actual_config_space);
         // This will read the number of sectors.
         // The block's backing file size is 0x1000, so there are 8 (4096/512) sectors.
         // The config space is little endian.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; 8] = [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
         assert_eq!(actual_config_space, expected_config_space);
 
+        // Read the discard sector alignment.
+        let mut discard_sector_alignment = [0u8; 4];
+        block.read_config(44, &mut discard_sector_alignment);
+        assert_eq!(
+            u32::from_le_bytes(discard_sector_alignment),
+            DISCARD_SECTOR_ALIGNMENT
+        );
+
         // Invalid read.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
+        let expected_config_space: [u8; 8] = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
         actual_config_space = expected_config_space;
         block.read_config(CONFIG_SPACE_SIZE as u64 + 1, &mut actual_config_space);
 
@@ -733,11 +1138,10 @@ pub(crate) mod tests {
     fn test_virtio_write_config() {
         let mut block = default_block(default_engine_type_for_kv());
 
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; 8] = [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
         block.write_config(0, &expected_config_space);
 
-        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        let mut actual_config_space = [0u8; 8];
         block.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
 
@@ -751,7 +1155,7 @@ pub(crate) mod tests {
 
         // Invalid write.
         let new_config_space = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
-        block.write_config(5, &new_config_space);
+        block.write_config(CONFIG_SPACE_SIZE as u64 - 3, &new_config_space);
         // Make sure nothing got written.
         block.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
@@ -925,6 +1329,7 @@ pub(crate) mod tests {
     #[test]
     fn test_end_of_region() {
         let mut block = default_block(default_engine_type_for_kv());
+        let metrics = block.metrics.clone();
         let mem = default_mem();
         let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
         set_queue(&mut block, 0, vq.create_queue());
@@ -944,7 +1349,7 @@ pub(crate) mod tests {
             .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
 
         check_metric_after_block!(
-            &METRICS.block.read_count,
+            &metrics.read_count,
             1,
             simulate_queue_and_async_completion_events(&mut block, true)
         );
@@ -959,6 +1364,7 @@ pub(crate) mod tests {
     #[test]
     fn test_read_write() {
         let mut block = default_block(default_engine_type_for_kv());
+        let metrics = block.metrics.clone();
         let mem = default_mem();
         let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
         set_queue(&mut block, 0, vq.create_queue());
@@ -1015,7 +1421,7 @@ pub(crate) mod tests {
             vq.used.idx.set(0);
 
             check_metric_after_block!(
-                &METRICS.block.invalid_reqs_count,
+                &metrics.invalid_reqs_count,
                 1,
                 simulate_queue_and_async_completion_events(&mut block, true)
             );
@@ -1043,7 +1449,7 @@ pub(crate) mod tests {
             mem.write_slice(&rand_data[..512], data_addr).unwrap();
 
             check_metric_after_block!(
-                &METRICS.block.write_count,
+                &metrics.write_count,
                 1,
                 simulate_queue_and_async_completion_events(&mut block, true)
             );
@@ -1094,7 +1500,7 @@ pub(crate) mod tests {
             mem.write_slice(empty_data.as_slice(), data_addr).unwrap();
 
             check_metric_after_block!(
-                &METRICS.block.read_count,
+                &metrics.read_count,
                 1,
                 simulate_queue_and_async_completion_events(&mut block, true)
             );
@@ -1236,7 +1642,7 @@ pub(crate) mod tests {
                 .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
 
             check_metric_after_block!(
-                &METRICS.block.invalid_reqs_count,
+                &metrics.invalid_reqs_count,
                 1,
                 simulate_queue_and_async_completion_events(&mut block, true)
             );
@@ -1255,6 +1661,11 @@ pub(crate) mod tests {
     #[test]
     fn test_flush() {
         let mut block = default_block(default_engine_type_for_kv());
+        let metrics = block.metrics.clone();
+        assert!(Arc::ptr_eq(
+            &metrics,
+            &METRICS.block.get(block.id()).unwrap()
+        ));
         let mem = default_mem();
         let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
         set_queue(&mut block, 0, vq.create_queue());
@@ -1271,7 +1682,11 @@ pub(crate) mod tests {
             mem.write_obj::<u32>(VIRTIO_BLK_T_FLUSH, request_type_addr)
                 .unwrap();
 
-            simulate_queue_and_async_completion_events(&mut block, true);
+            check_metric_after_block!(
+                &metrics.flush_latency_us,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
             assert_eq!(vq.used.idx.get(), 1);
             assert_eq!(vq.used.ring[0].get().id, 0);
             assert_eq!(vq.used.ring[0].get().len, 1);
@@ -1296,6 +1711,105 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_discard_write_zeroes() {
+        let mut block = default_block(default_engine_type_for_kv());
+        let metrics = block.metrics.clone();
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        let segment_len = std::mem::size_of::<DiscardWriteZeroesSegment>() as u32;
+
+        // Fill the backing file with non-zero data.
+        let rand_data = utils::rand::rand_alphanumerics(0x1000).as_bytes().to_vec();
+        block.disk.file().write_all(&rand_data).unwrap();
+
+        // Discard the first two sectors.
+        {
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].len.set(segment_len);
+            mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(0, 2, 0), data_addr)
+                .unwrap();
+
+            check_metric_after_block!(
+                &metrics.discard_count,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            // Only the status byte is written.
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(
+                mem.read_obj::<u8>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK as u8
+            );
+
+            let mut buf = vec![0u8; 2 * SECTOR_SIZE as usize];
+            block.disk.file().seek(SeekFrom::Start(0)).unwrap();
+            block.disk.file().read_exact(&mut buf).unwrap();
+            assert!(buf.iter().all(|&b| b == 0));
+        }
+
+        // Zero out the next two sectors, without deallocating them.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj::<u32>(VIRTIO_BLK_T_WRITE_ZEROES, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(2, 2, 0), data_addr)
+                .unwrap();
+
+            check_metric_after_block!(
+                &metrics.write_zeroes_count,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(
+                mem.read_obj::<u8>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK as u8
+            );
+
+            let mut buf = vec![0u8; 2 * SECTOR_SIZE as usize];
+            block
+                .disk
+                .file()
+                .seek(SeekFrom::Start(2 * SECTOR_SIZE))
+                .unwrap();
+            block.disk.file().read_exact(&mut buf).unwrap();
+            assert!(buf.iter().all(|&b| b == 0));
+
+            // The rest of the file is untouched and the file size is preserved.
+            let mut buf = vec![0u8; 4 * SECTOR_SIZE as usize];
+            block.disk.file().read_exact(&mut buf).unwrap();
+            assert_eq!(buf, rand_data[4 * SECTOR_SIZE as usize..]);
+            assert_eq!(block.disk.file().metadata().unwrap().len(), 0x1000);
+        }
+
+        // A range that goes beyond the end of the disk is rejected.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(7, 2, 0), data_addr)
+                .unwrap();
+
+            simulate_queue_event(&mut block, Some(true));
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+        }
+    }
+
     #[test]
     fn test_get_device_id() {
         let mut block = default_block(default_engine_type_for_kv());
@@ -1433,25 +1947,25 @@ pub(crate) mod tests {
             // Run scenario that doesn't trigger FullSq Error: Add sq_size flush requests.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             simulate_async_completion_event(&mut block, true);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES, &mem, &vq);
 
             // Run scenario that triggers FullSqError : Add sq_size + 10 flush requests.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES + 10);
             simulate_queue_event(&mut block, Some(false));
-            assert!(block.is_io_engine_throttled);
+            assert!(block.is_io_engine_throttled[0]);
             // When the async_completion_event is triggered:
             // 1. sq_size requests should be processed processed.
             // 2. is_io_engine_throttled should be set back to false.
             // 3. process_queue() should be called again.
             simulate_async_completion_event(&mut block, true);
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES, &mem, &vq);
             // check that process_queue() was called again resulting in the processing of the
             // remaining 10 ops.
             simulate_async_completion_event(&mut block, true);
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES + 10, &mem, &vq);
         }
 
@@ -1467,18 +1981,18 @@ pub(crate) mod tests {
             // completion. Then try to push another entry.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             thread::sleep(Duration::from_millis(150));
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             thread::sleep(Duration::from_millis(150));
 
             add_flush_requests_batch(&mut block, &mem, &vq, 1);
             simulate_queue_event(&mut block, Some(false));
-            assert!(block.is_io_engine_throttled);
+            assert!(block.is_io_engine_throttled[0]);
             simulate_async_completion_event(&mut block, true);
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES * 2, &mem, &vq);
         }
     }
@@ -1503,6 +2017,7 @@ pub(crate) mod tests {
     #[test]
     fn test_bandwidth_rate_limiter() {
         let mut block = default_block(default_engine_type_for_kv());
+        let metrics = block.metrics.clone();
         let mem = default_mem();
         let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
         set_queue(&mut block, 0, vq.create_queue());
@@ -1531,7 +2046,7 @@ pub(crate) mod tests {
         {
             // Trigger the attempt to write.
             check_metric_after_block!(
-                &METRICS.block.rate_limiter_throttled_events,
+                &metrics.rate_limiter_throttled_events,
                 1,
                 simulate_queue_event(&mut block, Some(false))
             );
@@ -1549,7 +2064,7 @@ pub(crate) mod tests {
         // Following write procedure should succeed because bandwidth should now be available.
         {
             check_metric_after_block!(
-                &METRICS.block.rate_limiter_throttled_events,
+                &metrics.rate_limiter_throttled_events,
                 0,
                 block.process_rate_limiter_event()
             );
@@ -1569,6 +2084,7 @@ pub(crate) mod tests {
     #[test]
     fn test_ops_rate_limiter() {
         let mut block = default_block(default_engine_type_for_kv());
+        let metrics = block.metrics.clone();
         let mem = default_mem();
         let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
         set_queue(&mut block, 0, vq.create_queue());
@@ -1597,7 +2113,7 @@ pub(crate) mod tests {
         {
             // Trigger the attempt to write.
             check_metric_after_block!(
-                &METRICS.block.rate_limiter_throttled_events,
+                &metrics.rate_limiter_throttled_events,
                 1,
                 simulate_queue_event(&mut block, Some(false))
             );
@@ -1612,7 +2128,7 @@ pub(crate) mod tests {
         {
             // Trigger the attempt to write.
             check_metric_after_block!(
-                &METRICS.block.rate_limiter_throttled_events,
+                &metrics.rate_limiter_throttled_events,
                 1,
                 simulate_queue_event(&mut block, Some(false))
             );
@@ -1630,7 +2146,7 @@ pub(crate) mod tests {
         // Following write procedure should succeed because ops budget should now be available.
         {
             check_metric_after_block!(
-                &METRICS.block.rate_limiter_throttled_events,
+                &metrics.rate_limiter_throttled_events,
                 0,
                 block.process_rate_limiter_event()
             );
@@ -5,16 +5,23 @@ use std::fs::File;
 use std::marker::PhantomData;
 use std::os::unix::io::AsRawFd;
 
-use io_uring::operation::{Cqe, OpCode, Operation};
+use io_uring::operation::{Cqe, FixedFd, OpCode, Operation};
 use io_uring::restriction::Restriction;
 use io_uring::{Error as IoUringError, IoUring};
-use logger::log_dev_preview_warning;
+use logger::{error, log_dev_preview_warning};
 use utils::eventfd::EventFd;
-use vm_memory::{mark_dirty_mem, GuestAddress, GuestMemory, GuestMemoryMmap};
+use vm_memory::{mark_dirty_mem, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
 
-use crate::virtio::block::io::UserDataError;
+use crate::virtio::block::io::direct::{self, AlignedBuffer};
+use crate::virtio::block::io::qcow2::{self, Extent, ExtentKind, Qcow2Image};
+use crate::virtio::block::io::{FileEngineOk, UserDataError, UserDataOk};
 use crate::virtio::block::IO_URING_NUM_ENTRIES;
 
+// Indices of the fds registered with the ring.
+const DISK_FD: FixedFd = 0;
+// Only registered for qcow2 overlays.
+const BASE_FD: FixedFd = 1;
+
 #[derive(Debug)]
 pub enum Error {
     IO(std::io::Error),
@@ -23,17 +30,29 @@ pub enum Error {
     SyncAll(std::io::Error),
     EventFd(std::io::Error),
     GuestMemory(vm_memory::GuestMemoryError),
+    Qcow2(qcow2::Error),
 }
 
 pub struct AsyncFileEngine<T> {
     file: File,
+    qcow2: Option<Qcow2Image>,
     ring: IoUring,
     completion_evt: EventFd,
+    // The alignment direct IO requires, if `file` was opened with `O_DIRECT`.
+    direct_io: Option<usize>,
     phantom: PhantomData<T>,
 }
 
+enum Target {
+    Submit(FixedFd, u64),
+    Executed(u32),
+}
+
 pub struct WrappedUserData<T> {
     addr: Option<GuestAddress>,
+    // Buffer the kernel transfers the data to or from, instead of guest memory. It has to live
+    // until the operation completes.
+    bounce: Option<AlignedBuffer>,
     user_data: T,
 }
 
@@ -41,6 +60,7 @@ impl<T> WrappedUserData<T> {
     fn new(user_data: T) -> Self {
         WrappedUserData {
             addr: None,
+            bounce: None,
             user_data,
         }
     }
@@ -48,12 +68,27 @@ impl<T> WrappedUserData<T> {
     fn new_with_dirty_tracking(addr: GuestAddress, user_data: T) -> Self {
         WrappedUserData {
             addr: Some(addr),
+            bounce: None,
             user_data,
         }
     }
 
+    fn with_bounce(mut self, bounce: Option<AlignedBuffer>) -> Self {
+        self.bounce = bounce;
+        self
+    }
+
     fn mark_dirty_mem_and_unwrap(self, mem: &GuestMemoryMmap, count: u32) -> T {
         if let Some(addr) = self.addr {
+            // Data read through a bounce buffer still has to be copied to guest memory.
+            if let Some(bounce) = self.bounce.as_ref() {
+                if let Err(err) = mem.write_slice(&bounce.as_slice()[..count as usize], addr) {
+                    error!(
+                        "Failed to copy the bounce buffer to guest memory: {:?}",
+                        err
+                    );
+                }
+            }
             mark_dirty_mem(mem, addr, count as usize)
         }
 
@@ -63,12 +98,36 @@ impl<T> WrappedUserData<T> {
 
 impl<T> AsyncFileEngine<T> {
     pub fn from_file(file: File) -> Result<AsyncFileEngine<T>, Error> {
+        Self::new(file, None, None)
+    }
+
+    /// Creates an engine for a qcow2 overlay. `file` is the overlay file.
+    pub fn from_qcow2(file: File, qcow2: Qcow2Image) -> Result<AsyncFileEngine<T>, Error> {
+        Self::new(file, Some(qcow2), None)
+    }
+
+    /// Creates an engine for a raw disk image opened with `O_DIRECT`, which requires IO to be
+    /// aligned to `alignment`.
+    pub fn from_direct_file(file: File, alignment: usize) -> Result<AsyncFileEngine<T>, Error> {
+        Self::new(file, None, Some(alignment))
+    }
+
+    fn new(
+        file: File,
+        qcow2: Option<Qcow2Image>,
+        direct_io: Option<usize>,
+    ) -> Result<AsyncFileEngine<T>, Error> {
         log_dev_preview_warning("Async file IO", Option::None);
 
+        let mut files = vec![&file];
+        if let Some(image) = qcow2.as_ref() {
+            files.push(image.base());
+        }
+
         let completion_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
         let ring = IoUring::new(
             IO_URING_NUM_ENTRIES as u32,
-            vec![&file],
+            files,
             vec![
                 // Make sure we only allow operations on pre-registered fds.
                 Restriction::RequireFixedFds,
@@ -76,6 +135,7 @@ impl<T> AsyncFileEngine<T> {
                 Restriction::AllowOpCode(OpCode::Read),
                 Restriction::AllowOpCode(OpCode::Write),
                 Restriction::AllowOpCode(OpCode::Fsync),
+                Restriction::AllowOpCode(OpCode::Fallocate),
             ],
             Some(completion_evt.as_raw_fd()),
         )
@@ -83,8 +143,10 @@ impl<T> AsyncFileEngine<T> {
 
         Ok(AsyncFileEngine {
             file,
+            qcow2,
             ring,
             completion_evt,
+            direct_io,
             phantom: PhantomData,
         })
     }
@@ -98,6 +160,62 @@ impl<T> AsyncFileEngine<T> {
         &self.completion_evt
     }
 
+    // Finds out where a request needs to be submitted. For qcow2 overlays, requests which don't
+    // map to a contiguous range of a single file are executed synchronously instead.
+    fn target(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        write: bool,
+    ) -> Result<Target, Error> {
+        let image = match self.qcow2.as_mut() {
+            Some(image) => image,
+            None => return Ok(Target::Submit(DISK_FD, offset)),
+        };
+
+        let extents = image.map(offset, count, write).map_err(Error::Qcow2)?;
+        let count = match extents[..] {
+            [Extent {
+                kind: ExtentKind::Overlay(offset),
+                ..
+            }] => return Ok(Target::Submit(DISK_FD, offset)),
+            [Extent {
+                kind: ExtentKind::Base(offset),
+                ..
+            }] => return Ok(Target::Submit(BASE_FD, offset)),
+            _ if write => image.write_extents(&extents, mem, addr),
+            _ => image.read_extents(&extents, mem, addr),
+        }
+        .map_err(Error::Qcow2)?;
+
+        Ok(Target::Executed(count))
+    }
+
+    // Finds out the host buffer the kernel transfers the data of a request to or from. With
+    // direct IO, a bounce buffer is returned if the guest buffer is not suitably aligned.
+    fn buffer(
+        &self,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<(usize, Option<AlignedBuffer>), Error> {
+        if let Some(alignment) = self.direct_io {
+            if direct::guest_buffer(mem, addr, count, alignment).is_none() {
+                // Make sure the guest buffer is valid before allocating the bounce buffer.
+                mem.get_slice(addr, count as usize)
+                    .map_err(Error::GuestMemory)?;
+                let bounce = AlignedBuffer::new(count as usize, alignment);
+                return Ok((bounce.as_ptr() as usize, Some(bounce)));
+            }
+        }
+
+        mem.get_slice(addr, count as usize)
+            .map(|slice| (slice.as_ptr() as usize, None))
+            .map_err(Error::GuestMemory)
+    }
+
     pub fn push_read(
         &mut self,
         offset: u64,
@@ -105,30 +223,30 @@ impl<T> AsyncFileEngine<T> {
         addr: GuestAddress,
         count: u32,
         user_data: T,
-    ) -> Result<(), UserDataError<T, Error>> {
-        let buf = match mem.get_slice(addr, count as usize) {
-            Ok(slice) => slice.as_ptr(),
-            Err(err) => {
-                return Err(UserDataError {
-                    user_data,
-                    error: Error::GuestMemory(err),
-                });
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        let (fd, offset) = match self.target(offset, mem, addr, count, false) {
+            Ok(Target::Submit(fd, offset)) => (fd, offset),
+            Ok(Target::Executed(count)) => {
+                return Ok(FileEngineOk::Executed(UserDataOk { user_data, count }))
             }
+            Err(error) => return Err(UserDataError { user_data, error }),
         };
 
-        let wrapped_user_data = WrappedUserData::new_with_dirty_tracking(addr, user_data);
+        let (buf, bounce) = match self.buffer(mem, addr, count) {
+            Ok(buffer) => buffer,
+            Err(error) => return Err(UserDataError { user_data, error }),
+        };
+
+        let wrapped_user_data =
+            WrappedUserData::new_with_dirty_tracking(addr, user_data).with_bounce(bounce);
 
         // Safe because we trust that the host kernel will pass us back a completed entry with this
         // same `user_data`, so that the value will not be leaked.
         unsafe {
-            self.ring.push(Operation::read(
-                0,
-                buf as usize,
-                count,
-                offset,
-                wrapped_user_data,
-            ))
+            self.ring
+                .push(Operation::read(fd, buf, count, offset, wrapped_user_data))
         }
+        .map(|()| FileEngineOk::Submitted)
         .map_err(|err_tuple| UserDataError {
             user_data: err_tuple.1.user_data,
             error: Error::IoUring(err_tuple.0),
@@ -142,26 +260,66 @@ impl<T> AsyncFileEngine<T> {
         addr: GuestAddress,
         count: u32,
         user_data: T,
-    ) -> Result<(), UserDataError<T, Error>> {
-        let buf = match mem.get_slice(addr, count as usize) {
-            Ok(slice) => slice.as_ptr(),
-            Err(err) => {
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        let (fd, offset) = match self.target(offset, mem, addr, count, true) {
+            Ok(Target::Submit(fd, offset)) => (fd, offset),
+            Ok(Target::Executed(count)) => {
+                return Ok(FileEngineOk::Executed(UserDataOk { user_data, count }))
+            }
+            Err(error) => return Err(UserDataError { user_data, error }),
+        };
+
+        let (buf, mut bounce) = match self.buffer(mem, addr, count) {
+            Ok(buffer) => buffer,
+            Err(error) => return Err(UserDataError { user_data, error }),
+        };
+        if let Some(bounce) = bounce.as_mut() {
+            if let Err(err) = mem.read_slice(&mut bounce.as_mut_slice()[..count as usize], addr) {
                 return Err(UserDataError {
                     user_data,
                     error: Error::GuestMemory(err),
                 });
             }
-        };
+        }
+
+        let wrapped_user_data = WrappedUserData::new(user_data).with_bounce(bounce);
+
+        // Safe because we trust that the host kernel will pass us back a completed entry with this
+        // same `user_data`, so that the value will not be leaked.
+        unsafe {
+            self.ring
+                .push(Operation::write(fd, buf, count, offset, wrapped_user_data))
+        }
+        .map(|()| FileEngineOk::Submitted)
+        .map_err(|err_tuple| UserDataError {
+            user_data: err_tuple.1.user_data,
+            error: Error::IoUring(err_tuple.0),
+        })
+    }
+
+    pub fn push_fallocate(
+        &mut self,
+        offset: u64,
+        count: u32,
+        mode: i32,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        if self.qcow2.is_some() {
+            return Err(UserDataError {
+                user_data,
+                error: Error::Qcow2(qcow2::Error::UnsupportedFeature("discard")),
+            });
+        }
 
         let wrapped_user_data = WrappedUserData::new(user_data);
 
         // Safe because we trust that the host kernel will pass us back a completed entry with this
         // same `user_data`, so that the value will not be leaked.
         unsafe {
-            self.ring.push(Operation::write(
-                0,
-                buf as usize,
-                count,
+            self.ring.push(Operation::fallocate(
+                DISK_FD,
+                u64::from(count),
+                mode as u32,
                 offset,
                 wrapped_user_data,
             ))
@@ -177,12 +335,12 @@ impl<T> AsyncFileEngine<T> {
 
         // Safe because we trust that the host kernel will pass us back a completed entry with this
         // same `user_data`, so that the value will not be leaked.
-        unsafe { self.ring.push(Operation::fsync(0, wrapped_user_data)) }.map_err(|err_tuple| {
-            UserDataError {
+        unsafe { self.ring.push(Operation::fsync(DISK_FD, wrapped_user_data)) }.map_err(
+            |err_tuple| UserDataError {
                 user_data: err_tuple.1.user_data,
                 error: Error::IoUring(err_tuple.0),
-            }
-        })
+            },
+        )
     }
 
     pub fn kick_submission_queue(&mut self) -> Result<(), Error> {
@@ -0,0 +1,205 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers for transferring data to and from files opened with `O_DIRECT`.
+//!
+//! Direct IO bypasses the host page cache, but the kernel requires the file offset, the length
+//! and the address of the buffer to be aligned to the logical block size of the backing storage.
+//! The device advertises that block size to the guest, so that requests operate on whole
+//! blocks; only the guest buffers may then be misaligned, and these are transferred through an
+//! aligned bounce buffer instead.
+
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::os::raw::c_uint;
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::ptr::NonNull;
+use std::{io, slice};
+
+use utils::ioctl::ioctl_with_mut_ref;
+use utils::{ioctl_expr, ioctl_io_nr, ioctl_ioc_nr};
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
+
+use crate::virtio::block::SECTOR_SIZE;
+
+/// Alignment used for direct IO on regular files, whose backing storage can't be queried. It
+/// covers the logical block size of both 512 byte and 4KiB sector storage.
+pub const DEFAULT_DIRECT_IO_ALIGNMENT: usize = 4096;
+
+const BLOCK: c_uint = 0x12;
+ioctl_io_nr!(BLKSSZGET, BLOCK, 104);
+
+/// Returns the alignment direct IO on `file` requires: the logical block size of block
+/// devices, and `DEFAULT_DIRECT_IO_ALIGNMENT` for regular files.
+pub fn alignment(file: &File) -> io::Result<usize> {
+    if !file.metadata()?.file_type().is_block_device() {
+        return Ok(DEFAULT_DIRECT_IO_ALIGNMENT);
+    }
+
+    let mut block_size: libc::c_int = 0;
+    // Safe because the kernel only writes an int to `block_size`, and we check the return value.
+    let ret = unsafe { ioctl_with_mut_ref(file, BLKSSZGET(), &mut block_size) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let block_size = block_size as usize;
+    if block_size < SECTOR_SIZE as usize || !block_size.is_power_of_two() {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    Ok(block_size)
+}
+
+/// Heap allocated buffer suitably aligned for direct IO.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// Safe because the buffer is exclusively owned by the `AlignedBuffer`.
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocates a zeroed buffer of `len` bytes, aligned to `alignment`.
+    pub fn new(len: usize, alignment: usize) -> AlignedBuffer {
+        // Zero sized allocations are undefined behavior, so allocate at least one block.
+        let layout = Layout::from_size_align(len.max(alignment), alignment)
+            .expect("Invalid direct IO buffer layout");
+        // Safe because the layout has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+
+        AlignedBuffer { ptr, layout }
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // Safe because the allocation is valid for `layout.size()` bytes.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safe because the allocation is valid for `layout.size()` bytes and we hold the only
+        // reference to it.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // Safe because the pointer was allocated with the same layout.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// Returns the host address of the guest buffer at `addr`, if the buffer is contiguous in host
+/// memory and aligned to `alignment`, so that it can be used for direct IO as is.
+pub fn guest_buffer(
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    count: u32,
+    alignment: usize,
+) -> Option<*mut u8> {
+    let ptr = mem.get_slice(addr, count as usize).ok()?.as_ptr();
+    if ptr as usize % alignment == 0 {
+        Some(ptr)
+    } else {
+        None
+    }
+}
+
+/// Reads from `file` at `offset` until `buf` is full or the end of the file is reached.
+/// `alignment` is the alignment direct IO on `file` requires.
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64, alignment: usize) -> io::Result<usize> {
+    let mut done = 0;
+    while done < buf.len() {
+        match file.read_at(&mut buf[done..], offset + done as u64) {
+            Ok(0) => break,
+            Ok(count) => {
+                done += count;
+                // A partial block can only be read at the end of the file, and further reads
+                // would be misaligned.
+                if count % alignment != 0 {
+                    break;
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(done)
+}
+
+/// Writes the whole `buf` to `file` at `offset`.
+pub fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    file.write_all_at(buf, offset).map(|()| buf.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::tempfile::TempFile;
+    use vm_memory::Bytes;
+
+    use super::*;
+    use crate::virtio::test_utils::default_mem;
+
+    #[test]
+    fn test_alignment() {
+        // The backing storage of regular files can't be queried.
+        let file = TempFile::new().unwrap().into_file();
+        assert_eq!(alignment(&file).unwrap(), DEFAULT_DIRECT_IO_ALIGNMENT);
+    }
+
+    #[test]
+    fn test_aligned_buffer() {
+        let mut buf = AlignedBuffer::new(1024, 4096);
+        assert_eq!(buf.as_ptr() as usize % 4096, 0);
+        assert_eq!(buf.as_slice(), &[0u8; 1024][..]);
+        buf.as_mut_slice()[1023] = 0xff;
+        assert_eq!(buf.as_slice()[1023], 0xff);
+
+        // Empty buffers are still backed by an allocation.
+        assert_eq!(AlignedBuffer::new(0, 512).as_slice().len(), 512);
+    }
+
+    #[test]
+    fn test_guest_buffer() {
+        let mem = default_mem();
+        assert!(guest_buffer(&mem, GuestAddress(0x200), 0x200, 512).is_some());
+        assert!(guest_buffer(&mem, GuestAddress(0x201), 0x200, 512).is_none());
+        // The guest memory is page aligned.
+        assert!(guest_buffer(&mem, GuestAddress(0x200), 0x200, 4096).is_none());
+        assert!(guest_buffer(&mem, GuestAddress(0x1000), 0x200, 4096).is_some());
+        // Buffers outside of the guest memory can't be used.
+        assert!(guest_buffer(&mem, GuestAddress(0xff00), 0x1000, 512).is_none());
+    }
+
+    #[test]
+    fn test_read_write_at() {
+        // Not all file systems support O_DIRECT, so exercise the helpers on a buffered file.
+        let file = TempFile::new().unwrap().into_file();
+
+        let mut buf = AlignedBuffer::new(1024, 512);
+        buf.as_mut_slice().fill(0xaa);
+        assert_eq!(write_at(&file, &buf.as_slice()[..512], 512).unwrap(), 512);
+
+        let mut read_buf = AlignedBuffer::new(2048, 512);
+        // The file ends after 1024 bytes.
+        assert_eq!(
+            read_at(&file, read_buf.as_mut_slice(), 0, 512).unwrap(),
+            1024
+        );
+        assert_eq!(&read_buf.as_slice()[..512], &[0u8; 512][..]);
+        assert_eq!(&read_buf.as_slice()[512..1024], &[0xaau8; 512][..]);
+
+        let mem = default_mem();
+        mem.write_slice(&read_buf.as_slice()[512..1024], GuestAddress(0x1000))
+            .unwrap();
+        let ptr = guest_buffer(&mem, GuestAddress(0x1000), 512, 512).unwrap();
+        // Safe because the guest memory is valid for 512 bytes at this address.
+        let guest_slice = unsafe { slice::from_raw_parts(ptr, 512) };
+        assert_eq!(guest_slice, &[0xaau8; 512][..]);
+    }
+}
@@ -2,6 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod async_io;
+pub mod direct;
+pub mod qcow2;
 pub mod sync_io;
 
 use std::fs::File;
@@ -9,6 +11,7 @@ use std::fs::File;
 use vm_memory::{GuestAddress, GuestMemoryMmap};
 
 pub use self::async_io::AsyncFileEngine;
+pub use self::qcow2::Qcow2Image;
 pub use self::sync_io::SyncFileEngine;
 use crate::virtio::block::device::FileEngineType;
 
@@ -56,6 +59,16 @@ pub enum FileEngine<T> {
 
 impl<T> FileEngine<T> {
     pub fn from_file(file: File, engine_type: FileEngineType) -> Result<FileEngine<T>, Error> {
+        Self::from_disk(file, None, engine_type)
+    }
+
+    /// Creates an engine for a raw disk image opened with `O_DIRECT`, which requires IO to be
+    /// aligned to `alignment`.
+    pub fn from_direct_file(
+        file: File,
+        alignment: usize,
+        engine_type: FileEngineType,
+    ) -> Result<FileEngine<T>, Error> {
         if !engine_type
             .is_supported()
             .map_err(Error::GetKernelVersion)?
@@ -64,9 +77,37 @@ impl<T> FileEngine<T> {
         }
         match engine_type {
             FileEngineType::Async => Ok(FileEngine::Async(
+                AsyncFileEngine::from_direct_file(file, alignment).map_err(Error::Async)?,
+            )),
+            FileEngineType::Sync => Ok(FileEngine::Sync(SyncFileEngine::from_direct_file(
+                file, alignment,
+            ))),
+        }
+    }
+
+    /// Creates an engine for `file`, which is treated as a qcow2 overlay if `qcow2` is set.
+    pub fn from_disk(
+        file: File,
+        qcow2: Option<Qcow2Image>,
+        engine_type: FileEngineType,
+    ) -> Result<FileEngine<T>, Error> {
+        if !engine_type
+            .is_supported()
+            .map_err(Error::GetKernelVersion)?
+        {
+            return Err(Error::UnsupportedEngine(engine_type));
+        }
+        match (engine_type, qcow2) {
+            (FileEngineType::Async, None) => Ok(FileEngine::Async(
                 AsyncFileEngine::from_file(file).map_err(Error::Async)?,
             )),
-            FileEngineType::Sync => Ok(FileEngine::Sync(SyncFileEngine::from_file(file))),
+            (FileEngineType::Async, Some(image)) => Ok(FileEngine::Async(
+                AsyncFileEngine::from_qcow2(file, image).map_err(Error::Async)?,
+            )),
+            (FileEngineType::Sync, None) => Ok(FileEngine::Sync(SyncFileEngine::from_file(file))),
+            (FileEngineType::Sync, Some(image)) => {
+                Ok(FileEngine::Sync(SyncFileEngine::from_qcow2(file, image)))
+            }
         }
     }
 
@@ -87,15 +128,12 @@ impl<T> FileEngine<T> {
         user_data: T,
     ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
         match self {
-            FileEngine::Async(engine) => {
-                match engine.push_read(offset, mem, addr, count, user_data) {
-                    Ok(_) => Ok(FileEngineOk::Submitted),
-                    Err(err) => Err(UserDataError {
-                        user_data: err.user_data,
-                        error: Error::Async(err.error),
-                    }),
-                }
-            }
+            FileEngine::Async(engine) => engine
+                .push_read(offset, mem, addr, count, user_data)
+                .map_err(|err| UserDataError {
+                    user_data: err.user_data,
+                    error: Error::Async(err.error),
+                }),
             FileEngine::Sync(engine) => match engine.read(offset, mem, addr, count) {
                 Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
                 Err(err) => Err(UserDataError {
@@ -113,10 +151,35 @@ impl<T> FileEngine<T> {
         addr: GuestAddress,
         count: u32,
         user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        match self {
+            FileEngine::Async(engine) => engine
+                .push_write(offset, mem, addr, count, user_data)
+                .map_err(|err| UserDataError {
+                    user_data: err.user_data,
+                    error: Error::Async(err.error),
+                }),
+            FileEngine::Sync(engine) => match engine.write(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Sync(err),
+                }),
+            },
+        }
+    }
+
+    /// Deallocates or zeroes the given range of the backing file, depending on `mode`.
+    pub fn fallocate(
+        &mut self,
+        offset: u64,
+        count: u32,
+        mode: i32,
+        user_data: T,
     ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
         match self {
             FileEngine::Async(engine) => {
-                match engine.push_write(offset, mem, addr, count, user_data) {
+                match engine.push_fallocate(offset, count, mode, user_data) {
                     Ok(_) => Ok(FileEngineOk::Submitted),
                     Err(err) => Err(UserDataError {
                         user_data: err.user_data,
@@ -124,8 +187,11 @@ impl<T> FileEngine<T> {
                     }),
                 }
             }
-            FileEngine::Sync(engine) => match engine.write(offset, mem, addr, count) {
-                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+            FileEngine::Sync(engine) => match engine.fallocate(offset, count, mode) {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
                 Err(err) => Err(UserDataError {
                     user_data,
                     error: Error::Sync(err),
@@ -173,14 +239,19 @@ impl<T> FileEngine<T> {
 
 #[cfg(test)]
 pub mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Read, Write};
     use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::OpenOptionsExt;
     use std::os::unix::io::FromRawFd;
 
     use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
+    use utils::tempdir::TempDir;
     use utils::tempfile::TempFile;
     use utils::{skip_if_io_uring_supported, skip_if_io_uring_unsupported};
     use vm_memory::{Bitmap, Bytes, GuestMemory};
 
+    use super::qcow2::tests::create_overlay;
     use super::*;
     use crate::virtio::block::device::FileEngineType;
     use crate::virtio::block::request::PendingRequest;
@@ -232,6 +303,18 @@ pub mod tests {
         }
     }
 
+    fn check_execution(
+        mem: &GuestMemoryMmap,
+        engine: &mut FileEngine<()>,
+        res: Result<FileEngineOk<()>, UserDataError<(), Error>>,
+        count: u32,
+    ) {
+        match res.unwrap() {
+            FileEngineOk::Submitted => assert_async_execution(mem, engine, count),
+            FileEngineOk::Executed(ok) => assert_eq!(ok.count, count),
+        }
+    }
+
     fn create_mem() -> GuestMemoryMmap {
         vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), MEM_LEN)], true)
             .unwrap()
@@ -276,6 +359,8 @@ pub mod tests {
         assert_err!(res, Error::Sync(sync_io::Error::Seek(_e)));
         let res = engine.flush(());
         assert_err!(res, Error::Sync(sync_io::Error::SyncAll(_e)));
+        let res = engine.fallocate(0, 0, libc::FALLOC_FL_PUNCH_HOLE, ());
+        assert_err!(res, Error::Sync(sync_io::Error::Fallocate(_e)));
 
         // Create backing file.
         let file = TempFile::new().unwrap().into_file();
@@ -340,6 +425,22 @@ pub mod tests {
 
         // Check other ops
         assert!(engine.flush(()).is_ok());
+        assert_sync_execution!(
+            engine.fallocate(
+                0,
+                FILE_LEN,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                ()
+            ),
+            0
+        );
+        let mut buf = vec![1u8; FILE_LEN as usize];
+        assert_sync_execution!(
+            engine.read(0, &mem, GuestAddress(0), FILE_LEN, ()),
+            FILE_LEN
+        );
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
         assert!(engine.drain(true).is_ok());
         assert!(engine.drain_and_flush(true).is_ok());
     }
@@ -404,7 +505,138 @@ pub mod tests {
         assert_queued!(engine.flush(()));
         assert_async_execution(&mem, &mut engine, 0);
 
+        assert_queued!(engine.fallocate(
+            0,
+            FILE_LEN,
+            libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+            ()
+        ));
+        assert_async_execution(&mem, &mut engine, 0);
+
         assert!(engine.drain(true).is_ok());
         assert!(engine.drain_and_flush(true).is_ok());
     }
+
+    #[test]
+    fn test_qcow2() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.as_path().join("base");
+        let overlay_path = dir.as_path().join("overlay");
+        let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
+            .as_bytes()
+            .to_vec();
+        File::create(&base_path).unwrap().write_all(&data).unwrap();
+
+        for engine_type in [FileEngineType::Sync, FileEngineType::Async] {
+            if !engine_type.is_supported().unwrap() {
+                continue;
+            }
+            create_overlay(&overlay_path, &base_path, MEM_LEN as u64);
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&overlay_path)
+                .unwrap();
+            let image = Qcow2Image::open(file.try_clone().unwrap(), &overlay_path).unwrap();
+            let mut engine = FileEngine::<()>::from_disk(file, Some(image), engine_type).unwrap();
+
+            // Served from the base image only.
+            let mem = create_mem();
+            let res = engine.read(0, &mem, GuestAddress(0), FILE_LEN, ());
+            check_execution(&mem, &mut engine, res, FILE_LEN);
+            let mut buf = vec![0u8; FILE_LEN as usize];
+            mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+            assert_eq!(buf, data);
+
+            // Served from the base image and zeroes past its end.
+            let mem = create_mem();
+            mem.write_slice(&[1u8; MEM_LEN], GuestAddress(0)).unwrap();
+            let res = engine.read(0, &mem, GuestAddress(0), MEM_LEN as u32, ());
+            check_execution(&mem, &mut engine, res, MEM_LEN as u32);
+            let mut buf = vec![0u8; MEM_LEN];
+            mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+            assert_eq!(buf[..FILE_LEN as usize], data[..]);
+            assert!(buf[FILE_LEN as usize..].iter().all(|&b| b == 0));
+            check_dirty_mem(&mem, GuestAddress(0), MEM_LEN as u32);
+
+            // Writes go to the overlay.
+            let new_data = vec![0xaau8; 512];
+            mem.write_slice(&new_data, GuestAddress(0)).unwrap();
+            let res = engine.write(512, &mem, GuestAddress(0), 512, ());
+            check_execution(&mem, &mut engine, res, 512);
+            let mem = create_mem();
+            let res = engine.read(0, &mem, GuestAddress(0), FILE_LEN, ());
+            check_execution(&mem, &mut engine, res, FILE_LEN);
+            let mut buf = vec![0u8; FILE_LEN as usize];
+            mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+            assert_eq!(buf[..512], data[..512]);
+            assert_eq!(buf[512..], new_data[..]);
+
+            // Discarding ranges of an overlay is not supported.
+            let res = engine.fallocate(0, 512, libc::FALLOC_FL_PUNCH_HOLE, ());
+            match engine {
+                FileEngine::Sync(_) => assert_err!(
+                    res,
+                    Error::Sync(sync_io::Error::Qcow2(qcow2::Error::UnsupportedFeature(_)))
+                ),
+                FileEngine::Async(_) => assert_err!(
+                    res,
+                    Error::Async(async_io::Error::Qcow2(qcow2::Error::UnsupportedFeature(_)))
+                ),
+            }
+
+            // The base image is never modified.
+            let mut buf = vec![0u8; FILE_LEN as usize];
+            File::open(&base_path)
+                .unwrap()
+                .read_exact(&mut buf)
+                .unwrap();
+            assert_eq!(buf, data);
+        }
+    }
+
+    #[test]
+    fn test_direct_io() {
+        for engine_type in [FileEngineType::Sync, FileEngineType::Async] {
+            if !engine_type.is_supported().unwrap() {
+                continue;
+            }
+            let tmp = TempFile::new().unwrap();
+            // Not all file systems support direct IO.
+            let file = match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(tmp.as_path())
+            {
+                Ok(file) => file,
+                Err(_) => return,
+            };
+            // The requests operate on whole logical blocks of the backing storage.
+            let alignment = direct::alignment(&file).unwrap();
+            let len = alignment as u32;
+            let data = utils::rand::rand_alphanumerics(alignment)
+                .as_bytes()
+                .to_vec();
+            let mut engine =
+                FileEngine::<()>::from_direct_file(file, alignment, engine_type).unwrap();
+
+            // Both aligned guest buffers and misaligned ones, which need a bounce buffer.
+            for addr in [GuestAddress(0), GuestAddress(0x801)] {
+                let mem = create_mem();
+                mem.write_slice(&data, addr).unwrap();
+                let res = engine.write(0, &mem, addr, len, ());
+                check_execution(&mem, &mut engine, res, len);
+
+                let mem = create_mem();
+                let res = engine.read(0, &mem, addr, len, ());
+                check_execution(&mem, &mut engine, res, len);
+                let mut buf = vec![0u8; alignment];
+                mem.read_slice(&mut buf, addr).unwrap();
+                assert_eq!(buf, data);
+                check_dirty_mem(&mem, addr, len);
+            }
+            assert!(engine.drain_and_flush(true).is_ok());
+        }
+    }
 }
@@ -0,0 +1,833 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Support for qcow2 copy-on-write overlays on top of raw, read-only, base images.
+//!
+//! Only the subset of the format produced by `qemu-img create -f qcow2 -F raw -b <base>` is
+//! supported: no compression, no encryption, no internal snapshots and 16 bit refcounts.
+//! Reads of clusters that were never written are served from the base image, while writes
+//! allocate clusters at the end of the overlay, copying the rest of the cluster from the base
+//! image when needed.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::{cmp, result};
+
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+const QCOW2_MAGIC: u32 = 0x5146_49fb;
+const V2_HEADER_LEN: usize = 72;
+const V3_HEADER_LEN: usize = 104;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+// Only 16 bit refcounts are supported, which is the default for both versions of the format.
+const REFCOUNT_ORDER: u32 = 4;
+const MAX_BACKING_FILE_NAME_LEN: u32 = 1023;
+// The L1 and refcount tables are loaded in memory when the image is opened, so their sizes,
+// which come from the header, are capped like QEMU does.
+const MAX_L1_TABLE_SIZE: u64 = 32 << 20;
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 << 20;
+// Header extension types.
+const HEADER_EXT_END: u32 = 0;
+const HEADER_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+// Bits 9-55 of L1 and L2 entries hold the offset of a L2 table or of a data cluster.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+// Bits 9-63 of refcount table entries hold the offset of a refcount block.
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+// Set when the refcount of the cluster is exactly one.
+const COPIED_FLAG: u64 = 1 << 63;
+const COMPRESSED_FLAG: u64 = 1 << 62;
+// Only valid for version 3 images: the cluster reads as zeroes.
+const ZERO_FLAG: u64 = 1;
+
+type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidHeader,
+    InvalidMagic,
+    InvalidOffset,
+    Io(std::io::Error),
+    NoBackingFile,
+    OpenBackingFile(std::io::Error),
+    RefcountTableFull,
+    Transfer(GuestMemoryError),
+    UnsupportedFeature(&'static str),
+    UnsupportedVersion(u32),
+}
+
+/// Where the data of a contiguous range of the virtual disk lives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtentKind {
+    /// Offset in the overlay file.
+    Overlay(u64),
+    /// Offset in the base file.
+    Base(u64),
+    /// The range reads as zeroes.
+    Zero,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub kind: ExtentKind,
+    pub len: u32,
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// Makes sure that a table of `entries` entries at `offset` is at most `max_size` bytes long, and
+// fits in the `file_len` bytes of the file.
+fn check_table(offset: u64, entries: u64, max_size: u64, file_len: u64) -> Result<()> {
+    let size = entries
+        .checked_mul(8)
+        .filter(|size| *size <= max_size)
+        .ok_or(Error::InvalidHeader)?;
+    match offset.checked_add(size) {
+        Some(end) if end <= file_len => Ok(()),
+        _ => Err(Error::InvalidHeader),
+    }
+}
+
+fn read_table(file: &File, offset: u64, entries: u64) -> Result<Vec<u64>> {
+    let mut buf = vec![0u8; entries as usize * 8];
+    file.read_exact_at(&mut buf, offset).map_err(Error::Io)?;
+    Ok(buf
+        .chunks_exact(8)
+        .map(|entry| u64::from_be_bytes(entry.try_into().unwrap()))
+        .collect())
+}
+
+fn read_from_file(
+    file: &mut File,
+    offset: u64,
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    count: u32,
+) -> Result<u32> {
+    file.seek(SeekFrom::Start(offset)).map_err(Error::Io)?;
+    mem.read_from(addr, file, count as usize)
+        .map(|count| count as u32)
+        .map_err(Error::Transfer)
+}
+
+/// A qcow2 overlay image, together with its base image.
+pub struct Qcow2Image {
+    // Handle of the overlay, used for metadata updates and for executing requests in place.
+    overlay: File,
+    base: File,
+    base_len: u64,
+    version: u32,
+    cluster_bits: u32,
+    virtual_size: u64,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    // L2 tables are loaded lazily and never evicted, they are small compared to the disk size.
+    l2_cache: HashMap<u64, Vec<u64>>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    // New clusters are always allocated at the end of the overlay.
+    next_cluster_offset: u64,
+}
+
+impl Qcow2Image {
+    /// Opens the qcow2 image stored in `overlay`, located at `overlay_path`.
+    ///
+    /// The base image is the backing file referenced by the qcow2 header. Relative paths are
+    /// resolved against the directory of the overlay, like qemu does.
+    pub fn open(overlay: File, overlay_path: &Path) -> Result<Qcow2Image> {
+        let overlay_len = overlay.metadata().map_err(Error::Io)?.len();
+        if overlay_len < V2_HEADER_LEN as u64 {
+            return Err(Error::InvalidHeader);
+        }
+        let mut header = vec![0u8; cmp::min(overlay_len, V3_HEADER_LEN as u64) as usize];
+        overlay.read_exact_at(&mut header, 0).map_err(Error::Io)?;
+
+        if be_u32(&header, 0) != QCOW2_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let version = be_u32(&header, 4);
+        let header_len = match version {
+            2 => V2_HEADER_LEN,
+            3 => {
+                if header.len() < V3_HEADER_LEN {
+                    return Err(Error::InvalidHeader);
+                }
+                if be_u64(&header, 72) != 0 {
+                    return Err(Error::UnsupportedFeature("incompatible features"));
+                }
+                if be_u32(&header, 96) != REFCOUNT_ORDER {
+                    return Err(Error::UnsupportedFeature("refcount order"));
+                }
+                be_u32(&header, 100) as usize
+            }
+            _ => return Err(Error::UnsupportedVersion(version)),
+        };
+
+        let cluster_bits = be_u32(&header, 20);
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(Error::InvalidHeader);
+        }
+        if be_u32(&header, 32) != 0 {
+            return Err(Error::UnsupportedFeature("encryption"));
+        }
+        if be_u32(&header, 60) != 0 {
+            return Err(Error::UnsupportedFeature("internal snapshots"));
+        }
+        Self::check_backing_format(&overlay, header_len, 1u64 << cluster_bits)?;
+
+        let base = Self::open_backing_file(&overlay, &header, overlay_path)?;
+        let base_len = base.metadata().map_err(Error::Io)?.len();
+
+        let virtual_size = be_u64(&header, 24);
+        let cluster_size = 1u64 << cluster_bits;
+        let l1_entries = u64::from(be_u32(&header, 36));
+        // Each L1 entry covers a full L2 table worth of clusters.
+        let bytes_per_l1_entry = cluster_size * (cluster_size / 8);
+        let min_l1_entries =
+            virtual_size / bytes_per_l1_entry + u64::from(virtual_size % bytes_per_l1_entry != 0);
+        if l1_entries < min_l1_entries {
+            return Err(Error::InvalidHeader);
+        }
+        let l1_table_offset = be_u64(&header, 40);
+        check_table(l1_table_offset, l1_entries, MAX_L1_TABLE_SIZE, overlay_len)?;
+        let l1_table = read_table(&overlay, l1_table_offset, l1_entries)?;
+
+        let refcount_table_offset = be_u64(&header, 48);
+        // The size of the refcount table is given in clusters.
+        let refcount_table_entries = u64::from(be_u32(&header, 56))
+            .checked_mul(cluster_size / 8)
+            .ok_or(Error::InvalidHeader)?;
+        check_table(
+            refcount_table_offset,
+            refcount_table_entries,
+            MAX_REFCOUNT_TABLE_SIZE,
+            overlay_len,
+        )?;
+        let refcount_table = read_table(&overlay, refcount_table_offset, refcount_table_entries)?;
+
+        Ok(Qcow2Image {
+            overlay,
+            base,
+            base_len,
+            version,
+            cluster_bits,
+            virtual_size,
+            l1_table_offset,
+            l1_table,
+            l2_cache: HashMap::new(),
+            refcount_table_offset,
+            refcount_table,
+            next_cluster_offset: (overlay_len + cluster_size - 1) & !(cluster_size - 1),
+        })
+    }
+
+    // Makes sure that, if the image specifies the format of its backing file, it is a raw one.
+    fn check_backing_format(overlay: &File, header_len: usize, cluster_size: u64) -> Result<()> {
+        let mut offset = header_len as u64;
+        // Header extensions are stored in the first cluster, right after the header.
+        while offset + 8 <= cluster_size {
+            let mut ext_header = [0u8; 8];
+            overlay
+                .read_exact_at(&mut ext_header, offset)
+                .map_err(Error::Io)?;
+            let ext_type = be_u32(&ext_header, 0);
+            let ext_len = be_u32(&ext_header, 4);
+            match ext_type {
+                HEADER_EXT_END => return Ok(()),
+                HEADER_EXT_BACKING_FORMAT => {
+                    let mut format = vec![0u8; ext_len as usize];
+                    overlay
+                        .read_exact_at(&mut format, offset + 8)
+                        .map_err(Error::Io)?;
+                    if format != b"raw" {
+                        return Err(Error::UnsupportedFeature("non-raw backing file"));
+                    }
+                }
+                _ => {}
+            }
+            // Extension data is padded to a multiple of 8 bytes.
+            offset += 8 + ((u64::from(ext_len) + 7) & !7);
+        }
+        Ok(())
+    }
+
+    fn open_backing_file(overlay: &File, header: &[u8], overlay_path: &Path) -> Result<File> {
+        let backing_file_offset = be_u64(header, 8);
+        let backing_file_len = be_u32(header, 16);
+        if backing_file_offset == 0 || backing_file_len == 0 {
+            return Err(Error::NoBackingFile);
+        }
+        if backing_file_len > MAX_BACKING_FILE_NAME_LEN {
+            return Err(Error::InvalidHeader);
+        }
+
+        let mut name = vec![0u8; backing_file_len as usize];
+        overlay
+            .read_exact_at(&mut name, backing_file_offset)
+            .map_err(Error::Io)?;
+        let name = String::from_utf8(name).map_err(|_| Error::InvalidHeader)?;
+        let path = match overlay_path.parent() {
+            Some(dir) if Path::new(&name).is_relative() => dir.join(name),
+            _ => Path::new(&name).to_path_buf(),
+        };
+
+        // The base image is shared between overlays, so it is never opened for writing.
+        OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(Error::OpenBackingFile)
+    }
+
+    /// The size of the virtual disk, in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.virtual_size
+    }
+
+    /// Handle of the base image.
+    pub fn base(&self) -> &File {
+        &self.base
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    // Splits a virtual disk offset into L1 and L2 table indices.
+    fn table_indices(&self, offset: u64) -> (usize, usize) {
+        let cluster_index = offset >> self.cluster_bits;
+        (
+            (cluster_index / self.l2_entries()) as usize,
+            (cluster_index % self.l2_entries()) as usize,
+        )
+    }
+
+    fn l2_table(&mut self, l2_offset: u64) -> Result<&mut Vec<u64>> {
+        let entries = self.l2_entries();
+        match self.l2_cache.entry(l2_offset) {
+            Entry::Occupied(table) => Ok(table.into_mut()),
+            Entry::Vacant(slot) => Ok(slot.insert(read_table(&self.overlay, l2_offset, entries)?)),
+        }
+    }
+
+    fn l2_entry(&mut self, offset: u64) -> Result<u64> {
+        let (l1_index, l2_index) = self.table_indices(offset);
+        let l2_offset = self.l1_table.get(l1_index).copied().unwrap_or(0) & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        Ok(self.l2_table(l2_offset)?[l2_index])
+    }
+
+    fn write_table_entry(&self, offset: u64, entry: u64) -> Result<()> {
+        self.overlay
+            .write_all_at(&entry.to_be_bytes(), offset)
+            .map_err(Error::Io)
+    }
+
+    // Reserves a new cluster at the end of the overlay. Its content reads as zeroes.
+    fn reserve_cluster(&mut self) -> Result<u64> {
+        let offset = self.next_cluster_offset;
+        self.next_cluster_offset += self.cluster_size();
+        self.overlay
+            .set_len(self.next_cluster_offset)
+            .map_err(Error::Io)?;
+        Ok(offset)
+    }
+
+    fn allocate_cluster(&mut self) -> Result<u64> {
+        let offset = self.reserve_cluster()?;
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    fn set_refcount(&mut self, cluster_offset: u64, refcount: u16) -> Result<()> {
+        let cluster_index = cluster_offset >> self.cluster_bits;
+        // Each refcount block holds 16 bit refcounts.
+        let block_entries = self.cluster_size() / 2;
+        let table_index = (cluster_index / block_entries) as usize;
+        if table_index >= self.refcount_table.len() {
+            return Err(Error::RefcountTableFull);
+        }
+
+        let mut block_offset = self.refcount_table[table_index] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset == 0 {
+            block_offset = self.reserve_cluster()?;
+            self.write_table_entry(
+                self.refcount_table_offset + table_index as u64 * 8,
+                block_offset,
+            )?;
+            self.refcount_table[table_index] = block_offset;
+            // The new refcount block needs to be accounted for as well. This either lands in
+            // the block we just allocated, or allocates another one, until it converges.
+            self.set_refcount(block_offset, 1)?;
+        }
+
+        self.overlay
+            .write_all_at(
+                &refcount.to_be_bytes(),
+                block_offset + (cluster_index % block_entries) * 2,
+            )
+            .map_err(Error::Io)
+    }
+
+    // Returns the overlay cluster backing the virtual disk cluster at `offset`, allocating it
+    // if needed. When `overwrite` is false, the new cluster is populated from the base image.
+    fn cluster_for_write(&mut self, offset: u64, overwrite: bool) -> Result<u64> {
+        let (l1_index, l2_index) = self.table_indices(offset);
+        if l1_index >= self.l1_table.len() {
+            return Err(Error::InvalidOffset);
+        }
+
+        let mut l2_offset = self.l1_table[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            l2_offset = self.allocate_cluster()?;
+            self.l2_cache
+                .insert(l2_offset, vec![0; self.l2_entries() as usize]);
+            self.write_table_entry(
+                self.l1_table_offset + l1_index as u64 * 8,
+                l2_offset | COPIED_FLAG,
+            )?;
+            self.l1_table[l1_index] = l2_offset | COPIED_FLAG;
+        }
+
+        let entry = self.l2_table(l2_offset)?[l2_index];
+        if entry & COMPRESSED_FLAG != 0 {
+            return Err(Error::UnsupportedFeature("compressed clusters"));
+        }
+        let is_zero = self.version >= 3 && entry & ZERO_FLAG != 0;
+        let mut cluster_offset = entry & OFFSET_MASK;
+        if cluster_offset != 0 && !is_zero {
+            return Ok(cluster_offset);
+        }
+
+        let cluster_size = self.cluster_size();
+        if cluster_offset == 0 {
+            cluster_offset = self.allocate_cluster()?;
+        } else if !overwrite {
+            // Preallocated zero cluster.
+            self.overlay
+                .write_all_at(&vec![0u8; cluster_size as usize], cluster_offset)
+                .map_err(Error::Io)?;
+        }
+
+        let cluster_start = offset & !(cluster_size - 1);
+        if !overwrite && !is_zero && cluster_start < self.base_len {
+            let mut data =
+                vec![0u8; cmp::min(cluster_size, self.base_len - cluster_start) as usize];
+            self.base
+                .read_exact_at(&mut data, cluster_start)
+                .map_err(Error::Io)?;
+            self.overlay
+                .write_all_at(&data, cluster_offset)
+                .map_err(Error::Io)?;
+        }
+
+        // Only point the L2 entry to the new cluster once its content is in place.
+        self.write_table_entry(
+            l2_offset + l2_index as u64 * 8,
+            cluster_offset | COPIED_FLAG,
+        )?;
+        self.l2_table(l2_offset)?[l2_index] = cluster_offset | COPIED_FLAG;
+
+        Ok(cluster_offset)
+    }
+
+    fn push_extent(extents: &mut Vec<Extent>, kind: ExtentKind, len: u32) {
+        if let Some(last) = extents.last_mut() {
+            let contiguous = match (last.kind, kind) {
+                (ExtentKind::Overlay(prev), ExtentKind::Overlay(next))
+                | (ExtentKind::Base(prev), ExtentKind::Base(next)) => {
+                    prev + u64::from(last.len) == next
+                }
+                (ExtentKind::Zero, ExtentKind::Zero) => true,
+                _ => false,
+            };
+            if contiguous {
+                last.len += len;
+                return;
+            }
+        }
+        extents.push(Extent { kind, len });
+    }
+
+    /// Maps a range of the virtual disk to the locations holding its data.
+    ///
+    /// When mapping for a write, all the clusters in the range are allocated in the overlay,
+    /// so that the returned extents only reference the overlay.
+    pub fn map(&mut self, offset: u64, count: u32, for_write: bool) -> Result<Vec<Extent>> {
+        let end = offset
+            .checked_add(u64::from(count))
+            .filter(|end| *end <= self.virtual_size)
+            .ok_or(Error::InvalidOffset)?;
+        let cluster_size = self.cluster_size();
+        let mut extents = Vec::new();
+        let mut pos = offset;
+
+        while pos < end {
+            let offset_in_cluster = pos & (cluster_size - 1);
+            let mut len = cmp::min(cluster_size - offset_in_cluster, end - pos);
+
+            let kind = if for_write {
+                let cluster_offset = self.cluster_for_write(pos, len == cluster_size)?;
+                ExtentKind::Overlay(cluster_offset + offset_in_cluster)
+            } else {
+                let entry = self.l2_entry(pos)?;
+                if entry & COMPRESSED_FLAG != 0 {
+                    return Err(Error::UnsupportedFeature("compressed clusters"));
+                }
+                let cluster_offset = entry & OFFSET_MASK;
+                if self.version >= 3 && entry & ZERO_FLAG != 0 {
+                    ExtentKind::Zero
+                } else if cluster_offset != 0 {
+                    ExtentKind::Overlay(cluster_offset + offset_in_cluster)
+                } else if pos < self.base_len {
+                    // The base image may be smaller than the virtual disk.
+                    len = cmp::min(len, self.base_len - pos);
+                    ExtentKind::Base(pos)
+                } else {
+                    ExtentKind::Zero
+                }
+            };
+
+            Self::push_extent(&mut extents, kind, len as u32);
+            pos += len;
+        }
+
+        Ok(extents)
+    }
+
+    /// Reads the data of `extents` into guest memory, starting at `addr`.
+    pub fn read_extents(
+        &mut self,
+        extents: &[Extent],
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+    ) -> Result<u32> {
+        let mut transferred = 0u32;
+        for extent in extents {
+            let addr = addr.unchecked_add(u64::from(transferred));
+            let count = match extent.kind {
+                ExtentKind::Overlay(offset) => {
+                    read_from_file(&mut self.overlay, offset, mem, addr, extent.len)?
+                }
+                ExtentKind::Base(offset) => {
+                    read_from_file(&mut self.base, offset, mem, addr, extent.len)?
+                }
+                ExtentKind::Zero => {
+                    mem.write_slice(&vec![0u8; extent.len as usize], addr)
+                        .map_err(Error::Transfer)?;
+                    extent.len
+                }
+            };
+            transferred += count;
+            if count < extent.len {
+                break;
+            }
+        }
+        Ok(transferred)
+    }
+
+    /// Writes guest memory, starting at `addr`, to `extents`. The extents must only reference
+    /// the overlay.
+    pub fn write_extents(
+        &mut self,
+        extents: &[Extent],
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+    ) -> Result<u32> {
+        let mut transferred = 0u32;
+        for extent in extents {
+            let offset = match extent.kind {
+                ExtentKind::Overlay(offset) => offset,
+                _ => return Err(Error::InvalidOffset),
+            };
+            self.overlay
+                .seek(SeekFrom::Start(offset))
+                .map_err(Error::Io)?;
+            let count = mem
+                .write_to(
+                    addr.unchecked_add(u64::from(transferred)),
+                    &mut self.overlay,
+                    extent.len as usize,
+                )
+                .map(|count| count as u32)
+                .map_err(Error::Transfer)?;
+            transferred += count;
+            if count < extent.len {
+                break;
+            }
+        }
+        Ok(transferred)
+    }
+
+    pub fn read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32> {
+        let extents = self.map(offset, count, false)?;
+        self.read_extents(&extents, mem, addr)
+    }
+
+    pub fn write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32> {
+        let extents = self.map(offset, count, true)?;
+        self.write_extents(&extents, mem, addr)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+
+    use utils::tempdir::TempDir;
+    use vm_memory::test_utils::create_anon_guest_memory;
+
+    use super::*;
+
+    pub(crate) const TEST_CLUSTER_BITS: u32 = 16;
+
+    /// Creates a qcow2 (version 3) overlay of `base_path`, with the same layout as qemu-img:
+    /// header, L1 table, refcount table and a refcount block, one cluster each.
+    pub(crate) fn create_overlay(overlay_path: &Path, base_path: &Path, virtual_size: u64) {
+        let cluster_size = 1u64 << TEST_CLUSTER_BITS;
+        let bytes_per_l1_entry = cluster_size * (cluster_size / 8);
+        let l1_entries = (virtual_size + bytes_per_l1_entry - 1) / bytes_per_l1_entry;
+        let backing_file = base_path.to_str().unwrap().as_bytes();
+        let backing_file_offset = V3_HEADER_LEN as u64 + 8;
+
+        let mut image = vec![0u8; 4 * cluster_size as usize];
+        let mut put = |offset: u64, bytes: &[u8]| {
+            image[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes)
+        };
+        put(0, &QCOW2_MAGIC.to_be_bytes());
+        put(4, &3u32.to_be_bytes());
+        put(8, &backing_file_offset.to_be_bytes());
+        put(16, &(backing_file.len() as u32).to_be_bytes());
+        put(20, &TEST_CLUSTER_BITS.to_be_bytes());
+        put(24, &virtual_size.to_be_bytes());
+        put(36, &(l1_entries as u32).to_be_bytes());
+        put(40, &cluster_size.to_be_bytes());
+        put(48, &(2 * cluster_size).to_be_bytes());
+        put(56, &1u32.to_be_bytes());
+        put(96, &REFCOUNT_ORDER.to_be_bytes());
+        put(100, &(V3_HEADER_LEN as u32).to_be_bytes());
+        // The header extensions area only holds the end marker, followed by the backing file.
+        put(backing_file_offset, backing_file);
+        // Refcount table entry for the refcount block.
+        put(2 * cluster_size, &(3 * cluster_size).to_be_bytes());
+        // All four metadata clusters are in use.
+        for cluster in 0..4 {
+            put(3 * cluster_size + cluster * 2, &1u16.to_be_bytes());
+        }
+
+        File::create(overlay_path)
+            .unwrap()
+            .write_all(&image)
+            .unwrap();
+    }
+
+    fn open_overlay(path: &Path) -> Result<Qcow2Image> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        Qcow2Image::open(file, path)
+    }
+
+    fn refcount(image: &Qcow2Image, cluster_offset: u64) -> u16 {
+        let block_entries = image.cluster_size() / 2;
+        let cluster_index = cluster_offset >> image.cluster_bits;
+        let block_offset = image.refcount_table[(cluster_index / block_entries) as usize];
+        let mut buf = [0u8; 2];
+        image
+            .overlay
+            .read_exact_at(&mut buf, block_offset + (cluster_index % block_entries) * 2)
+            .unwrap();
+        u16::from_be_bytes(buf)
+    }
+
+    #[test]
+    fn test_open() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.as_path().join("base");
+        let overlay_path = dir.as_path().join("overlay");
+        File::create(&base_path).unwrap().set_len(0x1000).unwrap();
+
+        // Not a qcow2 image.
+        File::create(&overlay_path)
+            .unwrap()
+            .set_len(0x1000)
+            .unwrap();
+        assert!(matches!(
+            open_overlay(&overlay_path),
+            Err(Error::InvalidMagic)
+        ));
+
+        // Too small to hold a header.
+        File::create(&overlay_path).unwrap().set_len(10).unwrap();
+        assert!(matches!(
+            open_overlay(&overlay_path),
+            Err(Error::InvalidHeader)
+        ));
+
+        // Missing base image.
+        create_overlay(&overlay_path, &dir.as_path().join("missing"), 0x1000);
+        assert!(matches!(
+            open_overlay(&overlay_path),
+            Err(Error::OpenBackingFile(_))
+        ));
+
+        // The backing file path is resolved relative to the overlay.
+        create_overlay(&overlay_path, Path::new("base"), 0x1000);
+        let image = open_overlay(&overlay_path).unwrap();
+        assert_eq!(image.virtual_size(), 0x1000);
+        assert_eq!(image.base_len, 0x1000);
+
+        // The L1 and refcount tables have to be within the overlay, and not too large.
+        let cluster_size = 1u64 << TEST_CLUSTER_BITS;
+        let overlay_len = 4 * cluster_size;
+        for (offset, value) in [
+            (36, u64::from(u32::MAX) << 32),
+            (40, overlay_len),
+            (40, u64::MAX),
+            (48, overlay_len - 8),
+            (56, u64::from(u32::MAX) << 32),
+        ] {
+            create_overlay(&overlay_path, Path::new("base"), 0x1000);
+            let overlay = OpenOptions::new().write(true).open(&overlay_path).unwrap();
+            overlay.write_all_at(&value.to_be_bytes(), offset).unwrap();
+            assert!(matches!(
+                open_overlay(&overlay_path),
+                Err(Error::InvalidHeader)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_read_write() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.as_path().join("base");
+        let overlay_path = dir.as_path().join("overlay");
+        let cluster_size = 1u64 << TEST_CLUSTER_BITS;
+        // The virtual disk is larger than the base image.
+        let base_data = utils::rand::rand_alphanumerics(3 * cluster_size as usize / 2)
+            .as_bytes()
+            .to_vec();
+        File::create(&base_path)
+            .unwrap()
+            .write_all(&base_data)
+            .unwrap();
+        create_overlay(&overlay_path, &base_path, 4 * cluster_size);
+
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 4 * cluster_size as usize)], false)
+            .unwrap();
+        let mut image = open_overlay(&overlay_path).unwrap();
+
+        // Unallocated clusters are served from the base image, and read as zeroes past its end.
+        let extents = image.map(0, 2 * cluster_size as u32, false).unwrap();
+        assert_eq!(
+            extents,
+            vec![
+                Extent {
+                    kind: ExtentKind::Base(0),
+                    len: base_data.len() as u32
+                },
+                Extent {
+                    kind: ExtentKind::Zero,
+                    len: 2 * cluster_size as u32 - base_data.len() as u32
+                }
+            ]
+        );
+        mem.write_slice(&vec![0xff; cluster_size as usize * 2], GuestAddress(0))
+            .unwrap();
+        assert_eq!(
+            image
+                .read(0, &mem, GuestAddress(0), 2 * cluster_size as u32)
+                .unwrap(),
+            2 * cluster_size as u32
+        );
+        let mut buf = vec![0u8; 2 * cluster_size as usize];
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf[..base_data.len()], base_data[..]);
+        assert!(buf[base_data.len()..].iter().all(|&b| b == 0));
+
+        // A partial write copies the rest of the cluster from the base image.
+        let data = vec![0xaa; 512];
+        mem.write_slice(&data, GuestAddress(0)).unwrap();
+        assert_eq!(image.write(1024, &mem, GuestAddress(0), 512).unwrap(), 512);
+        let extents = image.map(0, cluster_size as u32, false).unwrap();
+        assert_eq!(extents.len(), 1);
+        let cluster_offset = match extents[0].kind {
+            ExtentKind::Overlay(offset) => offset,
+            _ => panic!("the cluster should have been allocated"),
+        };
+        assert_eq!(refcount(&image, cluster_offset), 1);
+
+        // Data survives reopening the image and the base image is left untouched.
+        let mut image = open_overlay(&overlay_path).unwrap();
+        image
+            .read(0, &mem, GuestAddress(0), cluster_size as u32)
+            .unwrap();
+        let mut buf = vec![0u8; cluster_size as usize];
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf[..1024], base_data[..1024]);
+        assert_eq!(buf[1024..1536], data[..]);
+        assert_eq!(buf[1536..], base_data[1536..cluster_size as usize]);
+        let mut buf = vec![0u8; base_data.len()];
+        File::open(&base_path)
+            .unwrap()
+            .read_exact_at(&mut buf, 0)
+            .unwrap();
+        assert_eq!(buf, base_data);
+
+        // A write spanning two clusters allocates both, contiguously.
+        assert_eq!(
+            image
+                .write(
+                    cluster_size * 2,
+                    &mem,
+                    GuestAddress(0),
+                    2 * cluster_size as u32
+                )
+                .unwrap(),
+            2 * cluster_size as u32
+        );
+        assert_eq!(
+            image
+                .map(cluster_size * 2, 2 * cluster_size as u32, false)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Out of bounds accesses are rejected.
+        assert!(matches!(
+            image.map(4 * cluster_size - 512, 1024, true),
+            Err(Error::InvalidOffset)
+        ));
+    }
+}
@@ -3,13 +3,22 @@
 
 use std::fs::File;
 use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 use std::result::Result;
 
-use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use utils::syscall::SyscallReturnCode;
+
+use vm_memory::{mark_dirty_mem, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+use crate::virtio::block::io::direct::{self, AlignedBuffer};
+use crate::virtio::block::io::qcow2::{self, Qcow2Image};
 
 #[derive(Debug)]
 pub enum Error {
+    DirectIo(std::io::Error),
+    Fallocate(std::io::Error),
     Flush(std::io::Error),
+    Qcow2(qcow2::Error),
     Seek(std::io::Error),
     SyncAll(std::io::Error),
     Transfer(GuestMemoryError),
@@ -17,13 +26,39 @@ pub enum Error {
 
 pub struct SyncFileEngine {
     file: File,
+    qcow2: Option<Qcow2Image>,
+    // The alignment direct IO requires, if `file` was opened with `O_DIRECT`.
+    direct_io: Option<usize>,
 }
 
 unsafe impl Send for SyncFileEngine {}
 
 impl SyncFileEngine {
     pub fn from_file(file: File) -> SyncFileEngine {
-        SyncFileEngine { file }
+        SyncFileEngine {
+            file,
+            qcow2: None,
+            direct_io: None,
+        }
+    }
+
+    /// Creates an engine for a qcow2 overlay. `file` is the overlay file.
+    pub fn from_qcow2(file: File, qcow2: Qcow2Image) -> SyncFileEngine {
+        SyncFileEngine {
+            file,
+            qcow2: Some(qcow2),
+            direct_io: None,
+        }
+    }
+
+    /// Creates an engine for a raw disk image opened with `O_DIRECT`, which requires IO to be
+    /// aligned to `alignment`.
+    pub fn from_direct_file(file: File, alignment: usize) -> SyncFileEngine {
+        SyncFileEngine {
+            file,
+            qcow2: None,
+            direct_io: Some(alignment),
+        }
     }
 
     #[cfg(test)]
@@ -38,6 +73,13 @@ impl SyncFileEngine {
         addr: GuestAddress,
         count: u32,
     ) -> Result<u32, Error> {
+        if let Some(image) = self.qcow2.as_mut() {
+            return image.read(offset, mem, addr, count).map_err(Error::Qcow2);
+        }
+        if let Some(alignment) = self.direct_io {
+            return self.direct_read(offset, mem, addr, count, alignment);
+        }
+
         self.file
             .seek(SeekFrom::Start(offset))
             .map_err(Error::Seek)?;
@@ -53,6 +95,13 @@ impl SyncFileEngine {
         addr: GuestAddress,
         count: u32,
     ) -> Result<u32, Error> {
+        if let Some(image) = self.qcow2.as_mut() {
+            return image.write(offset, mem, addr, count).map_err(Error::Qcow2);
+        }
+        if let Some(alignment) = self.direct_io {
+            return self.direct_write(offset, mem, addr, count, alignment);
+        }
+
         self.file
             .seek(SeekFrom::Start(offset))
             .map_err(Error::Seek)?;
@@ -61,6 +110,83 @@ impl SyncFileEngine {
             .map_err(Error::Transfer)
     }
 
+    // Reads straight into guest memory if the buffer is suitable for direct IO, otherwise
+    // through a bounce buffer.
+    fn direct_read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        alignment: usize,
+    ) -> Result<u32, Error> {
+        let count = match direct::guest_buffer(mem, addr, count, alignment) {
+            Some(ptr) => {
+                // Safe because the guest memory is valid for `count` bytes at this address.
+                let buf = unsafe { std::slice::from_raw_parts_mut(ptr, count as usize) };
+                let count =
+                    direct::read_at(&self.file, buf, offset, alignment).map_err(Error::DirectIo)?;
+                // The guest memory was written without going through the dirty page tracking.
+                mark_dirty_mem(mem, addr, count);
+                count
+            }
+            None => {
+                let mut bounce = AlignedBuffer::new(count as usize, alignment);
+                let buf = &mut bounce.as_mut_slice()[..count as usize];
+                let count =
+                    direct::read_at(&self.file, buf, offset, alignment).map_err(Error::DirectIo)?;
+                mem.write_slice(&buf[..count], addr)
+                    .map_err(Error::Transfer)?;
+                count
+            }
+        };
+        Ok(count as u32)
+    }
+
+    // Writes straight from guest memory if the buffer is suitable for direct IO, otherwise
+    // through a bounce buffer.
+    fn direct_write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        alignment: usize,
+    ) -> Result<u32, Error> {
+        let count = match direct::guest_buffer(mem, addr, count, alignment) {
+            Some(ptr) => {
+                // Safe because the guest memory is valid for `count` bytes at this address.
+                let buf = unsafe { std::slice::from_raw_parts(ptr, count as usize) };
+                direct::write_at(&self.file, buf, offset).map_err(Error::DirectIo)?
+            }
+            None => {
+                let mut bounce = AlignedBuffer::new(count as usize, alignment);
+                let buf = &mut bounce.as_mut_slice()[..count as usize];
+                mem.read_slice(buf, addr).map_err(Error::Transfer)?;
+                direct::write_at(&self.file, buf, offset).map_err(Error::DirectIo)?
+            }
+        };
+        Ok(count as u32)
+    }
+
+    pub fn fallocate(&mut self, offset: u64, count: u32, mode: i32) -> Result<(), Error> {
+        if self.qcow2.is_some() {
+            return Err(Error::Qcow2(qcow2::Error::UnsupportedFeature("discard")));
+        }
+
+        // Safe because the file descriptor is valid and we check the return value.
+        SyscallReturnCode(unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                mode,
+                offset as libc::off_t,
+                libc::off_t::from(count),
+            )
+        })
+        .into_empty_result()
+        .map_err(Error::Fallocate)
+    }
+
     pub fn flush(&mut self) -> Result<(), Error> {
         // flush() first to force any cached data out of rust buffers.
         self.file.flush().map_err(Error::Flush)?;
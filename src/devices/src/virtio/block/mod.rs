@@ -14,12 +14,17 @@ pub use self::device::{Block, CacheType};
 pub use self::event_handler::*;
 pub use self::request::*;
 
-pub const CONFIG_SPACE_SIZE: usize = 8;
+pub const CONFIG_SPACE_SIZE: usize = std::mem::size_of::<device::ConfigSpace>();
 pub const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01_u64) << SECTOR_SHIFT;
+// The largest range covered by a discard or write zeroes request, in sectors. It is chosen so
+// that the size of the range in bytes still fits in an u32.
+pub const MAX_DISCARD_SECTORS: u32 = u32::MAX >> SECTOR_SHIFT;
+pub const MAX_WRITE_ZEROES_SECTORS: u32 = u32::MAX >> SECTOR_SHIFT;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 1;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+pub const DEFAULT_NUM_QUEUES: u16 = 1;
+// There is no point in having more queues than vCPUs, and Firecracker supports up to 32 vCPUs.
+pub const MAX_NUM_QUEUES: u16 = 32;
 // The virtio queue can hold up to 256 descriptors, but 1 request spreads across 2-3 descriptors.
 // So we can use 128 IO_URING entries without ever triggering a FullSq Error.
 pub const IO_URING_NUM_ENTRIES: u16 = 128;
@@ -36,6 +41,10 @@ pub enum Error {
     GuestMemory(GuestMemoryError),
     /// The data length is invalid.
     InvalidDataLength,
+    /// The discard or write zeroes segment has unsupported flags set.
+    InvalidFlags,
+    /// The number of queues is either zero or larger than `MAX_NUM_QUEUES`.
+    InvalidNumQueues(u16),
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
     /// Guest gave us a read only descriptor that protocol says to write to.
@@ -54,4 +63,6 @@ pub enum Error {
     RateLimiter(std::io::Error),
     // Persistence error.
     Persist(crate::virtio::persist::Error),
+    // Error opening a qcow2 disk image.
+    Qcow2(io::qcow2::Error),
 }
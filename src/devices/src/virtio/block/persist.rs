@@ -17,7 +17,7 @@ use virtio_gen::virtio_blk::VIRTIO_BLK_F_RO;
 use vm_memory::GuestMemoryMmap;
 
 use super::*;
-use crate::virtio::block::device::FileEngineType;
+use crate::virtio::block::device::{DiskFormat, FileEngineType};
 use crate::virtio::persist::VirtioDeviceState;
 use crate::virtio::{DeviceState, TYPE_BLOCK};
 
@@ -79,6 +79,31 @@ impl Default for FileEngineTypeState {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum DiskFormatState {
+    Raw,
+    Qcow2,
+}
+
+impl From<DiskFormat> for DiskFormatState {
+    fn from(disk_format: DiskFormat) -> Self {
+        match disk_format {
+            DiskFormat::Raw => DiskFormatState::Raw,
+            DiskFormat::Qcow2 => DiskFormatState::Qcow2,
+        }
+    }
+}
+
+impl From<DiskFormatState> for DiskFormat {
+    fn from(disk_format_state: DiskFormatState) -> Self {
+        match disk_format_state {
+            DiskFormatState::Raw => DiskFormat::Raw,
+            DiskFormatState::Qcow2 => DiskFormat::Qcow2,
+        }
+    }
+}
+
 #[derive(Clone, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct BlockState {
@@ -99,6 +124,20 @@ pub struct BlockState {
     // v1.0 are incompatible with older FC versions (due to incompatible notification suppression
     // feature).
     file_engine_type: FileEngineTypeState,
+    #[version(
+        start = 4,
+        ser_fn = "disk_format_ser",
+        default_fn = "default_disk_format"
+    )]
+    disk_format: DiskFormatState,
+    #[version(
+        start = 4,
+        ser_fn = "num_queues_ser",
+        default_fn = "default_num_queues"
+    )]
+    num_queues: u16,
+    #[version(start = 4, ser_fn = "direct_io_ser")]
+    direct_io: bool,
 }
 
 impl BlockState {
@@ -116,6 +155,47 @@ impl BlockState {
     fn default_cache_type_flush(_source_version: u16) -> CacheTypeState {
         CacheTypeState::Unsafe
     }
+
+    fn disk_format_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would open the overlay as a raw disk, corrupting it on the first write.
+        if target_version < 4 && self.disk_format != DiskFormatState::Raw {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support qcow2 disk images.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_disk_format(_source_version: u16) -> DiskFormatState {
+        DiskFormatState::Raw
+    }
+
+    fn num_queues_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would only restore the first queue, losing the state of the others.
+        if target_version < 4 && self.num_queues != DEFAULT_NUM_QUEUES {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support multiple block queues.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_num_queues(_source_version: u16) -> u16 {
+        DEFAULT_NUM_QUEUES
+    }
+
+    fn direct_io_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 4 && self.direct_io {
+            warn!(
+                "Target version does not implement direct IO. The disk image will be accessed \
+                 through the host page cache."
+            );
+        }
+
+        Ok(())
+    }
 }
 
 pub struct BlockConstructorArgs {
@@ -138,6 +218,9 @@ impl Persist<'_> for Block {
             virtio_state: VirtioDeviceState::from_device(self),
             rate_limiter_state: self.rate_limiter.save(),
             file_engine_type: FileEngineTypeState::from(self.file_engine_type()),
+            disk_format: DiskFormatState::from(self.disk_format()),
+            num_queues: self.num_queues(),
+            direct_io: self.direct_io(),
         }
     }
 
@@ -158,6 +241,9 @@ impl Persist<'_> for Block {
             state.root_device,
             rate_limiter,
             state.file_engine_type.into(),
+            state.disk_format.into(),
+            state.num_queues,
+            state.direct_io,
         )
         .or_else(|err| match err {
             Error::FileEngine(io::Error::UnsupportedEngine(FileEngineType::Async)) => {
@@ -179,6 +265,9 @@ impl Persist<'_> for Block {
                     state.root_device,
                     rate_limiter,
                     FileEngineType::Sync,
+                    state.disk_format.into(),
+                    state.num_queues,
+                    state.direct_io,
                 )
             }
             other_err => Err(other_err),
@@ -186,7 +275,12 @@ impl Persist<'_> for Block {
 
         block.queues = state
             .virtio_state
-            .build_queues_checked(&constructor_args.mem, TYPE_BLOCK, NUM_QUEUES, QUEUE_SIZE)
+            .build_queues_checked(
+                &constructor_args.mem,
+                TYPE_BLOCK,
+                state.num_queues as usize,
+                QUEUE_SIZE,
+            )
             .map_err(Error::Persist)?;
         block.irq_trigger.irq_status =
             Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
@@ -257,6 +351,9 @@ mod tests {
             false,
             RateLimiter::default(),
             FileEngineType::default(),
+            DiskFormat::Raw,
+            DEFAULT_NUM_QUEUES,
+            false,
         )
         .unwrap();
 
@@ -311,6 +408,9 @@ mod tests {
                 // Need to use Sync because it will otherwise return an error.
                 // We'll overwrite the state instead.
                 FileEngineType::Sync,
+                DiskFormat::Raw,
+                DEFAULT_NUM_QUEUES,
+                false,
             )
             .unwrap();
 
@@ -338,6 +438,108 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_disk_format_state() {
+        assert_eq!(DiskFormatState::Raw, DiskFormatState::from(DiskFormat::Raw));
+        assert_eq!(
+            DiskFormatState::Qcow2,
+            DiskFormatState::from(DiskFormat::Qcow2)
+        );
+        assert_eq!(DiskFormat::Raw, DiskFormatState::Raw.into());
+        assert_eq!(DiskFormat::Qcow2, DiskFormatState::Qcow2.into());
+        assert_eq!(BlockState::default_disk_format(3), DiskFormatState::Raw);
+
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::default(),
+            DiskFormat::Raw,
+            DEFAULT_NUM_QUEUES,
+            false,
+        )
+        .unwrap();
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+        let mut mem = vec![0; 4096];
+
+        // Raw disks can be saved for any version.
+        let mut block_state = <Block as Persist>::save(&block);
+        assert!(block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .is_ok());
+
+        // Qcow2 disks can't be saved for versions which don't know about them.
+        block_state.disk_format = DiskFormatState::Qcow2;
+        assert!(matches!(
+            block_state.serialize(&mut mem.as_mut_slice(), &version_map, 2),
+            Err(VersionizeError::Semantic(_))
+        ));
+        block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_state = BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap();
+        assert_eq!(restored_state.disk_format, DiskFormatState::Qcow2);
+    }
+
+    #[test]
+    fn test_num_queues_state() {
+        assert_eq!(BlockState::default_num_queues(3), DEFAULT_NUM_QUEUES);
+
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::default(),
+            DiskFormat::Raw,
+            4,
+            false,
+        )
+        .unwrap();
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+        let mut mem = vec![0; 4096];
+
+        // Multiple queues can't be saved for versions which don't know about them.
+        let block_state = <Block as Persist>::save(&block);
+        assert!(matches!(
+            block_state.serialize(&mut mem.as_mut_slice(), &version_map, 2),
+            Err(VersionizeError::Semantic(_))
+        ));
+        block_state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+
+        let restored_block = Block::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.num_queues(), 4);
+        assert_eq!(restored_block.queues().len(), 4);
+        assert_eq!(restored_block.queue_evts.len(), 4);
+    }
+
     #[test]
     fn test_persistence() {
         // We create the backing file here so that it exists for the whole lifetime of the test.
@@ -354,6 +556,9 @@ mod tests {
             false,
             RateLimiter::default(),
             FileEngineType::default(),
+            DiskFormat::Raw,
+            DEFAULT_NUM_QUEUES,
+            false,
         )
         .unwrap();
         let guest_mem = default_mem();
@@ -8,23 +8,33 @@
 use std::convert::From;
 use std::result;
 
-use logger::{error, IncMetric, METRICS};
+use logger::{error, BlockDeviceMetrics, IncMetric};
 use rate_limiter::{RateLimiter, TokenType};
+use utils::time::{get_time_us, ClockType};
 pub use virtio_gen::virtio_blk::{
     VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
-    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+    VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
+    VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
 };
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
 use super::super::DescriptorChain;
-use super::{io as block_io, Error, SECTOR_SHIFT};
+use super::{io as block_io, Error, MAX_DISCARD_SECTORS, MAX_WRITE_ZEROES_SECTORS, SECTOR_SHIFT};
 use crate::virtio::block::device::DiskProperties;
 use crate::virtio::SECTOR_SIZE;
 
 #[derive(Debug, derive_more::From)]
 pub enum IoErr {
     GetId(GuestMemoryError),
-    PartialTransfer { completed: u32, expected: u32 },
+    PartialTransfer {
+        completed: u32,
+        expected: u32,
+    },
+    Unaligned {
+        offset: u64,
+        len: u32,
+        alignment: usize,
+    },
     FileEngine(block_io::Error),
 }
 
@@ -34,6 +44,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -44,6 +56,8 @@ impl From<u32> for RequestType {
             VIRTIO_BLK_T_OUT => RequestType::Out,
             VIRTIO_BLK_T_FLUSH => RequestType::Flush,
             VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
+            VIRTIO_BLK_T_DISCARD => RequestType::Discard,
+            VIRTIO_BLK_T_WRITE_ZEROES => RequestType::WriteZeroes,
             t => RequestType::Unsupported(t),
         }
     }
@@ -91,17 +105,24 @@ pub struct PendingRequest {
     data_len: u32,
     status_addr: GuestAddress,
     desc_idx: u16,
+    // Monotonic time, in microseconds, at which the request was fetched from the queue.
+    start_us: u64,
 }
 
 impl PendingRequest {
-    fn write_status_and_finish(self, status: &Status, mem: &GuestMemoryMmap) -> FinishedRequest {
+    fn write_status_and_finish(
+        self,
+        status: &Status,
+        mem: &GuestMemoryMmap,
+        metrics: &BlockDeviceMetrics,
+    ) -> FinishedRequest {
         let (num_bytes_to_mem, status_code) = match status {
             Status::Ok { num_bytes_to_mem } => (*num_bytes_to_mem, VIRTIO_BLK_S_OK),
             Status::IoErr {
                 num_bytes_to_mem,
                 err,
             } => {
-                METRICS.block.invalid_reqs_count.inc();
+                metrics.invalid_reqs_count.inc();
                 error!(
                     "Failed to execute {:?} virtio block request: {:?}",
                     self.r#type, err
@@ -109,7 +130,7 @@ impl PendingRequest {
                 (*num_bytes_to_mem, VIRTIO_BLK_S_IOERR)
             }
             Status::Unsupported { op } => {
-                METRICS.block.invalid_reqs_count.inc();
+                metrics.invalid_reqs_count.inc();
                 error!("Received unsupported virtio block request: {}", op);
                 (0, VIRTIO_BLK_S_UNSUPP)
             }
@@ -133,26 +154,43 @@ impl PendingRequest {
         }
     }
 
-    pub fn finish(self, mem: &GuestMemoryMmap, res: Result<u32, IoErr>) -> FinishedRequest {
+    fn record_latency(&self, metrics: &BlockDeviceMetrics) {
+        let histogram = match self.r#type {
+            RequestType::In => &metrics.read_latency_us,
+            RequestType::Out => &metrics.write_latency_us,
+            RequestType::Flush => &metrics.flush_latency_us,
+            _ => return,
+        };
+        histogram.record(get_time_us(ClockType::Monotonic).saturating_sub(self.start_us));
+    }
+
+    pub fn finish(
+        self,
+        mem: &GuestMemoryMmap,
+        res: Result<u32, IoErr>,
+        metrics: &BlockDeviceMetrics,
+    ) -> FinishedRequest {
+        self.record_latency(metrics);
+
         let status = match (res, self.r#type) {
             (Ok(transferred_data_len), RequestType::In) => {
                 let status = Status::from_data(self.data_len, transferred_data_len, true);
-                METRICS.block.read_bytes.add(transferred_data_len as usize);
+                metrics.read_bytes.add(transferred_data_len as usize);
                 if let Status::Ok { .. } = status {
-                    METRICS.block.read_count.inc();
+                    metrics.read_count.inc();
                 }
                 status
             }
             (Ok(transferred_data_len), RequestType::Out) => {
                 let status = Status::from_data(self.data_len, transferred_data_len, false);
-                METRICS.block.write_bytes.add(transferred_data_len as usize);
+                metrics.write_bytes.add(transferred_data_len as usize);
                 if let Status::Ok { .. } = status {
-                    METRICS.block.write_count.inc();
+                    metrics.write_count.inc();
                 }
                 status
             }
             (Ok(_), RequestType::Flush) => {
-                METRICS.block.flush_count.inc();
+                metrics.flush_count.inc();
                 Status::Ok {
                     num_bytes_to_mem: 0,
                 }
@@ -160,6 +198,20 @@ impl PendingRequest {
             (Ok(transferred_data_len), RequestType::GetDeviceID) => {
                 Status::from_data(self.data_len, transferred_data_len, true)
             }
+            (Ok(_), RequestType::Discard) => {
+                metrics.discard_count.inc();
+                metrics.discard_bytes.add(self.data_len as usize);
+                Status::Ok {
+                    num_bytes_to_mem: 0,
+                }
+            }
+            (Ok(_), RequestType::WriteZeroes) => {
+                metrics.write_zeroes_count.inc();
+                metrics.write_zeroes_bytes.add(self.data_len as usize);
+                Status::Ok {
+                    num_bytes_to_mem: 0,
+                }
+            }
             (_, RequestType::Unsupported(op)) => Status::Unsupported { op },
             (Err(err), _) => Status::IoErr {
                 num_bytes_to_mem: 0,
@@ -167,7 +219,7 @@ impl PendingRequest {
             },
         };
 
-        self.write_status_and_finish(&status, mem)
+        self.write_status_and_finish(&status, mem, metrics)
     }
 }
 
@@ -191,6 +243,34 @@ pub struct RequestHeader {
 // Safe because RequestHeader only contains plain data.
 unsafe impl ByteValued for RequestHeader {}
 
+/// The payload of discard and write zeroes requests.
+///
+/// It describes a range of sectors, mirroring `struct virtio_blk_discard_write_zeroes` from the
+/// virtio specification:
+///   * sector: the first sector of the range.
+///   * num_sectors: the number of sectors in the range.
+///   * flags: only the `unmap` bit is defined, and only for write zeroes requests.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct DiscardWriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// Safe because DiscardWriteZeroesSegment only contains plain data.
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
+impl DiscardWriteZeroesSegment {
+    pub fn new(sector: u64, num_sectors: u32, flags: u32) -> DiscardWriteZeroesSegment {
+        DiscardWriteZeroesSegment {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+}
+
 impl RequestHeader {
     pub fn new(request_type: u32, sector: u64) -> RequestHeader {
         RequestHeader {
@@ -220,6 +300,9 @@ pub struct Request {
     pub status_addr: GuestAddress,
     sector: u64,
     data_addr: GuestAddress,
+    unmap: bool,
+    // The direct IO alignment the transfer of a read or write request doesn't satisfy.
+    unaligned_to: Option<usize>,
 }
 
 impl Request {
@@ -227,6 +310,7 @@ impl Request {
         avail_desc: &DescriptorChain,
         mem: &GuestMemoryMmap,
         num_disk_sectors: u64,
+        direct_io_alignment: Option<usize>,
     ) -> result::Result<Request, Error> {
         // The head contains the request type which MUST be readable.
         if avail_desc.is_write_only() {
@@ -240,6 +324,8 @@ impl Request {
             data_addr: GuestAddress(0),
             data_len: 0,
             status_addr: GuestAddress(0),
+            unmap: false,
+            unaligned_to: None,
         };
 
         let data_desc;
@@ -260,7 +346,12 @@ impl Request {
                 .next_descriptor()
                 .ok_or(Error::DescriptorChainTooShort)?;
 
-            if data_desc.is_write_only() && req.r#type == RequestType::Out {
+            if data_desc.is_write_only()
+                && matches!(
+                    req.r#type,
+                    RequestType::Out | RequestType::Discard | RequestType::WriteZeroes
+                )
+            {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
             if !data_desc.is_write_only() && req.r#type == RequestType::In {
@@ -282,13 +373,38 @@ impl Request {
                 if u64::from(req.data_len) % SECTOR_SIZE != 0 {
                     return Err(Error::InvalidDataLength);
                 }
-                let top_sector = req
-                    .sector
-                    .checked_add(u64::from(req.data_len) >> SECTOR_SHIFT)
-                    .ok_or(Error::InvalidOffset)?;
-                if top_sector > num_disk_sectors {
-                    return Err(Error::InvalidOffset);
+                req.check_range(num_disk_sectors)?;
+                // Direct IO only transfers whole logical blocks of the backing storage, so
+                // the other requests are failed when they are processed.
+                req.unaligned_to = direct_io_alignment.filter(|alignment| {
+                    let alignment = *alignment as u64;
+                    req.offset() % alignment != 0 || u64::from(req.data_len) % alignment != 0
+                });
+            }
+            RequestType::Discard | RequestType::WriteZeroes => {
+                // We only advertise support for a single segment per request.
+                if req.data_len as usize != std::mem::size_of::<DiscardWriteZeroesSegment>() {
+                    return Err(Error::InvalidDataLength);
+                }
+                let segment: DiscardWriteZeroesSegment =
+                    mem.read_obj(req.data_addr).map_err(Error::GuestMemory)?;
+
+                let (max_sectors, allowed_flags) = match req.r#type {
+                    RequestType::Discard => (MAX_DISCARD_SECTORS, 0),
+                    _ => (MAX_WRITE_ZEROES_SECTORS, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+                };
+                if segment.num_sectors > max_sectors {
+                    return Err(Error::InvalidDataLength);
                 }
+                if segment.flags & !allowed_flags != 0 {
+                    return Err(Error::InvalidFlags);
+                }
+
+                // From now on, the request describes the range of sectors it operates on.
+                req.sector = segment.sector;
+                req.data_len = segment.num_sectors << SECTOR_SHIFT;
+                req.unmap = segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+                req.check_range(num_disk_sectors)?;
             }
             RequestType::GetDeviceID => {
                 if req.data_len < VIRTIO_BLK_ID_BYTES {
@@ -312,6 +428,17 @@ impl Request {
         Ok(req)
     }
 
+    fn check_range(&self, num_disk_sectors: u64) -> result::Result<(), Error> {
+        let top_sector = self
+            .sector
+            .checked_add(u64::from(self.data_len) >> SECTOR_SHIFT)
+            .ok_or(Error::InvalidOffset)?;
+        if top_sector > num_disk_sectors {
+            return Err(Error::InvalidOffset);
+        }
+        Ok(())
+    }
+
     pub(crate) fn rate_limit(&self, rate_limiter: &mut RateLimiter) -> bool {
         // If limiter.consume() fails it means there is no more TokenType::Ops
         // budget and rate limiting is in effect.
@@ -319,7 +446,12 @@ impl Request {
             return true;
         }
         // Exercise the rate limiter only if this request is of data transfer type.
-        if self.r#type == RequestType::In || self.r#type == RequestType::Out {
+        // Write zeroes requests are accounted as writes of the whole range, while discard
+        // requests only consume an operation since they release storage instead of using it.
+        if matches!(
+            self.r#type,
+            RequestType::In | RequestType::Out | RequestType::WriteZeroes
+        ) {
             // If limiter.consume() fails it means there is no more TokenType::Bytes
             // budget and rate limiting is in effect.
             if !rate_limiter.consume(u64::from(self.data_len), TokenType::Bytes) {
@@ -342,56 +474,89 @@ impl Request {
             data_len: self.data_len,
             status_addr: self.status_addr,
             desc_idx,
+            start_us: get_time_us(ClockType::Monotonic),
         }
     }
 
     pub(crate) fn process(
         self,
         disk: &mut DiskProperties,
+        queue_index: usize,
         desc_idx: u16,
         mem: &GuestMemoryMmap,
+        metrics: &BlockDeviceMetrics,
     ) -> ProcessingResult {
         let pending = self.to_pending_request(desc_idx);
+        if let Some(alignment) = self.unaligned_to {
+            let err = IoErr::Unaligned {
+                offset: self.offset(),
+                len: self.data_len,
+                alignment,
+            };
+            return ProcessingResult::Executed(pending.finish(mem, Err(err), metrics));
+        }
         let res = match self.r#type {
-            RequestType::In => disk.file_engine_mut().read(
+            RequestType::In => disk.file_engine_mut(queue_index).read(
                 self.offset(),
                 mem,
                 self.data_addr,
                 self.data_len,
                 pending,
             ),
-            RequestType::Out => disk.file_engine_mut().write(
+            RequestType::Out => disk.file_engine_mut(queue_index).write(
                 self.offset(),
                 mem,
                 self.data_addr,
                 self.data_len,
                 pending,
             ),
-            RequestType::Flush => disk.file_engine_mut().flush(pending),
+            RequestType::Flush => disk.file_engine_mut(queue_index).flush(pending),
+            RequestType::Discard => disk.file_engine_mut(queue_index).fallocate(
+                self.offset(),
+                self.data_len,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                pending,
+            ),
+            RequestType::WriteZeroes => {
+                // Punching a hole also zeroes the range, so use it when the driver allows us to
+                // deallocate the underlying storage.
+                let mode = match self.unmap {
+                    true => libc::FALLOC_FL_PUNCH_HOLE,
+                    false => libc::FALLOC_FL_ZERO_RANGE,
+                };
+                disk.file_engine_mut(queue_index).fallocate(
+                    self.offset(),
+                    self.data_len,
+                    mode | libc::FALLOC_FL_KEEP_SIZE,
+                    pending,
+                )
+            }
             RequestType::GetDeviceID => {
                 let res = mem
                     .write_slice(disk.image_id(), self.data_addr)
                     .map(|_| VIRTIO_BLK_ID_BYTES)
                     .map_err(IoErr::GetId);
-                return ProcessingResult::Executed(pending.finish(mem, res));
+                return ProcessingResult::Executed(pending.finish(mem, res, metrics));
             }
             RequestType::Unsupported(_) => {
-                return ProcessingResult::Executed(pending.finish(mem, Ok(0)));
+                return ProcessingResult::Executed(pending.finish(mem, Ok(0), metrics));
             }
         };
 
         match res {
             Ok(block_io::FileEngineOk::Submitted) => ProcessingResult::Submitted,
             Ok(block_io::FileEngineOk::Executed(res)) => {
-                ProcessingResult::Executed(res.user_data.finish(mem, Ok(res.count)))
+                ProcessingResult::Executed(res.user_data.finish(mem, Ok(res.count), metrics))
             }
             Err(err) => {
                 if err.error.is_throttling_err() {
                     ProcessingResult::Throttled
                 } else {
-                    ProcessingResult::Executed(
-                        err.user_data.finish(mem, Err(IoErr::FileEngine(err.error))),
-                    )
+                    ProcessingResult::Executed(err.user_data.finish(
+                        mem,
+                        Err(IoErr::FileEngine(err.error)),
+                        metrics,
+                    ))
                 }
             }
         }
@@ -421,6 +586,8 @@ mod tests {
             VIRTIO_BLK_T_OUT,
             VIRTIO_BLK_T_FLUSH,
             VIRTIO_BLK_T_GET_ID,
+            VIRTIO_BLK_T_DISCARD,
+            VIRTIO_BLK_T_WRITE_ZEROES,
         ];
 
         for request_type in supported_request_types {
@@ -447,6 +614,14 @@ mod tests {
             RequestType::from(VIRTIO_BLK_T_GET_ID),
             RequestType::GetDeviceID
         );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_DISCARD),
+            RequestType::Discard
+        );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_WRITE_ZEROES),
+            RequestType::WriteZeroes
+        );
         assert_eq!(RequestType::from(42), RequestType::Unsupported(42));
     }
 
@@ -516,7 +691,7 @@ mod tests {
         fn check_parse_err(&self, _e: Error) {
             let mut q = self.vq.create_queue();
             assert!(matches!(
-                Request::parse(&q.pop(self.mem).unwrap(), self.mem, NUM_DISK_SECTORS),
+                Request::parse(&q.pop(self.mem).unwrap(), self.mem, NUM_DISK_SECTORS, None),
                 Err(_e)
             ));
         }
@@ -524,7 +699,8 @@ mod tests {
         fn check_parse(&self, check_data: bool) {
             let mut q = self.vq.create_queue();
             let request =
-                Request::parse(&q.pop(self.mem).unwrap(), self.mem, NUM_DISK_SECTORS).unwrap();
+                Request::parse(&q.pop(self.mem).unwrap(), self.mem, NUM_DISK_SECTORS, None)
+                    .unwrap();
             assert_eq!(request.r#type, RequestType::from(self.hdr().request_type));
             assert_eq!(request.sector, self.hdr().sector);
 
@@ -618,6 +794,21 @@ mod tests {
         queue.mut_hdr().sector = NUM_DISK_SECTORS - 1;
         queue.set_status_desc(0x3000, 0x1000, VIRTQ_DESC_F_WRITE);
         queue.check_parse(true);
+
+        // With direct IO, both the offset and the length have to be aligned.
+        let parse_unaligned_to = |queue: &RequestVirtQueue| {
+            let mut q = queue.vq.create_queue();
+            Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS, Some(4096))
+                .unwrap()
+                .unaligned_to
+        };
+        assert_eq!(parse_unaligned_to(&queue), Some(4096));
+        queue.mut_hdr().sector = 8;
+        assert_eq!(parse_unaligned_to(&queue), Some(4096));
+        queue.mut_data_desc().len.set(4096);
+        assert_eq!(parse_unaligned_to(&queue), None);
+        queue.mut_hdr().sector = 9;
+        assert_eq!(parse_unaligned_to(&queue), Some(4096));
     }
 
     #[test]
@@ -693,6 +884,78 @@ mod tests {
         queue.check_parse(true);
     }
 
+    #[test]
+    fn test_parse_discard_write_zeroes() {
+        let mem = &create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        let mut queue = RequestVirtQueue::new(GuestAddress(0), mem);
+        let segment_len = std::mem::size_of::<DiscardWriteZeroesSegment>() as u32;
+
+        let request_header = RequestHeader::new(VIRTIO_BLK_T_DISCARD, 0);
+        queue.set_hdr_desc(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, request_header);
+        queue.set_status_desc(0x3000, 0x1000, VIRTQ_DESC_F_WRITE);
+
+        // Write only data descriptor for DISCARD.
+        queue.set_data_desc(0x2000, segment_len, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        queue.check_parse_err(Error::UnexpectedWriteOnlyDescriptor);
+
+        // More than one segment.
+        queue.mut_data_desc().flags.set(VIRTQ_DESC_F_NEXT);
+        queue.mut_data_desc().len.set(2 * segment_len);
+        queue.check_parse_err(Error::InvalidDataLength);
+
+        // Range goes beyond the end of the disk.
+        queue.mut_data_desc().len.set(segment_len);
+        mem.write_obj(
+            DiscardWriteZeroesSegment::new(NUM_DISK_SECTORS - 1, 2, 0),
+            GuestAddress(0x2000),
+        )
+        .unwrap();
+        queue.check_parse_err(Error::InvalidOffset);
+
+        // The unmap flag is not valid for discard requests.
+        mem.write_obj(
+            DiscardWriteZeroesSegment::new(10, 2, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+            GuestAddress(0x2000),
+        )
+        .unwrap();
+        queue.check_parse_err(Error::InvalidFlags);
+
+        // Valid discard request.
+        mem.write_obj(
+            DiscardWriteZeroesSegment::new(10, 2, 0),
+            GuestAddress(0x2000),
+        )
+        .unwrap();
+        let mut q = queue.vq.create_queue();
+        let request = Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS, None).unwrap();
+        assert_eq!(request.r#type, RequestType::Discard);
+        assert_eq!(request.sector, 10);
+        assert_eq!(request.data_len, 2 << SECTOR_SHIFT);
+        assert!(!request.unmap);
+
+        // Valid write zeroes request with the unmap flag.
+        queue.mut_hdr().request_type = VIRTIO_BLK_T_WRITE_ZEROES;
+        mem.write_obj(
+            DiscardWriteZeroesSegment::new(20, 4, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+            GuestAddress(0x2000),
+        )
+        .unwrap();
+        let mut q = queue.vq.create_queue();
+        let request = Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS, None).unwrap();
+        assert_eq!(request.r#type, RequestType::WriteZeroes);
+        assert_eq!(request.sector, 20);
+        assert_eq!(request.data_len, 4 << SECTOR_SHIFT);
+        assert!(request.unmap);
+
+        // Unknown flags for write zeroes requests.
+        mem.write_obj(
+            DiscardWriteZeroesSegment::new(20, 4, 0x10),
+            GuestAddress(0x2000),
+        )
+        .unwrap();
+        queue.check_parse_err(Error::InvalidFlags);
+    }
+
     use std::convert::TryInto;
 
     /// -------------------------------------
@@ -735,9 +998,9 @@ mod tests {
                     1u32,
                     std::sync::Arc::new(Strategy::prop_map(any::<u32>(), |id| {
                         // Random unsupported requests for our implementation start at
-                        // VIRTIO_BLK_T_GET_ID + 1 = 9.
-                        // This can be further refined to include unsupported requests ids < 9.
-                        RequestType::Unsupported(id.checked_add(9).unwrap_or(9))
+                        // VIRTIO_BLK_T_WRITE_ZEROES + 1 = 14.
+                        // This can be further refined to include unsupported requests ids < 14.
+                        RequestType::Unsupported(id.checked_add(14).unwrap_or(14))
                     })),
                 ),
             ))
@@ -751,6 +1014,8 @@ mod tests {
                 RequestType::Out => VIRTIO_BLK_T_OUT,
                 RequestType::Flush => VIRTIO_BLK_T_FLUSH,
                 RequestType::GetDeviceID => VIRTIO_BLK_T_GET_ID,
+                RequestType::Discard => VIRTIO_BLK_T_DISCARD,
+                RequestType::WriteZeroes => VIRTIO_BLK_T_WRITE_ZEROES,
                 RequestType::Unsupported(id) => id,
             }
         }
@@ -763,6 +1028,7 @@ mod tests {
             RequestType::Out => VIRTQ_DESC_F_NEXT,
             RequestType::Flush => VIRTQ_DESC_F_NEXT,
             RequestType::GetDeviceID => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            RequestType::Discard | RequestType::WriteZeroes => VIRTQ_DESC_F_NEXT,
             RequestType::Unsupported(_) => VIRTQ_DESC_F_NEXT,
         }
     }
@@ -856,6 +1122,8 @@ mod tests {
             status_addr,
             sector: sector & (NUM_DISK_SECTORS - sectors_len),
             data_addr,
+            unmap: false,
+            unaligned_to: None,
         };
         let request_header = RequestHeader::new(virtio_request_id, request.sector);
 
@@ -979,7 +1247,7 @@ mod tests {
     fn parse_random_requests() {
         let cfg = ProptestConfig::with_cases(1000);
         proptest!(cfg, |(mut request in random_request_parse())| {
-            let result = Request::parse(&request.2.pop(&request.1).unwrap(), &request.1, NUM_DISK_SECTORS);
+            let result = Request::parse(&request.2.pop(&request.1).unwrap(), &request.1, NUM_DISK_SECTORS, None);
             match result {
                 Ok(r) => prop_assert!(r == request.0.unwrap()),
                 Err(err) => {
@@ -12,9 +12,10 @@ use rate_limiter::RateLimiter;
 use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
 use utils::tempfile::TempFile;
 
-use crate::virtio::block::device::FileEngineType;
+use crate::virtio::block::device::{DiskFormat, FileEngineType};
 #[cfg(test)]
 use crate::virtio::block::io::FileEngine;
+use crate::virtio::block::DEFAULT_NUM_QUEUES;
 #[cfg(test)]
 use crate::virtio::IrqType;
 use crate::virtio::{Block, CacheType, Queue};
@@ -53,6 +54,9 @@ pub fn default_block_with_path(path: String, file_engine_type: FileEngineType) -
         false,
         rate_limiter,
         file_engine_type,
+        DiskFormat::Raw,
+        DEFAULT_NUM_QUEUES,
+        false,
     )
     .unwrap()
 }
@@ -74,7 +78,7 @@ pub fn simulate_queue_event(b: &mut Block, maybe_expected_irq: Option<bool>) {
     // Trigger the queue event.
     b.queue_evts[0].write(1).unwrap();
     // Handle event.
-    b.process_queue_event();
+    b.process_queue_event(0);
     // Validate the queue operation finished successfully.
     if let Some(expected_irq) = maybe_expected_irq {
         assert_eq!(b.irq_trigger.has_pending_irq(IrqType::Vring), expected_irq);
@@ -83,13 +87,15 @@ pub fn simulate_queue_event(b: &mut Block, maybe_expected_irq: Option<bool>) {
 
 #[cfg(test)]
 pub fn simulate_async_completion_event(b: &mut Block, expected_irq: bool) {
-    if let FileEngine::Async(engine) = b.disk.file_engine_mut() {
-        // Wait for all the async operations to complete.
-        engine.drain(false).unwrap();
-        // Wait for the async completion event to be sent.
-        thread::sleep(Duration::from_millis(150));
-        // Handle event.
-        b.process_async_completion_event();
+    for queue_index in 0..b.queues.len() {
+        if let FileEngine::Async(engine) = b.disk.file_engine_mut(queue_index) {
+            // Wait for all the async operations to complete.
+            engine.drain(false).unwrap();
+            // Wait for the async completion event to be sent.
+            thread::sleep(Duration::from_millis(150));
+            // Handle event.
+            b.process_async_completion_event(queue_index);
+        }
     }
 
     // Validate if there are pending IRQs.
@@ -98,12 +104,12 @@ pub fn simulate_async_completion_event(b: &mut Block, expected_irq: bool) {
 
 #[cfg(test)]
 pub fn simulate_queue_and_async_completion_events(b: &mut Block, expected_irq: bool) {
-    match b.disk.file_engine_mut() {
-        FileEngine::Async(_) => {
+    match b.file_engine_type() {
+        FileEngineType::Async => {
             simulate_queue_event(b, None);
             simulate_async_completion_event(b, expected_irq);
         }
-        FileEngine::Sync(_) => {
+        FileEngineType::Sync => {
             simulate_queue_event(b, Some(expected_irq));
         }
     }
@@ -0,0 +1,112 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Placeholder device backing the MMIO slots reserved for hot-plugging.
+//!
+//! A reserved slot is announced to the guest at boot time like any other virtio-mmio device,
+//! but it reports a device ID of 0. The guest virtio-mmio driver refuses to bind to such a
+//! device, leaving the platform device in place so that it can be re-probed once a real
+//! device has been plugged in the slot.
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use utils::eventfd::EventFd;
+use vm_memory::GuestMemoryMmap;
+
+use super::{ActivateError, ActivateResult, Queue, VirtioDevice};
+
+/// Virtio device ID reported by an empty hot-plug slot.
+pub const TYPE_EMPTY_SLOT: u32 = 0;
+
+/// Virtio device occupying a free hot-plug slot.
+pub struct EmptySlot {
+    interrupt_evt: EventFd,
+    interrupt_status: Arc<AtomicUsize>,
+}
+
+impl EmptySlot {
+    /// Creates a new empty slot.
+    pub fn new() -> std::io::Result<EmptySlot> {
+        Ok(EmptySlot {
+            interrupt_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+}
+
+impl VirtioDevice for EmptySlot {
+    fn avail_features(&self) -> u64 {
+        0
+    }
+
+    fn acked_features(&self) -> u64 {
+        0
+    }
+
+    fn set_acked_features(&mut self, _acked_features: u64) {}
+
+    fn device_type(&self) -> u32 {
+        TYPE_EMPTY_SLOT
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &[]
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut []
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &[]
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.interrupt_evt
+    }
+
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.interrupt_status.clone()
+    }
+
+    fn read_config(&self, _offset: u64, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            *b = 0;
+        }
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    fn activate(&mut self, _mem: GuestMemoryMmap) -> ActivateResult {
+        Err(ActivateError::BadActivate)
+    }
+
+    fn is_activated(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::test_utils::default_mem;
+
+    #[test]
+    fn test_empty_slot() {
+        let mut slot = EmptySlot::new().unwrap();
+
+        assert_eq!(slot.device_type(), TYPE_EMPTY_SLOT);
+        assert_eq!(slot.avail_features(), 0);
+        assert!(slot.queues().is_empty());
+        assert!(slot.queues_mut().is_empty());
+        assert!(slot.queue_events().is_empty());
+
+        let mut data = [0xffu8; 4];
+        slot.read_config(0, &mut data);
+        assert_eq!(data, [0u8; 4]);
+
+        assert!(slot.activate(default_mem()).is_err());
+        assert!(!slot.is_activated());
+    }
+}
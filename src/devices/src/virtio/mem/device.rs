@@ -0,0 +1,1006 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cmp;
+use std::io::Write;
+use std::ops::Range;
+use std::result::Result;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use logger::{error, IncMetric, METRICS};
+use utils::eventfd::EventFd;
+use virtio_gen::virtio_blk::VIRTIO_F_VERSION_1;
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::super::balloon::utils::remove_range;
+use super::super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_MEM};
+use super::{
+    GUEST_REQUEST_INDEX, MEM_DEV_ID, NUM_QUEUES, QUEUE_SIZES, VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE,
+    VIRTIO_MEM_REQ_PLUG, VIRTIO_MEM_REQ_STATE, VIRTIO_MEM_REQ_UNPLUG, VIRTIO_MEM_REQ_UNPLUG_ALL,
+    VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_RESP_ERROR, VIRTIO_MEM_RESP_NACK, VIRTIO_MEM_STATE_MIXED,
+    VIRTIO_MEM_STATE_PLUGGED, VIRTIO_MEM_STATE_UNPLUGGED,
+};
+use crate::virtio::mem::Error as MemError;
+use crate::virtio::{IrqTrigger, IrqType};
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct ConfigSpace {
+    pub block_size: u64,
+    pub node_id: u16,
+    pub padding: [u8; 6],
+    pub addr: u64,
+    pub region_size: u64,
+    pub usable_region_size: u64,
+    pub plugged_size: u64,
+    pub requested_size: u64,
+}
+
+// Safe because ConfigSpace only contains plain data.
+unsafe impl ByteValued for ConfigSpace {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Request {
+    req_type: u16,
+    padding: [u16; 3],
+    addr: u64,
+    nb_blocks: u16,
+    padding_1: [u16; 3],
+}
+
+// Safe because Request only contains plain data.
+unsafe impl ByteValued for Request {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Response {
+    resp_type: u16,
+    padding: [u16; 3],
+    state: u16,
+}
+
+// Safe because Response only contains plain data.
+unsafe impl ByteValued for Response {}
+
+impl Response {
+    fn with_type(resp_type: u16) -> Self {
+        Response {
+            resp_type,
+            ..Default::default()
+        }
+    }
+
+    fn with_state(state: u16) -> Self {
+        Response {
+            resp_type: VIRTIO_MEM_RESP_ACK,
+            state,
+            ..Default::default()
+        }
+    }
+}
+
+const SIZE_OF_REQUEST: usize = std::mem::size_of::<Request>();
+const SIZE_OF_RESPONSE: usize = std::mem::size_of::<Response>();
+
+// VirtioMemConfig holds the layout and the sizes of the hot-pluggable region, in bytes.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct VirtioMemConfig {
+    pub addr: u64,
+    pub region_size: u64,
+    pub block_size: u64,
+    pub plugged_size: u64,
+    pub requested_size: u64,
+}
+
+/// Maps the blocks plugged by the guest in the guest physical address space. The guest can't
+/// access the blocks which are not mapped.
+pub trait BlockMapper: Send {
+    /// Maps the `index`th block of the region.
+    fn map_block(&mut self, index: usize) -> std::io::Result<()>;
+    /// Unmaps the `index`th block of the region.
+    fn unmap_block(&mut self, index: usize) -> std::io::Result<()>;
+}
+
+// Virtio memory device.
+pub struct VirtioMem {
+    // Virtio fields.
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    pub(crate) config_space: ConfigSpace,
+    pub(crate) activate_evt: EventFd,
+
+    // Transport related fields.
+    pub(crate) queues: Vec<Queue>,
+    pub(crate) queue_evts: [EventFd; NUM_QUEUES],
+    pub(crate) device_state: DeviceState,
+    pub(crate) irq_trigger: IrqTrigger,
+
+    // Implementation specific fields.
+    pub(crate) restored: bool,
+    // Whether each block of the region is plugged.
+    pub(crate) plugged_blocks: Vec<bool>,
+    pub(crate) block_mapper: Option<Box<dyn BlockMapper>>,
+}
+
+impl VirtioMem {
+    pub fn new(
+        addr: GuestAddress,
+        region_size: u64,
+        block_size: u64,
+        restored: bool,
+    ) -> Result<VirtioMem, MemError> {
+        if !block_size.is_power_of_two() || region_size == 0 || region_size % block_size != 0 {
+            return Err(MemError::InvalidBlockSize(block_size));
+        }
+
+        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK).map_err(MemError::EventFd)?];
+        let queues: Vec<Queue> = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+
+        Ok(VirtioMem {
+            avail_features: (1u64 << VIRTIO_F_VERSION_1)
+                | (1u64 << VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE),
+            acked_features: 0u64,
+            config_space: ConfigSpace {
+                block_size,
+                addr: addr.0,
+                region_size,
+                usable_region_size: region_size,
+                ..Default::default()
+            },
+            queue_evts,
+            queues,
+            irq_trigger: IrqTrigger::new().map_err(MemError::EventFd)?,
+            device_state: DeviceState::Inactive,
+            activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(MemError::EventFd)?,
+            restored,
+            plugged_blocks: vec![false; (region_size / block_size) as usize],
+            block_mapper: None,
+        })
+    }
+
+    /// Sets the mapper of the plugged blocks, and maps the blocks which are already plugged.
+    pub fn set_block_mapper(&mut self, mut mapper: Box<dyn BlockMapper>) -> Result<(), MemError> {
+        for (index, _) in self.plugged_blocks.iter().enumerate().filter(|(_, p)| **p) {
+            mapper.map_block(index).map_err(MemError::MapBlock)?;
+        }
+        self.block_mapper = Some(mapper);
+        Ok(())
+    }
+
+    // Maps all the blocks in `range`, or none of them.
+    fn map_blocks(&mut self, range: Range<usize>) -> std::io::Result<()> {
+        if let Some(mapper) = self.block_mapper.as_mut() {
+            for index in range.clone() {
+                if let Err(err) = mapper.map_block(index) {
+                    for mapped in range.start..index {
+                        let _ = mapper.unmap_block(mapped);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn unmap_blocks(&mut self, range: Range<usize>) -> std::io::Result<()> {
+        if let Some(mapper) = self.block_mapper.as_mut() {
+            for index in range {
+                mapper.unmap_block(index)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn process_guest_request_queue_event(&mut self) -> Result<(), MemError> {
+        self.queue_evts[GUEST_REQUEST_INDEX]
+            .read()
+            .map_err(MemError::EventFd)?;
+        self.process_guest_request_queue()
+    }
+
+    pub(crate) fn process_guest_request_queue(&mut self) -> Result<(), MemError> {
+        // This is safe since we checked in the event handler that the device is activated.
+        // The memory is cloned, since the blocks are plugged and unplugged while the queue
+        // is in use.
+        let mem = self.device_state.mem().unwrap().clone();
+        let mut needs_interrupt = false;
+
+        while let Some(head) = self.queues[GUEST_REQUEST_INDEX].pop(&mem) {
+            let head_index = head.index;
+            // Each request is followed by a write-only descriptor holding the response.
+            let len = match head.next_descriptor() {
+                Some(resp_desc)
+                    if !head.is_write_only()
+                        && head.len as usize >= SIZE_OF_REQUEST
+                        && resp_desc.is_write_only()
+                        && resp_desc.len as usize >= SIZE_OF_RESPONSE =>
+                {
+                    let request = mem
+                        .read_obj::<Request>(head.addr)
+                        .map_err(MemError::GuestMemory)?;
+                    let response = self.handle_request(&mem, &request);
+                    mem.write_obj(response, resp_desc.addr)
+                        .map_err(MemError::GuestMemory)?;
+                    SIZE_OF_RESPONSE as u32
+                }
+                _ => {
+                    error!("virtio-mem: Received malformed request.");
+                    METRICS.virtio_mem.event_fails.inc();
+                    0
+                }
+            };
+
+            self.queues[GUEST_REQUEST_INDEX]
+                .add_used(&mem, head_index, len)
+                .map_err(MemError::Queue)?;
+            needs_interrupt = true;
+        }
+
+        if needs_interrupt {
+            self.signal_used_queue()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn handle_request(&mut self, mem: &GuestMemoryMmap, request: &Request) -> Response {
+        match request.req_type {
+            VIRTIO_MEM_REQ_PLUG => {
+                let response = self.plug_blocks(request.addr, request.nb_blocks);
+                if response.resp_type == VIRTIO_MEM_RESP_ACK {
+                    METRICS.virtio_mem.plug_count.inc();
+                } else {
+                    METRICS.virtio_mem.plug_fails.inc();
+                }
+                response
+            }
+            VIRTIO_MEM_REQ_UNPLUG => {
+                let response = self.unplug_blocks(mem, request.addr, request.nb_blocks);
+                if response.resp_type == VIRTIO_MEM_RESP_ACK {
+                    METRICS.virtio_mem.unplug_count.inc();
+                } else {
+                    METRICS.virtio_mem.unplug_fails.inc();
+                }
+                response
+            }
+            VIRTIO_MEM_REQ_UNPLUG_ALL => {
+                let response = self.unplug_all(mem);
+                if response.resp_type == VIRTIO_MEM_RESP_ACK {
+                    METRICS.virtio_mem.unplug_count.inc();
+                } else {
+                    METRICS.virtio_mem.unplug_fails.inc();
+                }
+                response
+            }
+            VIRTIO_MEM_REQ_STATE => self.blocks_state(request.addr, request.nb_blocks),
+            req_type => {
+                error!("virtio-mem: Received unknown request type {}.", req_type);
+                Response::with_type(VIRTIO_MEM_RESP_ERROR)
+            }
+        }
+    }
+
+    // Returns the indices of the `nb_blocks` blocks starting at `addr`, if they all lie in the
+    // usable part of the region.
+    fn block_range(&self, addr: u64, nb_blocks: u16) -> Option<Range<usize>> {
+        let block_size = self.config_space.block_size;
+        let offset = addr.checked_sub(self.config_space.addr)?;
+        if nb_blocks == 0 || offset % block_size != 0 {
+            return None;
+        }
+
+        let first = offset / block_size;
+        let end = first + u64::from(nb_blocks);
+        if end > self.config_space.usable_region_size / block_size {
+            return None;
+        }
+
+        Some(first as usize..end as usize)
+    }
+
+    fn plug_blocks(&mut self, addr: u64, nb_blocks: u16) -> Response {
+        let range = match self.block_range(addr, nb_blocks) {
+            Some(range) => range,
+            None => return Response::with_type(VIRTIO_MEM_RESP_ERROR),
+        };
+        if self.plugged_blocks[range.clone()]
+            .iter()
+            .any(|&plugged| plugged)
+        {
+            return Response::with_type(VIRTIO_MEM_RESP_ERROR);
+        }
+
+        // The driver can't plug more memory than requested.
+        let size = u64::from(nb_blocks) * self.config_space.block_size;
+        if self.config_space.plugged_size + size > self.config_space.requested_size {
+            return Response::with_type(VIRTIO_MEM_RESP_NACK);
+        }
+
+        if let Err(err) = self.map_blocks(range.clone()) {
+            error!("virtio-mem: Error mapping plugged memory: {:?}", err);
+            return Response::with_type(VIRTIO_MEM_RESP_ERROR);
+        }
+
+        self.plugged_blocks[range].fill(true);
+        self.config_space.plugged_size += size;
+        Response::with_type(VIRTIO_MEM_RESP_ACK)
+    }
+
+    fn unplug_blocks(&mut self, mem: &GuestMemoryMmap, addr: u64, nb_blocks: u16) -> Response {
+        let range = match self.block_range(addr, nb_blocks) {
+            Some(range) => range,
+            None => return Response::with_type(VIRTIO_MEM_RESP_ERROR),
+        };
+        if !self.plugged_blocks[range.clone()]
+            .iter()
+            .all(|&plugged| plugged)
+        {
+            return Response::with_type(VIRTIO_MEM_RESP_ERROR);
+        }
+
+        if let Err(err) = self.unmap_blocks(range.clone()) {
+            error!("virtio-mem: Error unmapping unplugged memory: {:?}", err);
+            return Response::with_type(VIRTIO_MEM_RESP_ERROR);
+        }
+
+        let size = u64::from(nb_blocks) * self.config_space.block_size;
+        if let Err(err) = remove_range(mem, (GuestAddress(addr), size), self.restored) {
+            error!("virtio-mem: Error releasing unplugged memory: {:?}", err);
+            return Response::with_type(VIRTIO_MEM_RESP_ERROR);
+        }
+
+        self.plugged_blocks[range].fill(false);
+        self.config_space.plugged_size -= size;
+        Response::with_type(VIRTIO_MEM_RESP_ACK)
+    }
+
+    fn unplug_all(&mut self, mem: &GuestMemoryMmap) -> Response {
+        for index in 0..self.plugged_blocks.len() {
+            if !self.plugged_blocks[index] {
+                continue;
+            }
+            if let Err(err) = self.unmap_blocks(index..index + 1) {
+                error!("virtio-mem: Error unmapping unplugged memory: {:?}", err);
+                return Response::with_type(VIRTIO_MEM_RESP_ERROR);
+            }
+            // Keep track of the blocks which were unmapped, should unmapping the next one fail.
+            self.plugged_blocks[index] = false;
+            self.config_space.plugged_size -= self.config_space.block_size;
+        }
+
+        let region = (
+            GuestAddress(self.config_space.addr),
+            self.config_space.region_size,
+        );
+        if let Err(err) = remove_range(mem, region, self.restored) {
+            error!("virtio-mem: Error releasing unplugged memory: {:?}", err);
+            return Response::with_type(VIRTIO_MEM_RESP_ERROR);
+        }
+
+        Response::with_type(VIRTIO_MEM_RESP_ACK)
+    }
+
+    fn blocks_state(&self, addr: u64, nb_blocks: u16) -> Response {
+        let range = match self.block_range(addr, nb_blocks) {
+            Some(range) => range,
+            None => return Response::with_type(VIRTIO_MEM_RESP_ERROR),
+        };
+
+        let blocks = &self.plugged_blocks[range];
+        if blocks.iter().all(|&plugged| plugged) {
+            Response::with_state(VIRTIO_MEM_STATE_PLUGGED)
+        } else if blocks.iter().all(|&plugged| !plugged) {
+            Response::with_state(VIRTIO_MEM_STATE_UNPLUGGED)
+        } else {
+            Response::with_state(VIRTIO_MEM_STATE_MIXED)
+        }
+    }
+
+    pub(crate) fn signal_used_queue(&self) -> Result<(), MemError> {
+        self.irq_trigger.trigger_irq(IrqType::Vring).map_err(|err| {
+            METRICS.virtio_mem.event_fails.inc();
+            MemError::InterruptError(err)
+        })
+    }
+
+    /// Process device virtio queue(s).
+    pub fn process_virtio_queues(&mut self) {
+        let _ = self.process_guest_request_queue();
+    }
+
+    pub fn id(&self) -> &str {
+        MEM_DEV_ID
+    }
+
+    /// Asks the driver to plug or unplug blocks until `requested_size` bytes are plugged.
+    pub fn update_requested_size(&mut self, requested_size: u64) -> Result<(), MemError> {
+        if requested_size > self.config_space.usable_region_size
+            || requested_size % self.config_space.block_size != 0
+        {
+            return Err(MemError::InvalidRequestedSize(requested_size));
+        }
+
+        self.config_space.requested_size = requested_size;
+        // The driver reads the requested size when it is loaded, so it only has to be
+        // notified once the device is activated.
+        if self.is_activated() {
+            self.irq_trigger
+                .trigger_irq(IrqType::Config)
+                .map_err(MemError::InterruptError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the guest ranges of the region which are not plugged.
+    pub fn unplugged_ranges(&self) -> Vec<(GuestAddress, u64)> {
+        let block_size = self.config_space.block_size;
+        let mut ranges: Vec<(GuestAddress, u64)> = Vec::new();
+
+        for (index, &plugged) in self.plugged_blocks.iter().enumerate() {
+            if plugged {
+                continue;
+            }
+            let addr = self.config_space.addr + index as u64 * block_size;
+            match ranges.last_mut() {
+                Some((start, len)) if start.0 + *len == addr => *len += block_size,
+                _ => ranges.push((GuestAddress(addr), block_size)),
+            }
+        }
+
+        ranges
+    }
+
+    pub fn config(&self) -> VirtioMemConfig {
+        VirtioMemConfig {
+            addr: self.config_space.addr,
+            region_size: self.config_space.region_size,
+            block_size: self.config_space.block_size,
+            plugged_size: self.config_space.plugged_size,
+            requested_size: self.config_space.requested_size,
+        }
+    }
+}
+
+impl VirtioDevice for VirtioMem {
+    fn device_type(&self) -> u32 {
+        TYPE_MEM
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_evts
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.irq_trigger.irq_evt
+    }
+
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.irq_trigger.irq_status.clone()
+    }
+
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_space_bytes = self.config_space.as_slice();
+        let config_len = config_space_bytes.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(
+                &config_space_bytes[offset as usize..cmp::min(end, config_len) as usize],
+            )
+            .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // The configuration space is read-only for the driver.
+        error!(
+            "virtio-mem: Failed to write config space at offset {} of length {}",
+            offset,
+            data.len()
+        );
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        self.device_state = DeviceState::Activated(mem);
+        if self.activate_evt.write(1).is_err() {
+            error!("virtio-mem: Cannot write to activate_evt");
+            METRICS.virtio_mem.activate_fails.inc();
+            self.device_state = DeviceState::Inactive;
+            return Err(super::super::ActivateError::BadActivate);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use super::super::CONFIG_SPACE_SIZE;
+    use super::*;
+    use crate::virtio::test_utils::VirtQueue;
+    use crate::virtio::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const REGION_ADDR: u64 = 0x10_0000;
+    const REGION_SIZE: u64 = 0x1_0000;
+    const BLOCK_SIZE: u64 = 0x1000;
+    const REQUEST_ADDR: u64 = 0x1000;
+    const RESPONSE_ADDR: u64 = 0x2000;
+
+    pub(crate) fn default_mem_with_region() -> GuestMemoryMmap {
+        vm_memory::test_utils::create_anon_guest_memory(
+            &[
+                (GuestAddress(0), 0x1_0000),
+                (GuestAddress(REGION_ADDR), REGION_SIZE as usize),
+            ],
+            false,
+        )
+        .unwrap()
+    }
+
+    pub(crate) fn default_virtio_mem() -> VirtioMem {
+        VirtioMem::new(GuestAddress(REGION_ADDR), REGION_SIZE, BLOCK_SIZE, false).unwrap()
+    }
+
+    // Sends a request through the `idx`th descriptors pair of the queue, and returns the
+    // response of the device.
+    fn send_request(
+        virtio_mem: &mut VirtioMem,
+        mem: &GuestMemoryMmap,
+        queue: &VirtQueue,
+        idx: u16,
+        req_type: u16,
+        addr: u64,
+        nb_blocks: u16,
+    ) -> Response {
+        let request = Request {
+            req_type,
+            addr,
+            nb_blocks,
+            ..Default::default()
+        };
+        mem.write_obj(request, GuestAddress(REQUEST_ADDR)).unwrap();
+        mem.write_obj(Response::with_type(0xff), GuestAddress(RESPONSE_ADDR))
+            .unwrap();
+
+        queue.avail.ring[idx as usize].set(0);
+        queue.avail.idx.set(idx + 1);
+        queue.dtable[0].set(REQUEST_ADDR, SIZE_OF_REQUEST as u32, VIRTQ_DESC_F_NEXT, 1);
+        queue.dtable[1].set(
+            RESPONSE_ADDR,
+            SIZE_OF_RESPONSE as u32,
+            VIRTQ_DESC_F_WRITE,
+            0,
+        );
+
+        virtio_mem.queue_evts[GUEST_REQUEST_INDEX].write(1).unwrap();
+        virtio_mem.process_guest_request_queue_event().unwrap();
+        assert!(virtio_mem.irq_trigger.has_pending_irq(IrqType::Vring));
+        queue.check_used_elem(idx, 0, SIZE_OF_RESPONSE as u32);
+
+        mem.read_obj(GuestAddress(RESPONSE_ADDR)).unwrap()
+    }
+
+    #[test]
+    fn test_request_sizes() {
+        assert_eq!(std::mem::size_of::<ConfigSpace>(), CONFIG_SPACE_SIZE);
+        assert_eq!(SIZE_OF_REQUEST, 24);
+        assert_eq!(SIZE_OF_RESPONSE, 10);
+    }
+
+    #[test]
+    fn test_new() {
+        let virtio_mem = default_virtio_mem();
+        assert_eq!(virtio_mem.device_type(), TYPE_MEM);
+        assert_eq!(
+            virtio_mem.avail_features(),
+            (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE)
+        );
+        assert_eq!(virtio_mem.queues().len(), NUM_QUEUES);
+        assert_eq!(virtio_mem.plugged_blocks.len(), 16);
+        assert_eq!(
+            virtio_mem.config(),
+            VirtioMemConfig {
+                addr: REGION_ADDR,
+                region_size: REGION_SIZE,
+                block_size: BLOCK_SIZE,
+                plugged_size: 0,
+                requested_size: 0,
+            }
+        );
+
+        // The block size has to be a power of two.
+        assert!(matches!(
+            VirtioMem::new(GuestAddress(REGION_ADDR), REGION_SIZE, 0x3000, false),
+            Err(MemError::InvalidBlockSize(0x3000))
+        ));
+        // The region has to be made of whole blocks.
+        assert!(matches!(
+            VirtioMem::new(GuestAddress(REGION_ADDR), 0x1800, BLOCK_SIZE, false),
+            Err(MemError::InvalidBlockSize(BLOCK_SIZE))
+        ));
+        assert!(matches!(
+            VirtioMem::new(GuestAddress(REGION_ADDR), 0, BLOCK_SIZE, false),
+            Err(MemError::InvalidBlockSize(BLOCK_SIZE))
+        ));
+    }
+
+    #[test]
+    fn test_virtio_read_config() {
+        let mut virtio_mem = default_virtio_mem();
+        virtio_mem.update_requested_size(0x2000).unwrap();
+
+        let cfg = ConfigSpace {
+            block_size: BLOCK_SIZE,
+            addr: REGION_ADDR,
+            region_size: REGION_SIZE,
+            usable_region_size: REGION_SIZE,
+            requested_size: 0x2000,
+            ..Default::default()
+        };
+        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        virtio_mem.read_config(0, &mut actual_config_space);
+        assert_eq!(actual_config_space, cfg.as_slice());
+
+        // Partial reads are allowed, reads past the end are not.
+        let mut block_size = [0u8; 8];
+        virtio_mem.read_config(0, &mut block_size);
+        assert_eq!(u64::from_le_bytes(block_size), BLOCK_SIZE);
+        let mut data = [0xffu8; 8];
+        virtio_mem.read_config(CONFIG_SPACE_SIZE as u64, &mut data);
+        assert_eq!(data, [0xffu8; 8]);
+    }
+
+    #[test]
+    fn test_virtio_write_config() {
+        let mut virtio_mem = default_virtio_mem();
+        let expected_config_space = virtio_mem.config_space;
+
+        // The driver can't change the configuration.
+        virtio_mem.write_config(0, &[0u8; 8]);
+        assert_eq!(virtio_mem.config_space, expected_config_space);
+    }
+
+    #[test]
+    fn test_update_requested_size() {
+        let mut virtio_mem = default_virtio_mem();
+
+        // The device isn't notified before it is activated.
+        virtio_mem.update_requested_size(0x4000).unwrap();
+        assert_eq!(virtio_mem.config().requested_size, 0x4000);
+        assert!(!virtio_mem.irq_trigger.has_pending_irq(IrqType::Config));
+
+        virtio_mem.activate(default_mem_with_region()).unwrap();
+        virtio_mem.update_requested_size(REGION_SIZE).unwrap();
+        assert_eq!(virtio_mem.config().requested_size, REGION_SIZE);
+        assert!(virtio_mem.irq_trigger.has_pending_irq(IrqType::Config));
+
+        // The requested size has to fit in the region and be made of whole blocks.
+        assert!(matches!(
+            virtio_mem.update_requested_size(REGION_SIZE + BLOCK_SIZE),
+            Err(MemError::InvalidRequestedSize(_))
+        ));
+        assert!(matches!(
+            virtio_mem.update_requested_size(0x800),
+            Err(MemError::InvalidRequestedSize(0x800))
+        ));
+        assert_eq!(virtio_mem.config().requested_size, REGION_SIZE);
+    }
+
+    #[test]
+    fn test_plug_unplug() {
+        let mut virtio_mem = default_virtio_mem();
+        let mem = default_mem_with_region();
+        let queue = VirtQueue::new(GuestAddress(0x4000), &mem, 16);
+        virtio_mem.queues[GUEST_REQUEST_INDEX] = queue.create_queue();
+        virtio_mem.activate(mem.clone()).unwrap();
+        virtio_mem.update_requested_size(0x4000).unwrap();
+
+        // Plug 3 blocks.
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            0,
+            VIRTIO_MEM_REQ_PLUG,
+            REGION_ADDR,
+            3,
+        );
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_ACK));
+        assert_eq!(virtio_mem.config().plugged_size, 0x3000);
+
+        // Plugging more than requested is refused.
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            1,
+            VIRTIO_MEM_REQ_PLUG,
+            REGION_ADDR + 0x3000,
+            2,
+        );
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_NACK));
+
+        // Plugging blocks which are already plugged is an error.
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            2,
+            VIRTIO_MEM_REQ_PLUG,
+            REGION_ADDR + 0x2000,
+            1,
+        );
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_ERROR));
+
+        // So are unaligned and out of range requests.
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            3,
+            VIRTIO_MEM_REQ_PLUG,
+            REGION_ADDR + 0x3800,
+            1,
+        );
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_ERROR));
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            4,
+            VIRTIO_MEM_REQ_STATE,
+            REGION_ADDR + 0xf000,
+            2,
+        );
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_ERROR));
+
+        // The state of the blocks is reported.
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            5,
+            VIRTIO_MEM_REQ_STATE,
+            REGION_ADDR,
+            3,
+        );
+        assert_eq!(response, Response::with_state(VIRTIO_MEM_STATE_PLUGGED));
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            6,
+            VIRTIO_MEM_REQ_STATE,
+            REGION_ADDR + 0x2000,
+            2,
+        );
+        assert_eq!(response, Response::with_state(VIRTIO_MEM_STATE_MIXED));
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            7,
+            VIRTIO_MEM_REQ_STATE,
+            REGION_ADDR + 0x3000,
+            13,
+        );
+        assert_eq!(response, Response::with_state(VIRTIO_MEM_STATE_UNPLUGGED));
+
+        // Unplugging a block releases its memory.
+        mem.write_obj(0xaau8, GuestAddress(REGION_ADDR + 0x1000))
+            .unwrap();
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            8,
+            VIRTIO_MEM_REQ_UNPLUG,
+            REGION_ADDR + 0x1000,
+            1,
+        );
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_ACK));
+        assert_eq!(virtio_mem.config().plugged_size, 0x2000);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(REGION_ADDR + 0x1000))
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            virtio_mem.unplugged_ranges(),
+            vec![
+                (GuestAddress(REGION_ADDR + 0x1000), 0x1000),
+                (GuestAddress(REGION_ADDR + 0x3000), 0xd000)
+            ]
+        );
+
+        // Unplugging blocks which aren't all plugged is an error.
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            9,
+            VIRTIO_MEM_REQ_UNPLUG,
+            REGION_ADDR,
+            2,
+        );
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_ERROR));
+
+        // Unplug all the blocks.
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            10,
+            VIRTIO_MEM_REQ_UNPLUG_ALL,
+            0,
+            0,
+        );
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_ACK));
+        assert_eq!(virtio_mem.config().plugged_size, 0);
+        assert_eq!(
+            virtio_mem.unplugged_ranges(),
+            vec![(GuestAddress(REGION_ADDR), REGION_SIZE)]
+        );
+
+        // Unknown requests are errors.
+        let response = send_request(&mut virtio_mem, &mem, &queue, 11, 42, REGION_ADDR, 1);
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_ERROR));
+    }
+
+    // Keeps track of the mapped blocks, and fails to map the `fail_index`th one.
+    struct MockBlockMapper {
+        mapped: Arc<Mutex<Vec<bool>>>,
+        fail_index: usize,
+    }
+
+    impl BlockMapper for MockBlockMapper {
+        fn map_block(&mut self, index: usize) -> std::io::Result<()> {
+            if index == self.fail_index {
+                return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            self.mapped.lock().unwrap()[index] = true;
+            Ok(())
+        }
+
+        fn unmap_block(&mut self, index: usize) -> std::io::Result<()> {
+            self.mapped.lock().unwrap()[index] = false;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_block_mapper() {
+        let mut virtio_mem = default_virtio_mem();
+        let mem = default_mem_with_region();
+        let queue = VirtQueue::new(GuestAddress(0x4000), &mem, 16);
+        virtio_mem.queues[GUEST_REQUEST_INDEX] = queue.create_queue();
+        virtio_mem.activate(mem.clone()).unwrap();
+        virtio_mem.update_requested_size(0x8000).unwrap();
+
+        // The blocks which are already plugged are mapped along with the mapper.
+        virtio_mem.plugged_blocks[0] = true;
+        virtio_mem.config_space.plugged_size = BLOCK_SIZE;
+        let mapped = Arc::new(Mutex::new(vec![false; 16]));
+        virtio_mem
+            .set_block_mapper(Box::new(MockBlockMapper {
+                mapped: mapped.clone(),
+                fail_index: 5,
+            }))
+            .unwrap();
+        assert_eq!(mapped.lock().unwrap()[..3], [true, false, false]);
+
+        // Plugged blocks are mapped.
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            0,
+            VIRTIO_MEM_REQ_PLUG,
+            REGION_ADDR + 0x1000,
+            2,
+        );
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_ACK));
+        assert_eq!(mapped.lock().unwrap()[..3], [true, true, true]);
+
+        // Blocks which can't all be mapped are not plugged.
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            1,
+            VIRTIO_MEM_REQ_PLUG,
+            REGION_ADDR + 0x3000,
+            3,
+        );
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_ERROR));
+        assert_eq!(mapped.lock().unwrap()[3..6], [false, false, false]);
+        assert_eq!(virtio_mem.config().plugged_size, 0x3000);
+
+        // Unplugged blocks are unmapped.
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            2,
+            VIRTIO_MEM_REQ_UNPLUG,
+            REGION_ADDR + 0x1000,
+            1,
+        );
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_ACK));
+        assert_eq!(mapped.lock().unwrap()[..3], [true, false, true]);
+
+        let response = send_request(
+            &mut virtio_mem,
+            &mem,
+            &queue,
+            3,
+            VIRTIO_MEM_REQ_UNPLUG_ALL,
+            0,
+            0,
+        );
+        assert_eq!(response, Response::with_type(VIRTIO_MEM_RESP_ACK));
+        assert!(mapped.lock().unwrap().iter().all(|&mapped| !mapped));
+        assert_eq!(virtio_mem.config().plugged_size, 0);
+    }
+
+    #[test]
+    fn test_malformed_request() {
+        let mut virtio_mem = default_virtio_mem();
+        let mem = default_mem_with_region();
+        let queue = VirtQueue::new(GuestAddress(0x4000), &mem, 16);
+        virtio_mem.queues[GUEST_REQUEST_INDEX] = queue.create_queue();
+        virtio_mem.activate(mem.clone()).unwrap();
+        virtio_mem.update_requested_size(0x4000).unwrap();
+
+        // The request is missing the response descriptor.
+        let request = Request {
+            req_type: VIRTIO_MEM_REQ_PLUG,
+            addr: REGION_ADDR,
+            nb_blocks: 1,
+            ..Default::default()
+        };
+        mem.write_obj(request, GuestAddress(REQUEST_ADDR)).unwrap();
+        queue.avail.ring[0].set(0);
+        queue.avail.idx.set(1);
+        queue.dtable[0].set(REQUEST_ADDR, SIZE_OF_REQUEST as u32, 0, 0);
+
+        virtio_mem.queue_evts[GUEST_REQUEST_INDEX].write(1).unwrap();
+        virtio_mem.process_guest_request_queue_event().unwrap();
+        // The request is completed without being handled.
+        queue.check_used_elem(0, 0, 0);
+        assert_eq!(virtio_mem.config().plugged_size, 0);
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::os::unix::io::AsRawFd;
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use logger::{debug, error, warn};
+use utils::epoll::EventSet;
+
+use crate::report_virtio_mem_event_fail;
+use crate::virtio::mem::device::VirtioMem;
+use crate::virtio::mem::GUEST_REQUEST_INDEX;
+use crate::virtio::VirtioDevice;
+
+impl VirtioMem {
+    fn register_runtime_events(&self, ops: &mut EventOps) {
+        if let Err(err) = ops.add(Events::new(
+            &self.queue_evts[GUEST_REQUEST_INDEX],
+            EventSet::IN,
+        )) {
+            error!("Failed to register guest request queue event: {}", err);
+        }
+    }
+
+    fn register_activate_event(&self, ops: &mut EventOps) {
+        if let Err(err) = ops.add(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to register activate event: {}", err);
+        }
+    }
+
+    fn process_activate_event(&self, ops: &mut EventOps) {
+        debug!("virtio-mem: activate event");
+        if let Err(err) = self.activate_evt.read() {
+            error!("Failed to consume virtio-mem activate event: {:?}", err);
+        }
+        self.register_runtime_events(ops);
+        if let Err(err) = ops.remove(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to un-register activate event: {}", err);
+        }
+    }
+}
+
+impl MutEventSubscriber for VirtioMem {
+    fn process(&mut self, event: Events, ops: &mut EventOps) {
+        let source = event.fd();
+        let event_set = event.event_set();
+        let supported_events = EventSet::IN;
+
+        if !supported_events.contains(event_set) {
+            warn!(
+                "Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if self.is_activated() {
+            let virtq_guest_request_ev_fd = self.queue_evts[GUEST_REQUEST_INDEX].as_raw_fd();
+            let activate_fd = self.activate_evt.as_raw_fd();
+
+            // Looks better than C style if/else if/else.
+            match source {
+                _ if source == virtq_guest_request_ev_fd => self
+                    .process_guest_request_queue_event()
+                    .unwrap_or_else(report_virtio_mem_event_fail),
+                _ if activate_fd == source => self.process_activate_event(ops),
+                _ => {
+                    warn!("virtio-mem: Spurious event received: {:?}", source);
+                }
+            };
+        } else {
+            warn!(
+                "virtio-mem: The device is not yet activated. Spurious event received: {:?}",
+                source
+            );
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        // This function can be called during different points in the device lifetime:
+        //  - shortly after device creation,
+        //  - on device activation (is-activated already true at this point),
+        //  - on device restore from snapshot.
+        if self.is_activated() {
+            self.register_runtime_events(ops);
+        } else {
+            self.register_activate_event(ops);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use event_manager::{EventManager, SubscriberOps};
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::mem::device::tests::{default_mem_with_region, default_virtio_mem};
+    use crate::virtio::test_utils::VirtQueue;
+
+    #[test]
+    fn test_event_handler() {
+        let mut event_manager = EventManager::new().unwrap();
+        let mut virtio_mem = default_virtio_mem();
+        let mem = default_mem_with_region();
+        let queue = VirtQueue::new(GuestAddress(0), &mem, 16);
+        virtio_mem.queues[GUEST_REQUEST_INDEX] = queue.create_queue();
+
+        let virtio_mem = Arc::new(Mutex::new(virtio_mem));
+        let _id = event_manager.add_subscriber(virtio_mem.clone());
+
+        // Push a malformed request, which is still completed by the device.
+        queue.avail.ring[0].set(0);
+        queue.avail.idx.set(1);
+        queue.dtable[0].set(0x1000, 24, 0, 0);
+        virtio_mem.lock().unwrap().queue_evts[GUEST_REQUEST_INDEX]
+            .write(1)
+            .unwrap();
+
+        // EventManager should report no events since the device has only registered
+        // its activation event so far (even though there is also a queue event pending).
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 0);
+
+        // Now activate the device.
+        virtio_mem.lock().unwrap().activate(mem.clone()).unwrap();
+        // Process the activate event.
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 1);
+
+        // Handle the previously pushed queue event through EventManager.
+        event_manager
+            .run_with_timeout(100)
+            .expect("Metrics event timeout or error.");
+        // Make sure the queue advanced.
+        assert_eq!(queue.used.idx.get(), 1);
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements the virtio-mem device, which hot-plugs guest memory in blocks.
+//!
+//! The device owns a guest-physical region, which is part of the guest memory but left out
+//! of the memory map announced at boot time. The guest driver plugs blocks of the region
+//! until `requested_size` bytes are plugged, and unplugs them when the requested size
+//! shrinks. The memory of the unplugged blocks is released to the host, and the guest can't
+//! access it.
+
+pub mod device;
+pub mod event_handler;
+pub mod persist;
+
+use vm_memory::GuestMemoryError;
+
+pub use self::device::{BlockMapper, VirtioMem, VirtioMemConfig};
+
+/// Device ID used in MMIO device identification.
+/// Because the virtio-mem device is unique per-vm, this ID can be hardcoded.
+pub const MEM_DEV_ID: &str = "mem";
+pub const CONFIG_SPACE_SIZE: usize = 56;
+pub const QUEUE_SIZE: u16 = 128;
+pub const NUM_QUEUES: usize = 1;
+pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+// The index of the guest request queue.
+pub const GUEST_REQUEST_INDEX: usize = 0;
+
+// The unplugged memory must not be accessed by the driver.
+const VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE: u32 = 1;
+
+// The request types.
+const VIRTIO_MEM_REQ_PLUG: u16 = 0;
+const VIRTIO_MEM_REQ_UNPLUG: u16 = 1;
+const VIRTIO_MEM_REQ_UNPLUG_ALL: u16 = 2;
+const VIRTIO_MEM_REQ_STATE: u16 = 3;
+
+// The response types.
+const VIRTIO_MEM_RESP_ACK: u16 = 0;
+const VIRTIO_MEM_RESP_NACK: u16 = 1;
+const VIRTIO_MEM_RESP_ERROR: u16 = 3;
+
+// The states of a range of blocks, returned by the state request.
+const VIRTIO_MEM_STATE_PLUGGED: u16 = 0;
+const VIRTIO_MEM_STATE_UNPLUGGED: u16 = 1;
+const VIRTIO_MEM_STATE_MIXED: u16 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Activation error.
+    Activate(super::ActivateError),
+    /// No virtio-mem device found.
+    DeviceNotFound,
+    /// EventFd error.
+    EventFd(std::io::Error),
+    /// Guest gave us bad memory addresses.
+    GuestMemory(GuestMemoryError),
+    /// The block size is not a power of two, or the region is not made of whole blocks.
+    InvalidBlockSize(u64),
+    /// Error mapping the plugged blocks in the guest physical address space.
+    MapBlock(std::io::Error),
+    /// The requested size is larger than the region, or not made of whole blocks.
+    InvalidRequestedSize(u64),
+    /// Received error while sending an interrupt.
+    InterruptError(std::io::Error),
+    /// Guest gave us a malformed descriptor.
+    MalformedDescriptor,
+    /// Error restoring the virtio-mem device queues.
+    QueueRestoreError,
+    /// Error while processing the virt queues.
+    Queue(super::QueueError),
+    /// Error releasing the memory of unplugged blocks.
+    RemoveMemoryRegion(super::balloon::RemoveRegionError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
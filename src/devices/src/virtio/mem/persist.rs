@@ -0,0 +1,168 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the structures needed for saving/restoring virtio-mem devices.
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use snapshot::Persist;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+use super::*;
+use crate::virtio::mem::device::ConfigSpace;
+use crate::virtio::persist::VirtioDeviceState;
+use crate::virtio::{DeviceState, TYPE_MEM};
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VirtioMemConfigSpaceState {
+    block_size: u64,
+    node_id: u16,
+    addr: u64,
+    region_size: u64,
+    usable_region_size: u64,
+    plugged_size: u64,
+    requested_size: u64,
+}
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VirtioMemState {
+    config_space: VirtioMemConfigSpaceState,
+    plugged_blocks: Vec<bool>,
+    virtio_state: VirtioDeviceState,
+}
+
+pub struct VirtioMemConstructorArgs {
+    pub mem: GuestMemoryMmap,
+}
+
+impl Persist<'_> for VirtioMem {
+    type State = VirtioMemState;
+    type ConstructorArgs = VirtioMemConstructorArgs;
+    type Error = super::Error;
+
+    fn save(&self) -> Self::State {
+        VirtioMemState {
+            config_space: VirtioMemConfigSpaceState {
+                block_size: self.config_space.block_size,
+                node_id: self.config_space.node_id,
+                addr: self.config_space.addr,
+                region_size: self.config_space.region_size,
+                usable_region_size: self.config_space.usable_region_size,
+                plugged_size: self.config_space.plugged_size,
+                requested_size: self.config_space.requested_size,
+            },
+            plugged_blocks: self.plugged_blocks.clone(),
+            virtio_state: VirtioDeviceState::from_device(self),
+        }
+    }
+
+    fn restore(
+        constructor_args: Self::ConstructorArgs,
+        state: &Self::State,
+    ) -> std::result::Result<Self, Self::Error> {
+        let config_space = &state.config_space;
+        let mut virtio_mem = VirtioMem::new(
+            GuestAddress(config_space.addr),
+            config_space.region_size,
+            config_space.block_size,
+            true,
+        )?;
+
+        if virtio_mem.plugged_blocks.len() != state.plugged_blocks.len() {
+            return Err(Self::Error::InvalidBlockSize(config_space.block_size));
+        }
+
+        virtio_mem.queues = state
+            .virtio_state
+            .build_queues_checked(&constructor_args.mem, TYPE_MEM, NUM_QUEUES, QUEUE_SIZE)
+            .map_err(|_| Self::Error::QueueRestoreError)?;
+        virtio_mem.irq_trigger.irq_status =
+            Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
+        virtio_mem.avail_features = state.virtio_state.avail_features;
+        virtio_mem.acked_features = state.virtio_state.acked_features;
+        virtio_mem.config_space = ConfigSpace {
+            block_size: config_space.block_size,
+            node_id: config_space.node_id,
+            padding: [0u8; 6],
+            addr: config_space.addr,
+            region_size: config_space.region_size,
+            usable_region_size: config_space.usable_region_size,
+            plugged_size: config_space.plugged_size,
+            requested_size: config_space.requested_size,
+        };
+        virtio_mem.plugged_blocks = state.plugged_blocks.clone();
+
+        if state.virtio_state.activated {
+            virtio_mem.device_state = DeviceState::Activated(constructor_args.mem);
+        }
+
+        Ok(virtio_mem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::virtio::device::VirtioDevice;
+    use crate::virtio::mem::device::tests::{default_mem_with_region, default_virtio_mem};
+
+    #[test]
+    fn test_persistence() {
+        let mut mem = vec![0; 4096];
+        let version_map = VersionMap::new();
+
+        // Create and save the virtio-mem device.
+        let mut virtio_mem = default_virtio_mem();
+        virtio_mem.update_requested_size(0x2000).unwrap();
+        virtio_mem.plugged_blocks[1] = true;
+        virtio_mem.config_space.plugged_size = 0x1000;
+
+        <VirtioMem as Persist>::save(&virtio_mem)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+
+        // Deserialize and restore the virtio-mem device.
+        let restored_virtio_mem = VirtioMem::restore(
+            VirtioMemConstructorArgs {
+                mem: default_mem_with_region(),
+            },
+            &VirtioMemState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(restored_virtio_mem.device_type(), TYPE_MEM);
+        assert!(restored_virtio_mem.restored);
+
+        assert_eq!(
+            restored_virtio_mem.acked_features,
+            virtio_mem.acked_features
+        );
+        assert_eq!(
+            restored_virtio_mem.avail_features,
+            virtio_mem.avail_features
+        );
+        assert_eq!(restored_virtio_mem.config_space, virtio_mem.config_space);
+        assert_eq!(
+            restored_virtio_mem.plugged_blocks,
+            virtio_mem.plugged_blocks
+        );
+        assert_eq!(restored_virtio_mem.queues(), virtio_mem.queues());
+        assert_eq!(
+            restored_virtio_mem
+                .interrupt_status()
+                .load(Ordering::Relaxed),
+            virtio_mem.interrupt_status().load(Ordering::Relaxed)
+        );
+        assert_eq!(
+            restored_virtio_mem.is_activated(),
+            virtio_mem.is_activated()
+        );
+    }
+}
@@ -87,6 +87,13 @@ impl MmioTransport {
         self.device_status & (set | clr) == set
     }
 
+    /// Whether a guest driver is bound to the device: it started initializing the device, and
+    /// neither reset it, nor marked it as failed since. Resetting a device which doesn't support
+    /// it marks the device as failed.
+    pub fn is_driver_bound(&self) -> bool {
+        self.check_device_status(device_status::DRIVER, device_status::FAILED)
+    }
+
     fn are_queues_valid(&self) -> bool {
         self.locked_device()
             .queues()
@@ -835,19 +842,23 @@ pub(crate) mod tests {
         assert!(!d.are_queues_valid());
         assert!(!d.locked_device().is_activated());
         assert_eq!(d.device_status, 0);
+        assert!(!d.is_driver_bound());
         activate_device(&mut d);
+        assert!(d.is_driver_bound());
 
         // Marking device as FAILED should not affect device_activated state
         write_le_u32(&mut buf[..], 0x8f);
         d.write(0x70, &buf[..]);
         assert_eq!(d.device_status, 0x8f);
         assert!(d.locked_device().is_activated());
+        assert!(!d.is_driver_bound());
 
         // Nothing happens when backend driver doesn't support reset
         write_le_u32(&mut buf[..], 0x0);
         d.write(0x70, &buf[..]);
         assert_eq!(d.device_status, 0x8f);
         assert!(d.locked_device().is_activated());
+        assert!(!d.is_driver_bound());
     }
 
     #[test]
@@ -12,20 +12,27 @@ use std::io::Error as IOError;
 pub mod balloon;
 pub mod block;
 pub mod device;
+pub mod hotplug;
+pub mod mem;
 mod mmio;
 pub mod net;
 pub mod persist;
 mod queue;
 pub mod test_utils;
+pub mod vhost_user;
+pub mod vhost_user_block;
 pub mod vsock;
 
 pub use self::balloon::*;
 pub use self::block::*;
 pub use self::device::*;
+pub use self::hotplug::*;
+pub use self::mem::*;
 pub use self::mmio::*;
 pub use self::net::*;
 pub use self::persist::*;
 pub use self::queue::*;
+pub use self::vhost_user_block::*;
 pub use self::vsock::*;
 
 /// When the driver initializes the device, it lets the device know about the
@@ -50,6 +57,7 @@ mod device_status {
 pub const TYPE_NET: u32 = 1;
 pub const TYPE_BLOCK: u32 = 2;
 pub const TYPE_BALLOON: u32 = 5;
+pub const TYPE_MEM: u32 = 24;
 
 /// Offset from the base MMIO address of a virtio device used by the guest to notify the device of
 /// queue events.
@@ -6,32 +6,43 @@
 // found in the THIRD-PARTY file.
 use std::io::{Read, Write};
 use std::net::Ipv4Addr;
+use std::path::Path;
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
-use std::{cmp, mem, result};
+use std::{cmp, io, mem, result};
 
-use dumbo::pdu::ethernet::EthernetFrame;
+use dumbo::pdu::ethernet::{EthernetFrame, PAYLOAD_OFFSET};
 use libc::EAGAIN;
-use logger::{error, warn, IncMetric, METRICS};
+use logger::{error, warn, IncMetric, NetQueueMetrics, METRICS};
 use mmds::data_store::Mmds;
 use mmds::ns::MmdsNetworkStack;
-use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
+use rate_limiter::{BucketUpdate, RateLimiter, TokenBucket, TokenType};
+use serde::{Deserialize, Serialize};
 use utils::eventfd::EventFd;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
 use utils::rand_bytes;
 use virtio_gen::virtio_net::{
-    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
-    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MAC,
+    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_ANNOUNCE, VIRTIO_NET_CTRL_ANNOUNCE_ACK,
+    VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_ERR, VIRTIO_NET_F_CSUM,
+    VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_ANNOUNCE, VIRTIO_NET_F_GUEST_CSUM,
+    VIRTIO_NET_F_GUEST_ECN, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6,
+    VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_ECN, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_TSO6,
+    VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ, VIRTIO_NET_F_STATUS, VIRTIO_NET_OK,
+    VIRTIO_NET_S_ANNOUNCE, VIRTIO_NET_S_LINK_UP,
 };
 use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
-use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
 
+use crate::virtio::net::filter::{AntiSpoofing, Violation, MAX_CHECKED_PAYLOAD_LEN};
+use crate::virtio::net::iovec::IoVecBuffer;
+use crate::virtio::net::pcap::PacketCapture;
 use crate::virtio::net::tap::Tap;
 #[cfg(test)]
 use crate::virtio::net::test_utils::Mocks;
+use crate::virtio::net::vhost::{self, BackendState, VhostNetBackend};
 use crate::virtio::net::{
-    Error, NetQueue, Result, MAX_BUFFER_SIZE, QUEUE_SIZE, QUEUE_SIZES, RX_INDEX, TX_INDEX,
+    Error, NetQueue, Result, MAX_BUFFER_SIZE, MAX_NUM_QUEUE_PAIRS, NUM_QUEUES, QUEUE_SIZE,
+    RX_INDEX, TX_INDEX,
 };
 use crate::virtio::{
     ActivateResult, DescriptorChain, DeviceState, IrqTrigger, IrqType, Queue, VirtioDevice,
@@ -50,6 +61,9 @@ enum FrontendError {
 // KVM OUI MAC address is 52:54:00:xx:xx:xx
 const KVM_OUI: [u8; 3] = [0x52, 0x54, 0x00];
 
+// The control commands we support only carry a few bytes of data, anything beyond that is ignored.
+const MAX_CTRL_REQUEST_LEN: usize = 64;
+
 pub(crate) fn vnet_hdr_len() -> usize {
     mem::size_of::<virtio_net_hdr_v1>()
 }
@@ -89,31 +103,189 @@ fn generate_mac_address() -> MacAddr {
     MacAddr::from_bytes_unchecked(&random_mac)
 }
 
+// Creates a rate limiter with the same configuration as `rate_limiter`, and a full budget.
+fn duplicate_rate_limiter(rate_limiter: &RateLimiter) -> io::Result<RateLimiter> {
+    let bucket_config = |bucket: Option<&TokenBucket>| {
+        bucket.map_or((0, 0, 0), |bucket| {
+            (
+                bucket.capacity(),
+                bucket.initial_one_time_burst(),
+                bucket.refill_time_ms(),
+            )
+        })
+    };
+    let (bytes_size, bytes_burst, bytes_refill_time) = bucket_config(rate_limiter.bandwidth());
+    let (ops_size, ops_burst, ops_refill_time) = bucket_config(rate_limiter.ops());
+
+    RateLimiter::new(
+        bytes_size,
+        bytes_burst,
+        bytes_refill_time,
+        ops_size,
+        ops_burst,
+        ops_refill_time,
+    )
+}
+
+/// Returns the number of virtqueues of a device with `num_queue_pairs` queue pairs, followed by
+/// the control queue.
+pub fn num_queues(num_queue_pairs: u16) -> usize {
+    num_queue_pairs as usize * NUM_QUEUES + 1
+}
+
+/// Returns the index of the RX queue of a queue pair.
+pub fn rx_queue_index(queue_pair: usize) -> usize {
+    queue_pair * NUM_QUEUES + RX_INDEX
+}
+
+/// Returns the index of the TX queue of a queue pair.
+pub fn tx_queue_index(queue_pair: usize) -> usize {
+    queue_pair * NUM_QUEUES + TX_INDEX
+}
+
 #[derive(Clone, Copy)]
+#[repr(C)]
 pub struct ConfigSpace {
     pub guest_mac: [u8; MAC_ADDR_LEN],
+    pub status: u16,
+    pub max_virtqueue_pairs: u16,
 }
 
 impl Default for ConfigSpace {
     fn default() -> ConfigSpace {
         ConfigSpace {
             guest_mac: [0; MAC_ADDR_LEN],
+            status: 0,
+            max_virtqueue_pairs: 0,
         }
     }
 }
 
 unsafe impl ByteValued for ConfigSpace {}
 
-pub struct Net {
-    pub(crate) id: String,
+/// The state of the link reported to the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LinkState {
+    /// The link is up.
+    Up,
+    /// The link is down.
+    Down,
+}
 
-    pub tap: Tap,
+/// The offloads negotiated with the guest, in both directions.
+///
+/// All of them are enabled by default, and can be disabled individually, e.g. for debugging.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Offloads {
+    /// Checksum offload, which all the other offloads depend on.
+    pub checksum: bool,
+    /// TCP segmentation offload for IPv4.
+    pub tso4: bool,
+    /// TCP segmentation offload for IPv6.
+    pub tso6: bool,
+    /// UDP fragmentation offload.
+    pub ufo: bool,
+    /// TCP segmentation offload for segments with the ECN bits set. Depends on either `tso4` or
+    /// `tso6`.
+    pub ecn: bool,
+}
 
-    pub(crate) avail_features: u64,
-    pub(crate) acked_features: u64,
+impl Default for Offloads {
+    fn default() -> Self {
+        Offloads {
+            checksum: true,
+            tso4: true,
+            tso6: true,
+            ufo: true,
+            ecn: true,
+        }
+    }
+}
 
-    pub(crate) queues: Vec<Queue>,
-    pub(crate) queue_evts: Vec<EventFd>,
+impl Offloads {
+    // The virtio features, guest and host, and the TAP offload flag matching each offload.
+    fn flags(&self) -> [(bool, u32, u32, u32); 5] {
+        [
+            (
+                self.checksum,
+                VIRTIO_NET_F_GUEST_CSUM,
+                VIRTIO_NET_F_CSUM,
+                net_gen::TUN_F_CSUM,
+            ),
+            (
+                self.tso4,
+                VIRTIO_NET_F_GUEST_TSO4,
+                VIRTIO_NET_F_HOST_TSO4,
+                net_gen::TUN_F_TSO4,
+            ),
+            (
+                self.tso6,
+                VIRTIO_NET_F_GUEST_TSO6,
+                VIRTIO_NET_F_HOST_TSO6,
+                net_gen::TUN_F_TSO6,
+            ),
+            (
+                self.ufo,
+                VIRTIO_NET_F_GUEST_UFO,
+                VIRTIO_NET_F_HOST_UFO,
+                net_gen::TUN_F_UFO,
+            ),
+            (
+                self.ecn,
+                VIRTIO_NET_F_GUEST_ECN,
+                VIRTIO_NET_F_HOST_ECN,
+                net_gen::TUN_F_TSO_ECN,
+            ),
+        ]
+    }
+
+    /// Checks that the dependencies between the enabled offloads are satisfied, as both the
+    /// virtio specification and the TAP interface require.
+    pub fn validate(&self) -> Result<()> {
+        let segmentation = self.tso4 || self.tso6 || self.ufo;
+        if (!self.checksum && (segmentation || self.ecn)) || (self.ecn && !self.tso4 && !self.tso6)
+        {
+            return Err(Error::InvalidOffloads(*self));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the virtio features advertising the enabled offloads.
+    pub fn avail_features(&self) -> u64 {
+        self.flags().iter().filter(|&&(enabled, ..)| enabled).fold(
+            0,
+            |features, &(_, guest_feature, host_feature, _)| {
+                features | 1 << guest_feature | 1 << host_feature
+            },
+        )
+    }
+
+    /// Returns the offloads advertised by the given virtio features.
+    pub fn from_avail_features(avail_features: u64) -> Self {
+        let has_feature = |feature: u32| avail_features & (1 << feature) != 0;
+        Offloads {
+            checksum: has_feature(VIRTIO_NET_F_CSUM),
+            tso4: has_feature(VIRTIO_NET_F_HOST_TSO4),
+            tso6: has_feature(VIRTIO_NET_F_HOST_TSO6),
+            ufo: has_feature(VIRTIO_NET_F_HOST_UFO),
+            ecn: has_feature(VIRTIO_NET_F_HOST_ECN),
+        }
+    }
+
+    // Returns the TAP offload flags, which control the frames the TAP can hand to the guest.
+    fn tap_flags(&self) -> u32 {
+        self.flags()
+            .iter()
+            .filter(|&&(enabled, ..)| enabled)
+            .fold(0, |flags, &(_, _, _, tap_flag)| flags | tap_flag)
+    }
+}
+
+/// A RX/TX queue pair, served by its own queue of the TAP interface.
+pub struct QueuePair {
+    pub tap: Tap,
 
     pub(crate) rx_rate_limiter: RateLimiter,
     pub(crate) tx_rate_limiter: RateLimiter,
@@ -122,53 +294,116 @@ pub struct Net {
 
     rx_bytes_read: usize,
     rx_frame_buf: [u8; MAX_BUFFER_SIZE],
-
-    tx_iovec: Vec<(GuestAddress, usize)>,
+    // The descriptor chain the tap is read into, when it can hold any frame.
+    rx_iovec: IoVecBuffer,
+    // The head of the descriptor chain holding the current frame, when the frame was read
+    // straight into guest memory rather than into `rx_frame_buf`.
+    rx_pending_head: Option<u16>,
+
+    tx_iovec: IoVecBuffer,
+    // Only used when the MMDS has to inspect a frame.
     tx_frame_buf: [u8; MAX_BUFFER_SIZE],
 
+    pub(crate) metrics: Arc<NetQueueMetrics>,
+}
+
+impl QueuePair {
+    fn new(
+        tap: Tap,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+        metrics: Arc<NetQueueMetrics>,
+    ) -> Self {
+        QueuePair {
+            tap,
+            rx_rate_limiter,
+            tx_rate_limiter,
+            rx_deferred_frame: false,
+            rx_bytes_read: 0,
+            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
+            rx_iovec: IoVecBuffer::with_capacity(QUEUE_SIZE as usize),
+            rx_pending_head: None,
+            tx_iovec: IoVecBuffer::with_capacity(QUEUE_SIZE as usize),
+            tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
+            metrics,
+        }
+    }
+}
+
+pub struct Net {
+    pub(crate) id: String,
+
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+
+    pub(crate) queues: Vec<Queue>,
+    pub(crate) queue_evts: Vec<EventFd>,
+
+    pub(crate) queue_pairs: Vec<QueuePair>,
+    // The number of queue pairs enabled by the driver through the control queue.
+    pub(crate) active_queue_pairs: u16,
+
     pub(crate) irq_trigger: IrqTrigger,
 
     pub(crate) config_space: ConfigSpace,
     pub(crate) guest_mac: MacAddr,
+    // Whether the guest should announce itself on the network once the vm is resumed.
+    pub(crate) announce_pending: bool,
 
     pub(crate) device_state: DeviceState,
     pub(crate) activate_evt: EventFd,
 
     pub mmds_ns: Option<MmdsNetworkStack>,
 
+    // Processes the rx and tx queues in the kernel, in place of Firecracker.
+    pub(crate) vhost: Option<VhostNetBackend>,
+
+    // Records the frames going through the device, in both directions.
+    pub(crate) capture: Option<PacketCapture>,
+
+    // The source addresses and protocols the guest may use.
+    pub(crate) anti_spoofing: Option<AntiSpoofing>,
+
     #[cfg(test)]
     pub(crate) mocks: Mocks,
 }
 
 impl Net {
     /// Create a new virtio network device with the given TAP interface.
+    ///
+    /// Each of the `num_queue_pairs` queue pairs is served by its own queue of the TAP interface
+    /// and is rate limited independently, based on the configuration of the given rate limiters.
     pub fn new_with_tap(
         id: String,
         tap_if_name: String,
         guest_mac: Option<&MacAddr>,
         rx_rate_limiter: RateLimiter,
         tx_rate_limiter: RateLimiter,
+        num_queue_pairs: u16,
+        offloads: Offloads,
     ) -> Result<Self> {
-        let tap = Tap::open_named(&tap_if_name).map_err(Error::TapOpen)?;
+        if num_queue_pairs == 0 || num_queue_pairs > MAX_NUM_QUEUE_PAIRS {
+            return Err(Error::InvalidNumQueuePairs(num_queue_pairs));
+        }
+        offloads.validate()?;
 
-        // Set offload flags to match the virtio features below.
-        tap.set_offload(
-            net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6,
-        )
-        .map_err(Error::TapSetOffload)?;
+        let taps =
+            Tap::open_queues(&tap_if_name, num_queue_pairs as usize).map_err(Error::TapOpen)?;
 
         let vnet_hdr_size = vnet_hdr_len() as i32;
-        tap.set_vnet_hdr_size(vnet_hdr_size)
-            .map_err(Error::TapSetVnetHdrSize)?;
+        for tap in &taps {
+            // Set offload flags to match the virtio features below.
+            tap.set_offload(offloads.tap_flags())
+                .map_err(Error::TapSetOffload)?;
 
-        let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
-            | 1 << VIRTIO_NET_F_CSUM
-            | 1 << VIRTIO_NET_F_GUEST_TSO4
-            | 1 << VIRTIO_NET_F_GUEST_UFO
-            | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_UFO
+            tap.set_vnet_hdr_size(vnet_hdr_size)
+                .map_err(Error::TapSetVnetHdrSize)?;
+        }
+
+        let mut avail_features = offloads.avail_features()
             | 1 << VIRTIO_F_VERSION_1
-            | 1 << VIRTIO_RING_F_EVENT_IDX;
+            | 1 << VIRTIO_RING_F_EVENT_IDX
+            | 1 << VIRTIO_NET_F_CTRL_VQ;
 
         let mut config_space = ConfigSpace::default();
         // Enable feature to configure a MAC address
@@ -184,37 +419,76 @@ impl Net {
         };
         config_space.guest_mac.copy_from_slice(mac_addr.get_bytes());
 
+        // Report the link status, which starts up, and let the device ask the driver to announce
+        // itself on the network. The driver acknowledges the announcements through the control
+        // queue.
+        avail_features |= 1 << VIRTIO_NET_F_STATUS | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE;
+        config_space.status = VIRTIO_NET_S_LINK_UP as u16;
+
+        // The driver enables the additional queue pairs through the control queue.
+        if num_queue_pairs > 1 {
+            avail_features |= 1 << VIRTIO_NET_F_MQ;
+            config_space.max_virtqueue_pairs = num_queue_pairs;
+        }
+
         let mut queue_evts = Vec::new();
         let mut queues = Vec::new();
-        for &size in QUEUE_SIZES {
+        for _ in 0..num_queues(num_queue_pairs) {
             queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
-            queues.push(Queue::new(size));
+            queues.push(Queue::new(QUEUE_SIZE));
         }
 
-        Ok(Net {
+        // The first queue pair uses the given rate limiters, the others get copies of them.
+        let mut rx_rate_limiters = vec![rx_rate_limiter];
+        let mut tx_rate_limiters = vec![tx_rate_limiter];
+        for _ in 1..num_queue_pairs {
+            rx_rate_limiters.push(
+                duplicate_rate_limiter(&rx_rate_limiters[0]).map_err(Error::CreateRateLimiter)?,
+            );
+            tx_rate_limiters.push(
+                duplicate_rate_limiter(&tx_rate_limiters[0]).map_err(Error::CreateRateLimiter)?,
+            );
+        }
+        let queue_pairs = taps
+            .into_iter()
+            .zip(rx_rate_limiters.into_iter().zip(tx_rate_limiters))
+            .enumerate()
+            .map(|(index, (tap, (rx_rate_limiter, tx_rate_limiter)))| {
+                QueuePair::new(
+                    tap,
+                    rx_rate_limiter,
+                    tx_rate_limiter,
+                    METRICS.net_queues.alloc(&id, index as u16),
+                )
+            })
+            .collect();
+
+        let mut net = Net {
             id,
-            tap,
             avail_features,
             acked_features: 0u64,
             queues,
             queue_evts,
-            rx_rate_limiter,
-            tx_rate_limiter,
-            rx_deferred_frame: false,
-            rx_bytes_read: 0,
-            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
-            tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
-            tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
+            queue_pairs,
+            active_queue_pairs: num_queue_pairs,
             irq_trigger: IrqTrigger::new().map_err(Error::EventFd)?,
             device_state: DeviceState::Inactive,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
             config_space,
             mmds_ns: None,
             guest_mac: mac_addr,
+            announce_pending: false,
+            vhost: None,
+            capture: None,
+            anti_spoofing: None,
 
             #[cfg(test)]
             mocks: Mocks::default(),
-        })
+        };
+        // Only the first queue pair is used until the driver enables the others.
+        net.set_active_queue_pairs(1)?;
+
+        Ok(net)
     }
 
     /// Provides the ID of this net device.
@@ -229,7 +503,17 @@ impl Net {
 
     /// Provides the host IFACE name of this net device.
     pub fn iface_name(&self) -> String {
-        self.tap.if_name_as_str().to_string()
+        self.queue_pairs[0].tap.if_name_as_str().to_string()
+    }
+
+    /// Provides the offloads offered by this net device.
+    pub fn offloads(&self) -> Offloads {
+        Offloads::from_avail_features(self.avail_features)
+    }
+
+    /// Provides the number of RX/TX queue pairs of this net device.
+    pub fn num_queue_pairs(&self) -> u16 {
+        self.queue_pairs.len() as u16
     }
 
     /// Provides the MmdsNetworkStack of this net device.
@@ -252,23 +536,425 @@ impl Net {
         self.mmds_ns = None
     }
 
+    /// Provides the anti-spoofing policy of this net device, if any.
+    pub fn anti_spoofing(&self) -> Option<&AntiSpoofing> {
+        self.anti_spoofing.as_ref()
+    }
+
+    /// Sets the policy the frames sent by the guest have to follow. The frames breaking it are
+    /// dropped.
+    pub fn set_anti_spoofing(&mut self, anti_spoofing: Option<AntiSpoofing>) -> Result<()> {
+        if let Some(policy) = anti_spoofing.as_ref() {
+            policy.validate()?;
+        }
+        self.anti_spoofing = anti_spoofing;
+        Ok(())
+    }
+
+    // Checks the frame in `frame_iovec` against the anti-spoofing policy, using `frame_buf` to
+    // copy its headers. Frames without a VNET header are left to the TX path, which drops them.
+    fn check_anti_spoofing(
+        policy: &AntiSpoofing,
+        guest_mac: MacAddr,
+        frame_iovec: &IoVecBuffer,
+        frame_buf: &mut [u8],
+    ) -> bool {
+        let headers_len = vnet_hdr_len() + PAYLOAD_OFFSET + MAX_CHECKED_PAYLOAD_LEN;
+        let len = frame_iovec.read_at(&mut frame_buf[..headers_len], 0);
+        let frame = match frame_bytes_from_buf(&frame_buf[..len]) {
+            Ok(frame) => frame,
+            Err(_) => return true,
+        };
+
+        match policy.check(guest_mac, frame) {
+            Ok(()) => true,
+            Err(Violation::SourceMac) => {
+                METRICS.net.tx_spoofing_mac_drops.inc();
+                false
+            }
+            Err(Violation::Ethertype) => {
+                METRICS.net.tx_spoofing_ethertype_drops.inc();
+                false
+            }
+            Err(Violation::SourceIp) => {
+                METRICS.net.tx_spoofing_ip_drops.inc();
+                false
+            }
+        }
+    }
+
+    /// Specifies if the rx and tx queues of this net device are processed by vhost-net.
+    pub fn vhost_net(&self) -> bool {
+        self.vhost.is_some()
+    }
+
+    /// Hands the rx and tx queues over to the in-kernel vhost-net driver once the device is
+    /// activated.
+    ///
+    /// The device falls back to processing the queues in Firecracker if the MMDS, rate limiting,
+    /// anti-spoofing or a packet capture are configured at that point, since these need to
+    /// inspect every frame.
+    pub fn enable_vhost_net(&mut self) -> Result<()> {
+        let backend = VhostNetBackend::new(self.queue_pairs.len()).map_err(Error::VhostNet)?;
+        // The driver can ack these features, which change the layout of the vrings.
+        if backend.features() & (1 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::VhostNetMissingFeature("VIRTIO_F_VERSION_1"));
+        }
+        if backend.features() & (1 << VIRTIO_RING_F_EVENT_IDX) == 0 {
+            return Err(Error::VhostNetMissingFeature("VIRTIO_RING_F_EVENT_IDX"));
+        }
+
+        self.vhost = Some(backend);
+        Ok(())
+    }
+
+    // Returns why the queues can't be processed by vhost-net, if that's the case.
+    fn vhost_net_blocker(&self, mem: &GuestMemoryMmap) -> Option<&'static str> {
+        let rate_limited = |rate_limiter: &RateLimiter| {
+            rate_limiter.bandwidth().is_some() || rate_limiter.ops().is_some()
+        };
+
+        if self.mmds_ns.is_some() {
+            Some("the MMDS is configured")
+        } else if self.capture.is_some() {
+            Some("packet capture is in progress")
+        } else if self.anti_spoofing.is_some() {
+            Some("anti-spoofing is configured")
+        } else if self
+            .queue_pairs
+            .iter()
+            .any(|qp| rate_limited(&qp.rx_rate_limiter) || rate_limited(&qp.tx_rate_limiter))
+        {
+            Some("rate limiting is configured")
+        } else if vhost::tracks_dirty_pages(mem) {
+            // The kernel writes to the guest memory behind Firecracker's back.
+            Some("dirty page tracking is enabled")
+        } else {
+            None
+        }
+    }
+
+    // Starts the vhost-net backend of an activated device, falling back to processing the queues
+    // in Firecracker if that's not possible. The runtime events depend on which path is used,
+    // so this needs to be called before registering them.
+    pub(crate) fn start_vhost_net(&mut self) {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return,
+        };
+        match self.vhost.as_ref() {
+            Some(backend) if backend.state == BackendState::NotStarted => (),
+            _ => return,
+        }
+
+        if let Some(blocker) = self.vhost_net_blocker(mem) {
+            warn!(
+                "Net: Cannot use vhost-net for {} since {}, falling back to Firecracker.",
+                self.id, blocker
+            );
+            METRICS.net.vhost_net_fallbacks.inc();
+            self.vhost = None;
+            return;
+        }
+
+        let taps: Vec<&Tap> = self.queue_pairs.iter().map(|qp| &qp.tap).collect();
+        // Safe to unwrap since we checked above that there is a backend.
+        let backend = self.vhost.as_mut().unwrap();
+        if let Err(err) = backend.start(
+            self.acked_features,
+            &self.queues,
+            &self.queue_evts,
+            &taps,
+            self.active_queue_pairs as usize,
+            mem,
+        ) {
+            error!(
+                "Net: Failed to start vhost-net for {}, falling back to Firecracker: {:?}",
+                self.id, err
+            );
+            METRICS.net.vhost_net_fallbacks.inc();
+            // Some of the vrings may have been started already.
+            if let Err(err) = backend.stop(&mut self.queues, mem) {
+                error!("Net: Failed to stop vhost-net: {:?}", err);
+            }
+            self.vhost = None;
+        } else if self.link_state() == LinkState::Down {
+            // The backend is resumed once the link is brought up.
+            self.stop_vhost_net();
+        }
+    }
+
+    // Restarts the vhost-net backend after it was stopped to save the device state, or because
+    // the link was down.
+    fn resume_vhost_net(&mut self) {
+        if self.link_state() == LinkState::Down {
+            return;
+        }
+        let taps: Vec<&Tap> = self.queue_pairs.iter().map(|qp| &qp.tap).collect();
+        if let Some(backend) = self.vhost.as_mut() {
+            if backend.state == BackendState::Stopped {
+                if let Err(err) = backend.resume(&taps) {
+                    error!("Net: Failed to resume vhost-net: {:?}", err);
+                    METRICS.net.event_fails.inc();
+                }
+            }
+        }
+    }
+
+    /// Stops the vhost-net backend, if any, and pulls the state of the vrings back from the
+    /// kernel, so that it can be saved. The backend is restarted when the vm is resumed.
+    ///
+    /// RX descriptor chains holding frames which wait for the rate limiter are handed back to
+    /// the avail ring as well, dropping the frames.
+    pub fn prepare_save(&mut self) {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return,
+        };
+
+        for (index, qp) in self.queue_pairs.iter_mut().enumerate() {
+            if qp.rx_pending_head.take().is_some() {
+                self.queues[rx_queue_index(index)].undo_pop();
+                qp.rx_deferred_frame = false;
+            }
+        }
+
+        self.stop_vhost_net();
+    }
+
+    // Stops the vhost-net backend, if it is running, and pulls the state of the vrings back from
+    // the kernel.
+    fn stop_vhost_net(&mut self) {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return,
+        };
+
+        if let Some(backend) = self.vhost.as_mut() {
+            if backend.state == BackendState::Running {
+                if let Err(err) = backend.stop(&mut self.queues, mem) {
+                    error!("Net: Failed to stop vhost-net: {:?}", err);
+                    METRICS.net.event_fails.inc();
+                }
+            }
+        }
+    }
+
+    /// Starts writing the frames going through the device, in both directions, to a pcap file
+    /// at `path`. The capture stops on its own once the file reaches `max_size` bytes.
+    ///
+    /// A capture in progress is replaced by the new one. Frames can't be captured while the
+    /// queues are processed by vhost-net.
+    pub fn start_capture<P: AsRef<Path>>(&mut self, path: P, max_size: u64) -> Result<()> {
+        if self.vhost.is_some() {
+            return Err(Error::VhostNetPacketCapture);
+        }
+
+        self.capture = Some(PacketCapture::new(path, max_size).map_err(Error::PacketCapture)?);
+        Ok(())
+    }
+
+    /// Stops the packet capture, if any.
+    pub fn stop_capture(&mut self) {
+        self.capture = None;
+    }
+
+    /// Specifies if the frames of this net device are being captured.
+    pub fn is_capturing(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    // Records a frame with the given capture function, if a capture is in progress. The capture
+    // is stopped once the file is full or can't be written to.
+    fn capture_frame<F>(capture: &mut Option<PacketCapture>, write_frame: F)
+    where
+        F: FnOnce(&mut PacketCapture) -> io::Result<bool>,
+    {
+        let result = match capture.as_mut() {
+            Some(pcap) => write_frame(pcap),
+            None => return,
+        };
+        match result {
+            Ok(true) => METRICS.net.capture_frames_count.inc(),
+            Ok(false) => {
+                warn!("Net: The packet capture file is full, stopping the capture.");
+                *capture = None;
+            }
+            Err(err) => {
+                error!("Net: Failed to write to the packet capture file: {:?}", err);
+                METRICS.net.capture_fails.inc();
+                *capture = None;
+            }
+        }
+    }
+
     /// Provides a reference to the configured RX rate limiter.
+    ///
+    /// All the queue pairs share the same rate limiter configuration.
     pub fn rx_rate_limiter(&self) -> &RateLimiter {
-        &self.rx_rate_limiter
+        &self.queue_pairs[0].rx_rate_limiter
     }
 
     /// Provides a reference to the configured TX rate limiter.
+    ///
+    /// All the queue pairs share the same rate limiter configuration.
     pub fn tx_rate_limiter(&self) -> &RateLimiter {
-        &self.tx_rate_limiter
+        &self.queue_pairs[0].tx_rate_limiter
+    }
+
+    /// Provides the state of the link reported to the guest.
+    pub fn link_state(&self) -> LinkState {
+        if self.config_space.status & VIRTIO_NET_S_LINK_UP as u16 != 0 {
+            LinkState::Up
+        } else {
+            LinkState::Down
+        }
     }
 
-    fn signal_used_queue(&mut self, queue_type: NetQueue) -> result::Result<(), DeviceError> {
+    /// Brings the link reported to the guest up or down, notifying the driver of the change.
+    ///
+    /// No frames are received nor sent while the link is down: they wait on the tap and in the
+    /// tx queue until the link is brought back up.
+    pub fn set_link_state(&mut self, link_state: LinkState) -> result::Result<(), DeviceError> {
+        if self.link_state() == link_state {
+            return Ok(());
+        }
+
+        match link_state {
+            LinkState::Up => self.config_space.status |= VIRTIO_NET_S_LINK_UP as u16,
+            LinkState::Down => self.config_space.status &= !(VIRTIO_NET_S_LINK_UP as u16),
+        }
+        // The driver reads the status when it is initialized, so there is nothing to notify
+        // before the device is activated.
+        if !self.is_activated() {
+            return Ok(());
+        }
+        self.signal_config_change()?;
+        match link_state {
+            LinkState::Up => self.resume_traffic(),
+            LinkState::Down => {
+                self.stop_vhost_net();
+                Ok(())
+            }
+        }
+    }
+
+    // Handles the frames which were left on the taps and in the tx queues while the link was
+    // down.
+    fn resume_traffic(&mut self) -> result::Result<(), DeviceError> {
+        if self.vhost.is_some() {
+            self.resume_vhost_net();
+            return Ok(());
+        }
+
+        for queue_pair in 0..self.active_queue_pairs as usize {
+            let qp = &self.queue_pairs[queue_pair];
+            if !qp.rx_rate_limiter.is_blocked() {
+                match qp.rx_deferred_frame {
+                    true => self.handle_deferred_frame(queue_pair)?,
+                    false => self.process_rx(queue_pair)?,
+                }
+            }
+            if !self.queue_pairs[queue_pair].tx_rate_limiter.is_blocked() {
+                self.process_tx(queue_pair)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the driver to announce itself on the network, e.g. by sending gratuitous ARP packets,
+    /// which is needed after the guest has moved to another host.
+    pub fn announce(&mut self) -> result::Result<(), DeviceError> {
+        if !self.is_activated() || !self.has_feature(u64::from(VIRTIO_NET_F_GUEST_ANNOUNCE)) {
+            return Ok(());
+        }
+
+        // The driver clears the announce bit through the control queue once it is done.
+        self.config_space.status |= VIRTIO_NET_S_ANNOUNCE as u16;
+        self.signal_config_change()
+    }
+
+    /// Asks the driver to announce itself on the network if an announcement was requested while
+    /// the vm was paused, e.g. when restoring from a snapshot.
+    pub fn process_pending_announce(&mut self) {
+        if self.announce_pending {
+            self.announce_pending = false;
+            self.announce().unwrap_or_else(report_net_event_fail);
+        }
+    }
+
+    fn signal_config_change(&self) -> result::Result<(), DeviceError> {
+        self.irq_trigger
+            .trigger_irq(IrqType::Config)
+            .map_err(|err| {
+                METRICS.net.event_fails.inc();
+                DeviceError::FailedSignalingIrq(err)
+            })
+    }
+
+    // Returns the index of the control queue. Devices restored from snapshots created before the
+    // control queue was always offered may not have one.
+    pub(crate) fn ctrl_queue_index(&self) -> Option<usize> {
+        if self.avail_features & (1 << VIRTIO_NET_F_CTRL_VQ) != 0 {
+            Some(self.queue_pairs.len() * NUM_QUEUES)
+        } else {
+            None
+        }
+    }
+
+    // Enables the first `active_queue_pairs` queue pairs and disables the others, so that the
+    // host only steers traffic to the queues used by the driver. With vhost-net, the kernel
+    // only processes the vrings of the enabled queue pairs.
+    pub(crate) fn set_active_queue_pairs(&mut self, active_queue_pairs: u16) -> Result<()> {
+        if active_queue_pairs == 0 || active_queue_pairs > self.num_queue_pairs() {
+            return Err(Error::InvalidNumQueuePairs(active_queue_pairs));
+        }
+
+        let mut vhost = match (self.vhost.as_mut(), self.device_state.mem()) {
+            (Some(backend), Some(mem)) if backend.state == BackendState::Running => {
+                Some((backend, mem))
+            }
+            _ => None,
+        };
+        for (index, queue_pair) in self.queue_pairs.iter().enumerate() {
+            let was_active = index < self.active_queue_pairs as usize;
+            let is_active = index < active_queue_pairs as usize;
+            if was_active != is_active {
+                queue_pair
+                    .tap
+                    .set_queue_enabled(is_active)
+                    .map_err(Error::TapSetQueue)?;
+                if let Some((backend, mem)) = vhost.as_mut() {
+                    match is_active {
+                        true => backend.start_queue_pair(
+                            index,
+                            &self.queues,
+                            &self.queue_evts,
+                            &queue_pair.tap,
+                            mem,
+                        ),
+                        false => backend.stop_queue_pair(index, &mut self.queues, mem),
+                    }
+                    .map_err(Error::VhostNet)?;
+                }
+            }
+        }
+        self.active_queue_pairs = active_queue_pairs;
+
+        Ok(())
+    }
+
+    fn signal_used_queue(
+        &mut self,
+        queue_type: NetQueue,
+        queue_pair: usize,
+    ) -> result::Result<(), DeviceError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
         let queue = match queue_type {
-            NetQueue::Rx => &mut self.queues[RX_INDEX],
-            NetQueue::Tx => &mut self.queues[TX_INDEX],
+            NetQueue::Rx => &mut self.queues[rx_queue_index(queue_pair)],
+            NetQueue::Tx => &mut self.queues[tx_queue_index(queue_pair)],
         };
 
         if queue.prepare_kick(mem) {
@@ -286,35 +972,39 @@ impl Net {
     // Attempts to copy a single frame into the guest if there is enough
     // rate limiting budget.
     // Returns true on successful frame delivery.
-    fn rate_limited_rx_single_frame(&mut self) -> bool {
+    fn rate_limited_rx_single_frame(&mut self, queue_pair: usize) -> bool {
+        let qp = &mut self.queue_pairs[queue_pair];
         // If limiter.consume() fails it means there is no more TokenType::Ops
         // budget and rate limiting is in effect.
-        if !self.rx_rate_limiter.consume(1, TokenType::Ops) {
+        if !qp.rx_rate_limiter.consume(1, TokenType::Ops) {
             METRICS.net.rx_rate_limiter_throttled.inc();
+            qp.metrics.rx_rate_limiter_throttled.inc();
             return false;
         }
         // If limiter.consume() fails it means there is no more TokenType::Bytes
         // budget and rate limiting is in effect.
-        if !self
+        if !qp
             .rx_rate_limiter
-            .consume(self.rx_bytes_read as u64, TokenType::Bytes)
+            .consume(qp.rx_bytes_read as u64, TokenType::Bytes)
         {
             // revert the OPS consume()
-            self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
+            qp.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
             METRICS.net.rx_rate_limiter_throttled.inc();
+            qp.metrics.rx_rate_limiter_throttled.inc();
             return false;
         }
 
         // Attempt frame delivery.
-        let success = self.write_frame_to_guest();
+        let success = self.write_frame_to_guest(queue_pair);
 
         // Undo the tokens consumption if guest delivery failed.
         if !success {
+            let qp = &mut self.queue_pairs[queue_pair];
             // revert the OPS consume()
-            self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
+            qp.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
             // revert the BYTES consume()
-            self.rx_rate_limiter
-                .manual_replenish(self.rx_bytes_read as u64, TokenType::Bytes);
+            qp.rx_rate_limiter
+                .manual_replenish(qp.rx_bytes_read as u64, TokenType::Bytes);
         }
         success
     }
@@ -367,12 +1057,16 @@ impl Net {
         Err(FrontendError::DescriptorChainTooSmall)
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest.
-    fn do_write_frame_to_guest(&mut self) -> std::result::Result<(), FrontendError> {
+    // Copies a single frame from the `rx_frame_buf` of a queue pair into the guest.
+    fn do_write_frame_to_guest(
+        &mut self,
+        queue_pair: usize,
+    ) -> std::result::Result<(), FrontendError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
-        let queue = &mut self.queues[RX_INDEX];
+        let queue = &mut self.queues[rx_queue_index(queue_pair)];
+        let qp = &self.queue_pairs[queue_pair];
         let head_descriptor = queue.pop_or_enable_notification(mem).ok_or_else(|| {
             METRICS.net.no_rx_avail_buffer.inc();
             FrontendError::EmptyQueue
@@ -381,7 +1075,7 @@ impl Net {
 
         let result = Self::write_to_descriptor_chain(
             mem,
-            &self.rx_frame_buf[..self.rx_bytes_read],
+            &qp.rx_frame_buf[..qp.rx_bytes_read],
             head_descriptor,
         );
         // Mark the descriptor chain as used. If an error occurred, skip the descriptor chain.
@@ -389,7 +1083,9 @@ impl Net {
             METRICS.net.rx_fails.inc();
             0
         } else {
-            self.rx_bytes_read as u32
+            qp.metrics.rx_bytes_count.add(qp.rx_bytes_read);
+            qp.metrics.rx_packets_count.inc();
+            qp.rx_bytes_read as u32
         };
         queue.add_used(mem, head_index, used_len).map_err(|err| {
             error!("Failed to add available descriptor {}: {}", head_index, err);
@@ -399,12 +1095,17 @@ impl Net {
         result
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest. In case of an error retries
-    // the operation if possible. Returns true if the operation was successfull.
-    fn write_frame_to_guest(&mut self) -> bool {
-        let max_iterations = self.queues[RX_INDEX].actual_size();
+    // Copies a single frame from the `rx_frame_buf` of a queue pair into the guest, unless the
+    // frame was already read into a descriptor chain. In case of an error retries the operation
+    // if possible. Returns true if the operation was successfull.
+    fn write_frame_to_guest(&mut self, queue_pair: usize) -> bool {
+        if let Some(head_index) = self.queue_pairs[queue_pair].rx_pending_head {
+            return self.complete_pending_frame(queue_pair, head_index);
+        }
+
+        let max_iterations = self.queues[rx_queue_index(queue_pair)].actual_size();
         for _ in 0..max_iterations {
-            match self.do_write_frame_to_guest() {
+            match self.do_write_frame_to_guest(queue_pair) {
                 Ok(()) => return true,
                 Err(FrontendError::EmptyQueue) | Err(FrontendError::AddUsed) => {
                     return false;
@@ -421,14 +1122,17 @@ impl Net {
 
     // Tries to detour the frame to MMDS and if MMDS doesn't accept it, sends it on the host TAP.
     //
-    // `frame_buf` should contain the frame bytes in a slice of exact length.
+    // `frame_iovec` holds the frame bytes in guest memory. The frame is only copied to
+    // `frame_buf` when the MMDS has to inspect it, otherwise it is gathered by the kernel.
     // Returns whether MMDS consumed the frame.
     fn write_to_mmds_or_tap(
         mmds_ns: Option<&mut MmdsNetworkStack>,
         rate_limiter: &mut RateLimiter,
-        frame_buf: &[u8],
-        tap: &mut Tap,
+        frame_iovec: &IoVecBuffer,
+        frame_buf: &mut [u8],
+        tap: &Tap,
         guest_mac: MacAddr,
+        metrics: &NetQueueMetrics,
     ) -> Result<bool> {
         let checked_frame = |frame_buf| {
             frame_bytes_from_buf(frame_buf).map_err(|err| {
@@ -438,11 +1142,12 @@ impl Net {
             })
         };
         if let Some(ns) = mmds_ns {
-            if ns.detour_frame(checked_frame(frame_buf)?) {
+            let len = frame_iovec.read_at(frame_buf, 0);
+            if ns.detour_frame(checked_frame(&frame_buf[..len])?) {
                 METRICS.mmds.rx_accepted.inc();
 
                 // MMDS frames are not accounted by the rate limiter.
-                rate_limiter.manual_replenish(frame_buf.len() as u64, TokenType::Bytes);
+                rate_limiter.manual_replenish(len as u64, TokenType::Bytes);
                 rate_limiter.manual_replenish(1, TokenType::Ops);
 
                 // MMDS consumed the frame.
@@ -452,18 +1157,22 @@ impl Net {
 
         // This frame goes to the TAP.
 
-        // Check for guest MAC spoofing.
-        let _ = EthernetFrame::from_bytes(checked_frame(frame_buf)?).map(|eth_frame| {
-            if guest_mac != eth_frame.src_mac() {
-                METRICS.net.tx_spoofed_mac_count.inc();
-            }
-        });
+        // Check for guest MAC spoofing. Only the headers are needed for this.
+        let header_len = frame_iovec.read_at(&mut frame_buf[..vnet_hdr_len() + PAYLOAD_OFFSET], 0);
+        let _ =
+            EthernetFrame::from_bytes(checked_frame(&frame_buf[..header_len])?).map(|eth_frame| {
+                if guest_mac != eth_frame.src_mac() {
+                    METRICS.net.tx_spoofed_mac_count.inc();
+                }
+            });
 
-        match tap.write(frame_buf) {
+        match frame_iovec.writev(tap) {
             Ok(_) => {
-                METRICS.net.tx_bytes_count.add(frame_buf.len());
+                METRICS.net.tx_bytes_count.add(frame_iovec.len());
                 METRICS.net.tx_packets_count.inc();
                 METRICS.net.tx_count.inc();
+                metrics.tx_bytes_count.add(frame_iovec.len());
+                metrics.tx_packets_count.inc();
             }
             Err(err) => {
                 error!("Failed to write to tap: {:?}", err);
@@ -474,31 +1183,120 @@ impl Net {
     }
 
     // We currently prioritize packets from the MMDS over regular network packets.
-    fn read_from_mmds_or_tap(&mut self) -> Result<usize> {
+    fn read_from_mmds_or_tap(&mut self, queue_pair: usize) -> Result<usize> {
         if let Some(ns) = self.mmds_ns.as_mut() {
-            if let Some(len) =
-                ns.write_next_frame(frame_bytes_from_buf_mut(&mut self.rx_frame_buf)?)
-            {
+            let rx_frame_buf = &mut self.queue_pairs[queue_pair].rx_frame_buf;
+            if let Some(len) = ns.write_next_frame(frame_bytes_from_buf_mut(rx_frame_buf)?) {
                 let len = len.get();
                 METRICS.mmds.tx_frames.inc();
                 METRICS.mmds.tx_bytes.add(len);
-                init_vnet_hdr(&mut self.rx_frame_buf);
+                init_vnet_hdr(rx_frame_buf);
                 return Ok(vnet_hdr_len() + len);
             }
         }
 
-        self.read_tap().map_err(Error::IO)
+        // Frames are read straight into the next descriptor chain when it can hold a frame of
+        // any size. Otherwise the frame is read into `rx_frame_buf` and copied into the guest,
+        // since the tap drops the part of a frame which doesn't fit.
+        if let Some(head_index) = self.pop_rx_iovec(queue_pair) {
+            return match self.read_tap_iovec(queue_pair) {
+                Ok(count) => {
+                    let qp = &mut self.queue_pairs[queue_pair];
+                    // The frame was written to guest memory behind the back of the dirty page
+                    // tracking. This is safe since the device is activated.
+                    qp.rx_iovec
+                        .mark_dirty(self.device_state.mem().unwrap(), count);
+                    qp.rx_pending_head = Some(head_index);
+                    Ok(count)
+                }
+                Err(err) => {
+                    self.queues[rx_queue_index(queue_pair)].undo_pop();
+                    Err(Error::IO(err))
+                }
+            };
+        }
+
+        self.read_tap(queue_pair).map_err(Error::IO)
+    }
+
+    // Pops the next RX descriptor chain and maps it to the `rx_iovec` of a queue pair, if the
+    // chain is valid and large enough to hold any frame. Returns the index of the chain head.
+    fn pop_rx_iovec(&mut self, queue_pair: usize) -> Option<u16> {
+        // This is safe since we checked in the event handler that the device is activated.
+        let mem = self.device_state.mem().unwrap();
+
+        let queue = &mut self.queues[rx_queue_index(queue_pair)];
+        let qp = &mut self.queue_pairs[queue_pair];
+        let head = queue.pop(mem)?;
+        let head_index = head.index;
+        let mut next_desc = Some(head);
+
+        qp.rx_iovec.clear();
+        while let Some(desc) = next_desc {
+            let mapped =
+                desc.is_write_only() && qp.rx_iovec.push(mem, desc.addr, desc.len as usize).is_ok();
+            if !mapped {
+                qp.rx_iovec.clear();
+                break;
+            }
+            next_desc = desc.next_descriptor();
+        }
+
+        if qp.rx_iovec.len() < MAX_BUFFER_SIZE {
+            // Leave the chain to the copying path, which also discards invalid chains.
+            queue.undo_pop();
+            return None;
+        }
+        Some(head_index)
     }
 
-    fn process_rx(&mut self) -> result::Result<(), DeviceError> {
+    // Returns the descriptor chain holding the current frame to the guest. The frame was read
+    // straight into the chain.
+    fn complete_pending_frame(&mut self, queue_pair: usize, head_index: u16) -> bool {
+        // This is safe since we checked in the event handler that the device is activated.
+        let mem = self.device_state.mem().unwrap();
+
+        let queue = &mut self.queues[rx_queue_index(queue_pair)];
+        let qp = &mut self.queue_pairs[queue_pair];
+        if let Err(err) = queue.add_used(mem, head_index, qp.rx_bytes_read as u32) {
+            error!("Failed to add available descriptor {}: {}", head_index, err);
+            return false;
+        }
+        qp.rx_pending_head = None;
+
+        METRICS.net.rx_count.add(qp.rx_iovec.num_buffers());
+        METRICS.net.rx_bytes_count.add(qp.rx_bytes_read);
+        METRICS.net.rx_packets_count.inc();
+        qp.metrics.rx_bytes_count.add(qp.rx_bytes_read);
+        qp.metrics.rx_packets_count.inc();
+        true
+    }
+
+    fn process_rx(&mut self, queue_pair: usize) -> result::Result<(), DeviceError> {
+        // The frames wait on the tap until the link is brought back up.
+        if self.link_state() == LinkState::Down {
+            return Ok(());
+        }
+
         // Read as many frames as possible.
         loop {
-            match self.read_from_mmds_or_tap() {
+            match self.read_from_mmds_or_tap(queue_pair) {
                 Ok(count) => {
-                    self.rx_bytes_read = count;
+                    let qp = &mut self.queue_pairs[queue_pair];
+                    qp.rx_bytes_read = count;
                     METRICS.net.rx_count.inc();
-                    if !self.rate_limited_rx_single_frame() {
-                        self.rx_deferred_frame = true;
+                    // Frames are captured once, when read, even if the rate limiter defers them.
+                    if qp.rx_pending_head.is_some() {
+                        let rx_iovec = &qp.rx_iovec;
+                        Self::capture_frame(&mut self.capture, |pcap| {
+                            pcap.write_frame_iovec(rx_iovec, count)
+                        });
+                    } else {
+                        let frame = &qp.rx_frame_buf[..count];
+                        Self::capture_frame(&mut self.capture, |pcap| pcap.write_frame(frame));
+                    }
+                    if !self.rate_limited_rx_single_frame(queue_pair) {
+                        self.queue_pairs[queue_pair].rx_deferred_frame = true;
                         break;
                     }
                 }
@@ -523,30 +1321,38 @@ impl Net {
 
         // At this point we processed as many Rx frames as possible.
         // We have to wake the guest if at least one descriptor chain has been used.
-        self.signal_used_queue(NetQueue::Rx)
+        self.signal_used_queue(NetQueue::Rx, queue_pair)
     }
 
     // Process the deferred frame first, then continue reading from tap.
-    fn handle_deferred_frame(&mut self) -> result::Result<(), DeviceError> {
-        if self.rate_limited_rx_single_frame() {
-            self.rx_deferred_frame = false;
+    fn handle_deferred_frame(&mut self, queue_pair: usize) -> result::Result<(), DeviceError> {
+        if self.link_state() == LinkState::Down {
+            return Ok(());
+        }
+        if self.rate_limited_rx_single_frame(queue_pair) {
+            self.queue_pairs[queue_pair].rx_deferred_frame = false;
             // process_rx() was interrupted possibly before consuming all
             // packets in the tap; try continuing now.
-            return self.process_rx();
+            return self.process_rx(queue_pair);
         }
 
-        self.signal_used_queue(NetQueue::Rx)
+        self.signal_used_queue(NetQueue::Rx, queue_pair)
     }
 
-    fn resume_rx(&mut self) -> result::Result<(), DeviceError> {
-        if self.rx_deferred_frame {
-            self.handle_deferred_frame()
+    fn resume_rx(&mut self, queue_pair: usize) -> result::Result<(), DeviceError> {
+        if self.queue_pairs[queue_pair].rx_deferred_frame {
+            self.handle_deferred_frame(queue_pair)
         } else {
             Ok(())
         }
     }
 
-    fn process_tx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_tx(&mut self, queue_pair: usize) -> result::Result<(), DeviceError> {
+        // The driver's frames wait in the tx queue until the link is brought back up.
+        if self.link_state() == LinkState::Down {
+            return Ok(());
+        }
+
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
@@ -556,16 +1362,18 @@ impl Net {
         // with the MMDS network stack.
         let mut process_rx_for_mmds = false;
         let mut used_any = false;
-        let tx_queue = &mut self.queues[TX_INDEX];
+        let tx_queue = &mut self.queues[tx_queue_index(queue_pair)];
+        let qp = &mut self.queue_pairs[queue_pair];
 
         while let Some(head) = tx_queue.pop_or_enable_notification(mem) {
             // If limiter.consume() fails it means there is no more TokenType::Ops
             // budget and rate limiting is in effect.
-            if !self.tx_rate_limiter.consume(1, TokenType::Ops) {
+            if !qp.tx_rate_limiter.consume(1, TokenType::Ops) {
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
                 tx_queue.undo_pop();
                 METRICS.net.tx_rate_limiter_throttled.inc();
+                qp.metrics.tx_rate_limiter_throttled.inc();
                 break;
             }
 
@@ -573,72 +1381,88 @@ impl Net {
             let mut read_count = 0;
             let mut next_desc = Some(head);
 
-            self.tx_iovec.clear();
+            let mut chain_error = None;
+            qp.tx_iovec.clear();
             while let Some(desc) = next_desc {
                 if desc.is_write_only() {
-                    self.tx_iovec.clear();
+                    qp.tx_iovec.clear();
                     break;
                 }
-                self.tx_iovec.push((desc.addr, desc.len as usize));
                 read_count += desc.len as usize;
                 next_desc = desc.next_descriptor();
+
+                // Frames larger than the maximum frame size are truncated.
+                let len = cmp::min(desc.len as usize, MAX_BUFFER_SIZE - qp.tx_iovec.len());
+                if chain_error.is_some() || len == 0 {
+                    continue;
+                }
+                if let Err(err) = qp.tx_iovec.push(mem, desc.addr, len) {
+                    chain_error = Some((desc.addr, err));
+                    qp.tx_iovec.clear();
+                }
             }
 
             // If limiter.consume() fails it means there is no more TokenType::Bytes
             // budget and rate limiting is in effect.
-            if !self
+            if !qp
                 .tx_rate_limiter
                 .consume(read_count as u64, TokenType::Bytes)
             {
                 // revert the OPS consume()
-                self.tx_rate_limiter.manual_replenish(1, TokenType::Ops);
+                qp.tx_rate_limiter.manual_replenish(1, TokenType::Ops);
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
                 tx_queue.undo_pop();
                 METRICS.net.tx_rate_limiter_throttled.inc();
+                qp.metrics.tx_rate_limiter_throttled.inc();
                 break;
             }
 
-            read_count = 0;
-            // Copy buffer from across multiple descriptors.
-            // TODO(performance - Issue #420): change this to use `writev()` instead of `write()`
-            // and get rid of the intermediate buffer.
-            for (desc_addr, desc_len) in self.tx_iovec.drain(..) {
-                let limit = cmp::min((read_count + desc_len) as usize, self.tx_frame_buf.len());
-
-                let read_result = mem.read_slice(
-                    &mut self.tx_frame_buf[read_count..limit as usize],
-                    desc_addr,
-                );
-                match read_result {
-                    Ok(()) => {
-                        read_count += limit - read_count;
-                        METRICS.net.tx_count.inc();
-                    }
-                    Err(err) => {
-                        error!("Failed to read slice: {:?}", err);
-                        match err {
-                            GuestMemoryError::PartialBuffer { .. } => &METRICS.net.tx_partial_reads,
-                            _ => &METRICS.net.tx_fails,
-                        }
-                        .inc();
-                        read_count = 0;
-                        break;
+            match chain_error {
+                Some((desc_addr, err)) => {
+                    error!("Failed to read slice: {:?}", err);
+                    // The descriptor starts in guest memory, but doesn't fit.
+                    if mem.address_in_range(desc_addr) {
+                        METRICS.net.tx_partial_reads.inc();
+                    } else {
+                        METRICS.net.tx_fails.inc();
                     }
                 }
+                None => METRICS.net.tx_count.add(qp.tx_iovec.num_buffers()),
             }
 
-            let frame_consumed_by_mmds = Self::write_to_mmds_or_tap(
-                self.mmds_ns.as_mut(),
-                &mut self.tx_rate_limiter,
-                &self.tx_frame_buf[..read_count],
-                &mut self.tap,
-                self.guest_mac,
-            )
-            .unwrap_or(false);
-            if frame_consumed_by_mmds && !self.rx_deferred_frame {
-                // MMDS consumed this frame/request, let's also try to process the response.
-                process_rx_for_mmds = true;
+            // Frames are captured once they got through the rate limiter, so that throttled
+            // frames are not recorded twice.
+            let tx_iovec = &qp.tx_iovec;
+            Self::capture_frame(&mut self.capture, |pcap| {
+                pcap.write_frame_iovec(tx_iovec, tx_iovec.len())
+            });
+
+            // Frames breaking the anti-spoofing policy are dropped, for both the tap and MMDS.
+            let allowed = match self.anti_spoofing.as_ref() {
+                Some(policy) => Self::check_anti_spoofing(
+                    policy,
+                    self.guest_mac,
+                    &qp.tx_iovec,
+                    &mut qp.tx_frame_buf,
+                ),
+                None => true,
+            };
+            if allowed {
+                let frame_consumed_by_mmds = Self::write_to_mmds_or_tap(
+                    self.mmds_ns.as_mut(),
+                    &mut qp.tx_rate_limiter,
+                    &qp.tx_iovec,
+                    &mut qp.tx_frame_buf,
+                    &qp.tap,
+                    self.guest_mac,
+                    &qp.metrics,
+                )
+                .unwrap_or(false);
+                if frame_consumed_by_mmds && !qp.rx_deferred_frame {
+                    // MMDS consumed this frame/request, let's also try to process the response.
+                    process_rx_for_mmds = true;
+                }
             }
 
             tx_queue
@@ -651,49 +1475,187 @@ impl Net {
             METRICS.net.no_tx_avail_buffer.inc();
         }
 
-        self.signal_used_queue(NetQueue::Tx)?;
+        self.signal_used_queue(NetQueue::Tx, queue_pair)?;
 
         // An incoming frame for the MMDS may trigger the transmission of a new message.
         if process_rx_for_mmds {
-            self.process_rx()
+            self.process_rx(queue_pair)
         } else {
             Ok(())
         }
     }
 
-    /// Updates the parameters for the rate limiters
+    // Reads the command of a control queue descriptor chain. Returns the command bytes, along
+    // with the address where the driver expects the ack, if any.
+    fn read_ctrl_request(
+        mem: &GuestMemoryMmap,
+        head: DescriptorChain,
+    ) -> (Vec<u8>, Option<GuestAddress>) {
+        let mut request = Vec::new();
+        let mut next_desc = Some(head);
+
+        while let Some(desc) = next_desc {
+            // The ack is the only device writable part of the request.
+            if desc.is_write_only() {
+                return (request, Some(desc.addr));
+            }
+
+            let len = cmp::min(desc.len as usize, MAX_CTRL_REQUEST_LEN - request.len());
+            let start = request.len();
+            request.resize(start + len, 0);
+            if let Err(err) = mem.read_slice(&mut request[start..], desc.addr) {
+                error!("Failed to read control request: {:?}", err);
+                request.truncate(start);
+            }
+            next_desc = desc.next_descriptor();
+        }
+
+        (request, None)
+    }
+
+    // Handles a control command and returns the ack to report to the driver.
+    fn handle_ctrl_command(&mut self, request: &[u8]) -> u8 {
+        match request {
+            [class, command, ..]
+                if u32::from(*class) == VIRTIO_NET_CTRL_ANNOUNCE
+                    && u32::from(*command) == VIRTIO_NET_CTRL_ANNOUNCE_ACK =>
+            {
+                self.config_space.status &= !(VIRTIO_NET_S_ANNOUNCE as u16);
+                VIRTIO_NET_OK as u8
+            }
+            [class, command, pairs_lo, pairs_hi, ..]
+                if u32::from(*class) == VIRTIO_NET_CTRL_MQ
+                    && u32::from(*command) == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET =>
+            {
+                let active_queue_pairs = u16::from_le_bytes([*pairs_lo, *pairs_hi]);
+                match self.set_active_queue_pairs(active_queue_pairs) {
+                    Ok(()) => VIRTIO_NET_OK as u8,
+                    Err(err) => {
+                        error!("Failed to set the active queue pairs: {:?}", err);
+                        VIRTIO_NET_ERR as u8
+                    }
+                }
+            }
+            _ => {
+                warn!("Net: Unsupported control command: {:?}", request.get(..2));
+                VIRTIO_NET_ERR as u8
+            }
+        }
+    }
+
+    fn process_ctrl_queue(&mut self) -> result::Result<(), DeviceError> {
+        let ctrl_index = match self.ctrl_queue_index() {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let mut used_any = false;
+
+        loop {
+            // This is safe since we checked in the event handler that the device is activated.
+            let mem = self.device_state.mem().unwrap();
+            let head = match self.queues[ctrl_index].pop_or_enable_notification(mem) {
+                Some(head) => head,
+                None => break,
+            };
+            let head_index = head.index;
+            let (request, ack_addr) = Self::read_ctrl_request(mem, head);
+
+            let ack = self.handle_ctrl_command(&request);
+
+            let mem = self.device_state.mem().unwrap();
+            let used_len = match ack_addr.map(|addr| mem.write_obj(ack, addr)) {
+                // The ack is a single byte.
+                Some(Ok(())) => 1,
+                Some(Err(err)) => {
+                    error!("Failed to write control request ack: {:?}", err);
+                    0
+                }
+                None => {
+                    error!("Control request without ack descriptor");
+                    0
+                }
+            };
+            self.queues[ctrl_index]
+                .add_used(mem, head_index, used_len)
+                .map_err(DeviceError::QueueError)?;
+            used_any = true;
+        }
+
+        // This is safe since we checked in the event handler that the device is activated.
+        let mem = self.device_state.mem().unwrap();
+        if used_any && self.queues[ctrl_index].prepare_kick(mem) {
+            self.irq_trigger
+                .trigger_irq(IrqType::Vring)
+                .map_err(|err| {
+                    METRICS.net.event_fails.inc();
+                    DeviceError::FailedSignalingIrq(err)
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Updates the parameters for the rate limiters of all the queue pairs.
+    ///
+    /// The frames don't go through the rate limiters while the queues are processed by
+    /// vhost-net, so the rate limiters can't be enabled then.
     pub fn patch_rate_limiters(
         &mut self,
         rx_bytes: BucketUpdate,
         rx_ops: BucketUpdate,
         tx_bytes: BucketUpdate,
         tx_ops: BucketUpdate,
-    ) {
-        self.rx_rate_limiter.update_buckets(rx_bytes, rx_ops);
-        self.tx_rate_limiter.update_buckets(tx_bytes, tx_ops);
+    ) -> Result<()> {
+        let enables_rate_limiting = [&rx_bytes, &rx_ops, &tx_bytes, &tx_ops]
+            .iter()
+            .any(|update| matches!(update, BucketUpdate::Update(_)));
+        if self.vhost.is_some() && enables_rate_limiting {
+            return Err(Error::VhostNetRateLimiter);
+        }
+
+        for qp in &mut self.queue_pairs {
+            qp.rx_rate_limiter
+                .update_buckets(rx_bytes.clone(), rx_ops.clone());
+            qp.tx_rate_limiter
+                .update_buckets(tx_bytes.clone(), tx_ops.clone());
+        }
+
+        Ok(())
     }
 
     #[cfg(not(test))]
-    fn read_tap(&mut self) -> std::io::Result<usize> {
-        self.tap.read(&mut self.rx_frame_buf)
+    fn read_tap(&mut self, queue_pair: usize) -> std::io::Result<usize> {
+        let qp = &mut self.queue_pairs[queue_pair];
+        qp.tap.read(&mut qp.rx_frame_buf)
     }
 
-    pub fn process_rx_queue_event(&mut self) {
+    #[cfg(not(test))]
+    fn read_tap_iovec(&mut self, queue_pair: usize) -> std::io::Result<usize> {
+        let qp = &self.queue_pairs[queue_pair];
+        qp.rx_iovec.readv(&qp.tap)
+    }
+
+    pub fn process_rx_queue_event(&mut self, queue_pair: usize) {
         METRICS.net.rx_queue_event_count.inc();
 
-        if let Err(err) = self.queue_evts[RX_INDEX].read() {
+        if let Err(err) = self.queue_evts[rx_queue_index(queue_pair)].read() {
             // rate limiters present but with _very high_ allowed rate
             error!("Failed to get rx queue event: {:?}", err);
             METRICS.net.event_fails.inc();
-        } else if self.rx_rate_limiter.is_blocked() {
+        } else if self.queue_pairs[queue_pair].rx_rate_limiter.is_blocked() {
             METRICS.net.rx_rate_limiter_throttled.inc();
+            self.queue_pairs[queue_pair]
+                .metrics
+                .rx_rate_limiter_throttled
+                .inc();
         } else {
             // If the limiter is not blocked, resume the receiving of bytes.
-            self.resume_rx().unwrap_or_else(report_net_event_fail);
+            self.resume_rx(queue_pair)
+                .unwrap_or_else(report_net_event_fail);
         }
     }
 
-    pub fn process_tap_rx_event(&mut self) {
+    pub fn process_tap_rx_event(&mut self, queue_pair: usize) {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
         METRICS.net.rx_tap_event_count.inc();
@@ -702,51 +1664,75 @@ impl Net {
         // don't process any more incoming. Otherwise start processing a frame. In the
         // process the deferred_frame flag will be set in order to avoid freezing the
         // RX queue.
-        if self.queues[RX_INDEX].is_empty(mem) && self.rx_deferred_frame {
+        let qp = &self.queue_pairs[queue_pair];
+        if self.queues[rx_queue_index(queue_pair)].is_empty(mem) && qp.rx_deferred_frame {
             METRICS.net.no_rx_avail_buffer.inc();
             return;
         }
 
         // While limiter is blocked, don't process any more incoming.
-        if self.rx_rate_limiter.is_blocked() {
+        if qp.rx_rate_limiter.is_blocked() {
             METRICS.net.rx_rate_limiter_throttled.inc();
+            qp.metrics.rx_rate_limiter_throttled.inc();
             return;
         }
 
-        if self.rx_deferred_frame
+        if qp.rx_deferred_frame
         // Process a deferred frame first if available. Don't read from tap again
         // until we manage to receive this deferred frame.
         {
-            self.handle_deferred_frame()
+            self.handle_deferred_frame(queue_pair)
                 .unwrap_or_else(report_net_event_fail);
         } else {
-            self.process_rx().unwrap_or_else(report_net_event_fail);
+            self.process_rx(queue_pair)
+                .unwrap_or_else(report_net_event_fail);
         }
     }
 
-    pub fn process_tx_queue_event(&mut self) {
+    pub fn process_tx_queue_event(&mut self, queue_pair: usize) {
         METRICS.net.tx_queue_event_count.inc();
-        if let Err(err) = self.queue_evts[TX_INDEX].read() {
+        if let Err(err) = self.queue_evts[tx_queue_index(queue_pair)].read() {
             error!("Failed to get tx queue event: {:?}", err);
             METRICS.net.event_fails.inc();
-        } else if !self.tx_rate_limiter.is_blocked()
+        } else if !self.queue_pairs[queue_pair].tx_rate_limiter.is_blocked()
         // If the limiter is not blocked, continue transmitting bytes.
         {
-            self.process_tx().unwrap_or_else(report_net_event_fail);
+            self.process_tx(queue_pair)
+                .unwrap_or_else(report_net_event_fail);
         } else {
             METRICS.net.tx_rate_limiter_throttled.inc();
+            self.queue_pairs[queue_pair]
+                .metrics
+                .tx_rate_limiter_throttled
+                .inc();
         }
     }
 
-    pub fn process_rx_rate_limiter_event(&mut self) {
+    pub fn process_ctrl_queue_event(&mut self) {
+        let ctrl_index = match self.ctrl_queue_index() {
+            Some(index) => index,
+            None => return,
+        };
+
+        if let Err(err) = self.queue_evts[ctrl_index].read() {
+            error!("Failed to get ctrl queue event: {:?}", err);
+            METRICS.net.event_fails.inc();
+        } else {
+            self.process_ctrl_queue()
+                .unwrap_or_else(report_net_event_fail);
+        }
+    }
+
+    pub fn process_rx_rate_limiter_event(&mut self, queue_pair: usize) {
         METRICS.net.rx_event_rate_limiter_count.inc();
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
 
-        match self.rx_rate_limiter.event_handler() {
+        match self.queue_pairs[queue_pair].rx_rate_limiter.event_handler() {
             Ok(_) => {
                 // There might be enough budget now to receive the frame.
-                self.resume_rx().unwrap_or_else(report_net_event_fail);
+                self.resume_rx(queue_pair)
+                    .unwrap_or_else(report_net_event_fail);
             }
             Err(err) => {
                 error!("Failed to get rx rate-limiter event: {:?}", err);
@@ -755,14 +1741,15 @@ impl Net {
         }
     }
 
-    pub fn process_tx_rate_limiter_event(&mut self) {
+    pub fn process_tx_rate_limiter_event(&mut self, queue_pair: usize) {
         METRICS.net.tx_rate_limiter_event_count.inc();
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
-        match self.tx_rate_limiter.event_handler() {
+        match self.queue_pairs[queue_pair].tx_rate_limiter.event_handler() {
             Ok(_) => {
                 // There might be enough budget now to send the frame.
-                self.process_tx().unwrap_or_else(report_net_event_fail);
+                self.process_tx(queue_pair)
+                    .unwrap_or_else(report_net_event_fail);
             }
             Err(err) => {
                 error!("Failed to get tx rate-limiter event: {:?}", err);
@@ -771,10 +1758,33 @@ impl Net {
         }
     }
 
+    pub fn process_vhost_call_event(&mut self, queue_index: usize) {
+        // Safe to unwrap since the call events are only registered along with the backend.
+        let backend = self.vhost.as_ref().unwrap();
+        if let Err(err) = backend.call_evts[queue_index].read() {
+            error!("Failed to get vhost-net call event: {:?}", err);
+            METRICS.net.event_fails.inc();
+            return;
+        }
+
+        // The kernel already checked whether the driver wants to be notified.
+        self.irq_trigger
+            .trigger_irq(IrqType::Vring)
+            .unwrap_or_else(|err| report_net_event_fail(DeviceError::FailedSignalingIrq(err)));
+    }
+
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        let _ = self.resume_rx();
-        let _ = self.process_tx();
+        if self.vhost.is_some() {
+            // The rx and tx queues are processed by the kernel.
+            self.resume_vhost_net();
+        } else {
+            for queue_pair in 0..self.queue_pairs.len() {
+                let _ = self.resume_rx(queue_pair);
+                let _ = self.process_tx(queue_pair);
+            }
+        }
+        let _ = self.process_ctrl_queue();
     }
 }
 
@@ -835,7 +1845,8 @@ impl VirtioDevice for Net {
     fn write_config(&mut self, offset: u64, data: &[u8]) {
         let data_len = data.len() as u64;
         let config_space_bytes = self.config_space.as_mut_slice();
-        let config_len = config_space_bytes.len() as u64;
+        // Only the MAC address can be written by the driver.
+        let config_len = MAC_ADDR_LEN as u64;
         if offset + data_len > config_len {
             error!("Failed to write config space");
             METRICS.net.cfg_fails.inc();
@@ -873,6 +1884,8 @@ impl VirtioDevice for Net {
 #[macro_use]
 pub mod tests {
     use std::net::Ipv4Addr;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
     use std::time::Duration;
     use std::{io, mem, thread};
 
@@ -880,40 +1893,60 @@ pub mod tests {
     use dumbo::pdu::ethernet::ETHERTYPE_ARP;
     use logger::{IncMetric, METRICS};
     use rate_limiter::{RateLimiter, TokenBucket, TokenType};
+    use utils::tempfile::TempFile;
     use virtio_gen::virtio_net::{
-        virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
-        VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4,
-        VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC,
+        virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MQ_RSS_CONFIG, VIRTIO_NET_F_CSUM,
+        VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN, VIRTIO_NET_F_GUEST_TSO4,
+        VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_ECN,
+        VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC,
     };
-    use vm_memory::{Address, GuestMemory};
+    use vm_memory::{Address, Bitmap, GuestMemory, GuestMemoryRegion};
 
     use super::*;
     use crate::check_metric_after_block;
     use crate::virtio::net::device::{
         frame_bytes_from_buf, frame_bytes_from_buf_mut, init_vnet_hdr, vnet_hdr_len,
     };
+    use crate::virtio::net::filter::Ipv4Prefix;
+    use crate::virtio::net::pcap::{PCAP_HEADER_LEN, PCAP_RECORD_HEADER_LEN};
     use crate::virtio::net::test_utils::test::TestHelper;
     use crate::virtio::net::test_utils::{
-        default_net, if_index, inject_tap_tx_frame, set_mac, NetEvent, NetQueue, ReadTapMock,
+        default_guest_memory, default_net, default_net_no_mmds, default_net_with_queue_pairs,
+        if_index, inject_tap_tx_frame, set_mac, NetEvent, NetQueue, ReadTapMock,
         TapTrafficSimulator,
     };
-    use crate::virtio::net::QUEUE_SIZES;
+    use crate::virtio::net::{DEFAULT_NUM_QUEUE_PAIRS, QUEUE_SIZES};
+    use crate::virtio::test_utils::VirtQueue;
     use crate::virtio::{
-        Net, VirtioDevice, MAX_BUFFER_SIZE, RX_INDEX, TX_INDEX, TYPE_NET, VIRTQ_DESC_F_WRITE,
+        Net, VirtioDevice, MAX_BUFFER_SIZE, RX_INDEX, TX_INDEX, TYPE_NET, VIRTQ_DESC_F_NEXT,
+        VIRTQ_DESC_F_WRITE,
     };
 
     impl Net {
-        pub fn read_tap(&mut self) -> io::Result<usize> {
+        pub fn read_tap(&mut self, queue_pair: usize) -> io::Result<usize> {
+            let qp = &mut self.queue_pairs[queue_pair];
             match &self.mocks.read_tap {
                 ReadTapMock::MockFrame(frame) => {
-                    self.rx_frame_buf[..frame.len()].copy_from_slice(frame);
+                    qp.rx_frame_buf[..frame.len()].copy_from_slice(frame);
                     Ok(frame.len())
                 }
                 ReadTapMock::Failure => Err(io::Error::new(
                     io::ErrorKind::Other,
                     "Read tap synthetically failed.",
                 )),
-                ReadTapMock::TapFrame => self.tap.read(&mut self.rx_frame_buf),
+                ReadTapMock::TapFrame => qp.tap.read(&mut qp.rx_frame_buf),
+            }
+        }
+
+        pub fn read_tap_iovec(&mut self, queue_pair: usize) -> io::Result<usize> {
+            let qp = &self.queue_pairs[queue_pair];
+            match &self.mocks.read_tap {
+                ReadTapMock::MockFrame(frame) => Ok(qp.rx_iovec.write_at(frame, 0)),
+                ReadTapMock::Failure => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Read tap synthetically failed.",
+                )),
+                ReadTapMock::TapFrame => qp.rx_iovec.readv(&qp.tap),
             }
         }
     }
@@ -965,10 +1998,17 @@ pub mod tests {
         let features = 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_CSUM
             | 1 << VIRTIO_NET_F_GUEST_TSO4
+            | 1 << VIRTIO_NET_F_GUEST_TSO6
+            | 1 << VIRTIO_NET_F_GUEST_ECN
             | 1 << VIRTIO_NET_F_MAC
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
+            | 1 << VIRTIO_NET_F_HOST_TSO6
+            | 1 << VIRTIO_NET_F_HOST_ECN
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_STATUS
+            | 1 << VIRTIO_NET_F_CTRL_VQ
+            | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE
             | 1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_RING_F_EVENT_IDX;
 
@@ -985,6 +2025,109 @@ pub mod tests {
         assert_eq!(net.acked_features, features);
     }
 
+    #[test]
+    fn test_offloads() {
+        let offloads = Offloads::default();
+        offloads.validate().unwrap();
+        assert_eq!(
+            offloads.tap_flags(),
+            net_gen::TUN_F_CSUM
+                | net_gen::TUN_F_TSO4
+                | net_gen::TUN_F_TSO6
+                | net_gen::TUN_F_UFO
+                | net_gen::TUN_F_TSO_ECN
+        );
+        assert_eq!(
+            Offloads::from_avail_features(offloads.avail_features()),
+            offloads
+        );
+
+        let offloads = Offloads {
+            checksum: true,
+            tso4: false,
+            tso6: true,
+            ufo: false,
+            ecn: false,
+        };
+        offloads.validate().unwrap();
+        assert_eq!(
+            offloads.avail_features(),
+            1 << VIRTIO_NET_F_GUEST_CSUM
+                | 1 << VIRTIO_NET_F_CSUM
+                | 1 << VIRTIO_NET_F_GUEST_TSO6
+                | 1 << VIRTIO_NET_F_HOST_TSO6
+        );
+        assert_eq!(
+            offloads.tap_flags(),
+            net_gen::TUN_F_CSUM | net_gen::TUN_F_TSO6
+        );
+        assert_eq!(
+            Offloads::from_avail_features(offloads.avail_features()),
+            offloads
+        );
+
+        // All the offloads can be disabled.
+        let no_offloads = Offloads {
+            checksum: false,
+            tso4: false,
+            tso6: false,
+            ufo: false,
+            ecn: false,
+        };
+        no_offloads.validate().unwrap();
+        assert_eq!(no_offloads.avail_features(), 0);
+        assert_eq!(no_offloads.tap_flags(), 0);
+
+        // Segmentation offloads require checksum offload.
+        let offloads = Offloads {
+            tso4: true,
+            ..no_offloads
+        };
+        assert!(matches!(
+            offloads.validate(),
+            Err(Error::InvalidOffloads(o)) if o == offloads
+        ));
+        // ECN requires TCP segmentation offload.
+        let offloads = Offloads {
+            checksum: true,
+            ufo: true,
+            ecn: true,
+            ..no_offloads
+        };
+        assert!(offloads.validate().is_err());
+    }
+
+    #[test]
+    fn test_virtio_device_no_offloads() {
+        let no_offloads = Offloads {
+            checksum: false,
+            tso4: false,
+            tso6: false,
+            ufo: false,
+            ecn: false,
+        };
+        let net = Net::new_with_tap(
+            "net-no-offloads".to_string(),
+            "net-no-offloads".to_string(),
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+            DEFAULT_NUM_QUEUE_PAIRS,
+            no_offloads,
+        )
+        .unwrap();
+        assert_eq!(net.offloads(), no_offloads);
+        assert_eq!(
+            net.avail_features(),
+            1 << VIRTIO_NET_F_MAC
+                | 1 << VIRTIO_NET_F_STATUS
+                | 1 << VIRTIO_NET_F_CTRL_VQ
+                | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE
+                | 1 << VIRTIO_F_VERSION_1
+                | 1 << VIRTIO_RING_F_EVENT_IDX
+        );
+    }
+
     #[test]
     fn test_virtio_device_read_config() {
         let mut net = default_net();
@@ -996,12 +2139,131 @@ pub mod tests {
         net.read_config(0, &mut config_mac);
         assert_eq!(config_mac, mac.get_bytes());
 
+        // The link is up by default.
+        let mut status = [0u8; 2];
+        net.read_config(MAC_ADDR_LEN as u64, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP as u16);
+
         // Invalid read.
         config_mac = [0u8; MAC_ADDR_LEN];
-        net.read_config(MAC_ADDR_LEN as u64 + 1, &mut config_mac);
+        net.read_config(mem::size_of::<ConfigSpace>() as u64 + 1, &mut config_mac);
         assert_eq!(config_mac, [0u8, 0u8, 0u8, 0u8, 0u8, 0u8]);
     }
 
+    #[test]
+    fn test_link_state() {
+        let mut net = default_net();
+        let read_status = |net: &Net| {
+            let mut status = [0u8; 2];
+            net.read_config(MAC_ADDR_LEN as u64, &mut status);
+            u16::from_le_bytes(status)
+        };
+
+        // The driver is not notified before the device is activated.
+        assert_eq!(net.link_state(), LinkState::Up);
+        net.set_link_state(LinkState::Down).unwrap();
+        assert_eq!(net.link_state(), LinkState::Down);
+        assert_eq!(read_status(&net), 0);
+        assert!(!net.irq_trigger.has_pending_irq(IrqType::Config));
+
+        net.activate(default_guest_memory()).unwrap();
+        net.set_link_state(LinkState::Up).unwrap();
+        assert_eq!(read_status(&net), VIRTIO_NET_S_LINK_UP as u16);
+        assert!(net.irq_trigger.has_pending_irq(IrqType::Config));
+
+        // Setting the current state again is a no-op.
+        net.set_link_state(LinkState::Up).unwrap();
+        assert!(!net.irq_trigger.has_pending_irq(IrqType::Config));
+
+        net.set_link_state(LinkState::Down).unwrap();
+        assert_eq!(read_status(&net), 0);
+        assert!(net.irq_trigger.has_pending_irq(IrqType::Config));
+    }
+
+    #[test]
+    fn test_link_down_traffic() {
+        let mut th = TestHelper::default();
+        th.activate_net();
+        th.net().mocks.set_read_tap(ReadTapMock::TapFrame);
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
+        th.net().set_link_state(LinkState::Down).unwrap();
+
+        // Neither the frame waiting on the tap nor the one in the tx queue go through while
+        // the link is down.
+        th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
+        let rx_frame = inject_tap_tx_frame(&th.net(), 1000);
+        let desc_list = [(0, 300, 0)];
+        th.add_desc_chain(NetQueue::Tx, 4096, &desc_list);
+        let tx_frame = th.write_tx_frame(&desc_list, 300);
+        th.event_manager.run_with_timeout(100).unwrap();
+        assert_eq!(th.rxq.used.idx.get(), 0);
+        assert_eq!(th.txq.used.idx.get(), 0);
+        let mut buf = vec![0; 300];
+        assert!(!tap_traffic_simulator.pop_rx_packet(&mut buf[vnet_hdr_len()..]));
+
+        // Both go through once the link is back up.
+        th.net().set_link_state(LinkState::Up).unwrap();
+        assert_eq!(th.rxq.used.idx.get(), 1);
+        th.rxq.check_used_elem(0, 0, rx_frame.len() as u32);
+        th.rxq.dtable[0].check_data(&rx_frame);
+        assert_eq!(th.txq.used.idx.get(), 1);
+        th.txq.check_used_elem(0, 0, 0);
+        assert!(tap_traffic_simulator.pop_rx_packet(&mut buf[vnet_hdr_len()..]));
+        assert_eq!(&buf[..300], &tx_frame[..300]);
+    }
+
+    #[test]
+    fn test_announce() {
+        let mut net = default_net();
+        let mem = default_guest_memory();
+        let ctrlq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let ctrl_index = net.ctrl_queue_index().unwrap();
+        net.queues[ctrl_index] = ctrlq.create_queue();
+        let announce_status = (VIRTIO_NET_S_LINK_UP | VIRTIO_NET_S_ANNOUNCE) as u16;
+
+        // Nothing to announce before the device is activated.
+        net.announce().unwrap();
+        assert_eq!(net.config_space.status, VIRTIO_NET_S_LINK_UP as u16);
+
+        // Nor if the driver doesn't support announcements.
+        net.activate(mem.clone()).unwrap();
+        net.announce().unwrap();
+        assert_eq!(net.config_space.status, VIRTIO_NET_S_LINK_UP as u16);
+        assert!(!net.irq_trigger.has_pending_irq(IrqType::Config));
+
+        net.set_acked_features(1 << VIRTIO_NET_F_GUEST_ANNOUNCE | 1 << VIRTIO_NET_F_CTRL_VQ);
+        net.announce_pending = true;
+        net.process_pending_announce();
+        assert!(!net.announce_pending);
+        assert_eq!(net.config_space.status, announce_status);
+        assert!(net.irq_trigger.has_pending_irq(IrqType::Config));
+
+        // The driver acknowledges the announcement through the control queue.
+        let (request_addr, ack_addr) = (0x2000, 0x3000);
+        mem.write_slice(
+            &[
+                VIRTIO_NET_CTRL_ANNOUNCE as u8,
+                VIRTIO_NET_CTRL_ANNOUNCE_ACK as u8,
+            ],
+            GuestAddress(request_addr),
+        )
+        .unwrap();
+        ctrlq.dtable[0].set(request_addr, 2, VIRTQ_DESC_F_NEXT, 1);
+        ctrlq.dtable[1].set(ack_addr, 1, VIRTQ_DESC_F_WRITE, 0);
+        ctrlq.avail.ring[0].set(0);
+        ctrlq.avail.idx.set(1);
+        net.queue_evts[ctrl_index].write(1).unwrap();
+        net.process_ctrl_queue_event();
+
+        ctrlq.check_used_elem(0, 0, 1);
+        assert_eq!(
+            u32::from(mem.read_obj::<u8>(GuestAddress(ack_addr)).unwrap()),
+            VIRTIO_NET_OK
+        );
+        assert_eq!(net.config_space.status, VIRTIO_NET_S_LINK_UP as u16);
+    }
+
     #[test]
     fn test_virtio_device_rewrite_config() {
         let mut net = default_net();
@@ -1150,7 +2412,7 @@ pub mod tests {
         th.rxq.check_used_elem(1, 3, 0);
         th.rxq.check_used_elem(2, 4, 0);
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the frame has been written successfully to the valid Rx descriptor chain.
         th.rxq.check_used_elem(3, 5, frame.len() as u32);
         th.rxq.dtable[5].check_data(&frame);
@@ -1183,7 +2445,7 @@ pub mod tests {
         );
 
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 1);
         assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1222,7 +2484,7 @@ pub mod tests {
         );
 
         // Check that the frames weren't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 2);
         assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1236,11 +2498,124 @@ pub mod tests {
         th.rxq.dtable[3].check_data(&[0; 500]);
     }
 
+    #[test]
+    fn test_rx_zero_copy() {
+        let mut th = TestHelper::default();
+        th.activate_net();
+        th.net().mocks.set_read_tap(ReadTapMock::TapFrame);
+
+        // A descriptor chain which can hold any frame is read into directly.
+        let desc_len = (MAX_BUFFER_SIZE / 2 + 1) as u32;
+        th.add_desc_chain(
+            NetQueue::Rx,
+            0,
+            &[
+                (0, desc_len, VIRTQ_DESC_F_WRITE),
+                (1, desc_len, VIRTQ_DESC_F_WRITE),
+            ],
+        );
+        let frame = inject_tap_tx_frame(&th.net(), 1000);
+        check_metric_after_block!(
+            METRICS.net.rx_packets_count,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
+        assert!(th.net().queue_pairs[0].rx_pending_head.is_none());
+        assert_eq!(th.rxq.used.idx.get(), 1);
+        assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
+        th.rxq.check_used_elem(0, 0, frame.len() as u32);
+        th.rxq.dtable[0].check_data(&frame);
+    }
+
+    #[test]
+    fn test_rx_zero_copy_dirty_pages() {
+        let mut th = TestHelper::with_dirty_page_tracking();
+        th.activate_net();
+        th.net().mocks.set_read_tap(ReadTapMock::TapFrame);
+
+        let desc_len = (MAX_BUFFER_SIZE / 2 + 1) as u32;
+        th.add_desc_chain(
+            NetQueue::Rx,
+            0,
+            &[
+                (0, desc_len, VIRTQ_DESC_F_WRITE),
+                (1, desc_len, VIRTQ_DESC_F_WRITE),
+            ],
+        );
+        let frame = inject_tap_tx_frame(&th.net(), 1000);
+        check_metric_after_block!(
+            METRICS.net.rx_packets_count,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        th.rxq.check_used_elem(0, 0, frame.len() as u32);
+
+        // The frame was read straight into guest memory, and its pages have to be part of the
+        // next diff snapshot.
+        let frame_addr = GuestAddress(th.rxq.dtable[0].addr.get());
+        th.mem
+            .try_access(frame.len(), frame_addr, |_total, count, caddr, region| {
+                let bitmap = region.bitmap().as_ref().unwrap();
+                for offset in caddr.0 as usize..caddr.0 as usize + count {
+                    assert!(bitmap.dirty_at(offset));
+                }
+                Ok(count)
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_rx_zero_copy_rate_limited() {
+        let mut th = TestHelper::default();
+        th.activate_net();
+
+        // create bandwidth rate limiter that allows 40960 bytes/s with bucket size 4096 bytes
+        let mut rl = RateLimiter::new(0x1000, 0, 100, 0, 0, 0).unwrap();
+        // use up the budget
+        assert!(rl.consume(0x1000, TokenType::Bytes));
+        th.net().queue_pairs[0].rx_rate_limiter = rl;
+
+        let desc_len = MAX_BUFFER_SIZE as u32;
+        th.add_desc_chain(NetQueue::Rx, 0, &[(0, desc_len, VIRTQ_DESC_F_WRITE)]);
+        th.simulate_event(NetEvent::Tap);
+        // The frame waits for the rate limiter in the descriptor chain it was read into.
+        assert!(th.net().queue_pairs[0].rx_rate_limiter.is_blocked());
+        assert!(th.net().queue_pairs[0].rx_deferred_frame);
+        assert_eq!(th.net().queue_pairs[0].rx_pending_head, Some(0));
+        assert_eq!(th.rxq.used.idx.get(), 0);
+
+        // The descriptor chain is handed back to the avail ring when saving the device.
+        th.net().prepare_save();
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
+        assert!(th.net().queue_pairs[0].rx_pending_head.is_none());
+        assert_eq!(th.net().queues[RX_INDEX].next_avail.0, 0);
+        th.net().process_rx(0).unwrap();
+        assert_eq!(th.net().queue_pairs[0].rx_pending_head, Some(0));
+
+        // wait for 100ms to give the rate-limiter timer a chance to replenish
+        // wait for an extra 100ms to make sure the timerfd event makes its way from the kernel
+        thread::sleep(Duration::from_millis(200));
+
+        let frame = th.net().mocks.read_tap.mock_frame();
+        check_metric_after_block!(
+            METRICS.net.rx_packets_count,
+            1,
+            th.simulate_event(NetEvent::RxRateLimiter)
+        );
+        assert!(th.net().queue_pairs[0].rx_pending_head.is_none());
+        assert_eq!(th.rxq.used.idx.get(), 1);
+        th.rxq.check_used_elem(0, 0, frame.len() as u32);
+        th.rxq.dtable[0].check_data(&frame);
+    }
+
     #[test]
     fn test_tx_missing_queue_signal() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 4096, 0)]);
         th.net().queue_evts[TX_INDEX].read().unwrap();
@@ -1260,7 +2635,8 @@ pub mod tests {
     fn test_tx_writeable_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         let desc_list = [(0, 100, 0), (1, 100, VIRTQ_DESC_F_WRITE), (2, 500, 0)];
         th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
@@ -1279,7 +2655,8 @@ pub mod tests {
     fn test_tx_short_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         // Send an invalid frame (too small, VNET header missing).
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 1, 0)]);
@@ -1301,7 +2678,8 @@ pub mod tests {
     fn test_tx_partial_read() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         // The descriptor chain is created so that the last descriptor doesn't fit in the
         // guest memory.
@@ -1329,7 +2707,8 @@ pub mod tests {
     fn test_tx_retry() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         // Add invalid descriptor chain - writeable descriptor.
         th.add_desc_chain(
@@ -1369,7 +2748,8 @@ pub mod tests {
     fn test_tx_complex_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         // Add gaps between the descriptor ids in order to ensure that we follow
         // the `next` field.
@@ -1397,7 +2777,8 @@ pub mod tests {
     fn test_tx_multiple_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
 
         // Write the first frame to the Tx queue
         let desc_list = [(0, 50, 0), (1, 100, 0), (2, 150, 0)];
@@ -1461,6 +2842,21 @@ pub mod tests {
         (frame_buf, frame_len)
     }
 
+    // Writes `frame` to guest memory, split in two buffers, and returns the iovec pointing to it.
+    fn write_frame_to_mem(mem: &GuestMemoryMmap, frame: &[u8]) -> IoVecBuffer {
+        let split = frame.len() / 2;
+        mem.write_slice(&frame[..split], GuestAddress(0)).unwrap();
+        mem.write_slice(&frame[split..], GuestAddress(0x1000))
+            .unwrap();
+
+        let mut iovec = IoVecBuffer::with_capacity(2);
+        iovec.push(mem, GuestAddress(0), split).unwrap();
+        iovec
+            .push(mem, GuestAddress(0x1000), frame.len() - split)
+            .unwrap();
+        iovec
+    }
+
     #[test]
     fn test_mmds_detour_and_injection() {
         let mut net = default_net();
@@ -1471,6 +2867,9 @@ pub mod tests {
         let dst_ip = Ipv4Addr::new(169, 254, 169, 254);
 
         let (frame_buf, frame_len) = create_arp_request(src_mac, src_ip, dst_mac, dst_ip);
+        let mem = default_guest_memory();
+        let frame_iovec = write_frame_to_mem(&mem, &frame_buf[..frame_len]);
+        let qp = &mut net.queue_pairs[0];
 
         // Call the code which sends the packet to the host or MMDS.
         // Validate the frame was consumed by MMDS and that the metrics reflect that.
@@ -1479,10 +2878,12 @@ pub mod tests {
             1,
             assert!(Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
-                &frame_buf[..frame_len],
-                &mut net.tap,
+                &mut qp.tx_rate_limiter,
+                &frame_iovec,
+                &mut qp.tx_frame_buf,
+                &qp.tap,
                 src_mac,
+                &qp.metrics,
             )
             .unwrap())
         );
@@ -1491,7 +2892,7 @@ pub mod tests {
         check_metric_after_block!(
             &METRICS.mmds.tx_frames,
             1,
-            net.read_from_mmds_or_tap().unwrap()
+            net.read_from_mmds_or_tap(0).unwrap()
         );
     }
 
@@ -1506,6 +2907,9 @@ pub mod tests {
         let dst_ip = Ipv4Addr::new(10, 1, 1, 1);
 
         let (frame_buf, frame_len) = create_arp_request(guest_mac, guest_ip, dst_mac, dst_ip);
+        let mem = default_guest_memory();
+        let frame_iovec = write_frame_to_mem(&mem, &frame_buf[..frame_len]);
+        let qp = &mut net.queue_pairs[0];
 
         // Check that a legit MAC doesn't affect the spoofed MAC metric.
         check_metric_after_block!(
@@ -1513,10 +2917,12 @@ pub mod tests {
             0,
             Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
-                &frame_buf[..frame_len],
-                &mut net.tap,
+                &mut qp.tx_rate_limiter,
+                &frame_iovec,
+                &mut qp.tx_frame_buf,
+                &qp.tap,
                 guest_mac,
+                &qp.metrics,
             )
         );
 
@@ -1526,14 +2932,164 @@ pub mod tests {
             1,
             Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
-                &frame_buf[..frame_len],
-                &mut net.tap,
+                &mut qp.tx_rate_limiter,
+                &frame_iovec,
+                &mut qp.tx_frame_buf,
+                &qp.tap,
                 not_guest_mac,
+                &qp.metrics,
             )
         );
     }
 
+    #[test]
+    fn test_anti_spoofing() {
+        let mut th = TestHelper::default();
+        th.activate_net();
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(&th.net().queue_pairs[0].tap));
+        // The test frames have all their header fields zeroed.
+        let zero_mac = MacAddr::from_bytes_unchecked(&[0; MAC_ADDR_LEN]);
+        let desc_list = [(0, 1000, 0)];
+
+        // Invalid prefixes are rejected.
+        let invalid_policy = AntiSpoofing {
+            ipv4_prefixes: vec![Ipv4Prefix {
+                address: Ipv4Addr::new(10, 0, 0, 0),
+                prefix_len: 40,
+            }],
+            ..Default::default()
+        };
+        assert!(matches!(
+            th.net().set_anti_spoofing(Some(invalid_policy)),
+            Err(Error::InvalidAntiSpoofing(_))
+        ));
+        assert!(th.net().anti_spoofing().is_none());
+
+        // Only the guest MAC address is allowed by default.
+        th.net()
+            .set_anti_spoofing(Some(AntiSpoofing::default()))
+            .unwrap();
+        th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
+        th.write_tx_frame(&desc_list, 1000);
+        check_metric_after_block!(
+            METRICS.net.tx_spoofing_mac_drops,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        assert_eq!(th.txq.used.idx.get(), 1);
+        assert!(!tap_traffic_simulator.pop_rx_packet(&mut [0; 1000]));
+
+        // Ethertypes are checked once the MAC address is allowed.
+        th.net()
+            .set_anti_spoofing(Some(AntiSpoofing {
+                allowed_macs: vec![zero_mac],
+                ethertypes: vec![ETHERTYPE_ARP],
+                ..Default::default()
+            }))
+            .unwrap();
+        th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
+        th.write_tx_frame(&desc_list, 1000);
+        check_metric_after_block!(
+            METRICS.net.tx_spoofing_ethertype_drops,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        assert_eq!(th.txq.used.idx.get(), 2);
+        assert!(!tap_traffic_simulator.pop_rx_packet(&mut [0; 1000]));
+
+        // Frames following the policy are sent to the tap.
+        th.net()
+            .set_anti_spoofing(Some(AntiSpoofing {
+                allowed_macs: vec![zero_mac],
+                ..Default::default()
+            }))
+            .unwrap();
+        th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
+        let frame = th.write_tx_frame(&desc_list, 1000);
+        th.event_manager.run_with_timeout(100).unwrap();
+        assert_eq!(th.txq.used.idx.get(), 3);
+        let mut buf = vec![0; 1000];
+        assert!(tap_traffic_simulator.pop_rx_packet(&mut buf[vnet_hdr_len()..]));
+        assert_eq!(&buf, &frame);
+    }
+
+    #[test]
+    fn test_packet_capture() {
+        let mut th = TestHelper::default();
+        th.activate_net();
+        th.net().mocks.set_read_tap(ReadTapMock::TapFrame);
+        let file = TempFile::new().unwrap();
+        th.net().start_capture(file.as_path(), 1 << 20).unwrap();
+        assert!(th.net().is_capturing());
+
+        // The frames sent by the guest are captured.
+        let desc_list = [(0, 1000, 0)];
+        th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
+        let tx_frame = th.write_tx_frame(&desc_list, 1000);
+        check_metric_after_block!(
+            METRICS.net.capture_frames_count,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+
+        // So are the frames received by the guest.
+        th.add_desc_chain(NetQueue::Rx, 0x2000, &[(0, 1000, VIRTQ_DESC_F_WRITE)]);
+        let rx_frame = inject_tap_tx_frame(&th.net(), 1000);
+        check_metric_after_block!(
+            METRICS.net.capture_frames_count,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        assert_eq!(th.rxq.used.idx.get(), 1);
+
+        // Frames throttled by the rate limiter are captured once they get through it.
+        th.net().queue_pairs[0].tx_rate_limiter = RateLimiter::new(0, 0, 0, 1, 0, 100).unwrap();
+        assert!(th.net().queue_pairs[0]
+            .tx_rate_limiter
+            .consume(1, TokenType::Ops));
+        th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
+        th.write_tx_frame(&desc_list, 1000);
+        check_metric_after_block!(
+            METRICS.net.capture_frames_count,
+            0,
+            th.simulate_event(NetEvent::TxQueue)
+        );
+        assert_eq!(th.txq.used.idx.get(), 1);
+
+        let bytes = std::fs::read(file.as_path()).unwrap();
+        let mut offset = PCAP_HEADER_LEN as usize;
+        for frame in &[tx_frame, rx_frame] {
+            offset += PCAP_RECORD_HEADER_LEN as usize;
+            let frame = &frame[vnet_hdr_len()..];
+            assert_eq!(&bytes[offset..offset + frame.len()], frame);
+            offset += frame.len();
+        }
+        assert_eq!(bytes.len(), offset);
+
+        // The capture stops once the file is full.
+        th.net()
+            .start_capture(file.as_path(), PCAP_HEADER_LEN)
+            .unwrap();
+        th.add_desc_chain(NetQueue::Rx, 0x2000, &[(1, 1000, VIRTQ_DESC_F_WRITE)]);
+        inject_tap_tx_frame(&th.net(), 1000);
+        check_metric_after_block!(
+            METRICS.net.capture_frames_count,
+            0,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        assert!(!th.net().is_capturing());
+        assert_eq!(th.rxq.used.idx.get(), 2);
+
+        // Files which can't be created are reported.
+        assert!(matches!(
+            th.net().start_capture("/nonexistent/capture.pcap", 1 << 20),
+            Err(Error::PacketCapture(_))
+        ));
+        th.net().stop_capture();
+        assert!(!th.net().is_capturing());
+    }
+
     #[test]
     fn test_process_error_cases() {
         let mut th = TestHelper::default();
@@ -1566,7 +3122,7 @@ pub mod tests {
         th.net().mocks.set_read_tap(ReadTapMock::Failure);
 
         // The RX queue is empty and rx_deffered_frame is set.
-        th.net().rx_deferred_frame = true;
+        th.net().queue_pairs[0].rx_deferred_frame = true;
         check_metric_after_block!(
             &METRICS.net.no_rx_avail_buffer,
             1,
@@ -1576,7 +3132,7 @@ pub mod tests {
         // We need to set this here to false, otherwise the device will try to
         // handle a deferred frame, it will fail and will never try to read from
         // the tap.
-        th.net().rx_deferred_frame = false;
+        th.net().queue_pairs[0].rx_deferred_frame = false;
 
         // Fake an avail buffer; this time, tap reading should error out.
         th.rxq.avail.idx.set(1);
@@ -1604,7 +3160,7 @@ pub mod tests {
         );
         // The frame we read from the tap should be deferred now and
         // no frames should have been transmitted
-        assert!(th.net().rx_deferred_frame);
+        assert!(th.net().queue_pairs[0].rx_deferred_frame);
         assert_eq!(METRICS.net.rx_packets_count.count(), rx_packets_count);
 
         // Let's add a second frame, which should really have the same
@@ -1621,7 +3177,7 @@ pub mod tests {
             th.simulate_event(NetEvent::Tap)
         );
         // We should still have a deferred frame
-        assert!(th.net().rx_deferred_frame);
+        assert!(th.net().queue_pairs[0].rx_deferred_frame);
         // However, we should have delivered the first frame
         assert_eq!(METRICS.net.rx_packets_count.count(), rx_packets_count + 1);
 
@@ -1634,7 +3190,7 @@ pub mod tests {
         );
 
         // We should be done with any deferred frame
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
     }
 
     #[test]
@@ -1642,7 +3198,7 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().rx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
+        th.net().queue_pairs[0].rx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
         // There is no actual event on the rate limiter's timerfd.
         check_metric_after_block!(
             &METRICS.net.event_fails,
@@ -1656,7 +3212,7 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().tx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
+        th.net().queue_pairs[0].tx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
         th.simulate_event(NetEvent::TxRateLimiter);
         // There is no actual event on the rate limiter's timerfd.
         check_metric_after_block!(
@@ -1679,7 +3235,7 @@ pub mod tests {
             assert!(rl.consume(0x1000, TokenType::Bytes));
 
             // set this tx rate limiter to be used
-            th.net().tx_rate_limiter = rl;
+            th.net().queue_pairs[0].tx_rate_limiter = rl;
 
             // try doing TX
             // following TX procedure should fail because of bandwidth rate limiting
@@ -1689,7 +3245,7 @@ pub mod tests {
                 th.simulate_event(NetEvent::TxQueue);
 
                 // assert that limiter is blocked
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 assert_eq!(METRICS.net.tx_rate_limiter_throttled.count(), 1);
                 // make sure the data is still queued for processing
                 assert_eq!(th.txq.used.idx.get(), 0);
@@ -1718,7 +3274,7 @@ pub mod tests {
                 );
                 // This should be still blocked. We managed to send the first frame, but
                 // not enough budget for the second
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data queue advanced
                 assert_eq!(th.txq.used.idx.get(), 1);
             }
@@ -1734,7 +3290,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::TxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().tx_rate_limiter.is_blocked());
+                assert!(!th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data queue advance one more place
                 assert_eq!(th.txq.used.idx.get(), 2);
             }
@@ -1748,10 +3304,10 @@ pub mod tests {
             assert!(rl.consume(0x1000, TokenType::Bytes));
 
             // set this rx rate limiter to be used
-            th.net().rx_rate_limiter = rl;
+            th.net().queue_pairs[0].rx_rate_limiter = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().queue_pairs[0].rx_deferred_frame);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of bandwidth rate limiting
@@ -1760,9 +3316,9 @@ pub mod tests {
                 th.simulate_event(NetEvent::Tap);
 
                 // assert that limiter is blocked
-                assert!(th.net().rx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].rx_rate_limiter.is_blocked());
                 assert_eq!(METRICS.net.rx_rate_limiter_throttled.count(), 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().queue_pairs[0].rx_deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data is still queued for processing
@@ -1791,7 +3347,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::RxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().rx_rate_limiter.is_blocked());
+                assert!(!th.net().queue_pairs[0].rx_rate_limiter.is_blocked());
                 // make sure the virtio queue operation completed this time
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data queue advanced
@@ -1815,7 +3371,7 @@ pub mod tests {
             assert!(rl.consume(1, TokenType::Ops));
 
             // set this tx rate limiter to be used
-            th.net().tx_rate_limiter = rl;
+            th.net().queue_pairs[0].tx_rate_limiter = rl;
 
             // try doing TX
             // following TX procedure should fail because of ops rate limiting
@@ -1829,7 +3385,7 @@ pub mod tests {
                 );
 
                 // assert that limiter is blocked
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data is still queued for processing
                 assert_eq!(th.txq.used.idx.get(), 0);
             }
@@ -1847,7 +3403,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::TxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().tx_rate_limiter.is_blocked());
+                assert!(!th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data queue advanced
                 assert_eq!(th.txq.used.idx.get(), 1);
             }
@@ -1861,10 +3417,10 @@ pub mod tests {
             assert!(rl.consume(1, TokenType::Ops));
 
             // set this rx rate limiter to be used
-            th.net().rx_rate_limiter = rl;
+            th.net().queue_pairs[0].rx_rate_limiter = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().queue_pairs[0].rx_deferred_frame);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of ops rate limiting
@@ -1877,9 +3433,9 @@ pub mod tests {
                 );
 
                 // assert that limiter is blocked
-                assert!(th.net().rx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].rx_rate_limiter.is_blocked());
                 assert!(METRICS.net.rx_rate_limiter_throttled.count() >= 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().queue_pairs[0].rx_deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data is still queued for processing
@@ -1916,40 +3472,250 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().rx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
-        th.net().tx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
+        th.net().queue_pairs[0].rx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
+        th.net().queue_pairs[0].tx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
 
         let rx_bytes = TokenBucket::new(1000, 1001, 1002).unwrap();
         let rx_ops = TokenBucket::new(1003, 1004, 1005).unwrap();
         let tx_bytes = TokenBucket::new(1006, 1007, 1008).unwrap();
         let tx_ops = TokenBucket::new(1009, 1010, 1011).unwrap();
 
-        th.net().patch_rate_limiters(
-            BucketUpdate::Update(rx_bytes.clone()),
-            BucketUpdate::Update(rx_ops.clone()),
-            BucketUpdate::Update(tx_bytes.clone()),
-            BucketUpdate::Update(tx_ops.clone()),
-        );
+        th.net()
+            .patch_rate_limiters(
+                BucketUpdate::Update(rx_bytes.clone()),
+                BucketUpdate::Update(rx_ops.clone()),
+                BucketUpdate::Update(tx_bytes.clone()),
+                BucketUpdate::Update(tx_ops.clone()),
+            )
+            .unwrap();
         let compare_buckets = |a: &TokenBucket, b: &TokenBucket| {
             assert_eq!(a.capacity(), b.capacity());
             assert_eq!(a.one_time_burst(), b.one_time_burst());
             assert_eq!(a.refill_time_ms(), b.refill_time_ms());
         };
-        compare_buckets(th.net().rx_rate_limiter.bandwidth().unwrap(), &rx_bytes);
-        compare_buckets(th.net().rx_rate_limiter.ops().unwrap(), &rx_ops);
-        compare_buckets(th.net().tx_rate_limiter.bandwidth().unwrap(), &tx_bytes);
-        compare_buckets(th.net().tx_rate_limiter.ops().unwrap(), &tx_ops);
+        compare_buckets(
+            th.net().queue_pairs[0].rx_rate_limiter.bandwidth().unwrap(),
+            &rx_bytes,
+        );
+        compare_buckets(
+            th.net().queue_pairs[0].rx_rate_limiter.ops().unwrap(),
+            &rx_ops,
+        );
+        compare_buckets(
+            th.net().queue_pairs[0].tx_rate_limiter.bandwidth().unwrap(),
+            &tx_bytes,
+        );
+        compare_buckets(
+            th.net().queue_pairs[0].tx_rate_limiter.ops().unwrap(),
+            &tx_ops,
+        );
+
+        th.net()
+            .patch_rate_limiters(
+                BucketUpdate::Disabled,
+                BucketUpdate::Disabled,
+                BucketUpdate::Disabled,
+                BucketUpdate::Disabled,
+            )
+            .unwrap();
+        assert!(th.net().queue_pairs[0]
+            .rx_rate_limiter
+            .bandwidth()
+            .is_none());
+        assert!(th.net().queue_pairs[0].rx_rate_limiter.ops().is_none());
+        assert!(th.net().queue_pairs[0]
+            .tx_rate_limiter
+            .bandwidth()
+            .is_none());
+        assert!(th.net().queue_pairs[0].tx_rate_limiter.ops().is_none());
+    }
+
+    #[test]
+    fn test_multi_queue_config() {
+        for num_queue_pairs in [0, MAX_NUM_QUEUE_PAIRS + 1] {
+            assert!(matches!(
+                Net::new_with_tap(
+                    "net-mq-invalid".to_string(),
+                    "net-mq-invalid".to_string(),
+                    None,
+                    RateLimiter::default(),
+                    RateLimiter::default(),
+                    num_queue_pairs,
+                    Offloads::default(),
+                ),
+                Err(Error::InvalidNumQueuePairs(n)) if n == num_queue_pairs
+            ));
+        }
+
+        let mq_features: u64 = 1 << VIRTIO_NET_F_MQ;
+        let mut max_queue_pairs = [0u8; 2];
+
+        // A single queue pair is only followed by the control queue.
+        let net = default_net_no_mmds();
+        assert_eq!(net.queues().len(), NUM_QUEUES + 1);
+        assert_eq!(net.ctrl_queue_index(), Some(NUM_QUEUES));
+        assert_eq!(net.avail_features() & mq_features, 0);
+
+        let mut net = default_net_with_queue_pairs(4);
+        assert_eq!(net.num_queue_pairs(), 4);
+        assert_eq!(net.avail_features() & mq_features, mq_features);
+        // The number of queue pairs follows the MAC address and the link status.
+        net.read_config(8, &mut max_queue_pairs);
+        assert_eq!(u16::from_le_bytes(max_queue_pairs), 4);
+
+        // The RX/TX queue pairs are followed by the control queue.
+        assert_eq!(net.queues().len(), 9);
+        assert_eq!(net.queue_events().len(), 9);
+        assert_eq!((rx_queue_index(3), tx_queue_index(3)), (6, 7));
+        assert_eq!(net.ctrl_queue_index(), Some(8));
+        assert!(Arc::ptr_eq(
+            &net.queue_pairs[3].metrics,
+            &METRICS.net_queues.get(net.id(), 3).unwrap()
+        ));
+
+        // Only the first queue pair is active until the driver enables the others.
+        assert_eq!(net.active_queue_pairs, 1);
+        net.set_active_queue_pairs(4).unwrap();
+        net.set_active_queue_pairs(2).unwrap();
+        assert_eq!(net.active_queue_pairs, 2);
+        assert!(matches!(
+            net.set_active_queue_pairs(5),
+            Err(Error::InvalidNumQueuePairs(5))
+        ));
+        assert_eq!(net.active_queue_pairs, 2);
+
+        // The rate limiters of all the queue pairs are updated.
+        net.patch_rate_limiters(
+            BucketUpdate::None,
+            BucketUpdate::Update(TokenBucket::new(10, 0, 100).unwrap()),
+            BucketUpdate::None,
+            BucketUpdate::None,
+        )
+        .unwrap();
+        for queue_pair in &net.queue_pairs {
+            assert_eq!(queue_pair.rx_rate_limiter.ops().unwrap().capacity(), 10);
+            assert!(queue_pair.tx_rate_limiter.ops().is_none());
+        }
+    }
+
+    #[test]
+    fn test_multi_queue_rate_limiters() {
+        let net = Net::new_with_tap(
+            "net-mq-rl".to_string(),
+            "net-mq-rl".to_string(),
+            None,
+            RateLimiter::new(0, 0, 0, 10, 0, 100).unwrap(),
+            RateLimiter::default(),
+            2,
+            Offloads::default(),
+        )
+        .unwrap();
+
+        // Each queue pair gets its own rate limiter, with the same configuration.
+        for queue_pair in &net.queue_pairs {
+            assert_eq!(queue_pair.rx_rate_limiter.ops().unwrap().capacity(), 10);
+            assert!(queue_pair.tx_rate_limiter.ops().is_none());
+        }
+        assert_ne!(
+            net.queue_pairs[0].rx_rate_limiter.as_raw_fd(),
+            net.queue_pairs[1].rx_rate_limiter.as_raw_fd()
+        );
+    }
+
+    #[test]
+    fn test_multi_queue_tx() {
+        let mut net = default_net_with_queue_pairs(2);
+        let mem = default_guest_memory();
+        let txq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        net.queues[tx_queue_index(1)] = txq.create_queue();
+        net.activate(mem.clone()).unwrap();
+        // The driver enables the second queue pair before using it.
+        net.set_active_queue_pairs(2).unwrap();
+
+        let mut frame = vec![0xaa; vnet_hdr_len() + 100];
+        init_vnet_hdr(&mut frame);
+        mem.write_slice(&frame, GuestAddress(0x2000)).unwrap();
+        txq.dtable[0].set(0x2000, frame.len() as u32, 0, 0);
+        txq.avail.ring[0].set(0);
+        txq.avail.idx.set(1);
+
+        let metrics = net.queue_pairs[1].metrics.clone();
+        net.queue_evts[tx_queue_index(1)].write(1).unwrap();
+        check_metric_after_block!(metrics.tx_packets_count, 1, net.process_tx_queue_event(1));
+
+        // The frame was sent through the second queue pair only.
+        txq.check_used_elem(0, 0, 0);
+        assert_eq!(metrics.tx_bytes_count.count(), frame.len());
+        assert_eq!(net.queue_pairs[0].metrics.tx_packets_count.count(), 0);
+        assert!(net.irq_trigger.has_pending_irq(IrqType::Vring));
+    }
+
+    #[test]
+    fn test_ctrl_queue() {
+        let mut net = default_net_with_queue_pairs(4);
+        let mem = default_guest_memory();
+        let ctrlq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let ctrl_index = net.ctrl_queue_index().unwrap();
+        net.queues[ctrl_index] = ctrlq.create_queue();
+        net.activate(mem.clone()).unwrap();
+
+        // Sends a control command to the device and returns its ack.
+        let mut used_index = 0;
+        let mut send_command = |net: &mut Net, request: &[u8]| {
+            let (request_addr, ack_addr) = (0x2000, 0x3000);
+            mem.write_slice(request, GuestAddress(request_addr))
+                .unwrap();
+            ctrlq.dtable[0].set(request_addr, request.len() as u32, VIRTQ_DESC_F_NEXT, 1);
+            ctrlq.dtable[1].set(ack_addr, 1, VIRTQ_DESC_F_WRITE, 0);
+            ctrlq.avail.ring[used_index as usize].set(0);
+            ctrlq.avail.idx.set(used_index + 1);
+
+            net.queue_evts[ctrl_index].write(1).unwrap();
+            net.process_ctrl_queue_event();
+
+            // The ack is a single byte.
+            ctrlq.check_used_elem(used_index, 0, 1);
+            used_index += 1;
+            u32::from(mem.read_obj::<u8>(GuestAddress(ack_addr)).unwrap())
+        };
+        let set_queue_pairs = |pairs: u16| {
+            let pairs = pairs.to_le_bytes();
+            [
+                VIRTIO_NET_CTRL_MQ as u8,
+                VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8,
+                pairs[0],
+                pairs[1],
+            ]
+        };
+
+        assert_eq!(send_command(&mut net, &set_queue_pairs(4)), VIRTIO_NET_OK);
+        assert_eq!(net.active_queue_pairs, 4);
+        assert!(net.irq_trigger.has_pending_irq(IrqType::Vring));
 
-        th.net().patch_rate_limiters(
-            BucketUpdate::Disabled,
-            BucketUpdate::Disabled,
-            BucketUpdate::Disabled,
-            BucketUpdate::Disabled,
+        // The device doesn't have that many queue pairs.
+        assert_eq!(send_command(&mut net, &set_queue_pairs(5)), VIRTIO_NET_ERR);
+        assert_eq!(send_command(&mut net, &set_queue_pairs(0)), VIRTIO_NET_ERR);
+        assert_eq!(net.active_queue_pairs, 4);
+
+        assert_eq!(send_command(&mut net, &set_queue_pairs(2)), VIRTIO_NET_OK);
+        assert_eq!(net.active_queue_pairs, 2);
+
+        // Truncated and unsupported commands are rejected.
+        assert_eq!(
+            send_command(&mut net, &set_queue_pairs(4)[..3]),
+            VIRTIO_NET_ERR
+        );
+        assert_eq!(
+            send_command(
+                &mut net,
+                &[
+                    VIRTIO_NET_CTRL_MQ as u8,
+                    VIRTIO_NET_CTRL_MQ_RSS_CONFIG as u8
+                ]
+            ),
+            VIRTIO_NET_ERR
         );
-        assert!(th.net().rx_rate_limiter.bandwidth().is_none());
-        assert!(th.net().rx_rate_limiter.ops().is_none());
-        assert!(th.net().tx_rate_limiter.bandwidth().is_none());
-        assert!(th.net().tx_rate_limiter.ops().is_none());
+        assert_eq!(net.active_queue_pairs, 2);
     }
 
     #[test]
@@ -1984,4 +3750,137 @@ pub mod tests {
         assert!(queues[RX_INDEX].uses_notif_suppression);
         assert!(queues[TX_INDEX].uses_notif_suppression);
     }
+
+    #[test]
+    fn test_vhost_net() {
+        let mut th = TestHelper::default();
+        th.net().disable_mmds_network_stack();
+        // vhost-net is not available on all the hosts running the unit tests.
+        if !Path::new("/dev/vhost-net").exists() {
+            assert!(matches!(
+                th.net().enable_vhost_net(),
+                Err(Error::VhostNet(_))
+            ));
+            return;
+        }
+
+        th.net().enable_vhost_net().unwrap();
+        th.activate_net();
+        assert!(th.net().vhost_net());
+        assert_eq!(
+            th.net().vhost.as_ref().unwrap().state,
+            BackendState::Running
+        );
+        assert_eq!(th.net().vhost.as_ref().unwrap().started_pairs, [true]);
+
+        // The frames don't go through the rate limiters.
+        assert!(matches!(
+            th.net().patch_rate_limiters(
+                BucketUpdate::Update(TokenBucket::new(10, 0, 100).unwrap()),
+                BucketUpdate::None,
+                BucketUpdate::None,
+                BucketUpdate::None,
+            ),
+            Err(Error::VhostNetRateLimiter)
+        ));
+        // Nor through the packet capture.
+        assert!(matches!(
+            th.net().start_capture("/tmp/capture.pcap", 1 << 20),
+            Err(Error::VhostNetPacketCapture)
+        ));
+
+        // The used buffer notifications of the kernel are forwarded to the guest.
+        th.net().vhost.as_ref().unwrap().call_evts[TX_INDEX]
+            .write(1)
+            .unwrap();
+        let ev_count = th.event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 1);
+        assert!(th.net().irq_trigger.has_pending_irq(IrqType::Vring));
+
+        // The kernel hands the vrings back when the device is saved, and takes them over again
+        // once the vm is resumed.
+        th.net().prepare_save();
+        assert_eq!(
+            th.net().vhost.as_ref().unwrap().state,
+            BackendState::Stopped
+        );
+        assert_eq!(th.net().queues[TX_INDEX].next_avail.0, 0);
+        assert_eq!(th.net().queues[TX_INDEX].next_used.0, 0);
+        th.net().process_virtio_queues();
+        assert_eq!(
+            th.net().vhost.as_ref().unwrap().state,
+            BackendState::Running
+        );
+    }
+
+    #[test]
+    fn test_vhost_net_queue_pairs() {
+        // vhost-net is not available on all the hosts running the unit tests.
+        if !Path::new("/dev/vhost-net").exists() {
+            return;
+        }
+
+        let mut net = default_net_with_queue_pairs(3);
+        net.disable_mmds_network_stack();
+        let mem = default_guest_memory();
+        let vqs: Vec<_> = (0..4)
+            .map(|index| VirtQueue::new(GuestAddress(index * 0x1000), &mem, 16))
+            .collect();
+        // The driver didn't set up the queues of the third queue pair.
+        for (index, vq) in vqs.iter().enumerate() {
+            net.queues[index] = vq.create_queue();
+        }
+        net.enable_vhost_net().unwrap();
+        net.activate(mem).unwrap();
+        net.start_vhost_net();
+        assert!(net.vhost_net());
+
+        // Only the vrings of the active queue pairs are handed over to the kernel.
+        assert_eq!(
+            net.vhost.as_ref().unwrap().started_pairs,
+            [true, false, false]
+        );
+        net.set_active_queue_pairs(3).unwrap();
+        assert_eq!(
+            net.vhost.as_ref().unwrap().started_pairs,
+            [true, true, false]
+        );
+
+        // The kernel hands the vrings of the disabled queue pairs back.
+        net.queues[tx_queue_index(1)].next_avail.0 = 1;
+        net.set_active_queue_pairs(1).unwrap();
+        assert_eq!(
+            net.vhost.as_ref().unwrap().started_pairs,
+            [true, false, false]
+        );
+        assert_eq!(net.queues[tx_queue_index(1)].next_avail.0, 0);
+    }
+
+    #[test]
+    fn test_vhost_net_fallback() {
+        // vhost-net is not available on all the hosts running the unit tests.
+        if !Path::new("/dev/vhost-net").exists() {
+            return;
+        }
+
+        // The MMDS needs to see the frames sent by the guest.
+        let mut th = TestHelper::default();
+        th.net().enable_vhost_net().unwrap();
+        check_metric_after_block!(METRICS.net.vhost_net_fallbacks, 1, th.activate_net());
+        assert!(!th.net().vhost_net());
+
+        // So do the rate limiters.
+        let mut th = TestHelper::default();
+        th.net().disable_mmds_network_stack();
+        th.net().queue_pairs[0].tx_rate_limiter = RateLimiter::new(0, 0, 0, 10, 0, 100).unwrap();
+        th.net().enable_vhost_net().unwrap();
+        check_metric_after_block!(METRICS.net.vhost_net_fallbacks, 1, th.activate_net());
+        assert!(!th.net().vhost_net());
+
+        // The queues are processed by Firecracker.
+        th.add_desc_chain(NetQueue::Tx, 0, &[(0, 4096, 0)]);
+        th.net().queue_evts[TX_INDEX].write(1).unwrap();
+        th.event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(th.txq.used.idx.get(), 1);
+    }
 }
@@ -1,48 +1,78 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use event_manager::{EventOps, Events, MutEventSubscriber};
 use logger::{debug, error, warn, IncMetric, METRICS};
 use utils::epoll::EventSet;
 
-use crate::virtio::net::device::Net;
-use crate::virtio::{VirtioDevice, RX_INDEX, TX_INDEX};
+use crate::virtio::net::device::{Net, QueuePair};
+use crate::virtio::net::NUM_QUEUES;
+use crate::virtio::{VirtioDevice, RX_INDEX};
 
 impl Net {
     fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(err) = ops.add(Events::new(&self.queue_evts[RX_INDEX], EventSet::IN)) {
-            error!("Failed to register rx queue event: {}", err);
-        }
-        if let Err(err) = ops.add(Events::new(&self.queue_evts[TX_INDEX], EventSet::IN)) {
-            error!("Failed to register tx queue event: {}", err);
-        }
-        if let Err(err) = ops.add(Events::new(&self.rx_rate_limiter, EventSet::IN)) {
-            error!("Failed to register rx queue event: {}", err);
+        if let Some(backend) = self.vhost.as_ref() {
+            // The rx and tx queue events are consumed by the kernel, only the control queue and
+            // the used buffer notifications go through the VMM.
+            if let Some(ctrl_index) = self.ctrl_queue_index() {
+                if let Err(err) = ops.add(Events::new(&self.queue_evts[ctrl_index], EventSet::IN)) {
+                    error!("Failed to register queue event: {}", err);
+                }
+            }
+            for call_evt in &backend.call_evts {
+                if let Err(err) = ops.add(Events::new(call_evt, EventSet::IN)) {
+                    error!("Failed to register call event: {}", err);
+                }
+            }
+            return;
         }
-        if let Err(err) = ops.add(Events::new(&self.tx_rate_limiter, EventSet::IN)) {
-            error!("Failed to register tx queue event: {}", err);
+
+        for queue_evt in &self.queue_evts {
+            if let Err(err) = ops.add(Events::new(queue_evt, EventSet::IN)) {
+                error!("Failed to register queue event: {}", err);
+            }
         }
-        if let Err(err) = ops.add(Events::new(
-            &self.tap,
-            EventSet::IN | EventSet::EDGE_TRIGGERED,
-        )) {
-            error!("Failed to register tap event: {}", err);
+        for queue_pair in &self.queue_pairs {
+            if let Err(err) = ops.add(Events::new(&queue_pair.rx_rate_limiter, EventSet::IN)) {
+                error!("Failed to register rx queue event: {}", err);
+            }
+            if let Err(err) = ops.add(Events::new(&queue_pair.tx_rate_limiter, EventSet::IN)) {
+                error!("Failed to register tx queue event: {}", err);
+            }
+            if let Err(err) = ops.add(Events::new(
+                &queue_pair.tap,
+                EventSet::IN | EventSet::EDGE_TRIGGERED,
+            )) {
+                error!("Failed to register tap event: {}", err);
+            }
         }
     }
 
+    // Returns the index of the queue pair owning the file descriptor, selected by `fd`, which
+    // matches `source`.
+    fn queue_pair_index<F>(&self, source: RawFd, fd: F) -> Option<usize>
+    where
+        F: Fn(&QueuePair) -> RawFd,
+    {
+        self.queue_pairs
+            .iter()
+            .position(|queue_pair| fd(queue_pair) == source)
+    }
+
     fn register_activate_event(&self, ops: &mut EventOps) {
         if let Err(err) = ops.add(Events::new(&self.activate_evt, EventSet::IN)) {
             error!("Failed to register activate event: {}", err);
         }
     }
 
-    fn process_activate_event(&self, ops: &mut EventOps) {
+    fn process_activate_event(&mut self, ops: &mut EventOps) {
         debug!("net: activate event");
         if let Err(err) = self.activate_evt.read() {
             error!("Failed to consume net activate event: {:?}", err);
         }
+        self.start_vhost_net();
         self.register_runtime_events(ops);
         if let Err(err) = ops.remove(Events::new(&self.activate_evt, EventSet::IN)) {
             error!("Failed to un-register activate event: {}", err);
@@ -67,21 +97,44 @@ impl MutEventSubscriber for Net {
         }
 
         if self.is_activated() {
-            let virtq_rx_ev_fd = self.queue_evts[RX_INDEX].as_raw_fd();
-            let virtq_tx_ev_fd = self.queue_evts[TX_INDEX].as_raw_fd();
-            let rx_rate_limiter_fd = self.rx_rate_limiter.as_raw_fd();
-            let tx_rate_limiter_fd = self.tx_rate_limiter.as_raw_fd();
-            let tap_fd = self.tap.as_raw_fd();
+            let maybe_queue_index = self
+                .queue_evts
+                .iter()
+                .position(|queue_evt| queue_evt.as_raw_fd() == source);
+            let maybe_tap_pair = self.queue_pair_index(source, |qp| qp.tap.as_raw_fd());
+            let maybe_rx_rate_limiter_pair =
+                self.queue_pair_index(source, |qp| qp.rx_rate_limiter.as_raw_fd());
+            let maybe_tx_rate_limiter_pair =
+                self.queue_pair_index(source, |qp| qp.tx_rate_limiter.as_raw_fd());
+            let maybe_call_index = self.vhost.as_ref().and_then(|backend| {
+                backend
+                    .call_evts
+                    .iter()
+                    .position(|call_evt| call_evt.as_raw_fd() == source)
+            });
+            let ctrl_queue_index = self.ctrl_queue_index();
             let activate_fd = self.activate_evt.as_raw_fd();
 
             // Looks better than C style if/else if/else.
-            match source {
-                _ if source == virtq_rx_ev_fd => self.process_rx_queue_event(),
-                _ if source == tap_fd => self.process_tap_rx_event(),
-                _ if source == virtq_tx_ev_fd => self.process_tx_queue_event(),
-                _ if source == rx_rate_limiter_fd => self.process_rx_rate_limiter_event(),
-                _ if source == tx_rate_limiter_fd => self.process_tx_rate_limiter_event(),
+            match (
+                maybe_queue_index,
+                maybe_tap_pair,
+                maybe_rx_rate_limiter_pair,
+                maybe_tx_rate_limiter_pair,
+                maybe_call_index,
+            ) {
                 _ if activate_fd == source => self.process_activate_event(ops),
+                (Some(index), ..) if Some(index) == ctrl_queue_index => {
+                    self.process_ctrl_queue_event()
+                }
+                (Some(index), ..) if index % NUM_QUEUES == RX_INDEX => {
+                    self.process_rx_queue_event(index / NUM_QUEUES)
+                }
+                (Some(index), ..) => self.process_tx_queue_event(index / NUM_QUEUES),
+                (_, Some(pair), ..) => self.process_tap_rx_event(pair),
+                (_, _, Some(pair), ..) => self.process_rx_rate_limiter_event(pair),
+                (_, _, _, Some(pair), _) => self.process_tx_rate_limiter_event(pair),
+                (.., Some(index)) => self.process_vhost_call_event(index),
                 _ => {
                     warn!("Net: Spurious event received: {:?}", source);
                     METRICS.net.event_fails.inc();
@@ -101,6 +154,7 @@ impl MutEventSubscriber for Net {
         //  - on device activation (is-activated already true at this point),
         //  - on device restore from snapshot.
         if self.is_activated() {
+            self.start_vhost_net();
             self.register_runtime_events(ops);
         } else {
             self.register_activate_event(ops);
@@ -0,0 +1,525 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Filters the frames sent by the guest, so that it can't spoof the addresses of other hosts.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::result;
+
+use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4, ETHERTYPE_IPV6};
+use dumbo::pdu::ipv4::{IPv4Packet, IPV4_VERSION};
+use dumbo::pdu::ipv6::{IPv6Packet, IPV6_HEADER_LEN, IPV6_VERSION};
+use serde::{Deserialize, Serialize};
+use utils::net::mac::MacAddr;
+
+use crate::virtio::net::{Error, Result};
+
+// The length of an IPv4 header without options.
+const IPV4_HEADER_LEN: usize = 20;
+
+// The ethertypes of 802.1Q and 802.1ad VLAN tags.
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+// The length of a VLAN tag: the tag control information, followed by the inner ethertype.
+const VLAN_TAG_LEN: usize = 4;
+// Frames with more VLAN tags are dropped.
+const MAX_VLAN_TAGS: usize = 2;
+
+/// The number of bytes of a frame, past the ethernet header, needed to check it against the
+/// policy. This covers the VLAN tags, followed by the largest of the ARP, IPv4 and IPv6
+/// headers.
+pub const MAX_CHECKED_PAYLOAD_LEN: usize = MAX_VLAN_TAGS * VLAN_TAG_LEN + IPV6_HEADER_LEN;
+
+/// An IPv4 network, given by an address and the length of its prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ipv4Prefix {
+    /// An address in the network.
+    pub address: Ipv4Addr,
+    /// The number of leading bits of the address identifying the network, up to 32.
+    pub prefix_len: u8,
+}
+
+impl Ipv4Prefix {
+    /// Returns whether `addr` is part of the network.
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix_len.min(32)))
+            .unwrap_or(0);
+        u32::from(addr) & mask == u32::from(self.address) & mask
+    }
+}
+
+/// An IPv6 network, given by an address and the length of its prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ipv6Prefix {
+    /// An address in the network.
+    pub address: Ipv6Addr,
+    /// The number of leading bits of the address identifying the network, up to 128.
+    pub prefix_len: u8,
+}
+
+impl Ipv6Prefix {
+    /// Returns whether `addr` is part of the network.
+    pub fn contains(&self, addr: Ipv6Addr) -> bool {
+        let mask = u128::MAX
+            .checked_shl(128 - u32::from(self.prefix_len.min(128)))
+            .unwrap_or(0);
+        u128::from(addr) & mask == u128::from(self.address) & mask
+    }
+}
+
+/// The source addresses and protocols the guest may use in the frames it sends.
+///
+/// The frames breaking the policy are dropped. The lists of prefixes and ethertypes don't
+/// restrict anything when empty.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AntiSpoofing {
+    /// Source MAC addresses the guest may use, besides the MAC address of the interface.
+    pub allowed_macs: Vec<MacAddr>,
+    /// The networks the IPv4 source addresses and ARP sender addresses must be part of.
+    pub ipv4_prefixes: Vec<Ipv4Prefix>,
+    /// The networks the IPv6 source addresses must be part of.
+    pub ipv6_prefixes: Vec<Ipv6Prefix>,
+    /// The ethertypes the guest may use.
+    pub ethertypes: Vec<u16>,
+}
+
+/// The part of a frame breaking the anti-spoofing policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The source MAC address, which also covers frames too short to have one.
+    SourceMac,
+    /// The ethertype.
+    Ethertype,
+    /// The source IP address, which also covers IP and ARP headers which can't be parsed.
+    SourceIp,
+}
+
+impl AntiSpoofing {
+    /// Checks that the prefix lengths are valid.
+    pub fn validate(&self) -> Result<()> {
+        let invalid_v4 = self
+            .ipv4_prefixes
+            .iter()
+            .any(|prefix| prefix.prefix_len > 32);
+        let invalid_v6 = self
+            .ipv6_prefixes
+            .iter()
+            .any(|prefix| prefix.prefix_len > 128);
+        if invalid_v4 || invalid_v6 {
+            return Err(Error::InvalidAntiSpoofing(self.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Checks an ethernet frame sent by the guest, which has the MAC address `guest_mac`.
+    ///
+    /// Only the ethernet header and the first `MAX_CHECKED_PAYLOAD_LEN` bytes of its payload are
+    /// looked at, so `frame` doesn't need to hold more than that. The VLAN tags of tagged frames
+    /// are skipped, and the ethertypes of the tags are held to the same rules as the inner one.
+    pub fn check(&self, guest_mac: MacAddr, frame: &[u8]) -> result::Result<(), Violation> {
+        let eth_frame = EthernetFrame::from_bytes(frame).map_err(|_| Violation::SourceMac)?;
+        if !self.allows_mac(guest_mac, eth_frame.src_mac()) {
+            return Err(Violation::SourceMac);
+        }
+
+        let mut ethertype = eth_frame.ethertype();
+        let mut payload = eth_frame.payload();
+        let mut nb_tags = 0;
+        loop {
+            if !self.ethertypes.is_empty() && !self.ethertypes.contains(&ethertype) {
+                return Err(Violation::Ethertype);
+            }
+            if ethertype != ETHERTYPE_VLAN && ethertype != ETHERTYPE_QINQ {
+                break;
+            }
+            // Frames whose inner ethertype can't be found are dropped.
+            if nb_tags == MAX_VLAN_TAGS || payload.len() < VLAN_TAG_LEN {
+                return Err(Violation::Ethertype);
+            }
+            ethertype = u16::from_be_bytes([payload[2], payload[3]]);
+            payload = &payload[VLAN_TAG_LEN..];
+            nb_tags += 1;
+        }
+
+        match ethertype {
+            ETHERTYPE_ARP => self.check_arp(guest_mac, payload),
+            ETHERTYPE_IPV4 if !self.ipv4_prefixes.is_empty() => {
+                if payload.len() < IPV4_HEADER_LEN {
+                    return Err(Violation::SourceIp);
+                }
+                let packet = IPv4Packet::from_bytes_unchecked(payload);
+                if packet.version_and_header_len().0 != IPV4_VERSION
+                    || !self.allows_ipv4(packet.source_address())
+                {
+                    return Err(Violation::SourceIp);
+                }
+                Ok(())
+            }
+            ETHERTYPE_IPV6 if !self.ipv6_prefixes.is_empty() => {
+                if payload.len() < IPV6_HEADER_LEN {
+                    return Err(Violation::SourceIp);
+                }
+                let packet = IPv6Packet::from_bytes_unchecked(payload);
+                if packet.version() != IPV6_VERSION
+                    || !self
+                        .ipv6_prefixes
+                        .iter()
+                        .any(|prefix| prefix.contains(packet.source_address()))
+                {
+                    return Err(Violation::SourceIp);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The sender addresses of ARP frames announce the addresses of the guest to its neighbours,
+    // so they are held to the same rules as the source addresses.
+    fn check_arp(&self, guest_mac: MacAddr, payload: &[u8]) -> result::Result<(), Violation> {
+        if payload.len() < ETH_IPV4_FRAME_LEN {
+            if self.ipv4_prefixes.is_empty() {
+                return Ok(());
+            }
+            return Err(Violation::SourceIp);
+        }
+
+        let arp_frame = EthIPv4ArpFrame::from_bytes_unchecked(payload);
+        if !self.allows_mac(guest_mac, arp_frame.sha()) {
+            return Err(Violation::SourceMac);
+        }
+        if !self.ipv4_prefixes.is_empty() && !self.allows_ipv4(arp_frame.spa()) {
+            return Err(Violation::SourceIp);
+        }
+        Ok(())
+    }
+
+    fn allows_mac(&self, guest_mac: MacAddr, mac: MacAddr) -> bool {
+        mac == guest_mac || self.allowed_macs.contains(&mac)
+    }
+
+    fn allows_ipv4(&self, addr: Ipv4Addr) -> bool {
+        self.ipv4_prefixes
+            .iter()
+            .any(|prefix| prefix.contains(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dumbo::pdu::ethernet::PAYLOAD_OFFSET;
+
+    use super::*;
+
+    const GUEST_MAC: &str = "12:34:56:78:9a:bc";
+
+    fn guest_mac() -> MacAddr {
+        MacAddr::parse_str(GUEST_MAC).unwrap()
+    }
+
+    fn frame(src_mac: MacAddr, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; PAYLOAD_OFFSET + payload.len()];
+        EthernetFrame::write_incomplete(
+            frame.as_mut_slice(),
+            MacAddr::from_bytes_unchecked(&[0xff; 6]),
+            src_mac,
+            ethertype,
+        )
+        .unwrap();
+        frame[PAYLOAD_OFFSET..].copy_from_slice(payload);
+        frame
+    }
+
+    fn ipv4_header(src: Ipv4Addr) -> Vec<u8> {
+        let mut header = vec![0u8; IPV4_HEADER_LEN];
+        header[0] = IPV4_VERSION << 4 | 5;
+        header[12..16].copy_from_slice(&src.octets());
+        header
+    }
+
+    fn ipv6_header(src: Ipv6Addr) -> Vec<u8> {
+        let mut header = vec![0u8; IPV6_HEADER_LEN];
+        header[0] = IPV6_VERSION << 4;
+        header[8..24].copy_from_slice(&src.octets());
+        header
+    }
+
+    fn arp_frame(sha: MacAddr, spa: Ipv4Addr) -> Vec<u8> {
+        let mut payload = vec![0u8; ETH_IPV4_FRAME_LEN];
+        EthIPv4ArpFrame::write_request(
+            payload.as_mut_slice(),
+            sha,
+            spa,
+            MacAddr::from_bytes_unchecked(&[0; 6]),
+            Ipv4Addr::new(10, 0, 0, 1),
+        )
+        .unwrap();
+        frame(sha, ETHERTYPE_ARP, &payload)
+    }
+
+    fn policy() -> AntiSpoofing {
+        AntiSpoofing {
+            allowed_macs: vec![MacAddr::parse_str("02:00:00:00:00:01").unwrap()],
+            ipv4_prefixes: vec![Ipv4Prefix {
+                address: Ipv4Addr::new(10, 0, 0, 0),
+                prefix_len: 24,
+            }],
+            ipv6_prefixes: vec![Ipv6Prefix {
+                address: "fd00::".parse().unwrap(),
+                prefix_len: 64,
+            }],
+            ethertypes: vec![ETHERTYPE_ARP, ETHERTYPE_IPV4, ETHERTYPE_IPV6],
+        }
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let prefix = Ipv4Prefix {
+            address: Ipv4Addr::new(10, 1, 2, 3),
+            prefix_len: 16,
+        };
+        assert!(prefix.contains(Ipv4Addr::new(10, 1, 200, 1)));
+        assert!(!prefix.contains(Ipv4Addr::new(10, 2, 0, 1)));
+        let any = Ipv4Prefix {
+            address: Ipv4Addr::UNSPECIFIED,
+            prefix_len: 0,
+        };
+        assert!(any.contains(Ipv4Addr::new(192, 168, 0, 1)));
+        let host = Ipv4Prefix {
+            address: Ipv4Addr::new(10, 1, 2, 3),
+            prefix_len: 32,
+        };
+        assert!(host.contains(Ipv4Addr::new(10, 1, 2, 3)));
+        assert!(!host.contains(Ipv4Addr::new(10, 1, 2, 4)));
+
+        let prefix = Ipv6Prefix {
+            address: "fe80::".parse().unwrap(),
+            prefix_len: 10,
+        };
+        assert!(prefix.contains("fe80::1234".parse().unwrap()));
+        assert!(prefix.contains("febf::1".parse().unwrap()));
+        assert!(!prefix.contains("fec0::1".parse().unwrap()));
+        let any = Ipv6Prefix {
+            address: Ipv6Addr::UNSPECIFIED,
+            prefix_len: 0,
+        };
+        assert!(any.contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(policy().validate().is_ok());
+
+        let mut invalid = policy();
+        invalid.ipv4_prefixes[0].prefix_len = 33;
+        assert!(matches!(
+            invalid.validate(),
+            Err(Error::InvalidAntiSpoofing(_))
+        ));
+
+        let mut invalid = policy();
+        invalid.ipv6_prefixes[0].prefix_len = 129;
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let policy = policy();
+        let guest_mac = guest_mac();
+        let allowed_mac = policy.allowed_macs[0];
+        let other_mac = MacAddr::parse_str("02:00:00:00:00:02").unwrap();
+        let guest_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let other_ip = Ipv4Addr::new(10, 0, 1, 2);
+
+        // Source MAC addresses.
+        let ipv4 = ipv4_header(guest_ip);
+        assert_eq!(
+            policy.check(guest_mac, &frame(guest_mac, ETHERTYPE_IPV4, &ipv4)),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(guest_mac, &frame(allowed_mac, ETHERTYPE_IPV4, &ipv4)),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(guest_mac, &frame(other_mac, ETHERTYPE_IPV4, &ipv4)),
+            Err(Violation::SourceMac)
+        );
+        assert_eq!(
+            policy.check(guest_mac, &[0u8; PAYLOAD_OFFSET - 1]),
+            Err(Violation::SourceMac)
+        );
+
+        // Ethertypes.
+        assert_eq!(
+            policy.check(guest_mac, &frame(guest_mac, 0x88cc, &[])),
+            Err(Violation::Ethertype)
+        );
+
+        // IPv4 source addresses.
+        assert_eq!(
+            policy.check(
+                guest_mac,
+                &frame(guest_mac, ETHERTYPE_IPV4, &ipv4_header(other_ip))
+            ),
+            Err(Violation::SourceIp)
+        );
+        assert_eq!(
+            policy.check(guest_mac, &frame(guest_mac, ETHERTYPE_IPV4, &ipv4[..10])),
+            Err(Violation::SourceIp)
+        );
+        let mut ipv6_in_ipv4 = ipv4.clone();
+        ipv6_in_ipv4[0] = IPV6_VERSION << 4;
+        assert_eq!(
+            policy.check(guest_mac, &frame(guest_mac, ETHERTYPE_IPV4, &ipv6_in_ipv4)),
+            Err(Violation::SourceIp)
+        );
+
+        // ARP sender addresses.
+        assert_eq!(
+            policy.check(guest_mac, &arp_frame(guest_mac, guest_ip)),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(guest_mac, &arp_frame(guest_mac, other_ip)),
+            Err(Violation::SourceIp)
+        );
+        let mut spoofed_arp = arp_frame(other_mac, guest_ip);
+        spoofed_arp[6..12].copy_from_slice(guest_mac.get_bytes());
+        assert_eq!(
+            policy.check(guest_mac, &spoofed_arp),
+            Err(Violation::SourceMac)
+        );
+
+        // IPv6 source addresses.
+        let ipv6 = ipv6_header("fd00::2".parse().unwrap());
+        assert_eq!(
+            policy.check(guest_mac, &frame(guest_mac, ETHERTYPE_IPV6, &ipv6)),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(
+                guest_mac,
+                &frame(
+                    guest_mac,
+                    ETHERTYPE_IPV6,
+                    &ipv6_header("fd01::2".parse().unwrap())
+                )
+            ),
+            Err(Violation::SourceIp)
+        );
+        assert_eq!(
+            policy.check(guest_mac, &frame(guest_mac, ETHERTYPE_IPV6, &ipv6[..20])),
+            Err(Violation::SourceIp)
+        );
+
+        // The VLAN tags are skipped.
+        let mut policy = policy;
+        policy.ethertypes.extend(&[ETHERTYPE_VLAN, ETHERTYPE_QINQ]);
+        let tagged = |ethertype: u16, payload: &[u8]| {
+            let mut tagged_payload = vec![0x00, 0x2a];
+            tagged_payload.extend_from_slice(&ethertype.to_be_bytes());
+            tagged_payload.extend_from_slice(payload);
+            tagged_payload
+        };
+        assert_eq!(
+            policy.check(
+                guest_mac,
+                &frame(guest_mac, ETHERTYPE_VLAN, &tagged(ETHERTYPE_IPV4, &ipv4))
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(
+                guest_mac,
+                &frame(
+                    guest_mac,
+                    ETHERTYPE_VLAN,
+                    &tagged(ETHERTYPE_IPV4, &ipv4_header(other_ip))
+                )
+            ),
+            Err(Violation::SourceIp)
+        );
+        let double_tagged = tagged(
+            ETHERTYPE_VLAN,
+            &tagged(ETHERTYPE_IPV4, &ipv4_header(other_ip)),
+        );
+        assert_eq!(
+            policy.check(guest_mac, &frame(guest_mac, ETHERTYPE_QINQ, &double_tagged)),
+            Err(Violation::SourceIp)
+        );
+        assert_eq!(
+            policy.check(
+                guest_mac,
+                &frame(guest_mac, ETHERTYPE_VLAN, &tagged(0x88cc, &[]))
+            ),
+            Err(Violation::Ethertype)
+        );
+        // So are frames with too many tags, or truncated ones.
+        let triple_tagged = tagged(ETHERTYPE_VLAN, &double_tagged);
+        assert_eq!(
+            policy.check(guest_mac, &frame(guest_mac, ETHERTYPE_QINQ, &triple_tagged)),
+            Err(Violation::Ethertype)
+        );
+        assert_eq!(
+            policy.check(guest_mac, &frame(guest_mac, ETHERTYPE_VLAN, &[0x00, 0x2a])),
+            Err(Violation::Ethertype)
+        );
+        // The ethertypes of the tags have to be allowed as well.
+        policy.ethertypes.truncate(3);
+        assert_eq!(
+            policy.check(
+                guest_mac,
+                &frame(guest_mac, ETHERTYPE_VLAN, &tagged(ETHERTYPE_IPV4, &ipv4))
+            ),
+            Err(Violation::Ethertype)
+        );
+
+        // Empty lists don't restrict anything, aside from the MAC addresses.
+        let policy = AntiSpoofing::default();
+        assert_eq!(
+            policy.check(
+                guest_mac,
+                &frame(guest_mac, ETHERTYPE_IPV4, &ipv4_header(other_ip))
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(guest_mac, &frame(guest_mac, 0x88cc, &[])),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(guest_mac, &arp_frame(guest_mac, other_ip)),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(guest_mac, &frame(allowed_mac, ETHERTYPE_IPV4, &ipv4)),
+            Err(Violation::SourceMac)
+        );
+        assert_eq!(
+            policy.check(guest_mac, &arp_frame(other_mac, guest_ip)),
+            Err(Violation::SourceMac)
+        );
+        // Tagged frames are checked even when the ethertypes aren't restricted.
+        assert_eq!(
+            policy.check(
+                guest_mac,
+                &frame(
+                    guest_mac,
+                    ETHERTYPE_VLAN,
+                    &tagged(
+                        ETHERTYPE_ARP,
+                        &arp_frame(other_mac, guest_ip)[PAYLOAD_OFFSET..]
+                    )
+                )
+            ),
+            Err(Violation::SourceMac)
+        );
+    }
+}
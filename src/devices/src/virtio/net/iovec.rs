@@ -0,0 +1,270 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Scatter-gather lists over guest memory, used to move frames between the tap and the guest
+//! without intermediate copies.
+
+use std::io::{Error as IoError, Result as IoResult};
+use std::os::unix::io::AsRawFd;
+use std::{cmp, ptr};
+
+use vm_memory::{mark_dirty_mem, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
+
+/// A list of buffers in guest memory, backing a single frame.
+pub struct IoVecBuffer {
+    vecs: Vec<libc::iovec>,
+    // The guest address of each buffer, used for dirty page tracking.
+    addrs: Vec<GuestAddress>,
+    len: usize,
+}
+
+// Safe because the buffers point into guest memory, which outlives the devices and is shared
+// with the guest anyway.
+unsafe impl Send for IoVecBuffer {}
+
+impl IoVecBuffer {
+    /// Creates an empty list which can hold `capacity` buffers without reallocating.
+    pub fn with_capacity(capacity: usize) -> IoVecBuffer {
+        IoVecBuffer {
+            vecs: Vec::with_capacity(capacity),
+            addrs: Vec::with_capacity(capacity),
+            len: 0,
+        }
+    }
+
+    /// Removes all the buffers from the list.
+    pub fn clear(&mut self) {
+        self.vecs.clear();
+        self.addrs.clear();
+        self.len = 0;
+    }
+
+    /// Appends the guest buffer of `len` bytes at `addr` to the list.
+    ///
+    /// The buffer has to be contiguous in host memory, i.e. it can't cross memory regions.
+    pub fn push(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        len: usize,
+    ) -> Result<(), GuestMemoryError> {
+        let slice = mem.get_slice(addr, len)?;
+        self.vecs.push(libc::iovec {
+            iov_base: slice.as_ptr() as *mut libc::c_void,
+            iov_len: len,
+        });
+        self.addrs.push(addr);
+        self.len += len;
+
+        Ok(())
+    }
+
+    /// Returns the total length of the buffers, in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the number of buffers in the list.
+    pub fn num_buffers(&self) -> usize {
+        self.vecs.len()
+    }
+
+    /// Returns whether the list holds no bytes at all.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copies the bytes at `offset` out of the buffers, until either `buf` is full or the end of
+    /// the buffers is reached. Returns the number of copied bytes.
+    pub fn read_at(&self, buf: &mut [u8], offset: usize) -> usize {
+        let mut copied = 0;
+        self.for_each_segment(offset, buf.len(), |seg_ptr, seg_len| {
+            // Safe because the segment is valid guest memory and the destination has at least
+            // `seg_len` bytes left.
+            unsafe {
+                ptr::copy_nonoverlapping(seg_ptr, buf[copied..].as_mut_ptr(), seg_len);
+            }
+            copied += seg_len;
+        });
+        copied
+    }
+
+    /// Copies `buf` into the buffers, starting at `offset`, until either all of `buf` is copied
+    /// or the end of the buffers is reached. Returns the number of copied bytes.
+    pub fn write_at(&self, buf: &[u8], offset: usize) -> usize {
+        let mut copied = 0;
+        self.for_each_segment(offset, buf.len(), |seg_ptr, seg_len| {
+            // Safe because the segment is valid guest memory and the source has at least
+            // `seg_len` bytes left.
+            unsafe {
+                ptr::copy_nonoverlapping(buf[copied..].as_ptr(), seg_ptr, seg_len);
+            }
+            copied += seg_len;
+        });
+        copied
+    }
+
+    /// Gathers the buffers and writes them to `fd` with a single `writev`.
+    pub fn writev<F: AsRawFd>(&self, fd: &F) -> IoResult<usize> {
+        // Safe because the iovecs describe valid guest memory and we check the return value.
+        let ret = unsafe {
+            libc::writev(
+                fd.as_raw_fd(),
+                self.vecs.as_ptr(),
+                self.vecs.len() as libc::c_int,
+            )
+        };
+        if ret < 0 {
+            return Err(IoError::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    /// Reads from `fd` into the buffers with a single `readv`.
+    pub fn readv<F: AsRawFd>(&self, fd: &F) -> IoResult<usize> {
+        // Safe because the iovecs describe valid guest memory and we check the return value.
+        let ret = unsafe {
+            libc::readv(
+                fd.as_raw_fd(),
+                self.vecs.as_ptr(),
+                self.vecs.len() as libc::c_int,
+            )
+        };
+        if ret < 0 {
+            return Err(IoError::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    /// Marks the guest pages backing the first `count` bytes of the buffers as dirty. This is
+    /// needed after writing to the buffers through their host addresses, e.g. with `readv`,
+    /// since such writes bypass the dirty page tracking of the guest memory.
+    pub fn mark_dirty(&self, mem: &GuestMemoryMmap, mut count: usize) {
+        for (iov, addr) in self.vecs.iter().zip(self.addrs.iter()) {
+            if count == 0 {
+                break;
+            }
+            let seg_len = cmp::min(iov.iov_len, count);
+            mark_dirty_mem(mem, *addr, seg_len);
+            count -= seg_len;
+        }
+    }
+
+    // Calls `f` with the host address and length of the parts of the buffers that make up the
+    // `count` bytes at `offset`.
+    fn for_each_segment<F: FnMut(*mut u8, usize)>(
+        &self,
+        mut offset: usize,
+        mut count: usize,
+        mut f: F,
+    ) {
+        for iov in &self.vecs {
+            if count == 0 {
+                break;
+            }
+            if offset >= iov.iov_len {
+                offset -= iov.iov_len;
+                continue;
+            }
+
+            let seg_len = cmp::min(iov.iov_len - offset, count);
+            // Safe because `offset` is within the buffer.
+            f(unsafe { (iov.iov_base as *mut u8).add(offset) }, seg_len);
+            count -= seg_len;
+            offset = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::io::FromRawFd;
+
+    use utils::tempfile::TempFile;
+    use vm_memory::Bytes;
+
+    use super::*;
+    use crate::virtio::net::test_utils::default_guest_memory;
+
+    fn build_iovec(mem: &GuestMemoryMmap, segments: &[(u64, usize)]) -> IoVecBuffer {
+        let mut iovec = IoVecBuffer::with_capacity(segments.len());
+        for &(addr, len) in segments {
+            iovec.push(mem, GuestAddress(addr), len).unwrap();
+        }
+        iovec
+    }
+
+    #[test]
+    fn test_push() {
+        let mem = default_guest_memory();
+        let mut iovec = build_iovec(&mem, &[(0, 100), (0x1000, 50)]);
+        assert_eq!(iovec.len(), 150);
+        assert_eq!(iovec.num_buffers(), 2);
+        assert!(!iovec.is_empty());
+
+        // Buffers outside of the guest memory are rejected.
+        assert!(iovec.push(&mem, GuestAddress(0xff00), 0x1000).is_err());
+        assert!(iovec.push(&mem, GuestAddress(0x10000), 1).is_err());
+        assert_eq!(iovec.len(), 150);
+
+        iovec.clear();
+        assert!(iovec.is_empty());
+    }
+
+    #[test]
+    fn test_read_write_at() {
+        let mem = default_guest_memory();
+        let iovec = build_iovec(&mem, &[(0x100, 4), (0x200, 2), (0x300, 4)]);
+
+        assert_eq!(iovec.write_at(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11], 0), 10);
+        assert_eq!(
+            mem.read_obj::<[u8; 4]>(GuestAddress(0x100)).unwrap(),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(
+            mem.read_obj::<[u8; 2]>(GuestAddress(0x200)).unwrap(),
+            [5, 6]
+        );
+        assert_eq!(
+            mem.read_obj::<[u8; 4]>(GuestAddress(0x300)).unwrap(),
+            [7, 8, 9, 10]
+        );
+
+        let mut buf = [0u8; 4];
+        assert_eq!(iovec.read_at(&mut buf, 3), 4);
+        assert_eq!(buf, [4, 5, 6, 7]);
+        let mut buf = [0u8; 4];
+        assert_eq!(iovec.read_at(&mut buf, 8), 2);
+        assert_eq!(buf, [9, 10, 0, 0]);
+        assert_eq!(iovec.read_at(&mut buf, 10), 0);
+    }
+
+    #[test]
+    fn test_readv_writev() {
+        let mem = default_guest_memory();
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        mem.write_slice(&data[..120], GuestAddress(0x1000)).unwrap();
+        mem.write_slice(&data[120..], GuestAddress(0x3000)).unwrap();
+
+        let mut file: File = TempFile::new().unwrap().into_file();
+        let iovec = build_iovec(&mem, &[(0x1000, 120), (0x3000, 80)]);
+        assert_eq!(iovec.writev(&file).unwrap(), 200);
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let iovec = build_iovec(&mem, &[(0x5000, 150), (0x6000, 150)]);
+        assert_eq!(iovec.readv(&file).unwrap(), 200);
+        let mut buf = vec![0u8; 200];
+        mem.read_slice(&mut buf[..150], GuestAddress(0x5000))
+            .unwrap();
+        mem.read_slice(&mut buf[150..], GuestAddress(0x6000))
+            .unwrap();
+        assert_eq!(buf, data);
+
+        // Invalid file descriptors are reported.
+        let faulty_file = unsafe { File::from_raw_fd(-2) };
+        assert!(iovec.writev(&faulty_file).is_err());
+        assert!(iovec.readv(&faulty_file).is_err());
+    }
+}
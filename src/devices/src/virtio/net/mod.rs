@@ -5,23 +5,36 @@ use std::{io, result};
 
 pub const MAX_BUFFER_SIZE: usize = 65562;
 pub const QUEUE_SIZE: u16 = 256;
+// The number of queues making up a RX/TX queue pair.
 pub const NUM_QUEUES: usize = 2;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
-// The index of the rx queue from Net device queues/queues_evts vector.
+// The queues of a device with a single queue pair, followed by the control queue.
+pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES + 1];
+// The index of the rx queue of the first queue pair from Net device queues/queues_evts vector.
 pub const RX_INDEX: usize = 0;
-// The index of the tx queue from Net device queues/queues_evts vector.
+// The index of the tx queue of the first queue pair from Net device queues/queues_evts vector.
 pub const TX_INDEX: usize = 1;
+pub const DEFAULT_NUM_QUEUE_PAIRS: u16 = 1;
+// There is no point in having more queue pairs than vCPUs, and Firecracker supports up to 32 vCPUs.
+pub const MAX_NUM_QUEUE_PAIRS: u16 = 32;
 
 pub mod device;
 pub mod event_handler;
+pub mod filter;
+pub mod iovec;
+pub mod pcap;
 pub mod persist;
 mod tap;
 pub mod test_utils;
+mod vhost;
 
 pub use tap::Error as TapError;
+pub use vhost::Error as VhostNetError;
 
-pub use self::device::Net;
+pub use self::device::{LinkState, Net, Offloads};
 pub use self::event_handler::*;
+pub use self::filter::{AntiSpoofing, Ipv4Prefix, Ipv6Prefix};
+pub use self::iovec::IoVecBuffer;
+pub use self::pcap::PacketCapture;
 
 /// Enum representing the Net device queue types
 pub enum NetQueue {
@@ -43,10 +56,30 @@ pub enum Error {
     TapEnable(TapError),
     /// EventFd error.
     EventFd(io::Error),
+    /// The number of queue pairs is either zero or larger than `MAX_NUM_QUEUE_PAIRS`.
+    InvalidNumQueuePairs(u16),
+    /// Creating the rate limiter of a queue pair failed.
+    CreateRateLimiter(io::Error),
+    /// Attaching or detaching a queue of the tap interface failed.
+    TapSetQueue(TapError),
+    /// The offloads depend on other offloads which are disabled.
+    InvalidOffloads(Offloads),
+    /// The anti-spoofing policy has invalid prefix lengths.
+    InvalidAntiSpoofing(AntiSpoofing),
     /// IO error.
     IO(io::Error),
     /// The VNET header is missing from the frame.
     VnetHeaderMissing,
+    /// Setting up the vhost-net backend failed.
+    VhostNet(VhostNetError),
+    /// The kernel doesn't offer a feature required by the vhost-net backend.
+    VhostNetMissingFeature(&'static str),
+    /// Rate limiting can't be enabled while the queues are processed by vhost-net.
+    VhostNetRateLimiter,
+    /// Creating the packet capture file failed.
+    PacketCapture(io::Error),
+    /// Frames can't be captured while the queues are processed by vhost-net.
+    VhostNetPacketCapture,
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -0,0 +1,196 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Captures the frames of a network device to a file in the pcap format, which can be inspected
+//! with tools like `tcpdump` or `wireshark`.
+//!
+//! See https://wiki.wireshark.org/Development/LibpcapFileFormat for the file format.
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use utils::time::{get_time_us, ClockType};
+
+use crate::virtio::net::device::vnet_hdr_len;
+use crate::virtio::net::iovec::IoVecBuffer;
+use crate::virtio::net::MAX_BUFFER_SIZE;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+// Frames are never truncated.
+const PCAP_SNAPLEN: u32 = MAX_BUFFER_SIZE as u32;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// The size of the file header.
+pub const PCAP_HEADER_LEN: u64 = 24;
+/// The size of the header preceding each frame.
+pub const PCAP_RECORD_HEADER_LEN: u64 = 16;
+
+/// A pcap file, which frames are appended to until it reaches its size limit.
+pub struct PacketCapture {
+    writer: BufWriter<File>,
+    size: u64,
+    max_size: u64,
+}
+
+impl PacketCapture {
+    /// Creates the pcap file at `path`, or truncates the existing one. The file won't grow larger
+    /// than `max_size` bytes.
+    pub fn new<P: AsRef<Path>>(path: P, max_size: u64) -> io::Result<PacketCapture> {
+        if max_size < PCAP_HEADER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The size limit can't hold the pcap file header",
+            ));
+        }
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&PCAP_MAGIC.to_ne_bytes())?;
+        writer.write_all(&PCAP_VERSION_MAJOR.to_ne_bytes())?;
+        writer.write_all(&PCAP_VERSION_MINOR.to_ne_bytes())?;
+        // The timestamps are in UTC and their accuracy is not known.
+        writer.write_all(&0i32.to_ne_bytes())?;
+        writer.write_all(&0u32.to_ne_bytes())?;
+        writer.write_all(&PCAP_SNAPLEN.to_ne_bytes())?;
+        writer.write_all(&LINKTYPE_ETHERNET.to_ne_bytes())?;
+        writer.flush()?;
+
+        Ok(PacketCapture {
+            writer,
+            size: PCAP_HEADER_LEN,
+            max_size,
+        })
+    }
+
+    /// Returns the size of the file, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Appends a frame, prefixed by its VNET header, to the file. The VNET header is not
+    /// captured. Returns `false`, without writing anything, if the frame doesn't fit in the size
+    /// limit of the file.
+    pub fn write_frame(&mut self, frame_buf: &[u8]) -> io::Result<bool> {
+        let frame = frame_buf.get(vnet_hdr_len()..).unwrap_or_default();
+        self.write_record(frame.len(), |writer| writer.write_all(frame))
+    }
+
+    /// Appends the first `len` bytes of a frame in guest memory, prefixed by its VNET header, to
+    /// the file, like `write_frame`.
+    pub fn write_frame_iovec(&mut self, frame_iovec: &IoVecBuffer, len: usize) -> io::Result<bool> {
+        let len = cmp::min(len, frame_iovec.len()).saturating_sub(vnet_hdr_len());
+        self.write_record(len, |writer| {
+            let mut chunk = [0u8; 4096];
+            let mut offset = vnet_hdr_len();
+            let end = vnet_hdr_len() + len;
+            while offset < end {
+                let chunk_len = cmp::min(chunk.len(), end - offset);
+                let count = frame_iovec.read_at(&mut chunk[..chunk_len], offset);
+                writer.write_all(&chunk[..count])?;
+                offset += count;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_record<F>(&mut self, len: usize, write_frame: F) -> io::Result<bool>
+    where
+        F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+    {
+        // Malformed frames are not captured.
+        if len == 0 {
+            return Ok(true);
+        }
+        let record_len = PCAP_RECORD_HEADER_LEN + len as u64;
+        if self.size + record_len > self.max_size {
+            return Ok(false);
+        }
+
+        let timestamp_us = get_time_us(ClockType::Real);
+        self.writer
+            .write_all(&((timestamp_us / 1_000_000) as u32).to_ne_bytes())?;
+        self.writer
+            .write_all(&((timestamp_us % 1_000_000) as u32).to_ne_bytes())?;
+        // The whole frame is captured.
+        self.writer.write_all(&(len as u32).to_ne_bytes())?;
+        self.writer.write_all(&(len as u32).to_ne_bytes())?;
+        write_frame(&mut self.writer)?;
+        // Keep the file readable while the capture is in progress.
+        self.writer.flush()?;
+        self.size += record_len;
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use utils::tempfile::TempFile;
+    use vm_memory::{Bytes, GuestAddress};
+
+    use super::*;
+    use crate::virtio::net::test_utils::default_guest_memory;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        let mut field = [0u8; 4];
+        field.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_ne_bytes(field)
+    }
+
+    #[test]
+    fn test_packet_capture() {
+        let file = TempFile::new().unwrap();
+        // The size limit must fit the file header.
+        assert!(PacketCapture::new(file.as_path(), PCAP_HEADER_LEN - 1).is_err());
+
+        let frame_len = 100;
+        let max_size = PCAP_HEADER_LEN + 2 * (PCAP_RECORD_HEADER_LEN + frame_len as u64);
+        let mut capture = PacketCapture::new(file.as_path(), max_size).unwrap();
+        assert_eq!(capture.size(), PCAP_HEADER_LEN);
+
+        let frame: Vec<u8> = (0..vnet_hdr_len() + frame_len).map(|i| i as u8).collect();
+        assert!(capture.write_frame(&frame).unwrap());
+        // Frames without a VNET header are skipped.
+        assert!(capture.write_frame(&frame[..vnet_hdr_len()]).unwrap());
+
+        let mem = default_guest_memory();
+        mem.write_slice(&frame[..50], GuestAddress(0)).unwrap();
+        mem.write_slice(&frame[50..], GuestAddress(0x1000)).unwrap();
+        let mut frame_iovec = IoVecBuffer::with_capacity(2);
+        frame_iovec.push(&mem, GuestAddress(0), 50).unwrap();
+        frame_iovec
+            .push(&mem, GuestAddress(0x1000), 0x1000)
+            .unwrap();
+        assert!(capture
+            .write_frame_iovec(&frame_iovec, frame.len())
+            .unwrap());
+        assert_eq!(capture.size(), max_size);
+        // The file is full.
+        assert!(!capture.write_frame(&frame).unwrap());
+        assert_eq!(capture.size(), max_size);
+
+        let bytes = fs::read(file.as_path()).unwrap();
+        assert_eq!(bytes.len() as u64, max_size);
+        assert_eq!(read_u32(&bytes, 0), PCAP_MAGIC);
+        assert_eq!(read_u32(&bytes, 16), PCAP_SNAPLEN);
+        assert_eq!(read_u32(&bytes, 20), LINKTYPE_ETHERNET);
+        let mut offset = PCAP_HEADER_LEN as usize;
+        for _ in 0..2 {
+            assert_eq!(read_u32(&bytes, offset + 8), frame_len as u32);
+            assert_eq!(read_u32(&bytes, offset + 12), frame_len as u32);
+            offset += PCAP_RECORD_HEADER_LEN as usize;
+            assert_eq!(&bytes[offset..offset + frame_len], &frame[vnet_hdr_len()..]);
+            offset += frame_len;
+        }
+    }
+}
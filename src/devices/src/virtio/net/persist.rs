@@ -4,9 +4,11 @@
 //! Defines the structures needed for saving/restoring net devices.
 
 use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
 
+use logger::{warn, IncMetric, METRICS};
 use mmds::data_store::Mmds;
 use mmds::ns::MmdsNetworkStack;
 use mmds::persist::MmdsNetworkStackState;
@@ -14,12 +16,14 @@ use rate_limiter::persist::RateLimiterState;
 use rate_limiter::RateLimiter;
 use snapshot::Persist;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
+use virtio_gen::virtio_net::{VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_S_LINK_UP};
 use vm_memory::GuestMemoryMmap;
 
-use super::device::Net;
-use super::{NUM_QUEUES, QUEUE_SIZE};
+use super::device::{num_queues, LinkState, Net, Offloads};
+use super::filter::{AntiSpoofing, Ipv4Prefix, Ipv6Prefix};
+use super::{DEFAULT_NUM_QUEUE_PAIRS, QUEUE_SIZE};
 use crate::virtio::persist::{Error as VirtioStateError, VirtioDeviceState};
 use crate::virtio::{DeviceState, TYPE_NET};
 
@@ -29,16 +33,189 @@ pub struct NetConfigSpaceState {
     guest_mac: [u8; MAC_ADDR_LEN],
 }
 
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct QueuePairState {
+    rx_rate_limiter_state: RateLimiterState,
+    tx_rate_limiter_state: RateLimiterState,
+}
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct Ipv4PrefixState {
+    address: u32,
+    prefix_len: u8,
+}
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct Ipv6PrefixState {
+    // The upper and lower halves of the address.
+    address_hi: u64,
+    address_lo: u64,
+    prefix_len: u8,
+}
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct AntiSpoofingState {
+    allowed_macs: Vec<[u8; MAC_ADDR_LEN]>,
+    ipv4_prefixes: Vec<Ipv4PrefixState>,
+    ipv6_prefixes: Vec<Ipv6PrefixState>,
+    ethertypes: Vec<u16>,
+}
+
+impl From<&AntiSpoofing> for AntiSpoofingState {
+    fn from(policy: &AntiSpoofing) -> Self {
+        AntiSpoofingState {
+            allowed_macs: policy
+                .allowed_macs
+                .iter()
+                .map(|mac| {
+                    let mut bytes = [0u8; MAC_ADDR_LEN];
+                    bytes.copy_from_slice(mac.get_bytes());
+                    bytes
+                })
+                .collect(),
+            ipv4_prefixes: policy
+                .ipv4_prefixes
+                .iter()
+                .map(|prefix| Ipv4PrefixState {
+                    address: u32::from(prefix.address),
+                    prefix_len: prefix.prefix_len,
+                })
+                .collect(),
+            ipv6_prefixes: policy
+                .ipv6_prefixes
+                .iter()
+                .map(|prefix| {
+                    let address = u128::from(prefix.address);
+                    Ipv6PrefixState {
+                        address_hi: (address >> 64) as u64,
+                        address_lo: address as u64,
+                        prefix_len: prefix.prefix_len,
+                    }
+                })
+                .collect(),
+            ethertypes: policy.ethertypes.clone(),
+        }
+    }
+}
+
+impl From<&AntiSpoofingState> for AntiSpoofing {
+    fn from(state: &AntiSpoofingState) -> Self {
+        AntiSpoofing {
+            allowed_macs: state
+                .allowed_macs
+                .iter()
+                .map(|mac| MacAddr::from_bytes_unchecked(&mac[..]))
+                .collect(),
+            ipv4_prefixes: state
+                .ipv4_prefixes
+                .iter()
+                .map(|prefix| Ipv4Prefix {
+                    address: Ipv4Addr::from(prefix.address),
+                    prefix_len: prefix.prefix_len,
+                })
+                .collect(),
+            ipv6_prefixes: state
+                .ipv6_prefixes
+                .iter()
+                .map(|prefix| Ipv6Prefix {
+                    address: Ipv6Addr::from(
+                        (u128::from(prefix.address_hi) << 64) | u128::from(prefix.address_lo),
+                    ),
+                    prefix_len: prefix.prefix_len,
+                })
+                .collect(),
+            ethertypes: state.ethertypes.clone(),
+        }
+    }
+}
+
 #[derive(Clone, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct NetState {
     id: String,
     tap_if_name: String,
+    // The rate limiters of the first queue pair.
     rx_rate_limiter_state: RateLimiterState,
     tx_rate_limiter_state: RateLimiterState,
     pub mmds_ns: Option<MmdsNetworkStackState>,
     config_space: NetConfigSpaceState,
     virtio_state: VirtioDeviceState,
+    #[version(
+        start = 2,
+        ser_fn = "num_queue_pairs_ser",
+        default_fn = "default_num_queue_pairs"
+    )]
+    num_queue_pairs: u16,
+    #[version(start = 2, default_fn = "default_num_queue_pairs")]
+    active_queue_pairs: u16,
+    // The rate limiters of the queue pairs following the first one.
+    #[version(start = 2)]
+    queue_pairs: Vec<QueuePairState>,
+    #[version(start = 2, ser_fn = "link_up_ser", default_fn = "default_link_up")]
+    link_up: bool,
+    #[version(start = 2, ser_fn = "vhost_net_ser")]
+    vhost_net: bool,
+    #[version(start = 2, ser_fn = "anti_spoofing_ser")]
+    anti_spoofing: Option<AntiSpoofingState>,
+}
+
+impl NetState {
+    fn num_queue_pairs_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would only restore the first queue pair, losing the state of the others.
+        if target_version < 2 && self.num_queue_pairs != DEFAULT_NUM_QUEUE_PAIRS {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support multiple net queue pairs.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn link_up_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would bring the link of the restored device back up.
+        if target_version < 2 && !self.link_up {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support setting the net link down.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn vhost_net_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would process the queues in the VMM instead of handing them to the
+        // vhost-net backend.
+        if target_version < 2 && self.vhost_net {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support vhost-net.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn anti_spoofing_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would let the guest send frames from any address.
+        if target_version < 2 && self.anti_spoofing.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support net anti-spoofing.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_num_queue_pairs(_source_version: u16) -> u16 {
+        DEFAULT_NUM_QUEUE_PAIRS
+    }
+
+    fn default_link_up(_source_version: u16) -> bool {
+        true
+    }
 }
 
 pub struct NetConstructorArgs {
@@ -63,13 +240,25 @@ impl Persist<'_> for Net {
         NetState {
             id: self.id().clone(),
             tap_if_name: self.iface_name(),
-            rx_rate_limiter_state: self.rx_rate_limiter.save(),
-            tx_rate_limiter_state: self.tx_rate_limiter.save(),
+            rx_rate_limiter_state: self.queue_pairs[0].rx_rate_limiter.save(),
+            tx_rate_limiter_state: self.queue_pairs[0].tx_rate_limiter.save(),
             mmds_ns: self.mmds_ns.as_ref().map(|mmds| mmds.save()),
             config_space: NetConfigSpaceState {
                 guest_mac: self.config_space.guest_mac,
             },
             virtio_state: VirtioDeviceState::from_device(self),
+            num_queue_pairs: self.num_queue_pairs(),
+            active_queue_pairs: self.active_queue_pairs,
+            queue_pairs: self.queue_pairs[1..]
+                .iter()
+                .map(|queue_pair| QueuePairState {
+                    rx_rate_limiter_state: queue_pair.rx_rate_limiter.save(),
+                    tx_rate_limiter_state: queue_pair.tx_rate_limiter.save(),
+                })
+                .collect(),
+            link_up: self.link_state() == LinkState::Up,
+            vhost_net: self.vhost_net(),
+            anti_spoofing: self.anti_spoofing().map(AntiSpoofingState::from),
         }
     }
 
@@ -89,8 +278,20 @@ impl Persist<'_> for Net {
             .as_ref(),
             rx_rate_limiter,
             tx_rate_limiter,
+            state.num_queue_pairs,
+            Offloads::from_avail_features(state.virtio_state.avail_features),
         )?;
 
+        for (queue_pair, queue_pair_state) in
+            net.queue_pairs[1..].iter_mut().zip(&state.queue_pairs)
+        {
+            queue_pair.rx_rate_limiter =
+                RateLimiter::restore((), &queue_pair_state.rx_rate_limiter_state)?;
+            queue_pair.tx_rate_limiter =
+                RateLimiter::restore((), &queue_pair_state.tx_rate_limiter_state)?;
+        }
+        net.set_active_queue_pairs(state.active_queue_pairs)?;
+
         // We trust the MMIODeviceManager::restore to pass us an MMDS data store reference if
         // there is at least one net device having the MMDS NS present and/or the mmds version was
         // persisted in the snapshot.
@@ -108,10 +309,16 @@ impl Persist<'_> for Net {
             );
         }
 
+        // Devices created before the control queue was always offered may not have one.
+        let mut num_queues = num_queues(state.num_queue_pairs);
+        if state.virtio_state.avail_features & (1 << VIRTIO_NET_F_CTRL_VQ) == 0 {
+            num_queues -= 1;
+            net.queue_evts.truncate(num_queues);
+        }
         net.queues = state.virtio_state.build_queues_checked(
             &constructor_args.mem,
             TYPE_NET,
-            NUM_QUEUES,
+            num_queues,
             QUEUE_SIZE,
         )?;
         net.irq_trigger.irq_status =
@@ -119,8 +326,29 @@ impl Persist<'_> for Net {
         net.avail_features = state.virtio_state.avail_features;
         net.acked_features = state.virtio_state.acked_features;
 
+        if !state.link_up {
+            net.config_space.status &= !(VIRTIO_NET_S_LINK_UP as u16);
+        }
+
+        net.set_anti_spoofing(state.anti_spoofing.as_ref().map(AntiSpoofing::from))?;
+
+        // The state of the vrings was pulled back from the kernel when saving the device, so
+        // Firecracker can process the queues if vhost-net is not available on this host.
+        if state.vhost_net {
+            if let Err(err) = net.enable_vhost_net() {
+                warn!(
+                    "Net: Cannot use vhost-net for {}, falling back to Firecracker: {:?}",
+                    net.id, err
+                );
+                METRICS.net.vhost_net_fallbacks.inc();
+            }
+        }
+
         if state.virtio_state.activated {
             net.device_state = DeviceState::Activated(constructor_args.mem);
+            // The guest may be resumed on another host, so it should announce itself on the
+            // network once the vm is resumed.
+            net.announce_pending = true;
         }
 
         Ok(net)
@@ -133,7 +361,9 @@ mod tests {
 
     use super::*;
     use crate::virtio::device::VirtioDevice;
-    use crate::virtio::net::test_utils::{default_guest_memory, default_net, default_net_no_mmds};
+    use crate::virtio::net::test_utils::{
+        default_guest_memory, default_net, default_net_no_mmds, default_net_with_queue_pairs,
+    };
 
     fn validate_save_and_restore(net: Net, mmds_ds: Option<Arc<Mutex<Mmds>>>) {
         let guest_mem = default_guest_memory();
@@ -187,8 +417,8 @@ mod tests {
                     assert_eq!(&restored_net.id, &id);
                     assert_eq!(&restored_net.iface_name(), &tap_if_name);
                     assert_eq!(restored_net.mmds_ns.is_some(), allow_mmds_requests);
-                    assert_eq!(restored_net.rx_rate_limiter, RateLimiter::default());
-                    assert_eq!(restored_net.tx_rate_limiter, RateLimiter::default());
+                    assert_eq!(restored_net.rx_rate_limiter(), &RateLimiter::default());
+                    assert_eq!(restored_net.tx_rate_limiter(), &RateLimiter::default());
                 }
                 Err(Error::NoMmdsDataStore) => assert!(has_mmds_ns && !allow_mmds_requests),
                 _ => unreachable!(),
@@ -211,4 +441,116 @@ mod tests {
         // data store. This will return an error.
         validate_save_and_restore(default_net(), None);
     }
+
+    #[test]
+    fn test_persistence_multi_queue() {
+        let mut net = default_net_with_queue_pairs(2);
+        net.set_active_queue_pairs(2).unwrap();
+        net.queue_pairs[1].tx_rate_limiter = RateLimiter::new(0, 0, 0, 10, 0, 100).unwrap();
+        net.set_link_state(LinkState::Down).unwrap();
+        let anti_spoofing = AntiSpoofing {
+            allowed_macs: vec![MacAddr::parse_str("12:34:56:78:9a:bc").unwrap()],
+            ipv4_prefixes: vec![Ipv4Prefix {
+                address: Ipv4Addr::new(192, 168, 0, 0),
+                prefix_len: 16,
+            }],
+            ipv6_prefixes: vec![Ipv6Prefix {
+                address: Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0),
+                prefix_len: 10,
+            }],
+            ethertypes: vec![0x0800],
+        };
+        net.set_anti_spoofing(Some(anti_spoofing.clone())).unwrap();
+
+        // Older versions can't restore the link state, nor the anti-spoofing filters.
+        let mut single_queue_net = default_net_no_mmds();
+        single_queue_net.set_link_state(LinkState::Down).unwrap();
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+        let mut mem = vec![0; 4096];
+        assert!(<Net as Persist>::save(&single_queue_net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+        single_queue_net.set_link_state(LinkState::Up).unwrap();
+        single_queue_net
+            .set_anti_spoofing(Some(anti_spoofing.clone()))
+            .unwrap();
+        assert!(<Net as Persist>::save(&single_queue_net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+        single_queue_net.set_anti_spoofing(None).unwrap();
+        <Net as Persist>::save(&single_queue_net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+        let mut mem = vec![0; 4096];
+        let state = <Net as Persist>::save(&net);
+        // Older versions can't restore the additional queue pair.
+        assert!(state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+        state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+
+        drop(net);
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.num_queue_pairs(), 2);
+        assert_eq!(restored_net.active_queue_pairs, 2);
+        assert_eq!(restored_net.link_state(), LinkState::Down);
+        assert_eq!(restored_net.anti_spoofing(), Some(&anti_spoofing));
+        assert_eq!(restored_net.queues().len(), 5);
+        assert!(restored_net.queue_pairs[0].tx_rate_limiter.ops().is_none());
+        assert_eq!(
+            restored_net.queue_pairs[1]
+                .tx_rate_limiter
+                .ops()
+                .unwrap()
+                .capacity(),
+            10
+        );
+    }
+
+    #[test]
+    fn test_persistence_without_ctrl_queue() {
+        // Emulate a device created before the control queue was always offered.
+        let mut net = default_net_no_mmds();
+        net.avail_features &= !(1 << VIRTIO_NET_F_CTRL_VQ);
+        net.queues.pop();
+
+        let version_map = VersionMap::new();
+        let mut mem = vec![0; 4096];
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+
+        drop(net);
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.queues().len(), 2);
+        assert_eq!(restored_net.queue_events().len(), 2);
+        assert_eq!(restored_net.ctrl_queue_index(), None);
+        assert_eq!(restored_net.link_state(), LinkState::Up);
+        // Only the drivers of activated devices are asked to announce themselves.
+        assert!(!restored_net.announce_pending);
+    }
 }
@@ -37,6 +37,7 @@ const TUNTAP: ::std::os::raw::c_uint = 84;
 ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, ::std::os::raw::c_int);
 ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
+ioctl_iow_nr!(TUNSETQUEUE, TUNTAP, 217, ::std::os::raw::c_int);
 
 /// Handle for a network tap interface.
 ///
@@ -103,6 +104,29 @@ impl Tap {
     ///
     /// * `if_name` - the name of the interface.
     pub fn open_named(if_name: &str) -> Result<Tap> {
+        Self::open_with_flags(if_name, 0)
+    }
+
+    /// Open `num_queues` queues of a multi-queue TUN/TAP device given the interface name.
+    ///
+    /// A single queue opens the device as with `open_named`. Otherwise, the interface has to
+    /// be created on the host with multi-queue support, and each returned `Tap` serves one of
+    /// its queues.
+    /// # Arguments
+    ///
+    /// * `if_name` - the name of the interface.
+    /// * `num_queues` - the number of queues to open.
+    pub fn open_queues(if_name: &str, num_queues: usize) -> Result<Vec<Tap>> {
+        if num_queues == 1 {
+            return Ok(vec![Self::open_named(if_name)?]);
+        }
+
+        (0..num_queues)
+            .map(|_| Self::open_with_flags(if_name, net_gen::IFF_MULTI_QUEUE))
+            .collect()
+    }
+
+    fn open_with_flags(if_name: &str, extra_flags: u32) -> Result<Tap> {
         let terminated_if_name = build_terminated_if_name(if_name)?;
 
         let fd = unsafe {
@@ -121,7 +145,10 @@ impl Tap {
 
         let ifreq = IfReqBuilder::new()
             .if_name(&terminated_if_name)
-            .flags((net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR) as i16)
+            .flags(
+                (net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR | extra_flags)
+                    as i16,
+            )
             .execute(&tuntap, TUNSETIFF())?;
 
         // Safe since only the name is accessed, and it's cloned out.
@@ -161,6 +188,22 @@ impl Tap {
 
         Ok(())
     }
+
+    /// Attach or detach the queue of a multi-queue tap interface.
+    ///
+    /// The kernel only steers packets to the attached queues.
+    pub fn set_queue_enabled(&self, enabled: bool) -> Result<()> {
+        let flags = if enabled {
+            net_gen::IFF_ATTACH_QUEUE
+        } else {
+            net_gen::IFF_DETACH_QUEUE
+        };
+        IfReqBuilder::new()
+            .flags(flags as i16)
+            .execute(&self.tap_file, TUNSETQUEUE())?;
+
+        Ok(())
+    }
 }
 
 impl Read for Tap {
@@ -233,6 +276,32 @@ pub mod tests {
         Tap::open_named("exclusivetap").unwrap_err();
     }
 
+    #[test]
+    fn test_open_queues() {
+        // A single queue doesn't need a multi-queue interface.
+        let taps = Tap::open_queues("singlequeuetap", 1).unwrap();
+        assert_eq!(taps.len(), 1);
+        Tap::open_named("singlequeuetap").unwrap_err();
+        drop(taps);
+
+        let taps = Tap::open_queues("multiqueuetap", 4).unwrap();
+        assert_eq!(taps.len(), 4);
+        for tap in &taps {
+            assert_eq!(tap.if_name_as_str(), "multiqueuetap");
+        }
+        // Each queue has its own file descriptor.
+        assert_ne!(taps[0].as_raw_fd(), taps[1].as_raw_fd());
+        // Another queue can be attached to the multi-queue interface, but not a single queue.
+        Tap::open_queues("multiqueuetap", 2).unwrap();
+        Tap::open_named("multiqueuetap").unwrap_err();
+
+        taps[1].set_queue_enabled(false).unwrap();
+        taps[1].set_queue_enabled(true).unwrap();
+        // Queues of single queue interfaces can't be detached.
+        let tap = Tap::open_named("").unwrap();
+        assert!(tap.set_queue_enabled(false).is_err());
+    }
+
     #[test]
     fn test_set_options() {
         // This line will fail to provide an initialized FD if the test is not run as root.
@@ -21,6 +21,7 @@ use vm_memory::{GuestAddress, GuestMemoryMmap};
 #[cfg(test)]
 use crate::virtio::net::device::vnet_hdr_len;
 use crate::virtio::net::tap::{Error, IfReqBuilder, Tap};
+use crate::virtio::net::{Offloads, DEFAULT_NUM_QUEUE_PAIRS, RX_INDEX, TX_INDEX};
 use crate::virtio::test_utils::VirtQueue;
 use crate::virtio::{Net, Queue, QueueError};
 use crate::Error as DeviceError;
@@ -41,18 +42,24 @@ pub fn default_net() -> Net {
         Some(&guest_mac),
         RateLimiter::default(),
         RateLimiter::default(),
+        DEFAULT_NUM_QUEUE_PAIRS,
+        Offloads::default(),
     )
     .unwrap();
     net.configure_mmds_network_stack(
         MmdsNetworkStack::default_ipv4_addr(),
         Arc::new(Mutex::new(Mmds::default())),
     );
-    enable(&net.tap);
+    enable(&net.queue_pairs[0].tap);
 
     net
 }
 
 pub fn default_net_no_mmds() -> Net {
+    default_net_with_queue_pairs(DEFAULT_NUM_QUEUE_PAIRS)
+}
+
+pub fn default_net_with_queue_pairs(num_queue_pairs: u16) -> Net {
     let next_tap = NEXT_INDEX.fetch_add(1, Ordering::SeqCst);
     let tap_dev_name = format!("net-device{}", next_tap);
 
@@ -64,9 +71,11 @@ pub fn default_net_no_mmds() -> Net {
         Some(&guest_mac),
         RateLimiter::default(),
         RateLimiter::default(),
+        num_queue_pairs,
+        Offloads::default(),
     )
     .unwrap();
-    enable(&net.tap);
+    enable(&net.queue_pairs[0].tap);
 
     net
 }
@@ -259,7 +268,7 @@ pub fn enable(tap: &Tap) {
 #[cfg(test)]
 pub(crate) fn inject_tap_tx_frame(net: &Net, len: usize) -> Vec<u8> {
     assert!(len >= vnet_hdr_len());
-    let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&net.tap));
+    let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&net.queue_pairs[0].tap));
     let mut frame = utils::rand::rand_alphanumerics(len - vnet_hdr_len())
         .as_bytes()
         .to_vec();
@@ -304,9 +313,8 @@ pub fn set_mac(net: &mut Net, mac: MacAddr) {
 
 // Assigns "guest virtio driver" activated queues to the net device.
 pub fn assign_queues(net: &mut Net, rxq: Queue, txq: Queue) {
-    net.queues.clear();
-    net.queues.push(rxq);
-    net.queues.push(txq);
+    net.queues[RX_INDEX] = rxq;
+    net.queues[TX_INDEX] = txq;
 }
 
 #[cfg(test)]
@@ -344,11 +352,20 @@ pub mod test {
         const QUEUE_SIZE: u16 = 16;
 
         pub fn default() -> TestHelper<'a> {
+            Self::new(false)
+        }
+
+        pub fn with_dirty_page_tracking() -> TestHelper<'a> {
+            Self::new(true)
+        }
+
+        fn new(track_dirty_pages: bool) -> TestHelper<'a> {
             let mut event_manager = EventManager::new().unwrap();
             let mut net = default_net();
             let mem = vm_memory::test_utils::create_guest_memory_unguarded(
-                &[(GuestAddress(0), MAX_BUFFER_SIZE)],
-                false,
+                // Leave room for descriptor chains which can hold any frame.
+                &[(GuestAddress(0), 2 * MAX_BUFFER_SIZE)],
+                track_dirty_pages,
             )
             .unwrap();
             // transmute mem_ref lifetime to 'a
@@ -388,11 +405,11 @@ pub mod test {
 
         pub fn simulate_event(&mut self, event: NetEvent) {
             match event {
-                NetEvent::RxQueue => self.net().process_rx_queue_event(),
-                NetEvent::RxRateLimiter => self.net().process_rx_rate_limiter_event(),
-                NetEvent::Tap => self.net().process_tap_rx_event(),
-                NetEvent::TxQueue => self.net().process_tx_queue_event(),
-                NetEvent::TxRateLimiter => self.net().process_tx_rate_limiter_event(),
+                NetEvent::RxQueue => self.net().process_rx_queue_event(0),
+                NetEvent::RxRateLimiter => self.net().process_rx_rate_limiter_event(0),
+                NetEvent::Tap => self.net().process_tap_rx_event(0),
+                NetEvent::TxQueue => self.net().process_tx_queue_event(0),
+                NetEvent::TxRateLimiter => self.net().process_tx_rate_limiter_event(0),
             };
         }
 
@@ -452,7 +469,7 @@ pub mod test {
                 self.event_manager.run_with_timeout(100).unwrap()
             );
             // Check that the frame has been deferred.
-            assert!(self.net().rx_deferred_frame);
+            assert!(self.net().queue_pairs[0].rx_deferred_frame);
             // Check that the descriptor chain has been discarded.
             assert_eq!(self.rxq.used.idx.get(), used_idx + 1);
             assert!(&self.net().irq_trigger.has_pending_irq(IrqType::Vring));
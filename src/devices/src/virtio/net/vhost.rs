@@ -0,0 +1,470 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Offloads the datapath of a virtio network device to the in-kernel vhost-net driver.
+//!
+//! Each queue pair is served by its own `/dev/vhost-net` handle, which is bound to the TAP queue
+//! of the pair. The kernel then moves the frames between the vrings and the TAP interface on its
+//! own: it is notified of new buffers through the queue eventfds and signals used buffers
+//! through the call eventfds, which Firecracker forwards to the guest as interrupts.
+
+use std::fs::{File, OpenOptions};
+use std::io::Error as IoError;
+use std::os::raw::c_uint;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use utils::eventfd::EventFd;
+use utils::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref};
+use utils::{ioctl_expr, ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr};
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+};
+
+use crate::virtio::net::tap::Tap;
+use crate::virtio::vhost_user::{MemoryHeader, MemoryRegion, VringAddr, VringState};
+use crate::virtio::Queue;
+
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+
+const VHOST: c_uint = 0xAF;
+ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST, 0x00, u64);
+ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST, 0x00, u64);
+ioctl_io_nr!(VHOST_SET_OWNER, VHOST, 0x01);
+ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST, 0x03, MemoryHeader);
+ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST, 0x10, VringState);
+ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST, 0x11, VringAddr);
+ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST, 0x12, VringState);
+ioctl_iowr_nr!(VHOST_GET_VRING_BASE, VHOST, 0x12, VringState);
+ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST, 0x20, VringFile);
+ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST, 0x21, VringFile);
+ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST, 0x30, VringFile);
+
+// The vrings of a vhost-net handle.
+const RX_VRING: u32 = 0;
+const TX_VRING: u32 = 1;
+
+/// List of errors the vhost-net backend can throw.
+#[derive(Debug)]
+pub enum Error {
+    /// EventFd error.
+    EventFd(IoError),
+    /// Guest gave us bad memory addresses.
+    GuestMemory(GuestMemoryError),
+    /// ioctl failed.
+    IoctlError(IoError),
+    /// Couldn't open /dev/vhost-net.
+    OpenVhostNet(IoError),
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// A file descriptor attached to a vring.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct VringFile {
+    pub index: u32,
+    pub fd: RawFd,
+}
+
+// Safe because it only contains plain data.
+unsafe impl ByteValued for VringFile {}
+
+/// Handle for a vhost-net instance, which serves the rx and tx vrings of a queue pair.
+#[derive(Debug)]
+pub struct VhostNet {
+    file: File,
+}
+
+impl VhostNet {
+    /// Opens a new vhost-net instance and claims it for the current process.
+    pub fn new() -> Result<VhostNet> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(VHOST_NET_PATH)
+            .map_err(Error::OpenVhostNet)?;
+
+        let vhost_net = VhostNet { file };
+        // ioctl is safe since we call it with a valid vhost-net fd and check the return value.
+        let ret = unsafe { ioctl(&vhost_net.file, VHOST_SET_OWNER()) };
+        vhost_net.check_ret(ret)?;
+
+        Ok(vhost_net)
+    }
+
+    /// Gets the virtio features supported by the kernel.
+    pub fn get_features(&self) -> Result<u64> {
+        let mut features = 0u64;
+        // ioctl is safe. Called with a valid vhost-net fd and a reference to a `u64`, which is
+        // the size the kernel writes. We check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, VHOST_GET_FEATURES(), &mut features) };
+        self.check_ret(ret)?;
+        Ok(features)
+    }
+
+    /// Sets the virtio features acked by the driver.
+    pub fn set_features(&self, features: u64) -> Result<()> {
+        // ioctl is safe. Called with a valid vhost-net fd, and we check the return.
+        let ret = unsafe { ioctl_with_ref(&self.file, VHOST_SET_FEATURES(), &features) };
+        self.check_ret(ret)
+    }
+
+    /// Describes the guest memory to the kernel, which accesses it through the mappings of the
+    /// current process.
+    pub fn set_mem_table(&self, mem: &GuestMemoryMmap) -> Result<()> {
+        let header = MemoryHeader {
+            num_regions: mem.num_regions() as u32,
+            padding: 0,
+        };
+        let mut payload = header.as_slice().to_vec();
+        for region in mem.iter() {
+            // The kernel layout has a padding field in place of the mmap offset.
+            let memory_region = MemoryRegion {
+                guest_phys_addr: region.start_addr().raw_value(),
+                memory_size: region.len(),
+                userspace_addr: region.as_ptr() as u64,
+                mmap_offset: 0,
+            };
+            payload.extend_from_slice(memory_region.as_slice());
+        }
+
+        // ioctl is safe. Called with a valid vhost-net fd and a buffer which holds the header
+        // followed by `num_regions` regions, as the kernel expects. We check the return value.
+        let ret = unsafe { ioctl_with_ptr(&self.file, VHOST_SET_MEM_TABLE(), payload.as_ptr()) };
+        self.check_ret(ret)
+    }
+
+    /// Sets the size of the vring at `index`.
+    pub fn set_vring_num(&self, index: u32, num: u16) -> Result<()> {
+        let state = VringState {
+            index,
+            num: u32::from(num),
+        };
+        // ioctl is safe. Called with a valid vhost-net fd, and we check the return.
+        let ret = unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_NUM(), &state) };
+        self.check_ret(ret)
+    }
+
+    /// Sets the addresses of the descriptor table, available ring and used ring of `queue`.
+    pub fn set_vring_addr(&self, index: u32, queue: &Queue, mem: &GuestMemoryMmap) -> Result<()> {
+        let host_address = |addr| {
+            mem.get_host_address(addr)
+                .map(|host_addr| host_addr as u64)
+                .map_err(Error::GuestMemory)
+        };
+        let addr = VringAddr {
+            index,
+            flags: 0,
+            desc: host_address(queue.desc_table)?,
+            used: host_address(queue.used_ring)?,
+            avail: host_address(queue.avail_ring)?,
+            log: 0,
+        };
+        // ioctl is safe. Called with a valid vhost-net fd, and we check the return.
+        let ret = unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_ADDR(), &addr) };
+        self.check_ret(ret)
+    }
+
+    /// Sets the index of the next available descriptor the kernel should process.
+    pub fn set_vring_base(&self, index: u32, base: u16) -> Result<()> {
+        let state = VringState {
+            index,
+            num: u32::from(base),
+        };
+        // ioctl is safe. Called with a valid vhost-net fd, and we check the return.
+        let ret = unsafe { ioctl_with_ref(&self.file, VHOST_SET_VRING_BASE(), &state) };
+        self.check_ret(ret)
+    }
+
+    /// Returns the index of the next available descriptor the kernel would process.
+    pub fn get_vring_base(&self, index: u32) -> Result<u16> {
+        let mut state = VringState { index, num: 0 };
+        // ioctl is safe. Called with a valid vhost-net fd, and we check the return.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, VHOST_GET_VRING_BASE(), &mut state) };
+        self.check_ret(ret)?;
+        Ok(state.num as u16)
+    }
+
+    /// Sets the eventfd signaled by the guest when new buffers are available in the vring.
+    pub fn set_vring_kick(&self, index: u32, evt: &EventFd) -> Result<()> {
+        self.set_vring_file(VHOST_SET_VRING_KICK(), index, evt.as_raw_fd())
+    }
+
+    /// Sets the eventfd signaled by the kernel when it adds used buffers to the vring.
+    pub fn set_vring_call(&self, index: u32, evt: &EventFd) -> Result<()> {
+        self.set_vring_file(VHOST_SET_VRING_CALL(), index, evt.as_raw_fd())
+    }
+
+    /// Binds the vring at `index` to `tap`, which starts the processing of the vring. Passing
+    /// no TAP interface stops the vring.
+    pub fn set_backend(&self, index: u32, tap: Option<&Tap>) -> Result<()> {
+        self.set_vring_file(
+            VHOST_NET_SET_BACKEND(),
+            index,
+            tap.map_or(-1, |tap| tap.as_raw_fd()),
+        )
+    }
+
+    fn set_vring_file(&self, request: u64, index: u32, fd: RawFd) -> Result<()> {
+        let file = VringFile { index, fd };
+        // ioctl is safe. Called with a valid vhost-net fd, and we check the return.
+        let ret = unsafe { ioctl_with_ref(&self.file, request, &file) };
+        self.check_ret(ret)
+    }
+
+    fn check_ret(&self, ret: i32) -> Result<()> {
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+        Ok(())
+    }
+}
+
+/// Whether the kernel processes the vrings of a `VhostNetBackend`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendState {
+    /// The vrings haven't been handed over to the kernel yet.
+    NotStarted,
+    /// The kernel processes the vrings.
+    Running,
+    /// The vrings are set up, but the kernel doesn't process them.
+    Stopped,
+}
+
+/// The vhost-net instances serving the queue pairs of a network device.
+pub struct VhostNetBackend {
+    handles: Vec<VhostNet>,
+    features: u64,
+    // Whether the vrings of each queue pair are set up, i.e. handed over to the kernel.
+    pub(crate) started_pairs: Vec<bool>,
+    // Signaled by the kernel when it adds used buffers to the queue with the same index.
+    pub(crate) call_evts: Vec<EventFd>,
+    pub(crate) state: BackendState,
+}
+
+impl VhostNetBackend {
+    /// Opens a vhost-net instance for each of the `num_queue_pairs` queue pairs.
+    pub fn new(num_queue_pairs: usize) -> Result<Self> {
+        let handles = (0..num_queue_pairs)
+            .map(|_| VhostNet::new())
+            .collect::<Result<Vec<_>>>()?;
+        let features = handles[0].get_features()?;
+        let call_evts = (0..num_queue_pairs * 2)
+            .map(|_| EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(VhostNetBackend {
+            handles,
+            features,
+            started_pairs: vec![false; num_queue_pairs],
+            call_evts,
+            state: BackendState::NotStarted,
+        })
+    }
+
+    /// Provides the virtio features supported by the kernel.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Hands the vrings of the first `active_queue_pairs` queue pairs over to the kernel. The
+    /// other queue pairs are started once the driver enables them.
+    ///
+    /// The `queues` and `queue_evts` are laid out by queue pair, with the rx queue first, and
+    /// `taps` holds the TAP queue of each pair.
+    pub fn start(
+        &mut self,
+        acked_features: u64,
+        queues: &[Queue],
+        queue_evts: &[EventFd],
+        taps: &[&Tap],
+        active_queue_pairs: usize,
+        mem: &GuestMemoryMmap,
+    ) -> Result<()> {
+        for handle in self.handles.iter() {
+            // The offloads are handled by the TAP interface, the kernel is only told about the
+            // features which change the vring layout.
+            handle.set_features(acked_features & self.features)?;
+            handle.set_mem_table(mem)?;
+        }
+        self.state = BackendState::Running;
+        for (pair, tap) in taps.iter().enumerate().take(active_queue_pairs) {
+            self.start_queue_pair(pair, queues, queue_evts, tap, mem)?;
+        }
+        Ok(())
+    }
+
+    /// Hands the vrings of the queue pair `pair` over to the kernel, provided the driver set
+    /// both of them up. The arguments are laid out as for `start`.
+    pub fn start_queue_pair(
+        &mut self,
+        pair: usize,
+        queues: &[Queue],
+        queue_evts: &[EventFd],
+        tap: &Tap,
+        mem: &GuestMemoryMmap,
+    ) -> Result<()> {
+        let (rx_index, tx_index) = (pair * 2 + RX_VRING as usize, pair * 2 + TX_VRING as usize);
+        if self.started_pairs[pair] || !queues[rx_index].ready || !queues[tx_index].ready {
+            return Ok(());
+        }
+
+        let handle = &self.handles[pair];
+        for vring in [RX_VRING, TX_VRING] {
+            let index = pair * 2 + vring as usize;
+            let queue = &queues[index];
+            handle.set_vring_num(vring, queue.actual_size())?;
+            handle.set_vring_addr(vring, queue, mem)?;
+            handle.set_vring_base(vring, queue.next_avail.0)?;
+            handle.set_vring_call(vring, &self.call_evts[index])?;
+            handle.set_vring_kick(vring, &queue_evts[index])?;
+        }
+        // Only start processing the vrings once both of them are set up.
+        self.started_pairs[pair] = true;
+        handle.set_backend(RX_VRING, Some(tap))?;
+        handle.set_backend(TX_VRING, Some(tap))
+    }
+
+    /// Stops the processing of the vrings of the queue pair `pair`, once the driver disabled
+    /// it, and pulls their state back into `queues`.
+    pub fn stop_queue_pair(
+        &mut self,
+        pair: usize,
+        queues: &mut [Queue],
+        mem: &GuestMemoryMmap,
+    ) -> Result<()> {
+        if !self.started_pairs[pair] {
+            return Ok(());
+        }
+        self.started_pairs[pair] = false;
+        self.pull_queue_pair(pair, queues, mem)
+    }
+
+    /// Restarts the processing of the vrings after they have been stopped.
+    pub fn resume(&mut self, taps: &[&Tap]) -> Result<()> {
+        self.state = BackendState::Running;
+        for (pair, tap) in taps.iter().enumerate() {
+            if self.started_pairs[pair] {
+                self.handles[pair].set_backend(RX_VRING, Some(tap))?;
+                self.handles[pair].set_backend(TX_VRING, Some(tap))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops the processing of the vrings and pulls their state back into `queues`, so that
+    /// they can be saved or processed by Firecracker.
+    pub fn stop(&mut self, queues: &mut [Queue], mem: &GuestMemoryMmap) -> Result<()> {
+        self.state = BackendState::Stopped;
+        for pair in 0..self.handles.len() {
+            if self.started_pairs[pair] {
+                self.pull_queue_pair(pair, queues, mem)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Stops the processing of the vrings of the queue pair `pair` and pulls their state back
+    // into `queues`.
+    fn pull_queue_pair(
+        &self,
+        pair: usize,
+        queues: &mut [Queue],
+        mem: &GuestMemoryMmap,
+    ) -> Result<()> {
+        let handle = &self.handles[pair];
+        for vring in [RX_VRING, TX_VRING] {
+            handle.set_backend(vring, None)?;
+
+            let queue = &mut queues[pair * 2 + vring as usize];
+            queue.next_avail.0 = handle.get_vring_base(vring)?;
+            // The kernel completes the in flight buffers when the vring is stopped, and
+            // publishes the used index in the guest memory.
+            queue.next_used.0 = mem
+                .read_obj(queue.used_ring.unchecked_add(2))
+                .map_err(Error::GuestMemory)?;
+            queue.num_added.0 = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether vhost-net can write to `mem` without Firecracker losing track of the dirty
+/// pages.
+pub fn tracks_dirty_pages(mem: &GuestMemoryMmap) -> bool {
+    mem.iter().any(|region| region.bitmap().is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::test_utils::{default_mem, VirtQueue};
+
+    #[test]
+    fn test_ioctl_numbers() {
+        assert_eq!(VHOST_GET_FEATURES(), 0x8008_af00);
+        assert_eq!(VHOST_SET_FEATURES(), 0x4008_af00);
+        assert_eq!(VHOST_SET_OWNER(), 0xaf01);
+        assert_eq!(VHOST_SET_MEM_TABLE(), 0x4008_af03);
+        assert_eq!(VHOST_SET_VRING_NUM(), 0x4008_af10);
+        assert_eq!(VHOST_SET_VRING_ADDR(), 0x4028_af11);
+        assert_eq!(VHOST_SET_VRING_BASE(), 0x4008_af12);
+        assert_eq!(VHOST_GET_VRING_BASE(), 0xc008_af12);
+        assert_eq!(VHOST_SET_VRING_KICK(), 0x4008_af20);
+        assert_eq!(VHOST_SET_VRING_CALL(), 0x4008_af21);
+        assert_eq!(VHOST_NET_SET_BACKEND(), 0x4008_af30);
+    }
+
+    #[test]
+    fn test_vring_setup() {
+        // vhost-net is not available on all the hosts running the unit tests.
+        if !Path::new(VHOST_NET_PATH).exists() {
+            return;
+        }
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut queue = vq.create_queue();
+        queue.next_avail.0 = 3;
+
+        let vhost_net = VhostNet::new().unwrap();
+        assert_ne!(vhost_net.get_features().unwrap(), 0);
+        vhost_net.set_mem_table(&mem).unwrap();
+        vhost_net
+            .set_vring_num(RX_VRING, queue.actual_size())
+            .unwrap();
+        vhost_net.set_vring_addr(RX_VRING, &queue, &mem).unwrap();
+        vhost_net
+            .set_vring_base(RX_VRING, queue.next_avail.0)
+            .unwrap();
+        assert_eq!(vhost_net.get_vring_base(RX_VRING).unwrap(), 3);
+        vhost_net.set_backend(RX_VRING, None).unwrap();
+
+        // The descriptor table is outside of the guest memory.
+        queue.desc_table = GuestAddress(0x10_0000);
+        assert!(matches!(
+            vhost_net.set_vring_addr(RX_VRING, &queue, &mem),
+            Err(Error::GuestMemory(_))
+        ));
+        // There is no such vring.
+        assert!(matches!(
+            vhost_net.set_vring_num(2, 16),
+            Err(Error::IoctlError(_))
+        ));
+    }
+
+    #[test]
+    fn test_tracks_dirty_pages() {
+        assert!(!tracks_dirty_pages(&default_mem()));
+        let mem =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], true)
+                .unwrap();
+        assert!(tracks_dirty_pages(&mem));
+    }
+}
@@ -213,6 +213,7 @@ impl Persist<'_> for MmioTransport {
 
 #[cfg(test)]
 mod tests {
+    use rate_limiter::RateLimiter;
     use utils::tempfile::TempFile;
 
     use super::*;
@@ -410,7 +411,7 @@ mod tests {
         temp_uds_path.remove().unwrap();
         let uds_path = String::from(temp_uds_path.as_path().to_str().unwrap());
         let backend = VsockUnixBackend::new(guest_cid, uds_path).unwrap();
-        let vsock = Vsock::new(guest_cid, backend).unwrap();
+        let vsock = Vsock::new(guest_cid, backend, RateLimiter::default()).unwrap();
         let vsock = Arc::new(Mutex::new(vsock));
         let mmio_transport = MmioTransport::new(mem.clone(), vsock.clone());
 
@@ -0,0 +1,874 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements the frontend side of the
+//! [vhost-user protocol](https://qemu-project.gitlab.io/qemu/interop/vhost-user.html), which
+//! offloads the datapath of a virtio device to a backend running in a separate process.
+//!
+//! The backend maps the guest memory through the file descriptors shared in `SET_MEM_TABLE`, is
+//! notified of new buffers through the vring kick eventfds and signals used buffers through the
+//! vring call eventfds.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use utils::eventfd::EventFd;
+use utils::sock_ctrl_msg::ScmSocket;
+use vm_memory::{
+    Address, ByteValued, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+};
+
+use crate::virtio::Queue;
+
+/// Feature bit advertised by backends which support the protocol features negotiation.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u32 = 30;
+/// The backend supports multiple queues and can report how many through `GET_QUEUE_NUM`.
+pub const VHOST_USER_PROTOCOL_F_MQ: u32 = 0;
+/// The backend acknowledges the requests which have the `NEED_REPLY` flag set.
+pub const VHOST_USER_PROTOCOL_F_REPLY_ACK: u32 = 3;
+/// The backend exposes the device configuration space through `GET_CONFIG`.
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u32 = 9;
+
+// The maximum number of memory regions which can be sent in a `SET_MEM_TABLE` request.
+const MAX_MEM_REGIONS: usize = 8;
+// Header flags.
+const VHOST_USER_VERSION: u32 = 0x1;
+const VHOST_USER_REPLY_FLAG: u32 = 0x4;
+const VHOST_USER_NEED_REPLY_FLAG: u32 = 0x8;
+// A backend stuck on a request must not block the VMM thread forever.
+const REPLY_TIMEOUT_SECS: u64 = 5;
+
+/// Frontend requests defined by the vhost-user protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Request {
+    GetFeatures = 1,
+    SetFeatures = 2,
+    SetOwner = 3,
+    SetMemTable = 5,
+    SetVringNum = 8,
+    SetVringAddr = 9,
+    SetVringBase = 10,
+    GetVringBase = 11,
+    SetVringKick = 12,
+    SetVringCall = 13,
+    GetProtocolFeatures = 15,
+    SetProtocolFeatures = 16,
+    GetQueueNum = 17,
+    SetVringEnable = 18,
+    GetConfig = 24,
+}
+
+impl Request {
+    /// Returns the request matching the `code` from a message header.
+    pub fn from_code(code: u32) -> Option<Self> {
+        use self::Request::*;
+        [
+            GetFeatures,
+            SetFeatures,
+            SetOwner,
+            SetMemTable,
+            SetVringNum,
+            SetVringAddr,
+            SetVringBase,
+            GetVringBase,
+            SetVringKick,
+            SetVringCall,
+            GetProtocolFeatures,
+            SetProtocolFeatures,
+            GetQueueNum,
+            SetVringEnable,
+            GetConfig,
+        ]
+        .iter()
+        .copied()
+        .find(|request| *request as u32 == code)
+    }
+
+    // Requests which have a reply regardless of the `REPLY_ACK` protocol feature.
+    fn has_reply(self) -> bool {
+        matches!(
+            self,
+            Request::GetFeatures
+                | Request::GetProtocolFeatures
+                | Request::GetQueueNum
+                | Request::GetVringBase
+                | Request::GetConfig
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The backend replied with an unexpected message.
+    InvalidReply(Request),
+    /// The memory region is not backed by a file, so it can't be shared with the backend.
+    MemoryRegionNotShared(u64),
+    /// Guest gave us bad memory addresses.
+    GuestMemory(GuestMemoryError),
+    /// Failed to receive a reply from the backend.
+    Recv(io::Error),
+    /// The backend failed to handle the request.
+    RequestFailed(Request, u64),
+    /// Failed to send a request to the backend.
+    Send(io::Error),
+    /// Failed to connect to the backend socket.
+    SocketConnect(io::Error),
+    /// The guest memory has more regions than a `SET_MEM_TABLE` request can describe.
+    TooManyMemoryRegions(usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidReply(request) => write!(f, "Invalid reply to vhost-user {:?}", request),
+            MemoryRegionNotShared(addr) => write!(
+                f,
+                "Guest memory region at {:#x} is not backed by a file",
+                addr
+            ),
+            GuestMemory(err) => write!(f, "Invalid guest memory address: {}", err),
+            Recv(err) => write!(f, "Cannot receive vhost-user reply: {}", err),
+            RequestFailed(request, status) => write!(
+                f,
+                "vhost-user backend failed {:?} with status {}",
+                request, status
+            ),
+            Send(err) => write!(f, "Cannot send vhost-user request: {}", err),
+            SocketConnect(err) => write!(f, "Cannot connect to vhost-user socket: {}", err),
+            TooManyMemoryRegions(count) => write!(
+                f,
+                "Cannot share {} guest memory regions, the maximum is {}",
+                count, MAX_MEM_REGIONS
+            ),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct MessageHeader {
+    pub request: u32,
+    pub flags: u32,
+    pub size: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct VringState {
+    pub index: u32,
+    pub num: u32,
+}
+
+/// Addresses of the vring parts, in the frontend address space.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct VringAddr {
+    pub index: u32,
+    pub flags: u32,
+    pub desc: u64,
+    pub used: u64,
+    pub avail: u64,
+    pub log: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct MemoryHeader {
+    pub num_regions: u32,
+    pub padding: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemoryRegion {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    pub mmap_offset: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct ConfigHeader {
+    pub offset: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+// Safe because all these structures only contain plain data.
+unsafe impl ByteValued for MessageHeader {}
+unsafe impl ByteValued for VringState {}
+unsafe impl ByteValued for VringAddr {}
+unsafe impl ByteValued for MemoryHeader {}
+unsafe impl ByteValued for MemoryRegion {}
+unsafe impl ByteValued for ConfigHeader {}
+
+/// The frontend side of a connection to a vhost-user backend.
+pub struct VhostUserFrontend {
+    stream: UnixStream,
+    // Protocol features negotiated through `SET_PROTOCOL_FEATURES`.
+    protocol_features: u64,
+}
+
+impl VhostUserFrontend {
+    /// Connects to the backend listening at `socket_path`.
+    pub fn connect(socket_path: &str) -> Result<Self> {
+        let stream = UnixStream::connect(socket_path).map_err(Error::SocketConnect)?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(REPLY_TIMEOUT_SECS)))
+            .map_err(Error::SocketConnect)?;
+
+        Ok(VhostUserFrontend {
+            stream,
+            protocol_features: 0,
+        })
+    }
+
+    /// Returns the protocol features negotiated with the backend.
+    pub fn protocol_features(&self) -> u64 {
+        self.protocol_features
+    }
+
+    /// Claims the backend for this frontend.
+    pub fn set_owner(&mut self) -> Result<()> {
+        self.send_request(Request::SetOwner, &[], &[])
+    }
+
+    /// Gets the virtio features offered by the backend.
+    pub fn get_features(&mut self) -> Result<u64> {
+        self.send_request(Request::GetFeatures, &[], &[])?;
+        self.recv_reply(Request::GetFeatures)
+    }
+
+    /// Sets the virtio features acked by the driver.
+    pub fn set_features(&mut self, features: u64) -> Result<()> {
+        self.send_request(Request::SetFeatures, features.as_slice(), &[])
+    }
+
+    /// Gets the protocol features offered by the backend.
+    pub fn get_protocol_features(&mut self) -> Result<u64> {
+        self.send_request(Request::GetProtocolFeatures, &[], &[])?;
+        self.recv_reply(Request::GetProtocolFeatures)
+    }
+
+    /// Sets the protocol features used by the frontend.
+    pub fn set_protocol_features(&mut self, protocol_features: u64) -> Result<()> {
+        self.send_request(
+            Request::SetProtocolFeatures,
+            protocol_features.as_slice(),
+            &[],
+        )?;
+        self.protocol_features = protocol_features;
+        Ok(())
+    }
+
+    /// Gets the maximum number of queues supported by the backend.
+    pub fn get_queue_num(&mut self) -> Result<u64> {
+        self.send_request(Request::GetQueueNum, &[], &[])?;
+        self.recv_reply(Request::GetQueueNum)
+    }
+
+    /// Shares the guest memory with the backend.
+    ///
+    /// All the memory regions must be backed by a file, which is mapped by the backend.
+    pub fn set_mem_table(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        let num_regions = mem.num_regions();
+        if num_regions > MAX_MEM_REGIONS {
+            return Err(Error::TooManyMemoryRegions(num_regions));
+        }
+
+        let header = MemoryHeader {
+            num_regions: num_regions as u32,
+            padding: 0,
+        };
+        let mut payload = header.as_slice().to_vec();
+        let mut fds = Vec::with_capacity(num_regions);
+        for region in mem.iter() {
+            let file_offset = region
+                .file_offset()
+                .ok_or_else(|| Error::MemoryRegionNotShared(region.start_addr().raw_value()))?;
+            let memory_region = MemoryRegion {
+                guest_phys_addr: region.start_addr().raw_value(),
+                memory_size: region.len(),
+                userspace_addr: region.as_ptr() as u64,
+                mmap_offset: file_offset.start(),
+            };
+            payload.extend_from_slice(memory_region.as_slice());
+            fds.push(file_offset.file().as_raw_fd());
+        }
+
+        self.send_request(Request::SetMemTable, &payload, &fds)
+    }
+
+    /// Sets the size of the vring at `index`.
+    pub fn set_vring_num(&mut self, index: usize, num: u16) -> Result<()> {
+        let state = VringState {
+            index: index as u32,
+            num: u32::from(num),
+        };
+        self.send_request(Request::SetVringNum, state.as_slice(), &[])
+    }
+
+    /// Sets the addresses of the descriptor table, available ring and used ring of `queue`.
+    pub fn set_vring_addr(
+        &mut self,
+        index: usize,
+        queue: &Queue,
+        mem: &GuestMemoryMmap,
+    ) -> Result<()> {
+        let host_address = |addr| {
+            mem.get_host_address(addr)
+                .map(|host_addr| host_addr as u64)
+                .map_err(Error::GuestMemory)
+        };
+        let addr = VringAddr {
+            index: index as u32,
+            flags: 0,
+            desc: host_address(queue.desc_table)?,
+            used: host_address(queue.used_ring)?,
+            avail: host_address(queue.avail_ring)?,
+            log: 0,
+        };
+        self.send_request(Request::SetVringAddr, addr.as_slice(), &[])
+    }
+
+    /// Sets the index of the next available descriptor the backend should process.
+    pub fn set_vring_base(&mut self, index: usize, base: u16) -> Result<()> {
+        let state = VringState {
+            index: index as u32,
+            num: u32::from(base),
+        };
+        self.send_request(Request::SetVringBase, state.as_slice(), &[])
+    }
+
+    /// Stops the vring at `index` and returns the index of the next available descriptor.
+    pub fn get_vring_base(&mut self, index: usize) -> Result<u16> {
+        let state = VringState {
+            index: index as u32,
+            num: 0,
+        };
+        self.send_request(Request::GetVringBase, state.as_slice(), &[])?;
+        let state: VringState = self.recv_reply(Request::GetVringBase)?;
+        Ok(state.num as u16)
+    }
+
+    /// Sets the eventfd signaled by the guest when new buffers are available in the vring.
+    pub fn set_vring_kick(&mut self, index: usize, evt: &EventFd) -> Result<()> {
+        self.send_request(
+            Request::SetVringKick,
+            (index as u64).as_slice(),
+            &[evt.as_raw_fd()],
+        )
+    }
+
+    /// Sets the eventfd signaled by the backend when it adds used buffers to the vring.
+    pub fn set_vring_call(&mut self, index: usize, evt: &EventFd) -> Result<()> {
+        self.send_request(
+            Request::SetVringCall,
+            (index as u64).as_slice(),
+            &[evt.as_raw_fd()],
+        )
+    }
+
+    /// Enables or disables the processing of the vring at `index`.
+    pub fn set_vring_enable(&mut self, index: usize, enable: bool) -> Result<()> {
+        let state = VringState {
+            index: index as u32,
+            num: u32::from(enable),
+        };
+        self.send_request(Request::SetVringEnable, state.as_slice(), &[])
+    }
+
+    /// Reads `size` bytes from the start of the device configuration space.
+    pub fn get_config(&mut self, size: u32) -> Result<Vec<u8>> {
+        let header = ConfigHeader {
+            offset: 0,
+            size,
+            flags: 0,
+        };
+        let mut payload = header.as_slice().to_vec();
+        payload.resize(payload.len() + size as usize, 0);
+        self.send_request(Request::GetConfig, &payload, &[])?;
+
+        // The reply has the same layout as the request, with the payload filled in.
+        let reply_header = self.recv_header(Request::GetConfig, payload.len())?;
+        let mut reply = vec![0u8; reply_header.size as usize];
+        self.stream.read_exact(&mut reply).map_err(Error::Recv)?;
+        let config_header = ConfigHeader::from_slice(&reply[..std::mem::size_of::<ConfigHeader>()])
+            .copied()
+            .ok_or(Error::InvalidReply(Request::GetConfig))?;
+        if config_header.size != size {
+            return Err(Error::InvalidReply(Request::GetConfig));
+        }
+
+        Ok(reply.split_off(std::mem::size_of::<ConfigHeader>()))
+    }
+
+    fn send_request(&mut self, request: Request, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+        let need_reply = !request.has_reply()
+            && self.protocol_features & (1 << VHOST_USER_PROTOCOL_F_REPLY_ACK) != 0;
+        let header = MessageHeader {
+            request: request as u32,
+            flags: if need_reply {
+                VHOST_USER_VERSION | VHOST_USER_NEED_REPLY_FLAG
+            } else {
+                VHOST_USER_VERSION
+            },
+            size: payload.len() as u32,
+        };
+
+        let bufs = [header.as_slice(), payload];
+        let sent = self
+            .stream
+            .send_with_fds(&bufs[..], fds)
+            .map_err(|err| Error::Send(io::Error::from_raw_os_error(err.errno())))?;
+        if sent != header.as_slice().len() + payload.len() {
+            return Err(Error::Send(io::Error::from(io::ErrorKind::WriteZero)));
+        }
+
+        if need_reply {
+            let status: u64 = self.recv_reply(request)?;
+            if status != 0 {
+                return Err(Error::RequestFailed(request, status));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn recv_header(&mut self, request: Request, size: usize) -> Result<MessageHeader> {
+        let mut header = MessageHeader::default();
+        self.stream
+            .read_exact(header.as_mut_slice())
+            .map_err(Error::Recv)?;
+        if header.request != request as u32
+            || header.flags & VHOST_USER_REPLY_FLAG == 0
+            || header.size as usize != size
+        {
+            return Err(Error::InvalidReply(request));
+        }
+
+        Ok(header)
+    }
+
+    fn recv_reply<T: ByteValued>(&mut self, request: Request) -> Result<T> {
+        self.recv_header(request, std::mem::size_of::<T>())?;
+        let mut reply = T::default();
+        self.stream
+            .read_exact(reply.as_mut_slice())
+            .map_err(Error::Recv)?;
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+
+    use utils::tempdir::TempDir;
+    use virtio_gen::virtio_blk::VIRTIO_F_VERSION_1;
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::test_utils::VirtQueue;
+
+    /// State of a vring, as configured by the frontend.
+    #[derive(Default)]
+    pub(crate) struct TestVring {
+        pub num: u32,
+        pub addr: VringAddr,
+        pub base: u32,
+        pub kick: Option<File>,
+        pub call: Option<File>,
+        pub enabled: bool,
+    }
+
+    /// State of a `TestBackend`, as configured by the frontend.
+    #[derive(Default)]
+    pub(crate) struct TestBackendState {
+        pub features: u64,
+        pub protocol_features: u64,
+        pub queue_num: u64,
+        pub config: Vec<u8>,
+        pub owned: bool,
+        pub acked_features: u64,
+        pub acked_protocol_features: u64,
+        // Request which the backend fails, if any.
+        pub failing_request: Option<Request>,
+        pub mem_table: Vec<(MemoryRegion, File)>,
+        pub vrings: Vec<TestVring>,
+    }
+
+    impl TestVring {
+        /// Signals the frontend that used buffers were added to the vring.
+        pub fn signal_used(&self) {
+            let mut call = self.call.as_ref().unwrap();
+            call.write_all(&1u64.to_ne_bytes()).unwrap();
+        }
+    }
+
+    /// A vhost-user backend serving a single frontend connection from a separate thread.
+    ///
+    /// It records the configuration received from the frontend, without processing the vrings.
+    pub(crate) struct TestBackend {
+        pub state: Arc<Mutex<TestBackendState>>,
+        socket_path: String,
+        handle: Option<JoinHandle<()>>,
+        _socket_dir: TempDir,
+    }
+
+    impl TestBackend {
+        pub fn new(features: u64, protocol_features: u64, queue_num: u64, config: Vec<u8>) -> Self {
+            let socket_dir = TempDir::new().unwrap();
+            let socket_path = socket_dir
+                .as_path()
+                .join("vhost-user.sock")
+                .to_str()
+                .unwrap()
+                .to_string();
+            let listener = UnixListener::bind(&socket_path).unwrap();
+            let state = Arc::new(Mutex::new(TestBackendState {
+                features,
+                protocol_features,
+                queue_num,
+                config,
+                vrings: (0..queue_num).map(|_| TestVring::default()).collect(),
+                ..Default::default()
+            }));
+
+            let thread_state = state.clone();
+            let handle = thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                while Self::handle_request(&stream, &thread_state) {}
+            });
+
+            TestBackend {
+                state,
+                socket_path,
+                handle: Some(handle),
+                _socket_dir: socket_dir,
+            }
+        }
+
+        pub fn socket_path(&self) -> &str {
+            &self.socket_path
+        }
+
+        // Handles a request from the frontend. Returns `false` once the frontend disconnects.
+        fn handle_request(mut stream: &UnixStream, state: &Mutex<TestBackendState>) -> bool {
+            let mut header = MessageHeader::default();
+            let mut fds = [-1; MAX_MEM_REGIONS];
+            let mut iovecs = [libc::iovec {
+                iov_base: header.as_mut_slice().as_mut_ptr() as *mut libc::c_void,
+                iov_len: std::mem::size_of::<MessageHeader>(),
+            }];
+            let (read, fd_count) = stream.recv_with_fds(&mut iovecs[..], &mut fds).unwrap();
+            if read == 0 {
+                return false;
+            }
+            assert_eq!(read, std::mem::size_of::<MessageHeader>());
+            assert_eq!(header.flags & VHOST_USER_VERSION, VHOST_USER_VERSION);
+            // Safe because we own the received fds.
+            let mut files = fds[..fd_count]
+                .iter()
+                .map(|fd| unsafe { File::from_raw_fd(*fd) })
+                .collect::<Vec<_>>();
+
+            let mut payload = vec![0u8; header.size as usize];
+            stream.read_exact(&mut payload).unwrap();
+            let vring_state = || *VringState::from_slice(&payload[..8]).unwrap();
+
+            let request = Request::from_code(header.request).unwrap();
+            let mut state = state.lock().unwrap();
+            let reply = match request {
+                Request::GetFeatures => Some(state.features.as_slice().to_vec()),
+                Request::SetFeatures => {
+                    state.acked_features = *u64::from_slice(&payload).unwrap();
+                    None
+                }
+                Request::SetOwner => {
+                    state.owned = true;
+                    None
+                }
+                Request::GetProtocolFeatures => Some(state.protocol_features.as_slice().to_vec()),
+                Request::SetProtocolFeatures => {
+                    state.acked_protocol_features = *u64::from_slice(&payload).unwrap();
+                    None
+                }
+                Request::GetQueueNum => Some(state.queue_num.as_slice().to_vec()),
+                Request::SetMemTable => {
+                    let header_len = std::mem::size_of::<MemoryHeader>();
+                    let region_len = std::mem::size_of::<MemoryRegion>();
+                    let header = MemoryHeader::from_slice(&payload[..header_len]).unwrap();
+                    assert_eq!(header.num_regions as usize, files.len());
+                    state.mem_table = payload[header_len..]
+                        .chunks(region_len)
+                        .map(|chunk| *MemoryRegion::from_slice(chunk).unwrap())
+                        .zip(files.drain(..))
+                        .collect();
+                    None
+                }
+                Request::SetVringNum => {
+                    let vring_state = vring_state();
+                    state.vrings[vring_state.index as usize].num = vring_state.num;
+                    None
+                }
+                Request::SetVringAddr => {
+                    let addr = *VringAddr::from_slice(&payload).unwrap();
+                    state.vrings[addr.index as usize].addr = addr;
+                    None
+                }
+                Request::SetVringBase => {
+                    let vring_state = vring_state();
+                    state.vrings[vring_state.index as usize].base = vring_state.num;
+                    None
+                }
+                Request::GetVringBase => {
+                    let mut vring_state = vring_state();
+                    let vring = &mut state.vrings[vring_state.index as usize];
+                    vring.enabled = false;
+                    vring_state.num = vring.base;
+                    Some(vring_state.as_slice().to_vec())
+                }
+                Request::SetVringKick => {
+                    let index = *u64::from_slice(&payload).unwrap() as usize;
+                    state.vrings[index].kick = files.pop();
+                    None
+                }
+                Request::SetVringCall => {
+                    let index = *u64::from_slice(&payload).unwrap() as usize;
+                    state.vrings[index].call = files.pop();
+                    None
+                }
+                Request::SetVringEnable => {
+                    let vring_state = vring_state();
+                    state.vrings[vring_state.index as usize].enabled = vring_state.num == 1;
+                    None
+                }
+                Request::GetConfig => {
+                    let config_header = ConfigHeader::from_slice(&payload[..12]).unwrap();
+                    let mut reply = payload[..12].to_vec();
+                    reply.extend_from_slice(&state.config[..config_header.size as usize]);
+                    Some(reply)
+                }
+            };
+
+            // Requests without a reply of their own are only acknowledged when the frontend
+            // asks for it, which tells it whether they failed.
+            let reply = match reply {
+                Some(reply) => Some(reply),
+                None if header.flags & VHOST_USER_NEED_REPLY_FLAG != 0 => {
+                    let status = u64::from(state.failing_request == Some(request));
+                    Some(status.as_slice().to_vec())
+                }
+                None => None,
+            };
+            if let Some(reply) = reply {
+                let reply_header = MessageHeader {
+                    request: header.request,
+                    flags: VHOST_USER_VERSION | VHOST_USER_REPLY_FLAG,
+                    size: reply.len() as u32,
+                };
+                stream.write_all(reply_header.as_slice()).unwrap();
+                stream.write_all(&reply).unwrap();
+            }
+
+            true
+        }
+    }
+
+    impl Drop for TestBackend {
+        fn drop(&mut self) {
+            // Unblock the thread if it still waits for the frontend to connect.
+            if Arc::strong_count(&self.state) > 1 {
+                let _ = UnixStream::connect(&self.socket_path);
+            }
+            if let Some(handle) = self.handle.take() {
+                let _ = handle.join();
+            }
+        }
+    }
+
+    pub(crate) fn default_backend() -> TestBackend {
+        TestBackend::new(
+            1 << VIRTIO_F_VERSION_1 | 1 << VHOST_USER_F_PROTOCOL_FEATURES,
+            1 << VHOST_USER_PROTOCOL_F_MQ
+                | 1 << VHOST_USER_PROTOCOL_F_REPLY_ACK
+                | 1 << VHOST_USER_PROTOCOL_F_CONFIG,
+            2,
+            vec![0xaa; 64],
+        )
+    }
+
+    #[test]
+    fn test_request_from_code() {
+        assert_eq!(Request::from_code(1), Some(Request::GetFeatures));
+        assert_eq!(Request::from_code(24), Some(Request::GetConfig));
+        assert_eq!(Request::from_code(4), None);
+        assert!(Request::GetVringBase.has_reply());
+        assert!(!Request::SetVringBase.has_reply());
+    }
+
+    #[test]
+    fn test_connect() {
+        let socket_dir = TempDir::new().unwrap();
+        let socket_path = socket_dir.as_path().join("missing.sock");
+        assert!(matches!(
+            VhostUserFrontend::connect(socket_path.to_str().unwrap()),
+            Err(Error::SocketConnect(_))
+        ));
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let backend = default_backend();
+        let mut frontend = VhostUserFrontend::connect(backend.socket_path()).unwrap();
+
+        frontend.set_owner().unwrap();
+        assert_eq!(
+            frontend.get_features().unwrap(),
+            1 << VIRTIO_F_VERSION_1 | 1 << VHOST_USER_F_PROTOCOL_FEATURES
+        );
+        let protocol_features = frontend.get_protocol_features().unwrap();
+        frontend.set_protocol_features(protocol_features).unwrap();
+        assert_eq!(frontend.protocol_features(), protocol_features);
+        frontend.set_features(1 << VIRTIO_F_VERSION_1).unwrap();
+        assert_eq!(frontend.get_queue_num().unwrap(), 2);
+        assert_eq!(frontend.get_config(60).unwrap(), vec![0xaa; 60]);
+
+        let state = backend.state.lock().unwrap();
+        assert!(state.owned);
+        assert_eq!(state.acked_features, 1 << VIRTIO_F_VERSION_1);
+        assert_eq!(state.acked_protocol_features, protocol_features);
+    }
+
+    #[test]
+    fn test_set_mem_table() {
+        let backend = default_backend();
+        let mut frontend = VhostUserFrontend::connect(backend.socket_path()).unwrap();
+
+        // Anonymous memory can't be shared with the backend.
+        let mem =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false)
+                .unwrap();
+        assert!(matches!(
+            frontend.set_mem_table(&mem),
+            Err(Error::MemoryRegionNotShared(0))
+        ));
+
+        let mem = vm_memory::create_memfd_guest_memory(
+            &[(GuestAddress(0), 0x10000), (GuestAddress(0x20000), 0x10000)],
+            false,
+            None,
+        )
+        .unwrap();
+        frontend
+            .set_protocol_features(1 << VHOST_USER_PROTOCOL_F_REPLY_ACK)
+            .unwrap();
+        frontend.set_mem_table(&mem).unwrap();
+
+        let state = backend.state.lock().unwrap();
+        assert_eq!(state.mem_table.len(), 2);
+        for ((region, file), mem_region) in state.mem_table.iter().zip(mem.iter()) {
+            assert_eq!(region.guest_phys_addr, mem_region.start_addr().raw_value());
+            assert_eq!(region.memory_size, 0x10000);
+            assert_eq!(region.userspace_addr, mem_region.as_ptr() as u64);
+            assert_eq!(
+                region.mmap_offset,
+                mem_region.file_offset().unwrap().start()
+            );
+            assert_eq!(file.metadata().unwrap().len(), 0x20000);
+        }
+    }
+
+    #[test]
+    fn test_vring_setup() {
+        let backend = default_backend();
+        let mut frontend = VhostUserFrontend::connect(backend.socket_path()).unwrap();
+        frontend
+            .set_protocol_features(1 << VHOST_USER_PROTOCOL_F_REPLY_ACK)
+            .unwrap();
+
+        let mem = vm_memory::create_memfd_guest_memory(&[(GuestAddress(0), 0x10000)], false, None)
+            .unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let queue = vq.create_queue();
+        let kick_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let call_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+
+        frontend.set_vring_num(1, queue.actual_size()).unwrap();
+        frontend.set_vring_addr(1, &queue, &mem).unwrap();
+        frontend.set_vring_base(1, 3).unwrap();
+        frontend.set_vring_kick(1, &kick_evt).unwrap();
+        frontend.set_vring_call(1, &call_evt).unwrap();
+        frontend.set_vring_enable(1, true).unwrap();
+
+        {
+            let state = backend.state.lock().unwrap();
+            let vring = &state.vrings[1];
+            assert_eq!(vring.num, 16);
+            assert_eq!(
+                vring.addr.desc,
+                mem.get_host_address(queue.desc_table).unwrap() as u64
+            );
+            assert_eq!(
+                vring.addr.avail,
+                mem.get_host_address(queue.avail_ring).unwrap() as u64
+            );
+            assert_eq!(
+                vring.addr.used,
+                mem.get_host_address(queue.used_ring).unwrap() as u64
+            );
+            assert_eq!(vring.base, 3);
+            assert!(vring.enabled);
+
+            // The backend received the eventfds.
+            kick_evt.write(1).unwrap();
+            let mut buf = [0u8; 8];
+            let mut kick = vring.kick.as_ref().unwrap();
+            kick.read_exact(&mut buf).unwrap();
+            assert_eq!(u64::from_ne_bytes(buf), 1);
+            vring.signal_used();
+            assert_eq!(call_evt.read().unwrap(), 1);
+        }
+
+        assert_eq!(frontend.get_vring_base(1).unwrap(), 3);
+        assert!(!backend.state.lock().unwrap().vrings[1].enabled);
+    }
+
+    #[test]
+    fn test_request_failed() {
+        let backend = default_backend();
+        backend.state.lock().unwrap().failing_request = Some(Request::SetFeatures);
+        let mut frontend = VhostUserFrontend::connect(backend.socket_path()).unwrap();
+
+        // Without `REPLY_ACK`, the frontend doesn't know that the request failed, and the next
+        // reply is still the one of the next request.
+        frontend.set_features(0).unwrap();
+        assert_eq!(
+            frontend.get_features().unwrap(),
+            backend.state.lock().unwrap().features
+        );
+
+        frontend
+            .set_protocol_features(1 << VHOST_USER_PROTOCOL_F_REPLY_ACK)
+            .unwrap();
+        assert!(matches!(
+            frontend.set_features(0),
+            Err(Error::RequestFailed(Request::SetFeatures, 1))
+        ));
+        frontend.set_owner().unwrap();
+    }
+}
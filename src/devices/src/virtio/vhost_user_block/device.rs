@@ -0,0 +1,543 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cmp;
+use std::io::Write;
+use std::result;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use logger::{error, IncMetric, METRICS};
+use utils::eventfd::EventFd;
+use virtio_gen::virtio_blk::{
+    VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_GEOMETRY,
+    VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_SIZE_MAX,
+    VIRTIO_BLK_F_TOPOLOGY, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_F_VERSION_1,
+};
+use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use vm_memory::GuestMemoryMmap;
+
+use super::Error;
+use crate::virtio::block::{CacheType, CONFIG_SPACE_SIZE, MAX_NUM_QUEUES, QUEUE_SIZE};
+use crate::virtio::vhost_user::{
+    self, VhostUserFrontend, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG,
+    VHOST_USER_PROTOCOL_F_MQ, VHOST_USER_PROTOCOL_F_REPLY_ACK,
+};
+use crate::virtio::{
+    ActivateError, ActivateResult, DeviceState, IrqTrigger, IrqType, Queue, VirtioDevice,
+    TYPE_BLOCK,
+};
+
+// The virtio features which are passed through from the backend to the guest.
+const PASSTHROUGH_FEATURES: u64 = 1 << VIRTIO_F_VERSION_1
+    | 1 << VIRTIO_RING_F_EVENT_IDX
+    | 1 << VIRTIO_BLK_F_SIZE_MAX
+    | 1 << VIRTIO_BLK_F_SEG_MAX
+    | 1 << VIRTIO_BLK_F_GEOMETRY
+    | 1 << VIRTIO_BLK_F_RO
+    | 1 << VIRTIO_BLK_F_BLK_SIZE
+    | 1 << VIRTIO_BLK_F_FLUSH
+    | 1 << VIRTIO_BLK_F_TOPOLOGY
+    | 1 << VIRTIO_BLK_F_MQ
+    | 1 << VIRTIO_BLK_F_DISCARD
+    | 1 << VIRTIO_BLK_F_WRITE_ZEROES;
+const PROTOCOL_FEATURES: u64 = 1 << VHOST_USER_PROTOCOL_F_MQ
+    | 1 << VHOST_USER_PROTOCOL_F_REPLY_ACK
+    | 1 << VHOST_USER_PROTOCOL_F_CONFIG;
+// Offset of the `num_queues` field in the virtio block configuration space.
+const NUM_QUEUES_CONFIG_OFFSET: usize = 34;
+
+/// Virtio device exposing a block device served by a vhost-user backend.
+///
+/// Firecracker only sets up the backend on activation and forwards its used buffer
+/// notifications to the guest, the requests themselves never go through the VMM.
+pub struct VhostUserBlock {
+    // Backend connection and properties.
+    frontend: VhostUserFrontend,
+    socket_path: String,
+    backend_features: u64,
+    cache_type: CacheType,
+
+    // Virtio fields.
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    config_space: Vec<u8>,
+    pub(crate) activate_evt: EventFd,
+
+    // Transport related fields.
+    pub(crate) queues: Vec<Queue>,
+    pub(crate) queue_evts: Vec<EventFd>,
+    // Signaled by the backend when it adds used buffers to the queue with the same index.
+    pub(crate) call_evts: Vec<EventFd>,
+    pub(crate) device_state: DeviceState,
+    pub(crate) irq_trigger: IrqTrigger,
+
+    // Implementation specific fields.
+    pub(crate) id: String,
+    pub(crate) partuuid: Option<String>,
+    pub(crate) root_device: bool,
+}
+
+impl VhostUserBlock {
+    /// Create a new virtio block device served by the vhost-user backend listening at
+    /// `socket_path`.
+    pub fn new(
+        id: String,
+        partuuid: Option<String>,
+        cache_type: CacheType,
+        socket_path: String,
+        is_disk_read_only: bool,
+        is_disk_root: bool,
+        num_queues: u16,
+    ) -> result::Result<VhostUserBlock, Error> {
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+
+        let mut frontend = VhostUserFrontend::connect(&socket_path).map_err(Error::VhostUser)?;
+        frontend.set_owner().map_err(Error::VhostUser)?;
+        let backend_features = frontend.get_features().map_err(Error::VhostUser)?;
+        if backend_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::MissingFeature("VIRTIO_F_VERSION_1"));
+        }
+        if backend_features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) == 0 {
+            return Err(Error::MissingFeature("VHOST_USER_F_PROTOCOL_FEATURES"));
+        }
+
+        let protocol_features =
+            frontend.get_protocol_features().map_err(Error::VhostUser)? & PROTOCOL_FEATURES;
+        frontend
+            .set_protocol_features(protocol_features)
+            .map_err(Error::VhostUser)?;
+        // The capacity of the disk is only known to the backend.
+        if protocol_features & (1 << VHOST_USER_PROTOCOL_F_CONFIG) == 0 {
+            return Err(Error::MissingFeature("VHOST_USER_PROTOCOL_F_CONFIG"));
+        }
+
+        let mut avail_features = backend_features & PASSTHROUGH_FEATURES & !(1 << VIRTIO_BLK_F_MQ);
+        if num_queues > 1 {
+            if protocol_features & (1 << VHOST_USER_PROTOCOL_F_MQ) == 0
+                || backend_features & (1 << VIRTIO_BLK_F_MQ) == 0
+            {
+                return Err(Error::MissingFeature("VIRTIO_BLK_F_MQ"));
+            }
+            if frontend.get_queue_num().map_err(Error::VhostUser)? < u64::from(num_queues) {
+                return Err(Error::InvalidNumQueues(num_queues));
+            }
+            avail_features |= 1 << VIRTIO_BLK_F_MQ;
+        }
+        if cache_type == CacheType::Unsafe {
+            avail_features &= !(1 << VIRTIO_BLK_F_FLUSH);
+        }
+        if is_disk_read_only {
+            avail_features |= 1 << VIRTIO_BLK_F_RO;
+        }
+
+        let mut config_space = frontend
+            .get_config(CONFIG_SPACE_SIZE as u32)
+            .map_err(Error::VhostUser)?;
+        config_space[NUM_QUEUES_CONFIG_OFFSET..NUM_QUEUES_CONFIG_OFFSET + 2]
+            .copy_from_slice(&num_queues.to_le_bytes());
+
+        let new_evt = || EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd);
+        let queue_evts = (0..num_queues)
+            .map(|_| new_evt())
+            .collect::<result::Result<Vec<_>, _>>()?;
+        let call_evts = (0..num_queues)
+            .map(|_| new_evt())
+            .collect::<result::Result<Vec<_>, _>>()?;
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
+
+        Ok(VhostUserBlock {
+            frontend,
+            socket_path,
+            backend_features,
+            cache_type,
+            avail_features,
+            acked_features: 0u64,
+            config_space,
+            activate_evt: new_evt()?,
+            queues,
+            queue_evts,
+            call_evts,
+            device_state: DeviceState::Inactive,
+            irq_trigger: IrqTrigger::new().map_err(Error::EventFd)?,
+            id,
+            partuuid,
+            root_device: is_disk_root,
+        })
+    }
+
+    /// Shares the guest memory and the vrings with the backend, which starts processing them.
+    pub(crate) fn setup_backend(&mut self) -> result::Result<(), vhost_user::Error> {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return Ok(()),
+        };
+
+        // Features which are only offered to the guest (e.g. `VIRTIO_BLK_F_RO`) are not acked
+        // to the backend.
+        self.frontend.set_features(
+            self.acked_features & self.backend_features | 1 << VHOST_USER_F_PROTOCOL_FEATURES,
+        )?;
+        self.frontend.set_mem_table(mem)?;
+        for (index, queue) in self.queues.iter().enumerate() {
+            if !queue.ready {
+                continue;
+            }
+            self.frontend.set_vring_num(index, queue.actual_size())?;
+            self.frontend.set_vring_addr(index, queue, mem)?;
+            self.frontend.set_vring_base(index, queue.next_avail.0)?;
+            self.frontend
+                .set_vring_call(index, &self.call_evts[index])?;
+            self.frontend
+                .set_vring_kick(index, &self.queue_evts[index])?;
+            self.frontend.set_vring_enable(index, true)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn process_call_event(&mut self, queue_index: usize) {
+        if let Err(err) = self.call_evts[queue_index].read() {
+            error!("Failed to get vhost-user-blk call event: {:?}", err);
+            METRICS.vhost_user_block.event_fails.inc();
+            return;
+        }
+
+        if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+            error!("Failed to signal used queue: {:?}", err);
+            METRICS.vhost_user_block.event_fails.inc();
+        }
+    }
+
+    /// Provides the ID of this block device.
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    /// Provides the path of the vhost-user socket of this block device.
+    pub fn socket_path(&self) -> &String {
+        &self.socket_path
+    }
+
+    /// Provides the PARTUUID of this block device.
+    pub fn partuuid(&self) -> Option<&String> {
+        self.partuuid.as_ref()
+    }
+
+    /// Specifies if this block device is read only.
+    pub fn is_read_only(&self) -> bool {
+        self.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0
+    }
+
+    /// Specifies if this block device is the root device.
+    pub fn is_root_device(&self) -> bool {
+        self.root_device
+    }
+
+    /// Specifies block device cache type.
+    pub fn cache_type(&self) -> CacheType {
+        self.cache_type
+    }
+
+    /// Provides the number of queues of this block device.
+    pub fn num_queues(&self) -> u16 {
+        self.queues.len() as u16
+    }
+}
+
+impl VirtioDevice for VhostUserBlock {
+    fn device_type(&self) -> u32 {
+        TYPE_BLOCK
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_evts
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.irq_trigger.irq_evt
+    }
+
+    /// Returns the current device interrupt status.
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.irq_trigger.irq_status.clone()
+    }
+
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_len = self.config_space.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            METRICS.vhost_user_block.cfg_fails.inc();
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&self.config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {
+        // The configuration space is owned by the backend and none of the writable fields are
+        // offered to the guest.
+        error!("Failed to write config space");
+        METRICS.vhost_user_block.cfg_fails.inc();
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        // The backend is set up from the VMM thread, when handling the activate event.
+        if self.activate_evt.write(1).is_err() {
+            error!("vhost-user-blk: Cannot write to activate_evt");
+            return Err(ActivateError::BadActivate);
+        }
+        self.device_state = DeviceState::Activated(mem);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Read;
+
+    use vm_memory::{GuestAddress, GuestMemory};
+
+    use super::*;
+    use crate::virtio::test_utils::VirtQueue;
+    use crate::virtio::vhost_user::tests::TestBackend;
+
+    pub(crate) const TEST_CAPACITY: u64 = 0x1000;
+
+    pub(crate) fn block_backend(features: u64, protocol_features: u64) -> TestBackend {
+        let mut config = vec![0u8; CONFIG_SPACE_SIZE];
+        config[..8].copy_from_slice(&TEST_CAPACITY.to_le_bytes());
+        TestBackend::new(features, protocol_features, 2, config)
+    }
+
+    pub(crate) fn default_block_backend() -> TestBackend {
+        block_backend(
+            1 << VIRTIO_F_VERSION_1
+                | 1 << VHOST_USER_F_PROTOCOL_FEATURES
+                | 1 << VIRTIO_RING_F_EVENT_IDX
+                | 1 << VIRTIO_BLK_F_FLUSH
+                | 1 << VIRTIO_BLK_F_MQ,
+            PROTOCOL_FEATURES,
+        )
+    }
+
+    pub(crate) fn default_vhost_user_block(backend: &TestBackend) -> VhostUserBlock {
+        VhostUserBlock::new(
+            "test".to_string(),
+            None,
+            CacheType::Writeback,
+            backend.socket_path().to_string(),
+            false,
+            false,
+            1,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_new() {
+        let new_block = |socket_path: &str, num_queues| {
+            VhostUserBlock::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                socket_path.to_string(),
+                false,
+                false,
+                num_queues,
+            )
+        };
+
+        let backend = default_block_backend();
+        for num_queues in [0, MAX_NUM_QUEUES + 1] {
+            assert!(matches!(
+                new_block(backend.socket_path(), num_queues),
+                Err(Error::InvalidNumQueues(n)) if n == num_queues
+            ));
+        }
+        assert!(matches!(
+            new_block("/invalid/socket/path", 1),
+            Err(Error::VhostUser(vhost_user::Error::SocketConnect(_)))
+        ));
+
+        let backend = block_backend(1 << VHOST_USER_F_PROTOCOL_FEATURES, PROTOCOL_FEATURES);
+        assert!(matches!(
+            new_block(backend.socket_path(), 1),
+            Err(Error::MissingFeature("VIRTIO_F_VERSION_1"))
+        ));
+        let backend = block_backend(1 << VIRTIO_F_VERSION_1, PROTOCOL_FEATURES);
+        assert!(matches!(
+            new_block(backend.socket_path(), 1),
+            Err(Error::MissingFeature("VHOST_USER_F_PROTOCOL_FEATURES"))
+        ));
+        let backend = block_backend(
+            1 << VIRTIO_F_VERSION_1 | 1 << VHOST_USER_F_PROTOCOL_FEATURES,
+            1 << VHOST_USER_PROTOCOL_F_MQ,
+        );
+        assert!(matches!(
+            new_block(backend.socket_path(), 1),
+            Err(Error::MissingFeature("VHOST_USER_PROTOCOL_F_CONFIG"))
+        ));
+        let backend = block_backend(
+            1 << VIRTIO_F_VERSION_1 | 1 << VHOST_USER_F_PROTOCOL_FEATURES,
+            PROTOCOL_FEATURES,
+        );
+        assert!(matches!(
+            new_block(backend.socket_path(), 2),
+            Err(Error::MissingFeature("VIRTIO_BLK_F_MQ"))
+        ));
+
+        // The test backend supports 2 queues.
+        let backend = default_block_backend();
+        assert!(matches!(
+            new_block(backend.socket_path(), 3),
+            Err(Error::InvalidNumQueues(3))
+        ));
+        let backend = default_block_backend();
+        let block = new_block(backend.socket_path(), 2).unwrap();
+        assert_eq!(block.num_queues(), 2);
+        assert_eq!(block.queue_events().len(), 2);
+        assert_eq!(block.call_evts.len(), 2);
+        assert!(block.avail_features() & (1 << VIRTIO_BLK_F_MQ) != 0);
+        assert_eq!(block.socket_path(), backend.socket_path());
+        assert!(backend.state.lock().unwrap().owned);
+    }
+
+    #[test]
+    fn test_features() {
+        let backend = block_backend(
+            1 << VIRTIO_F_VERSION_1
+                | 1 << VHOST_USER_F_PROTOCOL_FEATURES
+                | 1 << VIRTIO_BLK_F_FLUSH
+                | 1 << VIRTIO_BLK_F_MQ
+                // Not supported by Firecracker.
+                | 1 << 7,
+            PROTOCOL_FEATURES,
+        );
+        let block = default_vhost_user_block(&backend);
+        assert_eq!(
+            block.avail_features(),
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_BLK_F_FLUSH
+        );
+        assert!(!block.is_read_only());
+        assert_eq!(block.cache_type(), CacheType::Writeback);
+        drop(block);
+
+        let backend = block_backend(
+            1 << VIRTIO_F_VERSION_1 | 1 << VHOST_USER_F_PROTOCOL_FEATURES | 1 << VIRTIO_BLK_F_FLUSH,
+            PROTOCOL_FEATURES,
+        );
+        let block = VhostUserBlock::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            backend.socket_path().to_string(),
+            true,
+            true,
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            block.avail_features(),
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_BLK_F_RO
+        );
+        assert!(block.is_read_only());
+        assert!(block.is_root_device());
+    }
+
+    #[test]
+    fn test_config_space() {
+        let backend = default_block_backend();
+        let mut block = default_vhost_user_block(&backend);
+
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), TEST_CAPACITY);
+        let mut num_queues = [0u8; 2];
+        block.read_config(NUM_QUEUES_CONFIG_OFFSET as u64, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), 1);
+
+        // Reads past the end of the config space are ignored.
+        let mut data = [0xffu8; 4];
+        block.read_config(CONFIG_SPACE_SIZE as u64, &mut data);
+        assert_eq!(data, [0xff; 4]);
+
+        // Writes are ignored.
+        block.write_config(0, &[0u8; 8]);
+        block.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), TEST_CAPACITY);
+    }
+
+    #[test]
+    fn test_setup_backend() {
+        let backend = default_block_backend();
+        let mut block = default_vhost_user_block(&backend);
+        let mem = vm_memory::create_memfd_guest_memory(&[(GuestAddress(0), 0x10000)], false, None)
+            .unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        block.queues[0] = vq.create_queue();
+        block.set_acked_features(block.avail_features());
+
+        block.activate(mem.clone()).unwrap();
+        assert!(block.is_activated());
+        block.setup_backend().unwrap();
+
+        let state = backend.state.lock().unwrap();
+        assert_eq!(
+            state.acked_features,
+            block.avail_features() | 1 << VHOST_USER_F_PROTOCOL_FEATURES
+        );
+        assert_eq!(state.acked_protocol_features, PROTOCOL_FEATURES);
+        assert_eq!(state.mem_table.len(), 1);
+        assert_eq!(state.mem_table[0].0.memory_size, 0x10000);
+        let vring = &state.vrings[0];
+        assert_eq!(vring.num, 16);
+        assert_eq!(
+            vring.addr.desc,
+            mem.get_host_address(vq.dtable_start()).unwrap() as u64
+        );
+        assert_eq!(vring.base, 0);
+        assert!(vring.enabled);
+
+        // The guest kicks reach the backend.
+        block.queue_evts[0].write(1).unwrap();
+        let mut buf = [0u8; 8];
+        let mut kick = vring.kick.as_ref().unwrap();
+        kick.read_exact(&mut buf).unwrap();
+        assert_eq!(u64::from_ne_bytes(buf), 1);
+
+        // The backend signals used buffers through the call eventfd.
+        vring.signal_used();
+        block.process_call_event(0);
+        assert!(block.irq_trigger.has_pending_irq(IrqType::Vring));
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+use std::os::unix::io::AsRawFd;
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use logger::{debug, error, warn, IncMetric, METRICS};
+use utils::epoll::EventSet;
+
+use super::device::VhostUserBlock;
+use crate::virtio::VirtioDevice;
+
+impl VhostUserBlock {
+    fn register_runtime_events(&self, ops: &mut EventOps) {
+        // The queue events are consumed by the backend, only the used buffer notifications go
+        // through the VMM.
+        for call_evt in &self.call_evts {
+            if let Err(err) = ops.add(Events::new(call_evt, EventSet::IN)) {
+                error!("Failed to register call event: {}", err);
+            }
+        }
+    }
+
+    fn register_activate_event(&self, ops: &mut EventOps) {
+        if let Err(err) = ops.add(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to register activate event: {}", err);
+        }
+    }
+
+    fn process_activate_event(&mut self, ops: &mut EventOps) {
+        debug!("vhost-user-blk: activate event");
+        if let Err(err) = self.activate_evt.read() {
+            error!("Failed to consume vhost-user-blk activate event: {:?}", err);
+        }
+        if let Err(err) = self.setup_backend() {
+            error!("Failed to set up the vhost-user-blk backend: {}", err);
+            METRICS.vhost_user_block.activate_fails.inc();
+        }
+        self.register_runtime_events(ops);
+        if let Err(err) = ops.remove(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to un-register activate event: {}", err);
+        }
+    }
+}
+
+impl MutEventSubscriber for VhostUserBlock {
+    // Handle an event for the activation or a used buffer notification from the backend.
+    fn process(&mut self, event: Events, ops: &mut EventOps) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        let supported_events = EventSet::IN;
+        if !supported_events.contains(event_set) {
+            warn!(
+                "vhost-user-blk: Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if self.is_activated() {
+            let activate_fd = self.activate_evt.as_raw_fd();
+            let maybe_queue_index = self
+                .call_evts
+                .iter()
+                .position(|call_evt| call_evt.as_raw_fd() == source);
+
+            match maybe_queue_index {
+                _ if activate_fd == source => self.process_activate_event(ops),
+                Some(queue_index) => self.process_call_event(queue_index),
+                None => warn!("vhost-user-blk: Spurious event received: {:?}", source),
+            }
+        } else {
+            warn!(
+                "vhost-user-blk: The device is not yet activated. Spurious event received: {:?}",
+                source
+            );
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        if self.is_activated() {
+            self.register_runtime_events(ops);
+        } else {
+            self.register_activate_event(ops);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use event_manager::{EventManager, SubscriberOps};
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::test_utils::VirtQueue;
+    use crate::virtio::vhost_user_block::device::tests::{
+        default_block_backend, default_vhost_user_block,
+    };
+    use crate::virtio::IrqType;
+
+    #[test]
+    fn test_event_handler() {
+        let backend = default_block_backend();
+        let mut event_manager = EventManager::new().unwrap();
+        let mut block = default_vhost_user_block(&backend);
+        let mem = vm_memory::create_memfd_guest_memory(&[(GuestAddress(0), 0x10000)], false, None)
+            .unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        block.queues[0] = vq.create_queue();
+
+        let block = Arc::new(Mutex::new(block));
+        let _id = event_manager.add_subscriber(block.clone());
+
+        // The backend isn't set up before the device is activated.
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 0);
+        assert!(backend.state.lock().unwrap().vrings[0].call.is_none());
+
+        // Now activate the device.
+        block.lock().unwrap().activate(mem.clone()).unwrap();
+        // Process the activate event.
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 1);
+        assert!(backend.state.lock().unwrap().vrings[0].enabled);
+
+        // Forward a used buffer notification to the guest.
+        backend.state.lock().unwrap().vrings[0].signal_used();
+        let ev_count = event_manager.run_with_timeout(50).unwrap();
+        assert_eq!(ev_count, 1);
+        assert!(block
+            .lock()
+            .unwrap()
+            .irq_trigger
+            .has_pending_irq(IrqType::Vring));
+    }
+}
@@ -0,0 +1,23 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements a virtio block device whose requests are served by a vhost-user backend.
+
+pub mod device;
+pub mod event_handler;
+
+pub use self::device::VhostUserBlock;
+pub use self::event_handler::*;
+use crate::virtio::vhost_user;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Error opening eventfd.
+    EventFd(std::io::Error),
+    /// The number of queues is either zero or larger than what the backend supports.
+    InvalidNumQueues(u16),
+    /// The backend doesn't offer a feature required by the device.
+    MissingFeature(&'static str),
+    /// Error communicating with the vhost-user backend.
+    VhostUser(vhost_user::Error),
+}
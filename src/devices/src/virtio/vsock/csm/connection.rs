@@ -13,6 +13,15 @@
 /// - `VsockEpollListener` for getting notified about the availability of data or free buffer
 ///   space at the host stream.
 ///
+/// Seqpacket connections go through the same state machine, with a message-oriented host
+/// stream (e.g. a SOCK_SEQPACKET Unix socket). Message boundaries are kept in both directions:
+/// TX data is buffered until the packet marked with VSOCK_FLAGS_SEQ_EOM (or VSOCK_FLAGS_SEQ_EOR)
+/// arrives, and is then written out with a single write, while RX messages are read whole and
+/// split across as many packets as needed, the last of them marked with both
+/// VSOCK_FLAGS_SEQ_EOM and VSOCK_FLAGS_SEQ_EOR, since every message of a SOCK_SEQPACKET socket
+/// is a record of its own. Zero-length messages go through as empty packets, while messages
+/// larger than `defs::CONN_RX_MSG_MAX_SIZE` get the connection reset.
+///
 /// Note: there is a certain asymmetry to the RX and TX data flows:
 ///       - RX transfers do not need any data buffering, since data is read straight from the
 ///         host stream and into the guest-provided RX buffer;
@@ -84,14 +93,16 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::time::{Duration, Instant};
 
 use logger::{debug, error, info, warn, IncMetric, METRICS};
+use snapshot::Persist;
 use utils::epoll::EventSet;
 use vm_memory::{GuestMemoryError, GuestMemoryMmap};
 
 use super::super::defs::uapi;
 use super::super::packet::VsockPacket;
+use super::super::persist::{VsockConnectionConstructorArgs, VsockConnectionState};
 use super::super::{Result as VsockResult, VsockChannel, VsockEpollListener, VsockError};
 use super::txbuf::TxBuf;
-use super::{defs, ConnState, Error, PendingRx, PendingRxSet, Result};
+use super::{defs, ConnState, Error, PendingRx, PendingRxSet, Result, VsockConnectionInfo};
 
 /// A self-managing connection object, that handles communication between a guest-side AF_VSOCK
 /// socket and a host-side `Read + Write + AsRawFd` stream.
@@ -106,10 +117,16 @@ pub struct VsockConnection<S: Read + Write + AsRawFd> {
     local_port: u32,
     /// The peer (guest) port.
     peer_port: u32,
+    /// The socket type of this connection - VSOCK_TYPE_STREAM or VSOCK_TYPE_SEQPACKET.
+    type_: u16,
     /// The (connected) host-side stream.
     stream: S,
     /// The TX buffer for this connection.
     tx_buf: TxBuf,
+    /// The last message read from a seqpacket host stream, and the offset of the data that is
+    /// yet to be sent to the peer.
+    rx_msg: Vec<u8>,
+    rx_msg_ofs: usize,
     /// Total number of bytes that have been successfully written to `self.stream`, either
     /// directly, or flushed from `self.tx_buf`.
     fwd_cnt: Wrapping<u32>,
@@ -119,6 +136,10 @@ pub struct VsockConnection<S: Read + Write + AsRawFd> {
     peer_fwd_cnt: Wrapping<u32>,
     /// The total number of bytes sent to the peer (guest vsock driver)
     rx_cnt: Wrapping<u32>,
+    /// Same as `rx_cnt` and `fwd_cnt`, respectively, but without wrapping around, for
+    /// accounting purposes only.
+    rx_bytes: u64,
+    tx_bytes: u64,
     /// Our `self.fwd_cnt`, as last sent to the peer. This is used to provide proactive credit
     /// updates, and let the peer know it's OK to send more data.
     last_fwd_cnt_to_peer: Wrapping<u32>,
@@ -207,13 +228,26 @@ where
             // the peer available buffer space.
             let max_len = std::cmp::min(pkt.buf_size(), self.peer_avail_credit());
 
-            // Read data from the stream straight to the RX buffer, for maximum throughput.
-            match pkt.read_at_offset_from(mem, 0, &mut self.stream, max_len) {
+            // Read data from the stream straight to the RX buffer, for maximum throughput. A
+            // seqpacket stream is read a whole message at a time, instead.
+            let res = if self.type_ == uapi::VSOCK_TYPE_SEQPACKET {
+                self.read_msg_fragment(pkt, mem, max_len)
+            } else {
+                pkt.read_at_offset_from(mem, 0, &mut self.stream, max_len)
+                    .map(|read_cnt| Some(read_cnt).filter(|read_cnt| *read_cnt > 0))
+            };
+            match res {
                 Ok(read_cnt) => {
-                    if read_cnt == 0 {
-                        // A 0-length read means the host stream was closed down. In that case,
-                        // we'll ask our peer to shut down the connection. We can neither send nor
-                        // receive any more data.
+                    if let Some(read_cnt) = read_cnt {
+                        // On a successful data read, we fill in the packet with the RW op, and
+                        // length of the read data.
+                        pkt.set_op(uapi::VSOCK_OP_RW).set_len(read_cnt as u32);
+                        self.rx_bytes += read_cnt as u64;
+                        METRICS.vsock.rx_bytes_count.add(read_cnt);
+                    } else {
+                        // The host stream was closed down. In that case, we'll ask our peer to
+                        // shut down the connection. We can neither send nor receive any more
+                        // data.
                         self.state = ConnState::LocalClosed;
                         self.expiry = Some(
                             Instant::now() + Duration::from_millis(defs::CONN_SHUTDOWN_TIMEOUT_MS),
@@ -221,11 +255,6 @@ where
                         pkt.set_op(uapi::VSOCK_OP_SHUTDOWN)
                             .set_flag(uapi::VSOCK_FLAGS_SHUTDOWN_RCV)
                             .set_flag(uapi::VSOCK_FLAGS_SHUTDOWN_SEND);
-                    } else {
-                        // On a successful data read, we fill in the packet with the RW op, and
-                        // length of the read data.
-                        pkt.set_op(uapi::VSOCK_OP_RW).set_len(read_cnt as u32);
-                        METRICS.vsock.rx_bytes_count.add(read_cnt);
                     }
                     self.rx_cnt += Wrapping(pkt.len());
                     self.last_fwd_cnt_to_peer = self.fwd_cnt;
@@ -284,6 +313,12 @@ where
         self.peer_fwd_cnt = Wrapping(pkt.fwd_cnt());
         METRICS.vsock.tx_packets_count.inc();
 
+        // The rest of a partially sent RX message is already read from the host stream, so
+        // no EPOLLIN event will remind us to send it, once the peer has enough credit.
+        if self.rx_msg_ofs < self.rx_msg.len() && !self.need_credit_update_from_peer() {
+            self.pending_rx.insert(PendingRx::Rw);
+        }
+
         match self.state {
             // Most frequent case: this is an established connection that needs to forward some
             // data to the host stream. Also works for a connection that has begun shutting
@@ -291,7 +326,8 @@ where
             ConnState::Established | ConnState::PeerClosed(_, false)
                 if pkt.op() == uapi::VSOCK_OP_RW =>
             {
-                if pkt.buf_size() == 0 {
+                // An empty packet can still end a message, so it's only dropped for streams.
+                if pkt.buf_size() == 0 && self.type_ != uapi::VSOCK_TYPE_SEQPACKET {
                     info!(
                         "vsock: dropping empty data packet from guest (lp={}, pp={}",
                         self.local_port, self.peer_port
@@ -300,7 +336,12 @@ where
                 }
 
                 // Unwrapping here is safe, since we just checked `pkt.buf()` above.
-                if let Err(err) = self.send_bytes(mem, pkt) {
+                let res = if self.type_ == uapi::VSOCK_TYPE_SEQPACKET {
+                    self.send_msg_fragment(mem, pkt)
+                } else {
+                    self.send_bytes(mem, pkt)
+                };
+                if let Err(err) = res {
                     // If we can't write to the host stream, that's an unrecoverable error, so
                     // we'll terminate this connection.
                     warn!(
@@ -417,7 +458,7 @@ where
     /// - data can be written to the host stream, and the TX buffer needs to be flushed.
     fn get_polled_evset(&self) -> EventSet {
         let mut evset = EventSet::empty();
-        if !self.tx_buf.is_empty() {
+        if self.tx_buf.has_flushable_data() {
             // There's data waiting in the TX buffer, so we are interested in being notified
             // when writing to the host stream wouldn't block.
             evset.insert(EventSet::OUT);
@@ -443,31 +484,12 @@ where
         if evset.contains(EventSet::OUT) {
             // Data can be written to the host stream. Time to flush out the TX buffer.
             //
-            if self.tx_buf.is_empty() {
+            if !self.tx_buf.has_flushable_data() {
                 METRICS.vsock.conn_event_fails.inc();
                 info!("vsock: connection received unexpected EPOLLOUT event");
                 return;
             }
-            let flushed = self
-                .tx_buf
-                .flush_to(&mut self.stream)
-                .unwrap_or_else(|err| {
-                    METRICS.vsock.tx_flush_fails.inc();
-                    warn!(
-                        "vsock: error flushing TX buf for (lp={}, pp={}): {:?}",
-                        self.local_port, self.peer_port, err
-                    );
-                    match err {
-                        Error::TxBufFlush(inner) if inner.kind() == ErrorKind::WouldBlock => {
-                            // This should never happen (EWOULDBLOCK after EPOLLOUT), but
-                            // it does, so let's absorb it.
-                        }
-                        _ => self.kill(),
-                    };
-                    0
-                });
-            self.fwd_cnt += Wrapping(flushed as u32);
-            METRICS.vsock.tx_bytes_count.add(flushed as usize);
+            self.flush_tx_buf();
 
             // If this connection was shutting down, but is waiting to drain the TX buffer
             // before forceful termination, the wait might be over.
@@ -482,11 +504,74 @@ where
     }
 }
 
+impl<S> Persist<'_> for VsockConnection<S>
+where
+    S: Read + Write + AsRawFd,
+{
+    type State = VsockConnectionState;
+    type ConstructorArgs = VsockConnectionConstructorArgs<S>;
+    type Error = Error;
+
+    fn save(&self) -> Self::State {
+        VsockConnectionState {
+            local_port: self.local_port,
+            peer_port: self.peer_port,
+            type_: self.type_,
+            // Only established stream connections can be resumed over a new host-side stream,
+            // since there's no handshake or message boundary to keep track of.
+            reconnect: self.state == ConnState::Established
+                && self.type_ == uapi::VSOCK_TYPE_STREAM,
+            fwd_cnt: self.fwd_cnt.0,
+            peer_buf_alloc: self.peer_buf_alloc,
+            peer_fwd_cnt: self.peer_fwd_cnt.0,
+            rx_cnt: self.rx_cnt.0,
+            last_fwd_cnt_to_peer: self.last_fwd_cnt_to_peer.0,
+            rx_bytes: self.rx_bytes,
+            tx_bytes: self.tx_bytes,
+            tx_buf: self.tx_buf.to_vec(),
+        }
+    }
+
+    /// Resume an established stream connection over the host-side stream in
+    /// `constructor_args`.
+    fn restore(constructor_args: Self::ConstructorArgs, state: &Self::State) -> Result<Self> {
+        let mut tx_buf = TxBuf::new();
+        if !state.tx_buf.is_empty() {
+            tx_buf.push(&state.tx_buf)?;
+        }
+
+        Ok(Self {
+            local_cid: constructor_args.local_cid,
+            peer_cid: constructor_args.peer_cid,
+            local_port: state.local_port,
+            peer_port: state.peer_port,
+            type_: uapi::VSOCK_TYPE_STREAM,
+            stream: constructor_args.stream,
+            state: ConnState::Established,
+            tx_buf,
+            rx_msg: Vec::new(),
+            rx_msg_ofs: 0,
+            fwd_cnt: Wrapping(state.fwd_cnt),
+            peer_buf_alloc: state.peer_buf_alloc,
+            peer_fwd_cnt: Wrapping(state.peer_fwd_cnt),
+            rx_cnt: Wrapping(state.rx_cnt),
+            rx_bytes: state.rx_bytes,
+            tx_bytes: state.tx_bytes,
+            last_fwd_cnt_to_peer: Wrapping(state.last_fwd_cnt_to_peer),
+            // Let the peer know about our buffer space right away, in case it was waiting
+            // for a credit update when the snapshot was taken.
+            pending_rx: PendingRxSet::from(PendingRx::CreditUpdate),
+            expiry: None,
+        })
+    }
+}
+
 impl<S> VsockConnection<S>
 where
     S: Read + Write + AsRawFd,
 {
-    /// Create a new guest-initiated connection object.
+    /// Create a new guest-initiated connection object, of the socket type requested by the
+    /// guest.
     pub fn new_peer_init(
         stream: S,
         local_cid: u64,
@@ -494,19 +579,30 @@ where
         local_port: u32,
         peer_port: u32,
         peer_buf_alloc: u32,
+        type_: u16,
     ) -> Self {
+        let tx_buf = if type_ == uapi::VSOCK_TYPE_SEQPACKET {
+            TxBuf::new_seqpacket()
+        } else {
+            TxBuf::new()
+        };
         Self {
             local_cid,
             peer_cid,
             local_port,
             peer_port,
+            type_,
             stream,
             state: ConnState::PeerInit,
-            tx_buf: TxBuf::new(),
+            tx_buf,
+            rx_msg: Vec::new(),
+            rx_msg_ofs: 0,
             fwd_cnt: Wrapping(0),
             peer_buf_alloc,
             peer_fwd_cnt: Wrapping(0),
             rx_cnt: Wrapping(0),
+            rx_bytes: 0,
+            tx_bytes: 0,
             last_fwd_cnt_to_peer: Wrapping(0),
             pending_rx: PendingRxSet::from(PendingRx::Response),
             expiry: None,
@@ -526,13 +622,18 @@ where
             peer_cid,
             local_port,
             peer_port,
+            type_: uapi::VSOCK_TYPE_STREAM,
             stream,
             state: ConnState::LocalInit,
             tx_buf: TxBuf::new(),
+            rx_msg: Vec::new(),
+            rx_msg_ofs: 0,
             fwd_cnt: Wrapping(0),
             peer_buf_alloc: 0,
             peer_fwd_cnt: Wrapping(0),
             rx_cnt: Wrapping(0),
+            rx_bytes: 0,
+            tx_bytes: 0,
             last_fwd_cnt_to_peer: Wrapping(0),
             pending_rx: PendingRxSet::from(PendingRx::Request),
             expiry: None,
@@ -574,6 +675,20 @@ where
         self.state
     }
 
+    /// Describe the connection, its traffic and its flow control state.
+    pub fn info(&self) -> VsockConnectionInfo {
+        VsockConnectionInfo {
+            local_port: self.local_port,
+            peer_port: self.peer_port,
+            state: self.state.to_string(),
+            rx_bytes: self.rx_bytes,
+            tx_bytes: self.tx_bytes,
+            tx_buf_bytes: self.tx_buf.len() as u32,
+            peer_buf_alloc: self.peer_buf_alloc,
+            peer_credit: self.peer_avail_credit() as u32,
+        }
+    }
+
     /// Send some raw, untracked, data straight to the underlying connected stream.
     /// Returns: number of bytes written, or the error describing the write failure.
     ///
@@ -623,6 +738,7 @@ where
         };
         // Move the "forwarded bytes" counter ahead by how much we were able to send out.
         self.fwd_cnt += Wrapping(written as u32);
+        self.tx_bytes += written as u64;
         METRICS.vsock.tx_bytes_count.add(written);
 
         // If we couldn't write the whole slice, we'll need to push the remaining data to our
@@ -634,6 +750,136 @@ where
         Ok(())
     }
 
+    /// Buffer a fragment of a message for the host stream, and write the message out as soon
+    /// as its last fragment arrives.
+    fn send_msg_fragment(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        pkt: &VsockPacket,
+    ) -> std::result::Result<(), VsockError> {
+        let len = pkt.len() as usize;
+        if len > 0 {
+            pkt.write_from_offset_to(mem, 0, &mut self.tx_buf, len)?;
+        }
+
+        // The end of a record is always the end of a message as well, even if the peer only
+        // flagged the former.
+        if pkt.flags() & (uapi::VSOCK_FLAGS_SEQ_EOM | uapi::VSOCK_FLAGS_SEQ_EOR) != 0 {
+            self.tx_buf.end_msg();
+            self.flush_tx_buf();
+        }
+
+        Ok(())
+    }
+
+    /// Fill in an RX packet with the next fragment of the last message read from the host
+    /// stream, reading a new message if needed. The packet holding the end of the message is
+    /// marked with VSOCK_FLAGS_SEQ_EOM and VSOCK_FLAGS_SEQ_EOR.
+    ///
+    /// Returns the number of bytes filled in, which is 0 for a zero-length message, or `None`
+    /// if the host stream was closed.
+    fn read_msg_fragment(
+        &mut self,
+        pkt: &mut VsockPacket,
+        mem: &GuestMemoryMmap,
+        max_len: usize,
+    ) -> std::result::Result<Option<usize>, VsockError> {
+        if self.rx_msg_ofs == self.rx_msg.len() {
+            // One extra byte tells messages that don't fit apart from the ones that just do,
+            // since the host stream silently truncates the messages to the read size.
+            self.rx_msg.resize(defs::CONN_RX_MSG_MAX_SIZE + 1, 0);
+            let res = self.stream.read(&mut self.rx_msg);
+            self.rx_msg.truncate(*res.as_ref().unwrap_or(&0));
+            self.rx_msg_ofs = 0;
+            res.map_err(|err| VsockError::GuestMemoryMmap(GuestMemoryError::IOError(err)))?;
+
+            if self.rx_msg.len() > defs::CONN_RX_MSG_MAX_SIZE {
+                self.rx_msg.clear();
+                return Err(VsockError::GuestMemoryMmap(GuestMemoryError::IOError(
+                    std::io::Error::from_raw_os_error(libc::EMSGSIZE),
+                )));
+            }
+            if self.rx_msg.is_empty() && self.stream_ended() {
+                return Ok(None);
+            }
+        }
+
+        let len = std::cmp::min(max_len, self.rx_msg.len() - self.rx_msg_ofs);
+        let read_cnt = pkt.read_at_offset_from(
+            mem,
+            0,
+            &mut &self.rx_msg[self.rx_msg_ofs..(self.rx_msg_ofs + len)],
+            len,
+        )?;
+        self.rx_msg_ofs += read_cnt;
+
+        // The RX packet header might still hold the flags of a previous packet.
+        pkt.set_flags(0);
+        if self.rx_msg_ofs < self.rx_msg.len() {
+            // There's more of this message to send, with or without any new data on the host
+            // stream.
+            self.pending_rx.insert(PendingRx::Rw);
+        } else {
+            pkt.set_flag(uapi::VSOCK_FLAGS_SEQ_EOM)
+                .set_flag(uapi::VSOCK_FLAGS_SEQ_EOR);
+        }
+
+        Ok(Some(read_cnt))
+    }
+
+    /// Tell whether a seqpacket host stream reached its end, after reading 0 bytes from it,
+    /// which is also what reading a zero-length message yields.
+    fn stream_ended(&self) -> bool {
+        let fd = self.stream.as_raw_fd();
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLRDHUP,
+            revents: 0,
+        };
+        // This is safe, since we pass a single valid `pollfd`, and check the return value.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        if ret < 0 {
+            return true;
+        }
+        if pollfd.revents & (libc::POLLRDHUP | libc::POLLHUP) == 0 {
+            return false;
+        }
+
+        // The host peer shut the stream down, but the messages it sent before that can still
+        // be read. Zero-length messages right before the end can't be told apart from it,
+        // though.
+        let mut queued: libc::c_int = 0;
+        // This is safe, since FIONREAD writes a single `c_int`, and we check the return value.
+        let ret = unsafe { libc::ioctl(fd, libc::FIONREAD, &mut queued) };
+        ret < 0 || queued == 0
+    }
+
+    /// Flush as much of the TX buffer as the host stream can take, and account for it.
+    fn flush_tx_buf(&mut self) {
+        let flushed = self
+            .tx_buf
+            .flush_to(&mut self.stream)
+            .unwrap_or_else(|err| {
+                METRICS.vsock.tx_flush_fails.inc();
+                warn!(
+                    "vsock: error flushing TX buf for (lp={}, pp={}): {:?}",
+                    self.local_port, self.peer_port, err
+                );
+                match err {
+                    Error::TxBufFlush(inner) if inner.kind() == ErrorKind::WouldBlock => {
+                        // This is expected when a message is flushed as soon as it's complete,
+                        // and should never happen otherwise (EWOULDBLOCK after EPOLLOUT), but
+                        // it does, so let's absorb it.
+                    }
+                    _ => self.kill(),
+                };
+                0
+            });
+        self.fwd_cnt += Wrapping(flushed as u32);
+        self.tx_bytes += flushed as u64;
+        METRICS.vsock.tx_bytes_count.add(flushed as usize);
+    }
+
     /// Check if the credit information the peer has last received from us is outdated.
     fn peer_needs_credit_update(&self) -> bool {
         let peer_seen_free_buf =
@@ -659,7 +905,7 @@ where
             .set_dst_cid(self.peer_cid)
             .set_src_port(self.local_port)
             .set_dst_port(self.peer_port)
-            .set_type(uapi::VSOCK_TYPE_STREAM)
+            .set_type(self.type_)
             .set_buf_alloc(defs::CONN_TX_BUF_SIZE)
             .set_fwd_cnt(self.fwd_cnt.0)
     }
@@ -668,7 +914,8 @@ where
 #[cfg(test)]
 mod tests {
     use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
-    use std::os::unix::io::RawFd;
+    use std::os::unix::io::{FromRawFd, RawFd};
+    use std::os::unix::net::UnixStream;
     use std::time::{Duration, Instant};
 
     use utils::eventfd::EventFd;
@@ -794,10 +1041,10 @@ mod tests {
     // packet.  A single `VsockConnection` object will also suffice for our testing needs. We'll
     // be using a specially crafted `Read + Write + AsRawFd` object as a backing stream, so that
     // we can control the various error conditions that might arise.
-    struct CsmTestContext {
+    struct CsmTestContext<S: Read + Write + AsRawFd = TestStream> {
         _vsock_test_ctx: TestContext,
         pkt: VsockPacket,
-        conn: VsockConnection<TestStream>,
+        conn: VsockConnection<S>,
     }
 
     impl CsmTestContext {
@@ -806,9 +1053,22 @@ mod tests {
         }
 
         fn new(conn_state: ConnState) -> Self {
+            Self::new_with_type(conn_state, uapi::VSOCK_TYPE_STREAM)
+        }
+
+        fn new_with_type(conn_state: ConnState, type_: u16) -> Self {
+            Self::new_with_stream(conn_state, type_, TestStream::new())
+        }
+
+        fn set_stream(&mut self, stream: TestStream) {
+            self.conn.stream = stream;
+        }
+    }
+
+    impl<S: Read + Write + AsRawFd> CsmTestContext<S> {
+        fn new_with_stream(conn_state: ConnState, type_: u16, stream: S) -> Self {
             let vsock_test_ctx = TestContext::new();
             let mut handler_ctx = vsock_test_ctx.create_event_handler_context();
-            let stream = TestStream::new();
             let mut pkt = VsockPacket::from_rx_virtq_head(
                 &handler_ctx.device.queues[RXQ_INDEX]
                     .pop(&vsock_test_ctx.mem)
@@ -816,25 +1076,27 @@ mod tests {
             )
             .unwrap();
             let conn = match conn_state {
-                ConnState::PeerInit => VsockConnection::<TestStream>::new_peer_init(
+                ConnState::PeerInit => VsockConnection::<S>::new_peer_init(
                     stream,
                     LOCAL_CID,
                     PEER_CID,
                     LOCAL_PORT,
                     PEER_PORT,
                     PEER_BUF_ALLOC,
+                    type_,
                 ),
-                ConnState::LocalInit => VsockConnection::<TestStream>::new_local_init(
+                ConnState::LocalInit => VsockConnection::<S>::new_local_init(
                     stream, LOCAL_CID, PEER_CID, LOCAL_PORT, PEER_PORT,
                 ),
                 ConnState::Established => {
-                    let mut conn = VsockConnection::<TestStream>::new_peer_init(
+                    let mut conn = VsockConnection::<S>::new_peer_init(
                         stream,
                         LOCAL_CID,
                         PEER_CID,
                         LOCAL_PORT,
                         PEER_PORT,
                         PEER_BUF_ALLOC,
+                        type_,
                     );
                     assert!(conn.has_pending_rx());
                     conn.recv_pkt(&mut pkt, &vsock_test_ctx.mem).unwrap();
@@ -851,10 +1113,6 @@ mod tests {
             }
         }
 
-        fn set_stream(&mut self, stream: TestStream) {
-            self.conn.stream = stream;
-        }
-
         fn set_peer_credit(&mut self, credit: u32) {
             assert!(credit < self.conn.peer_buf_alloc);
             self.conn.peer_fwd_cnt = Wrapping(0);
@@ -887,7 +1145,7 @@ mod tests {
             init_pkt(&mut self.pkt, op, len)
         }
 
-        fn init_data_pkt(&mut self, data: &[u8]) -> &VsockPacket {
+        fn init_data_pkt(&mut self, data: &[u8]) -> &mut VsockPacket {
             assert!(data.len() <= self.pkt.buf_size());
             self.init_pkt(uapi::VSOCK_OP_RW, data.len() as u32);
             self.pkt
@@ -898,10 +1156,40 @@ mod tests {
                     data.len(),
                 )
                 .unwrap();
-            &self.pkt
+            &mut self.pkt
         }
     }
 
+    // Build an established seqpacket connection over a SOCK_SEQPACKET socket pair, along with
+    // the host peer end of the pair.
+    fn seqpacket_test_context() -> (CsmTestContext<UnixStream>, UnixStream) {
+        let mut fds = [0; 2];
+        // This is safe, since `fds` has room for the two FDs, and we check the return value.
+        let ret = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(ret, 0);
+        // We just checked that the FDs are valid.
+        let (stream, host_peer) = unsafe {
+            (
+                UnixStream::from_raw_fd(fds[0]),
+                UnixStream::from_raw_fd(fds[1]),
+            )
+        };
+        stream.set_nonblocking(true).unwrap();
+        let ctx = CsmTestContext::new_with_stream(
+            ConnState::Established,
+            uapi::VSOCK_TYPE_SEQPACKET,
+            stream,
+        );
+        (ctx, host_peer)
+    }
+
     #[test]
     fn test_peer_request() {
         let mut ctx = CsmTestContext::new(ConnState::PeerInit);
@@ -1193,6 +1481,90 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_conn_info() {
+        let mut ctx = CsmTestContext::new_established();
+        let info = ctx.conn.info();
+        assert_eq!(info.local_port, LOCAL_PORT);
+        assert_eq!(info.peer_port, PEER_PORT);
+        assert_eq!(info.state, "Established");
+        assert_eq!(info.rx_bytes, 0);
+        assert_eq!(info.tx_bytes, 0);
+        assert_eq!(info.peer_buf_alloc, PEER_BUF_ALLOC);
+        assert_eq!(info.peer_credit, PEER_BUF_ALLOC);
+
+        // Host -> guest data is accounted for, and eats up the peer credit.
+        ctx.set_stream(TestStream::new_with_read_buf(&[1, 2, 3, 4]));
+        ctx.notify_epollin();
+        ctx.recv();
+        let info = ctx.conn.info();
+        assert_eq!(info.rx_bytes, 4);
+        assert_eq!(info.peer_credit, PEER_BUF_ALLOC - 4);
+
+        // Guest -> host data is accounted for once written to the host stream, and reported as
+        // buffered until then.
+        let mut stream = TestStream::new();
+        stream.write_state = StreamState::WouldBlock;
+        ctx.set_stream(stream);
+        ctx.init_data_pkt(&[5, 6]);
+        ctx.send();
+        let info = ctx.conn.info();
+        assert_eq!(info.tx_bytes, 0);
+        assert_eq!(info.tx_buf_bytes, 2);
+
+        ctx.set_stream(TestStream::new());
+        ctx.conn.notify(EventSet::OUT);
+        let info = ctx.conn.info();
+        assert_eq!(info.tx_bytes, 2);
+        assert_eq!(info.tx_buf_bytes, 0);
+
+        ctx.conn.kill();
+        assert_eq!(ctx.conn.info().state, "Killed");
+    }
+
+    #[test]
+    fn test_save_restore() {
+        let mut ctx = CsmTestContext::new_established();
+
+        // Leave some guest data buffered, waiting for the host stream.
+        let mut stream = TestStream::new();
+        stream.write_state = StreamState::WouldBlock;
+        ctx.set_stream(stream);
+        ctx.init_data_pkt(&[1, 2, 3, 4]);
+        ctx.send();
+
+        let state = ctx.conn.save();
+        assert!(state.reconnect);
+        assert_eq!(state.tx_buf, [1, 2, 3, 4]);
+
+        let mut conn = VsockConnection::restore(
+            VsockConnectionConstructorArgs {
+                stream: TestStream::new(),
+                local_cid: LOCAL_CID,
+                peer_cid: PEER_CID,
+            },
+            &state,
+        )
+        .unwrap();
+        assert_eq!(conn.info(), ctx.conn.info());
+
+        // The peer is sent a credit update first, then the buffered data reaches the new stream.
+        assert!(conn.has_pending_rx());
+        conn.recv_pkt(&mut ctx.pkt, &ctx._vsock_test_ctx.mem)
+            .unwrap();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_CREDIT_UPDATE);
+        assert!(conn.get_polled_evset().contains(EventSet::OUT));
+        conn.notify(EventSet::OUT);
+        assert_eq!(conn.stream.write_buf, [1, 2, 3, 4]);
+        assert_eq!(conn.info().tx_bytes, 4);
+
+        // Connections that aren't established can't be resumed.
+        let ctx = CsmTestContext::new(ConnState::LocalInit);
+        assert!(!ctx.conn.save().reconnect);
+        let ctx = CsmTestContext::new_with_type(ConnState::Established, uapi::VSOCK_TYPE_SEQPACKET);
+        assert!(!ctx.conn.save().reconnect);
+    }
+
     #[test]
     fn test_stream_write_error() {
         // Test case: sending a data packet to a broken / closed backing stream should kill it.
@@ -1262,4 +1634,145 @@ mod tests {
         ctx.recv();
         assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
     }
+
+    #[test]
+    fn test_seqpacket_tx() {
+        let mut ctx =
+            CsmTestContext::new_with_type(ConnState::Established, uapi::VSOCK_TYPE_SEQPACKET);
+
+        // A message is only written out to the host stream once its last fragment arrives.
+        ctx.init_data_pkt(&[1, 2]).set_flags(0);
+        ctx.send();
+        assert!(ctx.conn.stream.write_buf.is_empty());
+        assert_eq!(ctx.conn.tx_buf.len(), 2);
+        assert!(!ctx.conn.get_polled_evset().contains(EventSet::OUT));
+
+        ctx.init_data_pkt(&[3, 4])
+            .set_flag(uapi::VSOCK_FLAGS_SEQ_EOM);
+        ctx.send();
+        assert_eq!(ctx.conn.stream.write_buf, [1, 2, 3, 4]);
+        assert!(ctx.conn.tx_buf.is_empty());
+        assert_eq!(ctx.conn.fwd_cnt, Wrapping(4));
+
+        // A complete message that can't be written out right away stays buffered, until the
+        // host stream is writable.
+        let mut stream = TestStream::new();
+        stream.write_state = StreamState::WouldBlock;
+        ctx.set_stream(stream);
+        ctx.init_data_pkt(&[5, 6])
+            .set_flag(uapi::VSOCK_FLAGS_SEQ_EOM);
+        ctx.send();
+        assert_eq!(ctx.conn.state, ConnState::Established);
+        assert!(ctx.conn.get_polled_evset().contains(EventSet::OUT));
+
+        ctx.set_stream(TestStream::new());
+        ctx.notify_epollout();
+        assert_eq!(ctx.conn.stream.write_buf, [5, 6]);
+        assert!(ctx.conn.tx_buf.is_empty());
+
+        // The end of a record ends the message as well.
+        ctx.init_data_pkt(&[7, 8])
+            .set_flags(uapi::VSOCK_FLAGS_SEQ_EOR);
+        ctx.send();
+        assert_eq!(ctx.conn.stream.write_buf, [5, 6, 7, 8]);
+        assert!(ctx.conn.tx_buf.is_empty());
+    }
+
+    #[test]
+    fn test_seqpacket_rx() {
+        let mut ctx =
+            CsmTestContext::new_with_type(ConnState::Established, uapi::VSOCK_TYPE_SEQPACKET);
+        let data = &[1, 2, 3, 4, 5, 6];
+        ctx.set_stream(TestStream::new_with_read_buf(data));
+
+        // With credit for just a part of the message, the rest of it is held back.
+        ctx.set_peer_credit(4);
+        ctx.notify_epollin();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+        assert_eq!(ctx.pkt.len(), 4);
+        assert_eq!(ctx.pkt.flags(), 0);
+        assert!(ctx.conn.stream.read_buf.is_empty());
+        assert!(ctx.conn.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_CREDIT_REQUEST);
+        assert!(!ctx.conn.has_pending_rx());
+
+        // Once the peer has some credit again, the end of the message goes out.
+        ctx.init_pkt(uapi::VSOCK_OP_CREDIT_UPDATE, 0)
+            .set_fwd_cnt(PEER_BUF_ALLOC);
+        ctx.send();
+        assert!(ctx.conn.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.len(), 2);
+        assert_eq!(
+            ctx.pkt.flags(),
+            uapi::VSOCK_FLAGS_SEQ_EOM | uapi::VSOCK_FLAGS_SEQ_EOR
+        );
+
+        let mut buf = vec![];
+        ctx.pkt
+            .write_from_offset_to(&ctx._vsock_test_ctx.mem, 0, &mut buf, 2)
+            .unwrap();
+        assert_eq!(&buf, &data[4..]);
+    }
+
+    #[test]
+    fn test_seqpacket_rx_msg_boundaries() {
+        let (mut ctx, mut host_peer) = seqpacket_test_context();
+
+        // A zero-length message goes through as an empty packet, instead of closing the
+        // connection.
+        assert_eq!(host_peer.write(&[]).unwrap(), 0);
+        host_peer.write_all(&[1, 2]).unwrap();
+        ctx.notify_epollin();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.len(), 0);
+        assert_eq!(
+            ctx.pkt.flags(),
+            uapi::VSOCK_FLAGS_SEQ_EOM | uapi::VSOCK_FLAGS_SEQ_EOR
+        );
+        ctx.notify_epollin();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.len(), 2);
+        assert_eq!(ctx.conn.state, ConnState::Established);
+
+        // So does one sent right before the host peer shuts the stream down, as long as more
+        // messages follow it.
+        assert_eq!(host_peer.write(&[]).unwrap(), 0);
+        host_peer.write_all(&[3]).unwrap();
+        host_peer.shutdown(std::net::Shutdown::Write).unwrap();
+        ctx.notify_epollin();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.len(), 0);
+        ctx.notify_epollin();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.len(), 1);
+
+        // The end of the stream is still detected.
+        ctx.notify_epollin();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_SHUTDOWN);
+        assert_eq!(ctx.conn.state, ConnState::LocalClosed);
+    }
+
+    #[test]
+    fn test_seqpacket_rx_msg_too_large() {
+        let (mut ctx, mut host_peer) = seqpacket_test_context();
+
+        // A message that is too large to be read whole resets the connection, instead of
+        // being truncated.
+        host_peer
+            .write_all(&vec![0u8; csm_defs::CONN_RX_MSG_MAX_SIZE + 1])
+            .unwrap();
+        ctx.notify_epollin();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+    }
 }
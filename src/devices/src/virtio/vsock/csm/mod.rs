@@ -9,11 +9,16 @@ mod txbuf;
 use std::fmt;
 
 pub use connection::VsockConnection;
+use serde::Serialize;
 
 pub mod defs {
     /// Vsock connection TX buffer capacity.
     pub const CONN_TX_BUF_SIZE: u32 = 64 * 1024;
 
+    /// Maximum size of a message read from the host socket of a seqpacket connection. Larger
+    /// messages get the connection reset.
+    pub const CONN_RX_MSG_MAX_SIZE: usize = 64 * 1024;
+
     /// When the guest thinks we have less than this amount of free buffer space,
     /// we will send them a credit update packet.
     pub const CONN_CREDIT_UPDATE_THRESHOLD: u32 = 4 * 1024;
@@ -76,6 +81,41 @@ pub enum ConnState {
     Killed,
 }
 
+impl fmt::Display for ConnState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::LocalInit => write!(f, "LocalInit"),
+            Self::PeerInit => write!(f, "PeerInit"),
+            Self::Established => write!(f, "Established"),
+            Self::LocalClosed => write!(f, "LocalClosed"),
+            Self::PeerClosed(..) => write!(f, "PeerClosed"),
+            Self::Killed => write!(f, "Killed"),
+        }
+    }
+}
+
+/// A point-in-time view of a vsock connection, its traffic and its flow control state.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct VsockConnectionInfo {
+    /// The local (host) port.
+    pub local_port: u32,
+    /// The peer (guest) port.
+    pub peer_port: u32,
+    /// The connection state.
+    pub state: String,
+    /// Total number of bytes sent to the guest.
+    pub rx_bytes: u64,
+    /// Total number of bytes received from the guest and written to the host stream.
+    pub tx_bytes: u64,
+    /// Number of bytes received from the guest and still waiting in the TX buffer.
+    pub tx_buf_bytes: u32,
+    /// The amount of buffer space that the guest has allocated for this connection.
+    pub peer_buf_alloc: u32,
+    /// The number of bytes that can still be sent to the guest, before it has to grant us
+    /// more credit.
+    pub peer_credit: u32,
+}
+
 /// An RX indication, used by `VsockConnection` to schedule future `recv_pkt()` responses.
 /// For instance, after being notified that there is available data to be read from the host stream
 /// (via `notify()`), the connection will store a `PendingRx::Rw` to be later inspected by
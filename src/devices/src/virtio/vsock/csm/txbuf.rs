@@ -2,7 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::io::Write;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Write};
 use std::num::Wrapping;
 
 use super::{defs, Error, Result};
@@ -10,6 +11,10 @@ use super::{defs, Error, Result};
 /// A simple ring-buffer implementation, used by vsock connections to buffer TX (guest -> host)
 /// data.  Memory for this buffer is allocated lazily, since buffering will only be needed when
 /// the host can't read fast enough.
+///
+/// The buffer of a seqpacket connection is also aware of message boundaries: data is only
+/// flushed out one complete message at a time, with a single write, so that the host socket
+/// receives each message whole.
 pub struct TxBuf {
     /// The actual u8 buffer - only allocated after the first push.
     data: Option<Box<[u8]>>,
@@ -17,6 +22,9 @@ pub struct TxBuf {
     head: Wrapping<u32>,
     /// Ring-buffer tail offset - where data is flushed from.
     tail: Wrapping<u32>,
+    /// The head offsets at which the complete messages that are yet to be flushed end, if
+    /// this buffer is message-oriented.
+    msg_ends: Option<VecDeque<Wrapping<u32>>>,
 }
 
 impl TxBuf {
@@ -29,6 +37,15 @@ impl TxBuf {
             data: None,
             head: Wrapping(0),
             tail: Wrapping(0),
+            msg_ends: None,
+        }
+    }
+
+    /// Message-oriented ring-buffer constructor.
+    pub fn new_seqpacket() -> Self {
+        Self {
+            msg_ends: Some(VecDeque::new()),
+            ..Self::new()
         }
     }
 
@@ -76,6 +93,14 @@ impl TxBuf {
         Ok(())
     }
 
+    /// Mark the end of the message that was pushed so far. This is a no-op for a buffer that
+    /// isn't message-oriented.
+    pub fn end_msg(&mut self) {
+        if let Some(msg_ends) = self.msg_ends.as_mut() {
+            msg_ends.push_back(self.head);
+        }
+    }
+
     /// Flush the contents of the ring-buffer to a writable stream.
     ///
     /// Return the number of bytes that have been transferred out of the ring-buffer and into
@@ -84,6 +109,10 @@ impl TxBuf {
     where
         W: Write,
     {
+        if self.msg_ends.is_some() {
+            return self.flush_msgs_to(sink);
+        }
+
         // Nothing to do, if this buffer holds no data.
         if self.is_empty() {
             return Ok(0);
@@ -127,10 +156,81 @@ impl TxBuf {
         Ok(written + self.flush_to(sink).unwrap_or(0))
     }
 
+    /// Flush the complete messages in the ring-buffer to a writable, message-oriented stream,
+    /// one write per message.
+    ///
+    /// Return the number of bytes that have been transferred out of the ring-buffer and into
+    /// the writable stream.
+    fn flush_msgs_to<W>(&mut self, sink: &mut W) -> Result<usize>
+    where
+        W: Write,
+    {
+        let mut flushed = 0;
+
+        while let Some(&msg_end) = self.msg_ends.as_ref().and_then(VecDeque::front) {
+            let len = (msg_end - self.tail).0 as usize;
+            let tail_ofs = self.tail.0 as usize % Self::SIZE;
+            let data = self.data.as_deref().unwrap_or(&[]);
+
+            // A message that wraps around the end of the ring-buffer needs to be put back
+            // together first, since it has to be written out in one go.
+            let res = if tail_ofs + len <= Self::SIZE {
+                sink.write(&data[tail_ofs..(tail_ofs + len)])
+            } else {
+                let mut msg = Vec::with_capacity(len);
+                msg.extend_from_slice(&data[tail_ofs..]);
+                msg.extend_from_slice(&data[..(len - (Self::SIZE - tail_ofs))]);
+                sink.write(&msg)
+            };
+
+            match res {
+                Ok(written) if written == len => (),
+                // Message-oriented sockets never write just a part of a message, so this can
+                // only mean the sink is not one of them.
+                Ok(_) => {
+                    return Err(Error::TxBufFlush(std::io::Error::from(
+                        ErrorKind::WriteZero,
+                    )))
+                }
+                // Same as with byte streams, a failure after some messages have been flushed
+                // still counts as a successful flush.
+                Err(_) if flushed > 0 => break,
+                Err(err) => return Err(Error::TxBufFlush(err)),
+            }
+
+            self.tail = msg_end;
+            self.msg_ends.as_mut().unwrap().pop_front();
+            flushed += len;
+        }
+
+        Ok(flushed)
+    }
+
+    /// Copy out the data that has been pushed in, but not yet flushed out, oldest byte first.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.len());
+        if let Some(data) = self.data.as_ref() {
+            let tail_ofs = self.tail.0 as usize % Self::SIZE;
+            let len = std::cmp::min(Self::SIZE - tail_ofs, self.len());
+            buf.extend_from_slice(&data[tail_ofs..(tail_ofs + len)]);
+            buf.extend_from_slice(&data[..(self.len() - len)]);
+        }
+        buf
+    }
+
     /// Check if the buffer holds any data that hasn't yet been flushed out.
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Check if the buffer holds any data that can be flushed out right away. For a
+    /// message-oriented buffer, that excludes the message which is yet to be completed.
+    pub fn has_flushable_data(&self) -> bool {
+        match self.msg_ends.as_ref() {
+            Some(msg_ends) => !msg_ends.is_empty(),
+            None => !self.is_empty(),
+        }
+    }
 }
 
 impl Write for TxBuf {
@@ -239,6 +339,23 @@ mod tests {
         assert_eq!(sink.data, [5, 6, 7, 8]);
     }
 
+    #[test]
+    fn test_to_vec() {
+        let mut txbuf = TxBuf::new();
+        let mut sink = TestSink::new();
+        assert!(txbuf.to_vec().is_empty());
+
+        let tmp = vec![0u8; TxBuf::SIZE - 2];
+        txbuf.push(tmp.as_slice()).unwrap();
+        txbuf.flush_to(&mut sink).unwrap();
+
+        // The data wraps around the end of the ring-buffer.
+        txbuf.push(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(txbuf.to_vec(), [1, 2, 3, 4]);
+        // Copying the data out doesn't consume it.
+        assert_eq!(txbuf.len(), 4);
+    }
+
     #[test]
     fn test_push_error() {
         let mut txbuf = TxBuf::new();
@@ -279,6 +396,57 @@ mod tests {
         assert_eq!(sink.data, [1, 2, 3, 4]);
     }
 
+    #[test]
+    fn test_seqpacket_flush() {
+        let mut txbuf = TxBuf::new_seqpacket();
+        let mut sink = TestSink::new();
+
+        // An incomplete message is kept in the buffer.
+        txbuf.push(&[1, 2]).unwrap();
+        txbuf.push(&[3]).unwrap();
+        assert!(!txbuf.has_flushable_data());
+        assert_eq!(txbuf.flush_to(&mut sink).unwrap(), 0);
+        assert_eq!(txbuf.len(), 3);
+
+        // Complete messages are flushed, one write each.
+        txbuf.end_msg();
+        txbuf.push(&[4, 5]).unwrap();
+        txbuf.end_msg();
+        txbuf.push(&[6]).unwrap();
+        assert!(txbuf.has_flushable_data());
+        assert_eq!(txbuf.flush_to(&mut sink).unwrap(), 5);
+        assert_eq!(sink.data, [1, 2, 3, 4, 5]);
+        assert_eq!(txbuf.len(), 1);
+        assert!(!txbuf.has_flushable_data());
+        sink.clear();
+
+        // A message wrapping around the end of the buffer is flushed whole.
+        let mut tmp: Vec<u8> = Vec::new();
+        tmp.resize(TxBuf::SIZE - 3, 0);
+        txbuf.push(tmp.as_slice()).unwrap();
+        txbuf.end_msg();
+        assert_eq!(txbuf.flush_to(&mut sink).unwrap(), TxBuf::SIZE - 2);
+        assert_eq!(sink.data[0], 6);
+        sink.clear();
+
+        txbuf.push(&[7, 8, 9, 10]).unwrap();
+        txbuf.end_msg();
+        assert_eq!(txbuf.flush_to(&mut sink).unwrap(), 4);
+        assert_eq!(sink.data, [7, 8, 9, 10]);
+        assert!(txbuf.is_empty());
+        sink.clear();
+
+        // A message is never split across writes.
+        sink.set_capacity(2);
+        txbuf.push(&[1, 2, 3, 4]).unwrap();
+        txbuf.end_msg();
+        match txbuf.flush_to(&mut sink) {
+            Err(Error::TxBufFlush(ref err)) if err.kind() == ErrorKind::WriteZero => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(txbuf.len(), 4);
+    }
+
     #[test]
     fn test_flush_error() {
         const EACCESS: i32 = 13;
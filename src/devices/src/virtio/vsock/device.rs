@@ -18,12 +18,14 @@ use std::result;
 /// Upon its activation, the vsock device registers handlers for the following events/FDs:
 /// - an RX queue FD;
 /// - a TX queue FD;
-/// - an event queue FD; and
-/// - a backend FD.
+/// - an event queue FD;
+/// - a backend FD; and
+/// - a rate limiter FD.
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
 use logger::{debug, error, warn, IncMetric, METRICS};
+use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
 use utils::byte_order;
 use utils::eventfd::EventFd;
 use vm_memory::{Bytes, GuestMemoryMmap};
@@ -31,7 +33,7 @@ use vm_memory::{Bytes, GuestMemoryMmap};
 use super::super::super::Error as DeviceError;
 use super::defs::uapi;
 use super::packet::{VsockPacket, VSOCK_PKT_HDR_SIZE};
-use super::{defs, VsockBackend};
+use super::{defs, VsockBackend, VsockConnectionInfo};
 use crate::virtio::{
     ActivateError, ActivateResult, DeviceState, IrqTrigger, IrqType, Queue as VirtQueue,
     VirtioDevice, VsockError,
@@ -58,6 +60,8 @@ pub struct Vsock<B> {
     pub(crate) avail_features: u64,
     pub(crate) acked_features: u64,
     pub(crate) irq_trigger: IrqTrigger,
+    // Limits the traffic in both directions, RX and TX packets drawing from the same budget.
+    pub(crate) rate_limiter: RateLimiter,
     // This EventFd is the only one initially registered for a vsock device, and is used to convert
     // a VirtioDevice::activate call into an EventHandler read event which allows the other events
     // (queue and backend related) to be registered post virtio device activation. That's
@@ -76,32 +80,43 @@ impl<B> Vsock<B>
 where
     B: VsockBackend,
 {
-    pub fn with_queues(cid: u64, backend: B, queues: Vec<VirtQueue>) -> super::Result<Vsock<B>> {
+    pub fn with_queues(
+        cid: u64,
+        backend: B,
+        rate_limiter: RateLimiter,
+        queues: Vec<VirtQueue>,
+    ) -> super::Result<Vsock<B>> {
         let mut queue_events = Vec::new();
         for _ in 0..queues.len() {
             queue_events.push(EventFd::new(libc::EFD_NONBLOCK).map_err(VsockError::EventFd)?);
         }
 
+        let mut avail_features = AVAIL_FEATURES;
+        if backend.supports_seqpacket() {
+            avail_features |= 1 << uapi::VIRTIO_VSOCK_F_SEQPACKET as u64;
+        }
+
         Ok(Vsock {
             cid,
             queues,
             queue_events,
             backend,
-            avail_features: AVAIL_FEATURES,
+            avail_features,
             acked_features: 0,
             irq_trigger: IrqTrigger::new().map_err(VsockError::EventFd)?,
+            rate_limiter,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(VsockError::EventFd)?,
             device_state: DeviceState::Inactive,
         })
     }
 
-    /// Create a new virtio-vsock device with the given VM CID and vsock backend.
-    pub fn new(cid: u64, backend: B) -> super::Result<Vsock<B>> {
+    /// Create a new virtio-vsock device with the given VM CID, vsock backend and rate limiter.
+    pub fn new(cid: u64, backend: B, rate_limiter: RateLimiter) -> super::Result<Vsock<B>> {
         let queues: Vec<VirtQueue> = defs::QUEUE_SIZES
             .iter()
             .map(|&max_size| VirtQueue::new(max_size))
             .collect();
-        Self::with_queues(cid, backend, queues)
+        Self::with_queues(cid, backend, rate_limiter, queues)
     }
 
     pub fn id(&self) -> &str {
@@ -116,6 +131,34 @@ where
         &self.backend
     }
 
+    /// Provides a reference to the rate limiter of the device.
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Update the parameters of the rate limiter.
+    pub fn update_rate_limiter(&mut self, bytes: BucketUpdate, ops: BucketUpdate) {
+        self.rate_limiter.update_buckets(bytes, ops);
+    }
+
+    /// Describe the connections currently handled by the backend.
+    pub fn connections(&self) -> Vec<VsockConnectionInfo> {
+        self.backend.connections()
+    }
+
+    /// Forcefully terminate the connection between the host `local_port` and the guest
+    /// `peer_port`. Returns false if there's no such connection.
+    pub fn reset_connection(&mut self, local_port: u32, peer_port: u32) -> bool {
+        if !self.backend.reset_connection(local_port, peer_port) {
+            return false;
+        }
+        // Let the guest know right away, instead of waiting for the next vsock event.
+        if self.device_state.is_activated() && self.process_rx() {
+            self.signal_used_queue().unwrap_or_default();
+        }
+        true
+    }
+
     /// Signal the guest driver that we've used some virtio buffers that it had previously made
     /// available.
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
@@ -138,7 +181,20 @@ where
         while let Some(head) = self.queues[RXQ_INDEX].pop(mem) {
             let used_len = match VsockPacket::from_rx_virtq_head(&head) {
                 Ok(mut pkt) => {
+                    // The packet length is only known after the backend has filled in the
+                    // buffer, so the whole buffer is charged up front and the unused part is
+                    // given back afterwards.
+                    let buf_size = pkt.buf_size() as u64;
+                    if !consume_rate_limiter_budget(&mut self.rate_limiter, buf_size) {
+                        self.queues[RXQ_INDEX].undo_pop();
+                        break;
+                    }
+
                     if self.backend.recv_pkt(&mut pkt, mem).is_ok() {
+                        self.rate_limiter.manual_replenish(
+                            buf_size.saturating_sub(u64::from(pkt.len())),
+                            TokenType::Bytes,
+                        );
                         match pkt.commit_hdr(mem) {
                             // This addition cannot overflow, because packet length
                             // is previously validated against `MAX_PKT_BUF_SIZE`
@@ -154,6 +210,7 @@ where
                             }
                         }
                     } else {
+                        replenish_rate_limiter_budget(&mut self.rate_limiter, buf_size);
                         // We are using a consuming iterator over the virtio buffers, so, if we
                         // can't fill in this buffer, we'll need to undo the
                         // last iterator step.
@@ -203,7 +260,14 @@ where
                 }
             };
 
+            let len = u64::from(pkt.len());
+            if !consume_rate_limiter_budget(&mut self.rate_limiter, len) {
+                self.queues[TXQ_INDEX].undo_pop();
+                break;
+            }
+
             if self.backend.send_pkt(&pkt, mem).is_err() {
+                replenish_rate_limiter_budget(&mut self.rate_limiter, len);
                 self.queues[TXQ_INDEX].undo_pop();
                 break;
             }
@@ -246,6 +310,29 @@ where
     }
 }
 
+// Takes one operation and `len` bytes out of the rate limiter budget. Returns false, leaving the
+// budget untouched, if rate limiting is in effect.
+fn consume_rate_limiter_budget(rate_limiter: &mut RateLimiter, len: u64) -> bool {
+    if !rate_limiter.consume(1, TokenType::Ops) {
+        METRICS.vsock.rate_limiter_throttled.inc();
+        return false;
+    }
+    if !rate_limiter.consume(len, TokenType::Bytes) {
+        // Revert the ops consume().
+        rate_limiter.manual_replenish(1, TokenType::Ops);
+        METRICS.vsock.rate_limiter_throttled.inc();
+        return false;
+    }
+    true
+}
+
+// Gives back the budget taken by `consume_rate_limiter_budget()` for a packet that didn't make it
+// through.
+fn replenish_rate_limiter_budget(rate_limiter: &mut RateLimiter, len: u64) {
+    rate_limiter.manual_replenish(1, TokenType::Ops);
+    rate_limiter.manual_replenish(len, TokenType::Bytes);
+}
+
 impl<B> VirtioDevice for Vsock<B>
 where
     B: VsockBackend + 'static,
@@ -332,6 +419,9 @@ where
             return Err(ActivateError::BadActivate);
         }
 
+        self.backend.set_seqpacket_acked(
+            self.acked_features & (1 << uapi::VIRTIO_VSOCK_F_SEQPACKET as u64) != 0,
+        );
         self.device_state = DeviceState::Activated(mem);
 
         Ok(())
@@ -24,6 +24,8 @@
 ///   - forward the event to the backend; then
 ///   - again, attempt to fetch any incoming packets queued by the backend into virtio RX
 ///     buffers.
+/// - on rate limiter event:
+///   - resume the TX and RX processing that was halted when the rate limiter budget ran out.
 use std::os::unix::io::AsRawFd;
 
 use event_manager::{EventOps, Events, MutEventSubscriber};
@@ -116,6 +118,24 @@ where
         raise_irq
     }
 
+    pub fn handle_rate_limiter_event(&mut self) -> bool {
+        debug!("vsock: rate limiter event");
+        METRICS.vsock.rate_limiter_event_count.inc();
+
+        // Upon rate limiter event, call the rate limiter handler and restart processing the
+        // queues.
+        if let Err(err) = self.rate_limiter.event_handler() {
+            error!("Failed to get vsock rate limiter event: {:?}", err);
+            METRICS.vsock.rate_limiter_event_fails.inc();
+            return false;
+        }
+        let mut raise_irq = self.process_tx();
+        if self.backend.has_pending_rx() {
+            raise_irq |= self.process_rx();
+        }
+        raise_irq
+    }
+
     fn register_runtime_events(&self, ops: &mut EventOps) {
         if let Err(err) = ops.add(Events::new(&self.queue_events[RXQ_INDEX], EventSet::IN)) {
             error!("Failed to register rx queue event: {}", err);
@@ -129,6 +149,9 @@ where
         if let Err(err) = ops.add(Events::new(&self.backend, self.backend.get_polled_evset())) {
             error!("Failed to register vsock backend event: {}", err);
         }
+        if let Err(err) = ops.add(Events::new(&self.rate_limiter, EventSet::IN)) {
+            error!("Failed to register vsock rate limiter event: {}", err);
+        }
     }
 
     fn register_activate_event(&self, ops: &mut EventOps) {
@@ -160,6 +183,7 @@ where
         let txq = self.queue_events[TXQ_INDEX].as_raw_fd();
         let evq = self.queue_events[EVQ_INDEX].as_raw_fd();
         let backend = self.backend.as_raw_fd();
+        let rate_limiter = self.rate_limiter.as_raw_fd();
         let activate_evt = self.activate_evt.as_raw_fd();
 
         if self.is_activated() {
@@ -171,6 +195,7 @@ where
                 _ if source == backend => {
                     raise_irq = self.notify_backend(evset);
                 }
+                _ if source == rate_limiter => raise_irq = self.handle_rate_limiter_event(),
                 _ if source == activate_evt => {
                     self.handle_activate_event(ops);
                 }
@@ -203,8 +228,11 @@ where
 #[cfg(test)]
 mod tests {
     use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
 
     use event_manager::{EventManager, SubscriberOps};
+    use rate_limiter::{RateLimiter, TokenType};
     use vm_memory::Bytes;
 
     use super::super::*;
@@ -448,6 +476,69 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_rate_limiter_event() {
+        // Test case:
+        // - the driver has something to send, but the ops budget is depleted; and
+        // - the rate limiter timer fires once the budget has been replenished.
+        {
+            let test_ctx = TestContext::new();
+            let mut ctx = test_ctx.create_event_handler_context();
+            ctx.mock_activate(test_ctx.mem.clone());
+
+            ctx.device.rate_limiter = RateLimiter::new(0, 0, 0, 1, 0, 100).unwrap();
+            assert!(ctx.device.rate_limiter.consume(1, TokenType::Ops));
+            ctx.device.backend.set_pending_rx(false);
+            ctx.signal_txq_event();
+
+            // The TX descriptor should still be available.
+            assert!(ctx.device.rate_limiter.is_blocked());
+            assert_eq!(ctx.guest_txvq.used.idx.get(), 0);
+
+            // Wait for 100ms to give the rate limiter timer a chance to replenish, plus an extra
+            // 50ms to make sure the timerfd event makes its way from the kernel.
+            thread::sleep(Duration::from_millis(150));
+
+            assert!(ctx.device.handle_rate_limiter_event());
+            assert!(!ctx.device.rate_limiter.is_blocked());
+            assert_eq!(ctx.guest_txvq.used.idx.get(), 1);
+        }
+
+        // Test case:
+        // - the backend has pending RX data, but there's not enough bandwidth budget left for
+        //   the whole RX buffer; and
+        // - the rate limiter timer fires once the budget has been replenished.
+        {
+            let test_ctx = TestContext::new();
+            let mut ctx = test_ctx.create_event_handler_context();
+            ctx.mock_activate(test_ctx.mem.clone());
+
+            ctx.device.rate_limiter = RateLimiter::new(4096, 0, 100, 0, 0, 0).unwrap();
+            assert!(ctx.device.rate_limiter.consume(1, TokenType::Bytes));
+            ctx.device.backend.set_pending_rx(true);
+            ctx.signal_rxq_event();
+
+            // The RX descriptor should still be available.
+            assert!(ctx.device.rate_limiter.is_blocked());
+            assert_eq!(ctx.guest_rxvq.used.idx.get(), 0);
+
+            thread::sleep(Duration::from_millis(150));
+
+            assert!(ctx.device.handle_rate_limiter_event());
+            assert_eq!(ctx.guest_rxvq.used.idx.get(), 1);
+        }
+
+        // Test case: spurious rate limiter event.
+        {
+            let test_ctx = TestContext::new();
+            let mut ctx = test_ctx.create_event_handler_context();
+            ctx.mock_activate(test_ctx.mem.clone());
+
+            assert!(!ctx.device.handle_rate_limiter_event());
+            assert_eq!(ctx.guest_txvq.used.idx.get(), 0);
+        }
+    }
+
     #[test]
     fn test_vsock_bof() {
         use vm_memory::GuestAddress;
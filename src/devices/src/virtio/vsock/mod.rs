@@ -8,8 +8,10 @@
 mod csm;
 mod device;
 mod event_handler;
+mod muxer;
 mod packet;
 pub mod persist;
+mod tcp;
 pub mod test_utils;
 mod unix;
 
@@ -19,9 +21,13 @@ use packet::VsockPacket;
 use utils::epoll::EventSet;
 use vm_memory::{GuestMemoryError, GuestMemoryMmap};
 
+pub use self::csm::VsockConnectionInfo;
 pub use self::defs::uapi::VIRTIO_ID_VSOCK as TYPE_VSOCK;
 pub use self::defs::VSOCK_DEV_ID;
 pub use self::device::Vsock;
+pub use self::tcp::{
+    Error as VsockTcpBackendError, VsockTcpBackend, VsockTcpDirection, VsockTcpPort,
+};
 pub use self::unix::{Error as VsockUnixBackendError, VsockUnixBackend};
 use crate::virtio::persist::Error as VirtioStateError;
 
@@ -52,6 +58,12 @@ mod defs {
         /// The device conforms to the virtio spec version 1.0.
         pub const VIRTIO_F_VERSION_1: u32 = 32;
 
+        /// Virtio vsock feature flags.
+        /// Defined in `/include/uapi/linux/virtio_vsock.h`.
+        ///
+        /// The device supports SOCK_SEQPACKET connections.
+        pub const VIRTIO_VSOCK_F_SEQPACKET: u32 = 1;
+
         /// Virtio vsock device ID.
         /// Defined in `include/uapi/linux/virtio_ids.h`.
         pub const VIRTIO_ID_VSOCK: u32 = 19;
@@ -81,12 +93,21 @@ mod defs {
         pub const VSOCK_FLAGS_SHUTDOWN_RCV: u32 = 1;
         /// Valid with a VSOCK_OP_SHUTDOWN packet: the packet sender will send no more data.
         pub const VSOCK_FLAGS_SHUTDOWN_SEND: u32 = 2;
+        /// Valid with a VSOCK_OP_RW packet of a seqpacket connection: the packet holds the end
+        /// of a message.
+        pub const VSOCK_FLAGS_SEQ_EOM: u32 = 1;
+        /// Valid with a VSOCK_OP_RW packet of a seqpacket connection: the packet holds the end
+        /// of a record (i.e. the message was sent with MSG_EOR).
+        pub const VSOCK_FLAGS_SEQ_EOR: u32 = 2;
 
         /// Vsock packet type.
         /// Defined in `/include/uapi/linux/virtio_vsock.h`.
         ///
-        /// Stream / connection-oriented packet (the only currently valid type).
+        /// Stream / connection-oriented packet.
         pub const VSOCK_TYPE_STREAM: u16 = 1;
+        /// Seqpacket / message-oriented packet. Only valid if VIRTIO_VSOCK_F_SEQPACKET was
+        /// negotiated.
+        pub const VSOCK_TYPE_SEQPACKET: u16 = 2;
 
         pub const VSOCK_HOST_CID: u64 = 2;
     }
@@ -114,6 +135,8 @@ pub enum VsockError {
     NoData,
     /// A data buffer was expected for the provided packet, but it is missing.
     PktBufMissing,
+    /// Failed to restore the rate limiter.
+    RateLimiter(std::io::Error),
     /// Encountered an unexpected write-only virtio descriptor.
     UnreadableDescriptor,
     /// Encountered an unexpected read-only virtio descriptor.
@@ -159,6 +182,32 @@ pub trait VsockChannel {
 }
 
 /// The vsock backend, which is basically an epoll-event-driven vsock channel.
-/// Currently, the only implementation we have is `crate::virtio::unix::muxer::VsockMuxer`, which
-/// translates guest-side vsock connections to host-side Unix domain socket connections.
-pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {}
+/// It is implemented by `crate::virtio::vsock::muxer::VsockMuxer`, over either:
+/// - `crate::virtio::vsock::unix::UnixHost`, which translates guest-side vsock connections to
+///   host-side Unix domain socket connections; or
+/// - `crate::virtio::vsock::tcp::TcpHost`, which translates them to host-side TCP
+///   connections, following a port-forwarding table.
+pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {
+    /// Whether the backend can carry seqpacket connections, in which case the device offers
+    /// VIRTIO_VSOCK_F_SEQPACKET to the guest.
+    fn supports_seqpacket(&self) -> bool {
+        false
+    }
+
+    /// Let the backend know whether the guest driver acked VIRTIO_VSOCK_F_SEQPACKET. Until it
+    /// does, seqpacket packets are refused.
+    fn set_seqpacket_acked(&mut self, _acked: bool) {}
+
+    /// Describe the connections currently handled by the backend.
+    fn connections(&self) -> Vec<VsockConnectionInfo>;
+
+    /// Schedule the connection between the host `local_port` and the guest `peer_port` for
+    /// forceful termination, via an RST packet. Returns false if there's no such connection.
+    fn reset_connection(&mut self, local_port: u32, peer_port: u32) -> bool;
+
+    /// Whether the backend carries its connections over across snapshot/restore, in which
+    /// case the guest doesn't need to be told to reset them when a snapshot is taken.
+    fn persists_connections(&self) -> bool {
+        false
+    }
+}
@@ -2,10 +2,13 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-/// `VsockMuxer` is the device-facing component of the Unix domain sockets vsock backend. I.e.
-/// by implementing the `VsockBackend` trait, it abstracts away the gory details of translating
-/// between AF_VSOCK and AF_UNIX, and presents a clean interface to the rest of the vsock
-/// device model.
+/// `VsockMuxer` is the device-facing component of the vsock backends. I.e. by implementing the
+/// `VsockBackend` trait, it abstracts away the gory details of translating between AF_VSOCK and
+/// host sockets, and presents a clean interface to the rest of the vsock device model.
+///
+/// The muxer is generic over a `MuxerHost`, which knows how to listen on, accept and connect
+/// to the host sockets of a given family (e.g. AF_UNIX, for `VsockUnixBackend`, and AF_INET,
+/// for `VsockTcpBackend`). Everything else is common to all backends.
 ///
 /// The vsock muxer has two main roles:
 /// 1. Vsock connection multiplexer:
@@ -17,44 +20,131 @@
 ///    (leading to the termination of an existing connection). All other packets, though, must
 ///    belong to an existing connection and, as such, the muxer simply forwards them.
 /// 2. Event dispatcher
-///    There are three event categories that the vsock backend is interested it:
-///    1. A new host-initiated connection is ready to be accepted from the listening host Unix
-///       socket;
+///    There are four event categories that the vsock backend is interested it:
+///    1. A new host-initiated connection is ready to be accepted from a listening host socket;
 ///    2. Data is available for reading from a newly-accepted host-initiated connection (i.e.
 ///       the host is ready to issue a vsock connection request, informing us of the
-///       destination port to which it wants to connect);
-///    3. Some event was triggered for a connected Unix socket, that belongs to a
+///       destination port to which it wants to connect), for hosts whose listeners aren't
+///       tied to a destination port;
+///    3. A host socket, connected on behalf of a guest-initiated connection, has completed
+///       its connection attempt;
+///    4. Some event was triggered for a connected host socket, that belongs to a
 ///       `VsockConnection`.
 ///    The muxer gets notified about all of these events, because, as a `VsockEpollListener`
 ///    implementor, it gets to register a nested epoll FD into the main VMM epolling loop. All
 ///    other pollable FDs are then registered under this nested epoll FD.
 ///    To route all these events to their handlers, the muxer uses another `HashMap` object,
 ///    mapping `RawFd`s to `EpollListener`s.
+mod muxer_killq;
+mod muxer_rxq;
+
 use std::collections::{HashMap, HashSet};
-use std::io::Read;
+use std::fmt::Debug;
+use std::io::{Read, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::os::unix::net::{UnixListener, UnixStream};
 
 use logger::{debug, error, info, warn, IncMetric, METRICS};
+use snapshot::Persist;
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 use vm_memory::GuestMemoryMmap;
 
-use super::super::csm::ConnState;
-use super::super::defs::uapi;
-use super::super::packet::VsockPacket;
-use super::super::{
-    Result as VsockResult, VsockBackend, VsockChannel, VsockEpollListener, VsockError,
+use self::muxer_killq::MuxerKillQ;
+use self::muxer_rxq::MuxerRxQ;
+use super::csm::{ConnState, VsockConnection};
+use super::defs::uapi;
+use super::packet::VsockPacket;
+use super::persist::{VsockConnectionConstructorArgs, VsockConnectionState};
+use super::{
+    Result as VsockResult, VsockBackend, VsockChannel, VsockConnectionInfo, VsockEpollListener,
+    VsockError,
 };
-use super::muxer_killq::MuxerKillQ;
-use super::muxer_rxq::MuxerRxQ;
-use super::{defs, Error, MuxerConnection, Result};
+
+mod defs {
+    /// Maximum number of established connections that we can handle.
+    pub const MAX_CONNECTIONS: usize = 1023;
+
+    /// Size of the muxer RX packet queue.
+    pub const MUXER_RXQ_SIZE: usize = 256;
+
+    /// Size of the muxer connection kill queue.
+    pub const MUXER_KILLQ_SIZE: usize = 128;
+}
+
+/// The errors raised by the muxer itself, regardless of its host. They are converted into the
+/// error type of the host.
+#[derive(Debug)]
+pub enum MuxerError {
+    /// The saved connection can't be resumed.
+    ConnectionNotResumable,
+    /// Error resuming a saved connection.
+    ConnectionRestore(super::csm::Error),
+    /// Error registering a new epoll-listening FD.
+    EpollAdd(std::io::Error),
+    /// Error creating an epoll FD.
+    EpollFdCreate(std::io::Error),
+    /// The host made an invalid vsock port connection request.
+    InvalidPortRequest,
+    /// Muxer connection limit reached.
+    TooManyConnections,
+}
+
+/// The host side of a `VsockMuxer`, i.e. the host sockets that vsock connections are carried
+/// over, and how they are listened on, accepted and connected to.
+pub trait MuxerHost: Send {
+    /// The host socket a vsock connection is carried over.
+    type Stream: Read + Write + AsRawFd + Send;
+    /// The host socket that host-initiated connections are accepted from.
+    type Listener: AsRawFd + Send;
+    /// The error type of the backend.
+    type Error: Debug + From<MuxerError>;
+
+    /// Bind the host sockets that host-initiated connections are accepted from.
+    fn listen(&self) -> Result<Vec<Self::Listener>, Self::Error>;
+
+    /// Accept a host-initiated connection from `listener`. Returns the nonblocking stream,
+    /// along with the guest port the connection is forwarded to, if `listener` is tied to one.
+    /// Otherwise, the port is read from the stream, via `read_port()`.
+    fn accept(&self, listener: &Self::Listener)
+        -> Result<(Self::Stream, Option<u32>), Self::Error>;
+
+    /// Read the guest port that a host-initiated connection is forwarded to, from the freshly
+    /// accepted `stream`, once it becomes readable.
+    fn read_port(&self, _stream: &mut Self::Stream) -> Result<u32, Self::Error> {
+        Err(MuxerError::InvalidPortRequest.into())
+    }
+
+    /// Start connecting to the host socket that guest connections to `port`, of the vsock
+    /// socket type `type_`, are forwarded to, without blocking. Returns the nonblocking stream,
+    /// along with whether the connection attempt has completed already. Otherwise, the stream
+    /// becomes writable once it completes. Either way, the outcome of the attempt is then
+    /// fetched via `finish_connect()`.
+    fn connect(&self, port: u32, type_: u16) -> Result<(Self::Stream, bool), Self::Error>;
+
+    /// Fetch the outcome of a completed connection attempt, started by `connect()`.
+    fn finish_connect(&self, stream: Self::Stream) -> Result<Self::Stream, Self::Error> {
+        Ok(stream)
+    }
+
+    /// The message that lets the host end of a host-initiated connection know that the guest
+    /// accepted it, if any.
+    fn local_init_ack(&self, _local_port: u32) -> Option<String> {
+        None
+    }
+
+    /// Whether guest seqpacket connections can be carried over the host sockets.
+    fn supports_seqpacket(&self) -> bool {
+        false
+    }
+}
+
+type MuxerConnection<H> = VsockConnection<<H as MuxerHost>::Stream>;
 
 /// A unique identifier of a `MuxerConnection` object. Connections are stored in a hash map,
 /// keyed by a `ConnMapKey` object.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct ConnMapKey {
-    local_port: u32,
-    peer_port: u32,
+    pub(crate) local_port: u32,
+    pub(crate) peer_port: u32,
 }
 
 /// A muxer RX queue item.
@@ -62,43 +152,55 @@ pub struct ConnMapKey {
 pub enum MuxerRx {
     /// The packet must be fetched from the connection identified by `ConnMapKey`.
     ConnRx(ConnMapKey),
-    /// The muxer must produce an RST packet.
-    RstPkt { local_port: u32, peer_port: u32 },
+    /// The muxer must produce an RST packet, of the given socket type.
+    RstPkt {
+        local_port: u32,
+        peer_port: u32,
+        type_: u16,
+    },
 }
 
 /// An epoll listener, registered under the muxer's nested epoll FD.
-enum EpollListener {
+enum EpollListener<H: MuxerHost> {
     /// The listener is a `MuxerConnection`, identified by `key`, and interested in the events
     /// in `evset`. Since `MuxerConnection` implements `VsockEpollListener`, notifications will
     /// be forwarded to the listener via `VsockEpollListener::notify()`.
     Connection { key: ConnMapKey, evset: EventSet },
     /// A listener interested in new host-initiated connections.
-    HostSock,
-    /// A listener interested in reading host "connect <port>" commands from a freshly
-    /// connected host socket.
-    LocalStream(UnixStream),
+    HostSock(H::Listener),
+    /// A listener interested in reading the destination port from a freshly accepted host
+    /// stream (e.g. a "connect <port>" command).
+    LocalStream(H::Stream),
+    /// A guest-initiated connection, identified by `key`, whose host connect is still in
+    /// progress. `buf_alloc` and `type_` come from the guest connection request.
+    Connecting {
+        key: ConnMapKey,
+        stream: H::Stream,
+        buf_alloc: u32,
+        type_: u16,
+    },
 }
 
 /// The vsock connection multiplexer.
-pub struct VsockMuxer {
+pub struct VsockMuxer<H: MuxerHost> {
     /// Guest CID.
     cid: u64,
+    /// The host side of the muxer.
+    host: H,
     /// A hash map used to store the active connections.
-    conn_map: HashMap<ConnMapKey, MuxerConnection>,
+    conn_map: HashMap<ConnMapKey, MuxerConnection<H>>,
     /// A hash map used to store epoll event listeners / handlers.
-    listener_map: HashMap<RawFd, EpollListener>,
+    listener_map: HashMap<RawFd, EpollListener<H>>,
+    /// The guest-initiated connections whose host connect is still in progress, mapped to the
+    /// FD of their `EpollListener::Connecting` listener.
+    connecting: HashMap<ConnMapKey, RawFd>,
     /// The RX queue. Items in this queue are consumed by `VsockMuxer::recv_pkt()`, and
     /// produced
     /// - by `VsockMuxer::send_pkt()` (e.g. RST in response to a connection request packet); and
-    /// - in response to EPOLLIN events (e.g. data available to be read from an AF_UNIX socket).
+    /// - in response to EPOLLIN events (e.g. data available to be read from a host socket).
     rxq: MuxerRxQ,
     /// A queue used for terminating connections that are taking too long to shut down.
     killq: MuxerKillQ,
-    /// The Unix socket, through which host-initiated connections are accepted.
-    host_sock: UnixListener,
-    /// The file system path of the host-side Unix socket. This is used to figure out the path
-    /// to Unix sockets listening on specific ports. I.e. "<this path>_<port number>".
-    pub(crate) host_sock_path: String,
     /// The nested epoll event set, used to register epoll listeners.
     epoll: Epoll,
     /// A hash set used to keep track of used host-side (local) ports, in order to assign local
@@ -106,9 +208,13 @@ pub struct VsockMuxer {
     local_port_set: HashSet<u32>,
     /// The last used host-side port.
     local_port_last: u32,
+    /// Whether the connections are carried over across snapshot/restore.
+    persist_connections: bool,
+    /// Whether the guest driver acked VIRTIO_VSOCK_F_SEQPACKET.
+    seqpacket_acked: bool,
 }
 
-impl VsockChannel for VsockMuxer {
+impl<H: MuxerHost> VsockChannel for VsockMuxer<H> {
     /// Deliver a vsock packet to the guest vsock driver.
     ///
     /// Retuns:
@@ -129,6 +235,7 @@ impl VsockChannel for VsockMuxer {
                 MuxerRx::RstPkt {
                     local_port,
                     peer_port,
+                    type_,
                 } => {
                     pkt.set_op(uapi::VSOCK_OP_RST)
                         .set_src_cid(uapi::VSOCK_HOST_CID)
@@ -136,7 +243,7 @@ impl VsockChannel for VsockMuxer {
                         .set_src_port(local_port)
                         .set_dst_port(peer_port)
                         .set_len(0)
-                        .set_type(uapi::VSOCK_TYPE_STREAM)
+                        .set_type(type_)
                         .set_flags(0)
                         .set_buf_alloc(0)
                         .set_fwd_cnt(0);
@@ -199,10 +306,12 @@ impl VsockChannel for VsockMuxer {
             pkt.hdr()
         );
 
-        // If this packet has an unsupported type (!=stream), we must send back an RST.
-        //
-        if pkt.type_() != uapi::VSOCK_TYPE_STREAM {
-            self.enq_rst(pkt.dst_port(), pkt.src_port());
+        // If this packet has an unsupported type (neither stream, nor seqpacket, if the guest
+        // driver acked the feature), we must send back an RST.
+        let supported_type = pkt.type_() == uapi::VSOCK_TYPE_STREAM
+            || (pkt.type_() == uapi::VSOCK_TYPE_SEQPACKET && self.seqpacket_acked);
+        if !supported_type {
+            self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
             return Ok(());
         }
 
@@ -216,6 +325,16 @@ impl VsockChannel for VsockMuxer {
             return Ok(());
         }
 
+        if let Some(fd) = self.connecting.remove(&conn_key) {
+            // The guest isn't expecting anything but a response to its connection request, so
+            // any packet (most likely an RST, if the guest timed out) aborts the connect.
+            self.remove_listener(fd);
+            if pkt.op() != uapi::VSOCK_OP_RST {
+                self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
+            }
+            return Ok(());
+        }
+
         if !self.conn_map.contains_key(&conn_key) {
             // This packet can't be routed to any active connection (based on its src and dst
             // ports).  The only orphan / unroutable packets we know how to handle are
@@ -225,7 +344,7 @@ impl VsockChannel for VsockMuxer {
                 self.handle_peer_request_pkt(pkt);
             } else {
                 // Send back an RST, to let the drive know we weren't expecting this packet.
-                self.enq_rst(pkt.dst_port(), pkt.src_port());
+                self.enq_rst(pkt.dst_port(), pkt.src_port(), pkt.type_());
             }
             return Ok(());
         }
@@ -254,7 +373,7 @@ impl VsockChannel for VsockMuxer {
     }
 }
 
-impl AsRawFd for VsockMuxer {
+impl<H: MuxerHost> AsRawFd for VsockMuxer<H> {
     /// Get the FD to be registered for polling upstream (in the main VMM epoll loop, in this
     /// case).
     ///
@@ -264,7 +383,7 @@ impl AsRawFd for VsockMuxer {
     }
 }
 
-impl VsockEpollListener for VsockMuxer {
+impl<H: MuxerHost> VsockEpollListener for VsockMuxer<H> {
     /// Get the epoll events to be polled upstream.
     ///
     /// Since the polled FD is a nested epoll FD, we're only interested in EPOLLIN events (i.e.
@@ -298,37 +417,137 @@ impl VsockEpollListener for VsockMuxer {
     }
 }
 
-impl VsockBackend for VsockMuxer {}
+impl<H: MuxerHost> VsockBackend for VsockMuxer<H> {
+    fn supports_seqpacket(&self) -> bool {
+        self.host.supports_seqpacket()
+    }
 
-impl VsockMuxer {
-    /// Muxer constructor.
-    pub fn new(cid: u64, host_sock_path: String) -> Result<Self> {
-        // Open/bind on the host Unix socket, so we can accept host-initiated
-        // connections.
-        let host_sock = UnixListener::bind(&host_sock_path)
-            .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
-            .map_err(Error::UnixBind)?;
+    fn set_seqpacket_acked(&mut self, acked: bool) {
+        self.seqpacket_acked = acked && self.host.supports_seqpacket();
+    }
+
+    fn connections(&self) -> Vec<VsockConnectionInfo> {
+        let mut conns: Vec<VsockConnectionInfo> =
+            self.conn_map.values().map(|conn| conn.info()).collect();
+        conns.sort_by_key(|info| (info.local_port, info.peer_port));
+        conns
+    }
+
+    fn reset_connection(&mut self, local_port: u32, peer_port: u32) -> bool {
+        let key = ConnMapKey {
+            local_port,
+            peer_port,
+        };
+        if !self.conn_map.contains_key(&key) {
+            return false;
+        }
+        self.kill_connection(key);
+        true
+    }
+
+    fn persists_connections(&self) -> bool {
+        self.persist_connections
+    }
+}
+
+impl<H: MuxerHost> VsockMuxer<H> {
+    /// Create a muxer for the guest `cid`, over the host sockets of `host`.
+    pub(crate) fn with_host(cid: u64, host: H) -> Result<Self, H::Error> {
+        // Bind the host sockets, so we can accept host-initiated connections.
+        let listeners = host.listen()?;
 
         let mut muxer = Self {
             cid,
-            host_sock,
-            host_sock_path,
-            epoll: Epoll::new().map_err(Error::EpollFdCreate)?,
+            host,
+            epoll: Epoll::new().map_err(MuxerError::EpollFdCreate)?,
             rxq: MuxerRxQ::new(),
             conn_map: HashMap::with_capacity(defs::MAX_CONNECTIONS),
-            listener_map: HashMap::with_capacity(defs::MAX_CONNECTIONS + 1),
+            listener_map: HashMap::with_capacity(defs::MAX_CONNECTIONS + listeners.len()),
+            connecting: HashMap::new(),
             killq: MuxerKillQ::new(),
             local_port_last: (1u32 << 30) - 1,
             local_port_set: HashSet::with_capacity(defs::MAX_CONNECTIONS),
+            persist_connections: false,
+            seqpacket_acked: false,
         };
 
-        // Listen on the host initiated socket, for incoming connections.
-        muxer.add_listener(muxer.host_sock.as_raw_fd(), EpollListener::HostSock)?;
+        // Listen on the host sockets, for incoming connections.
+        for listener in listeners {
+            muxer.add_listener(listener.as_raw_fd(), EpollListener::HostSock(listener))?;
+        }
         Ok(muxer)
     }
 
-    pub fn host_sock_path(&self) -> &str {
-        &self.host_sock_path
+    /// The host side of the muxer.
+    pub(crate) fn host(&self) -> &H {
+        &self.host
+    }
+
+    /// Set whether the connections are carried over across snapshot/restore, instead of
+    /// having the guest reset them.
+    pub fn set_persist_connections(&mut self, persist_connections: bool) {
+        self.persist_connections = persist_connections;
+    }
+
+    /// Save the state of the active connections.
+    pub(crate) fn save_connections(&self) -> Vec<VsockConnectionState> {
+        self.conn_map
+            .iter()
+            .map(|(key, conn)| {
+                let mut state = conn.save();
+                // The host end of a host-initiated connection is gone along with the host
+                // process that initiated it, so there is nothing to reconnect to.
+                state.reconnect &= !self.local_port_set.contains(&key.local_port);
+                state
+            })
+            .collect()
+    }
+
+    /// Resume the connections saved by `save_connections()`. Guest-initiated connections are
+    /// reconnected to the host socket that their port is forwarded to, and the guest is sent an
+    /// RST for any connection that can't be resumed.
+    pub(crate) fn restore_connections(&mut self, connections: &[VsockConnectionState]) {
+        for state in connections {
+            let key = ConnMapKey {
+                local_port: state.local_port,
+                peer_port: state.peer_port,
+            };
+            let restored = if state.reconnect {
+                self.host
+                    .connect(state.local_port, state.type_)
+                    .and_then(|(stream, connected)| {
+                        // The connection state can't be resumed over a host stream that
+                        // isn't connected yet.
+                        if connected {
+                            self.host.finish_connect(stream)
+                        } else {
+                            Err(MuxerError::ConnectionNotResumable.into())
+                        }
+                    })
+                    .and_then(|stream| {
+                        MuxerConnection::<H>::restore(
+                            VsockConnectionConstructorArgs {
+                                stream,
+                                local_cid: uapi::VSOCK_HOST_CID,
+                                peer_cid: self.cid,
+                            },
+                            state,
+                        )
+                        .map_err(|err| MuxerError::ConnectionRestore(err).into())
+                    })
+                    .and_then(|conn| self.add_connection(key, conn))
+            } else {
+                Err(MuxerError::ConnectionNotResumable.into())
+            };
+
+            restored.unwrap_or_else(|err| {
+                info!(
+                    "vsock: unable to resume connection lp={}, pp={}: {:?}",
+                    state.local_port, state.peer_port, err
+                );
+                self.enq_rst(state.local_port, state.peer_port, state.type_);
+            });
+        }
     }
 
     /// Handle/dispatch an epoll event to its listener.
@@ -353,29 +572,25 @@ impl VsockMuxer {
             }
 
             // A new host-initiated connection is ready to be accepted.
-            Some(EpollListener::HostSock) => {
+            Some(EpollListener::HostSock(listener)) => {
+                let accepted = self.host.accept(listener);
                 if self.conn_map.len() == defs::MAX_CONNECTIONS {
-                    // If we're already maxed-out on connections, we'll just accept and
-                    // immediately discard this potentially new one.
+                    // If we're already maxed-out on connections, we'll just discard this
+                    // potentially new one.
                     warn!("vsock: connection limit reached; refusing new host connection");
-                    self.host_sock.accept().map(|_| 0).unwrap_or(0);
                     return;
                 }
-                self.host_sock
-                    .accept()
-                    .map_err(Error::UnixAccept)
-                    .and_then(|(stream, _)| {
-                        stream
-                            .set_nonblocking(true)
-                            .map(|_| stream)
-                            .map_err(Error::UnixAccept)
-                    })
-                    .and_then(|stream| {
+                accepted
+                    .and_then(|(stream, peer_port)| match peer_port {
+                        // The listener is tied to a guest port, so the connection can be
+                        // forwarded to the guest right away.
+                        Some(peer_port) => self.add_local_init_connection(stream, peer_port),
                         // Before forwarding this connection to a listening AF_VSOCK socket on
                         // the guest side, we need to know the destination port. We'll read
-                        // that port from a "connect" command received on this socket, so the
-                        // next step is to ask to be notified the moment we can read from it.
-                        self.add_listener(stream.as_raw_fd(), EpollListener::LocalStream(stream))
+                        // that port from the stream, so the next step is to ask to be notified
+                        // the moment we can read from it.
+                        None => self
+                            .add_listener(stream.as_raw_fd(), EpollListener::LocalStream(stream)),
                     })
                     .unwrap_or_else(|err| {
                         warn!("vsock: unable to accept local connection: {:?}", err);
@@ -383,33 +598,34 @@ impl VsockMuxer {
             }
 
             // Data is ready to be read from a host-initiated connection. That would be the
-            // "connect" command that we're expecting.
+            // destination port that we're expecting.
             Some(EpollListener::LocalStream(_)) => {
                 if let Some(EpollListener::LocalStream(mut stream)) = self.remove_listener(fd) {
-                    Self::read_local_stream_port(&mut stream)
-                        .map(|peer_port| (self.allocate_local_port(), peer_port))
-                        .and_then(|(local_port, peer_port)| {
-                            self.add_connection(
-                                ConnMapKey {
-                                    local_port,
-                                    peer_port,
-                                },
-                                MuxerConnection::new_local_init(
-                                    stream,
-                                    uapi::VSOCK_HOST_CID,
-                                    self.cid,
-                                    local_port,
-                                    peer_port,
-                                ),
-                            )
-                        })
+                    self.host
+                        .read_port(&mut stream)
+                        .and_then(|peer_port| self.add_local_init_connection(stream, peer_port))
                         .unwrap_or_else(|err| {
                             info!("vsock: error adding local-init connection: {:?}", err);
                         })
                 }
             }
 
-            _ => {
+            // The host connect of a guest-initiated connection has completed.
+            Some(EpollListener::Connecting { .. }) => {
+                if let Some(EpollListener::Connecting {
+                    key,
+                    stream,
+                    buf_alloc,
+                    type_,
+                }) = self.remove_listener(fd)
+                {
+                    self.connecting.remove(&key);
+                    let res = self.host.finish_connect(stream);
+                    self.complete_peer_connect(key, res, buf_alloc, type_);
+                }
+            }
+
+            None => {
                 info!(
                     "vsock: unexpected event: fd={:?}, evset={:?}",
                     fd, event_set
@@ -419,51 +635,39 @@ impl VsockMuxer {
         }
     }
 
-    /// Parse a host "connect" command, and extract the destination vsock port.
-    fn read_local_stream_port(stream: &mut UnixStream) -> Result<u32> {
-        let mut buf = [0u8; 32];
-
-        // This is the minimum number of bytes that we should be able to read, when parsing a
-        // valid connection request. I.e. `b"connect 0\n".len()`.
-        const MIN_READ_LEN: usize = 10;
-
-        // Bring in the minimum number of bytes that we should be able to read.
-        stream
-            .read_exact(&mut buf[..MIN_READ_LEN])
-            .map_err(Error::UnixRead)?;
-
-        // Now, finish reading the destination port number, by bringing in one byte at a time,
-        // until we reach an EOL terminator (or our buffer space runs out).  Yeah, not
-        // particularly proud of this approach, but it will have to do for now.
-        let mut blen = MIN_READ_LEN;
-        while buf[blen - 1] != b'\n' && blen < buf.len() {
-            stream
-                .read_exact(&mut buf[blen..=blen])
-                .map_err(Error::UnixRead)?;
-            blen += 1;
-        }
-
-        let mut word_iter = std::str::from_utf8(&buf[..blen])
-            .map_err(|_| Error::InvalidPortRequest)?
-            .split_whitespace();
-
-        word_iter
-            .next()
-            .ok_or(Error::InvalidPortRequest)
-            .and_then(|word| {
-                if word.to_lowercase() == "connect" {
-                    Ok(())
-                } else {
-                    Err(Error::InvalidPortRequest)
-                }
-            })
-            .and_then(|_| word_iter.next().ok_or(Error::InvalidPortRequest))
-            .and_then(|word| word.parse::<u32>().map_err(|_| Error::InvalidPortRequest))
-            .map_err(|_| Error::InvalidPortRequest)
+    /// Add a new host-initiated connection, forwarded to the guest `peer_port`, to the active
+    /// connection pool.
+    fn add_local_init_connection(
+        &mut self,
+        stream: H::Stream,
+        peer_port: u32,
+    ) -> Result<(), H::Error> {
+        let local_port = self.allocate_local_port();
+        self.add_connection(
+            ConnMapKey {
+                local_port,
+                peer_port,
+            },
+            MuxerConnection::<H>::new_local_init(
+                stream,
+                uapi::VSOCK_HOST_CID,
+                self.cid,
+                local_port,
+                peer_port,
+            ),
+        )
+        .map_err(|err| {
+            self.free_local_port(local_port);
+            err
+        })
     }
 
     /// Add a new connection to the active connection pool.
-    fn add_connection(&mut self, key: ConnMapKey, conn: MuxerConnection) -> Result<()> {
+    fn add_connection(
+        &mut self,
+        key: ConnMapKey,
+        conn: MuxerConnection<H>,
+    ) -> Result<(), H::Error> {
         // We might need to make room for this new connection, so let's sweep the kill queue
         // first.  It's fine to do this here because:
         // - unless the kill queue is out of sync, this is a pretty inexpensive operation; and
@@ -475,7 +679,7 @@ impl VsockMuxer {
                 "vsock: muxer connection limit reached ({})",
                 defs::MAX_CONNECTIONS
             );
-            return Err(Error::TooManyConnections);
+            return Err(MuxerError::TooManyConnections.into());
         }
 
         self.add_listener(
@@ -528,11 +732,14 @@ impl VsockMuxer {
     }
 
     /// Register a new epoll listener under the muxer's nested epoll FD.
-    fn add_listener(&mut self, fd: RawFd, listener: EpollListener) -> Result<()> {
+    fn add_listener(&mut self, fd: RawFd, listener: EpollListener<H>) -> Result<(), H::Error> {
         let evset = match listener {
             EpollListener::Connection { evset, .. } => evset,
             EpollListener::LocalStream(_) => EventSet::IN,
-            EpollListener::HostSock => EventSet::IN,
+            EpollListener::HostSock(_) => EventSet::IN,
+            // A connect attempt completes by making the socket writable, or by reporting an
+            // error.
+            EpollListener::Connecting { .. } => EventSet::OUT,
         };
 
         self.epoll
@@ -540,13 +747,13 @@ impl VsockMuxer {
             .map(|_| {
                 self.listener_map.insert(fd, listener);
             })
-            .map_err(Error::EpollAdd)?;
+            .map_err(MuxerError::EpollAdd)?;
 
         Ok(())
     }
 
     /// Remove (and return) a previously registered epoll listener.
-    fn remove_listener(&mut self, fd: RawFd) -> Option<EpollListener> {
+    fn remove_listener(&mut self, fd: RawFd) -> Option<EpollListener<H>> {
         let maybe_listener = self.listener_map.remove(&fd);
 
         if maybe_listener.is_some() {
@@ -585,33 +792,86 @@ impl VsockMuxer {
 
     /// Handle a new connection request comming from our peer (the guest vsock driver).
     ///
-    /// This will attempt to connect to a host-side Unix socket, expected to be listening at
-    /// the file system path corresponing to the destination port. If successful, a new
-    /// connection object will be created and added to the connection pool. On failure, a new
-    /// RST packet will be scheduled for delivery to the guest.
+    /// This will start connecting to the host socket that the destination port is forwarded
+    /// to. Unless the connection is established right away, the connect is completed once the
+    /// host socket becomes writable. If successful, a new connection object will be created and
+    /// added to the connection pool. On failure, a new RST packet will be scheduled for
+    /// delivery to the guest.
     fn handle_peer_request_pkt(&mut self, pkt: &VsockPacket) {
-        let port_path = format!("{}_{}", self.host_sock_path, pkt.dst_port());
+        let key = ConnMapKey {
+            local_port: pkt.dst_port(),
+            peer_port: pkt.src_port(),
+        };
+
+        if self.conn_map.len() + self.connecting.len() >= defs::MAX_CONNECTIONS {
+            info!(
+                "vsock: muxer connection limit reached ({})",
+                defs::MAX_CONNECTIONS
+            );
+            self.enq_rst(key.local_port, key.peer_port, pkt.type_());
+            return;
+        }
 
-        UnixStream::connect(port_path)
-            .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
-            .map_err(Error::UnixConnect)
+        match self.host.connect(pkt.dst_port(), pkt.type_()) {
+            Ok((stream, true)) => {
+                let res = self.host.finish_connect(stream);
+                self.complete_peer_connect(key, res, pkt.buf_alloc(), pkt.type_());
+            }
+            Ok((stream, false)) => {
+                let fd = stream.as_raw_fd();
+                self.add_listener(
+                    fd,
+                    EpollListener::Connecting {
+                        key,
+                        stream,
+                        buf_alloc: pkt.buf_alloc(),
+                        type_: pkt.type_(),
+                    },
+                )
+                .map(|_| {
+                    self.connecting.insert(key, fd);
+                })
+                .unwrap_or_else(|err| {
+                    info!("vsock: error connecting peer-init connection: {:?}", err);
+                    self.enq_rst(key.local_port, key.peer_port, pkt.type_());
+                });
+            }
+            Err(err) => {
+                info!("vsock: error connecting peer-init connection: {:?}", err);
+                self.enq_rst(key.local_port, key.peer_port, pkt.type_());
+            }
+        }
+    }
+
+    /// Complete the host connect of a guest-initiated connection, by adding the connection to
+    /// the connection pool, which has it send a response to the guest. On failure, a new RST
+    /// packet will be scheduled for delivery to the guest.
+    fn complete_peer_connect(
+        &mut self,
+        key: ConnMapKey,
+        stream: Result<H::Stream, H::Error>,
+        buf_alloc: u32,
+        type_: u16,
+    ) {
+        stream
             .and_then(|stream| {
                 self.add_connection(
-                    ConnMapKey {
-                        local_port: pkt.dst_port(),
-                        peer_port: pkt.src_port(),
-                    },
-                    MuxerConnection::new_peer_init(
+                    key,
+                    MuxerConnection::<H>::new_peer_init(
                         stream,
                         uapi::VSOCK_HOST_CID,
                         self.cid,
-                        pkt.dst_port(),
-                        pkt.src_port(),
-                        pkt.buf_alloc(),
+                        key.local_port,
+                        key.peer_port,
+                        buf_alloc,
+                        type_,
                     ),
                 )
             })
-            .unwrap_or_else(|_| self.enq_rst(pkt.dst_port(), pkt.src_port()));
+            .unwrap_or_else(|err| {
+                info!("vsock: error adding peer-init connection: {:?}", err);
+                self.enq_rst(key.local_port, key.peer_port, type_);
+            });
     }
 
     /// Perform an action that might mutate a connection's state.
@@ -623,7 +883,7 @@ impl VsockMuxer {
     /// - kill the connection if an unrecoverable error occurs.
     fn apply_conn_mutation<F>(&mut self, key: ConnMapKey, mut_fn: F)
     where
-        F: FnOnce(&mut MuxerConnection),
+        F: FnOnce(&mut MuxerConnection<H>),
     {
         if let Some(conn) = self.conn_map.get_mut(&key) {
             let had_rx = conn.has_pending_rx();
@@ -632,23 +892,24 @@ impl VsockMuxer {
 
             mut_fn(conn);
 
-            // If this is a host-initiated connection that has just become established, we'll have
-            // to send an ack message to the host end.
+            // If this is a host-initiated connection that has just become established, we may
+            // have to send an ack message to the host end.
             if prev_state == ConnState::LocalInit && conn.state() == ConnState::Established {
-                let msg = format!("OK {}\n", key.local_port);
-                match conn.send_bytes_raw(msg.as_bytes()) {
-                    Ok(written) if written == msg.len() => (),
-                    Ok(_) => {
-                        // If we can't write a dozen bytes to a pristine connection something
-                        // must be really wrong. Killing it.
-                        conn.kill();
-                        warn!("vsock: unable to fully write connection ack msg.");
-                    }
-                    Err(err) => {
-                        conn.kill();
-                        warn!("vsock: unable to ack host connection: {:?}", err);
-                    }
-                };
+                if let Some(msg) = self.host.local_init_ack(key.local_port) {
+                    match conn.send_bytes_raw(msg.as_bytes()) {
+                        Ok(written) if written == msg.len() => (),
+                        Ok(_) => {
+                            // If we can't write a dozen bytes to a pristine connection something
+                            // must be really wrong. Killing it.
+                            conn.kill();
+                            warn!("vsock: unable to fully write connection ack msg.");
+                        }
+                        Err(err) => {
+                            conn.kill();
+                            warn!("vsock: unable to ack host connection: {:?}", err);
+                        }
+                    };
+                }
             }
 
             // If the connection wasn't previously scheduled for RX, add it to our RX queue.
@@ -751,10 +1012,11 @@ impl VsockMuxer {
     /// Enqueue errors aren't propagated up the call chain, since there is nothing we can do to
     /// handle them. We do, however, log a warning, since not being able to enqueue an RST
     /// packet means we have to drop it, which is not normal operation.
-    fn enq_rst(&mut self, local_port: u32, peer_port: u32) {
+    fn enq_rst(&mut self, local_port: u32, peer_port: u32, type_: u16) {
         let pushed = self.rxq.push(MuxerRx::RstPkt {
             local_port,
             peer_port,
+            type_,
         });
         if !pushed {
             warn!(
@@ -769,12 +1031,14 @@ impl VsockMuxer {
 mod tests {
     use std::io::{Read, Write};
     use std::ops::Drop;
+    use std::os::unix::io::FromRawFd;
     use std::os::unix::net::{UnixListener, UnixStream};
     use std::path::{Path, PathBuf};
 
     use utils::tempfile::TempFile;
 
-    use super::super::super::csm::defs as csm_defs;
+    use super::super::csm::defs as csm_defs;
+    use super::super::unix::{UnixHost, VsockUnixBackend};
     use super::*;
     use crate::virtio::vsock::device::RXQ_INDEX;
     use crate::virtio::vsock::test_utils::TestContext as VsockTestContext;
@@ -785,12 +1049,12 @@ mod tests {
     struct MuxerTestContext {
         _vsock_test_ctx: VsockTestContext,
         pkt: VsockPacket,
-        muxer: VsockMuxer,
+        muxer: VsockUnixBackend,
     }
 
     impl Drop for MuxerTestContext {
         fn drop(&mut self) {
-            std::fs::remove_file(self.muxer.host_sock_path.as_str()).unwrap();
+            std::fs::remove_file(self.muxer.host_sock_path()).unwrap();
         }
     }
 
@@ -816,7 +1080,7 @@ mod tests {
             )
             .unwrap();
 
-            let muxer = VsockMuxer::new(PEER_CID, get_file(name)).unwrap();
+            let muxer = VsockUnixBackend::new(PEER_CID, get_file(name)).unwrap();
             Self {
                 _vsock_test_ctx: vsock_test_ctx,
                 pkt,
@@ -885,13 +1149,17 @@ mod tests {
         }
 
         fn create_local_listener(&self, port: u32) -> LocalListener {
-            LocalListener::new(format!("{}_{}", self.muxer.host_sock_path, port))
+            LocalListener::new(format!("{}_{}", self.muxer.host_sock_path(), port))
+        }
+
+        fn create_seqpacket_listener(&self, port: u32) -> LocalListener {
+            seqpacket_listener(&format!("{}_{}", self.muxer.host_sock_path(), port))
         }
 
         fn local_connect(&mut self, peer_port: u32) -> (UnixStream, u32) {
             let (init_local_lsn_count, init_conn_lsn_count) = self.count_epoll_listeners();
 
-            let mut stream = UnixStream::connect(self.muxer.host_sock_path.clone()).unwrap();
+            let mut stream = UnixStream::connect(self.muxer.host_sock_path()).unwrap();
             stream.set_nonblocking(true).unwrap();
             // The muxer would now get notified of a new connection having arrived at its Unix
             // socket, so it can accept it.
@@ -968,6 +1236,31 @@ mod tests {
         }
     }
 
+    // `UnixListener::bind()` only creates SOCK_STREAM listeners.
+    fn seqpacket_listener(path: &str) -> LocalListener {
+        let fd =
+            unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0) };
+        assert!(fd >= 0);
+        let sock = unsafe { UnixListener::from_raw_fd(fd) };
+        let (addr, addr_len) = UnixHost::sockaddr_un(path).unwrap();
+        assert_eq!(
+            unsafe {
+                libc::bind(
+                    fd,
+                    &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                    addr_len,
+                )
+            },
+            0
+        );
+        assert_eq!(unsafe { libc::listen(fd, 1) }, 0);
+        sock.set_nonblocking(true).unwrap();
+        LocalListener {
+            path: PathBuf::from(path),
+            sock,
+        }
+    }
+
     #[test]
     fn test_muxer_epoll_listener() {
         let ctx = MuxerTestContext::new("muxer_epoll_listener");
@@ -997,7 +1290,7 @@ mod tests {
     fn test_bad_peer_pkt() {
         const LOCAL_PORT: u32 = 1026;
         const PEER_PORT: u32 = 1025;
-        const SOCK_DGRAM: u16 = 2;
+        const SOCK_DGRAM: u16 = 3;
 
         let mut ctx = MuxerTestContext::new("bad_peer_pkt");
         ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
@@ -1005,7 +1298,7 @@ mod tests {
         ctx.send();
 
         // The guest sent a SOCK_DGRAM packet. Per the vsock spec, we need to reply with an RST
-        // packet, since vsock only supports stream sockets.
+        // packet, since we only support stream and seqpacket sockets.
         assert!(ctx.muxer.has_pending_rx());
         ctx.recv();
         assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
@@ -1116,6 +1409,187 @@ mod tests {
         assert!(!ctx.muxer.has_pending_rx());
     }
 
+    #[test]
+    fn test_peer_seqpacket_connection() {
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::new("peer_seqpacket_connection");
+        assert!(ctx.muxer.supports_seqpacket());
+
+        // Seqpacket connection requests are refused until the guest driver acks the feature.
+        let mut listener = ctx.create_seqpacket_listener(LOCAL_PORT);
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET);
+        ctx.send();
+        assert!(ctx.muxer.conn_map.is_empty());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+
+        ctx.muxer.set_seqpacket_acked(true);
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET);
+        ctx.send();
+        assert_eq!(ctx.muxer.conn_map.len(), 1);
+        let mut stream = listener.accept();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+
+        // Test guest -> host data flow. The message should only be delivered once its last
+        // fragment (i.e. the one marked with EOM) arrives.
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &[1, 2])
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET);
+        ctx.send();
+        let mut buf = vec![0; 16];
+        assert_eq!(
+            stream.read(buf.as_mut_slice()).unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &[3, 4])
+            .set_type(uapi::VSOCK_TYPE_SEQPACKET)
+            .set_flags(uapi::VSOCK_FLAGS_SEQ_EOM);
+        ctx.send();
+        assert_eq!(stream.read(buf.as_mut_slice()).unwrap(), 4);
+        assert_eq!(&buf[..4], &[1, 2, 3, 4]);
+
+        // Test host -> guest data flow.
+        let data = [5u8, 6, 7, 8];
+        stream.write_all(&data).unwrap();
+        ctx.notify_muxer();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.type_(), uapi::VSOCK_TYPE_SEQPACKET);
+        assert_eq!(
+            ctx.pkt.flags(),
+            uapi::VSOCK_FLAGS_SEQ_EOM | uapi::VSOCK_FLAGS_SEQ_EOR
+        );
+
+        let mut buf = vec![];
+        ctx.pkt
+            .write_from_offset_to(
+                &ctx._vsock_test_ctx.mem,
+                0,
+                &mut buf,
+                ctx.pkt.len() as usize,
+            )
+            .unwrap();
+        assert_eq!(&buf, &data);
+    }
+
+    #[test]
+    fn test_connections_info_and_reset() {
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::new("connections_info_and_reset");
+        assert!(ctx.muxer.connections().is_empty());
+
+        let mut listener = ctx.create_local_listener(LOCAL_PORT);
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        let mut stream = listener.accept();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+
+        let data = [1, 2, 3, 4];
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data);
+        ctx.send();
+        let mut buf = vec![0; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+
+        let conns = ctx.muxer.connections();
+        assert_eq!(conns.len(), 1);
+        assert_eq!(conns[0].local_port, LOCAL_PORT);
+        assert_eq!(conns[0].peer_port, PEER_PORT);
+        assert_eq!(conns[0].state, "Established");
+        assert_eq!(conns[0].tx_bytes, data.len() as u64);
+
+        // Resetting an unknown connection fails.
+        assert!(!ctx.muxer.reset_connection(PEER_PORT, LOCAL_PORT));
+
+        // A reset connection is terminated with an RST packet, and then removed.
+        assert!(ctx.muxer.reset_connection(LOCAL_PORT, PEER_PORT));
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), LOCAL_PORT);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+        assert!(ctx.muxer.connections().is_empty());
+    }
+
+    #[test]
+    fn test_save_restore_connections() {
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+        const LOCAL_INIT_PEER_PORT: u32 = 1027;
+
+        let mut ctx = MuxerTestContext::new("save_connections");
+        assert!(!ctx.muxer.persists_connections());
+        ctx.muxer.set_persist_connections(true);
+        assert!(ctx.muxer.persists_connections());
+
+        // Establish a guest-initiated connection.
+        let mut listener = ctx.create_local_listener(LOCAL_PORT);
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        let mut stream = listener.accept();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        let data = [1, 2, 3, 4];
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data);
+        ctx.send();
+        let mut buf = vec![0; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+
+        // And a host-initiated one.
+        let (_local_stream, local_init_port) = ctx.local_connect(LOCAL_INIT_PEER_PORT);
+
+        let states = ctx.muxer.save_connections();
+        assert_eq!(states.len(), 2);
+        for state in states.iter() {
+            // Only the guest-initiated connection can be resumed.
+            assert_eq!(state.reconnect, state.local_port == LOCAL_PORT);
+        }
+
+        // Restore the connections into a muxer listening on another path, as a clone would.
+        let mut ctx = MuxerTestContext::new("restore_connections");
+        let mut listener = ctx.create_local_listener(LOCAL_PORT);
+        ctx.muxer.restore_connections(&states);
+        let mut stream = listener.accept();
+        let key = ConnMapKey {
+            local_port: LOCAL_PORT,
+            peer_port: PEER_PORT,
+        };
+        assert_eq!(ctx.muxer.conn_map.len(), 1);
+        assert_eq!(ctx.muxer.conn_map[&key].info().tx_bytes, data.len() as u64);
+
+        // The guest gets a credit update for the resumed connection, and an RST for the other.
+        let mut ops = Vec::new();
+        while ctx.muxer.has_pending_rx() {
+            ctx.recv();
+            ops.push((ctx.pkt.src_port(), ctx.pkt.dst_port(), ctx.pkt.op()));
+        }
+        ops.sort_unstable();
+        assert_eq!(
+            ops,
+            vec![
+                (LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_CREDIT_UPDATE),
+                (local_init_port, LOCAL_INIT_PEER_PORT, uapi::VSOCK_OP_RST),
+            ]
+        );
+
+        // Data keeps flowing over the resumed connection.
+        let data = [5, 6, 7, 8];
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data);
+        ctx.send();
+        let mut buf = vec![0; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(buf.as_slice(), data);
+    }
+
     #[test]
     fn test_local_connection() {
         let mut ctx = MuxerTestContext::new("local_connection");
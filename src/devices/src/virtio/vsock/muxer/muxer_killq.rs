@@ -25,10 +25,13 @@
 /// queue, created by walking the connection pool, looking for connections that will be
 /// expiring in the future.
 use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
 use std::time::Instant;
 
-use super::muxer::ConnMapKey;
-use super::{defs, MuxerConnection};
+use super::super::csm::VsockConnection;
+use super::defs;
+use super::ConnMapKey;
 
 /// A kill queue item, holding the connection key and the scheduled time for termination.
 #[derive(Clone, Copy)]
@@ -65,7 +68,10 @@ impl MuxerKillQ {
     /// set to expire at some point in the future.
     /// Note: if more than `Self::SIZE` connections are found, the queue will be created in an
     ///       out-of-sync state, and will be discarded after it is emptied.
-    pub fn from_conn_map(conn_map: &HashMap<ConnMapKey, MuxerConnection>) -> Self {
+    pub fn from_conn_map<S>(conn_map: &HashMap<ConnMapKey, VsockConnection<S>>) -> Self
+    where
+        S: Read + Write + AsRawFd,
+    {
         let mut q_buf: Vec<MuxerKillQItem> = Vec::with_capacity(Self::SIZE);
         let mut synced = true;
         for (key, conn) in conn_map.iter() {
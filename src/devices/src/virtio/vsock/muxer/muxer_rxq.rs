@@ -16,10 +16,13 @@
 /// performed here, as part of building an RX queue from the connection pool. When an
 /// out-of-sync is drained, the muxer will discard it, and attempt to rebuild a synced one.
 use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
 
+use super::super::csm::VsockConnection;
 use super::super::VsockChannel;
-use super::muxer::{ConnMapKey, MuxerRx};
-use super::{defs, MuxerConnection};
+use super::defs;
+use super::{ConnMapKey, MuxerRx};
 
 /// The muxer RX queue.
 pub struct MuxerRxQ {
@@ -44,7 +47,10 @@ impl MuxerRxQ {
     /// Note: the resulting queue may still be desynchronized, if there are too many connections
     ///       that have pending RX data. In that case, the muxer will first drain this queue, and
     ///       then try again to build a synchronized one.
-    pub fn from_conn_map(conn_map: &HashMap<ConnMapKey, MuxerConnection>) -> Self {
+    pub fn from_conn_map<S>(conn_map: &HashMap<ConnMapKey, VsockConnection<S>>) -> Self
+    where
+        S: Read + Write + AsRawFd,
+    {
         let mut q = VecDeque::new();
         let mut synced = true;
 
@@ -56,7 +56,8 @@ pub struct VsockPacketHeader {
     dst_port: u32,
     // Data length (in bytes) - may be 0, if there is no data buffer.
     len: u32,
-    // Socket type. Currently, only connection-oriented streams are defined by the vsock protocol.
+    // Socket type - either a connection-oriented stream (VSOCK_TYPE_STREAM), or a connection-
+    // oriented sequence of messages (VSOCK_TYPE_SEQPACKET).
     type_: u16,
     // Operation ID - one of the VSOCK_OP_* values; e.g.
     // - VSOCK_OP_RW: a data packet;
@@ -65,7 +66,8 @@ pub struct VsockPacketHeader {
     // etc (see `super::defs::uapi` for the full list).
     op: u16,
     // Additional options (flags) associated with the current operation (`op`).
-    // Currently, only used with shutdown requests (VSOCK_OP_SHUTDOWN).
+    // Used with shutdown requests (VSOCK_OP_SHUTDOWN), and with the data packets of seqpacket
+    // connections (VSOCK_OP_RW), to mark message and record boundaries (EOM / EOR).
     flags: u32,
     // Size (in bytes) of the packet sender receive buffer (for the connection to which this packet
     // belongs).
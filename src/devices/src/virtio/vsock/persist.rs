@@ -6,11 +6,15 @@
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
+use logger::warn;
+use rate_limiter::persist::RateLimiterState;
+use rate_limiter::RateLimiter;
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::GuestMemoryMmap;
 
+use super::device::RXQ_INDEX;
 use super::*;
 use crate::virtio::persist::VirtioDeviceState;
 use crate::virtio::{DeviceState, TYPE_VSOCK};
@@ -28,6 +32,22 @@ pub struct VsockState {
 pub struct VsockFrontendState {
     pub cid: u64,
     virtio_state: VirtioDeviceState,
+    // Only present if the rate limiter has any bucket configured.
+    #[version(start = 2, ser_fn = "rate_limiter_ser")]
+    rate_limiter_state: Option<RateLimiterState>,
+}
+
+impl VsockFrontendState {
+    fn rate_limiter_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && self.rate_limiter_state.is_some() {
+            warn!(
+                "Target version does not implement vsock rate limiting. The vsock device will \
+                 not be rate limited."
+            );
+        }
+
+        Ok(())
+    }
 }
 
 /// An enum for the serializable backend state types.
@@ -35,6 +55,7 @@ pub struct VsockFrontendState {
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub enum VsockBackendState {
     Uds(VsockUdsState),
+    Tcp(VsockTcpState),
 }
 
 /// The Vsock Unix Backend serializable state.
@@ -43,6 +64,84 @@ pub enum VsockBackendState {
 pub struct VsockUdsState {
     /// The path for the UDS socket.
     pub(crate) path: String,
+    /// Whether the guest connections are kept alive across snapshot/restore.
+    #[version(start = 2, ser_fn = "persist_connections_ser")]
+    pub(crate) persist_connections: bool,
+    /// The connections that were active when the snapshot was taken. Only saved if
+    /// `persist_connections` is set.
+    #[version(start = 2)]
+    pub(crate) connections: Vec<VsockConnectionState>,
+}
+
+impl VsockUdsState {
+    fn persist_connections_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // The guest wasn't told to reset its connections when this state was saved, and older
+        // versions would leave them hanging.
+        if target_version < 2 && self.persist_connections {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support persisting vsock connections.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The path of the host-side Unix socket.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Override the path of the host-side Unix socket that the restored backend binds to, and
+    /// that restored connections reconnect through.
+    pub fn set_path(&mut self, path: String) {
+        self.path = path;
+    }
+}
+
+/// The serializable state of a Unix backend vsock connection.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VsockConnectionState {
+    pub(crate) local_port: u32,
+    pub(crate) peer_port: u32,
+    pub(crate) type_: u16,
+    /// Whether the connection can be carried over to the restored backend. Connections that
+    /// can't are reset on restore.
+    pub(crate) reconnect: bool,
+    pub(crate) fwd_cnt: u32,
+    pub(crate) peer_buf_alloc: u32,
+    pub(crate) peer_fwd_cnt: u32,
+    pub(crate) rx_cnt: u32,
+    pub(crate) last_fwd_cnt_to_peer: u32,
+    pub(crate) rx_bytes: u64,
+    pub(crate) tx_bytes: u64,
+    /// The guest data that was yet to be written to the host-side stream.
+    pub(crate) tx_buf: Vec<u8>,
+}
+
+/// The serializable direction of a TCP backend port.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum VsockTcpDirectionState {
+    HostToGuest,
+    GuestToHost,
+}
+
+/// The serializable entry of the TCP backend port-forwarding table.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VsockTcpPortState {
+    pub(crate) vsock_port: u32,
+    pub(crate) host_addr: String,
+    pub(crate) direction: VsockTcpDirectionState,
+}
+
+/// The Vsock TCP Backend serializable state.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VsockTcpState {
+    /// The port-forwarding table.
+    pub(crate) ports: Vec<VsockTcpPortState>,
 }
 
 /// A helper structure that holds the constructor arguments for VsockUnixBackend
@@ -57,6 +156,20 @@ pub struct VsockUdsConstructorArgs {
     pub cid: u64,
 }
 
+/// A helper structure that holds the constructor arguments for a restored vsock connection.
+pub struct VsockConnectionConstructorArgs<S> {
+    /// The new host-side stream of the connection.
+    pub stream: S,
+    pub local_cid: u64,
+    pub peer_cid: u64,
+}
+
+/// A helper structure that holds the constructor arguments for VsockTcpBackend
+pub struct VsockTcpConstructorArgs {
+    // cid available in VsockFrontendState.
+    pub cid: u64,
+}
+
 impl Persist<'_> for VsockUnixBackend {
     type State = VsockBackendState;
     type ConstructorArgs = VsockUdsConstructorArgs;
@@ -64,7 +177,13 @@ impl Persist<'_> for VsockUnixBackend {
 
     fn save(&self) -> Self::State {
         VsockBackendState::Uds(VsockUdsState {
-            path: self.host_sock_path.clone(),
+            path: self.host_sock_path().to_owned(),
+            persist_connections: self.persists_connections(),
+            connections: if self.persists_connections() {
+                self.save_connections()
+            } else {
+                Vec::new()
+            },
         })
     }
 
@@ -73,10 +192,69 @@ impl Persist<'_> for VsockUnixBackend {
         state: &Self::State,
     ) -> std::result::Result<Self, Self::Error> {
         match state {
-            VsockBackendState::Uds(uds_state) => Ok(VsockUnixBackend::new(
-                constructor_args.cid,
-                uds_state.path.clone(),
-            )?),
+            VsockBackendState::Uds(uds_state) => {
+                let mut backend =
+                    VsockUnixBackend::new(constructor_args.cid, uds_state.path.clone())?;
+                backend.set_persist_connections(uds_state.persist_connections);
+                backend.restore_connections(&uds_state.connections);
+                Ok(backend)
+            }
+            VsockBackendState::Tcp(_) => Err(VsockUnixBackendError::InvalidBackendState),
+        }
+    }
+}
+
+impl Persist<'_> for VsockTcpBackend {
+    type State = VsockBackendState;
+    type ConstructorArgs = VsockTcpConstructorArgs;
+    type Error = VsockTcpBackendError;
+
+    fn save(&self) -> Self::State {
+        VsockBackendState::Tcp(VsockTcpState {
+            ports: self
+                .ports()
+                .iter()
+                .map(|port| VsockTcpPortState {
+                    vsock_port: port.vsock_port,
+                    host_addr: port.host_addr.to_string(),
+                    direction: match port.direction {
+                        VsockTcpDirection::HostToGuest => VsockTcpDirectionState::HostToGuest,
+                        VsockTcpDirection::GuestToHost => VsockTcpDirectionState::GuestToHost,
+                    },
+                })
+                .collect(),
+        })
+    }
+
+    fn restore(
+        constructor_args: Self::ConstructorArgs,
+        state: &Self::State,
+    ) -> std::result::Result<Self, Self::Error> {
+        match state {
+            VsockBackendState::Tcp(tcp_state) => {
+                let ports = tcp_state
+                    .ports
+                    .iter()
+                    .map(|port| {
+                        Ok(VsockTcpPort {
+                            vsock_port: port.vsock_port,
+                            host_addr: port.host_addr.parse().map_err(|_| {
+                                VsockTcpBackendError::InvalidHostAddress(port.host_addr.clone())
+                            })?,
+                            direction: match port.direction {
+                                VsockTcpDirectionState::HostToGuest => {
+                                    VsockTcpDirection::HostToGuest
+                                }
+                                VsockTcpDirectionState::GuestToHost => {
+                                    VsockTcpDirection::GuestToHost
+                                }
+                            },
+                        })
+                    })
+                    .collect::<std::result::Result<Vec<_>, Self::Error>>()?;
+                VsockTcpBackend::new(constructor_args.cid, ports)
+            }
+            VsockBackendState::Uds(_) => Err(VsockTcpBackendError::InvalidBackendState),
         }
     }
 }
@@ -90,9 +268,17 @@ where
     type Error = VsockError;
 
     fn save(&self) -> Self::State {
+        let rate_limiter = self.rate_limiter();
         VsockFrontendState {
             cid: self.cid(),
             virtio_state: VirtioDeviceState::from_device(self),
+            rate_limiter_state: if rate_limiter.bandwidth().is_some()
+                || rate_limiter.ops().is_some()
+            {
+                Some(rate_limiter.save())
+            } else {
+                None
+            },
         }
     }
 
@@ -110,17 +296,36 @@ where
                 defs::QUEUE_SIZE,
             )
             .map_err(VsockError::VirtioState)?;
-        let mut vsock = Self::with_queues(state.cid, constructor_args.backend, queues)?;
+        let rate_limiter = match state.rate_limiter_state {
+            Some(ref rate_limiter_state) => {
+                RateLimiter::restore((), rate_limiter_state).map_err(VsockError::RateLimiter)?
+            }
+            None => RateLimiter::default(),
+        };
+        let mut vsock =
+            Self::with_queues(state.cid, constructor_args.backend, rate_limiter, queues)?;
 
         vsock.acked_features = state.virtio_state.acked_features;
         vsock.avail_features = state.virtio_state.avail_features;
         vsock.irq_trigger.irq_status =
             Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
+        vsock.backend.set_seqpacket_acked(
+            vsock.acked_features & (1 << defs::uapi::VIRTIO_VSOCK_F_SEQPACKET as u64) != 0,
+        );
         vsock.device_state = if state.virtio_state.activated {
             DeviceState::Activated(constructor_args.mem)
         } else {
             DeviceState::Inactive
         };
+
+        // A backend that restored its connections may already have packets for the guest
+        // (e.g. RSTs for the connections that couldn't be carried over), so have the RX queue
+        // processed as soon as the device gets to handle events.
+        if state.virtio_state.activated && vsock.backend.has_pending_rx() {
+            vsock.queue_events[RXQ_INDEX]
+                .write(1)
+                .map_err(VsockError::EventFd)?;
+        }
         Ok(vsock)
     }
 }
@@ -128,6 +333,7 @@ where
 #[cfg(test)]
 pub(crate) mod tests {
     use utils::byte_order;
+    use utils::tempfile::TempFile;
 
     use super::device::AVAIL_FEATURES;
     use super::*;
@@ -143,6 +349,8 @@ pub(crate) mod tests {
         fn save(&self) -> Self::State {
             VsockBackendState::Uds(VsockUdsState {
                 path: "test".to_owned(),
+                persist_connections: false,
+                connections: Vec::new(),
             })
         }
 
@@ -152,6 +360,7 @@ pub(crate) mod tests {
         ) -> std::result::Result<Self, Self::Error> {
             match state {
                 VsockBackendState::Uds(_) => Ok(TestBackend::new()),
+                VsockBackendState::Tcp(_) => Err(VsockUnixBackendError::InvalidBackendState),
             }
         }
     }
@@ -193,6 +402,7 @@ pub(crate) mod tests {
                         assert_eq!(uds_state.path, "test".to_owned());
                         TestBackend::new()
                     }
+                    VsockBackendState::Tcp(_) => panic!("Unexpected TCP backend state."),
                 },
             },
             &restored_state.frontend,
@@ -236,4 +446,150 @@ pub(crate) mod tests {
         restored_device.read_config(2, &mut data);
         assert_eq!(data, [0u8, 1, 2, 3, 4, 5, 6, 7]);
     }
+
+    #[test]
+    fn test_persist_tcp_backend() {
+        let ports = vec![
+            VsockTcpPort {
+                vsock_port: 1024,
+                host_addr: "127.0.0.1:0".parse().unwrap(),
+                direction: VsockTcpDirection::HostToGuest,
+            },
+            VsockTcpPort {
+                vsock_port: 1025,
+                host_addr: "[::1]:8080".parse().unwrap(),
+                direction: VsockTcpDirection::GuestToHost,
+            },
+        ];
+        let backend = VsockTcpBackend::new(3, ports.clone()).unwrap();
+
+        let mut mem = vec![0; 4096];
+        let version_map = VersionMap::new();
+        backend
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+
+        let state = VsockBackendState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap();
+        let restored_backend =
+            VsockTcpBackend::restore(VsockTcpConstructorArgs { cid: 3 }, &state).unwrap();
+        assert_eq!(restored_backend.ports(), ports.as_slice());
+
+        // The state of a TCP backend can't be used to restore a Unix backend.
+        assert!(matches!(
+            VsockUnixBackend::restore(VsockUdsConstructorArgs { cid: 3 }, &state),
+            Err(VsockUnixBackendError::InvalidBackendState)
+        ));
+    }
+
+    #[test]
+    fn test_persist_rate_limiter() {
+        let ctx = TestContext::new();
+        let rate_limiter = RateLimiter::new(1000, 0, 100, 10, 0, 100).unwrap();
+        let device = Vsock::new(ctx.cid, TestBackend::new(), rate_limiter).unwrap();
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(VsockFrontendState::type_id(), 2);
+
+        // The rate limiter is saved starting with version 2 of the frontend state.
+        let mut mem = vec![0; 4096];
+        device
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let state = VsockFrontendState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+        let restored_device = Vsock::restore(
+            VsockConstructorArgs {
+                mem: ctx.mem.clone(),
+                backend: TestBackend::new(),
+            },
+            &state,
+        )
+        .unwrap();
+        assert_eq!(
+            restored_device
+                .rate_limiter()
+                .bandwidth()
+                .unwrap()
+                .capacity(),
+            1000
+        );
+        assert_eq!(restored_device.rate_limiter().ops().unwrap().capacity(), 10);
+
+        // Older versions drop it.
+        let mut mem = vec![0; 4096];
+        device
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        let state = VsockFrontendState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap();
+        let restored_device = Vsock::restore(
+            VsockConstructorArgs {
+                mem: ctx.mem.clone(),
+                backend: TestBackend::new(),
+            },
+            &state,
+        )
+        .unwrap();
+        assert!(restored_device.rate_limiter().bandwidth().is_none());
+        assert!(restored_device.rate_limiter().ops().is_none());
+    }
+
+    #[test]
+    fn test_persist_uds_connections() {
+        let path = TempFile::new()
+            .unwrap()
+            .as_path()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let mut backend = VsockUnixBackend::new(3, path.clone()).unwrap();
+        backend.set_persist_connections(true);
+
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(VsockUdsState::type_id(), 2);
+
+        let mut mem = vec![0; 4096];
+        backend
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let mut state =
+            VsockBackendState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+
+        // Restore the backend on a different host socket path, as a clone would.
+        let new_path = TempFile::new()
+            .unwrap()
+            .as_path()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        match state {
+            VsockBackendState::Uds(ref mut uds_state) => {
+                assert!(uds_state.persist_connections);
+                uds_state.set_path(new_path.clone());
+            }
+            VsockBackendState::Tcp(_) => panic!("Unexpected TCP backend state."),
+        }
+        let restored_backend =
+            VsockUnixBackend::restore(VsockUdsConstructorArgs { cid: 3 }, &state).unwrap();
+        assert!(restored_backend.persists_connections());
+        assert_eq!(restored_backend.host_sock_path(), new_path);
+
+        // Older versions can't carry the connections over.
+        let mut mem = vec![0; 4096];
+        assert!(matches!(
+            backend
+                .save()
+                .serialize(&mut mem.as_mut_slice(), &version_map, 1),
+            Err(VersionizeError::Semantic(_))
+        ));
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(new_path).unwrap();
+    }
 }
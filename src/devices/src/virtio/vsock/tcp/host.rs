@@ -0,0 +1,491 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+
+/// `TcpHost` is the host side of the TCP vsock backend. Which host TCP sockets are used is
+/// decided by the port-forwarding table the backend is created with:
+/// - for each `HostToGuest` entry, the backend listens on the host TCP address, and each
+///   accepted connection is forwarded to the guest vsock port, without any handshake;
+/// - for each `GuestToHost` entry, the guest connection requests addressed to the vsock port,
+///   on the host CID, are forwarded to the host TCP address.
+/// Connection requests for ports not present in the table are answered with an RST.
+///
+/// Host TCP connections are established asynchronously, since a TCP connect can take as long
+/// as the kernel's SYN timeout, and must not stall the VMM event loop: the guest is only sent
+/// a response (or an RST) once the host socket reports the outcome of the connection attempt.
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use super::super::muxer::{MuxerHost, VsockMuxer};
+use super::{Error, Result, VsockTcpDirection, VsockTcpPort};
+
+/// A host TCP listener, whose connections are forwarded to the guest `vsock_port`.
+pub struct TcpHostListener {
+    listener: TcpListener,
+    vsock_port: u32,
+}
+
+impl AsRawFd for TcpHostListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+/// The host side of the TCP vsock backend.
+pub struct TcpHost {
+    /// The port-forwarding table.
+    ports: Vec<VsockTcpPort>,
+    /// The host TCP addresses that guest-initiated connections are forwarded to, keyed by the
+    /// vsock port the guest connects to.
+    host_targets: HashMap<u32, SocketAddr>,
+}
+
+impl MuxerHost for TcpHost {
+    type Stream = TcpStream;
+    type Listener = TcpHostListener;
+    type Error = Error;
+
+    fn listen(&self) -> Result<Vec<TcpHostListener>> {
+        self.ports
+            .iter()
+            .filter(|port| port.direction == VsockTcpDirection::HostToGuest)
+            .map(|port| {
+                TcpListener::bind(port.host_addr)
+                    .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
+                    .map(|listener| TcpHostListener {
+                        listener,
+                        vsock_port: port.vsock_port,
+                    })
+                    .map_err(Error::TcpBind)
+            })
+            .collect()
+    }
+
+    fn accept(&self, listener: &TcpHostListener) -> Result<(TcpStream, Option<u32>)> {
+        listener
+            .listener
+            .accept()
+            .and_then(|(stream, _)| Self::configure_stream(stream))
+            .map(|stream| (stream, Some(listener.vsock_port)))
+            .map_err(Error::TcpAccept)
+    }
+
+    fn connect(&self, port: u32, _type_: u16) -> Result<(TcpStream, bool)> {
+        self.host_targets
+            .get(&port)
+            .ok_or(Error::UnknownPort(port))
+            .and_then(|addr| Self::connect_nonblocking(addr).map_err(Error::TcpConnect))
+    }
+
+    fn finish_connect(&self, stream: TcpStream) -> Result<TcpStream> {
+        stream
+            .take_error()
+            .and_then(|err| err.map_or(Ok(()), Err))
+            .and_then(|_| Self::configure_stream(stream))
+            .map_err(Error::TcpConnect)
+    }
+}
+
+impl TcpHost {
+    fn new(ports: Vec<VsockTcpPort>) -> Result<Self> {
+        let mut host_targets = HashMap::new();
+        for port in ports.iter() {
+            if port.direction == VsockTcpDirection::GuestToHost
+                && host_targets
+                    .insert(port.vsock_port, port.host_addr)
+                    .is_some()
+            {
+                return Err(Error::DuplicatePort(port.vsock_port));
+            }
+        }
+
+        Ok(Self {
+            ports,
+            host_targets,
+        })
+    }
+
+    /// Make a freshly connected TCP stream nonblocking, and disable Nagle's algorithm on it,
+    /// since vsock traffic is usually made of small messages.
+    fn configure_stream(stream: TcpStream) -> std::io::Result<TcpStream> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    /// Start connecting to the host TCP address `addr`, without blocking. Returns the stream,
+    /// along with whether it is connected already. Otherwise, the stream becomes writable once
+    /// the connection attempt completes, and its outcome can be fetched via `take_error()`.
+    fn connect_nonblocking(addr: &SocketAddr) -> io::Result<(TcpStream, bool)> {
+        let domain = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        // This is safe, since we check the return value.
+        let fd = unsafe {
+            libc::socket(
+                domain,
+                libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // We just checked that the fd is valid. Owning it right away makes sure it gets
+        // closed, should connecting fail.
+        let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+        let (storage, storage_len) = Self::sockaddr(addr);
+        // This is safe, since `storage` holds a valid socket address, `storage_len` bytes
+        // long, and we check the return value.
+        let ret = unsafe {
+            libc::connect(
+                fd,
+                &storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+                storage_len,
+            )
+        };
+        if ret == 0 {
+            return Ok((stream, true));
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINPROGRESS) => Ok((stream, false)),
+            _ => Err(err),
+        }
+    }
+
+    /// Build the C representation of a socket address, along with its length.
+    fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        // This is safe, since an all-zero `sockaddr_storage` is a valid value.
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                // This is safe, since `sockaddr_storage` is large and aligned enough to hold
+                // any socket address.
+                let sin = unsafe {
+                    &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in)
+                };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                std::mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                // This is safe, since `sockaddr_storage` is large and aligned enough to hold
+                // any socket address.
+                let sin6 = unsafe {
+                    &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6)
+                };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_scope_id = addr.scope_id();
+                std::mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        (storage, len as libc::socklen_t)
+    }
+}
+
+impl VsockMuxer<TcpHost> {
+    /// Muxer constructor.
+    pub fn new(cid: u64, ports: Vec<VsockTcpPort>) -> Result<Self> {
+        Self::with_host(cid, TcpHost::new(ports)?)
+    }
+
+    /// Returns the port-forwarding table of the muxer.
+    pub fn ports(&self) -> &[VsockTcpPort] {
+        &self.host().ports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use utils::epoll::EventSet;
+
+    use super::super::super::defs::uapi;
+    use super::super::super::packet::VsockPacket;
+    use super::super::super::{VsockBackend, VsockChannel, VsockEpollListener};
+    use super::super::VsockTcpBackend;
+    use super::*;
+    use crate::virtio::vsock::device::RXQ_INDEX;
+    use crate::virtio::vsock::test_utils::TestContext as VsockTestContext;
+
+    const PEER_CID: u64 = 3;
+    const PEER_BUF_ALLOC: u32 = 64 * 1024;
+
+    struct MuxerTestContext {
+        _vsock_test_ctx: VsockTestContext,
+        pkt: VsockPacket,
+        muxer: VsockTcpBackend,
+    }
+
+    impl MuxerTestContext {
+        fn new(ports: Vec<VsockTcpPort>) -> Self {
+            let vsock_test_ctx = VsockTestContext::new();
+            let mut handler_ctx = vsock_test_ctx.create_event_handler_context();
+            let pkt = VsockPacket::from_rx_virtq_head(
+                &handler_ctx.device.queues[RXQ_INDEX]
+                    .pop(&vsock_test_ctx.mem)
+                    .unwrap(),
+            )
+            .unwrap();
+
+            let muxer = VsockTcpBackend::new(PEER_CID, ports).unwrap();
+            Self {
+                _vsock_test_ctx: vsock_test_ctx,
+                pkt,
+                muxer,
+            }
+        }
+
+        fn init_pkt(&mut self, local_port: u32, peer_port: u32, op: u16) -> &mut VsockPacket {
+            self.pkt
+                .set_type(uapi::VSOCK_TYPE_STREAM)
+                .set_src_cid(PEER_CID)
+                .set_dst_cid(uapi::VSOCK_HOST_CID)
+                .set_src_port(peer_port)
+                .set_dst_port(local_port)
+                .set_op(op)
+                .set_buf_alloc(PEER_BUF_ALLOC)
+        }
+
+        fn init_data_pkt(
+            &mut self,
+            local_port: u32,
+            peer_port: u32,
+            data: &[u8],
+        ) -> &mut VsockPacket {
+            assert!(data.len() <= self.pkt.buf_size());
+            self.init_pkt(local_port, peer_port, uapi::VSOCK_OP_RW)
+                .set_len(data.len() as u32);
+            self.pkt
+                .read_at_offset_from(
+                    &self._vsock_test_ctx.mem,
+                    0,
+                    &mut std::io::Cursor::new(data.to_vec()),
+                    data.len(),
+                )
+                .unwrap();
+            &mut self.pkt
+        }
+
+        fn read_data_pkt(&self) -> Vec<u8> {
+            let mut buf = vec![];
+            self.pkt
+                .write_from_offset_to(
+                    &self._vsock_test_ctx.mem,
+                    0,
+                    &mut buf,
+                    self.pkt.len() as usize,
+                )
+                .unwrap();
+            buf
+        }
+
+        fn send(&mut self) {
+            self.muxer
+                .send_pkt(&self.pkt, &self._vsock_test_ctx.mem)
+                .unwrap();
+        }
+
+        fn recv(&mut self) {
+            self.muxer
+                .recv_pkt(&mut self.pkt, &self._vsock_test_ctx.mem)
+                .unwrap();
+        }
+
+        fn notify_muxer(&mut self) {
+            self.muxer.notify(EventSet::IN);
+        }
+
+        fn has_connection(&self, local_port: u32, peer_port: u32) -> bool {
+            self.muxer
+                .connections()
+                .iter()
+                .any(|info| info.local_port == local_port && info.peer_port == peer_port)
+        }
+    }
+
+    fn local_addr() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    // Returns a free local TCP address, which nothing listens on.
+    fn free_local_addr() -> SocketAddr {
+        TcpListener::bind(local_addr())
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_new() {
+        let ctx = MuxerTestContext::new(vec![VsockTcpPort {
+            vsock_port: 1024,
+            host_addr: local_addr(),
+            direction: VsockTcpDirection::HostToGuest,
+        }]);
+        assert_eq!(ctx.muxer.get_polled_evset(), EventSet::IN);
+        assert!(!ctx.muxer.supports_seqpacket());
+        assert_eq!(ctx.muxer.ports().len(), 1);
+
+        // The same vsock port can't be forwarded to two host addresses.
+        let port = VsockTcpPort {
+            vsock_port: 1024,
+            host_addr: local_addr(),
+            direction: VsockTcpDirection::GuestToHost,
+        };
+        match VsockTcpBackend::new(PEER_CID, vec![port.clone(), port]) {
+            Err(Error::DuplicatePort(1024)) => (),
+            _ => panic!("Expected a duplicate port error."),
+        }
+    }
+
+    #[test]
+    fn test_peer_connection() {
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+
+        let host_listener = TcpListener::bind(local_addr()).unwrap();
+        let mut ctx = MuxerTestContext::new(vec![VsockTcpPort {
+            vsock_port: LOCAL_PORT,
+            host_addr: host_listener.local_addr().unwrap(),
+            direction: VsockTcpDirection::GuestToHost,
+        }]);
+
+        // Connection requests for ports which aren't forwarded are refused.
+        ctx.init_pkt(LOCAL_PORT + 1, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), LOCAL_PORT + 1);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+
+        // Connection requests for forwarded ports are accepted, once the host TCP connect
+        // completes.
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        let (mut stream, _) = host_listener.accept().unwrap();
+        ctx.notify_muxer();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        assert_eq!(ctx.pkt.src_cid(), uapi::VSOCK_HOST_CID);
+        assert_eq!(ctx.pkt.dst_cid(), PEER_CID);
+        assert_eq!(ctx.pkt.src_port(), LOCAL_PORT);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+        assert!(ctx.has_connection(LOCAL_PORT, PEER_PORT));
+
+        // Test guest -> host data flow.
+        let data = [1, 2, 3, 4];
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data);
+        ctx.send();
+        let mut buf = vec![0; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(buf.as_slice(), data);
+
+        // Test host -> guest data flow.
+        let data = [5u8, 6, 7, 8];
+        stream.write_all(&data).unwrap();
+        // Give the data some time to go through the loopback interface.
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        ctx.notify_muxer();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.read_data_pkt(), data);
+
+        // A reset from the guest closes the host stream.
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_RST);
+        ctx.send();
+        assert!(ctx.muxer.connections().is_empty());
+        assert_eq!(stream.read(buf.as_mut_slice()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_peer_connection_refused() {
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::new(vec![VsockTcpPort {
+            vsock_port: LOCAL_PORT,
+            host_addr: free_local_addr(),
+            direction: VsockTcpDirection::GuestToHost,
+        }]);
+
+        // The refused host connect, whether reported right away or from the completion path,
+        // results in an RST for the guest.
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        ctx.notify_muxer();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), LOCAL_PORT);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+        assert!(ctx.muxer.connections().is_empty());
+        assert!(!ctx.muxer.has_pending_rx());
+    }
+
+    #[test]
+    fn test_local_connection() {
+        const PEER_PORT: u32 = 1025;
+
+        let host_addr = free_local_addr();
+        let mut ctx = MuxerTestContext::new(vec![VsockTcpPort {
+            vsock_port: PEER_PORT,
+            host_addr,
+            direction: VsockTcpDirection::HostToGuest,
+        }]);
+
+        let mut stream = TcpStream::connect(host_addr).unwrap();
+        // The muxer would now get notified of a new connection having arrived at its TCP
+        // listener, so it can accept it.
+        ctx.notify_muxer();
+
+        // A connection request for the guest port should now be available from the muxer,
+        // without any handshake from the host.
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_REQUEST);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+        let local_port = ctx.pkt.src_port();
+        assert!(ctx.has_connection(local_port, PEER_PORT));
+
+        ctx.init_pkt(local_port, PEER_PORT, uapi::VSOCK_OP_RESPONSE);
+        ctx.send();
+
+        // Test guest -> host data flow.
+        let data = [1, 2, 3, 4];
+        ctx.init_data_pkt(local_port, PEER_PORT, &data);
+        ctx.send();
+        let mut buf = vec![0u8; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(buf.as_slice(), &data);
+
+        // Test host -> guest data flow.
+        let data = [5, 6, 7, 8];
+        stream.write_all(&data).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        ctx.notify_muxer();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.src_port(), local_port);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+        assert_eq!(ctx.read_data_pkt(), data);
+
+        // The connection is gone once the guest resets it.
+        ctx.init_pkt(local_port, PEER_PORT, uapi::VSOCK_OP_RST);
+        ctx.send();
+        assert!(ctx.muxer.connections().is_empty());
+    }
+}
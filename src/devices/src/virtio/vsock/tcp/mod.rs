@@ -0,0 +1,91 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+
+/// This module implements the TCP backend for vsock - a mediator between guest-side AF_VSOCK
+/// sockets and host-side TCP sockets, driven by a port-forwarding table. Unlike the Unix
+/// backend, there is no "connect" handshake: each host TCP listener is tied to a guest vsock
+/// port, and each vsock port the guest connects to on the host CID is tied to a host TCP
+/// address. The heavy lifting is performed by `super::muxer::VsockMuxer`, which is shared
+/// with the Unix backend, over the host TCP sockets provided by `host::TcpHost`.
+mod host;
+
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use super::muxer::{MuxerError, VsockMuxer};
+
+pub use host::TcpHost;
+
+/// The TCP vsock backend.
+pub type VsockTcpBackend = VsockMuxer<TcpHost>;
+
+/// The direction in which connections are forwarded through an entry of the port-forwarding
+/// table.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum VsockTcpDirection {
+    /// Connections accepted on the host TCP address are forwarded to the guest, which is
+    /// expected to listen on the vsock port.
+    HostToGuest,
+    /// Guest connections to the vsock port, on the host CID, are forwarded to the host TCP
+    /// address.
+    GuestToHost,
+}
+
+/// An entry of the port-forwarding table of the TCP backend.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockTcpPort {
+    /// The vsock port, on the guest side.
+    pub vsock_port: u32,
+    /// The TCP address, on the host side.
+    pub host_addr: SocketAddr,
+    /// The direction in which connections are forwarded.
+    pub direction: VsockTcpDirection,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The saved connection can't be resumed.
+    ConnectionNotResumable,
+    /// Error resuming a saved connection.
+    ConnectionRestore(super::csm::Error),
+    /// The same vsock port is forwarded to more than one host TCP address.
+    DuplicatePort(u32),
+    /// Error registering a new epoll-listening FD.
+    EpollAdd(std::io::Error),
+    /// Error creating an epoll FD.
+    EpollFdCreate(std::io::Error),
+    /// The saved backend state doesn't belong to a TCP backend.
+    InvalidBackendState,
+    /// The saved host TCP address can't be parsed.
+    InvalidHostAddress(String),
+    /// The host made an invalid vsock port connection request.
+    InvalidPortRequest,
+    /// Error accepting a new connection from a host-side TCP listener.
+    TcpAccept(std::io::Error),
+    /// Error binding to a host-side TCP address.
+    TcpBind(std::io::Error),
+    /// Error connecting to a host-side TCP address.
+    TcpConnect(std::io::Error),
+    /// Muxer connection limit reached.
+    TooManyConnections,
+    /// The guest connected to a vsock port which isn't forwarded to the host.
+    UnknownPort(u32),
+}
+
+impl From<MuxerError> for Error {
+    fn from(err: MuxerError) -> Self {
+        match err {
+            MuxerError::ConnectionNotResumable => Error::ConnectionNotResumable,
+            MuxerError::ConnectionRestore(err) => Error::ConnectionRestore(err),
+            MuxerError::EpollAdd(err) => Error::EpollAdd(err),
+            MuxerError::EpollFdCreate(err) => Error::EpollFdCreate(err),
+            MuxerError::InvalidPortRequest => Error::InvalidPortRequest,
+            MuxerError::TooManyConnections => Error::TooManyConnections,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
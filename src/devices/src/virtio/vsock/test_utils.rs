@@ -5,6 +5,7 @@
 
 use std::os::unix::io::{AsRawFd, RawFd};
 
+use rate_limiter::RateLimiter;
 use utils::epoll::EventSet;
 use utils::eventfd::EventFd;
 use vm_memory::{GuestAddress, GuestMemoryMmap};
@@ -13,8 +14,8 @@ use crate::virtio::test_utils::VirtQueue as GuestQ;
 use crate::virtio::vsock::device::{RXQ_INDEX, TXQ_INDEX};
 use crate::virtio::vsock::packet::{VsockPacket, VSOCK_PKT_HDR_SIZE};
 use crate::virtio::{
-    VirtioDevice, Vsock, VsockBackend, VsockChannel, VsockEpollListener, VsockError,
-    VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
+    VirtioDevice, Vsock, VsockBackend, VsockChannel, VsockConnectionInfo, VsockEpollListener,
+    VsockError, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
 };
 
 type Result<T> = std::result::Result<T, VsockError>;
@@ -108,7 +109,14 @@ impl VsockEpollListener for TestBackend {
         self.evset = Some(evset);
     }
 }
-impl VsockBackend for TestBackend {}
+impl VsockBackend for TestBackend {
+    fn connections(&self) -> Vec<VsockConnectionInfo> {
+        Vec::new()
+    }
+    fn reset_connection(&mut self, _local_port: u32, _peer_port: u32) -> bool {
+        false
+    }
+}
 
 pub struct TestContext {
     pub cid: u64,
@@ -128,7 +136,7 @@ impl TestContext {
             cid: CID,
             mem,
             mem_size: MEM_SIZE,
-            device: Vsock::new(CID, TestBackend::new()).unwrap(),
+            device: Vsock::new(CID, TestBackend::new(), RateLimiter::default()).unwrap(),
         }
     }
 
@@ -165,7 +173,13 @@ impl TestContext {
             guest_rxvq,
             guest_txvq,
             guest_evvq,
-            device: Vsock::with_queues(self.cid, TestBackend::new(), queues).unwrap(),
+            device: Vsock::with_queues(
+                self.cid,
+                TestBackend::new(),
+                RateLimiter::default(),
+                queues,
+            )
+            .unwrap(),
         }
     }
 }
@@ -0,0 +1,174 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+
+/// `UnixHost` is the host side of the Unix domain sockets vsock backend. Host-initiated
+/// connections are accepted from a single Unix socket, and have to issue a "connect <port>"
+/// command, naming the guest port they want to connect to. Guest-initiated connections are
+/// forwarded to the Unix socket listening at "<host_sock_path>_<port number>".
+use std::io::Read;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use super::super::defs::uapi;
+use super::super::muxer::{MuxerHost, VsockMuxer};
+use super::{Error, Result};
+
+/// The host side of the Unix domain sockets vsock backend.
+pub struct UnixHost {
+    /// The file system path of the host-side Unix socket. This is used to figure out the path
+    /// to Unix sockets listening on specific ports. I.e. "<this path>_<port number>".
+    host_sock_path: String,
+}
+
+impl MuxerHost for UnixHost {
+    type Stream = UnixStream;
+    type Listener = UnixListener;
+    type Error = Error;
+
+    fn listen(&self) -> Result<Vec<UnixListener>> {
+        // Open/bind on the host Unix socket, so we can accept host-initiated
+        // connections.
+        UnixListener::bind(&self.host_sock_path)
+            .and_then(|sock| sock.set_nonblocking(true).map(|_| vec![sock]))
+            .map_err(Error::UnixBind)
+    }
+
+    fn accept(&self, listener: &UnixListener) -> Result<(UnixStream, Option<u32>)> {
+        // The destination port is read from a "connect" command, received on the stream.
+        listener
+            .accept()
+            .and_then(|(stream, _)| stream.set_nonblocking(true).map(|_| (stream, None)))
+            .map_err(Error::UnixAccept)
+    }
+
+    /// Parse a host "connect" command, and extract the destination vsock port.
+    fn read_port(&self, stream: &mut UnixStream) -> Result<u32> {
+        let mut buf = [0u8; 32];
+
+        // This is the minimum number of bytes that we should be able to read, when parsing a
+        // valid connection request. I.e. `b"connect 0\n".len()`.
+        const MIN_READ_LEN: usize = 10;
+
+        // Bring in the minimum number of bytes that we should be able to read.
+        stream
+            .read_exact(&mut buf[..MIN_READ_LEN])
+            .map_err(Error::UnixRead)?;
+
+        // Now, finish reading the destination port number, by bringing in one byte at a time,
+        // until we reach an EOL terminator (or our buffer space runs out).  Yeah, not
+        // particularly proud of this approach, but it will have to do for now.
+        let mut blen = MIN_READ_LEN;
+        while buf[blen - 1] != b'\n' && blen < buf.len() {
+            stream
+                .read_exact(&mut buf[blen..=blen])
+                .map_err(Error::UnixRead)?;
+            blen += 1;
+        }
+
+        let mut word_iter = std::str::from_utf8(&buf[..blen])
+            .map_err(|_| Error::InvalidPortRequest)?
+            .split_whitespace();
+
+        word_iter
+            .next()
+            .ok_or(Error::InvalidPortRequest)
+            .and_then(|word| {
+                if word.to_lowercase() == "connect" {
+                    Ok(())
+                } else {
+                    Err(Error::InvalidPortRequest)
+                }
+            })
+            .and_then(|_| word_iter.next().ok_or(Error::InvalidPortRequest))
+            .and_then(|word| word.parse::<u32>().map_err(|_| Error::InvalidPortRequest))
+            .map_err(|_| Error::InvalidPortRequest)
+    }
+
+    /// Connect to the host-side Unix socket, expected to be listening at the file system path
+    /// corresponing to the destination port. The host socket is of the SOCK_SEQPACKET type for
+    /// seqpacket connections, and of the SOCK_STREAM type otherwise. Connecting to a Unix
+    /// socket doesn't block, so the connection attempt always completes right away.
+    fn connect(&self, port: u32, type_: u16) -> Result<(UnixStream, bool)> {
+        let port_path = format!("{}_{}", self.host_sock_path, port);
+
+        let stream = if type_ == uapi::VSOCK_TYPE_SEQPACKET {
+            Self::connect_seqpacket(&port_path)
+        } else {
+            UnixStream::connect(port_path)
+        };
+        stream
+            .and_then(|stream| stream.set_nonblocking(true).map(|_| (stream, true)))
+            .map_err(Error::UnixConnect)
+    }
+
+    /// Host-initiated connections get an "OK <local port>" reply, once the guest accepts them.
+    fn local_init_ack(&self, local_port: u32) -> Option<String> {
+        Some(format!("OK {}\n", local_port))
+    }
+
+    /// Guest seqpacket connections are forwarded to host SOCK_SEQPACKET Unix sockets.
+    fn supports_seqpacket(&self) -> bool {
+        true
+    }
+}
+
+impl UnixHost {
+    /// Connect to a host-side Unix socket of the SOCK_SEQPACKET type, which `UnixStream` can't
+    /// do on its own. Reads and writes on the returned stream move whole messages.
+    fn connect_seqpacket(path: &str) -> std::io::Result<UnixStream> {
+        // This is safe, since we check the return value.
+        let fd =
+            unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // We just checked that the fd is valid. Owning it right away makes sure it gets
+        // closed, should connecting fail.
+        let stream = unsafe { UnixStream::from_raw_fd(fd) };
+
+        let (addr, addr_len) = Self::sockaddr_un(path)?;
+        // This is safe, since `addr` is a valid `sockaddr_un`, at least `addr_len` bytes long,
+        // and we check the return value.
+        let ret = unsafe {
+            libc::connect(
+                fd,
+                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                addr_len,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(stream)
+    }
+
+    /// Build the address of the Unix socket at `path`, along with its length.
+    pub(crate) fn sockaddr_un(path: &str) -> std::io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+        // This is safe, since an all-zero `sockaddr_un` is a valid value.
+        let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        // Leave room for the NUL terminator.
+        if path.len() >= addr.sun_path.len() {
+            return Err(std::io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+        }
+        for (dst, src) in addr.sun_path.iter_mut().zip(path.as_bytes()) {
+            *dst = *src as libc::c_char;
+        }
+        let addr_len = std::mem::size_of::<libc::sa_family_t>() + path.len() + 1;
+
+        Ok((addr, addr_len as libc::socklen_t))
+    }
+}
+
+impl VsockMuxer<UnixHost> {
+    /// Muxer constructor.
+    pub fn new(cid: u64, host_sock_path: String) -> Result<Self> {
+        Self::with_host(cid, UnixHost { host_sock_path })
+    }
+
+    pub fn host_sock_path(&self) -> &str {
+        &self.host().host_sock_path
+    }
+}
@@ -4,32 +4,31 @@
 
 /// This module implements the Unix Domain Sockets backend for vsock - a mediator between
 /// guest-side AF_VSOCK sockets and host-side AF_UNIX sockets. The heavy lifting is performed by
-/// `muxer::VsockMuxer`, a connection multiplexer that uses `super::csm::VsockConnection` for
-/// handling vsock connection states.
-/// Check out `muxer.rs` for a more detailed explanation of the inner workings of this backend.
-mod muxer;
-mod muxer_killq;
-mod muxer_rxq;
+/// `super::muxer::VsockMuxer`, a connection multiplexer that uses
+/// `super::csm::VsockConnection` for handling vsock connection states, over the host Unix
+/// sockets provided by `host::UnixHost`.
+/// Check out `host.rs` for a more detailed explanation of how the host sockets are used.
+mod host;
 
-pub use muxer::VsockMuxer as VsockUnixBackend;
+use super::muxer::{MuxerError, VsockMuxer};
 
-mod defs {
-    /// Maximum number of established connections that we can handle.
-    pub const MAX_CONNECTIONS: usize = 1023;
+pub use host::UnixHost;
 
-    /// Size of the muxer RX packet queue.
-    pub const MUXER_RXQ_SIZE: usize = 256;
-
-    /// Size of the muxer connection kill queue.
-    pub const MUXER_KILLQ_SIZE: usize = 128;
-}
+/// The Unix domain sockets vsock backend.
+pub type VsockUnixBackend = VsockMuxer<UnixHost>;
 
 #[derive(Debug)]
 pub enum Error {
+    /// The saved connection can't be resumed.
+    ConnectionNotResumable,
+    /// Error resuming a saved connection.
+    ConnectionRestore(super::csm::Error),
     /// Error registering a new epoll-listening FD.
     EpollAdd(std::io::Error),
     /// Error creating an epoll FD.
     EpollFdCreate(std::io::Error),
+    /// The saved backend state doesn't belong to a Unix backend.
+    InvalidBackendState,
     /// The host made an invalid vsock port connection request.
     InvalidPortRequest,
     /// Error accepting a new connection from the host-side Unix socket.
@@ -44,5 +43,17 @@ pub enum Error {
     TooManyConnections,
 }
 
+impl From<MuxerError> for Error {
+    fn from(err: MuxerError) -> Self {
+        match err {
+            MuxerError::ConnectionNotResumable => Error::ConnectionNotResumable,
+            MuxerError::ConnectionRestore(err) => Error::ConnectionRestore(err),
+            MuxerError::EpollAdd(err) => Error::EpollAdd(err),
+            MuxerError::EpollFdCreate(err) => Error::EpollFdCreate(err),
+            MuxerError::InvalidPortRequest => Error::InvalidPortRequest,
+            MuxerError::TooManyConnections => Error::TooManyConnections,
+        }
+    }
+}
+
 type Result<T> = std::result::Result<T, Error>;
-type MuxerConnection = super::csm::VsockConnection<std::os::unix::net::UnixStream>;
@@ -13,9 +13,11 @@ use utils::net::mac::MacAddr;
 
 pub use crate::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
 pub use crate::pdu::ethernet::{
-    EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4, PAYLOAD_OFFSET as ETHERNET_PAYLOAD_OFFSET,
+    EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4, ETHERTYPE_IPV6,
+    PAYLOAD_OFFSET as ETHERNET_PAYLOAD_OFFSET,
 };
 pub use crate::pdu::ipv4::{IPv4Packet, PROTOCOL_TCP, PROTOCOL_UDP};
+pub use crate::pdu::ipv6::IPv6Packet;
 pub use crate::pdu::udp::{UdpDatagram, UDP_HEADER_SIZE};
 
 /// Represents a generalization of a borrowed `[u8]` slice.
@@ -0,0 +1,198 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Contains support for parsing IPv6 packets.
+//!
+//! Only the fixed header is interpreted, extension headers are part of the payload. A picture of
+//! the IPv6 packet header can be found [here].
+//!
+//! [here]: https://en.wikipedia.org/wiki/IPv6_packet#Fixed_header
+
+use std::convert::TryFrom;
+use std::net::Ipv6Addr;
+use std::result::Result;
+
+use crate::pdu::bytes::{InnerBytes, NetworkBytes};
+
+const VERSION_OFFSET: usize = 0;
+const PAYLOAD_LEN_OFFSET: usize = 4;
+const NEXT_HEADER_OFFSET: usize = 6;
+const HOP_LIMIT_OFFSET: usize = 7;
+const SOURCE_ADDRESS_OFFSET: usize = 8;
+const DESTINATION_ADDRESS_OFFSET: usize = 24;
+
+/// The length of the fixed IPv6 header.
+pub const IPV6_HEADER_LEN: usize = 40;
+
+/// Indicates version 6 of the IP protocol
+pub const IPV6_VERSION: u8 = 0x06;
+
+/// Describes the errors which may occur while handling IPv6 packets.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The length of the given slice does not match the length of the packet.
+    SliceExactLen,
+    /// The length of the given slice is less than the IPv6 header length.
+    SliceTooShort,
+    /// The version header field is invalid.
+    Version,
+}
+
+/// Interprets the inner bytes as an IPv6 packet.
+pub struct IPv6Packet<'a, T: 'a> {
+    bytes: InnerBytes<'a, T>,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl<'a, T: NetworkBytes> IPv6Packet<'a, T> {
+    /// Interpret `bytes` as an IPv6Packet without checking the validity of the header fields, and
+    /// the length of the inner byte sequence.
+    ///
+    /// # Panics
+    ///
+    /// This method does not panic, but further method calls on the resulting object may panic if
+    /// `bytes` contains invalid input.
+    #[inline]
+    pub fn from_bytes_unchecked(bytes: T) -> Self {
+        IPv6Packet {
+            bytes: InnerBytes::new(bytes),
+        }
+    }
+
+    /// Attempts to interpret `bytes` as an IPv6 packet, checking the validity of the header fields
+    /// and the length of the inner byte sequence.
+    pub fn from_bytes(bytes: T) -> Result<Self, Error> {
+        let bytes_len = bytes.len();
+
+        if bytes_len < IPV6_HEADER_LEN {
+            return Err(Error::SliceTooShort);
+        }
+
+        let packet = IPv6Packet::from_bytes_unchecked(bytes);
+
+        if packet.version() != IPV6_VERSION {
+            return Err(Error::Version);
+        }
+
+        if IPV6_HEADER_LEN + packet.payload_len() as usize != bytes_len {
+            return Err(Error::SliceExactLen);
+        }
+
+        Ok(packet)
+    }
+
+    /// Returns the value of the `version` header field.
+    #[inline]
+    pub fn version(&self) -> u8 {
+        self.bytes[VERSION_OFFSET] >> 4
+    }
+
+    /// Returns the value of the `payload length` header field.
+    #[inline]
+    pub fn payload_len(&self) -> u16 {
+        self.bytes.ntohs_unchecked(PAYLOAD_LEN_OFFSET)
+    }
+
+    /// Returns the value of the `next header` header field.
+    #[inline]
+    pub fn next_header(&self) -> u8 {
+        self.bytes[NEXT_HEADER_OFFSET]
+    }
+
+    /// Returns the value of the `hop limit` header field.
+    #[inline]
+    pub fn hop_limit(&self) -> u8 {
+        self.bytes[HOP_LIMIT_OFFSET]
+    }
+
+    /// Returns the source IPv6 address of the packet.
+    #[inline]
+    pub fn source_address(&self) -> Ipv6Addr {
+        self.address_at(SOURCE_ADDRESS_OFFSET)
+    }
+
+    /// Returns the destination IPv6 address of the packet.
+    #[inline]
+    pub fn destination_address(&self) -> Ipv6Addr {
+        self.address_at(DESTINATION_ADDRESS_OFFSET)
+    }
+
+    /// Returns a byte slice that contains the payload of the packet.
+    #[inline]
+    pub fn payload(&self) -> &[u8] {
+        self.bytes.split_at(IPV6_HEADER_LEN).1
+    }
+
+    /// Returns the length of the inner byte sequence.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    #[inline]
+    fn address_at(&self, offset: usize) -> Ipv6Addr {
+        // Safe to unwrap since the slice is exactly 16 bytes long.
+        Ipv6Addr::from(<[u8; 16]>::try_from(&self.bytes[offset..offset + 16]).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC_ADDR: &str = "fe80::1";
+    const DST_ADDR: &str = "2001:db8::2";
+
+    fn packet(payload_len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; IPV6_HEADER_LEN + payload_len];
+        bytes[VERSION_OFFSET] = IPV6_VERSION << 4;
+        bytes[PAYLOAD_LEN_OFFSET..PAYLOAD_LEN_OFFSET + 2]
+            .copy_from_slice(&(payload_len as u16).to_be_bytes());
+        bytes[NEXT_HEADER_OFFSET] = 17;
+        bytes[HOP_LIMIT_OFFSET] = 64;
+        let src: Ipv6Addr = SRC_ADDR.parse().unwrap();
+        bytes[SOURCE_ADDRESS_OFFSET..SOURCE_ADDRESS_OFFSET + 16].copy_from_slice(&src.octets());
+        let dst: Ipv6Addr = DST_ADDR.parse().unwrap();
+        bytes[DESTINATION_ADDRESS_OFFSET..DESTINATION_ADDRESS_OFFSET + 16]
+            .copy_from_slice(&dst.octets());
+        bytes
+    }
+
+    #[test]
+    fn test_get() {
+        let bytes = packet(8);
+        let p = IPv6Packet::from_bytes(bytes.as_slice()).unwrap();
+
+        assert_eq!(p.version(), IPV6_VERSION);
+        assert_eq!(p.payload_len(), 8);
+        assert_eq!(p.next_header(), 17);
+        assert_eq!(p.hop_limit(), 64);
+        assert_eq!(p.source_address(), SRC_ADDR.parse::<Ipv6Addr>().unwrap());
+        assert_eq!(
+            p.destination_address(),
+            DST_ADDR.parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(p.payload(), &[0u8; 8]);
+        assert_eq!(p.len(), IPV6_HEADER_LEN + 8);
+    }
+
+    #[test]
+    fn test_from_bytes_errors() {
+        let bytes = packet(8);
+        assert_eq!(
+            IPv6Packet::from_bytes(&bytes[..IPV6_HEADER_LEN - 1]).err(),
+            Some(Error::SliceTooShort)
+        );
+        assert_eq!(
+            IPv6Packet::from_bytes(&bytes[..IPV6_HEADER_LEN + 4]).err(),
+            Some(Error::SliceExactLen)
+        );
+
+        let mut bytes = packet(0);
+        bytes[VERSION_OFFSET] = 4 << 4;
+        assert_eq!(
+            IPv6Packet::from_bytes(bytes.as_slice()).err(),
+            Some(Error::Version)
+        );
+    }
+}
@@ -49,9 +49,13 @@ impl ApiServerAdapter {
             event_manager
                 .run()
                 .expect("EventManager events driver fatal error");
-            if let Some(exit_code) = vmm.lock().unwrap().shutdown_exit_code() {
+            let mut locked_vmm = vmm.lock().unwrap();
+            if let Some(exit_code) = locked_vmm.shutdown_exit_code() {
                 return exit_code;
             }
+            // Devices hot-(un)plugged through the API are (un)registered here, since the
+            // event manager is not reachable while the request is being handled.
+            locked_vmm.update_hotplug_subscribers(event_manager);
         }
     }
 
@@ -29,6 +29,8 @@ pub enum OpCode {
     Write = bindings::IORING_OP_WRITE as u8,
     /// Fsync operation.
     Fsync = bindings::IORING_OP_FSYNC as u8,
+    /// Fallocate operation.
+    Fallocate = bindings::IORING_OP_FALLOCATE as u8,
 }
 
 // Useful for outputting errors.
@@ -38,6 +40,7 @@ impl From<OpCode> for &'static str {
             OpCode::Read => "read",
             OpCode::Write => "write",
             OpCode::Fsync => "fsync",
+            OpCode::Fallocate => "fallocate",
         }
     }
 }
@@ -114,6 +117,23 @@ impl<T> Operation<T> {
         }
     }
 
+    /// Construct a fallocate operation.
+    ///
+    /// The `mode` is passed verbatim to the kernel, e.g. `FALLOC_FL_PUNCH_HOLE`.
+    pub fn fallocate(fd: FixedFd, len: u64, mode: u32, offset: u64, user_data: T) -> Self {
+        Self {
+            fd,
+            opcode: OpCode::Fallocate,
+            // For fallocate, the kernel reads the length from the `addr` field and the mode
+            // from the `len` field of the sqe.
+            addr: Some(len as usize),
+            len: Some(mode),
+            flags: 0,
+            offset: Some(offset),
+            user_data: Box::new(user_data),
+        }
+    }
+
     pub(crate) fn fd(&self) -> FixedFd {
         self.fd
     }
@@ -43,6 +43,12 @@ const DEV_URANDOM_WITH_NUL: &[u8] = b"/dev/urandom\0";
 const DEV_URANDOM_MAJOR: u32 = 1;
 const DEV_URANDOM_MINOR: u32 = 9;
 
+// vhost-net device minor/major numbers are taken from
+// https://www.kernel.org/doc/Documentation/admin-guide/devices.txt
+const DEV_VHOST_NET_WITH_NUL: &[u8] = b"/dev/vhost-net\0";
+const DEV_VHOST_NET_MAJOR: u32 = 10;
+const DEV_VHOST_NET_MINOR: u32 = 238;
+
 const DEV_NULL_WITH_NUL: &[u8] = b"/dev/null\0";
 
 // Relevant folders inside the jail that we create or/and for which we change ownership.
@@ -594,6 +600,21 @@ impl Env {
                 );
                 println!("MMDS version 2 will not be available to use.");
             });
+        // And for /dev/vhost-net with (major, minor) = (10, 238), which is only needed by the
+        // network interfaces using vhost-net.
+        let _ = self
+            .mknod_and_own_dev(
+                DEV_VHOST_NET_WITH_NUL,
+                DEV_VHOST_NET_MAJOR,
+                DEV_VHOST_NET_MINOR,
+            )
+            .map_err(|err| {
+                println!(
+                    "Warning! Could not create /dev/vhost-net device inside jailer: {}.",
+                    err
+                );
+                println!("vhost-net will not be available to use.");
+            });
 
         // Daemonize before exec, if so required (when the dev_null variable != None).
         if let Some(fd) = dev_null {
@@ -1000,6 +1021,11 @@ mod tests {
         let dev_infos: Vec<(&[u8], u32, u32)> = vec![
             (b"/dev/net/tun-test\0", DEV_NET_TUN_MAJOR, DEV_NET_TUN_MINOR),
             (b"/dev/kvm-test\0", DEV_KVM_MAJOR, DEV_KVM_MINOR),
+            (
+                b"/dev/vhost-net-test\0",
+                DEV_VHOST_NET_MAJOR,
+                DEV_VHOST_NET_MINOR,
+            ),
         ];
 
         for (dev, major, minor) in dev_infos {
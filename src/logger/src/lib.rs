@@ -18,8 +18,9 @@ pub use crate::logger::{LoggerError, LOGGER};
 #[cfg(target_arch = "aarch64")]
 pub use crate::metrics::RTCDeviceMetrics;
 pub use crate::metrics::{
-    IncMetric, MetricsError, ProcessTimeReporter, SerialDeviceMetrics, SharedIncMetric,
-    SharedStoreMetric, StoreMetric, METRICS,
+    BlockDeviceMetrics, IncMetric, LatencyHistogram, MetricsError, NetQueueMetrics,
+    ProcessTimeReporter, SerialDeviceMetrics, SharedIncMetric, SharedStoreMetric, StoreMetric,
+    LATENCY_BUCKETS_US, METRICS,
 };
 
 /// Prefix to be used in log lines for functions/modules in Firecracker
@@ -61,13 +61,15 @@
 //! If if turns out this approach is not really what we want, it's pretty easy to resort to
 //! something else, while working behind the same interface.
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io::Write;
 use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
 use lazy_static::lazy_static;
+use serde::ser::SerializeMap;
 use serde::{Serialize, Serializer};
 #[cfg(target_arch = "aarch64")]
 use vm_superio::rtc_pl031::RtcEvents;
@@ -268,6 +270,14 @@ impl IncMetric for SharedIncMetric {
     }
 }
 
+impl SharedIncMetric {
+    /// Returns the amount by which the counter was increased since the last flush, without
+    /// resetting it.
+    pub fn fetch_diff(&self) -> usize {
+        self.0.load(Ordering::Relaxed) - self.1.load(Ordering::Relaxed)
+    }
+}
+
 impl StoreMetric for SharedStoreMetric {
     fn fetch(&self) -> usize {
         self.0.load(Ordering::Relaxed)
@@ -300,6 +310,85 @@ impl Serialize for SharedStoreMetric {
     }
 }
 
+/// Inclusive upper bounds, in microseconds, of the buckets of a `LatencyHistogram`.
+/// Latencies above the last bound are counted in an additional overflow bucket.
+pub const LATENCY_BUCKETS_US: [u64; 12] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, 100_000,
+];
+
+/// Histogram of latencies measured in microseconds.
+///
+/// Each bucket behaves like a `SharedIncMetric`, so the serialized values only account for the
+/// samples recorded since the previous flush. The histogram is serialized as
+/// `{"count": _, "sum_us": _, "buckets": {"10": _, ..., "100000": _, "inf": _}}`, where every
+/// bucket is keyed by its inclusive upper bound.
+#[derive(Default)]
+pub struct LatencyHistogram {
+    buckets: [SharedIncMetric; LATENCY_BUCKETS_US.len() + 1],
+    sum_us: SharedIncMetric,
+}
+
+impl LatencyHistogram {
+    /// Records a sample of `latency_us` microseconds.
+    pub fn record(&self, latency_us: u64) {
+        let index = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&bound| latency_us <= bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[index].inc();
+        self.sum_us.add(latency_us as usize);
+    }
+
+    /// Returns the total number of recorded samples.
+    pub fn count(&self) -> usize {
+        self.buckets.iter().map(IncMetric::count).sum()
+    }
+
+    /// Returns the number of samples recorded in the bucket at `index`.
+    pub fn bucket_count(&self, index: usize) -> usize {
+        self.buckets[index].count()
+    }
+
+    /// Adds the samples `other` recorded since its last flush to this histogram.
+    pub fn aggregate(&self, other: &LatencyHistogram) {
+        for (bucket, other_bucket) in self.buckets.iter().zip(other.buckets.iter()) {
+            bucket.add(other_bucket.fetch_diff());
+        }
+        self.sum_us.add(other.sum_us.fetch_diff());
+    }
+}
+
+// Serializes the buckets of a `LatencyHistogram`, keyed by their upper bounds.
+struct LatencyBuckets<'a>(&'a [SharedIncMetric]);
+
+impl Serialize for LatencyBuckets<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (bound, bucket) in LATENCY_BUCKETS_US.iter().zip(self.0.iter()) {
+            map.serialize_entry(&bound.to_string(), bucket)?;
+        }
+        map.serialize_entry("inf", &self.0[LATENCY_BUCKETS_US.len()])?;
+        map.end()
+    }
+}
+
+impl Serialize for LatencyHistogram {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // The total is computed before the buckets get reset by their own serialization.
+        let count = self
+            .buckets
+            .iter()
+            .map(SharedIncMetric::fetch_diff)
+            .sum::<usize>();
+
+        let mut map = serializer.serialize_map(Some(3))?;
+        map.serialize_entry("count", &(count as u64))?;
+        map.serialize_entry("sum_us", &self.sum_us)?;
+        map.serialize_entry("buckets", &LatencyBuckets(&self.buckets))?;
+        map.end()
+    }
+}
+
 /// Reporter object which computes the process wall time and
 /// process CPU time and populates the metric with the results.
 pub struct ProcessTimeReporter {
@@ -440,6 +529,10 @@ pub struct PatchRequestsMetrics {
     pub mmds_count: SharedIncMetric,
     /// Number of failures in PATCHing an mmds.
     pub mmds_fails: SharedIncMetric,
+    /// Number of tries to PATCH a vsock device.
+    pub vsock_count: SharedIncMetric,
+    /// Number of failures in PATCHing a vsock device.
+    pub vsock_fails: SharedIncMetric,
 }
 
 /// Metrics related to deprecated user-facing API calls.
@@ -464,6 +557,22 @@ pub struct BalloonDeviceMetrics {
     pub stats_update_fails: SharedIncMetric,
     /// Number of balloon device deflations.
     pub deflate_count: SharedIncMetric,
+    /// Number of free page hinting runs requested from the driver.
+    pub free_page_hint_count: SharedIncMetric,
+    /// Number of bytes hinted as free by the driver.
+    pub free_page_hint_bytes: SharedIncMetric,
+    /// Number of free page reports received from the driver.
+    pub free_page_report_count: SharedIncMetric,
+    /// Number of bytes released through free page reporting.
+    pub free_page_report_freed: SharedIncMetric,
+    /// Number of reported free page ranges which couldn't be released.
+    pub free_page_report_fails: SharedIncMetric,
+    /// Number of times the automatic sizing policy inflated the balloon.
+    pub auto_inflate_count: SharedIncMetric,
+    /// Number of times the automatic sizing policy deflated the balloon.
+    pub auto_deflate_count: SharedIncMetric,
+    /// The latest size of the balloon, in MiB, set by the automatic sizing policy.
+    pub auto_target_mib: SharedStoreMetric,
     /// Number of times when handling events on a balloon device failed.
     pub event_fails: SharedIncMetric,
 }
@@ -506,6 +615,134 @@ pub struct BlockDeviceMetrics {
     /// Number of virtio events throttled because of the IO engine.
     /// This happens when the io_uring submission queue is full.
     pub io_engine_throttled_events: SharedIncMetric,
+    /// Number of successful discard operations.
+    pub discard_count: SharedIncMetric,
+    /// Number of bytes discarded by this block device.
+    pub discard_bytes: SharedIncMetric,
+    /// Number of successful write zeroes operations.
+    pub write_zeroes_count: SharedIncMetric,
+    /// Number of bytes zeroed by this block device.
+    pub write_zeroes_bytes: SharedIncMetric,
+    /// Time elapsed between fetching a read request from the queue and completing it.
+    pub read_latency_us: LatencyHistogram,
+    /// Time elapsed between fetching a write request from the queue and completing it.
+    pub write_latency_us: LatencyHistogram,
+    /// Time elapsed between fetching a flush request from the queue and completing it.
+    pub flush_latency_us: LatencyHistogram,
+}
+
+impl BlockDeviceMetrics {
+    /// Adds the values `other` accumulated since its last flush to these metrics.
+    pub fn aggregate(&self, other: &BlockDeviceMetrics) {
+        self.activate_fails.add(other.activate_fails.fetch_diff());
+        self.cfg_fails.add(other.cfg_fails.fetch_diff());
+        self.no_avail_buffer.add(other.no_avail_buffer.fetch_diff());
+        self.event_fails.add(other.event_fails.fetch_diff());
+        self.execute_fails.add(other.execute_fails.fetch_diff());
+        self.invalid_reqs_count
+            .add(other.invalid_reqs_count.fetch_diff());
+        self.flush_count.add(other.flush_count.fetch_diff());
+        self.queue_event_count
+            .add(other.queue_event_count.fetch_diff());
+        self.rate_limiter_event_count
+            .add(other.rate_limiter_event_count.fetch_diff());
+        self.update_count.add(other.update_count.fetch_diff());
+        self.update_fails.add(other.update_fails.fetch_diff());
+        self.read_bytes.add(other.read_bytes.fetch_diff());
+        self.write_bytes.add(other.write_bytes.fetch_diff());
+        self.read_count.add(other.read_count.fetch_diff());
+        self.write_count.add(other.write_count.fetch_diff());
+        self.rate_limiter_throttled_events
+            .add(other.rate_limiter_throttled_events.fetch_diff());
+        self.io_engine_throttled_events
+            .add(other.io_engine_throttled_events.fetch_diff());
+        self.discard_count.add(other.discard_count.fetch_diff());
+        self.discard_bytes.add(other.discard_bytes.fetch_diff());
+        self.write_zeroes_count
+            .add(other.write_zeroes_count.fetch_diff());
+        self.write_zeroes_bytes
+            .add(other.write_zeroes_bytes.fetch_diff());
+        self.read_latency_us.aggregate(&other.read_latency_us);
+        self.write_latency_us.aggregate(&other.write_latency_us);
+        self.flush_latency_us.aggregate(&other.flush_latency_us);
+    }
+}
+
+/// Metrics of all the block devices, keyed by drive ID.
+///
+/// Each block device holds its own `BlockDeviceMetrics` instance. Upon serialization, the metrics
+/// of every drive are emitted as `block_<drive_id>`, while `block` holds their sum.
+#[derive(Default)]
+pub struct BlockMetricsPerDevice {
+    devices: RwLock<BTreeMap<String, Arc<BlockDeviceMetrics>>>,
+}
+
+impl BlockMetricsPerDevice {
+    /// Returns the metrics of the drive identified by `drive_id`, creating them on first use.
+    ///
+    /// A drive which is re-created with the same ID, e.g. on update or hot-plug, keeps
+    /// reporting in the same instance.
+    pub fn alloc(&self, drive_id: &str) -> Arc<BlockDeviceMetrics> {
+        if let Some(metrics) = extract_guard(self.devices.read()).get(drive_id) {
+            return metrics.clone();
+        }
+        extract_guard(self.devices.write())
+            .entry(drive_id.to_string())
+            .or_insert_with(|| Arc::new(BlockDeviceMetrics::default()))
+            .clone()
+    }
+
+    /// Returns the metrics of the drive identified by `drive_id`, if any.
+    pub fn get(&self, drive_id: &str) -> Option<Arc<BlockDeviceMetrics>> {
+        extract_guard(self.devices.read()).get(drive_id).cloned()
+    }
+}
+
+impl Serialize for BlockMetricsPerDevice {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let devices = extract_guard(self.devices.read());
+
+        // The aggregate is computed first, since serializing the metrics of a drive resets them.
+        let aggregate = BlockDeviceMetrics::default();
+        for metrics in devices.values() {
+            aggregate.aggregate(metrics);
+        }
+
+        let mut map = serializer.serialize_map(Some(devices.len() + 1))?;
+        map.serialize_entry("block", &aggregate)?;
+        for (drive_id, metrics) in devices.iter() {
+            map.serialize_entry(&format!("block_{}", drive_id), metrics.as_ref())?;
+        }
+        map.end()
+    }
+}
+
+/// Metrics specific to the block devices served by a vhost-user backend.
+#[derive(Default, Serialize)]
+pub struct VhostUserBlockDeviceMetrics {
+    /// Number of times when setting up the vhost-user backend on activation failed.
+    pub activate_fails: SharedIncMetric,
+    /// Number of times when interacting with the space config of a block device failed.
+    pub cfg_fails: SharedIncMetric,
+    /// Number of times when handling events on a block device failed.
+    pub event_fails: SharedIncMetric,
+}
+
+/// Virtio-mem device associated metrics.
+#[derive(Default, Serialize)]
+pub struct VirtioMemDeviceMetrics {
+    /// Number of times when activate failed on a virtio-mem device.
+    pub activate_fails: SharedIncMetric,
+    /// Number of plug requests accepted from the driver.
+    pub plug_count: SharedIncMetric,
+    /// Number of plug requests refused or failed.
+    pub plug_fails: SharedIncMetric,
+    /// Number of unplug requests accepted from the driver.
+    pub unplug_count: SharedIncMetric,
+    /// Number of unplug requests failed.
+    pub unplug_fails: SharedIncMetric,
+    /// Number of times when handling events on a virtio-mem device failed.
+    pub event_fails: SharedIncMetric,
 }
 
 /// Metrics specific to the i8042 device.
@@ -570,6 +807,10 @@ pub struct MmdsMetrics {
 pub struct NetDeviceMetrics {
     /// Number of times when activate failed on a network device.
     pub activate_fails: SharedIncMetric,
+    /// Number of times writing to the packet capture file failed.
+    pub capture_fails: SharedIncMetric,
+    /// Number of frames written to the packet capture file.
+    pub capture_frames_count: SharedIncMetric,
     /// Number of times when interacting with the space config of a network device failed.
     pub cfg_fails: SharedIncMetric,
     //// Number of times the mac address was updated through the config space.
@@ -622,6 +863,81 @@ pub struct NetDeviceMetrics {
     pub tx_rate_limiter_throttled: SharedIncMetric,
     /// Number of packets with a spoofed mac, sent by the guest.
     pub tx_spoofed_mac_count: SharedIncMetric,
+    /// Number of frames sent by the guest and dropped since their ethertype is not allowed by
+    /// the anti-spoofing policy.
+    pub tx_spoofing_ethertype_drops: SharedIncMetric,
+    /// Number of frames sent by the guest and dropped since their source IP address is not
+    /// allowed by the anti-spoofing policy.
+    pub tx_spoofing_ip_drops: SharedIncMetric,
+    /// Number of frames sent by the guest and dropped since their source MAC address is not
+    /// allowed by the anti-spoofing policy.
+    pub tx_spoofing_mac_drops: SharedIncMetric,
+    /// Number of times the vhost-net datapath couldn't be used and the device fell back to
+    /// processing the queues in Firecracker.
+    pub vhost_net_fallbacks: SharedIncMetric,
+}
+
+/// Metrics of a single RX/TX queue pair of a network device.
+#[derive(Default, Serialize)]
+pub struct NetQueueMetrics {
+    /// Number of bytes received.
+    pub rx_bytes_count: SharedIncMetric,
+    /// Number of packets received.
+    pub rx_packets_count: SharedIncMetric,
+    /// Number of RX rate limiter throttling events.
+    pub rx_rate_limiter_throttled: SharedIncMetric,
+    /// Number of transmitted bytes.
+    pub tx_bytes_count: SharedIncMetric,
+    /// Number of transmitted packets.
+    pub tx_packets_count: SharedIncMetric,
+    /// Number of TX rate limiter throttling events.
+    pub tx_rate_limiter_throttled: SharedIncMetric,
+}
+
+/// Metrics of the queue pairs of all the network devices, keyed by interface ID and queue pair
+/// index.
+///
+/// Upon serialization, the metrics of every queue pair are emitted as `net_<iface_id>_q<index>`.
+/// The device-wide totals are still reported in `net`.
+#[derive(Default)]
+pub struct NetMetricsPerQueue {
+    queues: RwLock<BTreeMap<(String, u16), Arc<NetQueueMetrics>>>,
+}
+
+impl NetMetricsPerQueue {
+    /// Returns the metrics of a queue pair of the interface identified by `iface_id`, creating
+    /// them on first use.
+    pub fn alloc(&self, iface_id: &str, queue_pair: u16) -> Arc<NetQueueMetrics> {
+        let key = (iface_id.to_string(), queue_pair);
+        if let Some(metrics) = extract_guard(self.queues.read()).get(&key) {
+            return metrics.clone();
+        }
+        extract_guard(self.queues.write())
+            .entry(key)
+            .or_insert_with(|| Arc::new(NetQueueMetrics::default()))
+            .clone()
+    }
+
+    /// Returns the metrics of a queue pair of the interface identified by `iface_id`, if any.
+    pub fn get(&self, iface_id: &str, queue_pair: u16) -> Option<Arc<NetQueueMetrics>> {
+        extract_guard(self.queues.read())
+            .get(&(iface_id.to_string(), queue_pair))
+            .cloned()
+    }
+}
+
+impl Serialize for NetMetricsPerQueue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let queues = extract_guard(self.queues.read());
+        let mut map = serializer.serialize_map(Some(queues.len()))?;
+        for ((iface_id, queue_pair), metrics) in queues.iter() {
+            map.serialize_entry(
+                &format!("net_{}_q{}", iface_id, queue_pair),
+                metrics.as_ref(),
+            )?;
+        }
+        map.end()
+    }
 }
 
 /// Performance metrics related for the moment only to snapshots.
@@ -798,6 +1114,12 @@ pub struct VsockDeviceMetrics {
     pub tx_write_fails: SharedIncMetric,
     /// Number of times read() has failed.
     pub rx_read_fails: SharedIncMetric,
+    /// Number of events associated with the rate limiter.
+    pub rate_limiter_event_count: SharedIncMetric,
+    /// Number of times when handling rate limiter events on a vsock device failed.
+    pub rate_limiter_event_fails: SharedIncMetric,
+    /// Number of times the vsock device was throttled by the rate limiter.
+    pub rate_limiter_throttled: SharedIncMetric,
 }
 
 // The sole purpose of this struct is to produce an UTC timestamp when an instance is serialized.
@@ -820,8 +1142,9 @@ pub struct FirecrackerMetrics {
     pub api_server: ApiServerMetrics,
     /// A balloon device's related metrics.
     pub balloon: BalloonDeviceMetrics,
-    /// A block device's related metrics.
-    pub block: BlockDeviceMetrics,
+    /// Block devices' related metrics, both aggregated and per drive.
+    #[serde(flatten)]
+    pub block: BlockMetricsPerDevice,
     /// Metrics related to deprecated API calls.
     pub deprecated_api: DeprecatedApiMetrics,
     /// Metrics related to API GET requests.
@@ -836,6 +1159,9 @@ pub struct FirecrackerMetrics {
     pub mmds: MmdsMetrics,
     /// A network device's related metrics.
     pub net: NetDeviceMetrics,
+    /// Metrics of the individual queue pairs of the network devices.
+    #[serde(flatten)]
+    pub net_queues: NetMetricsPerQueue,
     /// Metrics related to API PATCH requests.
     pub patch_api_requests: PatchRequestsMetrics,
     /// Metrics related to API PUT requests.
@@ -847,6 +1173,10 @@ pub struct FirecrackerMetrics {
     pub seccomp: SeccompMetrics,
     /// Metrics related to a vcpu's functioning.
     pub vcpu: VcpuMetrics,
+    /// Metrics related to the block devices served by a vhost-user backend.
+    pub vhost_user_block: VhostUserBlockDeviceMetrics,
+    /// Metrics related to the virtio-mem device.
+    pub virtio_mem: VirtioMemDeviceMetrics,
     /// Metrics related to the virtual machine manager.
     pub vmm: VmmMetrics,
     /// Metrics related to the UART device.
@@ -929,6 +1259,85 @@ mod tests {
         assert_eq!(1, m1.fetch());
     }
 
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        histogram.record(0);
+        histogram.record(10);
+        histogram.record(11);
+        histogram.record(100_001);
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.bucket_count(0), 2);
+        assert_eq!(histogram.bucket_count(1), 1);
+        assert_eq!(histogram.bucket_count(LATENCY_BUCKETS_US.len()), 1);
+
+        let aggregate = LatencyHistogram::default();
+        aggregate.aggregate(&histogram);
+        assert_eq!(aggregate.count(), 4);
+
+        let json = serde_json::to_value(&histogram).unwrap();
+        assert_eq!(json["count"], 4);
+        assert_eq!(json["sum_us"], 100_022);
+        assert_eq!(json["buckets"]["10"], 2);
+        assert_eq!(json["buckets"]["25"], 1);
+        assert_eq!(json["buckets"]["inf"], 1);
+
+        // Serializing the histogram resets it.
+        let json = serde_json::to_value(&histogram).unwrap();
+        assert_eq!(json["count"], 0);
+        assert_eq!(json["buckets"]["10"], 0);
+    }
+
+    #[test]
+    fn test_block_metrics_per_device() {
+        let block_metrics = BlockMetricsPerDevice::default();
+        let drive1 = block_metrics.alloc("drive1");
+        let drive2 = block_metrics.alloc("drive2");
+        assert!(Arc::ptr_eq(&drive1, &block_metrics.alloc("drive1")));
+        assert!(Arc::ptr_eq(&drive2, &block_metrics.get("drive2").unwrap()));
+        assert!(block_metrics.get("drive3").is_none());
+
+        drive1.read_count.add(2);
+        drive1.read_latency_us.record(30);
+        drive2.read_count.inc();
+        drive2.write_latency_us.record(1_000);
+
+        let json = serde_json::to_value(&block_metrics).unwrap();
+        assert_eq!(json["block"]["read_count"], 3);
+        assert_eq!(json["block"]["read_latency_us"]["count"], 1);
+        assert_eq!(json["block"]["write_latency_us"]["buckets"]["1000"], 1);
+        assert_eq!(json["block_drive1"]["read_count"], 2);
+        assert_eq!(json["block_drive1"]["read_latency_us"]["buckets"]["50"], 1);
+        assert_eq!(json["block_drive2"]["read_count"], 1);
+        assert_eq!(json["block_drive2"]["write_latency_us"]["sum_us"], 1_000);
+
+        let json = serde_json::to_value(&block_metrics).unwrap();
+        assert_eq!(json["block"]["read_count"], 0);
+        assert_eq!(json["block_drive1"]["read_count"], 0);
+    }
+
+    #[test]
+    fn test_net_metrics_per_queue() {
+        let net_metrics = NetMetricsPerQueue::default();
+        let queue0 = net_metrics.alloc("eth0", 0);
+        let queue1 = net_metrics.alloc("eth0", 1);
+        assert!(Arc::ptr_eq(&queue0, &net_metrics.alloc("eth0", 0)));
+        assert!(Arc::ptr_eq(&queue1, &net_metrics.get("eth0", 1).unwrap()));
+        assert!(net_metrics.get("eth0", 2).is_none());
+        assert!(net_metrics.get("eth1", 0).is_none());
+
+        queue0.rx_packets_count.inc();
+        queue1.tx_bytes_count.add(100);
+
+        let json = serde_json::to_value(&net_metrics).unwrap();
+        assert_eq!(json["net_eth0_q0"]["rx_packets_count"], 1);
+        assert_eq!(json["net_eth0_q0"]["tx_bytes_count"], 0);
+        assert_eq!(json["net_eth0_q1"]["tx_bytes_count"], 100);
+
+        let json = serde_json::to_value(&net_metrics).unwrap();
+        assert_eq!(json["net_eth0_q1"]["tx_bytes_count"], 0);
+    }
+
     #[test]
     fn test_serialize() {
         let s = serde_json::to_string(&FirecrackerMetrics::default());
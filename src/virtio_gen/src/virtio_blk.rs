@@ -21,6 +21,8 @@ pub const VIRTIO_BLK_F_RO: u32 = 5;
 pub const VIRTIO_BLK_F_BLK_SIZE: u32 = 6;
 pub const VIRTIO_BLK_F_TOPOLOGY: u32 = 10;
 pub const VIRTIO_BLK_F_MQ: u32 = 12;
+pub const VIRTIO_BLK_F_DISCARD: u32 = 13;
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
 pub const VIRTIO_BLK_F_BARRIER: u32 = 0;
 pub const VIRTIO_BLK_F_SCSI: u32 = 7;
 pub const VIRTIO_BLK_F_FLUSH: u32 = 9;
@@ -32,6 +34,9 @@ pub const VIRTIO_BLK_T_OUT: u32 = 1;
 pub const VIRTIO_BLK_T_SCSI_CMD: u32 = 2;
 pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
 pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
+pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+pub const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
 pub const VIRTIO_BLK_T_BARRIER: u32 = 2147483648;
 pub const VIRTIO_BLK_S_OK: u32 = 0;
 pub const VIRTIO_BLK_S_IOERR: u32 = 1;
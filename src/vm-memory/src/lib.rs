@@ -5,8 +5,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use std::fs::File;
 use std::io::Error as IoError;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 
 use vm_memory_upstream::bitmap::AtomicBitmap;
 pub use vm_memory_upstream::bitmap::Bitmap;
@@ -24,6 +25,56 @@ pub type GuestMmapRegion = vm_memory_upstream::MmapRegion<Option<AtomicBitmap>>;
 
 const GUARD_PAGE_COUNT: usize = 1;
 
+/// The size of the huge pages backing the guest memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HugePageSize {
+    /// 2 MiB pages.
+    Size2M,
+    /// 1 GiB pages.
+    Size1G,
+}
+
+impl HugePageSize {
+    /// Returns the size of a page, in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            HugePageSize::Size2M => 2 << 20,
+            HugePageSize::Size1G => 1 << 30,
+        }
+    }
+
+    /// Returns the `mmap` flags which back a mapping with pages of this size.
+    pub fn mmap_flags(self) -> i32 {
+        libc::MAP_HUGETLB
+            | match self {
+                HugePageSize::Size2M => libc::MAP_HUGE_2MB,
+                HugePageSize::Size1G => libc::MAP_HUGE_1GB,
+            }
+    }
+
+    /// Returns the size of the huge pages backing a mapping created with the `mmap` flags
+    /// `flags`, if any.
+    pub fn from_mmap_flags(flags: i32) -> Option<HugePageSize> {
+        if flags & libc::MAP_HUGETLB == 0 {
+            return None;
+        }
+        match flags & (libc::MAP_HUGE_MASK << libc::MAP_HUGE_SHIFT) {
+            libc::MAP_HUGE_2MB => Some(HugePageSize::Size2M),
+            libc::MAP_HUGE_1GB => Some(HugePageSize::Size1G),
+            // We never map huge pages of the default size.
+            _ => None,
+        }
+    }
+
+    fn memfd_flags(self) -> libc::c_uint {
+        libc::MFD_HUGETLB
+            | match self {
+                HugePageSize::Size2M => libc::MFD_HUGE_2MB,
+                HugePageSize::Size1G => libc::MFD_HUGE_1GB,
+            }
+    }
+}
+
 /// Build a `MmapRegion` surrounded by guard pages.
 ///
 /// Initially, we map a `PROT_NONE` guard region of size:
@@ -37,6 +88,9 @@ const GUARD_PAGE_COUNT: usize = 1;
 /// This results in a border of `GUARD_PAGE_COUNT` pages on either side of the region, which
 /// acts as a safety net for accessing out-of-bounds addresses that are not allocated for the
 /// guest's memory.
+///
+/// Huge page mappings, selected through `flags`, have to start at an address aligned to the
+/// size of the huge pages, so the guard region is made large enough to align the nested region.
 fn build_guarded_region(
     maybe_file_offset: Option<FileOffset>,
     size: usize,
@@ -45,9 +99,16 @@ fn build_guarded_region(
     track_dirty_pages: bool,
 ) -> Result<GuestMmapRegion, MmapRegionError> {
     let page_size = utils::get_page_size().expect("Cannot retrieve page size.");
+    let alignment = HugePageSize::from_mmap_flags(flags).map_or(page_size, HugePageSize::bytes);
+    // The kernel would round the size of a huge page mapping up, over the right guard border.
+    if size % alignment != 0 {
+        return Err(MmapRegionError::Mmap(IoError::from_raw_os_error(
+            libc::EINVAL,
+        )));
+    }
     // Create the guarded range size (received size + X pages),
-    // where X is defined as a constant GUARD_PAGE_COUNT.
-    let guarded_size = size + GUARD_PAGE_COUNT * 2 * page_size;
+    // where X is defined as a constant GUARD_PAGE_COUNT, plus the room needed for the alignment.
+    let guarded_size = size + GUARD_PAGE_COUNT * 2 * page_size + (alignment - page_size);
 
     // Map the guarded range to PROT_NONE
     let guard_addr = unsafe {
@@ -73,7 +134,8 @@ fn build_guarded_region(
         None => (-1, 0),
     };
 
-    let region_start_addr = guard_addr as usize + page_size * GUARD_PAGE_COUNT;
+    let region_start_addr =
+        (guard_addr as usize + page_size * GUARD_PAGE_COUNT + alignment - 1) & !(alignment - 1);
 
     // Inside the protected range, starting with guard_addr + PAGE_SIZE,
     // map the requested range with received protection and flags
@@ -107,18 +169,100 @@ fn build_guarded_region(
 }
 
 /// Helper for creating the guest memory.
+///
+/// The anonymous regions are backed by huge pages of size `huge_pages`, if set.
 pub fn create_guest_memory(
     regions: &[(Option<FileOffset>, GuestAddress, usize)],
     track_dirty_pages: bool,
+    huge_pages: Option<HugePageSize>,
+) -> std::result::Result<GuestMemoryMmap, Error> {
+    build_guest_memory(regions, libc::MAP_PRIVATE, track_dirty_pages, huge_pages)
+}
+
+/// Helper for creating the guest memory backed by a single memfd, mapped `MAP_SHARED`.
+///
+/// Each region is backed by a distinct range of the memfd, so that other processes (e.g.
+/// vhost-user backends) can map the guest memory by receiving the file descriptor. The memfd
+/// is backed by huge pages of size `huge_pages`, if set.
+pub fn create_memfd_guest_memory(
+    regions: &[(GuestAddress, usize)],
+    track_dirty_pages: bool,
+    huge_pages: Option<HugePageSize>,
+) -> std::result::Result<GuestMemoryMmap, Error> {
+    let memfd =
+        create_memfd(huge_pages).map_err(|err| Error::MmapRegion(MmapRegionError::Mmap(err)))?;
+
+    create_file_guest_memory(memfd, regions, track_dirty_pages, huge_pages)
+}
+
+/// Helper for creating the guest memory backed by a single `file`, mapped `MAP_SHARED`.
+///
+/// The file is resized to hold the whole guest memory, and each region is backed by a distinct
+/// range of it. The file has to be on a hugetlbfs mount if `huge_pages` is set.
+pub fn create_file_guest_memory(
+    file: File,
+    regions: &[(GuestAddress, usize)],
+    track_dirty_pages: bool,
+    huge_pages: Option<HugePageSize>,
+) -> std::result::Result<GuestMemoryMmap, Error> {
+    let mem_size = regions.iter().map(|region| region.1 as u64).sum();
+    file.set_len(mem_size)
+        .map_err(|err| Error::MmapRegion(MmapRegionError::Mmap(err)))?;
+
+    let mut offset = 0;
+    let mut file_regions = Vec::with_capacity(regions.len());
+    for region in regions {
+        let file = file
+            .try_clone()
+            .map_err(|err| Error::MmapRegion(MmapRegionError::Mmap(err)))?;
+        file_regions.push((Some(FileOffset::new(file, offset)), region.0, region.1));
+        offset += region.1 as u64;
+    }
+
+    build_guest_memory(
+        &file_regions,
+        libc::MAP_SHARED,
+        track_dirty_pages,
+        huge_pages,
+    )
+}
+
+fn create_memfd(huge_pages: Option<HugePageSize>) -> std::result::Result<File, IoError> {
+    let name = b"guest_mem\0";
+    let flags = libc::MFD_CLOEXEC | huge_pages.map_or(0, HugePageSize::memfd_flags);
+    // Safe because the name is a valid NUL-terminated string and we check the return value.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_memfd_create,
+            name.as_ptr() as *const libc::c_char,
+            flags,
+        )
+    };
+    if fd < 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    // Safe because we have just created the fd and nothing else owns it.
+    Ok(unsafe { File::from_raw_fd(fd as i32) })
+}
+
+// `map_type` is either `MAP_PRIVATE` or `MAP_SHARED`, and applies to the file-backed regions.
+// Only the anonymous regions and the regions backed by a hugetlbfs file can use `huge_pages`.
+fn build_guest_memory(
+    regions: &[(Option<FileOffset>, GuestAddress, usize)],
+    map_type: i32,
+    track_dirty_pages: bool,
+    huge_pages: Option<HugePageSize>,
 ) -> std::result::Result<GuestMemoryMmap, Error> {
     let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let huge_page_flags = huge_pages.map_or(0, HugePageSize::mmap_flags);
     let mut mmap_regions = Vec::with_capacity(regions.len());
 
     for region in regions {
         let flags = match region.0 {
             None => libc::MAP_NORESERVE | libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-            Some(_) => libc::MAP_NORESERVE | libc::MAP_PRIVATE,
-        };
+            Some(_) => libc::MAP_NORESERVE | map_type,
+        } | huge_page_flags;
 
         let mmap_region =
             build_guarded_region(region.0.clone(), region.2, prot, flags, track_dirty_pages)
@@ -183,6 +327,7 @@ pub mod test_utils {
         create_guest_memory(
             &regions.iter().map(|r| (None, r.0, r.1)).collect::<Vec<_>>(),
             track_dirty_pages,
+            None,
         )
     }
 }
@@ -351,7 +496,7 @@ mod tests {
                 (None, GuestAddress(0x30000), region_size),
             ];
 
-            let guest_memory = create_guest_memory(&regions, false).unwrap();
+            let guest_memory = create_guest_memory(&regions, false, None).unwrap();
             guest_memory.iter().for_each(|region| {
                 validate_guard_region(region);
                 loop_guard_region_to_sigsegv(region);
@@ -368,7 +513,7 @@ mod tests {
                 (None, GuestAddress(0x30000), region_size),
             ];
 
-            let guest_memory = create_guest_memory(&regions, false).unwrap();
+            let guest_memory = create_guest_memory(&regions, false, None).unwrap();
             guest_memory.iter().for_each(|region| {
                 assert!(region.bitmap().is_none());
             });
@@ -384,13 +529,116 @@ mod tests {
                 (None, GuestAddress(0x30000), region_size),
             ];
 
-            let guest_memory = create_guest_memory(&regions, true).unwrap();
+            let guest_memory = create_guest_memory(&regions, true, None).unwrap();
             guest_memory.iter().for_each(|region| {
                 assert!(region.bitmap().is_some());
             });
         }
     }
 
+    #[test]
+    fn test_create_memfd_guest_memory() {
+        let region_size = 0x10000;
+        let regions = vec![
+            (GuestAddress(0x0), region_size),
+            (GuestAddress(0x20000), region_size),
+        ];
+
+        let guest_memory = create_memfd_guest_memory(&regions, false, None).unwrap();
+        let memfd = guest_memory
+            .iter()
+            .next()
+            .unwrap()
+            .file_offset()
+            .unwrap()
+            .file();
+        assert_eq!(memfd.metadata().unwrap().len(), 2 * region_size as u64);
+
+        for (index, region) in guest_memory.iter().enumerate() {
+            let file_offset = region.file_offset().unwrap();
+            assert_eq!(file_offset.start(), (index * region_size) as u64);
+            assert_eq!(region.flags(), libc::MAP_NORESERVE | libc::MAP_SHARED);
+            validate_guard_region(region);
+        }
+
+        // Writes to the guest memory are visible through the memfd.
+        guest_memory
+            .write_obj(0xdead_beef_u32, GuestAddress(0x20000))
+            .unwrap();
+        let mut buf = [0u8; 4];
+        let read = unsafe {
+            libc::pread(
+                memfd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                region_size as libc::off_t,
+            )
+        };
+        assert_eq!(read, 4);
+        assert_eq!(u32::from_ne_bytes(buf), 0xdead_beef);
+    }
+
+    #[test]
+    fn test_create_file_guest_memory() {
+        let region_size = 0x10000;
+        let regions = vec![
+            (GuestAddress(0x0), region_size),
+            (GuestAddress(0x20000), region_size),
+        ];
+
+        let file = TempFile::new().unwrap();
+        let guest_memory =
+            create_file_guest_memory(file.as_file().try_clone().unwrap(), &regions, false, None)
+                .unwrap();
+        // The file is resized to hold the whole guest memory.
+        assert_eq!(
+            file.as_file().metadata().unwrap().len(),
+            2 * region_size as u64
+        );
+
+        for (index, region) in guest_memory.iter().enumerate() {
+            assert_eq!(
+                region.file_offset().unwrap().start(),
+                (index * region_size) as u64
+            );
+            assert_eq!(region.flags(), libc::MAP_NORESERVE | libc::MAP_SHARED);
+        }
+
+        // Writes to the guest memory land in the file.
+        guest_memory
+            .write_obj(0xdead_beef_u32, GuestAddress(0x20000 + 0x10))
+            .unwrap();
+        let contents = std::fs::read(file.as_path()).unwrap();
+        assert_eq!(
+            &contents[region_size + 0x10..region_size + 0x14],
+            &0xdead_beef_u32.to_ne_bytes()
+        );
+    }
+
+    #[test]
+    fn test_huge_page_size() {
+        for huge_pages in [HugePageSize::Size2M, HugePageSize::Size1G].iter() {
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | huge_pages.mmap_flags();
+            assert_eq!(HugePageSize::from_mmap_flags(flags), Some(*huge_pages));
+        }
+        assert_eq!(HugePageSize::Size2M.bytes(), 0x20_0000);
+        assert_eq!(HugePageSize::Size1G.bytes(), 0x4000_0000);
+        assert_eq!(
+            HugePageSize::from_mmap_flags(libc::MAP_PRIVATE | libc::MAP_ANONYMOUS),
+            None
+        );
+
+        // The regions must be made of whole huge pages.
+        let regions = vec![(None, GuestAddress(0), 0x10000)];
+        assert!(create_guest_memory(&regions, false, Some(HugePageSize::Size2M)).is_err());
+        assert!(create_memfd_guest_memory(
+            &[(GuestAddress(0), 0x10000)],
+            false,
+            Some(HugePageSize::Size2M)
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_mark_dirty_mem() {
         let page_size = utils::get_page_size().unwrap();
@@ -401,7 +649,7 @@ mod tests {
             (None, GuestAddress(region_size as u64), region_size), // pages 3-5
             (None, GuestAddress(region_size as u64 * 2), region_size), // pages 6-8
         ];
-        let guest_memory = create_guest_memory(&regions, true).unwrap();
+        let guest_memory = create_guest_memory(&regions, true, None).unwrap();
 
         let dirty_map = [
             // page 0: not dirty
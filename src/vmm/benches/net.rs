@@ -0,0 +1,100 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Benchmark testing
+//
+// Compare moving a network frame between guest memory and a file descriptor through an
+// intermediate buffer, the way the network device used to, with scatter-gather IO straight
+// from guest memory:
+//  - 64 KiB frame
+//  - split in 16 guest buffers of 4 KiB, which are not contiguous
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use devices::virtio::IoVecBuffer;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+const FRAME_SIZE: usize = 0x10000;
+const SEGMENT_SIZE: usize = 0x1000;
+const NUM_SEGMENTS: usize = FRAME_SIZE / SEGMENT_SIZE;
+
+// The guest buffers are interleaved with unused pages.
+fn segment_addr(index: usize) -> GuestAddress {
+    GuestAddress((2 * index * SEGMENT_SIZE) as u64)
+}
+
+fn build_iovec(mem: &GuestMemoryMmap, iovec: &mut IoVecBuffer) {
+    iovec.clear();
+    for index in 0..NUM_SEGMENTS {
+        iovec.push(mem, segment_addr(index), SEGMENT_SIZE).unwrap();
+    }
+}
+
+#[inline]
+pub fn bench_tx_copy(mem: &GuestMemoryMmap, frame_buf: &mut [u8], mut sink: &File) {
+    for (index, chunk) in frame_buf.chunks_mut(SEGMENT_SIZE).enumerate() {
+        mem.read_slice(chunk, segment_addr(index)).unwrap();
+    }
+    sink.write_all(frame_buf).unwrap();
+}
+
+#[inline]
+pub fn bench_tx_writev(mem: &GuestMemoryMmap, iovec: &mut IoVecBuffer, sink: &File) {
+    build_iovec(mem, iovec);
+    iovec.writev(sink).unwrap();
+}
+
+#[inline]
+pub fn bench_rx_copy(mem: &GuestMemoryMmap, frame_buf: &mut [u8], mut source: &File) {
+    source.read_exact(frame_buf).unwrap();
+    for (index, chunk) in frame_buf.chunks(SEGMENT_SIZE).enumerate() {
+        mem.write_slice(chunk, segment_addr(index)).unwrap();
+    }
+}
+
+#[inline]
+pub fn bench_rx_readv(mem: &GuestMemoryMmap, iovec: &mut IoVecBuffer, source: &File) {
+    build_iovec(mem, iovec);
+    iovec.readv(source).unwrap();
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let mem = vm_memory::test_utils::create_anon_guest_memory(
+        &[(GuestAddress(0), 2 * FRAME_SIZE)],
+        false,
+    )
+    .unwrap();
+    let mut frame_buf = vec![0u8; FRAME_SIZE];
+    let mut iovec = IoVecBuffer::with_capacity(NUM_SEGMENTS);
+    let sink = OpenOptions::new().write(true).open("/dev/null").unwrap();
+    let source = File::open("/dev/zero").unwrap();
+
+    c.bench_function("Net TX copy", |b| {
+        b.iter(|| bench_tx_copy(black_box(&mem), black_box(&mut frame_buf), &sink))
+    });
+
+    c.bench_function("Net TX writev", |b| {
+        b.iter(|| bench_tx_writev(black_box(&mem), black_box(&mut iovec), &sink))
+    });
+
+    c.bench_function("Net RX copy", |b| {
+        b.iter(|| bench_rx_copy(black_box(&mem), black_box(&mut frame_buf), &source))
+    });
+
+    c.bench_function("Net RX readv", |b| {
+        b.iter(|| bench_rx_readv(black_box(&mem), black_box(&mut iovec), &source))
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(200).output_directory(Path::new("../../build/vmm_benchmark"));
+    targets = criterion_benchmark
+}
+
+criterion_main! {
+    benches
+}
@@ -3,10 +3,13 @@
 
 //! Enables pre-boot setup, instantiation and booting of a Firecracker VMM.
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
+use std::fs::OpenOptions;
 use std::io::{self, Read, Seek, SeekFrom};
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 use arch::InitrdConfig;
@@ -16,7 +19,9 @@ use devices::legacy::serial::ReadableFd;
 #[cfg(target_arch = "aarch64")]
 use devices::legacy::RTCDevice;
 use devices::legacy::{EventFdTrigger, SerialDevice, SerialEventsWrapper, SerialWrapper};
-use devices::virtio::{Balloon, Block, MmioTransport, Net, VirtioDevice, Vsock, VsockUnixBackend};
+use devices::virtio::{
+    Balloon, Block, MmioTransport, Net, VhostUserBlock, VirtioDevice, VirtioMem,
+};
 use event_manager::{MutEventSubscriber, SubscriberOps};
 use libc::EFD_NONBLOCK;
 use linux_loader::cmdline::Cmdline as LoaderKernelCmdline;
@@ -43,15 +48,18 @@ use crate::construct_kvm_mpidrs;
 use crate::device_manager::legacy::PortIODeviceManager;
 use crate::device_manager::mmio::MMIODeviceManager;
 use crate::device_manager::persist::MMIODevManagerConstructorArgs;
-use crate::persist::{MicrovmState, MicrovmStateError};
+use crate::persist::{MicrovmState, MicrovmStateError, ShareGuestMemoryError};
 use crate::resources::VmResources;
 use crate::vmm_config::boot_source::BootConfig;
 use crate::vmm_config::instance_info::InstanceInfo;
-use crate::vmm_config::machine_config::{VmConfigError, VmUpdateConfig};
+use crate::vmm_config::machine_config::{
+    HugePageConfig, SharedMemoryConfig, VmConfigError, VmUpdateConfig,
+};
+use crate::vmm_config::vsock::VsockDevice;
 use crate::vstate::system::KvmContext;
 use crate::vstate::vcpu::{Vcpu, VcpuConfig};
 use crate::vstate::vm::Vm;
-use crate::{device_manager, Error, EventManager, Vmm, VmmEventsObserver};
+use crate::{device_manager, Error, EventManager, HotplugSubscriber, Vmm, VmmEventsObserver};
 
 /// Errors associated with starting the instance.
 #[derive(Debug)]
@@ -64,8 +72,14 @@ pub enum StartMicrovmError {
     CreateNetDevice(devices::virtio::net::Error),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(io::Error),
+    /// Failed to create the virtio-mem device.
+    CreateVirtioMem(devices::virtio::mem::Error),
     /// Memory regions are overlapping or mmap fails.
     GuestMemoryMmap(vm_memory::Error),
+    /// Cannot open the file backing the guest memory.
+    GuestMemoryFile(io::Error),
+    /// The region of hot-pluggable memory doesn't fit in the guest physical address space.
+    InvalidMemoryHotplugRegion,
     /// Cannot load initrd due to an invalid memory configuration.
     InitrdLoad,
     /// Cannot load initrd due to an invalid image.
@@ -94,6 +108,8 @@ pub enum StartMicrovmError {
     RestoreMicrovmState(MicrovmStateError),
     /// Unable to set VmResources.
     SetVmResources(VmConfigError),
+    /// Cannot share the guest memory with another process.
+    ShareGuestMemory(ShareGuestMemoryError),
 }
 impl std::error::Error for StartMicrovmError {}
 /// It's convenient to automatically convert `linux_loader::cmdline::Error`s
@@ -113,6 +129,7 @@ impl Display for StartMicrovmError {
             }
             ConfigureSystem(err) => write!(f, "System configuration error: {:?}", err),
             CreateRateLimiter(err) => write!(f, "Cannot create RateLimiter: {}", err),
+            CreateVirtioMem(err) => write!(f, "Cannot create the virtio-mem device: {:?}", err),
             CreateNetDevice(err) => {
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace('\"', "");
@@ -125,6 +142,13 @@ impl Display for StartMicrovmError {
                 err_msg = err_msg.replace('\"', "");
                 write!(f, "Invalid Memory Configuration: {}", err_msg)
             }
+            GuestMemoryFile(err) => {
+                write!(f, "Cannot open the file backing the guest memory: {}", err)
+            }
+            InvalidMemoryHotplugRegion => write!(
+                f,
+                "The hot-pluggable memory doesn't fit in the guest physical address space."
+            ),
             InitrdLoad => write!(
                 f,
                 "Cannot load initrd due to an invalid memory configuration."
@@ -177,6 +201,7 @@ impl Display for StartMicrovmError {
             }
             RestoreMicrovmState(err) => write!(f, "Cannot restore microvm state. Error: {}", err),
             SetVmResources(err) => write!(f, "Cannot set vm resources. Error: {}", err),
+            ShareGuestMemory(err) => write!(f, "Cannot share the guest memory: {}", err),
         }
     }
 }
@@ -303,6 +328,8 @@ fn create_vmm_and_vcpus(
         mmio_device_manager,
         #[cfg(target_arch = "x86_64")]
         pio_device_manager,
+        hotplugged_blocks: HashMap::new(),
+        unplugged_subscribers: Vec::new(),
     };
 
     Ok((vmm, vcpus))
@@ -331,11 +358,49 @@ pub fn build_microvm_for_boot(
         .ok_or(MissingKernelConfig)?;
 
     let track_dirty_pages = vm_resources.track_dirty_pages();
-    let guest_memory =
-        create_guest_memory(vm_resources.vm_config().mem_size_mib, track_dirty_pages)?;
+    let shared_memory = vm_resources.vm_config().shared_memory.as_ref();
+    let hotplug_region = vm_resources
+        .memory_hotplug
+        .as_ref()
+        .map(|config| {
+            arch::memory_hotplug_region(
+                vm_resources.vm_config().mem_size_mib << 20,
+                config.total_size_mib << 20,
+            )
+            .ok_or(InvalidMemoryHotplugRegion)
+        })
+        .transpose()?;
+    // Vhost-user backends need to map the guest memory in their own address space.
+    let guest_memory = create_guest_memory(
+        vm_resources.vm_config().mem_size_mib,
+        track_dirty_pages,
+        vm_resources.block.has_vhost_user_devices() || shared_memory.is_some(),
+        shared_memory.and_then(|config| config.mem_file_path.as_deref()),
+        vm_resources.vm_config().huge_pages,
+        hotplug_region,
+    )?;
+    // The guest boots without the hot-pluggable memory, which is plugged later on
+    // through the virtio-mem device.
+    let boot_memory = match hotplug_region {
+        Some((addr, size)) => {
+            guest_memory
+                .remove_region(addr, size as u64)
+                .map_err(|_| InvalidMemoryHotplugRegion)?
+                .0
+        }
+        None => guest_memory.clone(),
+    };
+    if let Some(uds_path) = shared_memory.and_then(|config| config.uds_path.as_ref()) {
+        crate::persist::share_guest_memory(
+            uds_path,
+            &guest_memory,
+            vm_resources.vm_config().huge_pages,
+        )
+        .map_err(ShareGuestMemory)?;
+    }
     let vcpu_config = vm_resources.vcpu_config();
-    let entry_addr = load_kernel(boot_config, &guest_memory)?;
-    let initrd = load_initrd_from_config(boot_config, &guest_memory)?;
+    let entry_addr = load_kernel(boot_config, &boot_memory)?;
+    let initrd = load_initrd_from_config(boot_config, &boot_memory)?;
     // Clone the command-line so that a failed boot doesn't pollute the original.
     #[allow(unused_mut)]
     let mut boot_cmdline = linux_loader::cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE);
@@ -380,21 +445,74 @@ pub fn build_microvm_for_boot(
         attach_balloon_device(&mut vmm, &mut boot_cmdline, balloon, event_manager)?;
     }
 
-    attach_block_devices(
-        &mut vmm,
-        &mut boot_cmdline,
-        vm_resources.block.list.iter(),
-        event_manager,
-    )?;
+    if let (Some(config), Some((addr, size))) =
+        (vm_resources.memory_hotplug.as_ref(), hotplug_region)
+    {
+        let virtio_mem = VirtioMem::new(
+            addr,
+            size as u64,
+            (config.block_size_mib as u64) << 20,
+            // `restored` flag is false because this code path
+            // is never called by snapshot restore functionality.
+            false,
+        )
+        .map_err(CreateVirtioMem)?;
+        attach_virtio_mem_device(
+            &mut vmm,
+            &mut boot_cmdline,
+            &Arc::new(Mutex::new(virtio_mem)),
+            event_manager,
+        )?;
+        // The guest can only access the hot-pluggable memory blocks it plugged.
+        vmm.setup_hotplug_memory_slots(track_dirty_pages)
+            .map_err(Internal)?;
+    }
+
+    // The root block device has to be attached first in order to be exposed as /dev/vda.
+    if vm_resources.block.has_vhost_user_root_device() {
+        attach_vhost_user_block_devices(
+            &mut vmm,
+            &mut boot_cmdline,
+            vm_resources.block.vhost_user_list.iter(),
+            event_manager,
+        )?;
+        attach_block_devices(
+            &mut vmm,
+            &mut boot_cmdline,
+            vm_resources.block.list.iter(),
+            event_manager,
+        )?;
+    } else {
+        attach_block_devices(
+            &mut vmm,
+            &mut boot_cmdline,
+            vm_resources.block.list.iter(),
+            event_manager,
+        )?;
+        attach_vhost_user_block_devices(
+            &mut vmm,
+            &mut boot_cmdline,
+            vm_resources.block.vhost_user_list.iter(),
+            event_manager,
+        )?;
+    }
     attach_net_devices(
         &mut vmm,
         &mut boot_cmdline,
         vm_resources.net_builder.iter(),
         event_manager,
     )?;
-    if let Some(unix_vsock) = vm_resources.vsock.get() {
-        attach_unixsock_vsock_device(&mut vmm, &mut boot_cmdline, unix_vsock, event_manager)?;
+    if let Some(vsock) = vm_resources.vsock.get() {
+        attach_vsock_device(&mut vmm, &mut boot_cmdline, vsock, event_manager)?;
     }
+    vmm.mmio_device_manager
+        .reserve_hotplug_slots(
+            vmm.vm.fd(),
+            &vmm.guest_memory,
+            vm_resources.vm_config().block_hotplug_slots,
+            &mut boot_cmdline,
+        )
+        .map_err(RegisterMmioDevice)?;
 
     if let Some(init) = init_params {
         boot_cmdline.insert_str(format!("--{}", init))?;
@@ -405,6 +523,7 @@ pub fn build_microvm_for_boot(
 
     configure_system_for_boot(
         &vmm,
+        &boot_memory,
         vcpus.as_mut(),
         vcpu_config,
         entry_addr,
@@ -486,6 +605,9 @@ pub enum BuildMicrovmFromSnapshotError {
     /// Failed to emulate MMIO serial.
     #[error("Failed to emulate MMIO serial: {0}")]
     EmulateSerialInit(#[from] crate::EmulateSerialInitError),
+    /// Failed to set up the memory slots of the hot-pluggable memory.
+    #[error("Failed to set up the memory slots of the hot-pluggable memory: {0}")]
+    HotplugMemorySlots(crate::Error),
     /// Failed start vCPUs as no vCPU seccomp filter found.
     #[error("Failed start vCPUs as no vCPU seccomp filter found.")]
     MissingVcpuSeccompFilters,
@@ -505,6 +627,9 @@ pub enum BuildMicrovmFromSnapshotError {
 
 /// Builds and starts a microVM based on the provided MicrovmState.
 ///
+/// `shared_memory` describes how the restored `guest_memory` is shared with other processes,
+/// if it is.
+///
 /// An `Arc` reference of the built `Vmm` is also plugged in the `EventManager`, while another
 /// is returned.
 #[allow(clippy::too_many_arguments)]
@@ -515,6 +640,7 @@ pub fn build_microvm_from_snapshot(
     guest_memory: GuestMemoryMmap,
     uffd: Option<Uffd>,
     track_dirty_pages: bool,
+    shared_memory: Option<SharedMemoryConfig>,
     seccomp_filters: &BpfThreadMap,
     vm_resources: &mut VmResources,
 ) -> std::result::Result<Arc<Mutex<Vmm>>, BuildMicrovmFromSnapshotError> {
@@ -569,12 +695,22 @@ pub fn build_microvm_from_snapshot(
         smt: Some(microvm_state.vm_info.smt),
         cpu_template: Some(microvm_state.vm_info.cpu_template),
         track_dirty_pages: Some(track_dirty_pages),
+        block_hotplug_slots: Some(
+            microvm_state
+                .device_states
+                .hotplug
+                .as_ref()
+                .map_or(0, |hotplug| hotplug.slots.len() as u8),
+        ),
+        huge_pages: Some(microvm_state.vm_info.huge_pages),
+        shared_memory: Some(shared_memory),
     })?;
 
     // Restore the boot source config paths.
     vm_resources.set_boot_source_config(microvm_state.vm_info.boot_source);
 
     // Restore devices states.
+    let mut hotplug_subscribers = HashMap::new();
     let mmio_ctor_args = MMIODevManagerConstructorArgs {
         mem: guest_memory,
         vm: vmm.vm.fd(),
@@ -582,11 +718,20 @@ pub fn build_microvm_from_snapshot(
         for_each_restored_device: VmResources::update_from_restored_device,
         vm_resources,
         instance_id: &instance_info.id,
+        hotplug_subscribers: &mut hotplug_subscribers,
     };
 
     vmm.mmio_device_manager =
         MMIODeviceManager::restore(mmio_ctor_args, &microvm_state.device_states)
             .map_err(MicrovmStateError::RestoreDevices)?;
+    // The restored hot-plugged devices are already registered with the event manager.
+    vmm.hotplugged_blocks = hotplug_subscribers
+        .into_iter()
+        .map(|(drive_id, id)| (drive_id, HotplugSubscriber::Registered(id)))
+        .collect();
+    // The guest can only access the hot-pluggable memory blocks it plugged.
+    vmm.setup_hotplug_memory_slots(track_dirty_pages)
+        .map_err(BuildMicrovmFromSnapshotError::HotplugMemorySlots)?;
     vmm.emulate_serial_init()?;
 
     // Move vcpus to their own threads and start their state machine in the 'Paused' state.
@@ -615,13 +760,48 @@ pub fn build_microvm_from_snapshot(
     Ok(vmm)
 }
 
-/// Creates GuestMemory of `mem_size_mib` MiB in size.
+/// Creates GuestMemory of `mem_size_mib` MiB in size, backed by the huge pages `huge_pages`.
+/// If `shared` is set, the memory is backed by a memfd mapped as shared, so that it can be
+/// handed over to other processes. The file at `mem_file_path` backs the shared memory instead
+/// of a memfd, if set. The region of hot-pluggable memory `hotplug_region` is mapped after the
+/// memory of the microVM, if set.
 pub fn create_guest_memory(
     mem_size_mib: usize,
     track_dirty_pages: bool,
+    shared: bool,
+    mem_file_path: Option<&Path>,
+    huge_pages: HugePageConfig,
+    hotplug_region: Option<(GuestAddress, usize)>,
 ) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
     let mem_size = mem_size_mib << 20;
-    let arch_mem_regions = arch::arch_memory_regions(mem_size);
+    let mut arch_mem_regions = arch::arch_memory_regions(mem_size);
+    arch_mem_regions.extend(hotplug_region);
+
+    if shared {
+        return match mem_file_path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)
+                    .map_err(StartMicrovmError::GuestMemoryFile)?;
+                vm_memory::create_file_guest_memory(
+                    file,
+                    &arch_mem_regions,
+                    track_dirty_pages,
+                    huge_pages.page_size(),
+                )
+            }
+            None => vm_memory::create_memfd_guest_memory(
+                &arch_mem_regions,
+                track_dirty_pages,
+                huge_pages.page_size(),
+            ),
+        }
+        .map_err(StartMicrovmError::GuestMemoryMmap);
+    }
 
     vm_memory::create_guest_memory(
         &arch_mem_regions
@@ -629,6 +809,7 @@ pub fn create_guest_memory(
             .map(|(addr, size)| (None, *addr, *size))
             .collect::<Vec<_>>()[..],
         track_dirty_pages,
+        huge_pages.page_size(),
     )
     .map_err(StartMicrovmError::GuestMemoryMmap)
 }
@@ -848,6 +1029,7 @@ fn create_vcpus(vm: &Vm, vcpu_count: u8, exit_evt: &EventFd) -> super::Result<Ve
 #[cfg_attr(target_arch = "aarch64", allow(unused))]
 pub fn configure_system_for_boot(
     vmm: &Vmm,
+    boot_memory: &GuestMemoryMmap,
     vcpus: &mut [Vcpu],
     vcpu_config: VcpuConfig,
     entry_addr: GuestAddress,
@@ -860,7 +1042,7 @@ pub fn configure_system_for_boot(
         for vcpu in vcpus.iter_mut() {
             vcpu.kvm_vcpu
                 .configure(
-                    vmm.guest_memory(),
+                    boot_memory,
                     entry_addr,
                     &vcpu_config,
                     vmm.vm.supported_cpuid().clone(),
@@ -872,13 +1054,13 @@ pub fn configure_system_for_boot(
         // Write the kernel command line to guest memory. This is x86_64 specific, since on
         // aarch64 the command line will be specified through the FDT.
         linux_loader::loader::load_cmdline::<vm_memory::GuestMemoryMmap>(
-            vmm.guest_memory(),
+            boot_memory,
             GuestAddress(arch::x86_64::layout::CMDLINE_START),
             &boot_cmdline,
         )
         .map_err(LoadCommandline)?;
         arch::x86_64::configure_system(
-            &vmm.guest_memory,
+            boot_memory,
             vm_memory::GuestAddress(arch::x86_64::layout::CMDLINE_START),
             boot_cmdline.as_str().len() + 1,
             initrd,
@@ -890,7 +1072,7 @@ pub fn configure_system_for_boot(
     {
         for vcpu in vcpus.iter_mut() {
             vcpu.kvm_vcpu
-                .configure(vmm.guest_memory(), entry_addr)
+                .configure(boot_memory, entry_addr)
                 .map_err(Error::VcpuConfigure)
                 .map_err(Internal)?;
         }
@@ -900,10 +1082,10 @@ pub fn configure_system_for_boot(
             .map(|cpu| cpu.kvm_vcpu.get_mpidr())
             .collect();
         arch::aarch64::configure_system(
-            &vmm.guest_memory,
+            boot_memory,
             boot_cmdline.as_str(),
             vcpu_mpidr,
-            vmm.mmio_device_manager.get_device_info(),
+            &vmm.mmio_device_manager.get_fdt_device_info(),
             vmm.vm.get_irqchip(),
             initrd,
         )
@@ -947,6 +1129,23 @@ pub(crate) fn attach_boot_timer_device(
     Ok(())
 }
 
+fn insert_root_device_cmdline(
+    cmdline: &mut LoaderKernelCmdline,
+    partuuid: Option<&String>,
+    is_read_only: bool,
+) -> std::result::Result<(), StartMicrovmError> {
+    cmdline.insert_str(if let Some(partuuid) = partuuid {
+        format!("root=PARTUUID={}", partuuid)
+    } else {
+        // If no PARTUUID was specified for the root device, try with the /dev/vda.
+        "root=/dev/vda".to_string()
+    })?;
+
+    let flags = if is_read_only { "ro" } else { "rw" };
+    cmdline.insert_str(flags)?;
+    Ok(())
+}
+
 fn attach_block_devices<'a>(
     vmm: &mut Vmm,
     cmdline: &mut LoaderKernelCmdline,
@@ -957,15 +1156,27 @@ fn attach_block_devices<'a>(
         let id = {
             let locked = block.lock().expect("Poisoned lock");
             if locked.is_root_device() {
-                cmdline.insert_str(if let Some(partuuid) = locked.partuuid() {
-                    format!("root=PARTUUID={}", partuuid)
-                } else {
-                    // If no PARTUUID was specified for the root device, try with the /dev/vda.
-                    "root=/dev/vda".to_string()
-                })?;
-
-                let flags = if locked.is_read_only() { "ro" } else { "rw" };
-                cmdline.insert_str(flags)?;
+                insert_root_device_cmdline(cmdline, locked.partuuid(), locked.is_read_only())?;
+            }
+            locked.id().clone()
+        };
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        attach_virtio_device(event_manager, vmm, id, block.clone(), cmdline)?;
+    }
+    Ok(())
+}
+
+fn attach_vhost_user_block_devices<'a>(
+    vmm: &mut Vmm,
+    cmdline: &mut LoaderKernelCmdline,
+    blocks: impl Iterator<Item = &'a Arc<Mutex<VhostUserBlock>>>,
+    event_manager: &mut EventManager,
+) -> std::result::Result<(), StartMicrovmError> {
+    for block in blocks {
+        let id = {
+            let locked = block.lock().expect("Poisoned lock");
+            if locked.is_root_device() {
+                insert_root_device_cmdline(cmdline, locked.partuuid(), locked.is_read_only())?;
             }
             locked.id().clone()
         };
@@ -989,15 +1200,23 @@ fn attach_net_devices<'a>(
     Ok(())
 }
 
-fn attach_unixsock_vsock_device(
+fn attach_vsock_device(
     vmm: &mut Vmm,
     cmdline: &mut LoaderKernelCmdline,
-    unix_vsock: &Arc<Mutex<Vsock<VsockUnixBackend>>>,
+    vsock: &VsockDevice,
     event_manager: &mut EventManager,
 ) -> std::result::Result<(), StartMicrovmError> {
-    let id = String::from(unix_vsock.lock().expect("Poisoned lock").id());
     // The device mutex mustn't be locked here otherwise it will deadlock.
-    attach_virtio_device(event_manager, vmm, id, unix_vsock.clone(), cmdline)
+    match vsock {
+        VsockDevice::Unix(unix_vsock) => {
+            let id = String::from(unix_vsock.lock().expect("Poisoned lock").id());
+            attach_virtio_device(event_manager, vmm, id, unix_vsock.clone(), cmdline)
+        }
+        VsockDevice::Tcp(tcp_vsock) => {
+            let id = String::from(tcp_vsock.lock().expect("Poisoned lock").id());
+            attach_virtio_device(event_manager, vmm, id, tcp_vsock.clone(), cmdline)
+        }
+    }
 }
 
 fn attach_balloon_device(
@@ -1011,6 +1230,17 @@ fn attach_balloon_device(
     attach_virtio_device(event_manager, vmm, id, balloon.clone(), cmdline)
 }
 
+fn attach_virtio_mem_device(
+    vmm: &mut Vmm,
+    cmdline: &mut LoaderKernelCmdline,
+    virtio_mem: &Arc<Mutex<VirtioMem>>,
+    event_manager: &mut EventManager,
+) -> std::result::Result<(), StartMicrovmError> {
+    let id = String::from(virtio_mem.lock().expect("Poisoned lock").id());
+    // The device mutex mustn't be locked here otherwise it will deadlock.
+    attach_virtio_device(event_manager, vmm, id, virtio_mem.clone(), cmdline)
+}
+
 // Adds `O_NONBLOCK` to the stdout flags.
 pub(crate) fn set_stdout_nonblocking() {
     let flags = unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_GETFL, 0) };
@@ -1029,7 +1259,7 @@ pub mod tests {
 
     use arch::DeviceType;
     use devices::virtio::vsock::VSOCK_DEV_ID;
-    use devices::virtio::{TYPE_BALLOON, TYPE_BLOCK, TYPE_VSOCK};
+    use devices::virtio::{MEM_DEV_ID, TYPE_BALLOON, TYPE_BLOCK, TYPE_MEM, TYPE_VSOCK};
     use linux_loader::cmdline::Cmdline;
     use mmds::data_store::{Mmds, MmdsVersion};
     use mmds::ns::MmdsNetworkStack;
@@ -1039,9 +1269,11 @@ pub mod tests {
     use super::*;
     use crate::vmm_config::balloon::{BalloonBuilder, BalloonDeviceConfig, BALLOON_DEV_ID};
     use crate::vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
-    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, CacheType, FileEngineType};
-    use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
-    use crate::vmm_config::vsock::tests::default_config;
+    use crate::vmm_config::drive::{
+        BlockBuilder, BlockDeviceConfig, CacheType, DiskFormat, FileEngineType,
+    };
+    use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig, Offloads};
+    use crate::vmm_config::vsock::tests::{default_config, tcp_config};
     use crate::vmm_config::vsock::{VsockBuilder, VsockDeviceConfig};
 
     pub(crate) struct CustomBlockConfig {
@@ -1105,7 +1337,8 @@ pub mod tests {
     }
 
     pub(crate) fn default_vmm() -> Vmm {
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory =
+            create_guest_memory(128, false, false, None, HugePageConfig::None, None).unwrap();
 
         let vcpus_exit_evt = EventFd::new(libc::EFD_NONBLOCK)
             .map_err(Error::EventFd)
@@ -1139,6 +1372,8 @@ pub mod tests {
             mmio_device_manager,
             #[cfg(target_arch = "x86_64")]
             pio_device_manager,
+            hotplugged_blocks: HashMap::new(),
+            unplugged_subscribers: Vec::new(),
         }
     }
 
@@ -1154,19 +1389,25 @@ pub mod tests {
             block_files.push(TempFile::new().unwrap());
             let block_device_config = BlockDeviceConfig {
                 drive_id: String::from(&custom_block_cfg.drive_id),
-                path_on_host: block_files
-                    .last()
-                    .unwrap()
-                    .as_path()
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
+                path_on_host: Some(
+                    block_files
+                        .last()
+                        .unwrap()
+                        .as_path()
+                        .to_str()
+                        .unwrap()
+                        .to_string(),
+                ),
+                socket: None,
                 is_root_device: custom_block_cfg.is_root_device,
                 partuuid: custom_block_cfg.partuuid.clone(),
                 is_read_only: custom_block_cfg.is_read_only,
                 cache_type: custom_block_cfg.cache_type,
                 rate_limiter: None,
                 file_engine_type: FileEngineType::default(),
+                disk_format: DiskFormat::default(),
+                num_queues: 1,
+                direct_io: false,
             };
             block_dev_configs.insert(block_device_config).unwrap();
         }
@@ -1215,10 +1456,9 @@ pub mod tests {
         vsock_config: VsockDeviceConfig,
     ) {
         let vsock_dev_id = VSOCK_DEV_ID.to_owned();
-        let vsock = VsockBuilder::create_unixsock_vsock(vsock_config).unwrap();
-        let vsock = Arc::new(Mutex::new(vsock));
+        let vsock = VsockBuilder::create_vsock(vsock_config).unwrap();
 
-        assert!(attach_unixsock_vsock_device(vmm, cmdline, &vsock, event_manager).is_ok());
+        assert!(attach_vsock_device(vmm, cmdline, &vsock, event_manager).is_ok());
 
         assert!(vmm
             .mmio_device_manager
@@ -1244,6 +1484,29 @@ pub mod tests {
             .is_some());
     }
 
+    pub(crate) fn insert_virtio_mem_device(
+        vmm: &mut Vmm,
+        cmdline: &mut Cmdline,
+        event_manager: &mut EventManager,
+        region_size_mib: usize,
+    ) {
+        let (addr, size) = arch::memory_hotplug_region(128 << 20, region_size_mib << 20).unwrap();
+        let virtio_mem = VirtioMem::new(addr, size as u64, 2 << 20, false).unwrap();
+
+        assert!(attach_virtio_mem_device(
+            vmm,
+            cmdline,
+            &Arc::new(Mutex::new(virtio_mem)),
+            event_manager
+        )
+        .is_ok());
+
+        assert!(vmm
+            .mmio_device_manager
+            .get_device(DeviceType::Virtio(TYPE_MEM), MEM_DEV_ID)
+            .is_some());
+    }
+
     fn make_test_bin() -> Vec<u8> {
         let mut fake_bin = Vec::new();
         fake_bin.resize(1_000_000, 0xAA);
@@ -1320,21 +1583,75 @@ pub mod tests {
 
         // Case 1: create guest memory without dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, false).unwrap();
+            let guest_memory =
+                create_guest_memory(mem_size, false, false, None, HugePageConfig::None, None)
+                    .unwrap();
             assert!(!is_dirty_tracking_enabled(&guest_memory));
         }
 
         // Case 2: create guest memory with dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, true).unwrap();
+            let guest_memory =
+                create_guest_memory(mem_size, true, false, None, HugePageConfig::None, None)
+                    .unwrap();
             assert!(is_dirty_tracking_enabled(&guest_memory));
         }
+
+        // Case 3: create shared guest memory
+        {
+            let guest_memory =
+                create_guest_memory(mem_size, false, true, None, HugePageConfig::None, None)
+                    .unwrap();
+            assert!(guest_memory
+                .iter()
+                .all(|region| region.file_offset().is_some()));
+        }
+
+        // Case 4: create shared guest memory backed by a file
+        {
+            let mem_file = TempFile::new().unwrap();
+            let guest_memory = create_guest_memory(
+                mem_size,
+                false,
+                true,
+                Some(mem_file.as_path()),
+                HugePageConfig::None,
+                None,
+            )
+            .unwrap();
+            assert_eq!(
+                mem_file.as_file().metadata().unwrap().len(),
+                guest_memory.iter().map(|region| region.len()).sum::<u64>()
+            );
+        }
+
+        // Case 5: the memory can't be made of whole huge pages
+        assert!(
+            create_guest_memory(1, false, false, None, HugePageConfig::Hugetlbfs2M, None).is_err()
+        );
+
+        // Case 6: create guest memory with a region of hot-pluggable memory
+        {
+            let hotplug_region = arch::memory_hotplug_region(mem_size, 4096).unwrap();
+            let guest_memory = create_guest_memory(
+                mem_size,
+                false,
+                false,
+                None,
+                HugePageConfig::None,
+                Some(hotplug_region),
+            )
+            .unwrap();
+            assert_eq!(guest_memory.num_regions(), 2);
+            assert!(guest_memory.address_in_range(hotplug_region.0));
+        }
     }
 
     #[test]
     fn test_create_vcpus() {
         let vcpu_count = 2;
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory =
+            create_guest_memory(128, false, false, None, HugePageConfig::None, None).unwrap();
 
         #[allow(unused_mut)]
         let mut vm = setup_kvm_vm(&guest_memory, false).unwrap();
@@ -1358,6 +1675,10 @@ pub mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            offloads: Offloads::default(),
+            vhost_net: false,
+            anti_spoofing: None,
         };
 
         let mut cmdline = default_kernel_cmdline();
@@ -1572,6 +1893,9 @@ pub mod tests {
             amount_mib: 0,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            free_page_hinting: false,
+            free_page_reporting: false,
+            auto_size: None,
         };
 
         let mut cmdline = default_kernel_cmdline();
@@ -1583,6 +1907,20 @@ pub mod tests {
             .contains("virtio_mmio.device=4K@0xd0000000:5"));
     }
 
+    #[test]
+    fn test_attach_virtio_mem_device() {
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let mut vmm = default_vmm();
+
+        let mut cmdline = default_kernel_cmdline();
+        insert_virtio_mem_device(&mut vmm, &mut cmdline, &mut event_manager, 1024);
+        // Check if the virtio-mem device is described in kernel_cmdline.
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        assert!(cmdline
+            .as_str()
+            .contains("virtio_mmio.device=4K@0xd0000000:5"));
+    }
+
     #[test]
     fn test_attach_vsock_device() {
         let mut event_manager = EventManager::new().expect("Unable to create EventManager");
@@ -1601,6 +1939,20 @@ pub mod tests {
             .contains("virtio_mmio.device=4K@0xd0000000:5"));
     }
 
+    #[test]
+    fn test_attach_tcp_vsock_device() {
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let mut vmm = default_vmm();
+
+        let mut cmdline = default_kernel_cmdline();
+        insert_vsock_device(&mut vmm, &mut cmdline, &mut event_manager, tcp_config());
+        // Check if the vsock device is described in kernel_cmdline.
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        assert!(cmdline
+            .as_str()
+            .contains("virtio_mmio.device=4K@0xd0000000:5"));
+    }
+
     #[test]
     fn test_error_messages() {
         use crate::builder::StartMicrovmError::*;
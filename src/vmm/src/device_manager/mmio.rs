@@ -6,7 +6,7 @@
 // found in the THIRD-PARTY file.
 
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::{fmt, io};
 
 #[cfg(target_arch = "aarch64")]
@@ -17,20 +17,22 @@ use arch::DeviceType::Virtio;
 use devices::legacy::RTCDevice;
 #[cfg(target_arch = "aarch64")]
 use devices::legacy::SerialDevice;
-use devices::pseudo::BootTimer;
+use devices::pseudo::{BootTimer, HotplugEvents};
+use devices::virtio::vsock::{Vsock, VsockBackend, VsockUnixBackend};
 use devices::virtio::{
-    Balloon, Block, MmioTransport, Net, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET,
-    TYPE_VSOCK,
+    Balloon, Block, EmptySlot, MmioTransport, Net, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK,
+    TYPE_NET, TYPE_VSOCK,
 };
 use devices::BusDevice;
 use kvm_ioctls::{IoEventAddress, VmFd};
 use linux_loader::cmdline as kernel_cmdline;
-use logger::info;
+use logger::{error, info};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_allocator::{AddressAllocator, AllocPolicy, IdAllocator};
 #[cfg(target_arch = "x86_64")]
 use vm_memory::GuestAddress;
+use vm_memory::GuestMemoryMmap;
 
 /// Errors for MMIO device manager.
 #[derive(Debug)]
@@ -39,6 +41,8 @@ pub enum Error {
     Bus(devices::BusError),
     /// Appending to kernel command line failed.
     Cmdline(linux_loader::cmdline::Error),
+    /// The device is in use by the guest.
+    DeviceInUse,
     /// The device couldn't be found.
     DeviceNotFound,
     /// Failure in creating or cloning an event fd.
@@ -49,10 +53,16 @@ pub enum Error {
     InternalDeviceError(String),
     /// Invalid configuration attempted.
     InvalidInput,
+    /// All the MMIO slots reserved for hot-plugging are in use.
+    NoFreeHotplugSlot,
     /// Registering an IO Event failed.
     RegisterIoEvent(kvm_ioctls::Error),
     /// Registering an IRQ FD failed.
     RegisterIrqFd(kvm_ioctls::Error),
+    /// Unregistering an IO Event failed.
+    UnregisterIoEvent(kvm_ioctls::Error),
+    /// Unregistering an IRQ FD failed.
+    UnregisterIrqFd(kvm_ioctls::Error),
     /// Failed to update the mmio device.
     UpdateFailed,
     /// Allocation logic error.
@@ -70,8 +80,15 @@ impl fmt::Display for Error {
             Error::IncorrectDeviceType => write!(f, "incorrect device type"),
             Error::InternalDeviceError(err) => write!(f, "device error: {}", err),
             Error::InvalidInput => write!(f, "invalid configuration"),
+            Error::NoFreeHotplugSlot => write!(f, "no free hot-plug slot available"),
             Error::RegisterIoEvent(e) => write!(f, "failed to register IO event: {}", e),
             Error::RegisterIrqFd(e) => write!(f, "failed to register irqfd: {}", e),
+            Error::UnregisterIoEvent(e) => write!(f, "failed to unregister IO event: {}", e),
+            Error::UnregisterIrqFd(e) => write!(f, "failed to unregister irqfd: {}", e),
+            Error::DeviceInUse => write!(
+                f,
+                "the device is in use by the guest, which was asked to release it"
+            ),
             Error::DeviceNotFound => write!(f, "the device couldn't be found"),
             Error::UpdateFailed => write!(f, "failed to update the mmio device"),
             Error::AllocatorError(e) => write!(f, "failed to allocate requested resource: {}", e),
@@ -99,12 +116,26 @@ pub struct MMIODeviceInfo {
     pub irqs: Vec<u32>,
 }
 
+/// A MMIO slot reserved at boot time for hot-plugging.
+#[derive(Clone, Debug, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct HotplugSlot {
+    /// Mmio address and irq of the slot.
+    pub device_info: MMIODeviceInfo,
+    /// Identifier of the device plugged in the slot, if any.
+    pub device_id: Option<String>,
+}
+
 /// Manages the complexities of registering a MMIO device.
 pub struct MMIODeviceManager {
     pub(crate) bus: devices::Bus,
     pub(crate) irq_allocator: IdAllocator,
     pub(crate) address_allocator: AddressAllocator,
     pub(crate) id_to_dev_info: HashMap<(DeviceType, String), MMIODeviceInfo>,
+    // Slots reserved at boot time for hot-plugging, in the order of their addresses.
+    pub(crate) hotplug_slots: Vec<HotplugSlot>,
+    // Device notifying the guest about the changes of the hot-plug slots.
+    pub(crate) hotplug_events: Option<Arc<Mutex<HotplugEvents>>>,
 }
 
 impl MMIODeviceManager {
@@ -120,6 +151,8 @@ impl MMIODeviceManager {
                 .map_err(Error::AllocatorError)?,
             bus: devices::Bus::new(),
             id_to_dev_info: HashMap::new(),
+            hotplug_slots: Vec::new(),
+            hotplug_events: None,
         })
     }
 
@@ -172,15 +205,7 @@ impl MMIODeviceManager {
         {
             let locked_device = mmio_device.locked_device();
             identifier = (DeviceType::Virtio(locked_device.device_type()), device_id);
-            for (i, queue_evt) in locked_device.queue_events().iter().enumerate() {
-                let io_addr = IoEventAddress::Mmio(
-                    device_info.addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET),
-                );
-                vm.register_ioevent(queue_evt, &io_addr, i as u32)
-                    .map_err(Error::RegisterIoEvent)?;
-            }
-            vm.register_irqfd(locked_device.interrupt_evt(), device_info.irqs[0])
-                .map_err(Error::RegisterIrqFd)?;
+            Self::register_virtio_events(vm, &*locked_device, device_info)?;
         }
 
         self.register_mmio_device(
@@ -190,6 +215,260 @@ impl MMIODeviceManager {
         )
     }
 
+    /// Wire the queue events and the interrupt of a virtio device to the given slot.
+    fn register_virtio_events(
+        vm: &VmFd,
+        device: &dyn VirtioDevice,
+        device_info: &MMIODeviceInfo,
+    ) -> Result<()> {
+        let io_addr =
+            IoEventAddress::Mmio(device_info.addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET));
+        for (i, queue_evt) in device.queue_events().iter().enumerate() {
+            vm.register_ioevent(queue_evt, &io_addr, i as u32)
+                .map_err(Error::RegisterIoEvent)?;
+        }
+        vm.register_irqfd(device.interrupt_evt(), device_info.irqs[0])
+            .map_err(Error::RegisterIrqFd)
+    }
+
+    /// Undo `register_virtio_events()`.
+    fn unregister_virtio_events(
+        vm: &VmFd,
+        device: &dyn VirtioDevice,
+        device_info: &MMIODeviceInfo,
+    ) -> Result<()> {
+        let io_addr =
+            IoEventAddress::Mmio(device_info.addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET));
+        for (i, queue_evt) in device.queue_events().iter().enumerate() {
+            vm.unregister_ioevent(queue_evt, &io_addr, i as u32)
+                .map_err(Error::UnregisterIoEvent)?;
+        }
+        vm.unregister_irqfd(device.interrupt_evt(), device_info.irqs[0])
+            .map_err(Error::UnregisterIrqFd)
+    }
+
+    /// Creates the transport occupying a free hot-plug slot.
+    fn empty_slot_transport(mem: &GuestMemoryMmap) -> Result<MmioTransport> {
+        let slot = EmptySlot::new().map_err(Error::EventFd)?;
+        Ok(MmioTransport::new(mem.clone(), Arc::new(Mutex::new(slot))))
+    }
+
+    /// Replaces the virtio device behind the transport registered at `device_info`, unless
+    /// a guest driver is bound to the current one.
+    fn swap_mmio_transport(
+        &self,
+        device_info: &MMIODeviceInfo,
+        mmio_device: MmioTransport,
+    ) -> Result<MmioTransport> {
+        let (_, bus_device) = self
+            .bus
+            .get_device(device_info.addr)
+            .ok_or(Error::DeviceNotFound)?;
+        let mut locked_bus_device = bus_device.lock().expect("Poisoned lock");
+        let transport = locked_bus_device
+            .as_mut_any()
+            .downcast_mut::<MmioTransport>()
+            .ok_or(Error::IncorrectDeviceType)?;
+        if transport.is_driver_bound() {
+            return Err(Error::DeviceInUse);
+        }
+        Ok(std::mem::replace(transport, mmio_device))
+    }
+
+    /// Puts a free slot transport at `device_info`.
+    pub(crate) fn insert_empty_slot(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        device_info: &MMIODeviceInfo,
+    ) -> Result<()> {
+        self.bus
+            .insert(
+                Arc::new(Mutex::new(Self::empty_slot_transport(mem)?)),
+                device_info.addr,
+                device_info.len,
+            )
+            .map_err(Error::Bus)
+    }
+
+    /// Reserve `count` empty virtio-over-MMIO slots which devices can later be hot-plugged in.
+    /// The slots are announced to the guest at boot time, like any other virtio device, along
+    /// with the device notifying the guest about their changes.
+    pub fn reserve_hotplug_slots(
+        &mut self,
+        vm: &VmFd,
+        mem: &GuestMemoryMmap,
+        count: u8,
+        _cmdline: &mut kernel_cmdline::Cmdline,
+    ) -> Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+
+        for _ in 0..count {
+            let device_info = self.allocate_mmio_resources(1)?;
+            self.insert_empty_slot(mem, &device_info)?;
+            #[cfg(target_arch = "x86_64")]
+            Self::add_virtio_device_to_cmdline(_cmdline, &device_info)?;
+            self.hotplug_slots.push(HotplugSlot {
+                device_info,
+                device_id: None,
+            });
+        }
+
+        let slot_addrs = self
+            .hotplug_slots
+            .iter()
+            .map(|slot| slot.device_info.addr)
+            .collect();
+        let events = HotplugEvents::new(slot_addrs).map_err(Error::EventFd)?;
+        let device_info = self.allocate_mmio_resources(1)?;
+        self.register_hotplug_events(vm, events, device_info.clone())?;
+        // Same format as the virtio-mmio devices: <size>@<baseaddr>:<irq>.
+        #[cfg(target_arch = "x86_64")]
+        _cmdline
+            .insert(
+                "hotplug_events.device",
+                &format!(
+                    "{}K@0x{:08x}:{}",
+                    device_info.len / 1024,
+                    device_info.addr,
+                    device_info.irqs[0]
+                ),
+            )
+            .map_err(Error::Cmdline)?;
+        Ok(())
+    }
+
+    /// Register the device notifying the guest about the changes of the hot-plug slots.
+    pub fn register_hotplug_events(
+        &mut self,
+        vm: &VmFd,
+        events: HotplugEvents,
+        device_info: MMIODeviceInfo,
+    ) -> Result<()> {
+        vm.register_irqfd(events.irq_evt(), device_info.irqs[0])
+            .map_err(Error::RegisterIrqFd)?;
+        let events = Arc::new(Mutex::new(events));
+        let identifier = (
+            DeviceType::HotplugEvents,
+            DeviceType::HotplugEvents.to_string(),
+        );
+        self.register_mmio_device(identifier, device_info, events.clone())?;
+        self.hotplug_events = Some(events);
+        Ok(())
+    }
+
+    /// Gets the slots reserved for hot-plugging.
+    pub fn hotplug_slots(&self) -> &[HotplugSlot] {
+        &self.hotplug_slots
+    }
+
+    /// Gets the device notifying the guest about the changes of the hot-plug slots.
+    pub(crate) fn locked_hotplug_events(&self) -> Option<MutexGuard<HotplugEvents>> {
+        self.hotplug_events
+            .as_ref()
+            .map(|events| events.lock().expect("Poisoned lock"))
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    /// Gets the information of the registered devices, along with the free hot-plug slots
+    /// which also need to be described in the FDT.
+    pub fn get_fdt_device_info(&self) -> HashMap<(DeviceType, String), MMIODeviceInfo> {
+        let mut device_info = self.id_to_dev_info.clone();
+        for (i, slot) in self.hotplug_slots.iter().enumerate() {
+            if slot.device_id.is_none() {
+                let identifier = (
+                    DeviceType::Virtio(devices::virtio::TYPE_EMPTY_SLOT),
+                    format!("hotplug_slot_{}", i),
+                );
+                device_info.insert(identifier, slot.device_info.clone());
+            }
+        }
+        device_info
+    }
+
+    /// Plug a virtio-over-MMIO device in the first free hot-plug slot, and notify the guest.
+    pub fn hotplug_mmio_virtio(
+        &mut self,
+        vm: &VmFd,
+        device_id: String,
+        mmio_device: MmioTransport,
+    ) -> Result<MMIODeviceInfo> {
+        let identifier = (
+            DeviceType::Virtio(mmio_device.locked_device().device_type()),
+            device_id,
+        );
+        if self.id_to_dev_info.contains_key(&identifier) {
+            return Err(Error::InvalidInput);
+        }
+        let slot_index = self
+            .hotplug_slots
+            .iter()
+            .position(|slot| slot.device_id.is_none())
+            .ok_or(Error::NoFreeHotplugSlot)?;
+        let device_info = self.hotplug_slots[slot_index].device_info.clone();
+
+        let device = mmio_device.device();
+        Self::register_virtio_events(vm, &*device.lock().expect("Poisoned lock"), &device_info)?;
+        if let Err(err) = self.swap_mmio_transport(&device_info, mmio_device) {
+            let _ = Self::unregister_virtio_events(
+                vm,
+                &*device.lock().expect("Poisoned lock"),
+                &device_info,
+            );
+            return Err(err);
+        }
+
+        self.hotplug_slots[slot_index].device_id = Some(identifier.1.clone());
+        self.id_to_dev_info.insert(identifier, device_info.clone());
+        if let Some(mut events) = self.locked_hotplug_events() {
+            events.set_present(slot_index, true);
+        }
+        Ok(device_info)
+    }
+
+    /// Unplug a virtio-over-MMIO device, turning its slot into a free hot-plug slot, and
+    /// notify the guest. Returns the unplugged device.
+    ///
+    /// The device can't be unplugged while a guest driver is bound to it: the guest is asked
+    /// to release the device instead, and the request has to be retried once it did.
+    pub fn unplug_mmio_virtio(
+        &mut self,
+        vm: &VmFd,
+        mem: &GuestMemoryMmap,
+        virtio_type: u32,
+        device_id: &str,
+    ) -> Result<Arc<Mutex<dyn VirtioDevice>>> {
+        let identifier = (DeviceType::Virtio(virtio_type), device_id.to_string());
+        let slot_index = self
+            .hotplug_slots
+            .iter()
+            .position(|slot| slot.device_id.as_deref() == Some(device_id))
+            .filter(|_| self.id_to_dev_info.contains_key(&identifier))
+            .ok_or(Error::DeviceNotFound)?;
+        let device_info = self.hotplug_slots[slot_index].device_info.clone();
+
+        let transport =
+            match self.swap_mmio_transport(&device_info, Self::empty_slot_transport(mem)?) {
+                Err(Error::DeviceInUse) => {
+                    if let Some(mut events) = self.locked_hotplug_events() {
+                        events.request_eject(slot_index);
+                    }
+                    return Err(Error::DeviceInUse);
+                }
+                result => result?,
+            };
+        let device = transport.device();
+        Self::unregister_virtio_events(vm, &*transport.locked_device(), &device_info)?;
+
+        self.id_to_dev_info.remove(&identifier);
+        self.hotplug_slots[slot_index].device_id = None;
+        if let Some(mut events) = self.locked_hotplug_events() {
+            events.set_present(slot_index, false);
+        }
+        Ok(device)
+    }
+
     /// Append a registered virtio-over-MMIO device to the kernel cmdline.
     #[cfg(target_arch = "x86_64")]
     pub fn add_virtio_device_to_cmdline(
@@ -440,13 +719,33 @@ impl MMIODeviceManager {
                     if net.is_activated() {
                         info!("kick net {}.", id);
                         net.process_virtio_queues();
+                        // Ask the guest to announce itself on the network, in case it was
+                        // restored from a snapshot on another host.
+                        net.process_pending_announce();
                     }
                 }
                 TYPE_VSOCK => {
                     // Vsock has complicated protocol that isn't resilient to any packet loss,
-                    // so for Vsock we don't support connection persistence through snapshot.
-                    // Any in-flight packets or events are simply lost.
-                    // Vsock is restored 'empty'.
+                    // so unless the backend persists its connections, the guest was told to
+                    // reset them when the snapshot was taken. Any in-flight packets or events
+                    // are simply lost, and vsock is restored 'empty'.
+                    // Otherwise (`persist_connections`, only supported by the Unix backend),
+                    // the guest connections which can be resumed are restored, and resets are
+                    // queued for the others, so the rx queue is kicked to hand these over to
+                    // the guest.
+                    if let Some(vsock) = virtio
+                        .as_mut_any()
+                        .downcast_mut::<Vsock<VsockUnixBackend>>()
+                    {
+                        if vsock.is_activated() && vsock.backend().persists_connections() {
+                            info!("kick vsock {}.", id);
+                            if vsock.process_rx() {
+                                vsock.signal_used_queue().unwrap_or_else(|err| {
+                                    error!("Failed to signal the vsock used queue: {:?}", err)
+                                });
+                            }
+                        }
+                    }
                 }
                 _ => (),
             }
@@ -673,13 +972,17 @@ mod tests {
             let msg = match err {
                 Error::Bus(_) => format!("{}{:?}", err, err),
                 Error::Cmdline(_) => format!("{}{:?}", err, err),
+                Error::DeviceInUse => format!("{}{:?}", err, err),
                 Error::DeviceNotFound => format!("{}{:?}", err, err),
                 Error::EventFd(_) => format!("{}{:?}", err, err),
                 Error::IncorrectDeviceType => format!("{}{:?}", err, err),
                 Error::InternalDeviceError(_) => format!("{}{:?}", err, err),
                 Error::InvalidInput => format!("{}{:?}", err, err),
+                Error::NoFreeHotplugSlot => format!("{}{:?}", err, err),
                 Error::RegisterIoEvent(_) => format!("{}{:?}", err, err),
                 Error::RegisterIrqFd(_) => format!("{}{:?}", err, err),
+                Error::UnregisterIoEvent(_) => format!("{}{:?}", err, err),
+                Error::UnregisterIrqFd(_) => format!("{}{:?}", err, err),
                 Error::UpdateFailed => format!("{}{:?}", err, err),
                 Error::AllocatorError(_) => format!("{}{:?}", err, err),
             };
@@ -687,14 +990,18 @@ mod tests {
         };
         check_fmt_err(Error::Bus(devices::BusError::Overlap));
         check_fmt_err(Error::Cmdline(linux_loader::cmdline::Error::TooLarge));
+        check_fmt_err(Error::DeviceInUse);
         check_fmt_err(Error::DeviceNotFound);
         check_fmt_err(Error::EventFd(io::Error::from_raw_os_error(0)));
         check_fmt_err(Error::IncorrectDeviceType);
         check_fmt_err(Error::InternalDeviceError(String::new()));
         check_fmt_err(Error::InvalidInput);
+        check_fmt_err(Error::NoFreeHotplugSlot);
         check_fmt_err(Error::AllocatorError(vm_allocator::Error::Overflow));
         check_fmt_err(Error::RegisterIoEvent(errno::Error::new(0)));
         check_fmt_err(Error::RegisterIrqFd(errno::Error::new(0)));
+        check_fmt_err(Error::UnregisterIoEvent(errno::Error::new(0)));
+        check_fmt_err(Error::UnregisterIrqFd(errno::Error::new(0)));
         check_fmt_err(Error::UpdateFailed);
     }
 
@@ -768,6 +1075,147 @@ mod tests {
         assert_eq!(device_manager.used_irqs_count(), 2);
     }
 
+    #[test]
+    fn test_hotplug_slots() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        let guest_mem = vm_memory::test_utils::create_anon_guest_memory(
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+            false,
+        )
+        .unwrap();
+        let mut vm = builder::setup_kvm_vm(&guest_mem, false).unwrap();
+
+        #[cfg(target_arch = "x86_64")]
+        assert!(builder::setup_interrupt_controller(&mut vm).is_ok());
+        #[cfg(target_arch = "aarch64")]
+        assert!(builder::setup_interrupt_controller(&mut vm, 1).is_ok());
+
+        let mut device_manager = MMIODeviceManager::new(
+            0xd000_0000,
+            arch::MMIO_MEM_SIZE,
+            (arch::IRQ_BASE, arch::IRQ_MAX),
+        )
+        .unwrap();
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        device_manager
+            .reserve_hotplug_slots(vm.fd(), &guest_mem, 2, &mut cmdline)
+            .unwrap();
+        let slots = device_manager.hotplug_slots().to_vec();
+        assert_eq!(slots.len(), 2);
+        assert!(slots.iter().all(|slot| slot.device_id.is_none()));
+        // Only the hot-plug events device is registered.
+        let events_info = device_manager
+            .get_device_info()
+            .get(&(
+                DeviceType::HotplugEvents,
+                DeviceType::HotplugEvents.to_string(),
+            ))
+            .unwrap()
+            .clone();
+        assert_eq!(device_manager.get_device_info().len(), 1);
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert!(cmdline.as_str().contains("virtio_mmio.device="));
+            assert!(cmdline.as_str().contains("hotplug_events.device="));
+        }
+
+        // Free slots are backed by an empty device.
+        let mut data = [0u8; 4];
+        assert!(device_manager
+            .bus
+            .read(slots[0].device_info.addr + 8, &mut data));
+        assert_eq!(u32::from_le_bytes(data), 0);
+
+        let dummy = Arc::new(Mutex::new(DummyDevice::new()));
+        let type_id = dummy.lock().unwrap().device_type();
+        let info = device_manager
+            .hotplug_mmio_virtio(
+                vm.fd(),
+                "foo".to_string(),
+                MmioTransport::new(guest_mem.clone(), dummy),
+            )
+            .unwrap();
+        assert_eq!(info, slots[0].device_info);
+        assert_eq!(
+            device_manager.hotplug_slots()[0].device_id.as_deref(),
+            Some("foo")
+        );
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(type_id), "foo")
+            .is_some());
+
+        // The guest is notified.
+        assert!(device_manager.bus.read(events_info.addr + 4, &mut data));
+        assert_eq!(u32::from_le_bytes(data), 0b01);
+        assert!(device_manager.bus.read(events_info.addr + 0xc, &mut data));
+        assert_eq!(u32::from_le_bytes(data), 0b01);
+
+        // Plugging a device with the same id fails and keeps the free slot.
+        let dummy = Arc::new(Mutex::new(DummyDevice::new()));
+        assert!(matches!(
+            device_manager.hotplug_mmio_virtio(
+                vm.fd(),
+                "foo".to_string(),
+                MmioTransport::new(guest_mem.clone(), dummy),
+            ),
+            Err(Error::InvalidInput)
+        ));
+        assert!(device_manager.hotplug_slots()[1].device_id.is_none());
+
+        let dummy = Arc::new(Mutex::new(DummyDevice::new()));
+        device_manager
+            .hotplug_mmio_virtio(
+                vm.fd(),
+                "bar".to_string(),
+                MmioTransport::new(guest_mem.clone(), dummy),
+            )
+            .unwrap();
+        let dummy = Arc::new(Mutex::new(DummyDevice::new()));
+        assert!(matches!(
+            device_manager.hotplug_mmio_virtio(
+                vm.fd(),
+                "baz".to_string(),
+                MmioTransport::new(guest_mem.clone(), dummy),
+            ),
+            Err(Error::NoFreeHotplugSlot)
+        ));
+
+        // A device can't be unplugged while the guest driver is bound to it. The guest is
+        // asked to release it instead.
+        let status_addr = slots[0].device_info.addr + 0x70;
+        assert!(device_manager.bus.write(status_addr, &1u32.to_le_bytes()));
+        assert!(device_manager.bus.write(status_addr, &3u32.to_le_bytes()));
+        assert!(matches!(
+            device_manager.unplug_mmio_virtio(vm.fd(), &guest_mem, type_id, "foo"),
+            Err(Error::DeviceInUse)
+        ));
+        assert!(device_manager.bus.read(events_info.addr + 8, &mut data));
+        assert_eq!(u32::from_le_bytes(data), 0b01);
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(type_id), "foo")
+            .is_some());
+
+        // Once the guest reset the device, it can be unplugged.
+        assert!(device_manager.bus.write(status_addr, &0u32.to_le_bytes()));
+        let device = device_manager
+            .unplug_mmio_virtio(vm.fd(), &guest_mem, type_id, "foo")
+            .unwrap();
+        assert_eq!(device.lock().unwrap().device_type(), type_id);
+        assert_eq!(device_manager.hotplug_slots()[0], slots[0]);
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(type_id), "foo")
+            .is_none());
+        assert!(device_manager.bus.read(events_info.addr + 4, &mut data));
+        assert_eq!(u32::from_le_bytes(data), 0b10);
+        assert!(device_manager.bus.read(events_info.addr + 8, &mut data));
+        assert_eq!(u32::from_le_bytes(data), 0);
+        assert!(matches!(
+            device_manager.unplug_mmio_virtio(vm.fd(), &guest_mem, type_id, "foo"),
+            Err(Error::DeviceNotFound)
+        ));
+    }
+
     #[test]
     fn test_slot_irq_allocation() {
         let mut device_manager = MMIODeviceManager::new(
@@ -3,24 +3,33 @@
 
 //! Provides functionality for saving/restoring the MMIO device manager and its devices.
 
+use std::collections::HashMap;
 use std::result::Result;
 use std::sync::{Arc, Mutex};
 
-#[cfg(target_arch = "aarch64")]
 use arch::DeviceType;
+use devices::pseudo::{HotplugEvents, HotplugEventsState};
 use devices::virtio::balloon::persist::{BalloonConstructorArgs, BalloonState};
 use devices::virtio::balloon::{Balloon, Error as BalloonError};
 use devices::virtio::block::persist::{BlockConstructorArgs, BlockState};
 use devices::virtio::block::{Block, Error as BlockError};
+use devices::virtio::mem::persist::{VirtioMemConstructorArgs, VirtioMemState};
+use devices::virtio::mem::{Error as MemError, VirtioMem};
 use devices::virtio::net::persist::{Error as NetError, NetConstructorArgs, NetState};
 use devices::virtio::net::Net;
 use devices::virtio::persist::{MmioTransportConstructorArgs, MmioTransportState};
-use devices::virtio::vsock::persist::{VsockConstructorArgs, VsockState, VsockUdsConstructorArgs};
-use devices::virtio::vsock::{Vsock, VsockError, VsockUnixBackend, VsockUnixBackendError};
+use devices::virtio::vsock::persist::{
+    VsockBackendState, VsockConstructorArgs, VsockState, VsockTcpConstructorArgs,
+    VsockUdsConstructorArgs,
+};
+use devices::virtio::vsock::{
+    Vsock, VsockBackend, VsockError, VsockTcpBackend, VsockTcpBackendError, VsockUnixBackend,
+    VsockUnixBackendError,
+};
 use devices::virtio::{
-    MmioTransport, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET, TYPE_VSOCK,
+    MmioTransport, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_MEM, TYPE_NET, TYPE_VSOCK,
 };
-use event_manager::{MutEventSubscriber, SubscriberOps};
+use event_manager::{MutEventSubscriber, SubscriberId, SubscriberOps};
 use kvm_ioctls::VmFd;
 use logger::{error, warn};
 use mmds::data_store::MmdsVersion;
@@ -33,6 +42,7 @@ use vm_memory::GuestMemoryMmap;
 use super::mmio::*;
 use crate::resources::VmResources;
 use crate::vmm_config::mmds::MmdsConfigError;
+use crate::vmm_config::vsock::VsockDevice;
 use crate::EventManager;
 
 /// Errors for (de)serialization of the MMIO device manager.
@@ -44,9 +54,11 @@ pub enum Error {
     MmioTransport,
     #[cfg(target_arch = "aarch64")]
     Legacy(crate::Error),
+    Mem(MemError),
     Net(NetError),
     Vsock(VsockError),
     VsockUnixBackend(VsockUnixBackendError),
+    VsockTcpBackend(VsockTcpBackendError),
     MmdsConfig(MmdsConfigError),
 }
 
@@ -106,6 +118,20 @@ pub struct ConnectedVsockState {
     pub device_info: MMIODeviceInfo,
 }
 
+#[derive(Clone, Versionize)]
+/// Holds the state of a virtio-mem device connected to the MMIO space.
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct ConnectedMemState {
+    /// Device identifier.
+    pub device_id: String,
+    /// Device state.
+    pub device_state: VirtioMemState,
+    /// Mmio transport state.
+    pub transport_state: MmioTransportState,
+    /// VmmResources.
+    pub device_info: MMIODeviceInfo,
+}
+
 #[cfg(target_arch = "aarch64")]
 #[derive(Clone, Versionize)]
 /// Holds the state of a legacy device connected to the MMIO space.
@@ -116,6 +142,18 @@ pub struct ConnectedLegacyState {
     pub device_info: MMIODeviceInfo,
 }
 
+#[derive(Clone, Versionize)]
+/// Holds the state of the MMIO slots reserved for hot-plugging.
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct ConnectedHotplugState {
+    /// Slots, along with the identifiers of the devices plugged in them.
+    pub slots: Vec<HotplugSlot>,
+    /// Hot-plug events device state.
+    pub events_state: HotplugEventsState,
+    /// Hot-plug events device resources.
+    pub events_info: MMIODeviceInfo,
+}
+
 /// Holds the MMDS data store version.
 #[derive(Debug, Clone, PartialEq, Eq, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
@@ -161,6 +199,12 @@ pub struct DeviceStates {
     /// Mmds version.
     #[version(start = 3, ser_fn = "mmds_version_serialize")]
     pub mmds_version: Option<MmdsVersionState>,
+    /// Virtio-mem device state.
+    #[version(start = 4, ser_fn = "mem_serialize")]
+    pub mem_device: Option<ConnectedMemState>,
+    /// Hot-plug slots state.
+    #[version(start = 4, ser_fn = "hotplug_serialize")]
+    pub hotplug: Option<ConnectedHotplugState>,
 }
 
 /// A type used to extract the concrete Arc<Mutex<T>> for each of the device types when restoring
@@ -169,7 +213,8 @@ pub enum SharedDeviceType {
     Block(Arc<Mutex<Block>>),
     Network(Arc<Mutex<Net>>),
     Balloon(Arc<Mutex<Balloon>>),
-    Vsock(Arc<Mutex<Vsock<VsockUnixBackend>>>),
+    Vsock(VsockDevice),
+    Mem(Arc<Mutex<VirtioMem>>),
 }
 
 impl DeviceStates {
@@ -193,6 +238,26 @@ impl DeviceStates {
 
         Ok(())
     }
+
+    fn mem_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 4 && self.mem_device.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the virtio-mem device.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn hotplug_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 4 && self.hotplug.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement hot-plug slots.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub struct MMIODevManagerConstructorArgs<'a> {
@@ -202,6 +267,30 @@ pub struct MMIODevManagerConstructorArgs<'a> {
     pub for_each_restored_device: fn(&mut VmResources, SharedDeviceType),
     pub vm_resources: &'a mut VmResources,
     pub instance_id: &'a str,
+    /// Filled with the event manager subscribers of the restored hot-plugged devices.
+    pub hotplug_subscribers: &'a mut HashMap<String, SubscriberId>,
+}
+
+/// Saves the state of a vsock device, regardless of its backend, and resets the guest
+/// connections if the device is activated, unless the backend carries them over.
+fn save_vsock<B>(vsock: &mut Vsock<B>) -> VsockState
+where
+    B: VsockBackend + for<'a> Persist<'a, State = VsockBackendState> + 'static,
+{
+    let vsock_state = VsockState {
+        backend: vsock.backend().save(),
+        frontend: vsock.save(),
+    };
+
+    // Send Transport event to reset connections if device
+    // is activated.
+    if vsock.is_activated() && !vsock.backend().persists_connections() {
+        vsock.send_transport_reset_event().unwrap_or_else(|err| {
+            error!("Failed to send reset transport event: {:?}", err);
+        });
+    }
+
+    vsock_state
 }
 
 impl<'a> Persist<'a> for MMIODeviceManager {
@@ -218,12 +307,22 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             #[cfg(target_arch = "aarch64")]
             legacy_devices: Vec::new(),
             mmds_version: None,
+            mem_device: None,
+            hotplug: None,
         };
         let _: Result<(), ()> = self.for_each_device(|devtype, devid, device_info, bus_dev| {
-            if *devtype == arch::DeviceType::BootTimer {
+            if *devtype == DeviceType::BootTimer {
                 // No need to save BootTimer state.
                 return Ok(());
             }
+            if *devtype == DeviceType::HotplugEvents {
+                states.hotplug = Some(ConnectedHotplugState {
+                    slots: self.hotplug_slots().to_vec(),
+                    events_state: self.locked_hotplug_events().unwrap().save(),
+                    events_info: device_info.clone(),
+                });
+                return Ok(());
+            }
 
             #[cfg(target_arch = "aarch64")]
             {
@@ -260,6 +359,19 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                         device_info: device_info.clone(),
                     });
                 }
+                TYPE_MEM => {
+                    let mem_state = locked_device
+                        .as_any()
+                        .downcast_ref::<VirtioMem>()
+                        .unwrap()
+                        .save();
+                    states.mem_device = Some(ConnectedMemState {
+                        device_id: devid.clone(),
+                        device_state: mem_state,
+                        transport_state,
+                        device_info: device_info.clone(),
+                    });
+                }
                 TYPE_BLOCK => {
                     let block = locked_device.as_mut_any().downcast_mut::<Block>().unwrap();
                     block.prepare_save();
@@ -271,7 +383,8 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                     });
                 }
                 TYPE_NET => {
-                    let net = locked_device.as_any().downcast_ref::<Net>().unwrap();
+                    let net = locked_device.as_mut_any().downcast_mut::<Net>().unwrap();
+                    net.prepare_save();
                     if let (Some(mmds_ns), None) =
                         (net.mmds_ns.as_ref(), states.mmds_version.as_ref())
                     {
@@ -287,25 +400,14 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                     });
                 }
                 TYPE_VSOCK => {
-                    let vsock = locked_device
-                        .as_mut_any()
-                        // Currently, VsockUnixBackend is the only implementation of VsockBackend.
-                        .downcast_mut::<Vsock<VsockUnixBackend>>()
-                        .unwrap();
-
-                    let vsock_state = VsockState {
-                        backend: vsock.backend().save(),
-                        frontend: vsock.save(),
+                    let vsock = locked_device.as_mut_any();
+                    let vsock_state = if vsock.is::<Vsock<VsockUnixBackend>>() {
+                        save_vsock(vsock.downcast_mut::<Vsock<VsockUnixBackend>>().unwrap())
+                    } else {
+                        // VsockTcpBackend is the only other implementation of VsockBackend.
+                        save_vsock(vsock.downcast_mut::<Vsock<VsockTcpBackend>>().unwrap())
                     };
 
-                    // Send Transport event to reset connections if device
-                    // is activated.
-                    if vsock.is_activated() {
-                        vsock.send_transport_reset_event().unwrap_or_else(|err| {
-                            error!("Failed to send reset transport event: {:?}", err);
-                        });
-                    }
-
                     states.vsock_device = Some(ConnectedVsockState {
                         device_id: devid.clone(),
                         device_state: vsock_state,
@@ -380,7 +482,7 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                                   state: &MmioTransportState,
                                   device_info: &MMIODeviceInfo,
                                   event_manager: &mut EventManager|
-         -> Result<(), Self::Error> {
+         -> Result<SubscriberId, Self::Error> {
             let restore_args = MmioTransportConstructorArgs {
                 mem: mem.clone(),
                 device,
@@ -409,8 +511,7 @@ impl<'a> Persist<'a> for MMIODeviceManager {
 
             dev_manager.register_mmio_virtio(vm, id.clone(), mmio_transport, device_info)?;
 
-            event_manager.add_subscriber(as_subscriber);
-            Ok(())
+            Ok(event_manager.add_subscriber(as_subscriber))
         };
 
         if let Some(balloon_state) = &state.balloon_device {
@@ -434,6 +535,27 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             )?;
         }
 
+        if let Some(mem_state) = &state.mem_device {
+            let device = Arc::new(Mutex::new(VirtioMem::restore(
+                VirtioMemConstructorArgs { mem: mem.clone() },
+                &mem_state.device_state,
+            )?));
+
+            (constructor_args.for_each_restored_device)(
+                constructor_args.vm_resources,
+                SharedDeviceType::Mem(device.clone()),
+            );
+
+            restore_helper(
+                device.clone(),
+                device,
+                &mem_state.device_id,
+                &mem_state.transport_state,
+                &mem_state.device_info,
+                constructor_args.event_manager,
+            )?;
+        }
+
         for block_state in &state.block_devices {
             let device = Arc::new(Mutex::new(Block::restore(
                 BlockConstructorArgs { mem: mem.clone() },
@@ -445,7 +567,7 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 SharedDeviceType::Block(device.clone()),
             );
 
-            restore_helper(
+            let subscriber_id = restore_helper(
                 device.clone(),
                 device,
                 &block_state.device_id,
@@ -453,6 +575,17 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 &block_state.device_info,
                 constructor_args.event_manager,
             )?;
+            // Hot-plugged devices have to be removed from the event manager when unplugged.
+            if state.hotplug.as_ref().map_or(false, |hotplug| {
+                hotplug
+                    .slots
+                    .iter()
+                    .any(|slot| slot.device_id.as_ref() == Some(&block_state.device_id))
+            }) {
+                constructor_args
+                    .hotplug_subscribers
+                    .insert(block_state.device_id.clone(), subscriber_id);
+            }
         }
 
         // If the snapshot has the mmds version persisted, initialise the data store with it.
@@ -501,32 +634,88 @@ impl<'a> Persist<'a> for MMIODeviceManager {
         }
 
         if let Some(vsock_state) = &state.vsock_device {
-            let ctor_args = VsockUdsConstructorArgs {
-                cid: vsock_state.device_state.frontend.cid,
+            let cid = vsock_state.device_state.frontend.cid;
+            let backend_state = &vsock_state.device_state.backend;
+            let device = match backend_state {
+                VsockBackendState::Uds(_) => {
+                    let backend =
+                        VsockUnixBackend::restore(VsockUdsConstructorArgs { cid }, backend_state)?;
+                    VsockDevice::Unix(Arc::new(Mutex::new(Vsock::restore(
+                        VsockConstructorArgs {
+                            mem: mem.clone(),
+                            backend,
+                        },
+                        &vsock_state.device_state.frontend,
+                    )?)))
+                }
+                VsockBackendState::Tcp(_) => {
+                    let backend =
+                        VsockTcpBackend::restore(VsockTcpConstructorArgs { cid }, backend_state)?;
+                    VsockDevice::Tcp(Arc::new(Mutex::new(Vsock::restore(
+                        VsockConstructorArgs {
+                            mem: mem.clone(),
+                            backend,
+                        },
+                        &vsock_state.device_state.frontend,
+                    )?)))
+                }
             };
-            let backend = VsockUnixBackend::restore(ctor_args, &vsock_state.device_state.backend)?;
-            let device = Arc::new(Mutex::new(Vsock::restore(
-                VsockConstructorArgs {
-                    mem: mem.clone(),
-                    backend,
-                },
-                &vsock_state.device_state.frontend,
-            )?));
 
             (constructor_args.for_each_restored_device)(
                 constructor_args.vm_resources,
                 SharedDeviceType::Vsock(device.clone()),
             );
 
-            restore_helper(
-                device.clone(),
-                device,
-                &vsock_state.device_id,
-                &vsock_state.transport_state,
-                &vsock_state.device_info,
-                constructor_args.event_manager,
-            )?;
+            match device {
+                VsockDevice::Unix(device) => restore_helper(
+                    device.clone(),
+                    device,
+                    &vsock_state.device_id,
+                    &vsock_state.transport_state,
+                    &vsock_state.device_info,
+                    constructor_args.event_manager,
+                )?,
+                VsockDevice::Tcp(device) => restore_helper(
+                    device.clone(),
+                    device,
+                    &vsock_state.device_id,
+                    &vsock_state.transport_state,
+                    &vsock_state.device_info,
+                    constructor_args.event_manager,
+                )?,
+            };
+        }
+
+        if let Some(hotplug_state) = &state.hotplug {
+            // The slots with a device plugged in were restored along with the device.
+            for slot in &hotplug_state.slots {
+                if slot.device_id.is_none() {
+                    dev_manager
+                        .address_allocator
+                        .allocate(
+                            MMIO_LEN,
+                            MMIO_LEN,
+                            AllocPolicy::ExactMatch(slot.device_info.addr),
+                        )
+                        .map_err(|e| Error::DeviceManager(super::mmio::Error::AllocatorError(e)))?;
+                    dev_manager.insert_empty_slot(mem, &slot.device_info)?;
+                }
+            }
+            dev_manager.hotplug_slots = hotplug_state.slots.clone();
+
+            let events = HotplugEvents::restore((), &hotplug_state.events_state)
+                .map_err(|e| Error::DeviceManager(super::mmio::Error::EventFd(e)))?;
+            dev_manager
+                .address_allocator
+                .allocate(
+                    MMIO_LEN,
+                    MMIO_LEN,
+                    AllocPolicy::ExactMatch(hotplug_state.events_info.addr),
+                )
+                .map_err(|e| Error::DeviceManager(super::mmio::Error::AllocatorError(e)))?;
+            dev_manager.register_hotplug_events(vm, events, hotplug_state.events_info.clone())?;
         }
+
         Ok(dev_manager)
     }
 }
@@ -541,7 +730,7 @@ mod tests {
     use crate::builder::tests::*;
     use crate::resources::VmmConfig;
     use crate::vmm_config::balloon::BalloonDeviceConfig;
-    use crate::vmm_config::net::NetworkInterfaceConfig;
+    use crate::vmm_config::net::{NetworkInterfaceConfig, Offloads};
     use crate::vmm_config::vsock::VsockDeviceConfig;
 
     impl PartialEq for ConnectedBalloonState {
@@ -612,12 +801,30 @@ mod tests {
         }
     }
 
+    impl PartialEq for ConnectedMemState {
+        fn eq(&self, other: &ConnectedMemState) -> bool {
+            // Actual device state equality is checked by the device's tests.
+            self.transport_state == other.transport_state && self.device_info == other.device_info
+        }
+    }
+
+    impl std::fmt::Debug for ConnectedMemState {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(
+                f,
+                "ConnectedMemDevice {{ transport_state: {:?}, device_info: {:?} }}",
+                self.transport_state, self.device_info
+            )
+        }
+    }
+
     impl PartialEq for DeviceStates {
         fn eq(&self, other: &DeviceStates) -> bool {
             self.balloon_device == other.balloon_device
                 && self.block_devices == other.block_devices
                 && self.net_devices == other.net_devices
                 && self.vsock_device == other.vsock_device
+                && self.mem_device == other.mem_device
         }
     }
 
@@ -640,15 +847,19 @@ mod tests {
             let mut clone =
                 MMIODeviceManager::new(dummy_mmio_base, arch::MMIO_MEM_SIZE, dummy_irq_range)
                     .unwrap();
-            // We only care about the device hashmap.
+            // We only care about the device hashmap and the hot-plug slots.
             clone.id_to_dev_info = self.id_to_dev_info.clone();
+            clone.hotplug_slots = self.hotplug_slots.clone();
             clone
         }
     }
 
     impl PartialEq for MMIODeviceManager {
         fn eq(&self, other: &MMIODeviceManager) -> bool {
-            // We only care about the device hashmap.
+            // We only care about the device hashmap and the hot-plug slots.
+            if self.hotplug_slots != other.hotplug_slots {
+                return false;
+            }
             if self.id_to_dev_info.len() != other.id_to_dev_info.len() {
                 return false;
             }
@@ -687,6 +898,9 @@ mod tests {
                 amount_mib: 123,
                 deflate_on_oom: false,
                 stats_polling_interval_s: 1,
+                free_page_hinting: false,
+                free_page_reporting: false,
+                auto_size: None,
             };
             insert_balloon_device(&mut vmm, &mut cmdline, &mut event_manager, balloon_cfg);
             // Add a block device.
@@ -707,6 +921,10 @@ mod tests {
                 guest_mac: Some(MacAddr::parse_str("00:00:00:00:00:00").unwrap()),
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                num_queue_pairs: 1,
+                offloads: Offloads::default(),
+                vhost_net: false,
+                anti_spoofing: None,
             };
             insert_net_device_with_mmds(
                 &mut vmm,
@@ -720,7 +938,10 @@ mod tests {
             let vsock_config = VsockDeviceConfig {
                 vsock_id: Some(vsock_dev_id.to_string()),
                 guest_cid: 3,
-                uds_path: tmp_sock_file.as_path().to_str().unwrap().to_string(),
+                uds_path: Some(tmp_sock_file.as_path().to_str().unwrap().to_string()),
+                tcp_ports: Vec::new(),
+                rate_limiter: None,
+                persist_connections: false,
             };
             insert_vsock_device(&mut vmm, &mut cmdline, &mut event_manager, vsock_config);
 
@@ -761,6 +982,31 @@ mod tests {
                 .serialize(&mut buf.as_mut_slice(), &version_map, 3)
                 .unwrap();
 
+            // Add a virtio-mem device.
+            insert_virtio_mem_device(&mut vmm, &mut cmdline, &mut event_manager, 1024);
+
+            assert_eq!(
+                vmm.mmio_device_manager
+                    .save()
+                    .serialize(&mut buf.as_mut_slice(), &version_map, 3),
+                Err(VersionizeError::Semantic(
+                    "Target version does not implement the virtio-mem device.".to_string()
+                ))
+            );
+
+            // Reserve hot-plug slots.
+            vmm.mmio_device_manager
+                .reserve_hotplug_slots(vmm.vm.fd(), &vmm.guest_memory, 2, &mut cmdline)
+                .unwrap();
+
+            version_map
+                .new_version()
+                .set_type_version(DeviceStates::type_id(), 4);
+            vmm.mmio_device_manager
+                .save()
+                .serialize(&mut buf.as_mut_slice(), &version_map, 4)
+                .unwrap();
+
             // We only want to keep the device map from the original MmioDeviceManager.
             vmm.mmio_device_manager.soft_clone()
         };
@@ -769,7 +1015,7 @@ mod tests {
         let mut event_manager = EventManager::new().expect("Unable to create EventManager");
         let vmm = default_vmm();
         let device_states: DeviceStates =
-            DeviceStates::deserialize(&mut buf.as_slice(), &version_map, 3).unwrap();
+            DeviceStates::deserialize(&mut buf.as_slice(), &version_map, 4).unwrap();
         let vm_resources = &mut VmResources::default();
         let restore_args = MMIODevManagerConstructorArgs {
             mem: vmm.guest_memory().clone(),
@@ -778,27 +1024,37 @@ mod tests {
             for_each_restored_device: VmResources::update_from_restored_device,
             vm_resources,
             instance_id: "microvm-id",
+            hotplug_subscribers: &mut HashMap::new(),
         };
         let restored_dev_manager =
             MMIODeviceManager::restore(restore_args, &device_states).unwrap();
+        assert_eq!(restored_dev_manager.hotplug_slots().len(), 2);
+        assert!(restored_dev_manager.locked_hotplug_events().is_some());
 
         let expected_vm_resources = format!(
             r#"{{
   "balloon": {{
     "amount_mib": 123,
     "deflate_on_oom": false,
-    "stats_polling_interval_s": 1
+    "stats_polling_interval_s": 1,
+    "free_page_hinting": false,
+    "free_page_reporting": false,
+    "auto_size": null
   }},
   "drives": [
     {{
       "drive_id": "root",
       "path_on_host": "{}",
+      "socket": null,
       "is_root_device": true,
       "partuuid": null,
       "is_read_only": true,
       "cache_type": "Unsafe",
       "rate_limiter": null,
-      "io_engine": "Sync"
+      "io_engine": "Sync",
+      "disk_format": "Raw",
+      "num_queues": 1,
+      "direct_io": false
     }}
   ],
   "boot-source": {{
@@ -812,6 +1068,10 @@ mod tests {
     "smt": false,
     "track_dirty_pages": false
   }},
+  "memory-hotplug": {{
+    "total_size_mib": 1024,
+    "block_size_mib": 2
+  }},
   "metrics": null,
   "mmds-config": {{
     "version": "V2",
@@ -826,7 +1086,17 @@ mod tests {
       "host_dev_name": "hostname",
       "guest_mac": "00:00:00:00:00:00",
       "rx_rate_limiter": null,
-      "tx_rate_limiter": null
+      "tx_rate_limiter": null,
+      "num_queue_pairs": 1,
+      "offloads": {{
+        "checksum": true,
+        "tso4": true,
+        "tso6": true,
+        "ufo": true,
+        "ecn": true
+      }},
+      "vhost_net": false,
+      "anti_spoofing": null
     }}
   ],
   "vsock": {{
@@ -36,18 +36,23 @@ use std::collections::HashMap;
 use std::os::unix::io::AsRawFd;
 use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
 use std::sync::{Arc, Barrier, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{fmt, io};
 
 use arch::DeviceType;
 use devices::legacy::serial::{IER_RDA_BIT, IER_RDA_OFFSET};
 use devices::virtio::balloon::Error as BalloonError;
+use devices::virtio::mem::Error as MemError;
 use devices::virtio::{
-    Balloon, BalloonConfig, BalloonStats, Block, MmioTransport, Net, BALLOON_DEV_ID, TYPE_BALLOON,
-    TYPE_BLOCK, TYPE_NET,
+    Balloon, BalloonConfig, BalloonStats, Block, MmioTransport, Net, VirtioDevice, VirtioMem,
+    VirtioMemConfig, Vsock, VsockConnectionInfo, VsockTcpBackend, VsockUnixBackend, BALLOON_DEV_ID,
+    MEM_DEV_ID, TYPE_BALLOON, TYPE_BLOCK, TYPE_MEM, TYPE_NET, TYPE_VSOCK, VSOCK_DEV_ID,
 };
 use devices::BusDevice;
-use event_manager::{EventManager as BaseEventManager, EventOps, Events, MutEventSubscriber};
+use event_manager::{
+    EventManager as BaseEventManager, EventOps, Events, MutEventSubscriber, SubscriberId,
+    SubscriberOps,
+};
 use logger::{error, info, warn, LoggerError, MetricsError, METRICS};
 use rate_limiter::BucketUpdate;
 use seccompiler::BpfProgram;
@@ -55,7 +60,7 @@ use snapshot::Persist;
 use userfaultfd::Uffd;
 use utils::epoll::EventSet;
 use utils::eventfd::EventFd;
-use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 use vstate::vcpu::{self, KvmVcpuConfigureError, StartThreadedError, VcpuSendEventError};
 
 #[cfg(target_arch = "x86_64")]
@@ -64,6 +69,8 @@ use crate::device_manager::mmio::MMIODeviceManager;
 use crate::memory_snapshot::SnapshotMemory;
 use crate::persist::{MicrovmState, MicrovmStateError, VmInfo};
 use crate::vmm_config::instance_info::{InstanceInfo, VmState};
+use crate::vmm_config::net::LinkState;
+use crate::vmm_config::vsock::VsockConfigError;
 use crate::vstate::vcpu::{Vcpu, VcpuEvent, VcpuHandle, VcpuResponse, VcpuState};
 use crate::vstate::vm::Vm;
 
@@ -132,6 +139,9 @@ pub enum Error {
     /// Cannot read from an Event file descriptor.
     #[error("Event fd error: {0}")]
     EventFd(io::Error),
+    /// Cannot collect the free page hints of the balloon device.
+    #[error("Cannot collect the free page hints of the balloon device: {0:?}")]
+    FreePageHints(BalloonError),
     /// I8042 Error.
     #[error("I8042 error: {0}")]
     I8042Error(devices::legacy::I8042DeviceError),
@@ -152,6 +162,9 @@ pub enum Error {
     /// Internal metrics system error.
     #[error("Metrics error: {0}")]
     Metrics(MetricsError),
+    /// Cannot fetch the page size of the host.
+    #[error("Cannot fetch the page size of the host: {0}")]
+    PageSize(utils::errno::Error),
     /// Cannot add a device to the MMIO Bus.
     #[error("Cannot add a device to the MMIO Bus. {0}")]
     RegisterMMIODevice(device_manager::mmio::Error),
@@ -198,6 +211,9 @@ pub enum Error {
     /// Cannot spawn a new Vcpu thread.
     #[error("Cannot spawn Vcpu thread: {0}")]
     VcpuSpawn(io::Error),
+    /// Virtio-mem device error.
+    #[error("Virtio-mem device error: {0:?}")]
+    VirtioMem(MemError),
     /// Vm error.
     #[error("Vm error: {0}")]
     Vm(vstate::vm::Error),
@@ -207,6 +223,9 @@ pub enum Error {
     /// Error thrown by observer object on Vmm teardown.
     #[error("Error thrown by observer object on Vmm teardown: {0}")]
     VmmObserverTeardown(utils::errno::Error),
+    /// The device was not hot-plugged, so it cannot be unplugged.
+    #[error("Device {0} was not hot-plugged.")]
+    NotHotplugged(String),
 }
 
 /// Trait for objects that need custom initialization and teardown during the Vmm lifetime.
@@ -227,6 +246,20 @@ pub type Result<T> = std::result::Result<T, Error>;
 /// Shorthand type for KVM dirty page bitmap.
 pub type DirtyBitmap = HashMap<usize, Vec<u64>>;
 
+// Runs `f` on the balloon device, which is only locked for the duration of the call.
+fn with_balloon<T, F>(virtio_device: &Mutex<dyn VirtioDevice>, f: F) -> Result<T>
+where
+    F: FnOnce(&mut Balloon) -> std::result::Result<T, BalloonError>,
+{
+    f(virtio_device
+        .lock()
+        .expect("Poisoned lock")
+        .as_mut_any()
+        .downcast_mut::<Balloon>()
+        .unwrap())
+    .map_err(Error::FreePageHints)
+}
+
 /// Returns the size of guest memory, in MiB.
 pub(crate) fn mem_size_mib(guest_memory: &GuestMemoryMmap) -> u64 {
     guest_memory.iter().map(|region| region.len()).sum::<u64>() >> 20
@@ -273,6 +306,14 @@ pub enum RestoreVcpusError {
     NotAllowed(String),
 }
 
+/// Event manager registration state of a hot-plugged device.
+enum HotplugSubscriber {
+    /// The device still has to be added to the event manager.
+    Pending(Arc<Mutex<Block>>),
+    /// The device was added to the event manager.
+    Registered(SubscriberId),
+}
+
 /// Contains the state and associated methods required for the Firecracker VMM.
 pub struct Vmm {
     events_observer: Option<Box<dyn VmmEventsObserver>>,
@@ -294,6 +335,10 @@ pub struct Vmm {
     mmio_device_manager: MMIODeviceManager,
     #[cfg(target_arch = "x86_64")]
     pio_device_manager: PortIODeviceManager,
+    // Block devices hot-plugged after boot, by drive id.
+    hotplugged_blocks: HashMap<String, HotplugSubscriber>,
+    // Event manager subscribers of unplugged devices, waiting to be removed.
+    unplugged_subscribers: Vec<SubscriberId>,
 }
 
 impl Vmm {
@@ -559,10 +604,7 @@ impl Vmm {
             .iter()
             .enumerate()
             .try_for_each(|(slot, region)| {
-                let bitmap_region = self
-                    .vm
-                    .fd()
-                    .get_dirty_log(slot as u32, region.len() as usize)?;
+                let bitmap_region = self.vm.get_dirty_log(slot, region)?;
                 bitmap.insert(slot, bitmap_region);
                 Ok(())
             })
@@ -609,6 +651,65 @@ impl Vmm {
             .map_err(Error::DeviceManager)
     }
 
+    /// Plugs the block device in a free hot-plug slot. The device is added to the event
+    /// manager by the next call to `update_hotplug_subscribers()`.
+    pub fn hotplug_block_device(&mut self, block: Arc<Mutex<Block>>) -> Result<()> {
+        let drive_id = block.lock().expect("Poisoned lock").id().clone();
+        let mmio_device = MmioTransport::new(self.guest_memory.clone(), block.clone());
+        self.mmio_device_manager
+            .hotplug_mmio_virtio(self.vm.fd(), drive_id.clone(), mmio_device)
+            .map_err(Error::DeviceManager)?;
+        self.hotplugged_blocks
+            .insert(drive_id, HotplugSubscriber::Pending(block));
+        Ok(())
+    }
+
+    /// Unplugs the hot-plugged block device with `drive_id` id, freeing its slot, and
+    /// completes its in-flight requests. Fails if the guest driver is still bound to the
+    /// device, in which case the guest is asked to release it.
+    pub fn unplug_block_device(&mut self, drive_id: &str) -> Result<()> {
+        if !self.hotplugged_blocks.contains_key(drive_id) {
+            return Err(Error::NotHotplugged(drive_id.to_string()));
+        }
+        let device = self
+            .mmio_device_manager
+            .unplug_mmio_virtio(self.vm.fd(), &self.guest_memory, TYPE_BLOCK, drive_id)
+            .map_err(Error::DeviceManager)?;
+        if let Some(block) = device
+            .lock()
+            .expect("Poisoned lock")
+            .as_mut_any()
+            .downcast_mut::<Block>()
+        {
+            block.drain();
+        }
+
+        if let Some(HotplugSubscriber::Registered(id)) = self.hotplugged_blocks.remove(drive_id) {
+            self.unplugged_subscribers.push(id);
+        }
+        Ok(())
+    }
+
+    /// Adds the hot-plugged devices to the event manager and removes the unplugged ones.
+    /// This cannot be done while handling the API request itself, because the request is
+    /// processed from within the event manager loop.
+    pub fn update_hotplug_subscribers(&mut self, event_manager: &mut EventManager) {
+        for subscriber in self.hotplugged_blocks.values_mut() {
+            if let HotplugSubscriber::Pending(block) = subscriber {
+                let id = event_manager.add_subscriber(block.clone());
+                *subscriber = HotplugSubscriber::Registered(id);
+            }
+        }
+        for id in self.unplugged_subscribers.drain(..) {
+            if let Err(err) = event_manager.remove_subscriber(id) {
+                error!(
+                    "Failed to remove unplugged device from event manager: {}",
+                    err
+                );
+            }
+        }
+    }
+
     /// Updates the rate limiter parameters for net device with `net_id` id.
     pub fn update_net_rate_limiters(
         &mut self,
@@ -620,7 +721,38 @@ impl Vmm {
     ) -> Result<()> {
         self.mmio_device_manager
             .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
-                net.patch_rate_limiters(rx_bytes, rx_ops, tx_bytes, tx_ops);
+                net.patch_rate_limiters(rx_bytes, rx_ops, tx_bytes, tx_ops)
+                    .map_err(|err| format!("{:?}", err))
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Brings the link of the net device with `net_id` id up or down.
+    pub fn update_net_link_state(&mut self, net_id: &str, link_state: LinkState) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.set_link_state(link_state)
+                    .map_err(|err| format!("{:?}", err))
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Starts writing the frames of the net device with `net_id` id to a pcap file at `path`,
+    /// which won't grow larger than `max_size` bytes.
+    pub fn start_net_capture(&mut self, net_id: &str, path: &str, max_size: u64) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.start_capture(path, max_size)
+                    .map_err(|err| format!("{:?}", err))
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Stops the packet capture of the net device with `net_id` id, if any.
+    pub fn stop_net_capture(&mut self, net_id: &str) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.stop_capture();
                 Ok(())
             })
             .map_err(Error::DeviceManager)
@@ -688,8 +820,11 @@ impl Vmm {
         amount_mib: u32,
     ) -> std::result::Result<(), BalloonError> {
         // The balloon cannot have a target size greater than the size of
-        // the guest memory.
-        if amount_mib as u64 > mem_size_mib(self.guest_memory()) {
+        // the guest memory, hot-pluggable memory excluded.
+        let hotplug_size_mib = self
+            .memory_hotplug_config()
+            .map_or(0, |config| config.region_size >> 20);
+        if amount_mib as u64 > mem_size_mib(self.guest_memory()) - hotplug_size_mib {
             return Err(BalloonError::TooManyPagesRequested);
         }
 
@@ -751,6 +886,295 @@ impl Vmm {
         }
     }
 
+    /// Asks the guest for its free memory through the free page hinting of the balloon
+    /// device, and returns the hinted ranges. The vCPUs are resumed while the guest sends
+    /// the hints, and paused again once it is done or after `timeout`. KVM tracks the pages
+    /// written meanwhile, since the guest may already reuse the pages it hinted, and those
+    /// pages are left out of the returned ranges.
+    pub fn collect_free_page_hints(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Vec<(GuestAddress, u64)>> {
+        let virtio_device =
+            match self.get_bus_device(DeviceType::Virtio(TYPE_BALLOON), BALLOON_DEV_ID) {
+                Some(busdev) => busdev
+                    .lock()
+                    .expect("Poisoned lock")
+                    .as_any()
+                    .downcast_ref::<MmioTransport>()
+                    // Only MmioTransport implements BusDevice at this point.
+                    .expect("Unexpected BusDevice type")
+                    .device(),
+                None => return Ok(Vec::new()),
+            };
+
+        let queue_evt = with_balloon(&virtio_device, |balloon| {
+            if !balloon.free_page_hinting() {
+                return Ok(None);
+            }
+            match balloon.start_free_page_hinting() {
+                Ok(()) => balloon
+                    .free_page_hint_queue_evt()
+                    .try_clone()
+                    .map(Some)
+                    .map_err(BalloonError::EventFd),
+                // The snapshot doesn't depend on the hints, so it is created without them
+                // when the driver doesn't support free page hinting.
+                Err(err) => {
+                    warn!("Cannot start free page hinting: {:?}", err);
+                    Ok(None)
+                }
+            }
+        })?;
+        let queue_evt = match queue_evt {
+            Some(queue_evt) => queue_evt,
+            None => return Ok(Vec::new()),
+        };
+
+        // The dirty pages already tracked for diff snapshots are kept in the bitmaps of the
+        // guest memory, since fetching them from KVM resets its dirty bitmap.
+        let track_dirty_pages = self
+            .guest_memory
+            .iter()
+            .any(|region| region.bitmap().is_some());
+        let page_size = utils::get_page_size().map_err(Error::PageSize)?;
+        let deadline = Instant::now() + timeout;
+        let wait_result = self
+            .start_hinted_pages_tracking(track_dirty_pages, page_size)
+            .and_then(|_| self.resume_vm())
+            .and_then(|_| loop {
+                let stopped = with_balloon(&virtio_device, |balloon| {
+                    balloon.process_free_page_hint_queue()?;
+                    Ok(balloon.free_page_hinting_stopped())
+                })?;
+                let now = Instant::now();
+                if stopped {
+                    break Ok(());
+                }
+                if now >= deadline {
+                    warn!("The guest did not hint all of its free pages in time.");
+                    break Ok(());
+                }
+
+                let mut pollfd = libc::pollfd {
+                    fd: queue_evt.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // Safe because `pollfd` is a valid pollfd structure, and we pass a length of 1.
+                // The result doesn't matter, since the queue is processed either way.
+                unsafe {
+                    libc::poll(
+                        &mut pollfd,
+                        1,
+                        (deadline - now).as_millis().max(1) as libc::c_int,
+                    )
+                };
+                let _ = queue_evt.read();
+            });
+        let pause_result = self.pause_vm();
+        let dirty_result = self.stop_hinted_pages_tracking(track_dirty_pages, page_size);
+
+        // Let the guest use the hinted memory again, even if the hinting failed.
+        let finish_result = with_balloon(&virtio_device, |balloon| {
+            balloon.process_free_page_hint_queue()?;
+            balloon.finish_free_page_hinting()
+        });
+        wait_result?;
+        pause_result?;
+        let dirty_bitmap = dirty_result?;
+
+        Ok(memory_snapshot::remove_dirty_pages(
+            &self.guest_memory,
+            &finish_result?,
+            &dirty_bitmap,
+            page_size,
+        ))
+    }
+
+    // Starts tracking the pages the guest writes while it hints its free pages.
+    fn start_hinted_pages_tracking(
+        &mut self,
+        track_dirty_pages: bool,
+        page_size: usize,
+    ) -> Result<()> {
+        if track_dirty_pages {
+            let dirty_bitmap = self.get_dirty_bitmap()?;
+            memory_snapshot::mark_dirty_pages(&self.guest_memory, &dirty_bitmap, page_size);
+            Ok(())
+        } else {
+            self.set_dirty_page_tracking(true)
+        }
+    }
+
+    // Returns the pages the guest wrote while it hinted its free pages, and restores the dirty
+    // page tracking of the guest memory.
+    fn stop_hinted_pages_tracking(
+        &mut self,
+        track_dirty_pages: bool,
+        page_size: usize,
+    ) -> Result<DirtyBitmap> {
+        let dirty_bitmap = self.get_dirty_bitmap();
+        if track_dirty_pages {
+            if let Ok(dirty_bitmap) = &dirty_bitmap {
+                memory_snapshot::mark_dirty_pages(&self.guest_memory, dirty_bitmap, page_size);
+            }
+        } else {
+            self.set_dirty_page_tracking(false)?;
+        }
+        dirty_bitmap
+    }
+
+    /// Returns the configuration of the virtio-mem device if present.
+    pub fn memory_hotplug_config(&self) -> std::result::Result<VirtioMemConfig, MemError> {
+        self.with_virtio_mem(|virtio_mem| Ok(virtio_mem.config()))
+    }
+
+    /// Asks the guest to plug `requested_size_mib` MiB of the hot-pluggable memory.
+    pub fn update_memory_hotplug_size(
+        &mut self,
+        requested_size_mib: usize,
+    ) -> std::result::Result<(), MemError> {
+        self.with_virtio_mem(|virtio_mem| {
+            virtio_mem.update_requested_size((requested_size_mib as u64) << 20)
+        })
+    }
+
+    /// Leaves the blocks of hot-pluggable memory which are not plugged by the guest out of the
+    /// guest physical address space. Does nothing without a virtio-mem device.
+    pub(crate) fn setup_hotplug_memory_slots(&mut self, track_dirty_pages: bool) -> Result<()> {
+        let config = match self.memory_hotplug_config() {
+            Ok(config) => config,
+            Err(MemError::DeviceNotFound) => return Ok(()),
+            Err(err) => return Err(Error::VirtioMem(err)),
+        };
+        let mapper = self
+            .vm
+            .hotplug_block_mapper(
+                &self.guest_memory,
+                GuestAddress(config.addr),
+                config.block_size,
+                track_dirty_pages,
+            )
+            .map_err(Error::Vm)?;
+        self.with_virtio_mem(|virtio_mem| virtio_mem.set_block_mapper(Box::new(mapper)))
+            .map_err(Error::VirtioMem)
+    }
+
+    /// Returns the ranges of hot-pluggable memory which are not plugged by the guest.
+    pub fn unplugged_memory_ranges(&self) -> Vec<(GuestAddress, u64)> {
+        self.with_virtio_mem(|virtio_mem| Ok(virtio_mem.unplugged_ranges()))
+            .unwrap_or_default()
+    }
+
+    fn with_virtio_mem<T, F>(&self, f: F) -> std::result::Result<T, MemError>
+    where
+        F: FnOnce(&mut VirtioMem) -> std::result::Result<T, MemError>,
+    {
+        let virtio_device = self
+            .get_bus_device(DeviceType::Virtio(TYPE_MEM), MEM_DEV_ID)
+            .ok_or(MemError::DeviceNotFound)?
+            .lock()
+            .expect("Poisoned lock")
+            .as_any()
+            .downcast_ref::<MmioTransport>()
+            // Only MmioTransport implements BusDevice at this point.
+            .expect("Unexpected BusDevice type")
+            .device();
+
+        let mut virtio_device = virtio_device.lock().expect("Poisoned lock");
+        f(virtio_device
+            .as_mut_any()
+            .downcast_mut::<VirtioMem>()
+            .unwrap())
+    }
+
+    /// Returns the connections of the vsock device.
+    pub fn vsock_connections(
+        &self,
+    ) -> std::result::Result<Vec<VsockConnectionInfo>, VsockConfigError> {
+        let virtio_device = self.vsock_device()?;
+        let virtio_device = virtio_device.lock().expect("Poisoned lock");
+        let vsock = virtio_device.as_any();
+        if vsock.is::<Vsock<VsockUnixBackend>>() {
+            Ok(vsock
+                .downcast_ref::<Vsock<VsockUnixBackend>>()
+                .unwrap()
+                .connections())
+        } else {
+            // VsockTcpBackend is the only other implementation of VsockBackend.
+            Ok(vsock
+                .downcast_ref::<Vsock<VsockTcpBackend>>()
+                .unwrap()
+                .connections())
+        }
+    }
+
+    /// Forcefully terminates the vsock connection between the host `local_port` and the guest
+    /// `peer_port`.
+    pub fn reset_vsock_connection(
+        &mut self,
+        local_port: u32,
+        peer_port: u32,
+    ) -> std::result::Result<(), VsockConfigError> {
+        let virtio_device = self.vsock_device()?;
+        let mut virtio_device = virtio_device.lock().expect("Poisoned lock");
+        let vsock = virtio_device.as_mut_any();
+        let found = if vsock.is::<Vsock<VsockUnixBackend>>() {
+            vsock
+                .downcast_mut::<Vsock<VsockUnixBackend>>()
+                .unwrap()
+                .reset_connection(local_port, peer_port)
+        } else {
+            // VsockTcpBackend is the only other implementation of VsockBackend.
+            vsock
+                .downcast_mut::<Vsock<VsockTcpBackend>>()
+                .unwrap()
+                .reset_connection(local_port, peer_port)
+        };
+        if !found {
+            return Err(VsockConfigError::UnknownConnection(local_port, peer_port));
+        }
+        Ok(())
+    }
+
+    /// Updates the rate limiter parameters of the vsock device.
+    pub fn update_vsock_rate_limiter(
+        &mut self,
+        rl_bytes: BucketUpdate,
+        rl_ops: BucketUpdate,
+    ) -> std::result::Result<(), VsockConfigError> {
+        let virtio_device = self.vsock_device()?;
+        let mut virtio_device = virtio_device.lock().expect("Poisoned lock");
+        let vsock = virtio_device.as_mut_any();
+        if vsock.is::<Vsock<VsockUnixBackend>>() {
+            vsock
+                .downcast_mut::<Vsock<VsockUnixBackend>>()
+                .unwrap()
+                .update_rate_limiter(rl_bytes, rl_ops);
+        } else {
+            // VsockTcpBackend is the only other implementation of VsockBackend.
+            vsock
+                .downcast_mut::<Vsock<VsockTcpBackend>>()
+                .unwrap()
+                .update_rate_limiter(rl_bytes, rl_ops);
+        }
+        Ok(())
+    }
+
+    fn vsock_device(&self) -> std::result::Result<Arc<Mutex<dyn VirtioDevice>>, VsockConfigError> {
+        Ok(self
+            .get_bus_device(DeviceType::Virtio(TYPE_VSOCK), VSOCK_DEV_ID)
+            .ok_or(VsockConfigError::DeviceNotFound)?
+            .lock()
+            .expect("Poisoned lock")
+            .as_any()
+            .downcast_ref::<MmioTransport>()
+            // Only MmioTransport implements BusDevice at this point.
+            .expect("Unexpected BusDevice type")
+            .device())
+    }
+
     /// Signals Vmm to stop and exit.
     pub fn stop(&mut self, exit_code: FcExitCode) {
         // To avoid cycles, all teardown paths take the following route:
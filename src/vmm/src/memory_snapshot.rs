@@ -4,14 +4,14 @@
 //! Defines functionality for creating guest memory snapshots.
 
 use std::fs::File;
-use std::io::SeekFrom;
+use std::io::{Seek, SeekFrom};
 
 use utils::{errno, get_page_size};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{
     Bitmap, Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
-    GuestMemoryRegion, MemoryRegionAddress,
+    GuestMemoryRegion, HugePageSize, MemoryRegionAddress,
 };
 
 use crate::DirtyBitmap;
@@ -53,12 +53,20 @@ where
         writer: &mut T,
         dirty_bitmap: &DirtyBitmap,
     ) -> std::result::Result<(), Error>;
+    /// Dumps all contents of GuestMemoryMmap to a writer, except for the guest ranges in
+    /// `holes`, which are skipped over.
+    fn dump_with_holes<T: std::io::Write + std::io::Seek>(
+        &self,
+        writer: &mut T,
+        holes: &[(GuestAddress, u64)],
+    ) -> std::result::Result<(), Error>;
     /// Creates a GuestMemoryMmap given a `file` containing the data
     /// and a `state` containing mapping information.
     fn restore(
         file: Option<&File>,
         state: &GuestMemoryState,
         track_dirty_pages: bool,
+        huge_pages: Option<HugePageSize>,
     ) -> std::result::Result<Self, Error>;
 }
 
@@ -80,6 +88,9 @@ pub enum Error {
     /// Cannot dump memory.
     #[error("Cannot dump memory: {0:?}")]
     WriteMemory(#[from] GuestMemoryError),
+    /// Cannot load memory.
+    #[error("Cannot load memory: {0:?}")]
+    ReadMemory(GuestMemoryError),
 }
 
 impl SnapshotMemory for GuestMemoryMmap {
@@ -169,25 +180,173 @@ impl SnapshotMemory for GuestMemoryMmap {
             .map_err(Error::WriteMemory)
     }
 
+    /// Dumps all contents of GuestMemoryMmap to a writer, except for the guest ranges in
+    /// `holes`, which are skipped over.
+    fn dump_with_holes<T: std::io::Write + std::io::Seek>(
+        &self,
+        writer: &mut T,
+        holes: &[(GuestAddress, u64)],
+    ) -> std::result::Result<(), Error> {
+        let mut holes = holes.to_vec();
+        holes.sort_unstable_by_key(|(addr, _)| addr.0);
+        let mut writer_offset = 0;
+
+        for region in self.iter() {
+            let region_start = region.start_addr().0;
+            let region_end = region_start + region.len();
+            // The offset in the region up to which the contents were handled.
+            let mut offset = 0;
+
+            let region_holes = holes.iter().filter_map(|(addr, len)| {
+                let start = addr.0.max(region_start);
+                let end = addr.0.saturating_add(*len).min(region_end);
+                if start < end {
+                    Some((start - region_start, end - region_start))
+                } else {
+                    None
+                }
+            });
+            for (hole_start, hole_end) in region_holes {
+                if hole_start > offset {
+                    writer.seek(SeekFrom::Start(writer_offset + offset))?;
+                    region.write_all_to(
+                        MemoryRegionAddress(offset),
+                        writer,
+                        (hole_start - offset) as usize,
+                    )?;
+                }
+                offset = offset.max(hole_end);
+            }
+            if offset < region.len() {
+                writer.seek(SeekFrom::Start(writer_offset + offset))?;
+                region.write_all_to(
+                    MemoryRegionAddress(offset),
+                    writer,
+                    (region.len() - offset) as usize,
+                )?;
+            }
+
+            writer_offset += region.len();
+        }
+
+        Ok(())
+    }
+
     /// Creates a GuestMemoryMmap backed by a `file` if present, otherwise backed
     /// by anonymous memory. Memory layout and ranges are described in `state` param.
+    ///
+    /// Huge pages can't be mapped from a regular file, so memory backed by huge pages is
+    /// anonymous, and the contents of `file` are copied into it instead.
     fn restore(
         file: Option<&File>,
         state: &GuestMemoryState,
         track_dirty_pages: bool,
+        huge_pages: Option<HugePageSize>,
     ) -> std::result::Result<Self, Error> {
         let mut regions = vec![];
         for region in state.regions.iter() {
             let f = match file {
-                Some(f) => Some(FileOffset::new(f.try_clone()?, region.offset)),
-                None => None,
+                Some(f) if huge_pages.is_none() => {
+                    Some(FileOffset::new(f.try_clone()?, region.offset))
+                }
+                _ => None,
             };
 
             regions.push((f, GuestAddress(region.base_address), region.size));
         }
 
-        vm_memory::create_guest_memory(&regions, track_dirty_pages).map_err(Error::CreateMemory)
+        let guest_memory = vm_memory::create_guest_memory(&regions, track_dirty_pages, huge_pages)
+            .map_err(Error::CreateMemory)?;
+
+        if let (Some(file), Some(_)) = (file, huge_pages) {
+            for (region, region_state) in guest_memory.iter().zip(state.regions.iter()) {
+                let mut reader = file.try_clone()?;
+                reader.seek(SeekFrom::Start(region_state.offset))?;
+                region
+                    .read_exact_from(MemoryRegionAddress(0), &mut reader, region_state.size)
+                    .map_err(Error::ReadMemory)?;
+                // Loading the snapshot doesn't make the memory dirty.
+                if let Some(bitmap) = region.bitmap() {
+                    bitmap.reset();
+                }
+            }
+        }
+
+        Ok(guest_memory)
+    }
+}
+
+// Returns whether KVM reports the page at `addr` as dirty in `dirty_bitmap`.
+fn is_page_dirty(
+    guest_memory: &GuestMemoryMmap,
+    dirty_bitmap: &DirtyBitmap,
+    page_size: u64,
+    addr: u64,
+) -> bool {
+    guest_memory.iter().enumerate().any(|(slot, region)| {
+        let region_start = region.start_addr().0;
+        if addr < region_start || addr - region_start >= region.len() {
+            return false;
+        }
+        let page = ((addr - region_start) / page_size) as usize;
+        dirty_bitmap
+            .get(&slot)
+            .and_then(|kvm_bitmap| kvm_bitmap.get(page / 64))
+            .map_or(false, |v| (v >> (page % 64)) & 1 != 0)
+    })
+}
+
+/// Marks the pages KVM reports in `dirty_bitmap` as dirty in the bitmaps of `guest_memory`,
+/// so that a later diff snapshot still dumps them.
+pub(crate) fn mark_dirty_pages(
+    guest_memory: &GuestMemoryMmap,
+    dirty_bitmap: &DirtyBitmap,
+    page_size: usize,
+) {
+    for (slot, region) in guest_memory.iter().enumerate() {
+        let kvm_bitmap = match dirty_bitmap.get(&slot) {
+            Some(kvm_bitmap) => kvm_bitmap,
+            None => continue,
+        };
+        for (i, v) in kvm_bitmap.iter().enumerate() {
+            for j in (0..64).filter(|j| (v >> j) & 1 != 0) {
+                region
+                    .bitmap()
+                    .mark_dirty((i * 64 + j) * page_size, page_size);
+            }
+        }
+    }
+}
+
+/// Returns the guest memory `ranges` without the pages KVM reports in `dirty_bitmap`.
+pub(crate) fn remove_dirty_pages(
+    guest_memory: &GuestMemoryMmap,
+    ranges: &[(GuestAddress, u64)],
+    dirty_bitmap: &DirtyBitmap,
+    page_size: usize,
+) -> Vec<(GuestAddress, u64)> {
+    let page_size = page_size as u64;
+    let mut clean_ranges = Vec::new();
+    for (addr, len) in ranges {
+        let end = addr.0.saturating_add(*len);
+        // The start of the range of clean pages being built.
+        let mut clean_start = addr.0;
+        let mut page_start = addr.0;
+        while page_start < end {
+            let page_end = ((page_start / page_size + 1) * page_size).min(end);
+            if is_page_dirty(guest_memory, dirty_bitmap, page_size, page_start) {
+                if clean_start < page_start {
+                    clean_ranges.push((GuestAddress(clean_start), page_start - clean_start));
+                }
+                clean_start = page_end;
+            }
+            page_start = page_end;
+        }
+        if clean_start < end {
+            clean_ranges.push((GuestAddress(clean_start), end - clean_start));
+        }
     }
+    clean_ranges
 }
 
 #[cfg(test)]
@@ -210,7 +369,7 @@ mod tests {
             (None, GuestAddress(0), page_size),
             (None, GuestAddress(page_size as u64 * 2), page_size),
         ];
-        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true).unwrap();
+        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true, None).unwrap();
 
         let expected_memory_state = GuestMemoryState {
             regions: vec![
@@ -235,7 +394,7 @@ mod tests {
             (None, GuestAddress(0), page_size * 3),
             (None, GuestAddress(page_size as u64 * 4), page_size * 3),
         ];
-        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true).unwrap();
+        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true, None).unwrap();
 
         let expected_memory_state = GuestMemoryState {
             regions: vec![
@@ -265,7 +424,7 @@ mod tests {
             (None, GuestAddress(0), page_size * 2),
             (None, GuestAddress(page_size as u64 * 3), page_size * 2),
         ];
-        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true).unwrap();
+        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true, None).unwrap();
         // Check that Firecracker bitmap is clean.
         let _res: std::result::Result<(), Error> = guest_memory.iter().try_for_each(|r| {
             assert!(!r.bitmap().dirty_at(0));
@@ -292,7 +451,7 @@ mod tests {
             guest_memory.dump(&mut memory_file.as_file()).unwrap();
 
             let restored_guest_memory =
-                GuestMemoryMmap::restore(Some(memory_file.as_file()), &memory_state, false)
+                GuestMemoryMmap::restore(Some(memory_file.as_file()), &memory_state, false, None)
                     .unwrap();
 
             // Check that the region contents are the same.
@@ -327,7 +486,7 @@ mod tests {
 
             // We can restore from this because this is the first dirty dump.
             let restored_guest_memory =
-                GuestMemoryMmap::restore(Some(file.as_file()), &memory_state, false).unwrap();
+                GuestMemoryMmap::restore(Some(file.as_file()), &memory_state, false, None).unwrap();
 
             // Check that the region contents are the same.
             let mut actual_region = vec![0u8; page_size * 2];
@@ -373,5 +532,82 @@ mod tests {
             reader.read_to_end(&mut diff_file_content).unwrap();
             assert_eq!(expected_first_region, diff_file_content);
         }
+
+        // Case 3: dump the memory without the hinted free pages.
+        {
+            let file = TempFile::new().unwrap();
+            let mut reader = file.as_file();
+            reader.set_len(page_size as u64 * 4).unwrap();
+            let zeros = vec![0u8; page_size];
+            let ones = vec![1u8; page_size];
+            let twos = vec![2u8; page_size];
+
+            // The second page of the first region was written with 2s above. The holes overlap in
+            // the gap between the regions, and cover a page of each region.
+            let holes = [
+                (GuestAddress(page_size as u64 * 2), page_size as u64 * 2),
+                (GuestAddress(page_size as u64), page_size as u64 * 2),
+            ];
+            guest_memory.dump_with_holes(&mut reader, &holes).unwrap();
+
+            let mut file_content = Vec::new();
+            let expected_content = [
+                ones.as_slice(),
+                zeros.as_slice(),
+                zeros.as_slice(),
+                twos.as_slice(),
+            ]
+            .concat();
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            reader.read_to_end(&mut file_content).unwrap();
+            assert_eq!(expected_content, file_content);
+        }
+    }
+
+    #[test]
+    fn test_dirty_pages_in_ranges() {
+        let page_size: usize = get_page_size().unwrap();
+
+        // Two regions of two pages each, with a one page gap between them.
+        let mem_regions = [
+            (None, GuestAddress(0), page_size * 2),
+            (None, GuestAddress(page_size as u64 * 3), page_size * 2),
+        ];
+        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true, None).unwrap();
+
+        // The second page of the first region and the first page of the second one are dirty.
+        let mut dirty_bitmap: DirtyBitmap = HashMap::new();
+        dirty_bitmap.insert(0, vec![0b10]);
+        dirty_bitmap.insert(1, vec![0b01]);
+
+        let page_size = page_size as u64;
+        let ranges = [
+            (GuestAddress(0), page_size * 5),
+            (GuestAddress(page_size * 4 + 1), 2),
+        ];
+        assert_eq!(
+            remove_dirty_pages(&guest_memory, &ranges, &dirty_bitmap, page_size as usize),
+            vec![
+                (GuestAddress(0), page_size),
+                (GuestAddress(page_size * 2), page_size),
+                (GuestAddress(page_size * 4), page_size),
+                (GuestAddress(page_size * 4 + 1), 2),
+            ]
+        );
+        assert!(remove_dirty_pages(
+            &guest_memory,
+            &[(GuestAddress(page_size), page_size)],
+            &dirty_bitmap,
+            page_size as usize
+        )
+        .is_empty());
+
+        mark_dirty_pages(&guest_memory, &dirty_bitmap, page_size as usize);
+        let first_region = guest_memory.iter().next().unwrap();
+        assert!(!first_region.bitmap().dirty_at(0));
+        assert!(first_region.bitmap().dirty_at(page_size as usize));
+        let second_region = guest_memory.iter().nth(1).unwrap();
+        assert!(second_region.bitmap().dirty_at(0));
+        assert!(!second_region.bitmap().dirty_at(page_size as usize));
     }
 }
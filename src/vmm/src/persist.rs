@@ -5,17 +5,23 @@
 
 use std::fmt::{Display, Formatter};
 use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[cfg(target_arch = "aarch64")]
 use arch::regs::{get_manufacturer_id_from_host, get_manufacturer_id_from_state};
 #[cfg(target_arch = "x86_64")]
 use cpuid::common::{get_vendor_id_from_cpuid, get_vendor_id_from_host};
-use devices::virtio::TYPE_NET;
+use devices::virtio::vsock::persist::VsockBackendState;
+use devices::virtio::{
+    VhostUserBlock, Vsock, VsockBackend, VsockTcpBackend, VsockUnixBackend, TYPE_BLOCK, TYPE_NET,
+    TYPE_VSOCK,
+};
 use logger::{error, info, warn};
 use seccompiler::BpfThreadMap;
 use serde::Serialize;
@@ -25,7 +31,9 @@ use utils::sock_ctrl_msg::ScmSocket;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
-use vm_memory::{GuestMemory, GuestMemoryMmap};
+use vm_memory::{
+    Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, MemoryRegionAddress,
+};
 
 use crate::builder::{self, BuildMicrovmFromSnapshotError};
 use crate::device_manager::persist::{DeviceStates, Error as DevicePersistError};
@@ -33,12 +41,14 @@ use crate::memory_snapshot::{GuestMemoryState, SnapshotMemory};
 use crate::resources::VmResources;
 #[cfg(target_arch = "x86_64")]
 use crate::version_map::FC_V0_23_SNAP_VERSION;
-use crate::version_map::{FC_V1_0_SNAP_VERSION, FC_V1_1_SNAP_VERSION, FC_VERSION_TO_SNAP_VERSION};
+use crate::version_map::{
+    FC_V1_0_SNAP_VERSION, FC_V1_1_SNAP_VERSION, FC_V1_2_SNAP_VERSION, FC_VERSION_TO_SNAP_VERSION,
+};
 use crate::vmm_config::boot_source::BootSourceConfig;
 use crate::vmm_config::instance_info::InstanceInfo;
-use crate::vmm_config::machine_config::{CpuFeaturesTemplate, MAX_SUPPORTED_VCPUS};
+use crate::vmm_config::machine_config::{CpuFeaturesTemplate, HugePageConfig, MAX_SUPPORTED_VCPUS};
 use crate::vmm_config::snapshot::{
-    CreateSnapshotParams, LoadSnapshotParams, MemBackendType, SnapshotType,
+    CreateSnapshotParams, LoadSnapshotParams, MemBackendType, SnapshotType, VsockOverride,
 };
 use crate::vstate::vcpu::{VcpuSendEventError, VcpuState};
 use crate::vstate::vm::VmState;
@@ -47,6 +57,9 @@ use crate::{mem_size_mib, memory_snapshot, vstate, Error as VmmError, EventManag
 #[cfg(target_arch = "x86_64")]
 const FC_V0_23_MAX_DEVICES: u32 = 11;
 
+// How long the guest is given to hint its free pages before a full snapshot is created.
+const FREE_PAGE_HINTING_TIMEOUT: Duration = Duration::from_secs(2);
+
 /// Holds information related to the VM that is not part of VmState.
 #[derive(Clone, Debug, Default, PartialEq, Eq, Versionize, Serialize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
@@ -66,6 +79,9 @@ pub struct VmInfo {
     /// Boot source information.
     #[version(start = 2, default_fn = "def_boot_source", ser_fn = "ser_boot_source")]
     pub boot_source: BootSourceConfig,
+    /// Huge pages backing the guest memory.
+    #[version(start = 2, default_fn = "def_huge_pages", ser_fn = "ser_huge_pages")]
+    pub huge_pages: HugePageConfig,
 }
 
 impl VmInfo {
@@ -101,6 +117,19 @@ impl VmInfo {
         warn!("Saving to older snapshot version, boot source information will not be saved.");
         Ok(())
     }
+
+    fn def_huge_pages(_: u16) -> HugePageConfig {
+        // Older versions do not support huge pages.
+        HugePageConfig::None
+    }
+
+    fn ser_huge_pages(&mut self, _target_version: u16) -> VersionizeResult<()> {
+        // v1.1 and older versions do not include huge pages info.
+        if self.huge_pages != HugePageConfig::None {
+            warn!("Saving to older snapshot version, huge pages information will not be saved.");
+        }
+        Ok(())
+    }
 }
 
 /// Contains the necesary state for saving/restoring a microVM.
@@ -136,6 +165,9 @@ pub struct GuestRegionUffdMapping {
     pub size: usize,
     /// Offset in the backend file/buffer where the region contents are.
     pub offset: u64,
+    /// Size of the pages backing the region, in KiB. The region must be
+    /// populated one whole page at a time.
+    pub page_size_kib: usize,
 }
 
 /// Errors related to saving and restoring Microvm state.
@@ -178,6 +210,8 @@ pub enum MicrovmStateError {
 pub enum CreateSnapshotError {
     /// Failed to get dirty bitmap.
     DirtyBitmap(VmmError),
+    /// Failed to collect the free page hints of the guest.
+    FreePageHints(VmmError),
     /// The virtio devices uses a features that is incompatible with older versions of Firecracker.
     IncompatibleVirtioFeature(&'static str),
     /// Invalid microVM version format
@@ -197,6 +231,9 @@ pub enum CreateSnapshotError {
     #[cfg(target_arch = "x86_64")]
     /// Number of devices exceeds the maximum supported devices for the snapshot data version.
     TooManyDevices(usize),
+    /// The device with the given id is served by a vhost-user backend, whose state can't be
+    /// saved.
+    VhostUserDevice(String),
 }
 
 impl Display for CreateSnapshotError {
@@ -204,6 +241,7 @@ impl Display for CreateSnapshotError {
         use self::CreateSnapshotError::*;
         match self {
             DirtyBitmap(err) => write!(f, "Cannot get dirty bitmap: {}", err),
+            FreePageHints(err) => write!(f, "Cannot collect the free page hints: {}", err),
             IncompatibleVirtioFeature(feature) => write!(
                 f,
                 "The virtio devices use a features that is incompatible with older versions of \
@@ -237,6 +275,11 @@ impl Display for CreateSnapshotError {
                  version requested is {}.",
                 val, FC_V0_23_MAX_DEVICES
             ),
+            VhostUserDevice(id) => write!(
+                f,
+                "Cannot snapshot the vhost-user device {}: its state is held by the backend.",
+                id
+            ),
         }
     }
 }
@@ -250,6 +293,26 @@ pub fn create_snapshot(
 ) -> std::result::Result<(), CreateSnapshotError> {
     // Fail early from invalid target version.
     let snapshot_data_version = get_snapshot_data_version(&params.version, &version_map, vmm)?;
+    validate_no_vhost_user_devices(vmm)?;
+
+    // The unplugged hot-pluggable memory, and the free pages when the request asks for them,
+    // are left out of a full snapshot, unless the memory is already in the file. Collecting
+    // the free pages runs the vCPUs of the paused microVM for a while, hence the opt-in.
+    let holes = match params.snapshot_type {
+        SnapshotType::Full
+            if shared_memory_file(vmm.guest_memory(), &params.mem_file_path).is_none() =>
+        {
+            let mut holes = if params.free_page_hinting {
+                vmm.collect_free_page_hints(FREE_PAGE_HINTING_TIMEOUT)
+                    .map_err(CreateSnapshotError::FreePageHints)?
+            } else {
+                Vec::new()
+            };
+            holes.extend(vmm.unplugged_memory_ranges());
+            holes
+        }
+        _ => Vec::new(),
+    };
 
     let microvm_state = vmm
         .save_state(vm_info)
@@ -262,7 +325,7 @@ pub fn create_snapshot(
         version_map,
     )?;
 
-    snapshot_memory_to_file(vmm, &params.mem_file_path, &params.snapshot_type)?;
+    snapshot_memory_to_file(vmm, &params.mem_file_path, &params.snapshot_type, &holes)?;
 
     Ok(())
 }
@@ -296,8 +359,26 @@ fn snapshot_memory_to_file(
     vmm: &Vmm,
     mem_file_path: &Path,
     snapshot_type: &SnapshotType,
+    holes: &[(GuestAddress, u64)],
 ) -> std::result::Result<(), CreateSnapshotError> {
     use self::CreateSnapshotError::*;
+
+    // The guest memory shared through the memory file is already in it, so only the dirty pages
+    // have to be cleared for the next diff snapshot.
+    if let Some(file) = shared_memory_file(vmm.guest_memory(), mem_file_path) {
+        if let SnapshotType::Diff = snapshot_type {
+            vmm.get_dirty_bitmap().map_err(DirtyBitmap)?;
+            vmm.guest_memory().iter().for_each(|region| {
+                if let Some(bitmap) = region.bitmap() {
+                    bitmap.reset();
+                }
+            });
+        }
+        return file
+            .sync_all()
+            .map_err(|err| MemoryBackingFile("sync_all", err));
+    }
+
     let mut file = OpenOptions::new()
         .write(true)
         .create(true)
@@ -317,7 +398,14 @@ fn snapshot_memory_to_file(
                 .dump_dirty(&mut file, &dirty_bitmap)
                 .map_err(Memory)
         }
-        SnapshotType::Full => vmm.guest_memory().dump(&mut file).map_err(Memory),
+        SnapshotType::Full if holes.is_empty() => {
+            vmm.guest_memory().dump(&mut file).map_err(Memory)
+        }
+        // The file is already as large as the memory, so the skipped ranges are left as holes.
+        SnapshotType::Full => vmm
+            .guest_memory()
+            .dump_with_holes(&mut file, holes)
+            .map_err(Memory),
     }?;
     file.flush()
         .map_err(|err| MemoryBackingFile("flush", err))?;
@@ -325,6 +413,21 @@ fn snapshot_memory_to_file(
         .map_err(|err| MemoryBackingFile("sync_all", err))
 }
 
+// Returns the file backing the guest memory, if it is shared and lives at `path`.
+fn shared_memory_file<'a>(guest_memory: &'a GuestMemoryMmap, path: &Path) -> Option<&'a File> {
+    let region = guest_memory.iter().next()?;
+    if region.flags() & libc::MAP_SHARED == 0 {
+        return None;
+    }
+
+    let file = region.file_offset()?.file();
+    if is_same_file(file, path) {
+        Some(file)
+    } else {
+        None
+    }
+}
+
 /// Validate the microVM version and translate it to its corresponding snapshot data format.
 pub fn get_snapshot_data_version(
     maybe_fc_version: &Option<String>,
@@ -364,6 +467,33 @@ pub fn get_snapshot_data_version(
             })?;
     }
 
+    if data_version < FC_V1_2_SNAP_VERSION {
+        vmm.mmio_device_manager
+            .for_each_virtio_device(|virtio_type, _id, _info, dev| {
+                if virtio_type != TYPE_VSOCK {
+                    return Ok(());
+                }
+                let locked_dev = dev.lock().expect("Poisoned lock");
+                // Versions older than v1.2 only know about the Unix backend of vsock.
+                if locked_dev.as_any().is::<Vsock<VsockTcpBackend>>() {
+                    return Err(CreateSnapshotError::IncompatibleVirtioFeature(
+                        "vsock TCP backend",
+                    ));
+                }
+                // They don't keep the vsock connections alive either.
+                if locked_dev
+                    .as_any()
+                    .downcast_ref::<Vsock<VsockUnixBackend>>()
+                    .map_or(false, |vsock| vsock.backend().persists_connections())
+                {
+                    return Err(CreateSnapshotError::IncompatibleVirtioFeature(
+                        "vsock connection persistence",
+                    ));
+                }
+                Ok(())
+            })?;
+    }
+
     Ok(data_version)
 }
 
@@ -514,6 +644,15 @@ pub enum RestoreFromSnapshotError {
     /// Failed build micro-VM from snapshot.
     #[error("Failed build micro-VM from snapshot: {0}")]
     Build(#[from] BuildMicrovmFromSnapshotError),
+    /// The vsock override doesn't apply to the snapshot.
+    #[error("The snapshot has no vsock device with Unix socket backend to override.")]
+    VsockOverride,
+    /// The guest memory served by a page fault handler can't be shared.
+    #[error("The guest memory can only be shared with the File memory backend.")]
+    SharedMemoryBackend,
+    /// Failed to share the guest memory.
+    #[error("Failed to share the guest memory: {0}")]
+    ShareGuestMemory(#[from] ShareGuestMemoryError),
 }
 /// Sub-Error type for [`restore_from_snapshot`] to contain either [`GuestMemoryFromFileError`] or
 /// [`GuestMemoryFromUffdError`] within [`RestoreFromSnapshotError`].
@@ -536,31 +675,58 @@ pub fn restore_from_snapshot(
     version_map: VersionMap,
     vm_resources: &mut VmResources,
 ) -> std::result::Result<Arc<Mutex<Vmm>>, RestoreFromSnapshotError> {
-    let microvm_state = snapshot_state_from_file(&params.snapshot_path, version_map)?;
+    let mut microvm_state = snapshot_state_from_file(&params.snapshot_path, version_map)?;
 
     // Some sanity checks before building the microvm.
     snapshot_state_sanity_check(&microvm_state)?;
 
+    if let Some(vsock_override) = &params.vsock_override {
+        override_vsock(&mut microvm_state, vsock_override)?;
+    }
+
     let mem_backend_path = &params.mem_backend.backend_path;
     let mem_state = &microvm_state.memory_state;
     let track_dirty_pages = params.enable_diff_snapshots;
+    let huge_pages = microvm_state.vm_info.huge_pages;
 
-    let (guest_memory, uffd) = match params.mem_backend.backend_type {
-        MemBackendType::File => (
-            guest_memory_from_file(mem_backend_path, mem_state, track_dirty_pages)
+    let (guest_memory, uffd) = match (&params.mem_backend.backend_type, &params.shared_memory) {
+        (MemBackendType::File, None) => (
+            guest_memory_from_file(mem_backend_path, mem_state, track_dirty_pages, huge_pages)
                 .map_err(RestoreFromSnapshotGuestMemoryError::File)?,
             None,
         ),
-        MemBackendType::Uffd => guest_memory_from_uffd(
+        (MemBackendType::File, Some(shared_memory)) => (
+            shared_guest_memory_from_file(
+                mem_backend_path,
+                mem_state,
+                track_dirty_pages,
+                huge_pages,
+                shared_memory.mem_file_path.as_deref(),
+            )
+            .map_err(RestoreFromSnapshotGuestMemoryError::File)?,
+            None,
+        ),
+        (MemBackendType::Uffd, Some(_)) => {
+            return Err(RestoreFromSnapshotError::SharedMemoryBackend)
+        }
+        (MemBackendType::Uffd, None) => guest_memory_from_uffd(
             mem_backend_path,
             mem_state,
             track_dirty_pages,
             // We enable the UFFD_FEATURE_EVENT_REMOVE feature only if a balloon device
             // is present in the microVM state.
             microvm_state.device_states.balloon_device.is_some(),
+            huge_pages,
         )
         .map_err(RestoreFromSnapshotGuestMemoryError::Uffd)?,
     };
+    if let Some(uds_path) = params
+        .shared_memory
+        .as_ref()
+        .and_then(|config| config.uds_path.as_ref())
+    {
+        share_guest_memory(uds_path, &guest_memory, huge_pages)?;
+    }
     builder::build_microvm_from_snapshot(
         instance_info,
         event_manager,
@@ -568,12 +734,32 @@ pub fn restore_from_snapshot(
         guest_memory,
         uffd,
         track_dirty_pages,
+        params.shared_memory.clone(),
         seccomp_filters,
         vm_resources,
     )
     .map_err(RestoreFromSnapshotError::Build)
 }
 
+/// Replaces the host-side configuration of the vsock device in the snapshot state.
+fn override_vsock(
+    microvm_state: &mut MicrovmState,
+    vsock_override: &VsockOverride,
+) -> std::result::Result<(), RestoreFromSnapshotError> {
+    let backend_state = microvm_state
+        .device_states
+        .vsock_device
+        .as_mut()
+        .map(|vsock_state| &mut vsock_state.device_state.backend);
+    match backend_state {
+        Some(VsockBackendState::Uds(uds_state)) => {
+            uds_state.set_path(vsock_override.uds_path.clone());
+            Ok(())
+        }
+        _ => Err(RestoreFromSnapshotError::VsockOverride),
+    }
+}
+
 /// Error type for [`snapshot_state_from_file`]
 #[derive(Debug, thiserror::Error)]
 pub enum SnapshotStateFromFileError {
@@ -615,12 +801,92 @@ fn guest_memory_from_file(
     mem_file_path: &Path,
     mem_state: &GuestMemoryState,
     track_dirty_pages: bool,
+    huge_pages: HugePageConfig,
 ) -> std::result::Result<GuestMemoryMmap, GuestMemoryFromFileError> {
     let mem_file = File::open(mem_file_path)?;
-    let guest_mem = GuestMemoryMmap::restore(Some(&mem_file), mem_state, track_dirty_pages)?;
+    let guest_mem = GuestMemoryMmap::restore(
+        Some(&mem_file),
+        mem_state,
+        track_dirty_pages,
+        huge_pages.page_size(),
+    )?;
     Ok(guest_mem)
 }
 
+// Restores the guest memory from the memory file at `mem_file_path` into shared memory, backed
+// by the file at `shared_file_path`, or by a memfd if missing. When the memory file itself
+// backs the shared memory, it is mapped in place and the guest writes to it.
+fn shared_guest_memory_from_file(
+    mem_file_path: &Path,
+    mem_state: &GuestMemoryState,
+    track_dirty_pages: bool,
+    huge_pages: HugePageConfig,
+    shared_file_path: Option<&Path>,
+) -> std::result::Result<GuestMemoryMmap, GuestMemoryFromFileError> {
+    let regions: Vec<_> = mem_state
+        .regions
+        .iter()
+        .map(|region| (GuestAddress(region.base_address), region.size))
+        .collect();
+    let mem_file = File::open(mem_file_path)?;
+
+    // The regions are laid out back to back in the memory file, as in the shared file, so the
+    // memory file can back the shared memory as is.
+    if let Some(path) = shared_file_path.filter(|path| is_same_file(&mem_file, path)) {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let guest_mem = vm_memory::create_file_guest_memory(
+            file,
+            &regions,
+            track_dirty_pages,
+            huge_pages.page_size(),
+        )
+        .map_err(memory_snapshot::Error::CreateMemory)?;
+        return Ok(guest_mem);
+    }
+
+    let guest_mem = match shared_file_path {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            vm_memory::create_file_guest_memory(
+                file,
+                &regions,
+                track_dirty_pages,
+                huge_pages.page_size(),
+            )
+        }
+        None => vm_memory::create_memfd_guest_memory(
+            &regions,
+            track_dirty_pages,
+            huge_pages.page_size(),
+        ),
+    }
+    .map_err(memory_snapshot::Error::CreateMemory)?;
+
+    for (region, region_state) in guest_mem.iter().zip(mem_state.regions.iter()) {
+        let mut reader = mem_file.try_clone()?;
+        reader.seek(SeekFrom::Start(region_state.offset))?;
+        region
+            .read_exact_from(MemoryRegionAddress(0), &mut reader, region_state.size)
+            .map_err(memory_snapshot::Error::ReadMemory)?;
+    }
+    Ok(guest_mem)
+}
+
+// Returns whether `file` is the file at `path`.
+fn is_same_file(file: &File, path: &Path) -> bool {
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(file_metadata), Ok(metadata)) => {
+            file_metadata.dev() == metadata.dev() && file_metadata.ino() == metadata.ino()
+        }
+        _ => false,
+    }
+}
+
 /// Error type for [`guest_memory_from_uffd`]
 #[derive(Debug, thiserror::Error)]
 pub enum GuestMemoryFromUffdError {
@@ -639,6 +905,70 @@ pub enum GuestMemoryFromUffdError {
     /// Failed to send file descriptor.
     #[error("Failed to sends file descriptor: {0}")]
     Send(#[from] utils::errno::Error),
+    /// Failed to get the page size.
+    #[error("Failed to get the page size: {0}")]
+    PageSize(utils::errno::Error),
+}
+
+/// Error type for [`share_guest_memory`].
+#[derive(Debug, thiserror::Error)]
+pub enum ShareGuestMemoryError {
+    /// The guest memory is not backed by a file.
+    #[error("The guest memory is not backed by a file.")]
+    NotFileBacked,
+    /// Failed to get the page size.
+    #[error("Failed to get the page size: {0}")]
+    PageSize(utils::errno::Error),
+    /// Failed to connect to UDS Unix stream.
+    #[error("Failed to connect to UDS Unix stream: {0}")]
+    Connect(#[from] std::io::Error),
+    /// Failed to send file descriptor.
+    #[error("Failed to send file descriptor: {0}")]
+    Send(#[from] utils::errno::Error),
+}
+
+/// Sends the file descriptor backing the shared `guest_memory` to the process listening on
+/// `uds_path`, along with the layout of the guest memory regions in the same format as for the
+/// page fault handlers. The other process can then map the guest memory in its own address space.
+pub fn share_guest_memory(
+    uds_path: &Path,
+    guest_memory: &GuestMemoryMmap,
+    huge_pages: HugePageConfig,
+) -> std::result::Result<(), ShareGuestMemoryError> {
+    let page_size = match huge_pages.page_size() {
+        Some(huge_page_size) => huge_page_size.bytes(),
+        None => utils::get_page_size().map_err(ShareGuestMemoryError::PageSize)?,
+    };
+
+    let mut mappings = Vec::with_capacity(guest_memory.num_regions());
+    for region in guest_memory.iter() {
+        let file_offset = region
+            .file_offset()
+            .ok_or(ShareGuestMemoryError::NotFileBacked)?;
+        mappings.push(GuestRegionUffdMapping {
+            base_host_virt_addr: region.as_ptr() as u64,
+            size: region.size(),
+            offset: file_offset.start(),
+            page_size_kib: page_size / 1024,
+        });
+    }
+    // All the regions are backed by the same file.
+    let fd = guest_memory
+        .iter()
+        .next()
+        .and_then(|region| region.file_offset())
+        .ok_or(ShareGuestMemoryError::NotFileBacked)?
+        .file()
+        .as_raw_fd();
+
+    // This is safe to unwrap() because we control the contents of the vector
+    // (i.e GuestRegionUffdMapping entries).
+    let mappings = serde_json::to_string(&mappings).unwrap();
+
+    let socket = UnixStream::connect(uds_path)?;
+    socket.send_with_fd(mappings.as_bytes(), fd)?;
+
+    Ok(())
 }
 
 fn guest_memory_from_uffd(
@@ -646,8 +976,14 @@ fn guest_memory_from_uffd(
     mem_state: &GuestMemoryState,
     track_dirty_pages: bool,
     enable_balloon: bool,
+    huge_pages: HugePageConfig,
 ) -> std::result::Result<(GuestMemoryMmap, Option<Uffd>), GuestMemoryFromUffdError> {
-    let guest_memory = GuestMemoryMmap::restore(None, mem_state, track_dirty_pages)?;
+    let guest_memory =
+        GuestMemoryMmap::restore(None, mem_state, track_dirty_pages, huge_pages.page_size())?;
+    let page_size = match huge_pages.page_size() {
+        Some(huge_page_size) => huge_page_size.bytes(),
+        None => utils::get_page_size().map_err(GuestMemoryFromUffdError::PageSize)?,
+    };
 
     let mut uffd_builder = UffdBuilder::new();
 
@@ -674,6 +1010,7 @@ fn guest_memory_from_uffd(
             base_host_virt_addr: host_base_addr as u64,
             size,
             offset: state_region.offset,
+            page_size_kib: page_size / 1024,
         });
     }
 
@@ -729,6 +1066,22 @@ fn validate_devices_number(device_number: usize) -> std::result::Result<(), Crea
     Ok(())
 }
 
+fn validate_no_vhost_user_devices(vmm: &Vmm) -> std::result::Result<(), CreateSnapshotError> {
+    vmm.mmio_device_manager
+        .for_each_virtio_device(|virtio_type, id, _info, dev| {
+            if virtio_type == TYPE_BLOCK
+                && dev
+                    .lock()
+                    .expect("Poisoned lock")
+                    .as_any()
+                    .is::<VhostUserBlock>()
+            {
+                return Err(CreateSnapshotError::VhostUserDevice(id.clone()));
+            }
+            Ok(())
+        })
+}
+
 fn validate_fc_version_format(version: &str) -> Result<(), CreateSnapshotError> {
     let v: Vec<_> = version.match_indices('.').collect();
     if v.len() != 2
@@ -761,8 +1114,8 @@ mod tests {
     use crate::version_map::{FC_VERSION_TO_SNAP_VERSION, VERSION_MAP};
     use crate::vmm_config::balloon::BalloonDeviceConfig;
     use crate::vmm_config::drive::CacheType;
-    use crate::vmm_config::net::NetworkInterfaceConfig;
-    use crate::vmm_config::vsock::tests::default_config;
+    use crate::vmm_config::net::{NetworkInterfaceConfig, Offloads};
+    use crate::vmm_config::vsock::tests::{default_config, tcp_config};
     use crate::Vmm;
 
     #[cfg(target_arch = "aarch64")]
@@ -778,6 +1131,9 @@ mod tests {
             amount_mib: 0,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            free_page_hinting: false,
+            free_page_reporting: false,
+            auto_size: None,
         };
         insert_balloon_device(&mut vmm, &mut cmdline, &mut event_manager, balloon_config);
 
@@ -799,6 +1155,10 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            offloads: Offloads::default(),
+            vhost_net: false,
+            anti_spoofing: None,
         };
         insert_net_device(
             &mut vmm,
@@ -911,6 +1271,86 @@ mod tests {
         assert!(get_snapshot_data_version(&Some("0.24.0".to_string()), &VERSION_MAP, &vmm).is_ok());
     }
 
+    #[test]
+    fn test_get_snapshot_data_version_tcp_vsock() {
+        let mut event_manager = EventManager::new().expect("Cannot create EventManager");
+        let mut vmm = default_vmm();
+        let mut cmdline = default_kernel_cmdline();
+        insert_vsock_device(&mut vmm, &mut cmdline, &mut event_manager, tcp_config());
+
+        assert!(get_snapshot_data_version(&Some("1.2.0".to_string()), &VERSION_MAP, &vmm).is_ok());
+        match get_snapshot_data_version(&Some("1.1.0".to_string()), &VERSION_MAP, &vmm) {
+            Err(CreateSnapshotError::IncompatibleVirtioFeature(feature)) => {
+                assert_eq!(feature, "vsock TCP backend")
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_get_snapshot_data_version_vsock_persist_connections() {
+        let mut event_manager = EventManager::new().expect("Cannot create EventManager");
+        let mut vmm = default_vmm();
+        let mut cmdline = default_kernel_cmdline();
+        let mut tmp_sock_file = TempFile::new().unwrap();
+        tmp_sock_file.remove().unwrap();
+        let mut vsock_config = default_config(&tmp_sock_file);
+        vsock_config.persist_connections = true;
+        insert_vsock_device(&mut vmm, &mut cmdline, &mut event_manager, vsock_config);
+
+        assert!(get_snapshot_data_version(&Some("1.2.0".to_string()), &VERSION_MAP, &vmm).is_ok());
+        match get_snapshot_data_version(&Some("1.1.0".to_string()), &VERSION_MAP, &vmm) {
+            Err(CreateSnapshotError::IncompatibleVirtioFeature(feature)) => {
+                assert_eq!(feature, "vsock connection persistence")
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_override_vsock() {
+        let vmm = default_vmm_with_devices();
+        let memory_state = vmm.guest_memory().describe();
+        let vcpu_states = vec![VcpuState::default()];
+        #[cfg(target_arch = "aarch64")]
+        let mpidrs = construct_kvm_mpidrs(&vcpu_states);
+        let mut microvm_state = MicrovmState {
+            device_states: vmm.mmio_device_manager.save(),
+            memory_state,
+            vcpu_states,
+            vm_info: VmInfo::default(),
+            #[cfg(target_arch = "aarch64")]
+            vm_state: vmm.vm.save_state(&mpidrs).unwrap(),
+            #[cfg(target_arch = "x86_64")]
+            vm_state: vmm.vm.save_state().unwrap(),
+        };
+        let vsock_override = VsockOverride {
+            uds_path: "clone.vsock".to_string(),
+        };
+
+        override_vsock(&mut microvm_state, &vsock_override).unwrap();
+        match &microvm_state
+            .device_states
+            .vsock_device
+            .as_ref()
+            .unwrap()
+            .device_state
+            .backend
+        {
+            VsockBackendState::Uds(uds_state) => {
+                assert_eq!(uds_state.path(), "clone.vsock")
+            }
+            VsockBackendState::Tcp(_) => unreachable!(),
+        }
+
+        // There's nothing to override without a vsock device.
+        microvm_state.device_states.vsock_device = None;
+        assert!(matches!(
+            override_vsock(&mut microvm_state, &vsock_override),
+            Err(RestoreFromSnapshotError::VsockOverride)
+        ));
+    }
+
     #[test]
     fn test_create_snapshot_error_display() {
         use vm_memory::GuestMemoryError;
@@ -920,6 +1360,11 @@ mod tests {
         let err = DirtyBitmap(VmmError::DirtyBitmap(kvm_ioctls::Error::new(20)));
         let _ = format!("{}{:?}", err, err);
 
+        let err = FreePageHints(VmmError::FreePageHints(
+            devices::virtio::balloon::Error::DeviceNotActive,
+        ));
+        let _ = format!("{}{:?}", err, err);
+
         let err = InvalidVersionFormat;
         let _ = format!("{}{:?}", err, err);
 
@@ -948,6 +1393,118 @@ mod tests {
             let err = TooManyDevices(0);
             let _ = format!("{}{:?}", err, err);
         }
+
+        let err = VhostUserDevice(String::from("drive"));
+        let _ = format!("{}{:?}", err, err);
+    }
+
+    #[test]
+    fn test_share_guest_memory() {
+        use std::os::unix::net::UnixListener;
+
+        use vm_memory::GuestAddress;
+
+        let regions = [(GuestAddress(0), 0x10000), (GuestAddress(0x20000), 0x10000)];
+        let mem_file = TempFile::new().unwrap();
+        let guest_memory = vm_memory::create_file_guest_memory(
+            mem_file.as_file().try_clone().unwrap(),
+            &regions,
+            false,
+            None,
+        )
+        .unwrap();
+
+        // Only the file backing the shared guest memory holds it.
+        assert!(shared_memory_file(&guest_memory, mem_file.as_path()).is_some());
+        let other_file = TempFile::new().unwrap();
+        assert!(shared_memory_file(&guest_memory, other_file.as_path()).is_none());
+        let private_memory = vm_memory::create_guest_memory(
+            &[(
+                Some(vm_memory::FileOffset::new(
+                    mem_file.as_file().try_clone().unwrap(),
+                    0,
+                )),
+                GuestAddress(0),
+                0x10000,
+            )],
+            false,
+            None,
+        )
+        .unwrap();
+        assert!(shared_memory_file(&private_memory, mem_file.as_path()).is_none());
+
+        let mut uds_file = TempFile::new().unwrap();
+        uds_file.remove().unwrap();
+        // Nobody listens on the socket.
+        assert!(matches!(
+            share_guest_memory(uds_file.as_path(), &guest_memory, HugePageConfig::None),
+            Err(ShareGuestMemoryError::Connect(_))
+        ));
+
+        let listener = UnixListener::bind(uds_file.as_path()).unwrap();
+        share_guest_memory(uds_file.as_path(), &guest_memory, HugePageConfig::None).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut buf = vec![0u8; 1024];
+        let (len, file) = stream.recv_with_fd(&mut buf[..]).unwrap();
+        assert!(file.is_some());
+        let mappings: serde_json::Value = serde_json::from_slice(&buf[..len]).unwrap();
+        let mappings = mappings.as_array().unwrap();
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[1]["size"], 0x10000);
+        assert_eq!(mappings[1]["offset"], 0x10000);
+        assert_eq!(
+            mappings[1]["page_size_kib"],
+            utils::get_page_size().unwrap() / 1024
+        );
+
+        // Private anonymous memory can't be shared.
+        let anon_memory = vm_memory::test_utils::create_anon_guest_memory(&regions, false).unwrap();
+        assert!(matches!(
+            share_guest_memory(uds_file.as_path(), &anon_memory, HugePageConfig::None),
+            Err(ShareGuestMemoryError::NotFileBacked)
+        ));
+    }
+
+    #[test]
+    fn test_shared_guest_memory_from_file() {
+        let regions = [(GuestAddress(0), 0x10000), (GuestAddress(0x20000), 0x10000)];
+        let guest_memory =
+            vm_memory::test_utils::create_anon_guest_memory(&regions, false).unwrap();
+        guest_memory
+            .write_obj(0xdead_beef_u32, GuestAddress(0x20010))
+            .unwrap();
+        let mem_state = guest_memory.describe();
+        let mem_file = TempFile::new().unwrap();
+        guest_memory.dump(&mut mem_file.as_file()).unwrap();
+
+        let mut shared_file = TempFile::new().unwrap();
+        shared_file.remove().unwrap();
+        for shared_file_path in [None, Some(shared_file.as_path()), Some(mem_file.as_path())] {
+            let restored = shared_guest_memory_from_file(
+                mem_file.as_path(),
+                &mem_state,
+                false,
+                HugePageConfig::None,
+                shared_file_path,
+            )
+            .unwrap();
+            assert!(restored
+                .iter()
+                .all(|region| region.flags() & libc::MAP_SHARED != 0));
+            assert_eq!(
+                restored.read_obj::<u32>(GuestAddress(0x20010)).unwrap(),
+                0xdead_beef
+            );
+
+            // The writes of the guest land in the file backing the shared memory.
+            restored
+                .write_obj(0xcafe_u32, GuestAddress(0x20020))
+                .unwrap();
+            if let Some(path) = shared_file_path {
+                let contents = std::fs::read(path).unwrap();
+                assert_eq!(&contents[0x10020..0x10024], &0xcafe_u32.to_ne_bytes());
+            }
+        }
     }
 
     #[test]
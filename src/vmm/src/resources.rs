@@ -4,6 +4,7 @@
 use std::convert::From;
 use std::sync::{Arc, Mutex, MutexGuard};
 
+use devices::virtio::Block;
 use logger::info;
 use mmds::data_store::{Mmds, MmdsVersion};
 use mmds::ns::MmdsNetworkStack;
@@ -18,7 +19,10 @@ use crate::vmm_config::boot_source::{
 use crate::vmm_config::drive::*;
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::logger::{init_logger, LoggerConfig, LoggerConfigError};
-use crate::vmm_config::machine_config::{VmConfig, VmConfigError, VmUpdateConfig};
+use crate::vmm_config::machine_config::{
+    VmConfig, VmConfigError, VmUpdateConfig, MAX_BLOCK_HOTPLUG_SLOTS,
+};
+use crate::vmm_config::memory_hotplug::{MemoryHotplugConfig, MemoryHotplugConfigError};
 use crate::vmm_config::metrics::{init_metrics, MetricsConfig, MetricsConfigError};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::*;
@@ -46,6 +50,8 @@ pub enum Error {
     Mmds(mmds::data_store::Error),
     /// MMDS configuration error.
     MmdsConfig(MmdsConfigError),
+    /// Memory hot-plug configuration error.
+    MemoryHotplug(MemoryHotplugConfigError),
     /// Net device configuration error.
     NetDevice(NetworkInterfaceError),
     /// microVM vCpus or memory configuration error.
@@ -65,6 +71,7 @@ impl std::fmt::Display for Error {
             Error::Metrics(err) => write!(f, "Metrics error: {}", err),
             Error::Mmds(err) => write!(f, "MMDS error: {}", err),
             Error::MmdsConfig(err) => write!(f, "MMDS config error: {}", err),
+            Error::MemoryHotplug(err) => write!(f, "Memory hot-plug error: {}", err),
             Error::NetDevice(err) => write!(f, "Network device error: {}", err),
             Error::VmConfig(err) => write!(f, "VM config error: {}", err),
             Error::VsockDevice(err) => write!(f, "Vsock device error: {}", err),
@@ -85,6 +92,8 @@ pub struct VmmConfig {
     logger: Option<LoggerConfig>,
     #[serde(rename = "machine-config")]
     machine_config: Option<VmConfig>,
+    #[serde(rename = "memory-hotplug")]
+    memory_hotplug: Option<MemoryHotplugConfig>,
     #[serde(rename = "metrics")]
     metrics: Option<MetricsConfig>,
     #[serde(rename = "mmds-config")]
@@ -109,6 +118,8 @@ pub struct VmResources {
     pub vsock: VsockBuilder,
     /// The balloon device.
     pub balloon: BalloonBuilder,
+    /// The region of hot-pluggable memory, backing the virtio-mem device.
+    pub memory_hotplug: Option<MemoryHotplugConfig>,
     /// The network devices builder.
     pub net_builder: NetBuilder,
     /// The optional Mmds data store.
@@ -166,6 +177,10 @@ impl VmResources {
             resources.set_balloon_device(balloon_config)?;
         }
 
+        if let Some(memory_hotplug_config) = vmm_config.memory_hotplug {
+            resources.set_memory_hotplug_config(memory_hotplug_config)?;
+        }
+
         // Init the data store from file, if present.
         if let Some(data) = metadata_json {
             resources.locked_mmds_or_default().put_data(
@@ -214,6 +229,12 @@ impl VmResources {
             SharedDeviceType::Vsock(vsock) => {
                 self.vsock.set_device(vsock);
             }
+
+            SharedDeviceType::Mem(mem) => {
+                self.memory_hotplug = Some(MemoryHotplugConfig::from(
+                    mem.lock().expect("Poisoned lock").config(),
+                ));
+            }
         }
     }
 
@@ -276,19 +297,48 @@ impl VmResources {
         }
 
         // The VM cannot have a memory size smaller than the target size
-        // of the balloon device, if present.
-        if self.balloon.get().is_some()
-            && mem_size_mib
-                < self
-                    .balloon
-                    .get_config()
-                    .map_err(|_| VmConfigError::InvalidVmState)?
-                    .amount_mib as usize
-        {
-            return Err(VmConfigError::IncompatibleBalloonSize);
+        // of the balloon device, if present, or than the largest size its
+        // automatic sizing policy may set.
+        if self.balloon.get().is_some() {
+            let balloon_config = self
+                .balloon
+                .get_config()
+                .map_err(|_| VmConfigError::InvalidVmState)?;
+            let max_balloon_mib = balloon_config
+                .auto_size
+                .map_or(balloon_config.amount_mib, |auto_size| {
+                    std::cmp::max(balloon_config.amount_mib, auto_size.max_mib)
+                });
+            if mem_size_mib < max_balloon_mib as usize {
+                return Err(VmConfigError::IncompatibleBalloonSize);
+            }
+        }
+
+        let huge_pages = machine_config
+            .huge_pages
+            .unwrap_or(self.vm_config.huge_pages);
+
+        // Each memory region is mapped separately, so they all have to be made of whole huge
+        // pages.
+        if let Some(page_size) = huge_pages.page_size() {
+            if arch::arch_memory_regions(mem_size_mib << 20)
+                .iter()
+                .any(|(_, size)| size % page_size.bytes() != 0)
+            {
+                return Err(VmConfigError::InvalidHugePageMemorySize);
+            }
+        }
+
+        // The blocks of the hot-pluggable memory are released separately, so they have to be
+        // made of whole huge pages too.
+        if let Some(config) = self.memory_hotplug.as_ref() {
+            if config.validate(huge_pages.page_size()).is_err() {
+                return Err(VmConfigError::InvalidHugePageMemorySize);
+            }
         }
 
         self.vm_config.mem_size_mib = mem_size_mib;
+        self.vm_config.huge_pages = huge_pages;
 
         // Update the CPU template
         if let Some(cpu_template) = machine_config.cpu_template {
@@ -300,6 +350,19 @@ impl VmResources {
             self.vm_config.track_dirty_pages = track_dirty_pages;
         }
 
+        // Update the number of block device hot-plug slots
+        if let Some(block_hotplug_slots) = machine_config.block_hotplug_slots {
+            if block_hotplug_slots > MAX_BLOCK_HOTPLUG_SLOTS {
+                return Err(VmConfigError::InvalidBlockHotplugSlots);
+            }
+            self.vm_config.block_hotplug_slots = block_hotplug_slots;
+        }
+
+        // Update the sharing of the guest memory
+        if let Some(shared_memory) = &machine_config.shared_memory {
+            self.vm_config.shared_memory = shared_memory.clone();
+        }
+
         Ok(())
     }
 
@@ -357,14 +420,29 @@ impl VmResources {
         config: BalloonDeviceConfig,
     ) -> Result<BalloonConfigError> {
         // The balloon cannot have a target size greater than the size of
-        // the guest memory.
-        if config.amount_mib as usize > self.vm_config.mem_size_mib {
+        // the guest memory, and neither can its automatic sizing policy.
+        if config.amount_mib as usize > self.vm_config.mem_size_mib
+            || config.auto_size.map_or(false, |auto_size| {
+                auto_size.max_mib as usize > self.vm_config.mem_size_mib
+            })
+        {
             return Err(BalloonConfigError::TooManyPagesRequested);
         }
 
         self.balloon.set(config)
     }
 
+    /// Reserves a region of hot-pluggable memory, backing a virtio-mem device attached when
+    /// the VM starts.
+    pub fn set_memory_hotplug_config(
+        &mut self,
+        config: MemoryHotplugConfig,
+    ) -> Result<MemoryHotplugConfigError> {
+        config.validate(self.vm_config.huge_pages.page_size())?;
+        self.memory_hotplug = Some(config);
+        Ok(())
+    }
+
     /// Obtains the boot source hooks (kernel fd, command line creation and validation).
     pub fn build_boot_source(
         &mut self,
@@ -390,6 +468,24 @@ impl VmResources {
         self.block.insert(block_device_config)
     }
 
+    /// Creates a block device to be hot-plugged in the running microVM.
+    pub fn create_hotplug_block_device(
+        &self,
+        block_device_config: BlockDeviceConfig,
+    ) -> std::result::Result<Arc<Mutex<Block>>, DriveError> {
+        self.block.create_hotplug_block(block_device_config)
+    }
+
+    /// Tracks a block device which was hot-plugged in the running microVM.
+    pub fn add_block_device(&mut self, block: Arc<Mutex<Block>>) {
+        self.block.add_device(block);
+    }
+
+    /// Stops tracking the block device with id `drive_id`, after it was hot-unplugged.
+    pub fn remove_block_device(&mut self, drive_id: &str) {
+        self.block.remove_device(drive_id);
+    }
+
     /// Builds a network device to be attached when the VM starts.
     pub fn build_net_device(
         &mut self,
@@ -484,6 +580,7 @@ impl From<&VmResources> for VmmConfig {
             boot_source: resources.boot_source_config().clone(),
             logger: None,
             machine_config: Some(resources.vm_config.clone()),
+            memory_hotplug: resources.memory_hotplug.clone(),
             metrics: None,
             mmds_config: resources.mmds_config(),
             net_devices: resources.net_builder.configs(),
@@ -496,6 +593,7 @@ impl From<&VmResources> for VmmConfig {
 mod tests {
     use std::fs::File;
     use std::os::linux::fs::MetadataExt;
+    use std::path::PathBuf;
 
     use devices::virtio::vsock::{VsockError, VSOCK_DEV_ID};
     use logger::{LevelFilter, LOGGER};
@@ -508,10 +606,12 @@ mod tests {
     use crate::vmm_config::boot_source::{
         BootConfig, BootSource, BootSourceConfig, DEFAULT_KERNEL_CMDLINE,
     };
-    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, FileEngineType};
-    use crate::vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig, VmConfigError};
-    use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
-    use crate::vmm_config::vsock::tests::default_config;
+    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, DiskFormat, FileEngineType};
+    use crate::vmm_config::machine_config::{
+        CpuFeaturesTemplate, HugePageConfig, SharedMemoryConfig, VmConfig, VmConfigError,
+    };
+    use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig, Offloads};
+    use crate::vmm_config::vsock::tests::{default_config, unix_vsock};
     use crate::vmm_config::RateLimiterConfig;
     use crate::vstate::vcpu::VcpuConfig;
     use crate::HTTP_MAX_PAYLOAD_SIZE;
@@ -530,6 +630,10 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str("01:23:45:67:89:0a").unwrap()),
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
+            num_queue_pairs: 1,
+            offloads: Offloads::default(),
+            vhost_net: false,
+            anti_spoofing: None,
         }
     }
 
@@ -545,13 +649,17 @@ mod tests {
         (
             BlockDeviceConfig {
                 drive_id: "block1".to_string(),
-                path_on_host: tmp_file.as_path().to_str().unwrap().to_string(),
+                path_on_host: Some(tmp_file.as_path().to_str().unwrap().to_string()),
+                socket: None,
                 is_root_device: false,
                 partuuid: Some("0eaa91a0-01".to_string()),
                 cache_type: CacheType::Unsafe,
                 is_read_only: false,
                 rate_limiter: Some(RateLimiterConfig::default()),
                 file_engine_type: FileEngineType::default(),
+                disk_format: DiskFormat::default(),
+                num_queues: 1,
+                direct_io: false,
             },
             tmp_file,
         )
@@ -585,6 +693,7 @@ mod tests {
             block: default_blocks(),
             vsock: Default::default(),
             balloon: Default::default(),
+            memory_hotplug: None,
             net_builder: default_net_builder(),
             mmds: None,
             boot_timer: false,
@@ -1291,6 +1400,12 @@ mod tests {
             smt: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
             track_dirty_pages: Some(false),
+            block_hotplug_slots: Some(2),
+            huge_pages: Some(HugePageConfig::Hugetlbfs2M),
+            shared_memory: Some(Some(SharedMemoryConfig {
+                mem_file_path: None,
+                uds_path: Some(PathBuf::from("mem.sock")),
+            })),
         };
 
         assert_ne!(
@@ -1323,6 +1438,22 @@ mod tests {
             Err(VmConfigError::InvalidMemorySize)
         );
 
+        // mem_size_mib which is not a multiple of the huge page size.
+        aux_vm_config.mem_size_mib = Some(513);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidHugePageMemorySize)
+        );
+        aux_vm_config.huge_pages = Some(HugePageConfig::Hugetlbfs1G);
+        aux_vm_config.mem_size_mib = Some(512);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidHugePageMemorySize)
+        );
+        aux_vm_config.mem_size_mib = Some(1024);
+        assert!(vm_resources.update_vm_config(&aux_vm_config).is_ok());
+        aux_vm_config.huge_pages = Some(HugePageConfig::None);
+
         // Incompatible mem_size_mib with balloon size.
         vm_resources.vm_config.mem_size_mib = 128;
         vm_resources
@@ -1330,6 +1461,9 @@ mod tests {
                 amount_mib: 100,
                 deflate_on_oom: false,
                 stats_polling_interval_s: 0,
+                free_page_hinting: false,
+                free_page_reporting: false,
+                auto_size: None,
             })
             .unwrap();
         aux_vm_config.mem_size_mib = Some(90);
@@ -1341,6 +1475,22 @@ mod tests {
         // mem_size_mib compatible with balloon size.
         aux_vm_config.mem_size_mib = Some(256);
         assert!(vm_resources.update_vm_config(&aux_vm_config).is_ok());
+
+        // Too many block device hot-plug slots.
+        aux_vm_config.block_hotplug_slots = Some(MAX_BLOCK_HOTPLUG_SLOTS + 1);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidBlockHotplugSlots)
+        );
+        aux_vm_config.block_hotplug_slots = Some(2);
+
+        // The guest memory stays shared unless the update stops sharing it.
+        aux_vm_config.shared_memory = None;
+        vm_resources.update_vm_config(&aux_vm_config).unwrap();
+        assert!(vm_resources.vm_config.shared_memory.is_some());
+        aux_vm_config.shared_memory = Some(None);
+        vm_resources.update_vm_config(&aux_vm_config).unwrap();
+        assert!(vm_resources.vm_config.shared_memory.is_none());
     }
 
     #[test]
@@ -1351,6 +1501,9 @@ mod tests {
             amount_mib: 100,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            free_page_hinting: false,
+            free_page_reporting: false,
+            auto_size: None,
         };
         assert!(vm_resources.balloon.get().is_none());
         vm_resources
@@ -1371,7 +1524,65 @@ mod tests {
         let mut vm_resources = default_vm_resources();
         vm_resources.balloon = BalloonBuilder::new();
         new_balloon_cfg.amount_mib = 256;
-        assert!(vm_resources.set_balloon_device(new_balloon_cfg).is_err());
+        assert!(vm_resources
+            .set_balloon_device(new_balloon_cfg.clone())
+            .is_err());
+
+        // Neither can the automatic sizing policy grow the balloon past the
+        // size of the guest memory.
+        new_balloon_cfg.amount_mib = 0;
+        new_balloon_cfg.stats_polling_interval_s = 1;
+        new_balloon_cfg.auto_size = Some(BalloonAutoSize {
+            min_mib: 0,
+            max_mib: 256,
+            target_free_mib: 32,
+            step_mib: 16,
+        });
+        match vm_resources.set_balloon_device(new_balloon_cfg.clone()) {
+            Err(BalloonConfigError::TooManyPagesRequested) => (),
+            _ => panic!("Expected a too many pages requested error."),
+        }
+        new_balloon_cfg.auto_size.as_mut().unwrap().max_mib = 64;
+        vm_resources.set_balloon_device(new_balloon_cfg).unwrap();
+
+        // The guest memory can't shrink below the largest size of the policy.
+        let mut aux_vm_config = VmUpdateConfig::from(vm_resources.vm_config.clone());
+        aux_vm_config.mem_size_mib = Some(32);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::IncompatibleBalloonSize)
+        );
+    }
+
+    #[test]
+    fn test_set_memory_hotplug_config() {
+        let mut vm_resources = default_vm_resources();
+        let config = MemoryHotplugConfig {
+            total_size_mib: 1024,
+            block_size_mib: 1,
+        };
+        vm_resources
+            .set_memory_hotplug_config(config.clone())
+            .unwrap();
+        assert_eq!(vm_resources.memory_hotplug, Some(config));
+
+        // The blocks have to be made of whole huge pages.
+        let mut aux_vm_config = VmUpdateConfig::from(vm_resources.vm_config.clone());
+        aux_vm_config.huge_pages = Some(HugePageConfig::Hugetlbfs2M);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidHugePageMemorySize)
+        );
+
+        let mut vm_resources = default_vm_resources();
+        assert!(matches!(
+            vm_resources.set_memory_hotplug_config(MemoryHotplugConfig {
+                total_size_mib: 1023,
+                block_size_mib: 2,
+            }),
+            Err(MemoryHotplugConfigError::InvalidTotalSize)
+        ));
+        assert!(vm_resources.memory_hotplug.is_none());
     }
 
     #[test]
@@ -1438,7 +1649,7 @@ mod tests {
         let (mut new_block_device_cfg, _file) = default_block_cfg();
         let tmp_file = TempFile::new().unwrap();
         new_block_device_cfg.drive_id = "block2".to_string();
-        new_block_device_cfg.path_on_host = tmp_file.as_path().to_str().unwrap().to_string();
+        new_block_device_cfg.path_on_host = Some(tmp_file.as_path().to_str().unwrap().to_string());
         assert_eq!(vm_resources.block.list.len(), 1);
         vm_resources.set_block_device(new_block_device_cfg).unwrap();
         assert_eq!(vm_resources.block.list.len(), 2);
@@ -1452,7 +1663,7 @@ mod tests {
         let new_vsock_cfg = default_config(&tmp_sock_file);
         assert!(vm_resources.vsock.get().is_none());
         vm_resources.set_vsock_device(new_vsock_cfg).unwrap();
-        let actual_vsock_cfg = vm_resources.vsock.get().unwrap();
+        let actual_vsock_cfg = unix_vsock(vm_resources.vsock.get().unwrap());
         assert_eq!(actual_vsock_cfg.lock().unwrap().id(), VSOCK_DEV_ID);
     }
 
@@ -34,13 +34,20 @@ use crate::vmm_config::drive::{BlockDeviceConfig, BlockDeviceUpdateConfig, Drive
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::logger::{LoggerConfig, LoggerConfigError};
 use crate::vmm_config::machine_config::{VmConfig, VmConfigError, VmUpdateConfig};
+use crate::vmm_config::memory_hotplug::{
+    MemoryHotplugConfig, MemoryHotplugConfigError, MemoryHotplugSizeUpdate, MemoryHotplugStatus,
+};
 use crate::vmm_config::metrics::{MetricsConfig, MetricsConfigError};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::{
-    NetworkInterfaceConfig, NetworkInterfaceError, NetworkInterfaceUpdateConfig,
+    NetworkCaptureConfig, NetworkInterfaceConfig, NetworkInterfaceError,
+    NetworkInterfaceUpdateConfig, PacketCaptureAction,
 };
 use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
-use crate::vmm_config::vsock::{VsockConfigError, VsockDeviceConfig};
+use crate::vmm_config::vsock::{
+    VsockConfigError, VsockConnectionInfo, VsockConnectionKey, VsockDeviceConfig,
+    VsockDeviceUpdateConfig,
+};
 use crate::vmm_config::{self, RateLimiterUpdate};
 use crate::{EventManager, FcExitCode};
 
@@ -60,12 +67,17 @@ pub enum VmmAction {
     /// Create a snapshot using as input the `CreateSnapshotParams`. This action can only be called
     /// after the microVM has booted and only when the microVM is in `Paused` state.
     CreateSnapshot(CreateSnapshotParams),
+    /// Hot-unplug the block device with the given id. This action can only be called after the
+    /// microVM has booted, for block devices which were hot-plugged.
+    DetachBlockDevice(String),
     /// Get the balloon device configuration.
     GetBalloonConfig,
     /// Get the ballon device latest statistics.
     GetBalloonStats,
     /// Get complete microVM configuration in JSON format.
     GetFullVmConfig,
+    /// Get the status of the hot-pluggable memory.
+    GetMemoryHotplug,
     /// Get MMDS contents.
     GetMMDS,
     /// Get the machine configuration of the microVM.
@@ -74,10 +86,13 @@ pub enum VmmAction {
     GetVmInstanceInfo,
     /// Get microVM version.
     GetVmmVersion,
+    /// Get the connections of the vsock device. This action can only be called after the microVM
+    /// has booted.
+    GetVsockConnections,
     /// Flush the metrics. This action can only be called after the logger has been configured.
     FlushMetrics,
     /// Add a new block device or update one that already exists using the `BlockDeviceConfig` as
-    /// input. This action can only be called before the microVM has booted.
+    /// input. After the microVM has booted, this hot-plugs a new block device in a free slot.
     InsertBlockDevice(BlockDeviceConfig),
     /// Add a new network interface config or update one that already exists using the
     /// `NetworkInterfaceConfig` as input. This action can only be called before the microVM has
@@ -93,12 +108,18 @@ pub enum VmmAction {
     Pause,
     /// Repopulate the MMDS contents.
     PutMMDS(Value),
+    /// Forcefully terminate the vsock connection identified by `VsockConnectionKey`. This action
+    /// can only be called after the microVM has booted.
+    ResetVsockConnection(VsockConnectionKey),
     /// Resume the guest, by resuming the microVM VCPUs.
     Resume,
     /// Set the balloon device or update the one that already exists using the
     /// `BalloonDeviceConfig` as input. This action can only be called before the microVM
     /// has booted.
     SetBalloonDevice(BalloonDeviceConfig),
+    /// Reserve a region of hot-pluggable memory using the `MemoryHotplugConfig` as input. This
+    /// action can only be called before the microVM has booted.
+    SetMemoryHotplug(MemoryHotplugConfig),
     /// Set the MMDS configuration.
     SetMmdsConfiguration(MmdsConfig),
     /// Set the vsock device or update the one that already exists using the
@@ -117,12 +138,20 @@ pub enum VmmAction {
     UpdateBalloonStatistics(BalloonUpdateStatsConfig),
     /// Update existing block device properties such as `path_on_host` or `rate_limiter`.
     UpdateBlockDevice(BlockDeviceUpdateConfig),
+    /// Update the amount of memory the guest is asked to hot-plug, after microVM start.
+    UpdateMemoryHotplug(MemoryHotplugSizeUpdate),
+    /// Start or stop capturing the frames of a network interface to a pcap file, after microVM
+    /// start.
+    UpdateNetworkCapture(NetworkCaptureConfig),
     /// Update a network interface, after microVM start. Currently, the only updatable properties
-    /// are the RX and TX rate limiters.
+    /// are the RX and TX rate limiters and the link state.
     UpdateNetworkInterface(NetworkInterfaceUpdateConfig),
     /// Update the microVM configuration (memory & vcpu) using `VmUpdateConfig` as input. This
     /// action can only be called before the microVM has booted.
     UpdateVmConfiguration(VmUpdateConfig),
+    /// Update the rate limiter of the vsock device, using `VsockDeviceUpdateConfig` as input.
+    /// This action can only be called after the microVM has booted.
+    UpdateVsockDevice(VsockDeviceUpdateConfig),
 }
 
 /// Wrapper for all errors associated with VMM actions.
@@ -134,7 +163,7 @@ pub enum VmmActionError {
     BootSource(BootSourceConfigError),
     /// The action `CreateSnapshot` failed.
     CreateSnapshot(CreateSnapshotError),
-    /// One of the actions `InsertBlockDevice` or `UpdateBlockDevicePath`
+    /// One of the actions `InsertBlockDevice`, `DetachBlockDevice` or `UpdateBlockDevicePath`
     /// failed because of bad user input.
     DriveConfig(DriveError),
     /// Internal Vmm error.
@@ -146,6 +175,9 @@ pub enum VmmActionError {
     /// One of the actions `GetVmConfiguration` or `UpdateVmConfiguration` failed because of bad
     /// input.
     MachineConfig(VmConfigError),
+    /// One of the actions `SetMemoryHotplug`, `GetMemoryHotplug` or `UpdateMemoryHotplug`
+    /// failed.
+    MemoryHotplugConfig(MemoryHotplugConfigError),
     /// The action `ConfigureMetrics` failed because of bad user input.
     Metrics(MetricsConfigError),
     /// One of the `GetMmds`, `PutMmds` or `PatchMmds` actions failed.
@@ -166,7 +198,8 @@ pub enum VmmActionError {
     OperationNotSupportedPreBoot,
     /// The action `StartMicroVm` failed because of an internal error.
     StartMicrovm(StartMicrovmError),
-    /// The action `SetVsockDevice` failed because of bad user input.
+    /// One of the actions `SetVsockDevice`, `GetVsockConnections`, `ResetVsockConnection` or
+    /// `UpdateVsockDevice` failed.
     VsockConfig(VsockConfigError),
 }
 
@@ -186,6 +219,7 @@ impl Display for VmmActionError {
                 LoadSnapshot(err) => format!("Load microVM snapshot error: {}", err),
                 Logger(err) => err.to_string(),
                 MachineConfig(err) => err.to_string(),
+                MemoryHotplugConfig(err) => err.to_string(),
                 Metrics(err) => err.to_string(),
                 Mmds(err) => err.to_string(),
                 MmdsConfig(err) => err.to_string(),
@@ -222,12 +256,16 @@ pub enum VmmData {
     FullVmConfig(VmmConfig),
     /// The microVM configuration represented by `VmConfig`.
     MachineConfiguration(VmConfig),
+    /// The status of the hot-pluggable memory.
+    MemoryHotplugStatus(MemoryHotplugStatus),
     /// Mmds contents.
     MmdsValue(serde_json::Value),
     /// The microVM instance information.
     InstanceInformation(InstanceInfo),
     /// The microVM version.
     VmmVersion(String),
+    /// The connections of the vsock device.
+    VsockConnections(Vec<VsockConnectionInfo>),
 }
 
 /// Shorthand result type for external VMM commands.
@@ -415,6 +453,7 @@ impl<'a> PrebootApiController<'a> {
                 );
                 Ok(VmmData::FullVmConfig((&*self.vm_resources).into()))
             }
+            GetMemoryHotplug => self.memory_hotplug_status(),
             GetMMDS => self.get_mmds(),
             GetVmMachineConfig => Ok(VmmData::MachineConfiguration(
                 self.vm_resources.vm_config().clone(),
@@ -429,20 +468,27 @@ impl<'a> PrebootApiController<'a> {
             PatchMMDS(value) => self.patch_mmds(value),
             PutMMDS(value) => self.put_mmds(value),
             SetBalloonDevice(config) => self.set_balloon_device(config),
+            SetMemoryHotplug(config) => self.set_memory_hotplug_config(config),
             SetVsockDevice(config) => self.set_vsock_device(config),
             SetMmdsConfiguration(config) => self.set_mmds_config(config),
             StartMicroVm => self.start_microvm(),
             UpdateVmConfiguration(config) => self.update_vm_config(config),
             // Operations not allowed pre-boot.
             CreateSnapshot(_)
+            | DetachBlockDevice(_)
             | FlushMetrics
             | Pause
             | Resume
             | GetBalloonStats
+            | GetVsockConnections
+            | ResetVsockConnection(_)
             | UpdateBalloon(_)
             | UpdateBalloonStatistics(_)
             | UpdateBlockDevice(_)
-            | UpdateNetworkInterface(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
+            | UpdateMemoryHotplug(_)
+            | UpdateNetworkCapture(_)
+            | UpdateNetworkInterface(_)
+            | UpdateVsockDevice(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => Err(VmmActionError::OperationNotSupportedPreBoot),
         }
@@ -456,6 +502,16 @@ impl<'a> PrebootApiController<'a> {
             .map_err(VmmActionError::BalloonConfig)
     }
 
+    fn memory_hotplug_status(&mut self) -> ActionResult {
+        self.vm_resources
+            .memory_hotplug
+            .as_ref()
+            .map(|config| VmmData::MemoryHotplugStatus(MemoryHotplugStatus::from(config)))
+            .ok_or(VmmActionError::MemoryHotplugConfig(
+                MemoryHotplugConfigError::DeviceNotFound,
+            ))
+    }
+
     fn insert_block_device(&mut self, cfg: BlockDeviceConfig) -> ActionResult {
         self.boot_path = true;
         self.vm_resources
@@ -480,6 +536,14 @@ impl<'a> PrebootApiController<'a> {
             .map_err(VmmActionError::BalloonConfig)
     }
 
+    fn set_memory_hotplug_config(&mut self, cfg: MemoryHotplugConfig) -> ActionResult {
+        self.boot_path = true;
+        self.vm_resources
+            .set_memory_hotplug_config(cfg)
+            .map(|()| VmmData::Empty)
+            .map_err(VmmActionError::MemoryHotplugConfig)
+    }
+
     fn set_boot_source(&mut self, cfg: BootSourceConfig) -> ActionResult {
         self.boot_path = true;
         self.vm_resources
@@ -626,6 +690,15 @@ impl RuntimeApiController {
                 .map(VmmData::BalloonStats)
                 .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
             GetFullVmConfig => Ok(VmmData::FullVmConfig((&self.vm_resources).into())),
+            GetMemoryHotplug => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .memory_hotplug_config()
+                .map(|config| VmmData::MemoryHotplugStatus(MemoryHotplugStatus::from(config)))
+                .map_err(|err| {
+                    VmmActionError::MemoryHotplugConfig(MemoryHotplugConfigError::from(err))
+                }),
             GetMMDS => self.get_mmds(),
             GetVmMachineConfig => Ok(VmmData::MachineConfiguration(
                 self.vm_resources.vm_config().clone(),
@@ -636,9 +709,23 @@ impl RuntimeApiController {
             GetVmmVersion => Ok(VmmData::VmmVersion(
                 self.vmm.lock().expect("Poisoned lock").version(),
             )),
+            GetVsockConnections => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .vsock_connections()
+                .map(VmmData::VsockConnections)
+                .map_err(VmmActionError::VsockConfig),
             PatchMMDS(value) => self.patch_mmds(value),
             Pause => self.pause(),
             PutMMDS(value) => self.put_mmds(value),
+            ResetVsockConnection(key) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .reset_vsock_connection(key.local_port, key.peer_port)
+                .map(|_| VmmData::Empty)
+                .map_err(VmmActionError::VsockConfig),
             Resume => self.resume(),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => self.send_ctrl_alt_del(),
@@ -657,16 +744,38 @@ impl RuntimeApiController {
                 .map(|_| VmmData::Empty)
                 .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
             UpdateBlockDevice(new_cfg) => self.update_block_device(new_cfg),
-            UpdateNetworkInterface(netif_update) => self.update_net_rate_limiters(netif_update),
+            UpdateMemoryHotplug(update) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .update_memory_hotplug_size(update.requested_size_mib)
+                .map(|_| VmmData::Empty)
+                .map_err(|err| {
+                    VmmActionError::MemoryHotplugConfig(MemoryHotplugConfigError::from(err))
+                }),
+            UpdateNetworkCapture(capture_cfg) => self.update_network_capture(capture_cfg),
+            UpdateNetworkInterface(netif_update) => self.update_network_interface(netif_update),
+            UpdateVsockDevice(new_cfg) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .update_vsock_rate_limiter(
+                    RateLimiterUpdate::from(new_cfg.rate_limiter).bandwidth,
+                    RateLimiterUpdate::from(new_cfg.rate_limiter).ops,
+                )
+                .map(|_| VmmData::Empty)
+                .map_err(VmmActionError::VsockConfig),
+            InsertBlockDevice(config) => self.hotplug_block_device(config),
+            DetachBlockDevice(drive_id) => self.detach_block_device(&drive_id),
 
             // Operations not allowed post-boot.
             ConfigureBootSource(_)
             | ConfigureLogger(_)
             | ConfigureMetrics(_)
-            | InsertBlockDevice(_)
             | InsertNetworkDevice(_)
             | LoadSnapshot(_)
             | SetBalloonDevice(_)
+            | SetMemoryHotplug(_)
             | SetVsockDevice(_)
             | SetMmdsConfiguration(_)
             | StartMicroVm
@@ -749,6 +858,7 @@ impl RuntimeApiController {
             smt: vm_cfg.smt,
             cpu_template: vm_cfg.cpu_template,
             boot_source: self.vm_resources.boot_source_config().clone(),
+            huge_pages: vm_cfg.huge_pages,
         };
         let create_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
 
@@ -784,6 +894,30 @@ impl RuntimeApiController {
         Ok(VmmData::Empty)
     }
 
+    /// Hot-plugs a new block device, which is tracked among the VM resources once plugged.
+    fn hotplug_block_device(&mut self, cfg: BlockDeviceConfig) -> ActionResult {
+        let block = self.vm_resources.create_hotplug_block_device(cfg)?;
+        self.vmm
+            .lock()
+            .expect("Poisoned lock")
+            .hotplug_block_device(block.clone())
+            .map_err(DriveError::DeviceHotplug)?;
+        self.vm_resources.add_block_device(block);
+        Ok(VmmData::Empty)
+    }
+
+    /// Hot-unplugs a block device released by the guest, after completing its in-flight
+    /// requests.
+    fn detach_block_device(&mut self, drive_id: &str) -> ActionResult {
+        self.vmm
+            .lock()
+            .expect("Poisoned lock")
+            .unplug_block_device(drive_id)
+            .map_err(DriveError::DeviceHotUnplug)?;
+        self.vm_resources.remove_block_device(drive_id);
+        Ok(VmmData::Empty)
+    }
+
     /// Updates block device properties:
     ///  - path of the host file backing the emulated block device, update the disk image on the
     ///    device and its virtio configuration
@@ -808,20 +942,37 @@ impl RuntimeApiController {
     }
 
     /// Updates configuration for an emulated net device as described in `new_cfg`.
-    fn update_net_rate_limiters(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
-        self.vmm
-            .lock()
-            .expect("Poisoned lock")
-            .update_net_rate_limiters(
-                &new_cfg.iface_id,
-                RateLimiterUpdate::from(new_cfg.rx_rate_limiter).bandwidth,
-                RateLimiterUpdate::from(new_cfg.rx_rate_limiter).ops,
-                RateLimiterUpdate::from(new_cfg.tx_rate_limiter).bandwidth,
-                RateLimiterUpdate::from(new_cfg.tx_rate_limiter).ops,
-            )
-            .map(|()| VmmData::Empty)
-            .map_err(NetworkInterfaceError::DeviceUpdate)
-            .map_err(VmmActionError::NetworkConfig)
+    fn update_network_interface(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
+        let mut vmm = self.vmm.lock().expect("Poisoned lock");
+        vmm.update_net_rate_limiters(
+            &new_cfg.iface_id,
+            RateLimiterUpdate::from(new_cfg.rx_rate_limiter).bandwidth,
+            RateLimiterUpdate::from(new_cfg.rx_rate_limiter).ops,
+            RateLimiterUpdate::from(new_cfg.tx_rate_limiter).bandwidth,
+            RateLimiterUpdate::from(new_cfg.tx_rate_limiter).ops,
+        )
+        .map_err(NetworkInterfaceError::DeviceUpdate)?;
+        if let Some(link_state) = new_cfg.link_state {
+            vmm.update_net_link_state(&new_cfg.iface_id, link_state)
+                .map_err(NetworkInterfaceError::DeviceUpdate)?;
+        }
+        Ok(VmmData::Empty)
+    }
+
+    /// Starts or stops the packet capture of an emulated net device as described in `cfg`.
+    fn update_network_capture(&mut self, cfg: NetworkCaptureConfig) -> ActionResult {
+        let mut vmm = self.vmm.lock().expect("Poisoned lock");
+        match (cfg.action, cfg.path_on_host.as_ref()) {
+            (PacketCaptureAction::Start, Some(path)) => {
+                vmm.start_net_capture(&cfg.iface_id, path, cfg.max_size())
+            }
+            (PacketCaptureAction::Start, None) => {
+                return Err(NetworkInterfaceError::CapturePathMissing.into())
+            }
+            (PacketCaptureAction::Stop, _) => vmm.stop_net_capture(&cfg.iface_id),
+        }
+        .map_err(NetworkInterfaceError::DeviceUpdate)?;
+        Ok(VmmData::Empty)
     }
 }
 
@@ -830,16 +981,19 @@ mod tests {
     use std::path::PathBuf;
 
     use devices::virtio::balloon::{BalloonConfig, Error as BalloonError};
-    use devices::virtio::VsockError;
+    use devices::virtio::mem::Error as MemError;
+    use devices::virtio::{Block, VirtioMemConfig, VsockError};
     use mmds::data_store::MmdsVersion;
     use seccompiler::BpfThreadMap;
 
     use super::*;
     use crate::vmm_config::balloon::BalloonBuilder;
-    use crate::vmm_config::drive::{CacheType, FileEngineType};
+    use crate::vmm_config::drive::{BlockBuilder, CacheType, DiskFormat, FileEngineType};
     use crate::vmm_config::logger::LoggerLevel;
+    use crate::vmm_config::net::{LinkState, Offloads};
     use crate::vmm_config::snapshot::{MemBackendConfig, MemBackendType};
     use crate::vmm_config::vsock::VsockBuilder;
+    use crate::vmm_config::RateLimiterConfig;
     use crate::HTTP_MAX_PAYLOAD_SIZE;
 
     impl PartialEq for VmmActionError {
@@ -855,6 +1009,7 @@ mod tests {
                     | (LoadSnapshot(_), LoadSnapshot(_))
                     | (Logger(_), Logger(_))
                     | (MachineConfig(_), MachineConfig(_))
+                    | (MemoryHotplugConfig(_), MemoryHotplugConfig(_))
                     | (Metrics(_), Metrics(_))
                     | (Mmds(_), Mmds(_))
                     | (MmdsLimitExceeded(_), MmdsLimitExceeded(_))
@@ -874,6 +1029,7 @@ mod tests {
     pub struct MockVmRes {
         vm_config: VmConfig,
         pub balloon: BalloonBuilder,
+        pub memory_hotplug: Option<MemoryHotplugConfig>,
         pub vsock: VsockBuilder,
         balloon_config_called: bool,
         balloon_set: bool,
@@ -923,6 +1079,9 @@ mod tests {
             self.vm_config.smt = machine_config.smt.unwrap();
             self.vm_config.cpu_template = machine_config.cpu_template.unwrap();
             self.vm_config.track_dirty_pages = machine_config.track_dirty_pages.unwrap();
+            self.vm_config.block_hotplug_slots = machine_config.block_hotplug_slots.unwrap();
+            self.vm_config.huge_pages = machine_config.huge_pages.unwrap();
+            self.vm_config.shared_memory = machine_config.shared_memory.clone().unwrap();
 
             Ok(())
         }
@@ -938,6 +1097,17 @@ mod tests {
             Ok(())
         }
 
+        pub fn set_memory_hotplug_config(
+            &mut self,
+            config: MemoryHotplugConfig,
+        ) -> Result<(), MemoryHotplugConfigError> {
+            if self.force_errors {
+                return Err(MemoryHotplugConfigError::InvalidTotalSize);
+            }
+            self.memory_hotplug = Some(config);
+            Ok(())
+        }
+
         pub fn build_boot_source(
             &mut self,
             boot_source: BootSourceConfig,
@@ -964,6 +1134,24 @@ mod tests {
             Ok(())
         }
 
+        pub fn create_hotplug_block_device(
+            &self,
+            cfg: BlockDeviceConfig,
+        ) -> Result<Arc<Mutex<Block>>, DriveError> {
+            if self.force_errors {
+                return Err(DriveError::HotplugRootDevice);
+            }
+            BlockBuilder::create_block(cfg).map(|block| Arc::new(Mutex::new(block)))
+        }
+
+        pub fn add_block_device(&mut self, _: Arc<Mutex<Block>>) {
+            self.block_set = true;
+        }
+
+        pub fn remove_block_device(&mut self, _: &str) {
+            self.block_set = false;
+        }
+
         pub fn build_net_device(
             &mut self,
             _: NetworkInterfaceConfig,
@@ -1026,6 +1214,7 @@ mod tests {
     pub struct MockVmm {
         pub balloon_config_called: bool,
         pub latest_balloon_stats_called: bool,
+        pub memory_hotplug_config_called: bool,
         pub pause_called: bool,
         pub resume_called: bool,
         #[cfg(target_arch = "x86_64")]
@@ -1033,7 +1222,16 @@ mod tests {
         pub update_balloon_config_called: bool,
         pub update_balloon_stats_config_called: bool,
         pub update_block_device_path_called: bool,
+        pub update_memory_hotplug_size_called: bool,
         pub update_net_rate_limiters_called: bool,
+        pub vsock_connections_called: bool,
+        pub reset_vsock_connection_called: bool,
+        pub update_vsock_rate_limiter_called: bool,
+        pub update_net_link_state_called: bool,
+        pub start_net_capture_called: bool,
+        pub stop_net_capture_called: bool,
+        pub hotplug_block_device_called: bool,
+        pub unplug_block_device_called: bool,
         // when `true`, all self methods are forced to fail
         pub force_errors: bool,
     }
@@ -1098,6 +1296,50 @@ mod tests {
             Ok(())
         }
 
+        pub fn memory_hotplug_config(&mut self) -> Result<VirtioMemConfig, MemError> {
+            if self.force_errors {
+                return Err(MemError::DeviceNotFound);
+            }
+            self.memory_hotplug_config_called = true;
+            Ok(VirtioMemConfig::default())
+        }
+
+        pub fn update_memory_hotplug_size(&mut self, _: usize) -> Result<(), MemError> {
+            if self.force_errors {
+                return Err(MemError::DeviceNotFound);
+            }
+            self.update_memory_hotplug_size_called = true;
+            Ok(())
+        }
+
+        pub fn vsock_connections(&mut self) -> Result<Vec<VsockConnectionInfo>, VsockConfigError> {
+            if self.force_errors {
+                return Err(VsockConfigError::DeviceNotFound);
+            }
+            self.vsock_connections_called = true;
+            Ok(Vec::new())
+        }
+
+        pub fn reset_vsock_connection(&mut self, _: u32, _: u32) -> Result<(), VsockConfigError> {
+            if self.force_errors {
+                return Err(VsockConfigError::DeviceNotFound);
+            }
+            self.reset_vsock_connection_called = true;
+            Ok(())
+        }
+
+        pub fn update_vsock_rate_limiter(
+            &mut self,
+            _: rate_limiter::BucketUpdate,
+            _: rate_limiter::BucketUpdate,
+        ) -> Result<(), VsockConfigError> {
+            if self.force_errors {
+                return Err(VsockConfigError::DeviceNotFound);
+            }
+            self.update_vsock_rate_limiter_called = true;
+            Ok(())
+        }
+
         pub fn update_block_device_path(&mut self, _: &str, _: String) -> Result<(), VmmError> {
             if self.force_errors {
                 return Err(VmmError::DeviceManager(
@@ -1108,6 +1350,24 @@ mod tests {
             Ok(())
         }
 
+        pub fn hotplug_block_device(&mut self, _: Arc<Mutex<Block>>) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::NoFreeHotplugSlot,
+                ));
+            }
+            self.hotplug_block_device_called = true;
+            Ok(())
+        }
+
+        pub fn unplug_block_device(&mut self, drive_id: &str) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::NotHotplugged(drive_id.to_string()));
+            }
+            self.unplug_block_device_called = true;
+            Ok(())
+        }
+
         pub fn update_block_rate_limiter(
             &mut self,
             _: &str,
@@ -1134,6 +1394,36 @@ mod tests {
             Ok(())
         }
 
+        pub fn update_net_link_state(&mut self, _: &str, _: LinkState) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_net_link_state_called = true;
+            Ok(())
+        }
+
+        pub fn start_net_capture(&mut self, _: &str, _: &str, _: u64) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.start_net_capture_called = true;
+            Ok(())
+        }
+
+        pub fn stop_net_capture(&mut self, _: &str) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.stop_net_capture_called = true;
+            Ok(())
+        }
+
         pub fn instance_info(&self) -> InstanceInfo {
             InstanceInfo::default()
         }
@@ -1305,10 +1595,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_preboot_memory_hotplug() {
+        let config = MemoryHotplugConfig {
+            total_size_mib: 1024,
+            block_size_mib: 2,
+        };
+        let req = VmmAction::SetMemoryHotplug(config.clone());
+        check_preboot_request(req, |result, vm_res| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert_eq!(vm_res.memory_hotplug, Some(config));
+        });
+
+        let req = VmmAction::SetMemoryHotplug(MemoryHotplugConfig {
+            total_size_mib: 1024,
+            block_size_mib: 2,
+        });
+        check_preboot_request_err(
+            req,
+            VmmActionError::MemoryHotplugConfig(MemoryHotplugConfigError::InvalidTotalSize),
+        );
+
+        // There is no region of hot-pluggable memory by default.
+        check_preboot_request_err(
+            VmmAction::GetMemoryHotplug,
+            VmmActionError::MemoryHotplugConfig(MemoryHotplugConfigError::DeviceNotFound),
+        );
+    }
+
     #[test]
     fn test_preboot_insert_block_dev() {
         let req = VmmAction::InsertBlockDevice(BlockDeviceConfig {
-            path_on_host: String::new(),
+            path_on_host: Some(String::new()),
+            socket: None,
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -1316,6 +1635,9 @@ mod tests {
             drive_id: String::new(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: 1,
+            direct_io: false,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1323,7 +1645,8 @@ mod tests {
         });
 
         let req = VmmAction::InsertBlockDevice(BlockDeviceConfig {
-            path_on_host: String::new(),
+            path_on_host: Some(String::new()),
+            socket: None,
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -1331,6 +1654,9 @@ mod tests {
             drive_id: String::new(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: 1,
+            direct_io: false,
         });
         check_preboot_request_err(
             req,
@@ -1346,6 +1672,10 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            offloads: Offloads::default(),
+            vhost_net: false,
+            anti_spoofing: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1358,6 +1688,10 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            offloads: Offloads::default(),
+            vhost_net: false,
+            anti_spoofing: None,
         });
         check_preboot_request_err(
             req,
@@ -1372,7 +1706,10 @@ mod tests {
         let req = VmmAction::SetVsockDevice(VsockDeviceConfig {
             vsock_id: Some(String::new()),
             guest_cid: 0,
-            uds_path: String::new(),
+            uds_path: Some(String::new()),
+            tcp_ports: Vec::new(),
+            rate_limiter: None,
+            persist_connections: false,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1382,7 +1719,10 @@ mod tests {
         let req = VmmAction::SetVsockDevice(VsockDeviceConfig {
             vsock_id: Some(String::new()),
             guest_cid: 0,
-            uds_path: String::new(),
+            uds_path: Some(String::new()),
+            tcp_ports: Vec::new(),
+            rate_limiter: None,
+            persist_connections: false,
         });
         check_preboot_request_err(
             req,
@@ -1652,6 +1992,8 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: false,
+            vsock_override: None,
+            shared_memory: None,
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1668,6 +2010,8 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: true,
+            vsock_override: None,
+            shared_memory: None,
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1696,6 +2040,23 @@ mod tests {
             VmmAction::GetBalloonStats,
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::GetVsockConnections,
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::ResetVsockConnection(VsockConnectionKey {
+                local_port: 1024,
+                peer_port: 1025,
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::UpdateMemoryHotplug(MemoryHotplugSizeUpdate {
+                requested_size_mib: 0,
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::UpdateBalloon(BalloonUpdateConfig { amount_mib: 0 }),
             VmmActionError::OperationNotSupportedPreBoot,
@@ -1710,11 +2071,29 @@ mod tests {
             VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig::default()),
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::DetachBlockDevice(String::new()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
                 iface_id: String::new(),
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                link_state: None,
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::UpdateVsockDevice(VsockDeviceUpdateConfig { rate_limiter: None }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
+        check_preboot_request_err(
+            VmmAction::UpdateNetworkCapture(NetworkCaptureConfig {
+                iface_id: String::new(),
+                action: PacketCaptureAction::Stop,
+                path_on_host: None,
+                max_size_mib: None,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
@@ -1724,6 +2103,7 @@ mod tests {
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
                 version: None,
+                free_page_hinting: false,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
@@ -1912,6 +2292,88 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_memory_hotplug() {
+        let req = VmmAction::GetMemoryHotplug;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Ok(VmmData::MemoryHotplugStatus(MemoryHotplugStatus::default()))
+            );
+            assert!(vmm.memory_hotplug_config_called)
+        });
+
+        let req = VmmAction::GetMemoryHotplug;
+        check_runtime_request_err(
+            req,
+            VmmActionError::MemoryHotplugConfig(MemoryHotplugConfigError::DeviceNotFound),
+        );
+
+        let req = VmmAction::UpdateMemoryHotplug(MemoryHotplugSizeUpdate {
+            requested_size_mib: 128,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_memory_hotplug_size_called)
+        });
+
+        let req = VmmAction::UpdateMemoryHotplug(MemoryHotplugSizeUpdate {
+            requested_size_mib: 128,
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::MemoryHotplugConfig(MemoryHotplugConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    fn test_runtime_vsock_connections() {
+        let req = VmmAction::GetVsockConnections;
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::VsockConnections(Vec::new())));
+            assert!(vmm.vsock_connections_called)
+        });
+
+        let req = VmmAction::GetVsockConnections;
+        check_runtime_request_err(
+            req,
+            VmmActionError::VsockConfig(VsockConfigError::DeviceNotFound),
+        );
+
+        let key = VsockConnectionKey {
+            local_port: 1024,
+            peer_port: 1025,
+        };
+        let req = VmmAction::ResetVsockConnection(key);
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.reset_vsock_connection_called)
+        });
+
+        let req = VmmAction::ResetVsockConnection(key);
+        check_runtime_request_err(
+            req,
+            VmmActionError::VsockConfig(VsockConfigError::DeviceNotFound),
+        );
+    }
+
+    #[test]
+    fn test_runtime_update_vsock_device() {
+        let req = VmmAction::UpdateVsockDevice(VsockDeviceUpdateConfig {
+            rate_limiter: Some(RateLimiterConfig::default()),
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_vsock_rate_limiter_called)
+        });
+
+        let req = VmmAction::UpdateVsockDevice(VsockDeviceUpdateConfig { rate_limiter: None });
+        check_runtime_request_err(
+            req,
+            VmmActionError::VsockConfig(VsockConfigError::DeviceNotFound),
+        );
+    }
+
     #[test]
     fn test_runtime_update_balloon_config() {
         let req = VmmAction::UpdateBalloon(BalloonUpdateConfig { amount_mib: 0 });
@@ -1969,23 +2431,123 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_hotplug_block_device() {
+        let backing_file = utils::tempfile::TempFile::new().unwrap();
+        let block_cfg = || BlockDeviceConfig {
+            path_on_host: Some(backing_file.as_path().to_str().unwrap().to_string()),
+            socket: None,
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("hotplug"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: 1,
+            direct_io: false,
+        };
+        check_runtime_request(VmmAction::InsertBlockDevice(block_cfg()), |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.hotplug_block_device_called)
+        });
+        check_runtime_request_err(
+            VmmAction::InsertBlockDevice(block_cfg()),
+            VmmActionError::DriveConfig(DriveError::DeviceHotplug(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::NoFreeHotplugSlot,
+            ))),
+        );
+
+        let req = VmmAction::DetachBlockDevice(String::from("hotplug"));
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.unplug_block_device_called)
+        });
+        check_runtime_request_err(
+            VmmAction::DetachBlockDevice(String::from("hotplug")),
+            VmmActionError::DriveConfig(DriveError::DeviceHotUnplug(VmmError::NotHotplugged(
+                String::from("hotplug"),
+            ))),
+        );
+    }
+
     #[test]
     fn test_runtime_update_net_rate_limiters() {
         let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
             iface_id: String::new(),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            link_state: None,
         });
         check_runtime_request(req, |result, vmm| {
             assert_eq!(result, Ok(VmmData::Empty));
-            assert!(vmm.update_net_rate_limiters_called)
+            assert!(vmm.update_net_rate_limiters_called);
+            assert!(!vmm.update_net_link_state_called);
         });
 
         let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
             iface_id: String::new(),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            link_state: Some(LinkState::Down),
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_net_link_state_called);
+        });
+
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            iface_id: String::new(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            link_state: None,
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceUpdate(
+                VmmError::DeviceManager(crate::device_manager::mmio::Error::IncorrectDeviceType),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_runtime_update_net_capture() {
+        let capture_cfg = |action, path_on_host: Option<&str>| NetworkCaptureConfig {
+            iface_id: String::new(),
+            action,
+            path_on_host: path_on_host.map(String::from),
+            max_size_mib: None,
+        };
+
+        let req = VmmAction::UpdateNetworkCapture(capture_cfg(
+            PacketCaptureAction::Start,
+            Some("capture.pcap"),
+        ));
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.start_net_capture_called);
+            assert!(!vmm.stop_net_capture_called);
+        });
+
+        let req = VmmAction::UpdateNetworkCapture(capture_cfg(PacketCaptureAction::Stop, None));
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.stop_net_capture_called);
+        });
+
+        let req = VmmAction::UpdateNetworkCapture(capture_cfg(PacketCaptureAction::Start, None));
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Err(VmmActionError::NetworkConfig(
+                    NetworkInterfaceError::CapturePathMissing
+                ))
+            );
+            assert!(!vmm.start_net_capture_called);
         });
+
+        let req = VmmAction::UpdateNetworkCapture(capture_cfg(PacketCaptureAction::Stop, None));
         check_runtime_request_err(
             req,
             VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceUpdate(
@@ -2015,19 +2577,6 @@ mod tests {
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
-        check_runtime_request_err(
-            VmmAction::InsertBlockDevice(BlockDeviceConfig {
-                path_on_host: String::new(),
-                is_root_device: false,
-                partuuid: None,
-                cache_type: CacheType::Unsafe,
-                is_read_only: false,
-                drive_id: String::new(),
-                rate_limiter: None,
-                file_engine_type: FileEngineType::default(),
-            }),
-            VmmActionError::OperationNotSupportedPostBoot,
-        );
         check_runtime_request_err(
             VmmAction::InsertNetworkDevice(NetworkInterfaceConfig {
                 iface_id: String::new(),
@@ -2035,6 +2584,10 @@ mod tests {
                 guest_mac: None,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                num_queue_pairs: 1,
+                offloads: Offloads::default(),
+                vhost_net: false,
+                anti_spoofing: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2042,7 +2595,10 @@ mod tests {
             VmmAction::SetVsockDevice(VsockDeviceConfig {
                 vsock_id: Some(String::new()),
                 guest_cid: 0,
-                uds_path: String::new(),
+                uds_path: Some(String::new()),
+                tcp_ports: Vec::new(),
+                rate_limiter: None,
+                persist_connections: false,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2050,11 +2606,21 @@ mod tests {
             VmmAction::SetBalloonDevice(BalloonDeviceConfig::default()),
             VmmActionError::OperationNotSupportedPostBoot,
         );
+        check_runtime_request_err(
+            VmmAction::SetMemoryHotplug(MemoryHotplugConfig {
+                total_size_mib: 1024,
+                block_size_mib: 2,
+            }),
+            VmmActionError::OperationNotSupportedPostBoot,
+        );
         check_runtime_request_err(
             VmmAction::SetVsockDevice(VsockDeviceConfig {
                 vsock_id: Some(String::new()),
                 guest_cid: 0,
-                uds_path: String::new(),
+                uds_path: Some(String::new()),
+                tcp_ports: Vec::new(),
+                rate_limiter: None,
+                persist_connections: false,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2079,6 +2645,8 @@ mod tests {
                 },
                 enable_diff_snapshots: false,
                 resume_vm: false,
+                vsock_override: None,
+                shared_memory: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2101,6 +2669,8 @@ mod tests {
             },
             enable_diff_snapshots: false,
             resume_vm: false,
+            vsock_override: None,
+            shared_memory: None,
         });
         let err = preboot.handle_preboot_request(req);
         assert_eq!(
@@ -2120,7 +2690,8 @@ mod tests {
         verify_load_snap_disallowed_after_boot_resources(req, "ConfigureBootSource");
 
         let req = VmmAction::InsertBlockDevice(BlockDeviceConfig {
-            path_on_host: String::new(),
+            path_on_host: Some(String::new()),
+            socket: None,
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -2128,6 +2699,9 @@ mod tests {
             drive_id: String::new(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: 1,
+            direct_io: false,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertBlockDevice");
 
@@ -2137,16 +2711,29 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            offloads: Offloads::default(),
+            vhost_net: false,
+            anti_spoofing: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertNetworkDevice");
 
         let req = VmmAction::SetBalloonDevice(BalloonDeviceConfig::default());
         verify_load_snap_disallowed_after_boot_resources(req, "SetBalloonDevice");
 
+        let req = VmmAction::SetMemoryHotplug(MemoryHotplugConfig {
+            total_size_mib: 1024,
+            block_size_mib: 2,
+        });
+        verify_load_snap_disallowed_after_boot_resources(req, "SetMemoryHotplug");
+
         let req = VmmAction::SetVsockDevice(VsockDeviceConfig {
             vsock_id: Some(String::new()),
             guest_cid: 0,
-            uds_path: String::new(),
+            uds_path: Some(String::new()),
+            tcp_ports: Vec::new(),
+            rate_limiter: None,
+            persist_connections: false,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "SetVsockDevice");
 
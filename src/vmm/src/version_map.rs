@@ -5,7 +5,10 @@
 
 use std::collections::HashMap;
 
+use devices::virtio::balloon::persist::{BalloonConfigSpaceState, BalloonState};
 use devices::virtio::block::persist::BlockState;
+use devices::virtio::net::persist::NetState;
+use devices::virtio::vsock::persist::{VsockFrontendState, VsockUdsState};
 use devices::virtio::QueueState;
 use lazy_static::lazy_static;
 use versionize::{VersionMap, Versionize};
@@ -53,6 +56,13 @@ lazy_static! {
 
         // v1.2 state change mappings.
         version_map.new_version().set_type_version(VmInfo::type_id(), 2);
+        version_map.set_type_version(BlockState::type_id(), 4);
+        version_map.set_type_version(NetState::type_id(), 2);
+        version_map.set_type_version(BalloonState::type_id(), 2);
+        version_map.set_type_version(BalloonConfigSpaceState::type_id(), 2);
+        version_map.set_type_version(DeviceStates::type_id(), 4);
+        version_map.set_type_version(VsockFrontendState::type_id(), 2);
+        version_map.set_type_version(VsockUdsState::type_id(), 2);
 
         version_map
     };
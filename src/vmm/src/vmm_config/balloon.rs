@@ -5,6 +5,7 @@ use std::fmt;
 use std::sync::{Arc, Mutex};
 
 pub use devices::virtio::balloon::device::BalloonStats;
+pub use devices::virtio::balloon::BalloonAutoSize;
 pub use devices::virtio::BALLOON_DEV_ID;
 use devices::virtio::{Balloon, BalloonConfig};
 use serde::{Deserialize, Serialize};
@@ -67,6 +68,16 @@ pub struct BalloonDeviceConfig {
     /// Interval in seconds between refreshing statistics.
     #[serde(default)]
     pub stats_polling_interval_s: u16,
+    /// Option to let full snapshots ask the guest for its free memory, which is then left
+    /// out of the memory file.
+    #[serde(default)]
+    pub free_page_hinting: bool,
+    /// Option to release the memory the guest reports as free.
+    #[serde(default)]
+    pub free_page_reporting: bool,
+    /// Policy resizing the balloon from the statistics of the guest.
+    #[serde(default)]
+    pub auto_size: Option<BalloonAutoSize>,
 }
 
 impl From<BalloonConfig> for BalloonDeviceConfig {
@@ -75,6 +86,9 @@ impl From<BalloonConfig> for BalloonDeviceConfig {
             amount_mib: state.amount_mib,
             deflate_on_oom: state.deflate_on_oom,
             stats_polling_interval_s: state.stats_polling_interval_s,
+            free_page_hinting: state.free_page_hinting,
+            free_page_reporting: state.free_page_reporting,
+            auto_size: state.auto_size,
         }
     }
 }
@@ -114,14 +128,18 @@ impl BalloonBuilder {
     /// Inserts a Balloon device in the store.
     /// If an entry already exists, it will overwrite it.
     pub fn set(&mut self, cfg: BalloonDeviceConfig) -> Result<()> {
-        self.inner = Some(Arc::new(Mutex::new(Balloon::new(
+        let mut balloon = Balloon::new(
             cfg.amount_mib,
             cfg.deflate_on_oom,
             cfg.stats_polling_interval_s,
+            cfg.free_page_hinting,
+            cfg.free_page_reporting,
             // `restored` flag is false because this code path
             // is never called by snapshot restore functionality.
             false,
-        )?)));
+        )?;
+        balloon.set_auto_size(cfg.auto_size)?;
+        self.inner = Some(Arc::new(Mutex::new(balloon)));
 
         Ok(())
     }
@@ -154,6 +172,9 @@ pub(crate) mod tests {
             amount_mib: 0,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            free_page_hinting: false,
+            free_page_reporting: false,
+            auto_size: None,
         }
     }
 
@@ -172,6 +193,9 @@ pub(crate) mod tests {
             amount_mib: 0,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            free_page_hinting: false,
+            free_page_reporting: false,
+            auto_size: None,
         };
         assert_eq!(default_balloon_config, balloon_config);
         let mut builder = BalloonBuilder::new();
@@ -187,18 +211,51 @@ pub(crate) mod tests {
         };
     }
 
+    #[test]
+    fn test_balloon_auto_size() {
+        let auto_size = BalloonAutoSize {
+            min_mib: 0,
+            max_mib: 256,
+            target_free_mib: 64,
+            step_mib: 16,
+        };
+        let mut builder = BalloonBuilder::new();
+
+        // The policy needs the statistics.
+        let mut balloon_config = BalloonDeviceConfig {
+            auto_size: Some(auto_size),
+            ..default_config()
+        };
+        assert!(matches!(
+            builder.set(balloon_config.clone()),
+            Err(BalloonConfigError::CreateFailure(
+                devices::virtio::balloon::Error::StatisticsDisabled
+            ))
+        ));
+
+        balloon_config.stats_polling_interval_s = 1;
+        builder.set(balloon_config.clone()).unwrap();
+        assert_eq!(builder.get_config().unwrap(), balloon_config);
+    }
+
     #[test]
     fn test_from_balloon_state() {
         let expected_balloon_config = BalloonDeviceConfig {
             amount_mib: 5,
             deflate_on_oom: false,
             stats_polling_interval_s: 3,
+            free_page_hinting: true,
+            free_page_reporting: true,
+            auto_size: None,
         };
 
         let actual_balloon_config = BalloonDeviceConfig::from(BalloonConfig {
             amount_mib: 5,
             deflate_on_oom: false,
             stats_polling_interval_s: 3,
+            free_page_hinting: true,
+            free_page_reporting: true,
+            auto_size: None,
         });
 
         assert_eq!(expected_balloon_config, actual_balloon_config);
@@ -233,7 +290,7 @@ pub(crate) mod tests {
     #[test]
     fn test_set_device() {
         let mut builder = BalloonBuilder::new();
-        let balloon = Balloon::new(0, true, 0, true).unwrap();
+        let balloon = Balloon::new(0, true, 0, false, false, true).unwrap();
         builder.set_device(Arc::new(Mutex::new(balloon)));
         assert!(builder.inner.is_some());
     }
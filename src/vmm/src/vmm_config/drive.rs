@@ -9,10 +9,11 @@ use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::{io, result};
 
-pub use devices::virtio::block::device::FileEngineType;
-use devices::virtio::block::Error as BlockError;
-use devices::virtio::Block;
+pub use devices::virtio::block::device::{DiskFormat, FileEngineType};
+use devices::virtio::block::{Error as BlockError, DEFAULT_NUM_QUEUES};
+use devices::virtio::vhost_user_block::Error as VhostUserBlockError;
 pub use devices::virtio::CacheType;
+use devices::virtio::{Block, VhostUserBlock};
 use serde::{Deserialize, Serialize};
 
 use super::RateLimiterConfig;
@@ -30,14 +31,28 @@ pub enum DriveError {
     CreateBlockDevice(BlockError),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(io::Error),
+    /// Unable to connect to or negotiate with the vhost-user backend of the block device.
+    CreateVhostUserBlockDevice(VhostUserBlockError),
+    /// Error during drive hot-plug.
+    DeviceHotplug(VmmError),
+    /// Error during drive hot-unplug.
+    DeviceHotUnplug(VmmError),
     /// Error during drive update (patch).
     DeviceUpdate(VmmError),
+    /// A drive with the same id already exists.
+    DriveIdAlreadyExists(String),
+    /// Root block devices cannot be hot-plugged.
+    HotplugRootDevice,
     /// The block device path is invalid.
     InvalidBlockDevicePath(String),
+    /// Exactly one of the host path and the vhost-user socket has to be specified.
+    InvalidDriveBackend,
     /// Cannot open block device due to invalid permissions or path.
     OpenBlockDevice(io::Error),
     /// A root block device was already added.
     RootBlockDeviceAlreadyAdded,
+    /// The option is not supported by block devices served by a vhost-user backend.
+    UnsupportedVhostUserOption(&'static str),
 }
 
 impl Display for DriveError {
@@ -47,14 +62,30 @@ impl Display for DriveError {
             CreateBlockDevice(err) => write!(f, "Unable to create the block device {:?}", err),
             BlockDeviceUpdateFailed(err) => write!(f, "The update operation failed: {}", err),
             CreateRateLimiter(err) => write!(f, "Cannot create RateLimiter: {}", err),
+            CreateVhostUserBlockDevice(err) => {
+                write!(f, "Unable to create the vhost-user block device {:?}", err)
+            }
+            DeviceHotplug(err) => write!(f, "Error during drive hot-plug: {}", err),
+            DeviceHotUnplug(err) => write!(f, "Error during drive hot-unplug: {}", err),
             DeviceUpdate(err) => write!(f, "Error during drive update (patch): {}", err),
+            DriveIdAlreadyExists(id) => write!(f, "A drive with id {} already exists.", id),
+            HotplugRootDevice => write!(f, "A root block device cannot be hot-plugged."),
             InvalidBlockDevicePath(path) => write!(f, "Invalid block device path: {}", path),
+            InvalidDriveBackend => write!(
+                f,
+                "Exactly one of path_on_host and socket has to be specified."
+            ),
             OpenBlockDevice(err) => write!(
                 f,
                 "Cannot open block device. Invalid permission/path: {}",
                 err
             ),
             RootBlockDeviceAlreadyAdded => write!(f, "A root block device already exists!"),
+            UnsupportedVhostUserOption(option) => write!(
+                f,
+                "The {} option is not supported by vhost-user block devices.",
+                option
+            ),
         }
     }
 }
@@ -66,7 +97,11 @@ pub struct BlockDeviceConfig {
     /// Unique identifier of the drive.
     pub drive_id: String,
     /// Path of the drive.
-    pub path_on_host: String,
+    pub path_on_host: Option<String>,
+    /// Path of the vhost-user socket of the backend serving the drive. It is mutually
+    /// exclusive with `path_on_host`.
+    #[serde(default)]
+    pub socket: Option<String>,
     /// If set to true, it makes the current device the root block device.
     /// Setting this flag to true will mount the block device in the
     /// guest under /dev/vda unless the partuuid is present.
@@ -87,6 +122,19 @@ pub struct BlockDeviceConfig {
     #[serde(default)]
     #[serde(rename = "io_engine")]
     pub file_engine_type: FileEngineType,
+    /// The format of the disk image.
+    #[serde(default)]
+    pub disk_format: DiskFormat,
+    /// The number of virtio queues exposed to the guest.
+    #[serde(default = "default_num_queues")]
+    pub num_queues: u16,
+    /// If set to true, the disk image is opened with `O_DIRECT`, bypassing the host page cache.
+    #[serde(default)]
+    pub direct_io: bool,
+}
+
+fn default_num_queues() -> u16 {
+    DEFAULT_NUM_QUEUES
 }
 
 impl From<&Block> for BlockDeviceConfig {
@@ -94,13 +142,36 @@ impl From<&Block> for BlockDeviceConfig {
         let rl: RateLimiterConfig = block.rate_limiter().into();
         BlockDeviceConfig {
             drive_id: block.id().clone(),
-            path_on_host: block.file_path().clone(),
+            path_on_host: Some(block.file_path().clone()),
+            socket: None,
             is_root_device: block.is_root_device(),
             partuuid: block.partuuid().cloned(),
             is_read_only: block.is_read_only(),
             cache_type: block.cache_type(),
             rate_limiter: rl.into_option(),
             file_engine_type: block.file_engine_type(),
+            disk_format: block.disk_format(),
+            num_queues: block.num_queues(),
+            direct_io: block.direct_io(),
+        }
+    }
+}
+
+impl From<&VhostUserBlock> for BlockDeviceConfig {
+    fn from(block: &VhostUserBlock) -> Self {
+        BlockDeviceConfig {
+            drive_id: block.id().clone(),
+            path_on_host: None,
+            socket: Some(block.socket_path().clone()),
+            is_root_device: block.is_root_device(),
+            partuuid: block.partuuid().cloned(),
+            is_read_only: block.is_read_only(),
+            cache_type: block.cache_type(),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: block.num_queues(),
+            direct_io: false,
         }
     }
 }
@@ -127,6 +198,9 @@ pub struct BlockBuilder {
     // specified in order to avoid bugs in case of switching from partuuid boot
     // scenarios to /dev/vda boot type.
     pub list: VecDeque<Arc<Mutex<Block>>>,
+    /// The list of block devices served by vhost-user backends, following the same ordering
+    /// rules as `list`.
+    pub vhost_user_list: VecDeque<Arc<Mutex<VhostUserBlock>>>,
 }
 
 impl BlockBuilder {
@@ -134,17 +208,43 @@ impl BlockBuilder {
     pub fn new() -> Self {
         Self {
             list: VecDeque::<Arc<Mutex<Block>>>::new(),
+            vhost_user_list: VecDeque::<Arc<Mutex<VhostUserBlock>>>::new(),
         }
     }
 
-    /// Specifies whether there is a root block device already present in the list.
-    fn has_root_device(&self) -> bool {
-        // If there is a root device, it would be at the top of the list.
+    /// Provides the id of the root block device, if any of the lists contains one.
+    fn root_device_id(&self) -> Option<String> {
+        // If there is a root device, it would be at the top of its list.
         if let Some(block) = self.list.get(0) {
-            block.lock().expect("Poisoned lock").is_root_device()
-        } else {
-            false
+            let block = block.lock().expect("Poisoned lock");
+            if block.is_root_device() {
+                return Some(block.id().clone());
+            }
         }
+        if let Some(block) = self.vhost_user_list.get(0) {
+            let block = block.lock().expect("Poisoned lock");
+            if block.is_root_device() {
+                return Some(block.id().clone());
+            }
+        }
+        None
+    }
+
+    /// Specifies whether there is a root block device already present in the lists.
+    fn has_root_device(&self) -> bool {
+        self.root_device_id().is_some()
+    }
+
+    /// Specifies whether the root block device is served by a vhost-user backend.
+    pub fn has_vhost_user_root_device(&self) -> bool {
+        self.vhost_user_list
+            .get(0)
+            .map_or(false, |b| b.lock().expect("Poisoned lock").is_root_device())
+    }
+
+    /// Specifies whether any of the block devices is served by a vhost-user backend.
+    pub fn has_vhost_user_devices(&self) -> bool {
+        !self.vhost_user_list.is_empty()
     }
 
     /// Gets the index of the device with the specified `drive_id` if it exists in the list.
@@ -154,6 +254,14 @@ impl BlockBuilder {
             .position(|b| b.lock().expect("Poisoned lock").id().eq(drive_id))
     }
 
+    /// Gets the index of the vhost-user device with the specified `drive_id` if it exists in
+    /// the vhost-user list.
+    fn get_vhost_user_index_of_drive_id(&self, drive_id: &str) -> Option<usize> {
+        self.vhost_user_list
+            .iter()
+            .position(|b| b.lock().expect("Poisoned lock").id().eq(drive_id))
+    }
+
     /// Inserts an existing block device.
     pub fn add_device(&mut self, block_device: Arc<Mutex<Block>>) {
         if block_device.lock().expect("Poisoned lock").is_root_device() {
@@ -163,49 +271,111 @@ impl BlockBuilder {
         }
     }
 
-    /// Inserts a `Block` in the block devices list using the specified configuration.
+    /// Removes the block device with the specified `drive_id`, if it exists in the list.
+    pub fn remove_device(&mut self, drive_id: &str) -> Option<Arc<Mutex<Block>>> {
+        let index = self.get_index_of_drive_id(drive_id)?;
+        self.list.remove(index)
+    }
+
+    /// Creates a `Block` to be hot-plugged in the running microVM. Unlike `insert()`, this
+    /// never overwrites an existing device. The device has to be inserted in the list with
+    /// `add_device()` once it is plugged.
+    pub fn create_hotplug_block(&self, config: BlockDeviceConfig) -> Result<Arc<Mutex<Block>>> {
+        if config.socket.is_some() {
+            return Err(DriveError::UnsupportedVhostUserOption("hot-plug"));
+        }
+        if config.is_root_device {
+            return Err(DriveError::HotplugRootDevice);
+        }
+        if self.get_index_of_drive_id(&config.drive_id).is_some()
+            || self
+                .get_vhost_user_index_of_drive_id(&config.drive_id)
+                .is_some()
+        {
+            return Err(DriveError::DriveIdAlreadyExists(config.drive_id));
+        }
+        Ok(Arc::new(Mutex::new(Self::create_block(config)?)))
+    }
+
+    /// Inserts a `Block` or a `VhostUserBlock` in the matching block devices list using the
+    /// specified configuration.
     /// If a block with the same id already exists, it will overwrite it.
     /// Inserting a secondary root block device will fail.
     pub fn insert(&mut self, config: BlockDeviceConfig) -> Result<()> {
+        if config.path_on_host.is_some() == config.socket.is_some() {
+            return Err(DriveError::InvalidDriveBackend);
+        }
+
         let is_root_device = config.is_root_device;
-        let position = self.get_index_of_drive_id(&config.drive_id);
-        let has_root_block = self.has_root_device();
+        let drive_id = config.drive_id.clone();
 
         // Don't allow adding a second root block device.
         // If the new device cfg is root and not an update to the existing root, fail fast.
-        if is_root_device && has_root_block && position != Some(0) {
+        if is_root_device && self.root_device_id().map_or(false, |id| id != drive_id) {
             return Err(DriveError::RootBlockDeviceAlreadyAdded);
         }
 
-        let block_dev = Arc::new(Mutex::new(Self::create_block(config)?));
-        // If the id of the drive already exists in the list, the operation is update/overwrite.
+        // The device may switch between the two backends on update.
+        if config.socket.is_some() {
+            let block_dev = Arc::new(Mutex::new(Self::create_vhost_user_block(config)?));
+            let position = self.get_vhost_user_index_of_drive_id(&drive_id);
+            Self::insert_into_list(
+                &mut self.vhost_user_list,
+                position,
+                block_dev,
+                is_root_device,
+            );
+            if let Some(index) = self.get_index_of_drive_id(&drive_id) {
+                self.list.remove(index);
+            }
+        } else {
+            let block_dev = Arc::new(Mutex::new(Self::create_block(config)?));
+            let position = self.get_index_of_drive_id(&drive_id);
+            Self::insert_into_list(&mut self.list, position, block_dev, is_root_device);
+            if let Some(index) = self.get_vhost_user_index_of_drive_id(&drive_id) {
+                self.vhost_user_list.remove(index);
+            }
+        }
+        Ok(())
+    }
+
+    // Inserts `block_dev` at `position` if the operation is an update/overwrite, otherwise
+    // appends it, keeping the root device first in the list.
+    fn insert_into_list<T>(
+        list: &mut VecDeque<Arc<Mutex<T>>>,
+        position: Option<usize>,
+        block_dev: Arc<Mutex<T>>,
+        is_root_device: bool,
+    ) {
         match position {
             // New block device.
             None => {
                 if is_root_device {
-                    self.list.push_front(block_dev);
+                    list.push_front(block_dev);
                 } else {
-                    self.list.push_back(block_dev);
+                    list.push_back(block_dev);
                 }
             }
             // Update existing block device.
             Some(index) => {
                 // Update the slot with the new block.
-                self.list[index] = block_dev;
+                list[index] = block_dev;
                 // Check if the root block device is being updated.
                 if index != 0 && is_root_device {
                     // Make sure the root device is on the first position.
-                    self.list.swap(0, index);
+                    list.swap(0, index);
                 }
             }
         }
-        Ok(())
     }
 
     /// Creates a Block device from a BlockDeviceConfig.
     pub fn create_block(block_device_config: BlockDeviceConfig) -> Result<Block> {
+        let path_on_host_str = block_device_config
+            .path_on_host
+            .ok_or(DriveError::InvalidDriveBackend)?;
         // check if the path exists
-        let path_on_host = PathBuf::from(&block_device_config.path_on_host);
+        let path_on_host = PathBuf::from(&path_on_host_str);
         if !path_on_host.exists() {
             return Err(DriveError::InvalidBlockDevicePath(format!(
                 "{}",
@@ -224,21 +394,61 @@ impl BlockBuilder {
             block_device_config.drive_id,
             block_device_config.partuuid,
             block_device_config.cache_type,
-            block_device_config.path_on_host,
+            path_on_host_str,
             block_device_config.is_read_only,
             block_device_config.is_root_device,
             rate_limiter.unwrap_or_default(),
             block_device_config.file_engine_type,
+            block_device_config.disk_format,
+            block_device_config.num_queues,
+            block_device_config.direct_io,
         )
         .map_err(DriveError::CreateBlockDevice)
     }
 
+    /// Creates a VhostUserBlock device from a BlockDeviceConfig.
+    pub fn create_vhost_user_block(
+        block_device_config: BlockDeviceConfig,
+    ) -> Result<VhostUserBlock> {
+        let socket = block_device_config
+            .socket
+            .ok_or(DriveError::InvalidDriveBackend)?;
+        // The requests are processed by the backend, so only the options that configure the
+        // virtio device are honored.
+        if block_device_config.rate_limiter.is_some() {
+            return Err(DriveError::UnsupportedVhostUserOption("rate_limiter"));
+        }
+        if block_device_config.file_engine_type != FileEngineType::default() {
+            return Err(DriveError::UnsupportedVhostUserOption("io_engine"));
+        }
+        if block_device_config.disk_format != DiskFormat::default() {
+            return Err(DriveError::UnsupportedVhostUserOption("disk_format"));
+        }
+        if block_device_config.direct_io {
+            return Err(DriveError::UnsupportedVhostUserOption("direct_io"));
+        }
+
+        VhostUserBlock::new(
+            block_device_config.drive_id,
+            block_device_config.partuuid,
+            block_device_config.cache_type,
+            socket,
+            block_device_config.is_read_only,
+            block_device_config.is_root_device,
+            block_device_config.num_queues,
+        )
+        .map_err(DriveError::CreateVhostUserBlockDevice)
+    }
+
     /// Returns a vec with the structures used to configure the devices.
     pub fn configs(&self) -> Vec<BlockDeviceConfig> {
         let mut ret = vec![];
         for block in &self.list {
             ret.push(BlockDeviceConfig::from(block.lock().unwrap().deref()));
         }
+        for block in &self.vhost_user_list {
+            ret.push(BlockDeviceConfig::from(block.lock().unwrap().deref()));
+        }
         ret
     }
 }
@@ -262,6 +472,7 @@ mod tests {
         fn clone(&self) -> Self {
             BlockDeviceConfig {
                 path_on_host: self.path_on_host.clone(),
+                socket: self.socket.clone(),
                 is_root_device: self.is_root_device,
                 partuuid: self.partuuid.clone(),
                 cache_type: self.cache_type,
@@ -269,6 +480,9 @@ mod tests {
                 drive_id: self.drive_id.clone(),
                 rate_limiter: None,
                 file_engine_type: FileEngineType::default(),
+                disk_format: self.disk_format,
+                num_queues: self.num_queues,
+                direct_io: self.direct_io,
             }
         }
     }
@@ -285,7 +499,8 @@ mod tests {
         let dummy_path = dummy_file.as_path().to_str().unwrap().to_string();
         let dummy_id = String::from("1");
         let dummy_block_device = BlockDeviceConfig {
-            path_on_host: dummy_path,
+            path_on_host: Some(dummy_path),
+            socket: None,
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Writeback,
@@ -293,6 +508,9 @@ mod tests {
             drive_id: dummy_id.clone(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -316,7 +534,8 @@ mod tests {
         let dummy_path = dummy_file.as_path().to_str().unwrap().to_string();
 
         let dummy_block_device = BlockDeviceConfig {
-            path_on_host: dummy_path,
+            path_on_host: Some(dummy_path),
+            socket: None,
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -324,6 +543,9 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -344,7 +566,8 @@ mod tests {
         let dummy_file_1 = TempFile::new().unwrap();
         let dummy_path_1 = dummy_file_1.as_path().to_str().unwrap().to_string();
         let root_block_device_1 = BlockDeviceConfig {
-            path_on_host: dummy_path_1,
+            path_on_host: Some(dummy_path_1),
+            socket: None,
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -352,12 +575,16 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
         let dummy_path_2 = dummy_file_2.as_path().to_str().unwrap().to_string();
         let root_block_device_2 = BlockDeviceConfig {
-            path_on_host: dummy_path_2,
+            path_on_host: Some(dummy_path_2),
+            socket: None,
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -365,6 +592,9 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -381,7 +611,8 @@ mod tests {
         let dummy_file_1 = TempFile::new().unwrap();
         let dummy_path_1 = dummy_file_1.as_path().to_str().unwrap().to_string();
         let root_block_device = BlockDeviceConfig {
-            path_on_host: dummy_path_1,
+            path_on_host: Some(dummy_path_1),
+            socket: None,
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -389,12 +620,16 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
         let dummy_path_2 = dummy_file_2.as_path().to_str().unwrap().to_string();
         let dummy_block_dev_2 = BlockDeviceConfig {
-            path_on_host: dummy_path_2,
+            path_on_host: Some(dummy_path_2),
+            socket: None,
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -402,12 +637,16 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
         let dummy_path_3 = dummy_file_3.as_path().to_str().unwrap().to_string();
         let dummy_block_dev_3 = BlockDeviceConfig {
-            path_on_host: dummy_path_3,
+            path_on_host: Some(dummy_path_3),
+            socket: None,
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -415,6 +654,9 @@ mod tests {
             drive_id: String::from("3"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -445,7 +687,8 @@ mod tests {
         let dummy_file_1 = TempFile::new().unwrap();
         let dummy_path_1 = dummy_file_1.as_path().to_str().unwrap().to_string();
         let root_block_device = BlockDeviceConfig {
-            path_on_host: dummy_path_1,
+            path_on_host: Some(dummy_path_1),
+            socket: None,
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -453,12 +696,16 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
         let dummy_path_2 = dummy_file_2.as_path().to_str().unwrap().to_string();
         let dummy_block_dev_2 = BlockDeviceConfig {
-            path_on_host: dummy_path_2,
+            path_on_host: Some(dummy_path_2),
+            socket: None,
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -466,12 +713,16 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
         let dummy_path_3 = dummy_file_3.as_path().to_str().unwrap().to_string();
         let dummy_block_dev_3 = BlockDeviceConfig {
-            path_on_host: dummy_path_3,
+            path_on_host: Some(dummy_path_3),
+            socket: None,
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -479,6 +730,9 @@ mod tests {
             drive_id: String::from("3"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -510,7 +764,8 @@ mod tests {
         let dummy_file_1 = TempFile::new().unwrap();
         let dummy_path_1 = dummy_file_1.as_path().to_str().unwrap().to_string();
         let root_block_device = BlockDeviceConfig {
-            path_on_host: dummy_path_1.clone(),
+            path_on_host: Some(dummy_path_1.clone()),
+            socket: None,
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -518,12 +773,16 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
         let dummy_path_2 = dummy_file_2.as_path().to_str().unwrap().to_string();
         let mut dummy_block_device_2 = BlockDeviceConfig {
-            path_on_host: dummy_path_2.clone(),
+            path_on_host: Some(dummy_path_2.clone()),
+            socket: None,
             is_root_device: false,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -531,6 +790,9 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -567,14 +829,14 @@ mod tests {
 
         // Update with invalid path.
         let dummy_path_3 = String::from("test_update_3");
-        dummy_block_device_2.path_on_host = dummy_path_3.clone();
+        dummy_block_device_2.path_on_host = Some(dummy_path_3.clone());
         assert_eq!(
             block_devs.insert(dummy_block_device_2.clone()),
             Err(DriveError::InvalidBlockDevicePath(dummy_path_3))
         );
 
         // Update with 2 root block devices.
-        dummy_block_device_2.path_on_host = dummy_path_2.clone();
+        dummy_block_device_2.path_on_host = Some(dummy_path_2.clone());
         dummy_block_device_2.is_root_device = true;
         assert_eq!(
             block_devs.insert(dummy_block_device_2),
@@ -582,7 +844,8 @@ mod tests {
         );
 
         let root_block_device = BlockDeviceConfig {
-            path_on_host: dummy_path_1,
+            path_on_host: Some(dummy_path_1),
+            socket: None,
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -590,12 +853,16 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
         // Switch roots and add a PARTUUID for the new one.
         let mut root_block_device_old = root_block_device;
         root_block_device_old.is_root_device = false;
         let root_block_device_new = BlockDeviceConfig {
-            path_on_host: dummy_path_2,
+            path_on_host: Some(dummy_path_2),
+            socket: None,
             is_root_device: true,
             partuuid: Some("0eaa91a0-01".to_string()),
             cache_type: CacheType::Unsafe,
@@ -603,6 +870,9 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
         assert!(block_devs.insert(root_block_device_old).is_ok());
         let root_block_id = root_block_device_new.drive_id.clone();
@@ -617,7 +887,8 @@ mod tests {
         let dummy_file = TempFile::new().unwrap();
 
         let dummy_block_device = BlockDeviceConfig {
-            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            path_on_host: Some(dummy_file.as_path().to_str().unwrap().to_string()),
+            socket: None,
             is_root_device: true,
             partuuid: None,
             cache_type: CacheType::Unsafe,
@@ -625,6 +896,9 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -635,6 +909,87 @@ mod tests {
         assert_eq!(configs.first().unwrap(), &dummy_block_device);
     }
 
+    #[test]
+    fn test_drive_backend() {
+        let dummy_file = TempFile::new().unwrap();
+        let mut block_device = BlockDeviceConfig {
+            path_on_host: Some(dummy_file.as_path().to_str().unwrap().to_string()),
+            socket: Some(String::from("/invalid/socket")),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
+        };
+        let mut block_devs = BlockBuilder::new();
+
+        // Both backends specified.
+        assert_eq!(
+            block_devs.insert(block_device.clone()),
+            Err(DriveError::InvalidDriveBackend)
+        );
+        // No backend specified.
+        block_device.path_on_host = None;
+        block_device.socket = None;
+        assert_eq!(
+            block_devs.insert(block_device.clone()),
+            Err(DriveError::InvalidDriveBackend)
+        );
+
+        // The options handled by Firecracker's own block backend are rejected.
+        block_device.socket = Some(String::from("/invalid/socket"));
+        block_device.file_engine_type = FileEngineType::Async;
+        assert_eq!(
+            block_devs.insert(block_device.clone()),
+            Err(DriveError::UnsupportedVhostUserOption("io_engine"))
+        );
+        block_device.file_engine_type = FileEngineType::Sync;
+        block_device.disk_format = DiskFormat::Qcow2;
+        assert_eq!(
+            block_devs.insert(block_device.clone()),
+            Err(DriveError::UnsupportedVhostUserOption("disk_format"))
+        );
+        block_device.disk_format = DiskFormat::Raw;
+        block_device.rate_limiter = Some(RateLimiterConfig::default());
+        assert_eq!(
+            block_devs.insert(block_device),
+            Err(DriveError::UnsupportedVhostUserOption("rate_limiter"))
+        );
+
+        assert!(block_devs.list.is_empty());
+        assert!(!block_devs.has_vhost_user_devices());
+    }
+
+    #[test]
+    fn test_vhost_user_connect_failure() {
+        let block_device = BlockDeviceConfig {
+            path_on_host: None,
+            socket: Some(String::from("/invalid/socket")),
+            is_root_device: true,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
+        };
+        let mut block_devs = BlockBuilder::new();
+        match block_devs.insert(block_device) {
+            Err(DriveError::CreateVhostUserBlockDevice(_)) => (),
+            _ => unreachable!(),
+        }
+        assert!(!block_devs.has_root_device());
+        assert!(!block_devs.has_vhost_user_root_device());
+    }
+
     #[test]
     fn test_add_device() {
         let mut block_devs = BlockBuilder::new();
@@ -649,6 +1004,9 @@ mod tests {
             true,
             RateLimiter::default(),
             FileEngineType::default(),
+            DiskFormat::default(),
+            DEFAULT_NUM_QUEUES,
+            false,
         )
         .unwrap();
 
@@ -666,4 +1024,55 @@ mod tests {
             block_id
         )
     }
+
+    #[test]
+    fn test_hotplug_block() {
+        let dummy_file = TempFile::new().unwrap();
+        let dummy_path = dummy_file.as_path().to_str().unwrap().to_string();
+        let mut config = BlockDeviceConfig {
+            path_on_host: Some(dummy_path),
+            socket: None,
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("hotplug"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            disk_format: DiskFormat::default(),
+            num_queues: DEFAULT_NUM_QUEUES,
+            direct_io: false,
+        };
+        let mut block_devs = BlockBuilder::new();
+
+        let block = block_devs.create_hotplug_block(config.clone()).unwrap();
+        // Creating the device doesn't track it.
+        assert!(block_devs.list.is_empty());
+        block_devs.add_device(block);
+        assert_eq!(block_devs.get_index_of_drive_id("hotplug"), Some(0));
+
+        assert_eq!(
+            block_devs.create_hotplug_block(config.clone()).unwrap_err(),
+            DriveError::DriveIdAlreadyExists(String::from("hotplug"))
+        );
+
+        config.drive_id = String::from("root");
+        config.is_root_device = true;
+        assert_eq!(
+            block_devs.create_hotplug_block(config.clone()).unwrap_err(),
+            DriveError::HotplugRootDevice
+        );
+
+        config.is_root_device = false;
+        config.path_on_host = None;
+        config.socket = Some(String::from("/tmp/vhost-user-blk.sock"));
+        assert_eq!(
+            block_devs.create_hotplug_block(config).unwrap_err(),
+            DriveError::UnsupportedVhostUserOption("hot-plug")
+        );
+
+        assert!(block_devs.remove_device("hotplug").is_some());
+        assert!(block_devs.list.is_empty());
+        assert!(block_devs.remove_device("hotplug").is_none());
+    }
 }
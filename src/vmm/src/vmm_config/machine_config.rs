@@ -1,16 +1,20 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 use std::fmt;
+use std::path::PathBuf;
 
 use serde::{de, Deserialize, Serialize};
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
+use vm_memory::HugePageSize;
 
 /// The default memory size of the VM, in MiB.
 pub const DEFAULT_MEM_SIZE_MIB: usize = 128;
 /// Firecracker aims to support small scale workloads only, so limit the maximum
 /// vCPUs supported.
 pub const MAX_SUPPORTED_VCPUS: u8 = 32;
+/// Maximum number of MMIO slots which can be reserved for hot-plugging block devices.
+pub const MAX_BLOCK_HOTPLUG_SLOTS: u8 = 8;
 
 /// Errors associated with configuring the microVM.
 #[derive(Debug, PartialEq, Eq)]
@@ -22,6 +26,10 @@ pub enum VmConfigError {
     /// The vcpu count is invalid. When SMT is enabled, the `cpu_count` must be either
     /// 1 or an even number.
     InvalidVcpuCount,
+    /// The number of block device hot-plug slots exceeds `MAX_BLOCK_HOTPLUG_SLOTS`.
+    InvalidBlockHotplugSlots,
+    /// The memory regions of the guest can't be made of whole huge pages.
+    InvalidHugePageMemorySize,
     /// Could not get the config of the balloon device from the VM resources, even though a
     /// balloon device was previously installed.
     InvalidVmState,
@@ -43,6 +51,16 @@ impl fmt::Display for VmConfigError {
                 "The vCPU number is invalid! The vCPU number can only be 1 or an even number when \
                  SMT is enabled.",
             ),
+            InvalidBlockHotplugSlots => write!(
+                f,
+                "The number of block device hot-plug slots is invalid! It cannot exceed {}.",
+                MAX_BLOCK_HOTPLUG_SLOTS
+            ),
+            InvalidHugePageMemorySize => write!(
+                f,
+                "The memory size (MiB) is invalid! The guest memory regions must be made of \
+                 whole huge pages.",
+            ),
             InvalidVmState => write!(
                 f,
                 "Could not get the configuration of the previously installed balloon device to \
@@ -75,6 +93,15 @@ pub struct VmConfig {
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(default)]
     pub track_dirty_pages: bool,
+    /// Number of MMIO slots reserved at boot time for hot-plugging block devices.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub block_hotplug_slots: u8,
+    /// The huge pages backing the guest memory.
+    #[serde(default, skip_serializing_if = "HugePageConfig::is_none")]
+    pub huge_pages: HugePageConfig,
+    /// Shares the guest memory with other processes, if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shared_memory: Option<SharedMemoryConfig>,
 }
 
 impl Default for VmConfig {
@@ -85,6 +112,9 @@ impl Default for VmConfig {
             smt: false,
             cpu_template: CpuFeaturesTemplate::None,
             track_dirty_pages: false,
+            block_hotplug_slots: 0,
+            huge_pages: HugePageConfig::None,
+            shared_memory: None,
         }
     }
 }
@@ -94,8 +124,16 @@ impl fmt::Display for VmConfig {
         write!(
             f,
             "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?}, \"smt\": {:?}, \"cpu_template\": \
-             {:?}, \"track_dirty_pages\": {:?} }}",
-            self.vcpu_count, self.mem_size_mib, self.smt, self.cpu_template, self.track_dirty_pages
+             {:?}, \"track_dirty_pages\": {:?}, \"block_hotplug_slots\": {:?}, \"huge_pages\": \
+             {:?}, \"shared_memory\": {:?} }}",
+            self.vcpu_count,
+            self.mem_size_mib,
+            self.smt,
+            self.cpu_template,
+            self.track_dirty_pages,
+            self.block_hotplug_slots,
+            self.huge_pages,
+            self.shared_memory
         )
     }
 }
@@ -135,6 +173,19 @@ pub struct VmUpdateConfig {
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub track_dirty_pages: Option<bool>,
+    /// Number of MMIO slots reserved at boot time for hot-plugging block devices.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_hotplug_slots: Option<u8>,
+    /// The huge pages backing the guest memory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub huge_pages: Option<HugePageConfig>,
+    /// Shares the guest memory with other processes. `Some(None)` stops sharing it.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_shared_memory"
+    )]
+    pub shared_memory: Option<Option<SharedMemoryConfig>>,
 }
 
 impl VmUpdateConfig {
@@ -147,6 +198,9 @@ impl VmUpdateConfig {
             && self.cpu_template.is_none()
             && self.smt.is_none()
             && self.track_dirty_pages.is_none()
+            && self.block_hotplug_slots.is_none()
+            && self.huge_pages.is_none()
+            && self.shared_memory.is_none()
         {
             return true;
         }
@@ -163,10 +217,29 @@ impl From<VmConfig> for VmUpdateConfig {
             smt: Some(cfg.smt),
             cpu_template: Some(cfg.cpu_template),
             track_dirty_pages: Some(cfg.track_dirty_pages),
+            block_hotplug_slots: Some(cfg.block_hotplug_slots),
+            huge_pages: Some(cfg.huge_pages),
+            shared_memory: Some(cfg.shared_memory),
         }
     }
 }
 
+fn is_zero(val: &u8) -> bool {
+    *val == 0
+}
+
+/// Deserialization function for the `shared_memory` field in `VmUpdateConfig`.
+/// This is called only when `shared_memory` is present in the JSON configuration, so that
+/// `null` can be told apart from a missing field.
+fn deserialize_shared_memory<'de, D>(
+    d: D,
+) -> std::result::Result<Option<Option<SharedMemoryConfig>>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    Option::<SharedMemoryConfig>::deserialize(d).map(Some)
+}
+
 /// Deserialization function for the `vcpu_num` field in `VmConfig` and `VmUpdateConfig`.
 /// This is called only when `vcpu_num` is present in the JSON configuration.
 /// `T` can be either `u8` or `Option<u8>` which both support ordering if `vcpu_num` is
@@ -236,6 +309,21 @@ where
     T::deserialize(_d)
 }
 
+/// Describes how the guest memory is shared with other processes. The guest memory is then
+/// backed by a file mapped `MAP_SHARED`, instead of private anonymous memory.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SharedMemoryConfig {
+    /// Path of the file backing the guest memory, which is created, or truncated, when the
+    /// microVM starts. The guest memory is backed by an anonymous memfd if missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem_file_path: Option<PathBuf>,
+    /// Path of the Unix socket the file descriptor of the guest memory, along with its
+    /// layout, is sent to when the microVM starts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uds_path: Option<PathBuf>,
+}
+
 /// Template types available for configuring the CPU features that map
 /// to EC2 instances.
 #[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, Versionize)]
@@ -274,6 +362,40 @@ impl Default for CpuFeaturesTemplate {
     }
 }
 
+/// The huge pages backing the guest memory, which are taken from the hugetlbfs pool of the host.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, Versionize)]
+pub enum HugePageConfig {
+    /// The guest memory is backed by regular pages.
+    None,
+    /// The guest memory is backed by 2 MiB pages.
+    #[serde(rename = "2M")]
+    Hugetlbfs2M,
+    /// The guest memory is backed by 1 GiB pages.
+    #[serde(rename = "1G")]
+    Hugetlbfs1G,
+}
+
+impl HugePageConfig {
+    fn is_none(&self) -> bool {
+        *self == HugePageConfig::None
+    }
+
+    /// Returns the size of the huge pages, if any.
+    pub fn page_size(&self) -> Option<HugePageSize> {
+        match self {
+            HugePageConfig::None => None,
+            HugePageConfig::Hugetlbfs2M => Some(HugePageSize::Size2M),
+            HugePageConfig::Hugetlbfs1G => Some(HugePageSize::Size1G),
+        }
+    }
+}
+
+impl Default for HugePageConfig {
+    fn default() -> Self {
+        HugePageConfig::None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -293,5 +415,79 @@ mod tests {
 
         let expected_str = "The memory size (MiB) is invalid.";
         assert_eq!(VmConfigError::InvalidMemorySize.to_string(), expected_str);
+
+        let expected_str = "The number of block device hot-plug slots is invalid! It cannot \
+                            exceed 8.";
+        assert_eq!(
+            VmConfigError::InvalidBlockHotplugSlots.to_string(),
+            expected_str
+        );
+
+        let expected_str = "The memory size (MiB) is invalid! The guest memory regions must be \
+                            made of whole huge pages.";
+        assert_eq!(
+            VmConfigError::InvalidHugePageMemorySize.to_string(),
+            expected_str
+        );
+    }
+
+    #[test]
+    fn test_huge_page_config() {
+        assert_eq!(HugePageConfig::default().page_size(), None);
+        assert_eq!(
+            HugePageConfig::Hugetlbfs2M.page_size(),
+            Some(HugePageSize::Size2M)
+        );
+        assert_eq!(
+            HugePageConfig::Hugetlbfs1G.page_size(),
+            Some(HugePageSize::Size1G)
+        );
+
+        let vm_config: VmConfig =
+            serde_json::from_str(r#"{"vcpu_count": 1, "mem_size_mib": 128, "huge_pages": "2M"}"#)
+                .unwrap();
+        assert_eq!(vm_config.huge_pages, HugePageConfig::Hugetlbfs2M);
+        assert!(serde_json::from_str::<VmConfig>(
+            r#"{"vcpu_count": 1, "mem_size_mib": 128, "huge_pages": "4M"}"#
+        )
+        .is_err());
+        // The default is left out of the serialized configuration.
+        assert!(!serde_json::to_string(&VmConfig::default())
+            .unwrap()
+            .contains("huge_pages"));
+    }
+
+    #[test]
+    fn test_shared_memory_config() {
+        let vm_config: VmConfig = serde_json::from_str(
+            r#"{"vcpu_count": 1, "mem_size_mib": 128, "shared_memory": {"uds_path": "mem.sock"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vm_config.shared_memory,
+            Some(SharedMemoryConfig {
+                mem_file_path: None,
+                uds_path: Some(PathBuf::from("mem.sock")),
+            })
+        );
+        assert!(!serde_json::to_string(&VmConfig::default())
+            .unwrap()
+            .contains("shared_memory"));
+
+        // A missing field leaves the setting in place, while `null` clears it.
+        let update: VmUpdateConfig = serde_json::from_str(r#"{"vcpu_count": 2}"#).unwrap();
+        assert_eq!(update.shared_memory, None);
+        let update: VmUpdateConfig = serde_json::from_str(r#"{"shared_memory": null}"#).unwrap();
+        assert_eq!(update.shared_memory, Some(None));
+        assert!(!update.is_empty());
+        let update: VmUpdateConfig =
+            serde_json::from_str(r#"{"shared_memory": {"mem_file_path": "mem"}}"#).unwrap();
+        assert_eq!(
+            update.shared_memory,
+            Some(Some(SharedMemoryConfig {
+                mem_file_path: Some(PathBuf::from("mem")),
+                uds_path: None,
+            }))
+        );
     }
 }
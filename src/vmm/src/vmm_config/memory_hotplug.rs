@@ -0,0 +1,204 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+
+pub use devices::virtio::mem::MEM_DEV_ID;
+use devices::virtio::VirtioMemConfig;
+use serde::{Deserialize, Serialize};
+use vm_memory::HugePageSize;
+
+/// Default size of the blocks of the hot-pluggable memory, in MiB.
+pub const DEFAULT_BLOCK_SIZE_MIB: usize = 2;
+
+/// Errors associated with the operations allowed on the hot-pluggable memory.
+#[derive(Debug)]
+pub enum MemoryHotplugConfigError {
+    /// The user made a request on an inexistent virtio-mem device.
+    DeviceNotFound,
+    /// The block size is not a power of two.
+    InvalidBlockSize,
+    /// The total size is zero or not made of whole blocks.
+    InvalidTotalSize,
+    /// The blocks are not made of whole huge pages.
+    IncompatibleHugePages,
+    /// Failed to update the requested size of the virtio-mem device.
+    UpdateFailure(devices::virtio::mem::Error),
+}
+
+impl fmt::Display for MemoryHotplugConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> std::fmt::Result {
+        use self::MemoryHotplugConfigError::*;
+        match self {
+            DeviceNotFound => write!(f, "No memory hot-plug device found."),
+            InvalidBlockSize => write!(f, "The block size must be a power of two."),
+            InvalidTotalSize => write!(
+                f,
+                "The total size must be a non-zero multiple of the block size."
+            ),
+            IncompatibleHugePages => write!(
+                f,
+                "The block size must be a multiple of the huge page size."
+            ),
+            UpdateFailure(err) => write!(
+                f,
+                "Error updating the size of the hot-plugged memory: {:?}",
+                err
+            ),
+        }
+    }
+}
+
+impl From<devices::virtio::mem::Error> for MemoryHotplugConfigError {
+    fn from(err: devices::virtio::mem::Error) -> Self {
+        match err {
+            devices::virtio::mem::Error::DeviceNotFound => Self::DeviceNotFound,
+            err => Self::UpdateFailure(err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, MemoryHotplugConfigError>;
+
+fn default_block_size_mib() -> usize {
+    DEFAULT_BLOCK_SIZE_MIB
+}
+
+/// This struct represents the strongly typed equivalent of the json body
+/// from memory hot-plug configuration requests.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryHotplugConfig {
+    /// Size of the region reserved for hot-plugging memory, in MiB.
+    pub total_size_mib: usize,
+    /// Granularity of plugging and unplugging memory, in MiB.
+    #[serde(default = "default_block_size_mib")]
+    pub block_size_mib: usize,
+}
+
+impl MemoryHotplugConfig {
+    /// Checks that the region is made of whole blocks, and that the blocks are made of
+    /// whole huge pages, if the guest memory is backed by huge pages.
+    pub fn validate(&self, huge_page_size: Option<HugePageSize>) -> Result<()> {
+        if !self.block_size_mib.is_power_of_two() {
+            return Err(MemoryHotplugConfigError::InvalidBlockSize);
+        }
+        if self.total_size_mib == 0 || self.total_size_mib % self.block_size_mib != 0 {
+            return Err(MemoryHotplugConfigError::InvalidTotalSize);
+        }
+        if let Some(page_size) = huge_page_size {
+            if (self.block_size_mib << 20) % page_size.bytes() != 0 {
+                return Err(MemoryHotplugConfigError::IncompatibleHugePages);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<VirtioMemConfig> for MemoryHotplugConfig {
+    fn from(config: VirtioMemConfig) -> Self {
+        MemoryHotplugConfig {
+            total_size_mib: (config.region_size >> 20) as usize,
+            block_size_mib: (config.block_size >> 20) as usize,
+        }
+    }
+}
+
+/// The data fed into a memory hot-plug update request.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryHotplugSizeUpdate {
+    /// Amount of memory the guest is asked to plug, in MiB.
+    pub requested_size_mib: usize,
+}
+
+/// The status of the hot-pluggable memory, returned by the API.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryHotplugStatus {
+    /// Size of the region reserved for hot-plugging memory, in MiB.
+    pub total_size_mib: usize,
+    /// Granularity of plugging and unplugging memory, in MiB.
+    pub block_size_mib: usize,
+    /// Amount of memory plugged by the guest, in MiB.
+    pub plugged_size_mib: usize,
+    /// Amount of memory the guest is asked to plug, in MiB.
+    pub requested_size_mib: usize,
+}
+
+impl From<&MemoryHotplugConfig> for MemoryHotplugStatus {
+    fn from(config: &MemoryHotplugConfig) -> Self {
+        MemoryHotplugStatus {
+            total_size_mib: config.total_size_mib,
+            block_size_mib: config.block_size_mib,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<VirtioMemConfig> for MemoryHotplugStatus {
+    fn from(config: VirtioMemConfig) -> Self {
+        MemoryHotplugStatus {
+            total_size_mib: (config.region_size >> 20) as usize,
+            block_size_mib: (config.block_size >> 20) as usize,
+            plugged_size_mib: (config.plugged_size >> 20) as usize,
+            requested_size_mib: (config.requested_size >> 20) as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut config: MemoryHotplugConfig =
+            serde_json::from_str(r#"{"total_size_mib": 1024}"#).unwrap();
+        assert_eq!(config.block_size_mib, DEFAULT_BLOCK_SIZE_MIB);
+        config.validate(None).unwrap();
+        config.validate(Some(HugePageSize::Size2M)).unwrap();
+        assert!(matches!(
+            config.validate(Some(HugePageSize::Size1G)),
+            Err(MemoryHotplugConfigError::IncompatibleHugePages)
+        ));
+
+        config.block_size_mib = 3;
+        assert!(matches!(
+            config.validate(None),
+            Err(MemoryHotplugConfigError::InvalidBlockSize)
+        ));
+
+        config.block_size_mib = 4;
+        config.total_size_mib = 6;
+        assert!(matches!(
+            config.validate(None),
+            Err(MemoryHotplugConfigError::InvalidTotalSize)
+        ));
+        config.total_size_mib = 0;
+        assert!(matches!(
+            config.validate(None),
+            Err(MemoryHotplugConfigError::InvalidTotalSize)
+        ));
+    }
+
+    #[test]
+    fn test_status_from_device_config() {
+        let status = MemoryHotplugStatus::from(VirtioMemConfig {
+            addr: 1 << 32,
+            region_size: 1 << 30,
+            block_size: 2 << 20,
+            plugged_size: 128 << 20,
+            requested_size: 256 << 20,
+        });
+        assert_eq!(
+            status,
+            MemoryHotplugStatus {
+                total_size_mib: 1024,
+                block_size_mib: 2,
+                plugged_size_mib: 128,
+                requested_size_mib: 256,
+            }
+        );
+    }
+}
@@ -6,7 +6,8 @@ use std::ops::Deref;
 use std::sync::{Arc, Mutex};
 use std::{fmt, result};
 
-use devices::virtio::net::TapError;
+pub use devices::virtio::net::{AntiSpoofing, Ipv4Prefix, Ipv6Prefix, LinkState, Offloads};
+use devices::virtio::net::{TapError, DEFAULT_NUM_QUEUE_PAIRS};
 use devices::virtio::Net;
 use serde::{Deserialize, Serialize};
 use utils::net::mac::MacAddr;
@@ -29,6 +30,23 @@ pub struct NetworkInterfaceConfig {
     pub rx_rate_limiter: Option<RateLimiterConfig>,
     /// Rate Limiter for transmitted packages.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// The number of RX/TX queue pairs exposed to the guest.
+    #[serde(default = "default_num_queue_pairs")]
+    pub num_queue_pairs: u16,
+    /// The offloads negotiated with the guest.
+    #[serde(default)]
+    pub offloads: Offloads,
+    /// Process the RX/TX queues in the kernel, through vhost-net. Firecracker falls back to
+    /// processing them itself if the MMDS, rate limiting or anti-spoofing are configured.
+    #[serde(default)]
+    pub vhost_net: bool,
+    /// The source addresses and ethertypes the guest is allowed to send frames with.
+    #[serde(default)]
+    pub anti_spoofing: Option<AntiSpoofing>,
+}
+
+fn default_num_queue_pairs() -> u16 {
+    DEFAULT_NUM_QUEUE_PAIRS
 }
 
 impl From<&Net> for NetworkInterfaceConfig {
@@ -41,12 +59,16 @@ impl From<&Net> for NetworkInterfaceConfig {
             guest_mac: Some(*net.guest_mac()),
             rx_rate_limiter: rx_rl.into_option(),
             tx_rate_limiter: tx_rl.into_option(),
+            num_queue_pairs: net.num_queue_pairs(),
+            offloads: net.offloads(),
+            vhost_net: net.vhost_net(),
+            anti_spoofing: net.anti_spoofing().cloned(),
         }
     }
 }
 
 /// The data fed into a network iface update request. Currently, only the RX and TX rate limiters
-/// can be updated.
+/// and the link state can be updated.
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct NetworkInterfaceUpdateConfig {
@@ -58,6 +80,44 @@ pub struct NetworkInterfaceUpdateConfig {
     /// New TX rate limiter config. Only provided data will be updated. I.e. if any optional data
     /// is missing, it will not be nullified, but left unchanged.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// New state of the link reported to the guest.
+    pub link_state: Option<LinkState>,
+}
+
+/// Starts or stops a packet capture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum PacketCaptureAction {
+    /// Start writing the frames of the interface to a pcap file.
+    Start,
+    /// Stop the capture in progress, if any.
+    Stop,
+}
+
+/// The default size limit of a pcap file, in MiB.
+pub const DEFAULT_CAPTURE_MAX_SIZE_MIB: u64 = 64;
+
+/// The data fed into a network iface packet capture request.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkCaptureConfig {
+    /// The net iface ID, as provided by the user at iface creation time.
+    pub iface_id: String,
+    /// Whether to start or stop the capture.
+    pub action: PacketCaptureAction,
+    /// Host level path of the pcap file, which is created or truncated. Required when starting
+    /// a capture.
+    pub path_on_host: Option<String>,
+    /// The size limit of the pcap file, in MiB. The capture stops once the file is full.
+    pub max_size_mib: Option<u64>,
+}
+
+impl NetworkCaptureConfig {
+    /// Returns the size limit of the pcap file, in bytes.
+    pub fn max_size(&self) -> u64 {
+        self.max_size_mib
+            .unwrap_or(DEFAULT_CAPTURE_MAX_SIZE_MIB)
+            .saturating_mul(1 << 20)
+    }
 }
 
 /// Errors associated with `NetworkInterfaceConfig`.
@@ -73,6 +133,8 @@ pub enum NetworkInterfaceError {
     DeviceUpdate(VmmError),
     /// Cannot open/create tap device.
     OpenTap(TapError),
+    /// The path of the pcap file is required to start a packet capture.
+    CapturePathMissing,
 }
 
 impl fmt::Display for NetworkInterfaceError {
@@ -96,6 +158,10 @@ impl fmt::Display for NetworkInterfaceError {
                     "Cannot open TAP device. Invalid name/permissions. {tap_err}",
                 )
             }
+            CapturePathMissing => write!(
+                f,
+                "The path of the pcap file is required to start a packet capture."
+            ),
         }
     }
 }
@@ -179,14 +245,24 @@ impl NetBuilder {
             .transpose()?;
 
         // Create and return the Net device
-        devices::virtio::net::Net::new_with_tap(
+        let mut net = devices::virtio::net::Net::new_with_tap(
             cfg.iface_id,
             cfg.host_dev_name.clone(),
             cfg.guest_mac.as_ref(),
             rx_rate_limiter.unwrap_or_default(),
             tx_rate_limiter.unwrap_or_default(),
+            cfg.num_queue_pairs,
+            cfg.offloads,
         )
-        .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        net.set_anti_spoofing(cfg.anti_spoofing)
+            .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        if cfg.vhost_net {
+            net.enable_vhost_net()
+                .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        }
+
+        Ok(net)
     }
 
     /// Returns a vec with the structures used to configure the net devices.
@@ -224,6 +300,10 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str(mac).unwrap()),
             rx_rate_limiter: RateLimiterConfig::default().into_option(),
             tx_rate_limiter: RateLimiterConfig::default().into_option(),
+            num_queue_pairs: DEFAULT_NUM_QUEUE_PAIRS,
+            offloads: Offloads::default(),
+            vhost_net: false,
+            anti_spoofing: None,
         }
     }
 
@@ -235,6 +315,10 @@ mod tests {
                 guest_mac: self.guest_mac,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                num_queue_pairs: self.num_queue_pairs,
+                offloads: self.offloads,
+                vhost_net: self.vhost_net,
+                anti_spoofing: self.anti_spoofing.clone(),
             }
         }
     }
@@ -369,6 +453,54 @@ mod tests {
         assert_eq!(configs.first().unwrap(), &net_if_cfg);
     }
 
+    #[test]
+    fn test_num_queue_pairs() {
+        let mut net_builder = NetBuilder::new();
+
+        let mut net_if_cfg = create_netif("id", "dev5", "01:23:45:67:89:0c");
+        net_if_cfg.num_queue_pairs = 0;
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::InvalidNumQueuePairs(0)
+            )
+            .to_string()
+        );
+
+        net_if_cfg.num_queue_pairs = 4;
+        assert!(net_builder.build(net_if_cfg.clone()).is_ok());
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+    }
+
+    #[test]
+    fn test_offloads() {
+        let mut net_builder = NetBuilder::new();
+
+        // Segmentation offloads can't be enabled without checksum offload.
+        let mut net_if_cfg = create_netif("id", "dev6", "01:23:45:67:89:0d");
+        net_if_cfg.offloads.checksum = false;
+        assert_eq!(
+            net_builder
+                .build(net_if_cfg.clone())
+                .err()
+                .unwrap()
+                .to_string(),
+            NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::InvalidOffloads(net_if_cfg.offloads)
+            )
+            .to_string()
+        );
+
+        net_if_cfg.offloads.checksum = true;
+        net_if_cfg.offloads.tso6 = false;
+        assert!(net_builder.build(net_if_cfg.clone()).is_ok());
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+    }
+
     #[test]
     fn test_add_device() {
         let mut net_builder = NetBuilder::new();
@@ -382,6 +514,8 @@ mod tests {
             Some(&MacAddr::parse_str(guest_mac).unwrap()),
             RateLimiter::default(),
             RateLimiter::default(),
+            DEFAULT_NUM_QUEUE_PAIRS,
+            Offloads::default(),
         )
         .unwrap();
 
@@ -413,6 +547,8 @@ mod tests {
             None,
             RateLimiter::default(),
             RateLimiter::default(),
+            DEFAULT_NUM_QUEUE_PAIRS,
+            Offloads::default(),
         )
         .unwrap();
 
@@ -7,6 +7,8 @@ use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
+use crate::vmm_config::machine_config::SharedMemoryConfig;
+
 /// The snapshot type options that are available when
 /// creating a new snapshot.
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -51,6 +53,11 @@ pub struct CreateSnapshotParams {
     /// Optional field for the microVM version. The default
     /// value is the current version.
     pub version: Option<String>,
+    /// Option to ask the guest for its free memory through the balloon
+    /// device before creating a full snapshot, which resumes the vCPUs
+    /// while the guest reports it.
+    #[serde(default)]
+    pub free_page_hinting: bool,
 }
 
 /// Stores the configuration that will be used for loading a snapshot.
@@ -66,6 +73,11 @@ pub struct LoadSnapshotParams {
     /// When set to true, the vm is also resumed if the snapshot load
     /// is successful.
     pub resume_vm: bool,
+    /// Overrides the host-side configuration of the vsock device, if any.
+    pub vsock_override: Option<VsockOverride>,
+    /// Shares the restored guest memory with other processes, as configured. Requires the
+    /// `File` memory backend.
+    pub shared_memory: Option<SharedMemoryConfig>,
 }
 
 /// Stores the configuration for loading a snapshot that is provided by the user.
@@ -88,6 +100,23 @@ pub struct LoadSnapshotConfig {
     /// Whether or not to resume the vm post snapshot load.
     #[serde(default)]
     pub resume_vm: bool,
+    /// Overrides the host-side configuration of the vsock device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vsock_override: Option<VsockOverride>,
+    /// Shares the restored guest memory with other processes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared_memory: Option<SharedMemoryConfig>,
+}
+
+/// Stores the host-side configuration of the vsock device to be used instead of the one in the
+/// snapshot, e.g. so that each clone of a microVM gets its own Unix socket.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockOverride {
+    /// Path to the local unix socket the restored device binds to. The guest connections that
+    /// are carried over are re-established through the sockets listening on
+    /// "<uds_path>_<port number>".
+    pub uds_path: String,
 }
 
 /// Stores the configuration used for managing snapshot memory.
@@ -1,22 +1,42 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::convert::TryFrom;
+use std::convert::{TryFrom, TryInto};
 use std::fmt;
 use std::sync::{Arc, Mutex};
 
-use devices::virtio::{Vsock, VsockError, VsockUnixBackend, VsockUnixBackendError};
+use devices::virtio::{
+    Vsock, VsockBackend, VsockError, VsockTcpBackend, VsockTcpBackendError, VsockUnixBackend,
+    VsockUnixBackendError,
+};
+pub use devices::virtio::{VsockConnectionInfo, VsockTcpDirection, VsockTcpPort};
 use serde::{Deserialize, Serialize};
 
+use super::RateLimiterConfig;
+
 type MutexVsockUnix = Arc<Mutex<Vsock<VsockUnixBackend>>>;
+type MutexVsockTcp = Arc<Mutex<Vsock<VsockTcpBackend>>>;
 
 /// Errors associated with `NetworkInterfaceConfig`.
 #[derive(Debug, derive_more::From)]
 pub enum VsockConfigError {
     /// Failed to create the backend for the vsock device.
     CreateVsockBackend(VsockUnixBackendError),
+    /// Failed to create the TCP backend for the vsock device.
+    CreateVsockTcpBackend(VsockTcpBackendError),
     /// Failed to create the vsock device.
     CreateVsockDevice(VsockError),
+    /// Failed to create the rate limiter of the vsock device.
+    CreateRateLimiter(std::io::Error),
+    /// The vsock device was not found.
+    DeviceNotFound,
+    /// Neither or both of the Unix socket path and the TCP ports were given.
+    InvalidBackend,
+    /// Connections can only be persisted by the Unix socket backend.
+    PersistConnectionsUnsupported,
+    /// There is no vsock connection between the given host and guest ports.
+    #[from(ignore)]
+    UnknownConnection(u32, u32),
 }
 
 impl fmt::Display for VsockConfigError {
@@ -26,7 +46,25 @@ impl fmt::Display for VsockConfigError {
             CreateVsockBackend(ref err) => {
                 write!(f, "Cannot create backend for vsock device: {:?}", err)
             }
+            CreateVsockTcpBackend(ref err) => {
+                write!(f, "Cannot create TCP backend for vsock device: {:?}", err)
+            }
             CreateVsockDevice(ref err) => write!(f, "Cannot create vsock device: {:?}", err),
+            CreateRateLimiter(ref err) => write!(f, "Cannot create RateLimiter: {}", err),
+            DeviceNotFound => write!(f, "No vsock device found."),
+            InvalidBackend => write!(
+                f,
+                "Exactly one of the Unix socket path and the TCP ports must be set."
+            ),
+            PersistConnectionsUnsupported => write!(
+                f,
+                "Only the Unix socket backend can persist connections across snapshots."
+            ),
+            UnknownConnection(local_port, peer_port) => write!(
+                f,
+                "No vsock connection between host port {} and guest port {}.",
+                local_port, peer_port
+            ),
         }
     }
 }
@@ -45,74 +83,157 @@ pub struct VsockDeviceConfig {
     /// A 32-bit Context Identifier (CID) used to identify the guest.
     pub guest_cid: u32,
     /// Path to local unix socket.
-    pub uds_path: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uds_path: Option<String>,
+    /// Port-forwarding table of the TCP backend, used instead of the unix socket.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tcp_ports: Vec<VsockTcpPort>,
+    /// Rate limiter for the traffic of the vsock device, in both directions.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limiter: Option<RateLimiterConfig>,
+    /// Keep the guest connections alive across snapshot/restore, instead of having the guest
+    /// reset them. Only supported by the unix socket backend.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub persist_connections: bool,
 }
 
-struct VsockAndUnixPath {
-    vsock: MutexVsockUnix,
-    uds_path: String,
+fn is_false(val: &bool) -> bool {
+    !*val
 }
 
-impl From<&VsockAndUnixPath> for VsockDeviceConfig {
-    fn from(vsock: &VsockAndUnixPath) -> Self {
-        let vsock_lock = vsock.vsock.lock().unwrap();
-        VsockDeviceConfig {
-            vsock_id: None,
-            guest_cid: u32::try_from(vsock_lock.cid()).unwrap(),
-            uds_path: vsock.uds_path.clone(),
+/// The data fed into a vsock device update request. Currently, only the rate limiter can be
+/// updated.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockDeviceUpdateConfig {
+    /// New rate limiter config. Only provided data will be updated. I.e. if any optional data
+    /// is missing, it will not be nullified, but left unchanged.
+    pub rate_limiter: Option<RateLimiterConfig>,
+}
+
+/// Identifies a vsock connection by its host and guest ports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockConnectionKey {
+    /// The local (host) port.
+    pub local_port: u32,
+    /// The peer (guest) port.
+    pub peer_port: u32,
+}
+
+/// A vsock device, along with the host-side backend it was created with.
+#[derive(Clone)]
+pub enum VsockDevice {
+    /// Vsock device backed by Unix domain sockets.
+    Unix(MutexVsockUnix),
+    /// Vsock device backed by TCP sockets.
+    Tcp(MutexVsockTcp),
+}
+
+impl From<&VsockDevice> for VsockDeviceConfig {
+    fn from(vsock: &VsockDevice) -> Self {
+        match vsock {
+            VsockDevice::Unix(vsock) => {
+                let vsock_lock = vsock.lock().expect("Poisoned lock");
+                let rate_limiter: RateLimiterConfig = vsock_lock.rate_limiter().into();
+                VsockDeviceConfig {
+                    vsock_id: None,
+                    guest_cid: u32::try_from(vsock_lock.cid()).unwrap(),
+                    uds_path: Some(vsock_lock.backend().host_sock_path().to_owned()),
+                    tcp_ports: Vec::new(),
+                    rate_limiter: rate_limiter.into_option(),
+                    persist_connections: vsock_lock.backend().persists_connections(),
+                }
+            }
+            VsockDevice::Tcp(vsock) => {
+                let vsock_lock = vsock.lock().expect("Poisoned lock");
+                let rate_limiter: RateLimiterConfig = vsock_lock.rate_limiter().into();
+                VsockDeviceConfig {
+                    vsock_id: None,
+                    guest_cid: u32::try_from(vsock_lock.cid()).unwrap(),
+                    uds_path: None,
+                    tcp_ports: vsock_lock.backend().ports().to_vec(),
+                    rate_limiter: rate_limiter.into_option(),
+                    persist_connections: false,
+                }
+            }
         }
     }
 }
 
-/// A builder of Vsock with Unix backend from 'VsockDeviceConfig'.
+/// A builder of Vsock with Unix or TCP backend from 'VsockDeviceConfig'.
 #[derive(Default)]
 pub struct VsockBuilder {
-    inner: Option<VsockAndUnixPath>,
+    inner: Option<VsockDevice>,
 }
 
 impl VsockBuilder {
-    /// Creates an empty Vsock with Unix backend Store.
+    /// Creates an empty Vsock Store.
     pub fn new() -> Self {
         Self { inner: None }
     }
 
     /// Inserts an existing vsock device.
-    pub fn set_device(&mut self, device: Arc<Mutex<Vsock<VsockUnixBackend>>>) {
-        self.inner = Some(VsockAndUnixPath {
-            uds_path: device
-                .lock()
-                .expect("Poisoned lock")
-                .backend()
-                .host_sock_path()
-                .to_owned(),
-            vsock: device.clone(),
-        });
+    pub fn set_device(&mut self, device: VsockDevice) {
+        self.inner = Some(device);
     }
 
-    /// Inserts a Unix backend Vsock in the store.
+    /// Inserts a Vsock in the store.
     /// If an entry already exists, it will overwrite it.
     pub fn insert(&mut self, cfg: VsockDeviceConfig) -> Result<()> {
         // Make sure to drop the old one and remove the socket before creating a new one.
-        if let Some(existing) = self.inner.take() {
-            std::fs::remove_file(existing.uds_path).map_err(VsockUnixBackendError::UnixBind)?;
+        if let Some(VsockDevice::Unix(existing)) = self.inner.take() {
+            let uds_path = existing
+                .lock()
+                .expect("Poisoned lock")
+                .backend()
+                .host_sock_path()
+                .to_owned();
+            std::fs::remove_file(uds_path).map_err(VsockUnixBackendError::UnixBind)?;
         }
-        self.inner = Some(VsockAndUnixPath {
-            uds_path: cfg.uds_path.clone(),
-            vsock: Arc::new(Mutex::new(Self::create_unixsock_vsock(cfg)?)),
-        });
+        self.inner = Some(Self::create_vsock(cfg)?);
         Ok(())
     }
 
     /// Provides a reference to the Vsock if present.
-    pub fn get(&self) -> Option<&MutexVsockUnix> {
-        self.inner.as_ref().map(|pair| &pair.vsock)
+    pub fn get(&self) -> Option<&VsockDevice> {
+        self.inner.as_ref()
     }
 
-    /// Creates a Vsock device from a VsockDeviceConfig.
-    pub fn create_unixsock_vsock(cfg: VsockDeviceConfig) -> Result<Vsock<VsockUnixBackend>> {
-        let backend = VsockUnixBackend::new(u64::from(cfg.guest_cid), cfg.uds_path)?;
-
-        Vsock::new(u64::from(cfg.guest_cid), backend).map_err(VsockConfigError::CreateVsockDevice)
+    /// Creates a Vsock device from a VsockDeviceConfig, with the backend it asks for.
+    pub fn create_vsock(cfg: VsockDeviceConfig) -> Result<VsockDevice> {
+        let cid = u64::from(cfg.guest_cid);
+        let rate_limiter = cfg
+            .rate_limiter
+            .map(RateLimiterConfig::try_into)
+            .transpose()?
+            .unwrap_or_default();
+        match (cfg.uds_path, cfg.tcp_ports.is_empty()) {
+            (Some(uds_path), true) => {
+                let mut backend = VsockUnixBackend::new(cid, uds_path)?;
+                backend.set_persist_connections(cfg.persist_connections);
+                Ok(VsockDevice::Unix(Arc::new(Mutex::new(Vsock::new(
+                    cid,
+                    backend,
+                    rate_limiter,
+                )?))))
+            }
+            (None, false) => {
+                if cfg.persist_connections {
+                    return Err(VsockConfigError::PersistConnectionsUnsupported);
+                }
+                let backend = VsockTcpBackend::new(cid, cfg.tcp_ports)?;
+                Ok(VsockDevice::Tcp(Arc::new(Mutex::new(Vsock::new(
+                    cid,
+                    backend,
+                    rate_limiter,
+                )?))))
+            }
+            _ => Err(VsockConfigError::InvalidBackend),
+        }
     }
 
     /// Returns the structure used to configure the vsock device.
@@ -124,15 +245,42 @@ impl VsockBuilder {
 #[cfg(test)]
 pub(crate) mod tests {
     use devices::virtio::vsock::VSOCK_DEV_ID;
+    use rate_limiter::RateLimiter;
     use utils::tempfile::TempFile;
 
     use super::*;
+    use crate::vmm_config::TokenBucketConfig;
 
     pub(crate) fn default_config(tmp_sock_file: &TempFile) -> VsockDeviceConfig {
         VsockDeviceConfig {
             vsock_id: None,
             guest_cid: 3,
-            uds_path: tmp_sock_file.as_path().to_str().unwrap().to_string(),
+            uds_path: Some(tmp_sock_file.as_path().to_str().unwrap().to_string()),
+            tcp_ports: Vec::new(),
+            rate_limiter: None,
+            persist_connections: false,
+        }
+    }
+
+    pub(crate) fn tcp_config() -> VsockDeviceConfig {
+        VsockDeviceConfig {
+            vsock_id: None,
+            guest_cid: 3,
+            uds_path: None,
+            tcp_ports: vec![VsockTcpPort {
+                vsock_port: 1024,
+                host_addr: "127.0.0.1:8080".parse().unwrap(),
+                direction: VsockTcpDirection::GuestToHost,
+            }],
+            rate_limiter: None,
+            persist_connections: false,
+        }
+    }
+
+    pub(crate) fn unix_vsock(device: &VsockDevice) -> &MutexVsockUnix {
+        match device {
+            VsockDevice::Unix(vsock) => vsock,
+            VsockDevice::Tcp(_) => panic!("Expected a vsock device with Unix backend."),
         }
     }
 
@@ -141,7 +289,36 @@ pub(crate) mod tests {
         let mut tmp_sock_file = TempFile::new().unwrap();
         tmp_sock_file.remove().unwrap();
         let vsock_config = default_config(&tmp_sock_file);
-        VsockBuilder::create_unixsock_vsock(vsock_config).unwrap();
+        assert!(matches!(
+            VsockBuilder::create_vsock(vsock_config.clone()),
+            Ok(VsockDevice::Unix(_))
+        ));
+        assert!(matches!(
+            VsockBuilder::create_vsock(tcp_config()),
+            Ok(VsockDevice::Tcp(_))
+        ));
+
+        // Exactly one backend must be configured.
+        let mut invalid_config = tcp_config();
+        invalid_config.uds_path = vsock_config.uds_path;
+        assert!(matches!(
+            VsockBuilder::create_vsock(invalid_config.clone()),
+            Err(VsockConfigError::InvalidBackend)
+        ));
+        invalid_config.uds_path = None;
+        invalid_config.tcp_ports.clear();
+        assert!(matches!(
+            VsockBuilder::create_vsock(invalid_config),
+            Err(VsockConfigError::InvalidBackend)
+        ));
+
+        // Only the Unix backend can persist connections.
+        let mut invalid_config = tcp_config();
+        invalid_config.persist_connections = true;
+        assert!(matches!(
+            VsockBuilder::create_vsock(invalid_config),
+            Err(VsockConfigError::PersistConnectionsUnsupported)
+        ));
     }
 
     #[test]
@@ -152,14 +329,19 @@ pub(crate) mod tests {
         let mut vsock_config = default_config(&tmp_sock_file);
 
         store.insert(vsock_config.clone()).unwrap();
-        let vsock = store.get().unwrap();
+        let vsock = unix_vsock(store.get().unwrap());
         assert_eq!(vsock.lock().unwrap().id(), VSOCK_DEV_ID);
 
         let new_cid = vsock_config.guest_cid + 1;
         vsock_config.guest_cid = new_cid;
         store.insert(vsock_config).unwrap();
-        let vsock = store.get().unwrap();
+        let vsock = unix_vsock(store.get().unwrap());
         assert_eq!(vsock.lock().unwrap().cid(), new_cid as u64);
+
+        // The Unix socket is removed when the device is replaced by one with TCP backend.
+        store.insert(tcp_config()).unwrap();
+        assert!(matches!(store.get(), Some(VsockDevice::Tcp(_))));
+        assert!(!tmp_sock_file.as_path().exists());
     }
 
     #[test]
@@ -173,6 +355,29 @@ pub(crate) mod tests {
         let config = vsock_builder.config();
         assert!(config.is_some());
         assert_eq!(config.unwrap(), vsock_config);
+
+        let vsock_config = tcp_config();
+        vsock_builder.insert(vsock_config.clone()).unwrap();
+        assert_eq!(vsock_builder.config().unwrap(), vsock_config);
+
+        let mut vsock_config = tcp_config();
+        vsock_config.rate_limiter = Some(RateLimiterConfig {
+            bandwidth: Some(TokenBucketConfig {
+                size: 1000,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+            ops: None,
+        });
+        vsock_builder.insert(vsock_config.clone()).unwrap();
+        assert_eq!(vsock_builder.config().unwrap(), vsock_config);
+
+        let mut tmp_sock_file = TempFile::new().unwrap();
+        tmp_sock_file.remove().unwrap();
+        let mut vsock_config = default_config(&tmp_sock_file);
+        vsock_config.persist_connections = true;
+        vsock_builder.insert(vsock_config.clone()).unwrap();
+        assert_eq!(vsock_builder.config().unwrap(), vsock_config);
     }
 
     #[test]
@@ -185,10 +390,24 @@ pub(crate) mod tests {
         ));
         let _ = format!("{}{:?}", err, err);
 
+        let err = CreateVsockTcpBackend(devices::virtio::VsockTcpBackendError::TcpBind(
+            io::Error::from_raw_os_error(0),
+        ));
+        let _ = format!("{}{:?}", err, err);
+
         let err = CreateVsockDevice(devices::virtio::VsockError::EventFd(
             io::Error::from_raw_os_error(0),
         ));
         let _ = format!("{}{:?}", err, err);
+
+        let err = CreateRateLimiter(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = InvalidBackend;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = PersistConnectionsUnsupported;
+        let _ = format!("{}{:?}", err, err);
     }
 
     #[test]
@@ -200,14 +419,15 @@ pub(crate) mod tests {
             0,
             VsockUnixBackend::new(1, tmp_sock_file.as_path().to_str().unwrap().to_string())
                 .unwrap(),
+            RateLimiter::default(),
         )
         .unwrap();
 
-        vsock_builder.set_device(Arc::new(Mutex::new(vsock)));
+        vsock_builder.set_device(VsockDevice::Unix(Arc::new(Mutex::new(vsock))));
         assert!(vsock_builder.inner.is_some());
         assert_eq!(
-            vsock_builder.inner.unwrap().uds_path,
-            tmp_sock_file.as_path().to_str().unwrap().to_string()
+            vsock_builder.config().unwrap().uds_path,
+            Some(tmp_sock_file.as_path().to_str().unwrap().to_string())
         )
     }
 }
@@ -6,12 +6,14 @@
 // found in the THIRD-PARTY file.
 
 use std::fmt::Formatter;
-use std::{fmt, result};
+use std::sync::{Arc, Mutex};
+use std::{fmt, io, result};
 
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::gic::GICDevice;
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::gic::GicState;
+use devices::virtio::mem::BlockMapper;
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
     kvm_clock_data, kvm_irqchip, kvm_pit_config, kvm_pit_state2, CpuId, MsrList,
@@ -22,7 +24,9 @@ use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
 use kvm_ioctls::{Kvm, VmFd};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
-use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_memory::{
+    Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
+};
 
 /// Errors associated with the wrappers over KVM ioctls.
 #[derive(Debug)]
@@ -30,6 +34,8 @@ pub enum Error {
     #[cfg(target_arch = "x86_64")]
     /// Retrieving supported guest MSRs fails.
     GuestMSRs(arch::x86_64::msr::Error),
+    /// The hot-pluggable memory region is not part of the guest memory.
+    InvalidHotplugRegion,
     /// The number of configured slots is bigger than the maximum reported by KVM.
     NotEnoughMemorySlots,
     /// Cannot set the memory regions.
@@ -109,6 +115,10 @@ impl fmt::Display for Error {
             ),
             VmFd(err) => write!(f, "Cannot open the VM file descriptor: {}", err),
             VmSetup(err) => write!(f, "Cannot configure the microvm: {}", err),
+            InvalidHotplugRegion => write!(
+                f,
+                "The hot-pluggable memory region is not part of the guest memory"
+            ),
             NotEnoughMemorySlots => write!(
                 f,
                 "The number of configured slots is bigger than the maximum reported by KVM"
@@ -136,9 +146,100 @@ impl fmt::Display for Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// KVM memory slots of the hot-pluggable memory region. Each block plugged by the guest gets
+/// its own slot, following the slots of the guest memory regions, while the unplugged blocks
+/// are left out of the guest physical address space.
+struct HotplugMemorySlots {
+    fd: Arc<VmFd>,
+    // Index of the region among the guest memory regions.
+    region_index: usize,
+    guest_addr: u64,
+    host_addr: u64,
+    block_size: u64,
+    first_slot: u32,
+    flags: u32,
+    // Whether each block of the region is plugged.
+    plugged: Vec<bool>,
+}
+
+impl HotplugMemorySlots {
+    fn set_block_slot(&self, index: usize, plugged: bool) -> kvm_ioctls::Result<()> {
+        let offset = index as u64 * self.block_size;
+        let memory_region = kvm_userspace_memory_region {
+            slot: self.first_slot + index as u32,
+            guest_phys_addr: self.guest_addr + offset,
+            // A slot of size 0 is deleted.
+            memory_size: if plugged { self.block_size } else { 0 },
+            userspace_addr: self.host_addr + offset,
+            flags: self.flags,
+        };
+
+        // Safe because the fd is a valid KVM file descriptor, and the host address range
+        // belongs to the guest memory.
+        unsafe { self.fd.set_user_memory_region(memory_region) }
+    }
+
+    fn set_flags(&mut self, flags: u32) -> kvm_ioctls::Result<()> {
+        self.flags = flags;
+        for (index, _) in self.plugged.iter().enumerate().filter(|(_, p)| **p) {
+            self.set_block_slot(index, true)?;
+        }
+        Ok(())
+    }
+
+    // Assembles the dirty bitmap of the whole region from the bitmaps of the plugged blocks.
+    fn dirty_log(&self) -> kvm_ioctls::Result<Vec<u64>> {
+        let block_pages = (self.block_size / utils::get_page_size()? as u64) as usize;
+        let mut bitmap = vec![0u64; (block_pages * self.plugged.len() + 63) / 64];
+        for (index, _) in self.plugged.iter().enumerate().filter(|(_, p)| **p) {
+            let block_bitmap = self
+                .fd
+                .get_dirty_log(self.first_slot + index as u32, self.block_size as usize)?;
+            for page in 0..block_pages {
+                if block_bitmap[page / 64] & (1 << (page % 64)) != 0 {
+                    let region_page = index * block_pages + page;
+                    bitmap[region_page / 64] |= 1 << (region_page % 64);
+                }
+            }
+        }
+        Ok(bitmap)
+    }
+}
+
+/// Maps the blocks plugged by the guest in their memory slots.
+pub struct HotplugBlockMapper(Arc<Mutex<HotplugMemorySlots>>);
+
+impl HotplugBlockMapper {
+    fn set_plugged(&mut self, index: usize, plugged: bool) -> io::Result<()> {
+        let mut slots = self.0.lock().expect("Poisoned lock");
+        if index >= slots.plugged.len() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        slots
+            .set_block_slot(index, plugged)
+            .map_err(|err| io::Error::from_raw_os_error(err.errno()))?;
+        slots.plugged[index] = plugged;
+        Ok(())
+    }
+}
+
+impl BlockMapper for HotplugBlockMapper {
+    fn map_block(&mut self, index: usize) -> io::Result<()> {
+        self.set_plugged(index, true)
+    }
+
+    fn unmap_block(&mut self, index: usize) -> io::Result<()> {
+        self.set_plugged(index, false)
+    }
+}
+
 /// A wrapper around creating and using a VM.
 pub struct Vm {
-    fd: VmFd,
+    fd: Arc<VmFd>,
+    // Maximum number of memory slots reported by KVM.
+    max_memslots: usize,
+    // Memory slots of the hot-pluggable memory region, if any.
+    hotplug_memory_slots: Option<Arc<Mutex<HotplugMemorySlots>>>,
 
     // X86 specific fields.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -167,7 +268,9 @@ impl Vm {
             arch::x86_64::msr::supported_guest_msrs(kvm).map_err(Error::GuestMSRs)?;
 
         Ok(Vm {
-            fd: vm_fd,
+            fd: Arc::new(vm_fd),
+            max_memslots: 0,
+            hotplug_memory_slots: None,
             #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
             supported_cpuid,
             #[cfg(target_arch = "x86_64")]
@@ -199,6 +302,7 @@ impl Vm {
         if guest_mem.num_regions() > kvm_max_memslots {
             return Err(Error::NotEnoughMemorySlots);
         }
+        self.max_memslots = kvm_max_memslots;
         self.set_kvm_memory_regions(guest_mem, track_dirty_pages)?;
         #[cfg(target_arch = "x86_64")]
         self.fd
@@ -343,6 +447,76 @@ impl Vm {
             .map_err(RestoreStateError)
     }
 
+    /// Leaves the hot-pluggable memory region at `region_addr`, made of blocks of `block_size`
+    /// bytes, out of the guest physical address space, except for the blocks mapped through
+    /// the returned mapper.
+    pub fn hotplug_block_mapper(
+        &mut self,
+        guest_mem: &GuestMemoryMmap,
+        region_addr: GuestAddress,
+        block_size: u64,
+        track_dirty_pages: bool,
+    ) -> Result<HotplugBlockMapper> {
+        let (region_index, region) = guest_mem
+            .iter()
+            .enumerate()
+            .find(|(_, region)| region.start_addr() == region_addr)
+            .ok_or(Error::InvalidHotplugRegion)?;
+        if block_size == 0 || region.len() % block_size != 0 {
+            return Err(Error::InvalidHotplugRegion);
+        }
+        let nb_blocks = (region.len() / block_size) as usize;
+        if guest_mem.num_regions() + nb_blocks > self.max_memslots {
+            return Err(Error::NotEnoughMemorySlots);
+        }
+
+        let slots = HotplugMemorySlots {
+            fd: self.fd.clone(),
+            region_index,
+            guest_addr: region_addr.raw_value(),
+            // It's safe to unwrap because the guest address is valid.
+            host_addr: guest_mem.get_host_address(region_addr).unwrap() as u64,
+            block_size,
+            first_slot: guest_mem.num_regions() as u32,
+            flags: if track_dirty_pages {
+                KVM_MEM_LOG_DIRTY_PAGES
+            } else {
+                0
+            },
+            plugged: vec![false; nb_blocks],
+        };
+        // Delete the slot of the whole region.
+        let memory_region = kvm_userspace_memory_region {
+            slot: region_index as u32,
+            guest_phys_addr: slots.guest_addr,
+            memory_size: 0,
+            userspace_addr: slots.host_addr,
+            flags: 0,
+        };
+        // Safe because the fd is a valid KVM file descriptor.
+        unsafe { self.fd.set_user_memory_region(memory_region) }
+            .map_err(Error::SetUserMemoryRegion)?;
+
+        let slots = Arc::new(Mutex::new(slots));
+        self.hotplug_memory_slots = Some(slots.clone());
+        Ok(HotplugBlockMapper(slots))
+    }
+
+    /// Retrieves the KVM dirty bitmap of the guest memory region `region`, the `index`th one.
+    pub(crate) fn get_dirty_log(
+        &self,
+        index: usize,
+        region: &GuestRegionMmap,
+    ) -> kvm_ioctls::Result<Vec<u64>> {
+        if let Some(slots) = &self.hotplug_memory_slots {
+            let slots = slots.lock().expect("Poisoned lock");
+            if slots.region_index == index {
+                return slots.dirty_log();
+            }
+        }
+        self.fd.get_dirty_log(index as u32, region.len() as usize)
+    }
+
     pub(crate) fn set_kvm_memory_regions(
         &self,
         guest_mem: &GuestMemoryMmap,
@@ -352,9 +526,15 @@ impl Vm {
         if track_dirty_pages {
             flags |= KVM_MEM_LOG_DIRTY_PAGES;
         }
+        let hotplug_region_index = self
+            .hotplug_memory_slots
+            .as_ref()
+            .map(|slots| slots.lock().expect("Poisoned lock").region_index);
         guest_mem
             .iter()
             .enumerate()
+            // Only the plugged blocks of the hot-pluggable memory region are registered.
+            .filter(|(index, _)| Some(*index) != hotplug_region_index)
             .try_for_each(|(index, region)| {
                 let memory_region = kvm_userspace_memory_region {
                     slot: index as u32,
@@ -369,6 +549,13 @@ impl Vm {
                 unsafe { self.fd.set_user_memory_region(memory_region) }
             })
             .map_err(Error::SetUserMemoryRegion)?;
+        if let Some(slots) = &self.hotplug_memory_slots {
+            slots
+                .lock()
+                .expect("Poisoned lock")
+                .set_flags(flags)
+                .map_err(Error::SetUserMemoryRegion)?;
+        }
         Ok(())
     }
 }
@@ -398,8 +585,6 @@ pub struct VmState {
 pub(crate) mod tests {
     use std::os::unix::io::FromRawFd;
 
-    use vm_memory::GuestAddress;
-
     use super::*;
     use crate::vstate::system::KvmContext;
 
@@ -457,6 +642,50 @@ pub(crate) mod tests {
             .is_ok());
     }
 
+    #[test]
+    fn test_hotplug_block_mapper() {
+        let kvm_context = KvmContext::new().unwrap();
+        let mut vm = Vm::new(kvm_context.fd()).expect("Cannot create new vm");
+        let gm = vm_memory::test_utils::create_anon_guest_memory(
+            &[
+                (GuestAddress(0), 0x1000),
+                (GuestAddress(0x10_0000), 0x10_0000),
+            ],
+            false,
+        )
+        .unwrap();
+        vm.memory_init(&gm, kvm_context.max_memslots(), true)
+            .unwrap();
+
+        // The region has to be one of the guest memory regions, made of whole blocks.
+        assert!(matches!(
+            vm.hotplug_block_mapper(&gm, GuestAddress(0x20_0000), 0x4_0000, true),
+            Err(Error::InvalidHotplugRegion)
+        ));
+        assert!(matches!(
+            vm.hotplug_block_mapper(&gm, GuestAddress(0x10_0000), 0x6_0000, true),
+            Err(Error::InvalidHotplugRegion)
+        ));
+
+        let mut mapper = vm
+            .hotplug_block_mapper(&gm, GuestAddress(0x10_0000), 0x4_0000, true)
+            .unwrap();
+        mapper.map_block(1).unwrap();
+        assert!(mapper.map_block(4).is_err());
+
+        // The dirty bitmap covers the whole region, the unplugged blocks included.
+        let region = gm.iter().nth(1).unwrap();
+        let page_size = utils::get_page_size().unwrap() as u64;
+        assert_eq!(
+            vm.get_dirty_log(1, region).unwrap().len() as u64,
+            (0x10_0000 / page_size + 63) / 64
+        );
+
+        // The plugged blocks stay mapped when dirty page tracking is toggled.
+        vm.set_kvm_memory_regions(&gm, false).unwrap();
+        mapper.unmap_block(1).unwrap();
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn test_vm_save_restore_state() {
@@ -164,6 +164,7 @@ fn verify_create_snapshot(is_diff: bool) -> (TempFile, TempFile) {
         snapshot_path: snapshot_file.as_path().to_path_buf(),
         mem_file_path: memory_file.as_path().to_path_buf(),
         version: Some(String::from("0.24.0")),
+        free_page_hinting: false,
     };
     let vm_info = VmInfo {
         mem_size_mib: 1u64,
@@ -240,6 +241,7 @@ fn verify_load_snapshot(snapshot_file: TempFile, memory_file: TempFile) {
         mem,
         None,
         false,
+        None,
         &empty_seccomp_filters,
         vm_resources,
     )
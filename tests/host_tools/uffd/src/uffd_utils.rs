@@ -31,6 +31,9 @@ pub struct GuestRegionUffdMapping {
     pub size: usize,
     /// Offset in the backend file/buffer where the region contents are.
     pub offset: u64,
+    /// Size of the pages backing the region, in KiB. The region must be
+    /// populated one whole page at a time.
+    pub page_size_kib: usize,
 }
 
 struct MemRegion {
@@ -116,13 +119,21 @@ impl UffdPfHandler {
         return (start_addr, start_addr + len as u64);
     }
 
-    fn zero_out(&mut self, addr: u64) -> (u64, u64) {
-        let page_size = get_page_size().unwrap();
-
-        let ret = unsafe {
-            self.uffd
-                .zeropage(addr as *mut _, page_size, true)
-                .expect("Uffd zeropage failed")
+    fn zero_out(&mut self, addr: u64, page_size: usize) -> (u64, u64) {
+        let ret = if page_size == get_page_size().unwrap() {
+            unsafe {
+                self.uffd
+                    .zeropage(addr as *mut _, page_size, true)
+                    .expect("Uffd zeropage failed")
+            }
+        } else {
+            // Zero pages are not supported on hugetlbfs, so copy them from a zeroed buffer.
+            let zeroes = vec![0u8; page_size];
+            unsafe {
+                self.uffd
+                    .copy(zeroes.as_ptr() as *const _, addr as *mut _, page_size, true)
+                    .expect("Uffd copy failed")
+            }
         };
         // Make sure the UFFD zeroed out some bytes.
         assert!(ret > 0);
@@ -131,14 +142,14 @@ impl UffdPfHandler {
     }
 
     pub fn serve_pf(&mut self, addr: *mut u8) {
-        let page_size = get_page_size().unwrap();
-
-        // Find the start of the page that the current faulting address belongs to.
-        let dst = (addr as usize & !(page_size as usize - 1)) as *mut c_void;
-        let fault_page_addr = dst as u64;
-
         // Get the state of the current faulting page.
         for region in self.mem_regions.iter() {
+            let page_size = region.mapping.page_size_kib * 1024;
+
+            // Find the start of the page that the current faulting address belongs to.
+            let dst = (addr as usize & !(page_size as usize - 1)) as *mut c_void;
+            let fault_page_addr = dst as u64;
+
             match region.page_states.get(&fault_page_addr) {
                 // Our simple PF handler has a simple strategy:
                 // There exist 4 states in which a memory page can be in:
@@ -154,7 +165,7 @@ impl UffdPfHandler {
                     return;
                 }
                 Some(MemPageState::Removed) | Some(MemPageState::Anonymous) => {
-                    let (start, end) = self.zero_out(fault_page_addr);
+                    let (start, end) = self.zero_out(fault_page_addr, page_size);
                     self.update_mem_state_mappings(start, end, &MemPageState::Anonymous);
                     return;
                 }
@@ -196,13 +207,13 @@ fn get_peer_process_credentials(stream: UnixStream) -> libc::ucred {
 }
 
 fn create_mem_regions(mappings: &Vec<GuestRegionUffdMapping>) -> Vec<MemRegion> {
-    let page_size = get_page_size().unwrap();
     let mut mem_regions: Vec<MemRegion> = Vec::with_capacity(mappings.len());
 
     for r in mappings.iter() {
         let mapping = r.clone();
         let mut addr = r.base_host_virt_addr;
         let end_addr = r.base_host_virt_addr + r.size as u64;
+        let page_size = r.page_size_kib * 1024;
         let mut page_states = HashMap::new();
 
         while addr < end_addr {